@@ -0,0 +1,438 @@
+//! Checkpoints of multi-file searches
+//!
+//! The search results of each batch of files are saved to the
+//! `sage-checkpoint` directory of `output_directory`, together with the
+//! fragment index. A manifest records the completed batches and a hash of the
+//! search parameters: if the same search is run again, e.g. after a crash or a
+//! cluster job hitting its time limit, completed batches are loaded instead of
+//! being searched again. Checkpoints are deleted once all outputs are written.
+
+use crate::input::{FileOverrides, Search};
+use crate::SageResults;
+use anyhow::Context;
+use log::{info, warn};
+use sage_cloudpath::CloudPath;
+use sage_core::qc::RunMetrics;
+use sage_core::scoring::Feature;
+use sage_core::spectrum::{ProcessedSpectrum, SkippedPrecursors};
+use sage_core::tmt::TmtQuant;
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const DIRECTORY: &str = "sage-checkpoint";
+const MANIFEST: &str = "manifest.json";
+
+/// Completed batches of a search, written to `manifest.json`
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+struct Manifest {
+    version: String,
+    /// Hash of the search parameters, see [`config_hash`]
+    config_hash: String,
+    batches: Vec<Batch>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Batch {
+    /// Position of the first file of the batch in `mzml_paths`, which is the
+    /// file id of its PSMs
+    start: usize,
+    /// Input files of the batch, in search order
+    files: Vec<String>,
+    /// Parameter overrides of each file
+    overrides: Vec<FileOverrides>,
+    /// Results of the batch, relative to the checkpoint directory
+    path: String,
+}
+
+/// Search results of a batch, as stored on disk
+#[derive(Serialize)]
+pub struct BatchRef<'a> {
+    features: &'a [Feature],
+    quant: &'a [TmtQuant],
+    ms1: &'a [ProcessedSpectrum],
+    skipped: &'a SkippedPrecursors,
+    runs: &'a [RunMetrics],
+}
+
+#[derive(Deserialize)]
+pub struct BatchOwned {
+    features: Vec<Feature>,
+    quant: Vec<TmtQuant>,
+    ms1: Vec<ProcessedSpectrum>,
+    skipped: SkippedPrecursors,
+    runs: Vec<RunMetrics>,
+}
+
+impl<'a> BatchRef<'a> {
+    pub fn new(results: &'a SageResults) -> Self {
+        BatchRef {
+            features: &results.features,
+            quant: &results.quant,
+            ms1: &results.ms1,
+            skipped: &results.skipped,
+            runs: &results.runs,
+        }
+    }
+}
+
+impl BatchOwned {
+    /// Search results of a batch searched by another run
+    pub fn into_results(mut self) -> SageResults {
+        // PSM identifiers must be unique within this run
+        for feature in &mut self.features {
+            feature.psm_id = sage_core::scoring::increment_psm_counter();
+        }
+        SageResults {
+            features: self.features,
+            quant: self.quant,
+            ms1: self.ms1,
+            skipped: self.skipped,
+            runs: self.runs,
+            ..Default::default()
+        }
+    }
+}
+
+pub struct Checkpoint {
+    directory: PathBuf,
+    manifest: Mutex<Manifest>,
+    /// Keep the checkpoints once the search completes
+    keep: bool,
+}
+
+/// Hash of every parameter that affects the results of a batch. Output-only
+/// parameters (e.g. `write_pin`) are excluded, except for `annotate_matches`,
+/// which determines whether matched fragments are stored. Input files and
+/// their overrides are recorded with each batch instead, so that files can
+/// be added to a search that is resumed
+pub fn config_hash(parameters: &Search) -> anyhow::Result<String> {
+    let mut value = serde_json::to_value(parameters)?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("mzml_paths");
+        object.remove("file_overrides");
+    }
+    let mut hasher = fnv::FnvHasher::default();
+    hasher.write(value.to_string().as_bytes());
+    hasher.write(format!("{:?}", parameters.tmt_design).as_bytes());
+    hasher.write_u8(parameters.annotate_matches as u8);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Write to a temporary file, then rename it to `path`, so that an
+/// interrupted write never leaves a truncated file behind
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<std::fs::File>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut wtr = BufWriter::new(std::fs::File::create(&tmp)?);
+    write(&mut wtr)?;
+    wtr.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl Checkpoint {
+    /// Open the checkpoint directory of a search, if `checkpoint` is set and
+    /// supported by the search. Checkpoints of a previous run are resumed
+    /// from if they were written with the same parameters, and discarded
+    /// otherwise, or if `overwrite` is set
+    pub fn open(parameters: &Search, overwrite: bool) -> anyhow::Result<Option<Self>> {
+        if !parameters.checkpoint {
+            return Ok(None);
+        }
+        if let Some(name) = Self::unsupported(parameters) {
+            warn!(
+                "`checkpoint` is not supported with {}, and is disabled",
+                name
+            );
+            return Ok(None);
+        }
+        let directory = match &parameters.output_directory {
+            CloudPath::Local(path) => path.join(DIRECTORY),
+            _ => {
+                warn!("`checkpoint` requires a local `output_directory`, and is disabled");
+                return Ok(None);
+            }
+        };
+
+        let manifest = Manifest {
+            version: parameters.version.clone(),
+            config_hash: config_hash(parameters)?,
+            batches: Vec::new(),
+        };
+        let previous = match std::fs::read(directory.join(MANIFEST)) {
+            Ok(bytes) => serde_json::from_slice::<Manifest>(&bytes).ok(),
+            Err(_) => None,
+        };
+        let manifest = match previous {
+            Some(previous) if overwrite => {
+                info!(
+                    "checkpoint: discarding {} searched batches of a previous run",
+                    previous.batches.len()
+                );
+                std::fs::remove_dir_all(&directory)?;
+                manifest
+            }
+            Some(previous)
+                if previous.version != manifest.version
+                    || previous.config_hash != manifest.config_hash =>
+            {
+                warn!(
+                    "checkpoint: `{}` was written with different search parameters, discarding it",
+                    directory.display()
+                );
+                std::fs::remove_dir_all(&directory)?;
+                manifest
+            }
+            Some(previous) => {
+                info!(
+                    "checkpoint: resuming from `{}`, {} files already searched",
+                    directory.display(),
+                    previous
+                        .batches
+                        .iter()
+                        .map(|b| b.files.len())
+                        .sum::<usize>()
+                );
+                previous
+            }
+            None => manifest,
+        };
+        std::fs::create_dir_all(&directory).with_context(|| {
+            format!(
+                "Failed to create checkpoint directory `{}`",
+                directory.display()
+            )
+        })?;
+        Ok(Some(Checkpoint {
+            directory,
+            manifest: Mutex::new(manifest),
+            keep: false,
+        }))
+    }
+
+    /// Search settings that checkpoints don't support, if any: their
+    /// results are not (only) computed one batch of files at a time
+    pub fn unsupported(parameters: &Search) -> Option<&'static str> {
+        [
+            (parameters.crosslink.is_some(), "`crosslink`"),
+            (parameters.prm.is_some(), "`prm`"),
+            (parameters.recalibration.is_some(), "`recalibration`"),
+            (
+                parameters.database.max_index_memory_mb.is_some(),
+                "`database.max_index_memory_mb`",
+            ),
+        ]
+        .into_iter()
+        .find(|(enabled, _)| *enabled)
+        .map(|(_, name)| name)
+    }
+
+    /// Keep the checkpoints once the search completes, so that a later
+    /// search with additional files only searches the new files
+    pub fn keep(mut self) -> Self {
+        self.keep = true;
+        self
+    }
+
+    /// Whether the checkpoints of a previous run of the same search can be
+    /// resumed from, see [`Checkpoint::open`]
+    pub fn resumable(parameters: &Search) -> bool {
+        let directory = match &parameters.output_directory {
+            CloudPath::Local(path) if parameters.checkpoint => path.join(DIRECTORY),
+            _ => return false,
+        };
+        let previous = std::fs::read(directory.join(MANIFEST))
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Manifest>(&bytes).ok());
+        match (previous, config_hash(parameters)) {
+            (Some(previous), Ok(hash)) => {
+                previous.version == parameters.version && previous.config_hash == hash
+            }
+            _ => false,
+        }
+    }
+
+    /// Path of the fragment index, which is re-used when resuming
+    pub fn index_path(&self) -> String {
+        self.directory.join("database.idx").display().to_string()
+    }
+
+    /// Load the results of a batch of files searched by a previous run
+    pub fn load(
+        &self,
+        start: usize,
+        files: &[String],
+        overrides: &[FileOverrides],
+    ) -> Option<SageResults> {
+        let path = {
+            let manifest = self.manifest.lock().expect("poisoned lock");
+            let batch = manifest
+                .batches
+                .iter()
+                .find(|b| b.start == start && b.files == files && b.overrides == overrides)?;
+            self.directory.join(&batch.path)
+        };
+        let batch = std::fs::File::open(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|f| {
+                Ok(bincode::deserialize_from::<_, BatchOwned>(BufReader::new(
+                    f,
+                ))?)
+            });
+        match batch {
+            Ok(batch) => Some(batch.into_results()),
+            Err(e) => {
+                warn!(
+                    "checkpoint: failed to load `{}`, searching again: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Save the results of a batch of files. Failing to save a checkpoint is
+    /// not an error: the batch will be searched again if the run is resumed
+    pub fn save(
+        &self,
+        start: usize,
+        files: &[String],
+        overrides: &[FileOverrides],
+        results: &SageResults,
+    ) {
+        if !results.failed.is_empty() {
+            return;
+        }
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write_usize(start);
+        files.iter().for_each(|file| hasher.write(file.as_bytes()));
+        let path = format!("batch-{:016x}.bin", hasher.finish());
+        let mut manifest = self.manifest.lock().expect("poisoned lock");
+        manifest
+            .batches
+            .retain(|batch| batch.start != start || batch.files != files);
+        let batch = BatchRef::new(results);
+        let saved = write_atomic(&self.directory.join(&path), |wtr| {
+            Ok(bincode::serialize_into(wtr, &batch)?)
+        })
+        .and_then(|_| {
+            manifest.batches.push(Batch {
+                start,
+                files: files.to_vec(),
+                overrides: overrides.to_vec(),
+                path,
+            });
+            write_atomic(&self.directory.join(MANIFEST), |wtr| {
+                Ok(serde_json::to_writer_pretty(wtr, &*manifest)?)
+            })
+        });
+        if let Err(e) = saved {
+            warn!(
+                "checkpoint: failed to save `{}`: {}",
+                self.directory.display(),
+                e
+            );
+        }
+    }
+
+    /// Delete the checkpoints of a completed search
+    pub fn finish(self) {
+        if self.keep {
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.directory) {
+            warn!(
+                "checkpoint: failed to delete `{}`: {}",
+                self.directory.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn search(directory: &Path) -> Search {
+        let input: crate::input::Input = serde_json::from_value(serde_json::json!({
+            "database": { "fasta": "none.fasta" },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+            "mzml_paths": ["a.mzML", "b.mzML"],
+            "output_directory": directory.display().to_string(),
+            "checkpoint": true,
+        }))
+        .unwrap();
+        input.build().unwrap()
+    }
+
+    #[test]
+    fn resume_checkpoint() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join("sage-checkpoint-test");
+        let _ = std::fs::remove_dir_all(&directory);
+        let parameters = search(&directory);
+        let files = vec!["a.mzML".to_string()];
+        let overrides = vec![FileOverrides::default()];
+
+        let checkpoint = Checkpoint::open(&parameters, false)?.unwrap();
+        assert!(checkpoint.load(0, &files, &overrides).is_none());
+        let results = SageResults {
+            features: vec![Feature {
+                psm_id: usize::MAX,
+                charge: 2,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        checkpoint.save(0, &files, &overrides, &results);
+        drop(checkpoint);
+
+        // Same parameters: the batch is loaded, with a new PSM identifier
+        let checkpoint = Checkpoint::open(&parameters, false)?.unwrap();
+        let loaded = checkpoint.load(0, &files, &overrides).unwrap();
+        assert_eq!(loaded.features.len(), 1);
+        assert_eq!(loaded.features[0].charge, 2);
+        assert_ne!(loaded.features[0].psm_id, usize::MAX);
+        assert!(checkpoint
+            .load(0, &["b.mzML".to_string()], &overrides)
+            .is_none());
+        // The file ids of PSMs depend on the position of the batch
+        assert!(checkpoint.load(1, &files, &overrides).is_none());
+        let min_peaks = FileOverrides {
+            min_peaks: Some(1),
+            ..Default::default()
+        };
+        assert!(checkpoint.load(0, &files, &[min_peaks]).is_none());
+        drop(checkpoint);
+
+        // Adding input files doesn't invalidate searched batches
+        let mut added = search(&directory);
+        added.mzml_paths.push("c.mzML".into());
+        added.file_overrides.push(FileOverrides::default());
+        let checkpoint = Checkpoint::open(&added, false)?.unwrap();
+        assert!(checkpoint.load(0, &files, &overrides).is_some());
+        drop(checkpoint);
+
+        // Overwriting, or changing parameters, discards the checkpoint
+        let checkpoint = Checkpoint::open(&parameters, true)?.unwrap();
+        assert!(checkpoint.load(0, &files, &overrides).is_none());
+        checkpoint.save(0, &files, &overrides, &results);
+        drop(checkpoint);
+        let mut changed = search(&directory);
+        changed.min_peaks += 1;
+        let checkpoint = Checkpoint::open(&changed, false)?.unwrap();
+        assert!(checkpoint.load(0, &files, &overrides).is_none());
+
+        checkpoint.finish();
+        assert!(!directory.join(DIRECTORY).exists());
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! Errors that determine the exit code of Sage
+
+use std::fmt;
+
+/// An input (spectra) file that could not be read
+#[derive(Debug)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: sage_cloudpath::Error,
+}
+
+impl fmt::Display for FailedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.error)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// Reading some input files failed. The remaining files were searched,
+    /// and their results written, unless no file could be read at all
+    #[error(
+        "{} of {} input files could not be read:\n{}",
+        .failed.len(),
+        .total,
+        .failed.iter().map(|file| format!("  - {}", file)).collect::<Vec<_>>().join("\n")
+    )]
+    InputFiles {
+        failed: Vec<FailedFile>,
+        total: usize,
+    },
+}
+
+impl Error {
+    /// Exit code of the process. Invalid command line arguments exit with
+    /// code 2, and all other errors with code 1
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InputFiles { .. } => 3,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! MS1 feature detection without a search
+//!
+//! `sage features` reads the MS1 scans of each input file, processed as they
+//! would be for a search (e.g. `rt_source` and `peak_cleanup`), and writes the
+//! features detected with the `ms1_features` settings of the configuration
+//! file - or the default settings - to `ms1_features.tsv`. Files are read
+//! `batch_size` at a time, restricted to `scan_range` and `rt_range` if set,
+//! and no FASTA database is needed.
+
+use crate::input::Input;
+use crate::output::write_ms1_features;
+use anyhow::ensure;
+use clap::ArgMatches;
+use log::info;
+use rayon::prelude::*;
+use sage_cloudpath::CloudPath;
+use sage_core::ms1_features::Ms1FeatureMap;
+use sage_core::spectrum::SpectrumProcessor;
+use std::time::Instant;
+
+pub fn run(matches: ArgMatches) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let parameters = Input::features_from_arguments(matches)?.build()?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(parameters.threads)
+        .build_global()?;
+    let finder = parameters.ms1_features.unwrap_or_default();
+    let processor = SpectrumProcessor {
+        rt_source: parameters.rt_source,
+        peak_cleanup: parameters.peak_cleanup,
+        ..SpectrumProcessor::new(
+            parameters.max_peaks,
+            parameters.database.fragment_min_mz,
+            parameters.database.fragment_max_mz,
+            false,
+        )
+    };
+
+    let mut features = Vec::new();
+    let mut failed = Vec::new();
+    let batch_size = parameters.batch_size.max(1);
+    for (batch, paths) in parameters.mzml_paths.chunks(batch_size).enumerate() {
+        let results = paths
+            .par_iter()
+            .enumerate()
+            .map(|(idx, path)| {
+                let file_id = batch * batch_size + idx;
+                let window = parameters.scan_window(file_id);
+                let mut ms1 = Vec::new();
+                crate::read_spectra(path, file_id, None, window, |spectrum| {
+                    if spectrum.ms_level == 1 {
+                        ms1.push(processor.process(spectrum));
+                    }
+                })
+                .map(|_| (ms1.len(), finder.detect(&ms1)))
+                .map_err(|error| format!("  - `{}`: {}", path, error))
+            })
+            .collect::<Vec<_>>();
+        for (path, result) in paths.iter().zip(results) {
+            match result {
+                Ok((scans, map)) => {
+                    info!(
+                        "- {}: detected {} MS1 features in {} MS1 scans",
+                        path,
+                        map.features().len(),
+                        scans
+                    );
+                    features.extend(map.into_features());
+                }
+                Err(error) => failed.push(error),
+            }
+        }
+    }
+
+    let filenames = parameters
+        .mzml_paths
+        .iter()
+        .map(|s| {
+            s.parse::<CloudPath>()
+                .ok()
+                .and_then(|c| c.filename().map(|s| s.to_string()))
+                .unwrap_or_else(|| s.clone())
+        })
+        .collect::<Vec<_>>();
+    let path = parameters.output_path("ms1_features.tsv");
+    write_ms1_features(&path, &Ms1FeatureMap::new(features), &filenames)?;
+    info!(
+        "wrote MS1 features to `{}` in {}s",
+        path,
+        start.elapsed().as_secs()
+    );
+
+    ensure!(
+        failed.is_empty(),
+        "{} of {} files could not be read:\n{}",
+        failed.len(),
+        parameters.mzml_paths.len(),
+        failed.join("\n")
+    );
+    Ok(())
+}
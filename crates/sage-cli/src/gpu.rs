@@ -0,0 +1,50 @@
+//! Preliminary scoring on the GPU, see [`sage_core::gpu`]. Without the `gpu`
+//! feature, [`FragmentMatcher`] has no values, so that searches can hold an
+//! `Option<FragmentMatcher>` regardless of the feature
+
+use sage_core::database::IndexedDatabase;
+#[cfg(not(feature = "gpu"))]
+use sage_core::scoring::{Feature, Scorer};
+#[cfg(not(feature = "gpu"))]
+use sage_core::spectrum::ProcessedSpectrum;
+
+#[cfg(feature = "gpu")]
+pub use sage_core::gpu::FragmentMatcher;
+
+#[cfg(not(feature = "gpu"))]
+pub enum FragmentMatcher {}
+
+#[cfg(not(feature = "gpu"))]
+impl FragmentMatcher {
+    pub fn matches(&self, _: &IndexedDatabase) -> bool {
+        match *self {}
+    }
+
+    pub fn score<Q>(&self, _: &Scorer, _: &[Q]) -> Vec<Vec<Feature>>
+    where
+        Q: std::borrow::Borrow<ProcessedSpectrum> + Sync,
+    {
+        match *self {}
+    }
+}
+
+/// Upload the fragment index of `database` to the GPU, if `enabled`. Software
+/// (CPU-emulated) adapters are not used: they are slower than the CPU scorer
+#[cfg(feature = "gpu")]
+pub fn fragment_matcher(enabled: bool, database: &IndexedDatabase) -> Option<FragmentMatcher> {
+    if !enabled {
+        return None;
+    }
+    let matcher = FragmentMatcher::new(database, false);
+    if matcher.is_none() {
+        log::warn!("gpu: no usable GPU found, scoring on the CPU");
+    }
+    matcher
+}
+
+/// `gpu` is rejected when parsing the parameters of a build without the
+/// feature
+#[cfg(not(feature = "gpu"))]
+pub fn fragment_matcher(_: bool, _: &IndexedDatabase) -> Option<FragmentMatcher> {
+    None
+}
@@ -1,47 +1,248 @@
+use crate::output::{PIN_COLUMNS, PIN_FIXED_COLUMNS};
 use anyhow::{ensure, Context};
 use clap::ArgMatches;
 use sage_cloudpath::CloudPath;
 use sage_core::{
-    database::{Builder, Parameters},
-    lfq::LfqSettings,
+    crosslink::{CrosslinkSettings, Linker},
+    database::{Builder, FastaPaths, Parameters},
+    faims::FaimsSettings,
+    glyco::{Glycan, GlycoMode, GlycoSettings},
+    inference::ProteinInferenceSettings,
+    ion_series::NeutralLoss,
+    irt::IrtSettings,
+    lfq::{IntegrationStrategy, LfqSettings},
+    library::LibrarySettings,
+    library_search::LibrarySearchSettings,
     mass::Tolerance,
-    tmt::Isobaric,
+    merge::SpectrumMerging,
+    ml::{linear_discriminant::FEATURE_NAMES, pep::PepEstimator, MlSettings, RescoringModel},
+    modification::{validate_var_mods, ValueOrVec},
+    ms1_features::FeatureFinder,
+    prefilter::PrefilterSettings,
+    prm::PrmSettings,
+    qc::QualityFilter,
+    recalibration::RecalibrationSettings,
+    rollup::ProteinRollup,
+    scoring::IsotopeErrorMode,
+    silac::SilacSettings,
+    spectrum::{
+        Centroiding, ChargeFilter, DeisotopeMethod, DuplicateMz, PeakCleanup, PeakFilter, PeakMask,
+        PrecursorGuards, RtSource, ScanWindow,
+    },
+    tmt::{ImpurityCorrection, Isobaric, Normalization},
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 
-#[derive(Serialize)]
+/// Parameter file with the default value of every common setting, written by
+/// `sage --write-default-config`
+pub const DEFAULT_CONFIG: &str = include_str!("default_config.jsonc");
+
+/// Remove `//` comments from a parameter file. Line breaks are kept, so that
+/// parsing errors refer to the right line
+fn strip_comments(json: &str) -> String {
+    let mut stripped = String::with_capacity(json.len());
+    for line in json.lines() {
+        let bytes = line.as_bytes();
+        let (mut in_string, mut escaped) = (false, false);
+        let mut end = line.len();
+        for (idx, &byte) in bytes.iter().enumerate() {
+            match (in_string, byte) {
+                (true, _) if escaped => escaped = false,
+                (true, b'\\') => escaped = true,
+                (_, b'"') => in_string = !in_string,
+                (false, b'/') if bytes.get(idx + 1) == Some(&b'/') => {
+                    end = idx;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        stripped.push_str(&line[..end]);
+        stripped.push('\n');
+    }
+    stripped
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
 /// Actual search parameters - may include overrides or default values not set by user
 pub struct Search {
     pub version: String,
     pub database: Parameters,
     pub quant: QuantSettings,
+    pub library: Option<LibraryParameters>,
+    /// Parsimonious protein grouping, if enabled
+    pub protein_inference: Option<ProteinInferenceSettings>,
     pub precursor_tol: Tolerance,
     pub fragment_tol: Tolerance,
     pub precursor_charge: (u8, u8),
     pub isotope_errors: (i8, i8),
+    pub isotope_error_mode: IsotopeErrorMode,
     pub deisotope: bool,
+    pub deisotope_method: DeisotopeMethod,
+    pub centroid: Centroiding,
     pub chimera: bool,
     pub wide_window: bool,
+    pub open_search: bool,
+    pub neutral_losses: Vec<NeutralLoss>,
+    pub localize_mods: bool,
+    pub prefilter: Option<PrefilterSettings>,
+    /// Removal of candidate peptides with incompatible ion mobilities, if
+    /// enabled
+    pub mobility_filter: Option<MobilityFilterParameters>,
+    /// Mass recalibration and re-search, if enabled
+    pub recalibration: Option<RecalibrationSettings>,
+    pub peak_cleanup: PeakCleanup,
     pub min_peaks: usize,
     pub max_peaks: usize,
+    /// Windowed selection of MS2 peaks, replacing the `max_peaks` cut
+    pub peak_filter: Option<PeakFilter>,
+    /// Removal of reporter ion and precursor peaks from MS2 spectra
+    pub peak_mask: Option<PeakMask>,
+    /// Removal of empty or noise-only MS2 spectra before scoring
+    pub quality_filter: QualityFilter,
+    /// Merging of MS2 spectra of the same precursor before scoring, if
+    /// enabled
+    pub merge_spectra: Option<SpectrumMerging>,
+    /// MS1 feature detection, if enabled
+    pub ms1_features: Option<FeatureFinder>,
     pub max_fragment_charge: Option<u8>,
     pub min_matched_peaks: u16,
+    /// Minimum fraction of peptide bonds explained by matched fragment ions
+    pub min_fragment_coverage: f32,
     pub report_psms: usize,
     pub predict_rt: bool,
+    pub rt_source: RtSource,
+    /// Processing of runs acquired at several FAIMS compensation voltages
+    pub faims: FaimsSettings,
+    /// PSM rescoring model
+    pub ml: MlSettings,
+    /// Calibration of retention times to iRT, if enabled
+    pub irt: Option<IrtSettings>,
+    #[serde(flatten)]
+    pub precursor_guards: PrecursorGuards,
+    /// Slice of each file that is searched, unless overridden for that file
+    #[serde(flatten)]
+    pub scan_window: ScanWindow,
+    /// Input files contain only MS2 spectra: MS1 spectra are not retained,
+    /// and MS1-dependent steps are skipped
+    pub ms2_only: bool,
+    /// Re-determine the monoisotopic m/z and charge of MS2 precursors from
+    /// the preceding MS1 scan
+    pub refine_precursors: bool,
+    /// Tolerance of precursor isotope peaks in MS1 scans, for isolation
+    /// purity and precursor refinement
+    pub ms1_tol: Tolerance,
+    /// Maximum protein-level q-value of proteins written to
+    /// `identified_proteins.fasta`, if requested
+    pub export_fasta: Option<f32>,
+    /// Rescoring feature columns written to `results.sage.pin`, if not all
+    pub pin_features: Option<Vec<String>>,
+    /// Cross-linked peptide search settings, if enabled
+    pub crosslink: Option<CrosslinkSettings>,
+    /// Glycopeptide search settings, if enabled
+    pub glyco: Option<GlycoSettings>,
+    /// Targeted (PRM) fragment extraction, if enabled
+    pub prm: Option<PrmParameters>,
+    /// Second-pass search of unmatched spectra, if enabled
+    pub cascade: Option<CascadeParameters>,
+    /// Spectral library search, replacing the database search, if enabled
+    pub library_search: Option<LibrarySearchParameters>,
+    /// Path to a spectral library of predicted fragment intensities, or to
+    /// an ONNX model that predicts them, used for rescoring, if enabled
+    pub predicted_intensities: Option<String>,
+    /// Normalized collision energy of the predicted fragment intensities, if
+    /// they are predicted with an ONNX model
+    pub prediction_collision_energy: f32,
     pub mzml_paths: Vec<String>,
+    /// Per-file parameter overrides, in the same order as `mzml_paths`
+    pub file_overrides: Vec<FileOverrides>,
     pub output_paths: Vec<String>,
 
+    /// TMT plexes resolved against `mzml_paths` and reporter ion channels
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub tmt_design: Option<TmtDesign>,
+
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     pub output_directory: CloudPath,
 
     #[serde(skip_serializing)]
+    #[schemars(skip)]
     pub write_pin: bool,
 
     #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub write_flashlfq: bool,
+
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub write_msstats: bool,
+
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub write_sqlite: bool,
+
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
     pub annotate_matches: bool,
+
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub diagnostics: bool,
+
+    /// Name of output files, where `{name}` is replaced by the default name
+    /// of each file
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub output_template: String,
+
+    /// Number of threads used for database building and searching
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub threads: usize,
+
+    /// Number of files read and searched at the same time
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub batch_size: usize,
+
+    /// Count matched fragments on the GPU
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub gpu: bool,
+
+    /// Save the results of each batch of files, so that an interrupted
+    /// search can be resumed
+    #[serde(skip_serializing)]
+    #[schemars(skip)]
+    pub checkpoint: bool,
+}
+
+impl Search {
+    /// Path of the output file with default name `file_name`, after applying
+    /// `output_template`
+    pub fn output_path<S: AsRef<str>>(&self, file_name: S) -> CloudPath {
+        let mut path = self.output_directory.clone();
+        path.push(self.output_template.replace("{name}", file_name.as_ref()));
+        path
+    }
+
+    /// Slice of file `file_id` that is searched, after applying any per-file
+    /// overrides
+    pub fn scan_window(&self, file_id: usize) -> ScanWindow {
+        let overrides = &self.file_overrides[file_id];
+        ScanWindow {
+            scan_range: overrides.scan_range.or(self.scan_window.scan_range),
+            rt_range: overrides.rt_range.or(self.scan_window.rt_range),
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 /// Input search parameters deserialized from JSON file
 pub struct Input {
     database: Builder,
@@ -50,29 +251,281 @@ pub struct Input {
     report_psms: Option<usize>,
     chimera: Option<bool>,
     wide_window: Option<bool>,
+    open_search: Option<bool>,
+    neutral_losses: Option<Vec<NeutralLoss>>,
+    localize_mods: Option<bool>,
+    prefilter: Option<PrefilterOptions>,
+    mobility_filter: Option<MobilityFilterOptions>,
+    recalibration: Option<RecalibrationOptions>,
+    peak_cleanup: Option<PeakCleanupOptions>,
     min_peaks: Option<usize>,
     max_peaks: Option<usize>,
+    peak_filter: Option<PeakFilterOptions>,
+    peak_mask: Option<PeakMaskOptions>,
+    quality_filter: Option<QualityFilterOptions>,
+    merge_spectra: Option<MergeSpectraOptions>,
+    ms1_features: Option<Ms1FeatureOptions>,
     max_fragment_charge: Option<u8>,
     min_matched_peaks: Option<u16>,
+    min_fragment_coverage: Option<f32>,
     precursor_charge: Option<(u8, u8)>,
     isotope_errors: Option<(i8, i8)>,
+    isotope_error_mode: Option<IsotopeErrorMode>,
     deisotope: Option<bool>,
+    /// Detection of isotopic envelopes when deisotoping MS2 spectra
+    deisotope_method: Option<DeisotopeMethod>,
+    /// Peak picking of MS2 spectra in profile mode
+    centroid: Option<Centroiding>,
     quant: Option<QuantOptions>,
+    library: Option<LibraryOptions>,
+    protein_inference: Option<ProteinInferenceOptions>,
     predict_rt: Option<bool>,
+    rt_source: Option<RtSource>,
+    faims: Option<FaimsOptions>,
+    ml: Option<MlOptions>,
+    irt: Option<IrtOptions>,
+    ms2_only: Option<bool>,
+    refine_precursors: Option<bool>,
+    ms1_tol: Option<Tolerance>,
+    max_precursor_mass: Option<f32>,
+    max_precursor_charge: Option<u8>,
+    charge_filter: Option<ChargeFilter>,
+    /// First and last scan number searched in each file
+    scan_range: Option<(u32, u32)>,
+    /// First and last scan start time (minutes) searched in each file
+    rt_range: Option<(f32, f32)>,
     output_directory: Option<String>,
-    mzml_paths: Option<Vec<String>>,
+    mzml_paths: Option<Vec<MzmlPath>>,
 
     annotate_matches: Option<bool>,
     write_pin: Option<bool>,
+    write_flashlfq: Option<bool>,
+    write_msstats: Option<bool>,
+    write_sqlite: Option<bool>,
+    diagnostics: Option<bool>,
+    /// Name of output files (default = `{name}`), with placeholders `{name}`
+    /// (the default name of each file, e.g. `results.sage.tsv`), `{stem}`
+    /// (the name of the input file, when searching a single file) and
+    /// `{date}` (the date of the search, as YYYY-MM-DD)
+    output_template: Option<String>,
+    /// Number of threads (default = number of CPUs)
+    threads: Option<usize>,
+    /// Number of files read and searched at the same time (default = half of
+    /// `threads`). Spectra of these files are held in memory at once
+    batch_size: Option<usize>,
+    /// Count the fragments of candidate peptides that match each spectrum on
+    /// the GPU, if one is found (default = false). Requires the `gpu` feature
+    gpu: Option<bool>,
+    /// Save the results of each batch of files to `output_directory`, and
+    /// skip already searched batches when the same search is run again
+    checkpoint: Option<bool>,
+    export_fasta: Option<f32>,
+    pin_features: Option<Vec<String>>,
+    crosslink: Option<CrosslinkOptions>,
+    glyco: Option<GlycoOptions>,
+    prm: Option<PrmOptions>,
+    cascade: Option<CascadeOptions>,
+    library_search: Option<LibrarySearchOptions>,
+    /// Path to a spectral library (`library.sage.tsv` format) of predicted
+    /// fragment intensities, or to an ONNX model that predicts them
+    predicted_intensities: Option<String>,
+    /// Normalized collision energy (e.g. 30) to predict fragment intensities
+    /// at, with an ONNX model
+    prediction_collision_energy: Option<f32>,
+}
+
+/// Search parameters that can be overridden for an individual file, e.g. a
+/// different fragment tolerance for ion trap MS2 spectra in an Orbitrap batch
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, JsonSchema)]
+pub struct FileOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub precursor_tol: Option<Tolerance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fragment_tol: Option<Tolerance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deisotope: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_peaks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_peaks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scan_range: Option<(u32, u32)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rt_range: Option<(f32, f32)>,
+}
+
+/// An entry of `mzml_paths`: either a path, or an object containing a path
+/// and parameter overrides for that file. Local paths can also be glob
+/// patterns or directories, which are expanded to the spectra files they
+/// contain
+#[derive(Deserialize, Debug, PartialEq, JsonSchema)]
+#[serde(untagged)]
+enum MzmlPath {
+    Path(String),
+    WithOverrides {
+        path: String,
+        #[serde(flatten)]
+        overrides: FileOverrides,
+    },
+}
+
+impl MzmlPath {
+    fn path(&self) -> &str {
+        match self {
+            MzmlPath::Path(path) | MzmlPath::WithOverrides { path, .. } => path,
+        }
+    }
+
+    fn split(self) -> (String, FileOverrides) {
+        match self {
+            MzmlPath::Path(path) => (path, FileOverrides::default()),
+            MzmlPath::WithOverrides { path, overrides } => (path, overrides),
+        }
+    }
+
+    fn with_path(&self, path: String) -> Self {
+        match self {
+            MzmlPath::Path(_) => MzmlPath::Path(path),
+            MzmlPath::WithOverrides { overrides, .. } => MzmlPath::WithOverrides {
+                path,
+                overrides: overrides.clone(),
+            },
+        }
+    }
+
+    /// Expand a local glob pattern (e.g. `/data/run_*.mzML`, or
+    /// `/data/**/*.mzML` to search subdirectories) or directory to the
+    /// spectra files it matches, sorted by path. Per-file overrides apply to
+    /// each of them. Other paths, and patterns that match no spectra files,
+    /// are returned unchanged
+    fn expand(self) -> anyhow::Result<Vec<Self>> {
+        let matches = match find_spectra_files(self.path())? {
+            Some(matches) if matches.is_empty() => {
+                log::warn!("`{}` does not match any spectra files", self.path());
+                return Ok(vec![self]);
+            }
+            Some(matches) => matches,
+            None => return Ok(vec![self]),
+        };
+        log::info!(
+            "- `{}`: expanded to {} spectra files",
+            self.path(),
+            matches.len()
+        );
+        Ok(matches
+            .into_iter()
+            .map(|path| self.with_path(path.display().to_string()))
+            .collect())
+    }
+}
+
+/// Spectra files matched by a local glob pattern, or contained in a local
+/// directory, sorted by path. `None` if `pattern` is neither
+pub fn find_spectra_files(pattern: &str) -> anyhow::Result<Option<Vec<PathBuf>>> {
+    let path = match pattern.parse::<CloudPath>() {
+        Ok(CloudPath::Local(path)) => path,
+        _ => return Ok(None),
+    };
+    let mut matches = if pattern.contains(['*', '?', '[']) {
+        glob::glob(pattern)
+            .with_context(|| format!("`{}` is not a valid glob pattern", pattern))?
+            .filter_map(Result::ok)
+            .filter(|path| is_spectra_file(path))
+            .collect::<Vec<_>>()
+    } else if path.is_dir() && !is_spectra_file(&path) {
+        std::fs::read_dir(&path)
+            .with_context(|| format!("Failed to list `{}`", path.display()))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| is_spectra_file(path))
+            .collect::<Vec<_>>()
+    } else {
+        return Ok(None);
+    };
+    matches.sort();
+    Ok(Some(matches))
+}
+
+/// Replace the `{stem}` and `{date}` placeholders of `output_template`.
+/// `{name}` is replaced when writing each output file
+fn resolve_output_template(
+    template: &str,
+    mzml_paths: &[String],
+    date: &str,
+) -> anyhow::Result<String> {
+    ensure!(
+        template.contains("{name}"),
+        "`output_template` must contain `{{name}}`, the default name of each output file"
+    );
+    ensure!(
+        !template.contains(['/', '\\']),
+        "`output_template` must be a file name, use `output_directory` to set the directory"
+    );
+    let mut resolved = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        resolved.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .with_context(|| format!("`output_template`: unclosed `{{` in `{}`", template))?;
+        match &rest[start + 1..end] {
+            "name" => resolved.push_str("{name}"),
+            "date" => resolved.push_str(date),
+            "stem" => {
+                ensure!(
+                    mzml_paths.len() == 1,
+                    "`output_template`: `{{stem}}` can only be used when searching a single file"
+                );
+                let path = mzml_paths[0].parse::<CloudPath>()?;
+                let filename = path.filename().unwrap_or(&mzml_paths[0]);
+                resolved.push_str(crate::output::run_name(filename));
+            }
+            other => anyhow::bail!(
+                "`output_template`: unknown placeholder `{{{}}}`, expected one of \
+                 `{{name}}`, `{{stem}}` or `{{date}}`",
+                other
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// Whether a path has the extension of a spectra file that Sage can read,
+/// or is a Bruker `.d` directory. Files within `.d` directories are excluded,
+/// so that they are only searched as part of their directory
+fn is_spectra_file(path: &std::path::Path) -> bool {
+    let in_bruker_directory = path.ancestors().skip(1).any(|dir| {
+        dir.extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("d"))
+    });
+    let name = path.to_string_lossy().to_lowercase();
+    let name = name
+        .strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".gzip"))
+        .unwrap_or(&name);
+    !in_bruker_directory
+        && [".mzml", ".mzxml", ".mgf", ".raw", ".d"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+}
+
+/// Whether `predicted_intensities` is an ONNX model that predicts fragment
+/// intensities, rather than a spectral library of predictions
+pub fn is_intensity_model(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".onnx")
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 pub struct LfqOptions {
     peak_scoring: Option<sage_core::lfq::PeakScoringStrategy>,
     integration: Option<sage_core::lfq::IntegrationStrategy>,
     spectral_angle: Option<f64>,
     ppm_tolerance: Option<f32>,
+    rt_tolerance: Option<f32>,
     combine_charge_states: Option<bool>,
+    mbr: Option<bool>,
 }
 
 impl From<LfqOptions> for LfqSettings {
@@ -83,9 +536,11 @@ impl From<LfqOptions> for LfqSettings {
             integration: value.integration.unwrap_or(default.integration),
             spectral_angle: value.spectral_angle.unwrap_or(default.spectral_angle).abs(),
             ppm_tolerance: value.ppm_tolerance.unwrap_or(default.ppm_tolerance).abs(),
+            rt_tolerance: value.rt_tolerance.unwrap_or(default.rt_tolerance).abs(),
             combine_charge_states: value
                 .combine_charge_states
                 .unwrap_or(default.combine_charge_states),
+            mbr: value.mbr.unwrap_or(default.mbr),
         };
         if settings.ppm_tolerance > 20.0 {
             log::warn!("lfq_settings.ppm_tolerance is higher than expected");
@@ -93,173 +548,1077 @@ impl From<LfqOptions> for LfqSettings {
         if settings.spectral_angle < 0.50 {
             log::warn!("lfq_settings.spectral_angle is lower than expected");
         }
+        if settings.rt_tolerance > 0.05 {
+            log::warn!("lfq_settings.rt_tolerance is higher than expected");
+        }
 
         settings
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct TmtOptions {
-    level: Option<u8>,
-    sn: Option<bool>,
-}
-
-#[derive(Copy, Clone, Serialize)]
-pub struct TmtSettings {
-    pub level: u8,
-    pub sn: bool,
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct SilacOptions {
+    labels: Option<HashMap<char, f32>>,
+    ppm_tolerance: Option<f32>,
+    rt_tolerance: Option<f32>,
+    min_scans: Option<usize>,
+    spectrum_fdr: Option<f32>,
 }
 
-impl From<TmtOptions> for TmtSettings {
-    fn from(value: TmtOptions) -> Self {
-        let default = Self::default();
-        Self {
-            level: value.level.unwrap_or(default.level),
-            sn: value.sn.unwrap_or(default.sn),
+impl From<SilacOptions> for SilacSettings {
+    fn from(value: SilacOptions) -> SilacSettings {
+        let default = SilacSettings::default();
+        let settings = SilacSettings {
+            labels: value.labels.unwrap_or(default.labels),
+            ppm_tolerance: value.ppm_tolerance.unwrap_or(default.ppm_tolerance).abs(),
+            rt_tolerance: value.rt_tolerance.unwrap_or(default.rt_tolerance).abs(),
+            min_scans: value.min_scans.unwrap_or(default.min_scans).max(1),
+            spectrum_fdr: value.spectrum_fdr.unwrap_or(default.spectrum_fdr),
+        };
+        if settings.labels.is_empty() {
+            log::warn!("quant.silac.labels is empty, no SILAC pairs will be detected");
         }
+        settings
     }
 }
 
-impl Default for TmtSettings {
-    fn default() -> Self {
-        Self {
-            level: 3,
-            sn: false,
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct RecalibrationOptions {
+    q_value: Option<f32>,
+    min_psms: Option<usize>,
+    tolerance_sd: Option<f32>,
+}
+
+impl From<RecalibrationOptions> for RecalibrationSettings {
+    fn from(value: RecalibrationOptions) -> RecalibrationSettings {
+        let default = RecalibrationSettings::default();
+        RecalibrationSettings {
+            q_value: value.q_value.unwrap_or(default.q_value),
+            min_psms: value.min_psms.unwrap_or(default.min_psms).max(1),
+            tolerance_sd: value.tolerance_sd.unwrap_or(default.tolerance_sd).abs(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct QuantOptions {
-    pub tmt: Option<Isobaric>,
-    #[serde(rename = "tmt_settings")]
-    pub tmt_options: Option<TmtOptions>,
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct MobilityFilterOptions {
+    /// Path to an ion mobility model written by a previous search
+    /// (`mobility_model.json`)
+    model: String,
+    tolerance: Option<f32>,
+}
 
-    pub lfq: Option<bool>,
-    #[serde(rename = "lfq_settings")]
-    pub lfq_options: Option<LfqOptions>,
+#[derive(Serialize, Clone, Debug, JsonSchema)]
+pub struct MobilityFilterParameters {
+    pub model: String,
+    /// Maximum absolute difference between predicted and observed 1/K0
+    pub tolerance: f32,
 }
 
-#[derive(Serialize, Default)]
-pub struct QuantSettings {
-    pub tmt: Option<Isobaric>,
-    pub tmt_settings: TmtSettings,
-    pub lfq: bool,
-    pub lfq_settings: LfqSettings,
+impl TryFrom<MobilityFilterOptions> for MobilityFilterParameters {
+    type Error = anyhow::Error;
+
+    fn try_from(value: MobilityFilterOptions) -> Result<Self, Self::Error> {
+        let tolerance = value.tolerance.unwrap_or(0.05);
+        ensure!(
+            tolerance > 0.0,
+            "`mobility_filter.tolerance` must be positive"
+        );
+        Ok(MobilityFilterParameters {
+            model: value.model,
+            tolerance,
+        })
+    }
 }
 
-impl From<QuantOptions> for QuantSettings {
-    fn from(value: QuantOptions) -> Self {
-        Self {
-            tmt: value.tmt,
-            tmt_settings: value.tmt_options.map(Into::into).unwrap_or_default(),
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct PrefilterOptions {
+    bin_width: Option<f32>,
+    hashes: Option<usize>,
+    min_collisions: Option<usize>,
+    query_peaks: Option<usize>,
+}
 
-            lfq: value.lfq.unwrap_or(false),
-            lfq_settings: value.lfq_options.map(Into::into).unwrap_or_default(),
+impl From<PrefilterOptions> for PrefilterSettings {
+    fn from(value: PrefilterOptions) -> PrefilterSettings {
+        let default = PrefilterSettings::default();
+        let settings = PrefilterSettings {
+            bin_width: value.bin_width.unwrap_or(default.bin_width).abs(),
+            hashes: value.hashes.unwrap_or(default.hashes).max(1),
+            min_collisions: value
+                .min_collisions
+                .unwrap_or(default.min_collisions)
+                .max(1),
+            query_peaks: value.query_peaks.unwrap_or(default.query_peaks).max(1),
+        };
+        if settings.min_collisions > settings.hashes {
+            log::warn!(
+                "prefilter.min_collisions ({}) is greater than prefilter.hashes ({}), no peptides will be shortlisted",
+                settings.min_collisions,
+                settings.hashes
+            );
         }
+        settings
     }
 }
 
-impl Input {
-    pub fn from_arguments(matches: ArgMatches) -> anyhow::Result<Self> {
-        let path = matches
-            .get_one::<String>("parameters")
-            .expect("required parameters");
-        let mut input = Input::load(path)
-            .with_context(|| format!("Failed to read parameters from `{path}`"))?;
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct PeakCleanupOptions {
+    remove_zero_intensity: Option<bool>,
+    duplicate_mz: Option<DuplicateMz>,
+}
 
-        // Handle JSON configuration overrides
-        if let Some(output_directory) = matches.get_one::<String>("output_directory") {
-            input.output_directory = Some(output_directory.into());
-        }
-        if let Some(fasta) = matches.get_one::<String>("fasta") {
-            input.database.fasta = Some(fasta.into());
-        }
-        if let Some(mzml_paths) = matches.get_many::<String>("mzml_paths") {
-            input.mzml_paths = Some(mzml_paths.into_iter().map(|p| p.into()).collect());
+impl From<PeakCleanupOptions> for PeakCleanup {
+    fn from(value: PeakCleanupOptions) -> PeakCleanup {
+        let default = PeakCleanup::default();
+        PeakCleanup {
+            remove_zero_intensity: value
+                .remove_zero_intensity
+                .unwrap_or(default.remove_zero_intensity),
+            duplicate_mz: value.duplicate_mz.unwrap_or(default.duplicate_mz),
         }
+    }
+}
 
-        if let Some(write_pin) = matches.get_one::<bool>("write-pin").copied() {
-            input.write_pin = Some(write_pin);
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct PeakFilterOptions {
+    window: Option<f32>,
+    peaks_per_window: Option<usize>,
+    min_relative_intensity: Option<f32>,
+}
+
+impl TryFrom<PeakFilterOptions> for PeakFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PeakFilterOptions) -> Result<Self, Self::Error> {
+        let default = PeakFilter::default();
+        let filter = PeakFilter {
+            window: value.window.unwrap_or(default.window),
+            peaks_per_window: value.peaks_per_window.unwrap_or(default.peaks_per_window),
+            min_relative_intensity: value
+                .min_relative_intensity
+                .unwrap_or(default.min_relative_intensity),
+        };
+        ensure!(
+            filter.window > 0.0 && filter.peaks_per_window > 0,
+            "`peak_filter.window` and `peak_filter.peaks_per_window` must be positive"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&filter.min_relative_intensity),
+            "`peak_filter.min_relative_intensity` must be between 0 and 1"
+        );
+        Ok(filter)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct PeakMaskOptions {
+    /// m/z range of isobaric reporter ions, e.g. `[125.5, 135.5]` for TMT
+    reporter_region: Option<(f32, f32)>,
+    /// Remove the unfragmented precursor and its neutral losses
+    precursor: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct QualityFilterOptions {
+    min_total_ion_current: Option<f32>,
+    min_dynamic_range: Option<f32>,
+}
+
+impl From<QualityFilterOptions> for QualityFilter {
+    fn from(value: QualityFilterOptions) -> QualityFilter {
+        let default = QualityFilter::default();
+        QualityFilter {
+            min_total_ion_current: value
+                .min_total_ion_current
+                .unwrap_or(default.min_total_ion_current),
+            min_dynamic_range: value.min_dynamic_range.unwrap_or(default.min_dynamic_range),
         }
+    }
+}
 
-        if let Some(annotate_matches) = matches.get_one::<bool>("annotate-matches").copied() {
-            input.annotate_matches = Some(annotate_matches);
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct MergeSpectraOptions {
+    rt_window: Option<f32>,
+    precursor_ppm: Option<f32>,
+    fragment_ppm: Option<f32>,
+}
+
+impl TryFrom<MergeSpectraOptions> for SpectrumMerging {
+    type Error = anyhow::Error;
+
+    fn try_from(value: MergeSpectraOptions) -> Result<Self, Self::Error> {
+        let default = SpectrumMerging::default();
+        let merging = SpectrumMerging {
+            rt_window: value.rt_window.unwrap_or(default.rt_window),
+            precursor_ppm: value.precursor_ppm.unwrap_or(default.precursor_ppm).abs(),
+            fragment_ppm: value.fragment_ppm.unwrap_or(default.fragment_ppm).abs(),
+        };
+        ensure!(
+            merging.rt_window > 0.0,
+            "`merge_spectra.rt_window` must be positive"
+        );
+        ensure!(
+            merging.precursor_ppm > 0.0 && merging.fragment_ppm > 0.0,
+            "`merge_spectra.precursor_ppm` and `merge_spectra.fragment_ppm` must be positive"
+        );
+        Ok(merging)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct FaimsOptions {
+    split_rt: Option<bool>,
+    split_quant: Option<bool>,
+    split_output: Option<bool>,
+}
+
+impl From<FaimsOptions> for FaimsSettings {
+    fn from(value: FaimsOptions) -> Self {
+        let default = FaimsSettings::default();
+        FaimsSettings {
+            split_rt: value.split_rt.unwrap_or(default.split_rt),
+            split_quant: value.split_quant.unwrap_or(default.split_quant),
+            split_output: value.split_output.unwrap_or(default.split_output),
         }
+    }
+}
 
-        // avoid to later panic if these parameters are not set (but doesn't check if files exist)
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct Ms1FeatureOptions {
+    ppm_tolerance: Option<f32>,
+    min_charge: Option<u8>,
+    max_charge: Option<u8>,
+    min_scans: Option<usize>,
+    max_gap: Option<usize>,
+}
 
+impl TryFrom<Ms1FeatureOptions> for FeatureFinder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Ms1FeatureOptions) -> Result<Self, Self::Error> {
+        let default = FeatureFinder::default();
+        let finder = FeatureFinder {
+            ppm_tolerance: value.ppm_tolerance.unwrap_or(default.ppm_tolerance).abs(),
+            min_charge: value.min_charge.unwrap_or(default.min_charge),
+            max_charge: value.max_charge.unwrap_or(default.max_charge),
+            min_scans: value.min_scans.unwrap_or(default.min_scans),
+            max_gap: value.max_gap.unwrap_or(default.max_gap),
+        };
         ensure!(
-            input.database.fasta.is_some(),
-            "`database.fasta` must be set. For more information try '--help'"
+            finder.ppm_tolerance > 0.0,
+            "`ms1_features.ppm_tolerance` must be positive"
         );
         ensure!(
-            input
-                .mzml_paths
-                .as_ref()
-                .map(|p| p.len())
-                .unwrap_or_default()
-                > 0,
-            "`mzml_paths` must be set. For more information try '--help'"
+            finder.min_charge >= 1 && finder.min_charge <= finder.max_charge,
+            "`ms1_features.min_charge` must be between 1 and `ms1_features.max_charge`"
+        );
+        ensure!(
+            finder.min_scans >= 2,
+            "`ms1_features.min_scans` must be at least 2"
         );
+        Ok(finder)
+    }
+}
 
-        Ok(input)
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct MlOptions {
+    model: Option<RescoringModel>,
+    pep: Option<PepEstimator>,
+    /// Rescoring features that are not used to train the rescoring model
+    exclude_features: Option<Vec<String>>,
+    export_features: Option<bool>,
+}
+
+impl From<MlOptions> for MlSettings {
+    fn from(value: MlOptions) -> MlSettings {
+        let default = MlSettings::default();
+        MlSettings {
+            model: value.model.unwrap_or(default.model),
+            pep: value.pep.unwrap_or(default.pep),
+            exclude_features: value.exclude_features.unwrap_or(default.exclude_features),
+            export_features: value.export_features.unwrap_or(default.export_features),
+        }
     }
+}
 
-    pub fn load<S: AsRef<str>>(path: S) -> anyhow::Result<Self> {
-        sage_cloudpath::util::read_json(path).map_err(anyhow::Error::from)
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct IrtOptions {
+    peptides: Option<BTreeMap<String, f32>>,
+    q_value: Option<f32>,
+    min_peptides: Option<usize>,
+}
+
+impl From<IrtOptions> for IrtSettings {
+    fn from(value: IrtOptions) -> IrtSettings {
+        let default = IrtSettings::default();
+        IrtSettings {
+            peptides: value.peptides.unwrap_or(default.peptides),
+            q_value: value.q_value.unwrap_or(default.q_value),
+            min_peptides: value.min_peptides.unwrap_or(default.min_peptides).max(2),
+        }
     }
+}
 
-    fn check_tolerances(tolerance: &Tolerance) {
-        let (lo, hi) = match tolerance {
-            Tolerance::Ppm(lo, hi) => (*lo, *hi),
-            Tolerance::Da(lo, hi) => (*lo, *hi),
+/// Column format of the spectral library
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LibraryFormat {
+    /// `library.sage.tsv`, which Sage can search (`library_search`)
+    #[default]
+    Sage,
+    /// `library.dia.tsv`, in the generic format of DIA-NN and Spectronaut
+    Dia,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct LibraryOptions {
+    spectrum_fdr: Option<f32>,
+    peptide_fdr: Option<f32>,
+    min_replicates: Option<usize>,
+    min_fragment_frequency: Option<f32>,
+    format: Option<LibraryFormat>,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct LibraryParameters {
+    pub format: LibraryFormat,
+    #[serde(flatten)]
+    pub settings: LibrarySettings,
+}
+
+impl From<LibraryOptions> for LibraryParameters {
+    fn from(value: LibraryOptions) -> LibraryParameters {
+        let default = LibrarySettings::default();
+        let settings = LibrarySettings {
+            spectrum_fdr: value.spectrum_fdr.unwrap_or(default.spectrum_fdr),
+            peptide_fdr: value.peptide_fdr.unwrap_or(default.peptide_fdr),
+            min_replicates: value
+                .min_replicates
+                .unwrap_or(default.min_replicates)
+                .max(1),
+            min_fragment_frequency: value
+                .min_fragment_frequency
+                .unwrap_or(default.min_fragment_frequency)
+                .clamp(0.0, 1.0),
         };
-        if hi.abs() > lo.abs() {
-            log::warn!(
-                "Tolerances are applied to experimental masses, not theoretical: [{}, {}]",
-                lo,
-                hi
-            );
-        }
-        if lo > 0.0 {
-            log::warn!(
-                "The `left` tolerance should probably be negative, for example: [{}, {}]",
-                -lo,
-                hi.abs()
-            )
+        LibraryParameters {
+            format: value.format.unwrap_or_default(),
+            settings,
         }
-        if hi < 0.0 {
-            log::warn!(
-                "The `right` tolerance should probably be positive, for example: [{}, {}]",
-                -lo.abs(),
-                hi
-            )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct ProteinInferenceOptions {
+    spectrum_fdr: Option<f32>,
+    peptide_fdr: Option<f32>,
+}
+
+impl From<ProteinInferenceOptions> for ProteinInferenceSettings {
+    fn from(value: ProteinInferenceOptions) -> ProteinInferenceSettings {
+        let default = ProteinInferenceSettings::default();
+        ProteinInferenceSettings {
+            spectrum_fdr: value.spectrum_fdr.unwrap_or(default.spectrum_fdr),
+            peptide_fdr: value.peptide_fdr.unwrap_or(default.peptide_fdr),
         }
     }
+}
 
-    pub fn build(mut self) -> anyhow::Result<Search> {
-        let database = self.database.make_parameters();
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct TmtOptions {
+    level: Option<u8>,
+    sn: Option<bool>,
+    /// Tolerance for matching reporter ions
+    tolerance: Option<Tolerance>,
+    plexes: Option<Vec<TmtPlex>>,
+    /// Isotopic impurities of the reagents: for each channel, the distribution
+    /// of its reporter ion signal across all channels
+    impurities: Option<Vec<Vec<f32>>>,
+    /// PSMs with a lower precursor isolation purity are not rolled up to
+    /// proteins
+    min_isolation_purity: Option<f32>,
+    /// Normalization of reporter ion channels within each file
+    normalize: Option<Normalization>,
+}
 
-        Self::check_tolerances(&self.fragment_tol);
-        Self::check_tolerances(&self.precursor_tol);
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct TmtSettings {
+    pub level: u8,
+    pub sn: bool,
+    pub tolerance: Tolerance,
+    pub plexes: Vec<TmtPlex>,
+    pub impurities: Option<Vec<Vec<f32>>>,
+    pub min_isolation_purity: f32,
+    pub normalize: Option<Normalization>,
+}
 
-        if let Some(isotope_errors) = self.isotope_errors {
-            if isotope_errors.0 > isotope_errors.1 {
-                log::error!("Minimum isotope_error value greater than maximum! Typical usage: `isotope_errors: [-1, 3]`");
-                std::process::exit(1);
+/// A set of files (e.g. fractions) labeled within the same TMT plex
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, JsonSchema)]
+pub struct TmtPlex {
+    /// Name used as a column prefix in `tmt_proteins.tsv`
+    pub name: Option<String>,
+    /// Files belonging to this plex, as listed in `mzml_paths`, or file names
+    pub files: Vec<String>,
+    /// Channels (e.g. `tmt_131` or `131`) containing the reference/bridge sample
+    pub reference_channels: Vec<String>,
+}
+
+/// Experimental design for internal reference scaling across plexes
+#[derive(Clone, Debug, PartialEq)]
+pub struct TmtDesign {
+    /// Plex names, used as column prefixes
+    pub names: Vec<String>,
+    /// Plex of each file, indexed by `file_id`
+    pub plex_of_file: Vec<Option<usize>>,
+    /// Indices of the reference channels of each plex
+    pub reference_channels: Vec<Vec<usize>>,
+}
+
+impl TmtDesign {
+    fn resolve(
+        plexes: &[TmtPlex],
+        isobaric: &Isobaric,
+        mzml_paths: &[String],
+    ) -> anyhow::Result<Self> {
+        let headers = isobaric.headers();
+        let channel_names = isobaric.channel_names();
+        let filenames = mzml_paths
+            .iter()
+            .map(|s| {
+                s.parse::<CloudPath>()
+                    .ok()
+                    .and_then(|c| c.filename().map(|s| s.to_string()))
+                    .unwrap_or_else(|| s.clone())
+            })
+            .collect::<Vec<_>>();
+
+        let mut design = TmtDesign {
+            names: Vec::with_capacity(plexes.len()),
+            plex_of_file: vec![None; mzml_paths.len()],
+            reference_channels: Vec::with_capacity(plexes.len()),
+        };
+
+        for (idx, plex) in plexes.iter().enumerate() {
+            let name = plex
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("plex{}", idx + 1));
+            ensure!(
+                !plex.reference_channels.is_empty(),
+                "TMT plex `{name}` must have at least one reference channel"
+            );
+            let mut channels = Vec::with_capacity(plex.reference_channels.len());
+            for channel in &plex.reference_channels {
+                let channel_idx = headers
+                    .iter()
+                    .zip(&channel_names)
+                    .position(|(header, name)| header == channel || name == channel)
+                    .with_context(|| {
+                        format!("TMT plex `{name}`: unknown reference channel `{channel}`")
+                    })?;
+                channels.push(channel_idx);
             }
-        }
-        if let Some(charges) = self.precursor_charge {
-            if charges.0 > charges.1 {
-                log::error!(
-                    "Precursor charges should be specified [low, high], user provided: [{}, {}]",
-                    charges.0,
-                    charges.1
+
+            for file in &plex.files {
+                let file_id = mzml_paths
+                    .iter()
+                    .zip(&filenames)
+                    .position(|(path, filename)| path == file || filename == file)
+                    .with_context(|| {
+                        format!("TMT plex `{name}`: `{file}` is not listed in `mzml_paths`")
+                    })?;
+                ensure!(
+                    design.plex_of_file[file_id].is_none(),
+                    "`{file}` is assigned to more than one TMT plex"
                 );
-                std::process::exit(1);
+                design.plex_of_file[file_id] = Some(idx);
             }
-        }
+
+            design.names.push(name);
+            design.reference_channels.push(channels);
+        }
+        Ok(design)
+    }
+}
+
+impl From<TmtOptions> for TmtSettings {
+    fn from(value: TmtOptions) -> Self {
+        let default = Self::default();
+        Self {
+            level: value.level.unwrap_or(default.level),
+            sn: value.sn.unwrap_or(default.sn),
+            tolerance: value.tolerance.unwrap_or(default.tolerance),
+            plexes: value.plexes.unwrap_or(default.plexes),
+            impurities: value.impurities,
+            min_isolation_purity: value
+                .min_isolation_purity
+                .unwrap_or(default.min_isolation_purity),
+            normalize: value.normalize,
+        }
+    }
+}
+
+impl Default for TmtSettings {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            sn: false,
+            tolerance: Tolerance::Ppm(-20.0, 20.0),
+            plexes: Vec::new(),
+            impurities: None,
+            min_isolation_purity: 0.0,
+            normalize: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum LinkerOption {
+    /// Name of a linker preset: "DSS", "BS3", "DSSO" or "DSBU"
+    Preset(String),
+    Custom(Linker),
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct CrosslinkOptions {
+    linker: Option<LinkerOption>,
+    alpha_candidates: Option<usize>,
+    min_matched_peaks: Option<u16>,
+}
+
+impl TryFrom<CrosslinkOptions> for CrosslinkSettings {
+    type Error = anyhow::Error;
+
+    fn try_from(value: CrosslinkOptions) -> anyhow::Result<Self> {
+        let default = CrosslinkSettings::default();
+        let linker = match value.linker {
+            Some(LinkerOption::Preset(name)) => Linker::preset(&name).with_context(|| {
+                format!(
+                    "unknown cross-linker `{}`, expected one of DSS, BS3, DSSO, DSBU",
+                    name
+                )
+            })?,
+            Some(LinkerOption::Custom(linker)) => linker,
+            None => default.linker,
+        };
+        ensure!(
+            !linker.residues.is_empty(),
+            "cross-linker `{}` must react with at least one residue",
+            linker.name
+        );
+        Ok(CrosslinkSettings {
+            linker,
+            alpha_candidates: value
+                .alpha_candidates
+                .unwrap_or(default.alpha_candidates)
+                .max(1),
+            min_matched_peaks: value.min_matched_peaks.unwrap_or(default.min_matched_peaks),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct GlycoOptions {
+    mode: GlycoMode,
+    /// Glycan compositions, e.g. `HexNAc(4)Hex(5)Fuc(1)`
+    glycans: Option<Vec<String>>,
+    oxonium_ions: Option<Vec<f32>>,
+    min_oxonium_ions: Option<usize>,
+}
+
+impl TryFrom<GlycoOptions> for GlycoSettings {
+    type Error = anyhow::Error;
+
+    fn try_from(value: GlycoOptions) -> anyhow::Result<Self> {
+        let default = GlycoSettings::new(value.mode);
+        let glycans = match value.glycans {
+            Some(glycans) => glycans
+                .iter()
+                .map(|s| s.parse::<Glycan>())
+                .collect::<Result<Vec<_>, _>>()?,
+            None => default.glycans,
+        };
+        ensure!(!glycans.is_empty(), "`glyco.glycans` must not be empty");
+        Ok(GlycoSettings {
+            glycans,
+            oxonium_ions: value.oxonium_ions.unwrap_or(default.oxonium_ions),
+            min_oxonium_ions: value.min_oxonium_ions.unwrap_or(default.min_oxonium_ions),
+            ..default
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct PrmOptions {
+    /// Target peptides, optionally with a charge state, e.g. `PEPTIDEK/2`
+    targets: Vec<String>,
+    /// Path to a spectral library (`library.sage.tsv`) with reference spectra
+    library: Option<String>,
+    fragments: Option<usize>,
+    min_fragments: Option<usize>,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct PrmParameters {
+    pub targets: Vec<String>,
+    pub library: Option<String>,
+    #[serde(flatten)]
+    pub settings: PrmSettings,
+}
+
+impl From<PrmOptions> for PrmParameters {
+    fn from(value: PrmOptions) -> Self {
+        let default = PrmSettings::default();
+        PrmParameters {
+            targets: value.targets,
+            library: value.library,
+            settings: PrmSettings {
+                fragments: value.fragments.unwrap_or(default.fragments).max(1),
+                min_fragments: value.min_fragments.unwrap_or(default.min_fragments),
+            },
+        }
+    }
+}
+
+impl PrmParameters {
+    /// Split a target into its peptide and (optional) charge state
+    pub fn parse_target(target: &str) -> anyhow::Result<(&str, Option<u8>)> {
+        match target.rsplit_once('/') {
+            Some((peptide, charge)) => {
+                let charge = charge
+                    .parse::<u8>()
+                    .with_context(|| format!("invalid charge in PRM target `{}`", target))?;
+                Ok((peptide, Some(charge)))
+            }
+            None => Ok((target, None)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+pub struct LibrarySearchOptions {
+    /// Path to a spectral library (`library.sage.tsv`)
+    path: String,
+    fragments: Option<usize>,
+    min_matched_peaks: Option<u16>,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct LibrarySearchParameters {
+    pub path: String,
+    #[serde(flatten)]
+    pub settings: LibrarySearchSettings,
+}
+
+impl From<LibrarySearchOptions> for LibrarySearchParameters {
+    fn from(value: LibrarySearchOptions) -> Self {
+        let default = LibrarySearchSettings::default();
+        LibrarySearchParameters {
+            path: value.path,
+            settings: LibrarySearchSettings {
+                fragments: value.fragments.unwrap_or(default.fragments).max(1),
+                min_matched_peaks: value.min_matched_peaks.unwrap_or(default.min_matched_peaks),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Default, JsonSchema)]
+pub struct CascadeOptions {
+    /// Additional FASTA file(s), only searched in the second pass
+    fasta: Option<FastaPaths>,
+    semi_enzymatic: Option<bool>,
+    missed_cleavages: Option<u8>,
+    /// Additional variable modifications, only searched in the second pass
+    variable_mods: Option<HashMap<String, ValueOrVec>>,
+    max_variable_mods: Option<usize>,
+    precursor_tol: Option<Tolerance>,
+    open_search: Option<bool>,
+    /// Spectra with a first-pass target PSM at or below this q-value are not
+    /// searched again
+    q_value: Option<f32>,
+    /// Restrict the second-pass database to the proteins identified in the
+    /// first pass
+    focused: Option<bool>,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct CascadeParameters {
+    /// Database searched in the second pass: the first-pass database, extended
+    /// with additional FASTA files, digestion rules or modifications
+    pub database: Parameters,
+    pub precursor_tol: Tolerance,
+    pub open_search: bool,
+    pub q_value: f32,
+    pub focused: bool,
+}
+
+impl CascadeParameters {
+    /// Extend the first-pass search parameters. Every peptide of the
+    /// first-pass database is also contained in the second-pass database
+    fn new(value: CascadeOptions, search: &Parameters, precursor_tol: Tolerance) -> Self {
+        let mut database = search.clone();
+        if let Some(fasta) = value.fasta {
+            database.fasta.extend(Vec::<String>::from(fasta));
+        }
+        if value.semi_enzymatic.unwrap_or(false) {
+            database.enzyme.semi_enzymatic = Some(true);
+        }
+        if let Some(missed_cleavages) = value.missed_cleavages {
+            let current = database.enzyme.missed_cleavages.unwrap_or(1);
+            database.enzyme.missed_cleavages = Some(missed_cleavages.max(current));
+        }
+        for (specificity, masses) in validate_var_mods(value.variable_mods) {
+            let existing = database.variable_mods.entry(specificity).or_default();
+            for mass in masses {
+                if !existing.contains(&mass) {
+                    existing.push(mass);
+                }
+            }
+        }
+        if let Some(max_variable_mods) = value.max_variable_mods {
+            database.max_variable_mods = database.max_variable_mods.max(max_variable_mods);
+        }
+        database.max_index_memory_mb = None;
+        database.prebuilt_index = None;
+
+        CascadeParameters {
+            database,
+            precursor_tol: value.precursor_tol.unwrap_or(precursor_tol),
+            open_search: value.open_search.unwrap_or(false),
+            q_value: value.q_value.unwrap_or(0.01),
+            focused: value.focused.unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, JsonSchema)]
+pub struct QuantOptions {
+    pub tmt: Option<Isobaric>,
+    #[serde(rename = "tmt_settings")]
+    pub tmt_options: Option<TmtOptions>,
+
+    pub lfq: Option<bool>,
+    #[serde(rename = "lfq_settings")]
+    pub lfq_options: Option<LfqOptions>,
+
+    pub silac: Option<SilacOptions>,
+
+    pub protein_rollup: Option<ProteinRollup>,
+}
+
+#[derive(Serialize, Default, Clone, JsonSchema)]
+pub struct QuantSettings {
+    pub tmt: Option<Isobaric>,
+    pub tmt_settings: TmtSettings,
+    pub lfq: bool,
+    pub lfq_settings: LfqSettings,
+    pub silac: Option<SilacSettings>,
+    /// Summarization of peptide quantities to proteins. If set, protein-level
+    /// label-free intensities are reported, and TMT proteins are summarized
+    /// with this method instead of summing PSMs
+    pub protein_rollup: Option<ProteinRollup>,
+}
+
+impl From<QuantOptions> for QuantSettings {
+    fn from(value: QuantOptions) -> Self {
+        Self {
+            tmt: value.tmt,
+            tmt_settings: value.tmt_options.map(Into::into).unwrap_or_default(),
+
+            lfq: value.lfq.unwrap_or(false),
+            lfq_settings: value.lfq_options.map(Into::into).unwrap_or_default(),
+            silac: value.silac.map(Into::into),
+            protein_rollup: value.protein_rollup,
+        }
+    }
+}
+
+impl Input {
+    pub fn from_arguments(matches: ArgMatches) -> anyhow::Result<Self> {
+        Self::from_matches(matches, true, true)
+    }
+
+    /// Parameters of `sage library`, a search that always builds a spectral
+    /// library: with the `library` settings of the configuration file if
+    /// present (or the defaults), in the `--format` given on the command line
+    pub fn library_from_arguments(matches: ArgMatches) -> anyhow::Result<Self> {
+        let format = match matches.get_one::<String>("format").map(String::as_str) {
+            Some("dia") => Some(LibraryFormat::Dia),
+            Some(_) => Some(LibraryFormat::Sage),
+            None => None,
+        };
+        let mut input = Self::from_matches(matches, true, true)?;
+        let library = input.library.get_or_insert_with(Default::default);
+        if format.is_some() {
+            library.format = format;
+        }
+        Ok(input)
+    }
+
+    /// Parameters of `sage serve`, which scores spectra sent by other
+    /// programs rather than reading spectra files: `mzml_paths` is ignored
+    pub fn serve_from_arguments(matches: ArgMatches) -> anyhow::Result<Self> {
+        Self::from_matches(matches, false, true)
+    }
+
+    /// Parameters of `sage features`, which reads spectra files without
+    /// searching them: `database.fasta` is ignored
+    pub fn features_from_arguments(matches: ArgMatches) -> anyhow::Result<Self> {
+        Self::from_matches(matches, true, false)
+    }
+
+    fn from_matches(matches: ArgMatches, read_spectra: bool, search: bool) -> anyhow::Result<Self> {
+        let path = matches
+            .get_one::<String>("parameters")
+            .expect("required parameters");
+        let mut input = Input::load(path)
+            .with_context(|| format!("Failed to read parameters from `{path}`"))?;
+
+        // Handle JSON configuration overrides
+        if let Some(output_directory) = matches.get_one::<String>("output_directory") {
+            input.output_directory = Some(output_directory.into());
+        }
+        if let Some(fasta) = matches.get_one::<String>("fasta") {
+            input.database.fasta = Some(fasta.as_str().into());
+        }
+        if let Some(precursor_tol) = matches.get_one::<Tolerance>("precursor-tol") {
+            input.precursor_tol = *precursor_tol;
+        }
+        if let Some(fragment_tol) = matches.get_one::<Tolerance>("fragment-tol") {
+            input.fragment_tol = *fragment_tol;
+        }
+        if let Some(mzml_paths) = matches.get_many::<String>("mzml_paths") {
+            input.mzml_paths = Some(
+                mzml_paths
+                    .into_iter()
+                    .map(|p| MzmlPath::Path(p.into()))
+                    .collect(),
+            );
+        }
+
+        if let Some(threads) = matches.get_one::<u16>("threads") {
+            input.threads = Some(*threads as usize);
+        }
+        if let Some(batch_size) = matches.get_one::<u16>("batch-size") {
+            input.batch_size = Some(*batch_size as usize);
+        }
+        if let Some(true) = matches.get_one::<bool>("checkpoint").copied() {
+            input.checkpoint = Some(true);
+        }
+        if matches.get_flag("gpu") {
+            input.gpu = Some(true);
+        }
+
+        if let Some(write_pin) = matches.get_one::<bool>("write-pin").copied() {
+            input.write_pin = Some(write_pin);
+        }
+
+        if let Some(write_flashlfq) = matches.get_one::<bool>("write-flashlfq").copied() {
+            input.write_flashlfq = Some(write_flashlfq);
+        }
+
+        if let Some(write_msstats) = matches.get_one::<bool>("write-msstats").copied() {
+            input.write_msstats = Some(write_msstats);
+        }
+
+        if let Some(write_sqlite) = matches.get_one::<bool>("write-sqlite").copied() {
+            input.write_sqlite = Some(write_sqlite);
+        }
+
+        if let Some(annotate_matches) = matches.get_one::<bool>("annotate-matches").copied() {
+            input.annotate_matches = Some(annotate_matches);
+        }
+
+        if let Some(output_template) = matches.get_one::<String>("output-template") {
+            input.output_template = Some(output_template.clone());
+        }
+
+        if matches.get_flag("diagnostics") {
+            input.diagnostics = Some(true);
+        }
+
+        // avoid to later panic if these parameters are not set (but doesn't check if files exist)
+
+        ensure!(
+            !search || input.database.fasta.is_some(),
+            "`database.fasta` must be set. For more information try '--help'"
+        );
+        if !search {
+            input.database.fasta = None;
+        }
+        if !read_spectra {
+            input.mzml_paths = Some(Vec::new());
+        }
+        ensure!(
+            !read_spectra
+                || input
+                    .mzml_paths
+                    .as_ref()
+                    .map(|p| p.len())
+                    .unwrap_or_default()
+                    > 0,
+            "`mzml_paths` must be set. For more information try '--help'"
+        );
+
+        // Expand glob patterns and directories, before checking for missing
+        // files, so that patterns matching no files are reported as missing
+        if let Some(mzml_paths) = input.mzml_paths.take() {
+            let mut expanded = Vec::with_capacity(mzml_paths.len());
+            for path in mzml_paths {
+                expanded.extend(path.expand()?);
+            }
+            input.mzml_paths = Some(expanded);
+        }
+
+        // Report every missing local input file before starting the search
+        let fasta: Vec<String> = input
+            .database
+            .fasta
+            .clone()
+            .map(Into::into)
+            .unwrap_or_default();
+        let spectra = input.mzml_paths.iter().flatten().map(MzmlPath::path);
+        let missing = fasta
+            .iter()
+            .map(String::as_str)
+            .chain(spectra)
+            .filter(|path| match path.parse::<CloudPath>() {
+                Ok(CloudPath::Local(path)) => !path.exists(),
+                _ => false,
+            })
+            .map(|path| format!("  - `{}`", path))
+            .collect::<Vec<_>>();
+        ensure!(
+            missing.is_empty(),
+            "input files not found:\n{}",
+            missing.join("\n")
+        );
+
+        Ok(input)
+    }
+
+    /// Database parameters of the configuration file passed to `sage index`
+    pub fn database_from_arguments(matches: &ArgMatches) -> anyhow::Result<Parameters> {
+        let path = matches
+            .get_one::<String>("parameters")
+            .expect("required parameters");
+        let mut input = Input::load(path)
+            .with_context(|| format!("Failed to read parameters from `{path}`"))?;
+        if let Some(fasta) = matches.get_one::<String>("fasta") {
+            input.database.fasta = Some(fasta.as_str().into());
+        }
+        ensure!(
+            input.database.fasta.is_some(),
+            "`database.fasta` must be set. For more information try '--help'"
+        );
+        let mut parameters = input.database.make_parameters();
+        parameters.tolerances = Some((input.precursor_tol, input.fragment_tol));
+        Ok(parameters)
+    }
+
+    /// Read a parameter file: TOML (`.toml`), YAML (`.yaml`, `.yml`) or
+    /// otherwise JSON. Keys that are not part of the configuration schema,
+    /// e.g. misspelled settings, are reported as errors
+    pub fn load<S: AsRef<str>>(path: S) -> anyhow::Result<Self> {
+        let contents = sage_cloudpath::util::read_string(path.as_ref())?;
+        Self::parse(path.as_ref(), &contents)
+    }
+
+    fn parse(path: &str, contents: &str) -> anyhow::Result<Self> {
+        let path = path.to_lowercase();
+        let value: serde_json::Value = if path.ends_with(".toml") {
+            toml::from_str(contents).context("invalid TOML")?
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(contents).context("invalid YAML")?
+        } else {
+            serde_json::from_str(&strip_comments(contents))?
+        };
+        let unknown = crate::schema::unknown_keys(&value);
+        ensure!(
+            unknown.is_empty(),
+            "unknown parameters (misspelled, or not supported by Sage {}):\n{}",
+            clap::crate_version!(),
+            unknown
+                .iter()
+                .map(|key| format!("  - {}", key))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn check_scan_window(window: &ScanWindow) -> anyhow::Result<()> {
+        if let Some((lo, hi)) = window.scan_range {
+            ensure!(
+                lo <= hi,
+                "`scan_range` must be [first, last], got [{}, {}]",
+                lo,
+                hi
+            );
+        }
+        if let Some((lo, hi)) = window.rt_range {
+            ensure!(
+                lo <= hi,
+                "`rt_range` must be [start, end] in minutes, got [{}, {}]",
+                lo,
+                hi
+            );
+        }
+        Ok(())
+    }
+
+    fn check_tolerances(tolerance: &Tolerance) {
+        let (lo, hi) = match tolerance {
+            Tolerance::Ppm(lo, hi) => (*lo, *hi),
+            Tolerance::Da(lo, hi) => (*lo, *hi),
+        };
+        if hi.abs() > lo.abs() {
+            log::warn!(
+                "Tolerances are applied to experimental masses, not theoretical: [{}, {}]",
+                lo,
+                hi
+            );
+        }
+        if lo > 0.0 {
+            log::warn!(
+                "The `left` tolerance should probably be negative, for example: [{}, {}]",
+                -lo,
+                hi.abs()
+            )
+        }
+        if hi < 0.0 {
+            log::warn!(
+                "The `right` tolerance should probably be positive, for example: [{}, {}]",
+                -lo.abs(),
+                hi
+            )
+        }
+    }
+
+    pub fn build(mut self) -> anyhow::Result<Search> {
+        let mut database = self.database.make_parameters();
+        database.tolerances = Some((self.precursor_tol, self.fragment_tol));
+
+        Self::check_tolerances(&self.fragment_tol);
+        Self::check_tolerances(&self.precursor_tol);
+
+        if let Some(isotope_errors) = self.isotope_errors {
+            ensure!(
+                isotope_errors.0 <= isotope_errors.1,
+                "Minimum isotope_error value greater than maximum! Typical usage: `isotope_errors: [-1, 3]`"
+            );
+        }
+        if let Some(charges) = self.precursor_charge {
+            ensure!(
+                charges.0 <= charges.1,
+                "Precursor charges should be specified [low, high], user provided: [{}, {}]",
+                charges.0,
+                charges.1
+            );
+        }
 
         if !self.predict_rt.unwrap_or(true)
             && self.quant.as_ref().and_then(|q| q.lfq).unwrap_or(false)
@@ -270,11 +1629,303 @@ impl Input {
             self.predict_rt = Some(true);
         }
 
-        let mzml_paths = self.mzml_paths.expect("'mzml_paths' must be provided!");
+        let threads = self.threads.unwrap_or_else(num_cpus::get);
+        ensure!(threads > 0, "`threads` must be at least 1");
+        let batch_size = self.batch_size.unwrap_or((threads / 2).max(1));
+        ensure!(batch_size > 0, "`batch_size` must be at least 1");
+        let gpu = self.gpu.unwrap_or(false);
+        ensure!(
+            !gpu || cfg!(feature = "gpu"),
+            "`gpu` is set, but Sage was built without the `gpu` feature: rebuild it with \
+             `--features gpu`"
+        );
+
+        let (mzml_paths, file_overrides): (Vec<_>, Vec<_>) = self
+            .mzml_paths
+            .context("`mzml_paths` must be set")?
+            .into_iter()
+            .map(MzmlPath::split)
+            .unzip();
+        let scan_window = ScanWindow {
+            scan_range: self.scan_range,
+            rt_range: self.rt_range,
+        };
+        Self::check_scan_window(&scan_window)?;
+        for overrides in &file_overrides {
+            if let Some(tol) = &overrides.precursor_tol {
+                Self::check_tolerances(tol);
+            }
+            if let Some(tol) = &overrides.fragment_tol {
+                Self::check_tolerances(tol);
+            }
+            Self::check_scan_window(&ScanWindow {
+                scan_range: overrides.scan_range,
+                rt_range: overrides.rt_range,
+            })?;
+        }
+
+        let open_search = self.open_search.unwrap_or(false);
+        if open_search {
+            let (lo, hi) = self.precursor_tol.bounds(1000.0);
+            if hi - lo < 10.0 {
+                log::warn!(
+                    "`open_search` is enabled, but the precursor tolerance is narrow - \
+                     an open search typically uses e.g. `\"da\": [-500, 100]`"
+                );
+            }
+        }
+
+        // DIA spectra contain fragments of several co-isolated peptides: by
+        // default, report multiple PSMs per spectrum in wide-window mode
+        let wide_window = self.wide_window.unwrap_or(false);
+        let chimera = self.chimera.unwrap_or(wide_window);
+        let report_psms = self.report_psms.unwrap_or(if wide_window { 5 } else { 1 });
+        let min_fragment_coverage = self.min_fragment_coverage.unwrap_or(0.0);
+        ensure!(
+            (0.0..=1.0).contains(&min_fragment_coverage),
+            "`min_fragment_coverage` must be between 0 and 1"
+        );
+
+        // Unless overridden, SILAC pairs are detected using the labels of the
+        // paired search
+        let mut quant = self.quant;
+        if let Some(silac) = quant.as_mut().and_then(|quant| quant.silac.as_mut()) {
+            if silac.labels.is_none() && !database.silac_labels.is_empty() {
+                silac.labels = Some(database.silac_labels.clone());
+            }
+        }
+        let mut quant: QuantSettings = quant.map(Into::into).unwrap_or_default();
+
+        let precursor_charge = self.precursor_charge.unwrap_or((2, 4));
+        let precursor_guards = PrecursorGuards {
+            max_precursor_mass: self.max_precursor_mass,
+            max_precursor_charge: self.max_precursor_charge,
+            charge_filter: self.charge_filter.unwrap_or_default(),
+            precursor_charge,
+        };
+        if let Some(max) = precursor_guards.max_precursor_charge {
+            if max < precursor_charge.1 {
+                log::warn!(
+                    "`max_precursor_charge` ({}) is lower than the maximum of `precursor_charge` ({}): \
+                     spectra with a reported charge above {} are skipped",
+                    max,
+                    precursor_charge.1,
+                    max
+                );
+            }
+        }
+        if let Some(max) = precursor_guards.max_precursor_mass {
+            if max < database.peptide_max_mass {
+                log::warn!(
+                    "`max_precursor_mass` ({}) is lower than `database.peptide_max_mass` ({})",
+                    max,
+                    database.peptide_max_mass
+                );
+            }
+        }
+
+        let ms2_only = self.ms2_only.unwrap_or(false);
+        if ms2_only && quant.lfq {
+            log::warn!("`lfq: true` requires MS1 spectra, and is disabled by `ms2_only: true`");
+            quant.lfq = false;
+        }
+        if ms2_only && quant.silac.is_some() {
+            log::warn!("`quant.silac` requires MS1 spectra, and is disabled by `ms2_only: true`");
+            quant.silac = None;
+        }
+        let mut ms1_features: Option<FeatureFinder> =
+            self.ms1_features.map(TryInto::try_into).transpose()?;
+        if quant.lfq && matches!(quant.lfq_settings.integration, IntegrationStrategy::Feature) {
+            ms1_features.get_or_insert_with(FeatureFinder::default);
+        }
+        if ms2_only && ms1_features.is_some() {
+            log::warn!("`ms1_features` requires MS1 spectra, and is disabled by `ms2_only: true`");
+            ms1_features = None;
+        }
+        let peak_mask = match self.peak_mask {
+            Some(options) => {
+                let mut reporter_region = options.reporter_region;
+                if let Some((lo, hi)) = reporter_region {
+                    ensure!(lo < hi, "`peak_mask.reporter_region` must be `[min, max]`");
+                }
+                if reporter_region.is_some() && quant.tmt.is_some() && quant.tmt_settings.level == 2
+                {
+                    log::warn!(
+                        "`peak_mask.reporter_region` is ignored with MS2-level `quant.tmt`: \
+                         reporter ions are quantified, then removed before scoring"
+                    );
+                    reporter_region = None;
+                }
+                Some(PeakMask {
+                    reporter_region,
+                    precursor: options.precursor.unwrap_or(true),
+                    tolerance: self.fragment_tol,
+                })
+            }
+            None => None,
+        };
+        let mut refine_precursors = self.refine_precursors.unwrap_or(false);
+        if refine_precursors && (ms2_only || wide_window) {
+            log::warn!(
+                "`refine_precursors` requires MS1 spectra and narrow isolation windows, and is disabled by {}",
+                if ms2_only { "`ms2_only: true`" } else { "`wide_window: true`" }
+            );
+            refine_precursors = false;
+        }
+        let mut merge_spectra: Option<SpectrumMerging> =
+            self.merge_spectra.map(TryInto::try_into).transpose()?;
+        if merge_spectra.is_some() && (wide_window || quant.tmt.is_some()) {
+            log::warn!(
+                "`merge_spectra` requires narrow isolation windows, and is disabled by {}",
+                if wide_window {
+                    "`wide_window: true`"
+                } else {
+                    "`quant.tmt`"
+                }
+            );
+            merge_spectra = None;
+        }
+        let faims: FaimsSettings = self.faims.map(Into::into).unwrap_or_default();
+        if faims.split_quant
+            && quant.lfq
+            && matches!(quant.lfq_settings.integration, IntegrationStrategy::Feature)
+        {
+            log::warn!(
+                "`faims.split_quant` has no effect on `quant.lfq_settings.integration: \"Feature\"`"
+            );
+        }
+        let crosslink: Option<CrosslinkSettings> =
+            self.crosslink.map(TryInto::try_into).transpose()?;
+        let crosslink = match (crosslink, database.max_index_memory_mb) {
+            (Some(_), Some(_)) => {
+                log::warn!(
+                    "`crosslink` search is not supported with `database.max_index_memory_mb`, and is disabled"
+                );
+                None
+            }
+            (crosslink, _) => crosslink,
+        };
+        let glyco: Option<GlycoSettings> = self.glyco.map(TryInto::try_into).transpose()?;
+        let cascade = match (self.cascade, database.max_index_memory_mb) {
+            (Some(_), Some(_)) => {
+                log::warn!(
+                    "`cascade` search is not supported with `database.max_index_memory_mb`, and is disabled"
+                );
+                None
+            }
+            (cascade, _) => cascade
+                .map(|cascade| CascadeParameters::new(cascade, &database, self.precursor_tol)),
+        };
+        let library_search = match (self.library_search, database.max_index_memory_mb) {
+            (Some(_), Some(_)) => {
+                log::warn!(
+                    "`library_search` is not supported with `database.max_index_memory_mb`, and is disabled"
+                );
+                None
+            }
+            (library_search, _) => library_search.map(Into::into),
+        };
+        let glyco = match (glyco, &library_search) {
+            (Some(_), Some(_)) => {
+                log::warn!(
+                    "`glyco` search is not supported with `library_search`, and is disabled"
+                );
+                None
+            }
+            (glyco, _) => glyco,
+        };
+        if glyco.is_some() && wide_window {
+            log::warn!("`glyco` search assumes a single precursor per spectrum, and is not recommended with `wide_window`");
+        }
+        if let (Some(isobaric), Some(impurities)) = (&quant.tmt, &quant.tmt_settings.impurities) {
+            let channels = isobaric.reporter_masses().len();
+            let correction = ImpurityCorrection::new(impurities).context(
+                "`quant.tmt_settings.impurities` must be a square, invertible matrix of \
+                 non-negative values",
+            )?;
+            ensure!(
+                correction.channels() == channels,
+                "`quant.tmt_settings.impurities` has {} channels, but `quant.tmt` has {}",
+                correction.channels(),
+                channels
+            );
+        }
+        if let Some(path) = &self.predicted_intensities {
+            ensure!(
+                !is_intensity_model(path) || cfg!(feature = "onnx"),
+                "`predicted_intensities` is an ONNX model, but Sage was built without the `onnx` \
+                 feature: rebuild it with `--features onnx`, or provide predicted intensities as \
+                 a spectral library (`library.sage.tsv` format)"
+            );
+        }
+        let prediction_collision_energy = self.prediction_collision_energy.unwrap_or(30.0);
+        ensure!(
+            (0.0..=100.0).contains(&prediction_collision_energy),
+            "`prediction_collision_energy` must be between 0 and 100"
+        );
+        let ml: MlSettings = self.ml.map(Into::into).unwrap_or_default();
+        for name in &ml.exclude_features {
+            ensure!(
+                FEATURE_NAMES.contains(&name.as_str()),
+                "unknown rescoring feature `{}` in `ml.exclude_features`, expected one of: {}",
+                name,
+                FEATURE_NAMES.join(", ")
+            );
+        }
+        if let Some(pin_features) = &self.pin_features {
+            let valid = PIN_COLUMNS
+                .iter()
+                .filter(|column| !PIN_FIXED_COLUMNS.contains(column))
+                .copied()
+                .collect::<Vec<_>>();
+            for name in pin_features {
+                ensure!(
+                    valid.contains(&name.as_str()),
+                    "unknown feature column `{}` in `pin_features`, expected one of: {}",
+                    name,
+                    valid.join(", ")
+                );
+            }
+        }
+        let irt: Option<IrtSettings> = self.irt.map(Into::into);
+        if let Some(irt) = &irt {
+            ensure!(
+                irt.peptides.len() >= irt.min_peptides,
+                "`irt.peptides` lists {} reference peptides, but `irt.min_peptides` is {}",
+                irt.peptides.len(),
+                irt.min_peptides
+            );
+        }
+        let tmt_design = match (&quant.tmt, quant.tmt_settings.plexes.is_empty()) {
+            (_, true) => None,
+            (Some(isobaric), false) => Some(TmtDesign::resolve(
+                &quant.tmt_settings.plexes,
+                isobaric,
+                &mzml_paths,
+            )?),
+            (None, false) => {
+                log::warn!("`quant.tmt_settings.plexes` is set, but `quant.tmt` is not");
+                None
+            }
+        };
+        ensure!(
+            quant.tmt_settings.normalize != Some(Normalization::Reference) || tmt_design.is_some(),
+            "`quant.tmt_settings.normalize: \"reference\"` requires `quant.tmt_settings.plexes`"
+        );
+
+        let date = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let output_template = match &self.output_template {
+            Some(template) => resolve_output_template(template, &mzml_paths, &date)?,
+            None => "{name}".into(),
+        };
 
         let output_directory = match self.output_directory {
             Some(path) => {
                 let path = path.parse::<CloudPath>()?;
+                ensure!(
+                    !matches!(path, CloudPath::Gcs { .. } | CloudPath::Http(_)),
+                    "`output_directory` must be a local or S3 path, HTTP(S) and GCS paths are read-only"
+                );
                 if let CloudPath::Local(p) = &path {
                     std::fs::create_dir_all(p)?;
                 }
@@ -286,32 +1937,99 @@ impl Input {
         Ok(Search {
             version: clap::crate_version!().into(),
             database,
-            quant: self.quant.map(Into::into).unwrap_or_default(),
+            quant,
+            library: self.library.map(Into::into),
+            protein_inference: self.protein_inference.map(Into::into),
             mzml_paths,
+            file_overrides,
             output_directory,
             precursor_tol: self.precursor_tol,
             fragment_tol: self.fragment_tol,
-            report_psms: self.report_psms.unwrap_or(1),
+            report_psms,
             max_peaks: self.max_peaks.unwrap_or(150),
+            peak_filter: self.peak_filter.map(TryInto::try_into).transpose()?,
+            peak_mask,
+            quality_filter: self.quality_filter.map(Into::into).unwrap_or_default(),
+            merge_spectra,
+            ms1_features,
             min_peaks: self.min_peaks.unwrap_or(15),
             min_matched_peaks: self.min_matched_peaks.unwrap_or(4),
+            min_fragment_coverage,
             max_fragment_charge: self.max_fragment_charge,
             annotate_matches: self.annotate_matches.unwrap_or(false),
-            precursor_charge: self.precursor_charge.unwrap_or((2, 4)),
+            precursor_charge,
             isotope_errors: self.isotope_errors.unwrap_or((0, 0)),
+            isotope_error_mode: self.isotope_error_mode.unwrap_or_default(),
             deisotope: self.deisotope.unwrap_or(true),
-            chimera: self.chimera.unwrap_or(false),
-            wide_window: self.wide_window.unwrap_or(false),
+            deisotope_method: self.deisotope_method.unwrap_or_default(),
+            centroid: self.centroid.unwrap_or_default(),
+            chimera,
+            wide_window,
+            open_search,
+            neutral_losses: self.neutral_losses.unwrap_or_default(),
+            localize_mods: self.localize_mods.unwrap_or(true),
+            prefilter: self.prefilter.map(Into::into),
+            mobility_filter: self.mobility_filter.map(TryInto::try_into).transpose()?,
+            recalibration: self.recalibration.map(Into::into),
+            peak_cleanup: self.peak_cleanup.map(Into::into).unwrap_or_default(),
             predict_rt: self.predict_rt.unwrap_or(true),
+            rt_source: self.rt_source.unwrap_or_default(),
+            faims,
+            ml,
+            irt,
+            ms2_only,
+            refine_precursors,
+            ms1_tol: self.ms1_tol.unwrap_or(Tolerance::Ppm(-10.0, 10.0)),
+            precursor_guards,
+            scan_window,
             output_paths: Vec::new(),
+            tmt_design,
             write_pin: self.write_pin.unwrap_or(false),
+            write_flashlfq: self.write_flashlfq.unwrap_or(false),
+            write_msstats: self.write_msstats.unwrap_or(false),
+            write_sqlite: self.write_sqlite.unwrap_or(false),
+            diagnostics: self.diagnostics.unwrap_or(false),
+            output_template,
+            threads,
+            batch_size,
+            gpu,
+            checkpoint: self.checkpoint.unwrap_or(false),
+            export_fasta: self.export_fasta,
+            pin_features: self.pin_features,
+            crosslink,
+            glyco,
+            prm: self.prm.map(Into::into),
+            cascade,
+            library_search,
+            predicted_intensities: self.predicted_intensities,
+            prediction_collision_energy,
         })
     }
 }
 
 #[cfg(test)]
 mod test {
-    use sage_core::{database::EnzymeBuilder, enzyme::EnzymeParameters};
+    use super::{
+        resolve_output_template, strip_comments, CascadeOptions, CascadeParameters,
+        CrosslinkOptions, FileOverrides, GlycoOptions, Input, MzmlPath, PrmParameters, Search,
+        TmtDesign, TmtPlex, DEFAULT_CONFIG,
+    };
+    use anyhow::Context;
+    use sage_core::{
+        crosslink::CrosslinkSettings,
+        database::{Builder, EnzymeBuilder},
+        enzyme::EnzymeParameters,
+        faims::FaimsSettings,
+        glyco::{GlycoMode, GlycoSettings},
+        ion_series::Kind,
+        mass::Tolerance,
+        merge::SpectrumMerging,
+        modification::ModificationSpecificity,
+        ms1_features::FeatureFinder,
+        silac::{LabelScheme, SilacLabels},
+        spectrum::ScanWindow,
+        tmt::Isobaric,
+    };
 
     #[test]
     fn deserialize_enzyme_builder() -> Result<(), serde_json::Error> {
@@ -331,4 +2049,524 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn strip_config_comments() -> Result<(), serde_json::Error> {
+        let json =
+            "{\n  \"a\": \"http://x//y\", // comment\n  // \"b\": 1,\n  \"c\": \"\\\"//\"\n}";
+        let stripped = strip_comments(json);
+        assert_eq!(stripped.lines().count(), 5);
+        let value: serde_json::Value = serde_json::from_str(&stripped)?;
+        assert_eq!(
+            value,
+            serde_json::json!({ "a": "http://x//y", "c": "\"//" })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_config_matches_defaults() -> anyhow::Result<()> {
+        let template: serde_json::Value = serde_json::from_str(&strip_comments(DEFAULT_CONFIG))?;
+        assert_eq!(crate::schema::unknown_keys(&template), Vec::<String>::new());
+
+        let minimal = serde_json::json!({
+            "database": { "fasta": "proteins.fasta" },
+            "precursor_tol": { "ppm": [-10.0, 10.0] },
+            "fragment_tol": { "ppm": [-10.0, 10.0] },
+            "mzml_paths": ["sample.mzML"],
+        });
+        let template = serde_json::from_value::<Input>(template)?.build()?;
+        let minimal = serde_json::from_value::<Input>(minimal)?.build()?;
+        assert_eq!(
+            serde_json::to_value(template)?,
+            serde_json::to_value(minimal)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shipped_configs_are_valid() -> anyhow::Result<()> {
+        let tests = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../../tests");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(tests)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            // Other JSON test data, e.g. expected LFQ results
+            let contents = std::fs::read_to_string(&path)?;
+            if !serde_json::from_str::<serde_json::Value>(&contents)?.is_object() {
+                continue;
+            }
+            let path = path.to_string_lossy();
+            Input::parse(&path, &contents).with_context(|| format!("`{}`", path))?;
+            checked += 1;
+        }
+        assert!(checked >= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_config_formats() -> anyhow::Result<()> {
+        let json = Input::parse("config.json", include_str!("../../../tests/config.json"))?;
+        let toml = Input::parse(
+            "config.toml",
+            r#"
+            deisotope = true
+            chimera = false
+            max_fragment_charge = 1
+            report_psms = 1
+            isotope_errors = [-1, 3]
+            mzml_paths = ["tests/LQSRPAAPPAPGPGQLTLR.mzML"]
+            precursor_tol = { ppm = [-50, 50] }
+            fragment_tol = { ppm = [-10, 10] }
+
+            # Comments are allowed
+            [database]
+            bucket_size = 16384
+            fragment_min_mz = 150.0
+            fragment_max_mz = 1500.0
+            static_mods = { C = 57.0216 }
+            decoy_tag = "rev_"
+            generate_decoys = true
+            fasta = "tests/Q99536.fasta"
+
+            [database.enzyme]
+            missed_cleavages = 1
+            cleave_at = "KR"
+            restrict = "P"
+            "#,
+        )?;
+        let yaml = Input::parse(
+            "config.YML",
+            r#"
+            database:
+              bucket_size: 16384
+              fragment_min_mz: 150.0
+              fragment_max_mz: 1500.0
+              enzyme: { missed_cleavages: 1, cleave_at: KR, restrict: P }
+              static_mods:
+                C: 57.0216 # carbamidomethylation
+              decoy_tag: rev_
+              generate_decoys: true
+              fasta: tests/Q99536.fasta
+            deisotope: true
+            chimera: false
+            max_fragment_charge: 1
+            report_psms: 1
+            precursor_tol: { ppm: [-50, 50] }
+            fragment_tol: { ppm: [-10, 10] }
+            isotope_errors: [-1, 3]
+            mzml_paths:
+              - tests/LQSRPAAPPAPGPGQLTLR.mzML
+            "#,
+        )?;
+
+        let json = serde_json::to_value(json.build()?)?;
+        assert_eq!(serde_json::to_value(toml.build()?)?, json);
+        assert_eq!(serde_json::to_value(yaml.build()?)?, json);
+
+        let typo = Input::parse("config.yaml", "databse: { fasta: a.fasta }")
+            .err()
+            .expect("unknown key");
+        assert!(typo.to_string().contains("(did you mean `database`?)"));
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_fragment_ions() -> Result<(), serde_json::Error> {
+        let builder: Builder = serde_json::from_value(serde_json::json!({
+            "fragment_ions": ["b", "y", "c", "z"],
+        }))?;
+        assert_eq!(
+            builder.ion_kinds,
+            Some(vec![Kind::B, Kind::Y, Kind::C, Kind::Z])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_silac_labels() -> Result<(), serde_json::Error> {
+        let builder: Builder = serde_json::from_value(serde_json::json!({
+            "silac_labels": "K8R10",
+        }))?;
+        assert_eq!(
+            builder.silac_labels,
+            Some(SilacLabels::Scheme(LabelScheme::Lys8Arg10))
+        );
+
+        let builder: Builder = serde_json::from_value(serde_json::json!({
+            "silac_labels": { "K": 6.020129 },
+            "fasta": "none",
+        }))?;
+        let labels = builder.make_parameters().silac_labels;
+        assert_eq!(labels, [('K', 6.020129)].into_iter().collect());
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_crosslink_linker() -> anyhow::Result<()> {
+        let options: CrosslinkOptions = serde_json::from_value(serde_json::json!({
+            "linker": "dsso",
+        }))?;
+        let settings: CrosslinkSettings = options.try_into()?;
+        assert_eq!(settings.linker.name, "DSSO");
+        assert_eq!(settings.linker.stubs.len(), 3);
+
+        let options: CrosslinkOptions = serde_json::from_value(serde_json::json!({
+            "linker": { "name": "EDC", "mass": -18.010565, "residues": "KDE" },
+            "alpha_candidates": 10,
+        }))?;
+        let settings: CrosslinkSettings = options.try_into()?;
+        assert_eq!(settings.linker.residues, "KDE");
+        assert!(settings.linker.stubs.is_empty());
+        assert_eq!(settings.alpha_candidates, 10);
+
+        let options: CrosslinkOptions = serde_json::from_value(serde_json::json!({
+            "linker": "foo",
+        }))?;
+        assert!(CrosslinkSettings::try_from(options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_glyco_options() -> anyhow::Result<()> {
+        let options: GlycoOptions = serde_json::from_value(serde_json::json!({
+            "mode": "o",
+        }))?;
+        let settings: GlycoSettings = options.try_into()?;
+        assert_eq!(settings.mode, GlycoMode::O);
+        assert_eq!(settings.glycans, GlycoMode::O.glycans());
+
+        let options: GlycoOptions = serde_json::from_value(serde_json::json!({
+            "mode": "n",
+            "glycans": ["HexNAc(2)Hex(9)", "HexNAc(4)Hex(5)NeuGc(1)"],
+            "min_oxonium_ions": 3,
+        }))?;
+        let settings: GlycoSettings = options.try_into()?;
+        assert_eq!(settings.glycans.len(), 2);
+        assert_eq!(settings.min_oxonium_ions, 3);
+
+        let options: GlycoOptions = serde_json::from_value(serde_json::json!({
+            "mode": "n",
+            "glycans": ["HexNAc(2)Man(9)"],
+        }))?;
+        assert!(GlycoSettings::try_from(options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn ms1_feature_options() -> anyhow::Result<()> {
+        let search = |extra: serde_json::Value| -> anyhow::Result<Search> {
+            let mut input = serde_json::json!({
+                "database": { "fasta": "proteins.fasta" },
+                "precursor_tol": { "ppm": [-10.0, 10.0] },
+                "fragment_tol": { "ppm": [-10.0, 10.0] },
+                "mzml_paths": ["sample.mzML"],
+            });
+            input
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value::<Input>(input)?.build()
+        };
+
+        assert_eq!(search(serde_json::json!({}))?.ms1_features, None);
+        let finder = search(serde_json::json!({ "ms1_features": { "min_scans": 5 } }))?
+            .ms1_features
+            .unwrap();
+        assert_eq!(finder.min_scans, 5);
+        assert_eq!(finder.max_charge, FeatureFinder::default().max_charge);
+        assert!(search(serde_json::json!({ "ms1_features": { "min_charge": 5 } })).is_err());
+
+        // Feature-based LFQ detects features with the default settings
+        let lfq = serde_json::json!({
+            "quant": { "lfq": true, "lfq_settings": { "integration": "Feature" } },
+        });
+        assert_eq!(
+            search(lfq.clone())?.ms1_features,
+            Some(FeatureFinder::default())
+        );
+        let mut ms2_only = lfq;
+        ms2_only["ms2_only"] = true.into();
+        assert_eq!(search(ms2_only)?.ms1_features, None);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_spectra_options() -> anyhow::Result<()> {
+        let search = |extra: serde_json::Value| -> anyhow::Result<Search> {
+            let mut input = serde_json::json!({
+                "database": { "fasta": "proteins.fasta" },
+                "precursor_tol": { "ppm": [-10.0, 10.0] },
+                "fragment_tol": { "ppm": [-10.0, 10.0] },
+                "mzml_paths": ["sample.mzML"],
+            });
+            input
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value::<Input>(input)?.build()
+        };
+
+        let merging = search(serde_json::json!({ "merge_spectra": { "rt_window": 1.0 } }))?
+            .merge_spectra
+            .unwrap();
+        assert_eq!(merging.rt_window, 1.0);
+        assert_eq!(
+            merging.fragment_ppm,
+            SpectrumMerging::default().fragment_ppm
+        );
+        assert!(search(serde_json::json!({ "merge_spectra": { "rt_window": 0.0 } })).is_err());
+        // DIA spectra are never merged
+        let dia = serde_json::json!({ "merge_spectra": {}, "wide_window": true });
+        assert_eq!(search(dia)?.merge_spectra, None);
+        Ok(())
+    }
+
+    #[test]
+    fn faims_options() -> anyhow::Result<()> {
+        let search = |faims: Option<serde_json::Value>| -> anyhow::Result<Search> {
+            let mut input = serde_json::json!({
+                "database": { "fasta": "proteins.fasta" },
+                "precursor_tol": { "ppm": [-10.0, 10.0] },
+                "fragment_tol": { "ppm": [-10.0, 10.0] },
+                "mzml_paths": ["sample.mzML"],
+            });
+            if let Some(faims) = faims {
+                input["faims"] = faims;
+            }
+            serde_json::from_value::<Input>(input)?.build()
+        };
+
+        assert_eq!(search(None)?.faims, FaimsSettings::default());
+        let faims = search(Some(
+            serde_json::json!({ "split_rt": false, "split_output": true }),
+        ))?;
+        assert_eq!(
+            faims.faims,
+            FaimsSettings {
+                split_rt: false,
+                split_quant: false,
+                split_output: true
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn scan_window_overrides() -> anyhow::Result<()> {
+        let input = |rt_range: serde_json::Value| -> anyhow::Result<Search> {
+            serde_json::from_value::<Input>(serde_json::json!({
+                "database": { "fasta": "proteins.fasta" },
+                "precursor_tol": { "ppm": [-10.0, 10.0] },
+                "fragment_tol": { "ppm": [-10.0, 10.0] },
+                "scan_range": [1000, 5000],
+                "rt_range": rt_range,
+                "mzml_paths": [
+                    "a.mzML",
+                    { "path": "b.mzML", "rt_range": [60.0, 90.0] },
+                ],
+            }))?
+            .build()
+        };
+
+        let search = input(serde_json::json!([30.0, 45.0]))?;
+        assert_eq!(
+            search.scan_window(0),
+            ScanWindow {
+                scan_range: Some((1000, 5000)),
+                rt_range: Some((30.0, 45.0)),
+            }
+        );
+        assert_eq!(
+            search.scan_window(1),
+            ScanWindow {
+                scan_range: Some((1000, 5000)),
+                rt_range: Some((60.0, 90.0)),
+            }
+        );
+        assert_eq!(
+            input(serde_json::Value::Null)?.scan_window(0).rt_range,
+            None
+        );
+        assert!(input(serde_json::json!([45.0, 30.0])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cascade_extends_database() -> anyhow::Result<()> {
+        let builder: Builder = serde_json::from_value(serde_json::json!({
+            "fasta": "human.fasta",
+            "enzyme": { "missed_cleavages": 2 },
+            "variable_mods": { "M": [15.9949] },
+        }))?;
+        let search = builder.make_parameters();
+
+        let options: CascadeOptions = serde_json::from_value(serde_json::json!({
+            "fasta": ["contaminants.fasta", "variants.fasta"],
+            "semi_enzymatic": true,
+            "missed_cleavages": 1,
+            "variable_mods": { "M": [15.9949], "S": [79.96633] },
+        }))?;
+        let cascade = CascadeParameters::new(options, &search, Tolerance::Ppm(-10.0, 10.0));
+        assert_eq!(
+            cascade.database.fasta,
+            vec!["human.fasta", "contaminants.fasta", "variants.fasta"]
+        );
+        assert_eq!(cascade.database.enzyme.semi_enzymatic, Some(true));
+        // The first-pass database must remain a subset of the second-pass one
+        assert_eq!(cascade.database.enzyme.missed_cleavages, Some(2));
+        let met = ModificationSpecificity::Residue(b'M');
+        let ser = ModificationSpecificity::Residue(b'S');
+        assert_eq!(cascade.database.variable_mods[&met], vec![15.9949]);
+        assert_eq!(cascade.database.variable_mods[&ser], vec![79.96633]);
+        assert_eq!(cascade.precursor_tol, Tolerance::Ppm(-10.0, 10.0));
+        assert!((cascade.q_value - 0.01).abs() < f32::EPSILON);
+        assert!(!cascade.focused);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_prm_targets() -> anyhow::Result<()> {
+        assert_eq!(
+            PrmParameters::parse_target("PEPTIDEK/2")?,
+            ("PEPTIDEK", Some(2))
+        );
+        assert_eq!(
+            PrmParameters::parse_target("PEPTIDEM[+15.9949]K")?,
+            ("PEPTIDEM[+15.9949]K", None)
+        );
+        assert!(PrmParameters::parse_target("PEPTIDEK/x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_mzml_paths() -> Result<(), serde_json::Error> {
+        let paths: Vec<MzmlPath> = serde_json::from_value(serde_json::json!([
+            "orbitrap.mzML",
+            { "path": "iontrap.mzML", "fragment_tol": { "da": [-0.5, 0.5] } },
+        ]))?;
+        let (paths, overrides): (Vec<_>, Vec<_>) = paths.into_iter().map(MzmlPath::split).unzip();
+
+        assert_eq!(paths, vec!["orbitrap.mzML", "iontrap.mzML"]);
+        assert_eq!(overrides[0], FileOverrides::default());
+        assert_eq!(
+            overrides[1],
+            FileOverrides {
+                fragment_tol: Some(Tolerance::Da(-0.5, 0.5)),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn expand_mzml_paths() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("sage-expand-mzml-paths");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub"))?;
+        std::fs::create_dir_all(dir.join("bruker.d"))?;
+        for file in [
+            "b.mzML",
+            "a.mgf.gz",
+            "notes.txt",
+            "sub/c.mzML",
+            "bruker.d/analysis.tdf",
+        ] {
+            std::fs::write(dir.join(file), "")?;
+        }
+        let expand = |path: String| -> anyhow::Result<Vec<String>> {
+            let paths = MzmlPath::Path(path).expand()?;
+            let dir = format!("{}/", dir.display());
+            Ok(paths.iter().map(|p| p.path().replace(&dir, "")).collect())
+        };
+        let root = dir.display().to_string();
+
+        assert_eq!(
+            expand(root.clone())?,
+            vec!["a.mgf.gz", "b.mzML", "bruker.d"]
+        );
+        assert_eq!(expand(format!("{}/*.mzML", root))?, vec!["b.mzML"]);
+        assert_eq!(
+            expand(format!("{}/**/*.mzML", root))?,
+            vec!["b.mzML", "sub/c.mzML"]
+        );
+        assert_eq!(
+            expand(format!("{}/**/*", root))?,
+            vec!["a.mgf.gz", "b.mzML", "bruker.d", "sub/c.mzML"]
+        );
+        // Unmatched patterns and other paths are unchanged
+        assert_eq!(expand(format!("{}/*.mzXML", root))?, vec!["*.mzXML"]);
+        assert_eq!(
+            expand("s3://bucket/*.mzML".into())?,
+            vec!["s3://bucket/*.mzML"]
+        );
+        assert_eq!(expand("bruker.d".into())?, vec!["bruker.d"]);
+
+        // Overrides apply to every expanded file
+        let overrides = FileOverrides {
+            fragment_tol: Some(Tolerance::Da(-0.5, 0.5)),
+            ..Default::default()
+        };
+        let paths = MzmlPath::WithOverrides {
+            path: format!("{}/sub", root),
+            overrides: overrides.clone(),
+        }
+        .expand()?;
+        let (paths, file_overrides): (Vec<_>, Vec<_>) =
+            paths.into_iter().map(MzmlPath::split).unzip();
+        assert_eq!(paths, vec![format!("{}/sub/c.mzML", root)]);
+        assert_eq!(file_overrides, vec![overrides]);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn output_templates() -> anyhow::Result<()> {
+        let one = vec!["/data/run_01.mzML.gz".to_string()];
+        let two = vec!["a.mzML".to_string(), "b.mzML".to_string()];
+        assert_eq!(
+            resolve_output_template("{stem}_{date}_{name}", &one, "2024-01-31")?,
+            "run_01_2024-01-31_{name}"
+        );
+        assert_eq!(
+            resolve_output_template("{date}.{name}", &two, "2024-01-31")?,
+            "2024-01-31.{name}"
+        );
+        assert!(resolve_output_template("{stem}_{name}", &two, "").is_err());
+        assert!(resolve_output_template("{stem}", &one, "").is_err());
+        assert!(resolve_output_template("out/{name}", &one, "").is_err());
+        assert!(resolve_output_template("{nmae}_{name}", &one, "").is_err());
+        assert!(resolve_output_template("{name", &one, "").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_tmt_design() -> anyhow::Result<()> {
+        let plexes: Vec<TmtPlex> = serde_json::from_value(serde_json::json!([
+            {"files": ["a1.mzML", "/data/a2.mzML"], "reference_channels": ["tmt_131"]},
+            {"name": "B", "files": ["b1.mzML"], "reference_channels": ["130C", "tmt_131"]}
+        ]))?;
+        let mzml_paths = [
+            "/data/a1.mzML",
+            "/data/a2.mzML",
+            "/data/b1.mzML",
+            "/data/c.mzML",
+        ]
+        .map(String::from);
+
+        let design = TmtDesign::resolve(&plexes, &Isobaric::Tmt10, &mzml_paths)?;
+        assert_eq!(design.names, vec!["plex1", "B"]);
+        assert_eq!(design.plex_of_file, vec![Some(0), Some(0), Some(1), None]);
+        assert_eq!(design.reference_channels, vec![vec![9], vec![8, 9]]);
+
+        // Channel does not exist in a TMT6 experiment
+        assert!(TmtDesign::resolve(&plexes, &Isobaric::Tmt6, &mzml_paths).is_err());
+        // File is not searched
+        assert!(TmtDesign::resolve(&plexes, &Isobaric::Tmt10, &mzml_paths[2..]).is_err());
+        Ok(())
+    }
 }
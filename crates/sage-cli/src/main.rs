@@ -1,31 +1,254 @@
 use anyhow::Context;
-use clap::{value_parser, Arg, Command, ValueHint};
-use input::{Input, Search};
+use checkpoint::Checkpoint;
+use clap::{value_parser, Arg, ArgMatches, Command, ValueHint};
+use error::FailedFile;
+use fnv::{FnvHashMap, FnvHashSet};
+use gpu::FragmentMatcher;
+use input::{CascadeParameters, Input, LibraryFormat, PrmParameters, Search};
 use log::info;
+use progress::Progress;
 use rayon::prelude::*;
 use sage_cloudpath::CloudPath;
-use sage_core::database::IndexedDatabase;
+use sage_core::crosslink::{CrosslinkMatch, CrosslinkScorer};
+use sage_core::database::{IndexedDatabase, Parameters, PeptideIx};
+use sage_core::fragment_prediction::PredictedSpectra;
+use sage_core::lfq::IntegrationStrategy;
+use sage_core::library_search::{LibraryScorer, LibrarySpectrum};
 use sage_core::mass::Tolerance;
-use sage_core::scoring::{Feature, Scorer};
-use sage_core::spectrum::{ProcessedSpectrum, SpectrumProcessor};
+use sage_core::ml::mobility_model::{MobilityFilter, MobilityModel};
+use sage_core::prefilter::SpectralIndex;
+use sage_core::prm::{PrmResult, PrmTarget};
+use sage_core::qc::{RunMetrics, RunMetricsBuilder};
+use sage_core::recalibration::{MassErrorModel, Recalibration, RecalibrationSettings};
+use sage_core::scoring::{merge_partitioned_features, Feature, Scorer};
+use sage_core::spectrum::{
+    ChargeFilter, PeakMask, PrecursorRefinement, ProcessedSpectrum, RawSpectrum, RefinedPrecursors,
+    RemovedPeaks, ScanWindow, SkippedPrecursors, SpectrumProcessor,
+};
 use sage_core::tmt::TmtQuant;
+use shard::Shard;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
+mod checkpoint;
+mod error;
+mod features;
+mod gpu;
 mod input;
 mod output;
+mod pin_merge;
+#[cfg(feature = "onnx")]
+mod prediction;
+mod progress;
+mod report;
+mod schema;
+mod serve;
+mod shard;
+mod sqlite;
 mod telemetry;
+mod test_data;
+mod watch;
+
+/// MS2 isolation windows wider than this (m/z) are assumed to come from DIA runs
+const DIA_ISOLATION_WIDTH: f32 = 4.0;
+
+/// Processed MS2 spectra waiting to be scored when files are searched as a
+/// pipeline, bounding memory use if parsing outpaces scoring
+const PIPELINE_CAPACITY: usize = 1024;
+
+/// Queued by the readers of a pipeline search: an MS2 spectrum to score (with
+/// its position in the file), or the MS1 spectra of a file once all of its MS2
+/// spectra are queued
+enum Queued {
+    Spectrum(usize, ProcessedSpectrum),
+    Read(usize, Vec<ProcessedSpectrum>),
+}
 
 struct Runner {
     database: IndexedDatabase,
+    /// Mass-partitioned slices of `database.peptides` to index & search
+    /// separately, if `database.max_index_memory_mb` is set
+    partitions: Option<Vec<Range<usize>>>,
+    /// Approximate nearest-neighbor prefilter over `database`, if enabled
+    prefilter: Option<SpectralIndex>,
+    /// Fragment index of `database` on the GPU, if enabled and one is found
+    gpu: Option<FragmentMatcher>,
+    /// Ion mobility filter of candidate peptides, if enabled
+    mobility_filter: Option<MobilityFilter>,
+    /// Target precursors of the targeted (PRM) extraction, if enabled
+    prm_targets: Vec<PrmTarget>,
+    /// Library spectra searched instead of `database`, if enabled
+    library_spectra: Vec<LibrarySpectrum>,
+    /// Predicted fragment intensities used for rescoring, if enabled
+    predicted_spectra: PredictedSpectra,
+    /// Model predicting the fragment intensities used for rescoring, if enabled
+    #[cfg(feature = "onnx")]
+    intensity_model: Option<prediction::IntensityModel>,
+    /// Mass recalibration of each file, once fit to the first-pass search
+    recalibration: Vec<Recalibration>,
+    /// Saved results of searched batches of files, if enabled
+    checkpoint: Option<Checkpoint>,
+    /// Combined results of a sharded search, used instead of searching
+    merged: Option<SageResults>,
     parameters: input::Search,
     start: Instant,
 }
 
+/// Scores MS2 spectra with the scorer of their file, and keeps track of the
+/// number of searched spectra
+struct SpectrumSearch<'a> {
+    parameters: &'a Search,
+    scorers: Vec<Scorer<'a>>,
+    library: Vec<LibraryScorer<'a>>,
+    /// Counts matched fragments of the searched database, if on the GPU
+    gpu: Option<&'a FragmentMatcher>,
+    searched: AtomicUsize,
+    skipped: AtomicUsize,
+    start: Instant,
+}
+
+impl<'a> SpectrumSearch<'a> {
+    fn new(runner: &'a Runner, scorer: &Scorer<'a>, gpu: Option<&'a FragmentMatcher>) -> Self {
+        let scorers = (0..runner.parameters.mzml_paths.len())
+            .map(|file_id| runner.file_scorer(scorer, file_id))
+            .collect::<Vec<_>>();
+        let library = match (
+            &runner.parameters.library_search,
+            runner.library_spectra.is_empty(),
+        ) {
+            (Some(library_search), false) => scorers
+                .iter()
+                .map(|scorer| LibraryScorer {
+                    db: scorer.db,
+                    spectra: &runner.library_spectra,
+                    settings: library_search.settings,
+                    precursor_tol: scorer.precursor_tol,
+                    fragment_tol: scorer.fragment_tol,
+                    min_precursor_charge: scorer.min_precursor_charge,
+                    max_precursor_charge: scorer.max_precursor_charge,
+                    report_psms: scorer.report_psms,
+                })
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+        // Library and glycopeptide searches are scored on the CPU
+        let gpu = gpu.filter(|gpu| {
+            library.is_empty() && runner.parameters.glyco.is_none() && gpu.matches(scorer.db)
+        });
+        SpectrumSearch {
+            parameters: &runner.parameters,
+            scorers,
+            library,
+            gpu,
+            searched: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Is `spec` an MS2 spectrum with enough peaks and candidate peptides?
+    fn keep(&self, spec: &ProcessedSpectrum) -> bool {
+        let min_peaks = self.parameters.file_overrides[spec.file_id]
+            .min_peaks
+            .unwrap_or(self.parameters.min_peaks);
+        if spec.peaks.len() < min_peaks || spec.level != 2 {
+            return false;
+        }
+
+        // Precursor-only prefilter: skip spectra without any candidate peptides.
+        // Glycopeptide precursors only match peptides after removing the glycan
+        let scorer = &self.scorers[spec.file_id];
+        let keep = scorer.has_candidates(spec)
+            || self
+                .parameters
+                .glyco
+                .as_ref()
+                .map_or(false, |glyco| glyco.is_glyco(spec, scorer.fragment_tol));
+        if !keep {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        let prev = self.searched.fetch_add(1, Ordering::Relaxed);
+        if prev > 0 && prev % 10_000 == 0 {
+            let duration = Instant::now().duration_since(self.start).as_millis() as usize;
+
+            let rate = prev * 1000 / (duration + 1);
+            log::trace!("- searched {} spectra ({} spectra/s)", prev, rate);
+        }
+        true
+    }
+
+    /// Score a spectrum, if it is an MS2 spectrum with enough peaks and
+    /// candidate peptides
+    fn score(&self, spec: &ProcessedSpectrum) -> Vec<Feature> {
+        if !self.keep(spec) {
+            return Vec::new();
+        }
+        let scorer = &self.scorers[spec.file_id];
+        match (self.library.get(spec.file_id), &self.parameters.glyco) {
+            (Some(library), _) => library.score(spec),
+            (None, Some(glyco)) => glyco.score(scorer, spec),
+            (None, None) => scorer.score(spec),
+        }
+    }
+
+    /// Score each of `spectra`, as [`SpectrumSearch::score`] does, counting
+    /// matched fragments of the spectra of each file together on the GPU
+    fn score_batch(&self, spectra: &[&ProcessedSpectrum]) -> Vec<Vec<Feature>> {
+        let gpu = match self.gpu {
+            Some(gpu) => gpu,
+            None => return spectra.par_iter().map(|spec| self.score(spec)).collect(),
+        };
+        let keep = spectra
+            .par_iter()
+            .map(|spec| self.keep(spec))
+            .collect::<Vec<_>>();
+        let mut files = FnvHashMap::<usize, Vec<usize>>::default();
+        for (idx, spec) in spectra.iter().enumerate().filter(|(idx, _)| keep[*idx]) {
+            files.entry(spec.file_id).or_default().push(idx);
+        }
+
+        let mut features = vec![Vec::new(); spectra.len()];
+        for (file_id, indices) in files {
+            let queries = indices.iter().map(|&idx| spectra[idx]).collect::<Vec<_>>();
+            let scored = gpu.score(&self.scorers[file_id], &queries);
+            for (idx, scored) in indices.into_iter().zip(scored) {
+                features[idx] = scored;
+            }
+        }
+        features
+    }
+
+    fn finish(self) {
+        let duration = Instant::now().duration_since(self.start).as_millis() as usize;
+        let searched = self.searched.into_inner();
+        let rate = searched * 1000 / (duration + 1);
+        log::info!("- search:  {:8} ms ({} spectra/s)", duration, rate);
+        log::info!(
+            "- prefilter: skipped {} spectra without candidate peptides",
+            self.skipped.into_inner()
+        );
+    }
+}
+
 #[derive(Default)]
 struct SageResults {
     ms1: Vec<ProcessedSpectrum>,
     features: Vec<Feature>,
     quant: Vec<TmtQuant>,
+    /// Best cross-linked peptide pair of each MS2 spectrum, if enabled
+    crosslinks: Vec<CrosslinkMatch>,
+    /// Fragment XIC extraction of each PRM target in each file
+    prm: Vec<PrmResult>,
+    /// MS2 spectra skipped by precursor mass/charge guards
+    skipped: SkippedPrecursors,
+    /// Spectrum quality metrics of each input file that was read
+    runs: Vec<RunMetrics>,
+    /// Input files that could not be read
+    failed: Vec<FailedFile>,
 }
 
 impl FromParallelIterator<SageResults> for SageResults {
@@ -38,7 +261,12 @@ impl FromParallelIterator<SageResults> for SageResults {
             .reduce(SageResults::default, |mut acc, x| {
                 acc.features.extend(x.features);
                 acc.quant.extend(x.quant);
+                acc.crosslinks.extend(x.crosslinks);
+                acc.prm.extend(x.prm);
                 acc.ms1.extend(x.ms1);
+                acc.skipped += x.skipped;
+                acc.runs.extend(x.runs);
+                acc.failed.extend(x.failed);
                 acc
             })
     }
@@ -54,199 +282,1364 @@ impl FromIterator<SageResults> for SageResults {
             .fold(SageResults::default(), |mut acc, x| {
                 acc.features.extend(x.features);
                 acc.quant.extend(x.quant);
+                acc.crosslinks.extend(x.crosslinks);
+                acc.prm.extend(x.prm);
                 acc.ms1.extend(x.ms1);
+                acc.skipped += x.skipped;
+                acc.runs.extend(x.runs);
+                acc.failed.extend(x.failed);
                 acc
             })
     }
 }
 
 impl Runner {
-    pub fn new(parameters: Search) -> anyhow::Result<Self> {
+    pub fn new(parameters: Search, checkpoint: Option<Checkpoint>) -> anyhow::Result<Self> {
         let start = Instant::now();
-        let fasta = sage_cloudpath::util::read_fasta(
-            &parameters.database.fasta,
-            &parameters.database.decoy_tag,
-            parameters.database.generate_decoys,
-        )
-        .with_context(|| {
-            format!(
-                "Failed to build database from `{}`",
-                parameters.database.fasta
-            )
-        })?;
-
-        let database = parameters.database.clone().build(fasta);
+        progress::stage("building database", start);
+        if let Some(limit) = parameters.database.max_index_memory_mb {
+            return Self::new_partitioned(parameters, limit, start);
+        }
+        let database = match (&parameters.database.prebuilt_index, &checkpoint) {
+            (Some(path), _) => Self::load_or_build_database(&parameters, path)?,
+            (None, Some(checkpoint)) => {
+                Self::load_or_build_database(&parameters, &checkpoint.index_path())?
+            }
+            (None, None) => Self::build_database(&parameters)?,
+        };
         info!(
             "generated {} fragments, {} peptides in {}ms",
             database.fragments.len(),
             database.peptides.len(),
             (Instant::now() - start).as_millis()
         );
+        let prefilter = Self::build_prefilter(&parameters, &database);
+        let mobility_filter = Self::mobility_filter(&parameters)?;
+        let prm_targets = Self::prm_targets(&parameters, &database)?;
+        let library_spectra = Self::library_spectra(&parameters, &database)?;
+        let gpu = match library_spectra.is_empty() {
+            true => Self::fragment_matcher(&parameters, &database),
+            false => None,
+        };
+        let predicted_spectra = Self::predicted_spectra(&parameters, &database)?;
+        #[cfg(feature = "onnx")]
+        let intensity_model = Self::intensity_model(&parameters)?;
+        Ok(Self {
+            database,
+            partitions: None,
+            prefilter,
+            gpu,
+            mobility_filter,
+            prm_targets,
+            library_spectra,
+            predicted_spectra,
+            #[cfg(feature = "onnx")]
+            intensity_model,
+            recalibration: Vec::new(),
+            checkpoint,
+            merged: None,
+            parameters,
+            start,
+        })
+    }
+
+    /// Digest the FASTA file, but defer building the fragment index: it will
+    /// be built in mass-partitioned slices of at most `limit` MiB during search
+    fn new_partitioned(parameters: Search, limit: usize, start: Instant) -> anyhow::Result<Self> {
+        if parameters.database.prebuilt_index.is_some() {
+            log::warn!(
+                "`database.prebuilt_index` is ignored when `database.max_index_memory_mb` is set"
+            );
+        }
+        let fasta = Self::read_fasta(&parameters)?;
+        let peptides = parameters.database.digest(&fasta);
+        drop(fasta);
+
+        let partitions = parameters
+            .database
+            .partition(&peptides, limit.saturating_mul(1024 * 1024));
+
+        // Peptide-only database, used for FDR and outputs - fragments are
+        // indexed separately for each partition during search
+        let mut database = parameters.database.build_from_peptides(Vec::new());
+        database.peptides = peptides;
+
+        info!(
+            "generated {} peptides in {}ms, split into {} partitions of <= {} MiB",
+            database.peptides.len(),
+            (Instant::now() - start).as_millis(),
+            partitions.len(),
+            limit
+        );
+        let mobility_filter = Self::mobility_filter(&parameters)?;
+        let prm_targets = Self::prm_targets(&parameters, &database)?;
+        let predicted_spectra = Self::predicted_spectra(&parameters, &database)?;
+        #[cfg(feature = "onnx")]
+        let intensity_model = Self::intensity_model(&parameters)?;
         Ok(Self {
             database,
+            partitions: Some(partitions),
+            prefilter: None,
+            gpu: None,
+            mobility_filter,
+            prm_targets,
+            library_spectra: Vec::new(),
+            predicted_spectra,
+            #[cfg(feature = "onnx")]
+            intensity_model,
+            recalibration: Vec::new(),
+            checkpoint: None,
+            merged: None,
             parameters,
             start,
         })
     }
 
+    /// Upload the fragment index of `database` to the GPU, if enabled.
+    /// Glycopeptide searches are scored on the CPU
+    fn fragment_matcher(
+        parameters: &Search,
+        database: &IndexedDatabase,
+    ) -> Option<FragmentMatcher> {
+        gpu::fragment_matcher(parameters.gpu && parameters.glyco.is_none(), database)
+    }
+
+    fn build_prefilter(parameters: &Search, database: &IndexedDatabase) -> Option<SpectralIndex> {
+        parameters.prefilter.map(|settings| {
+            let start = Instant::now();
+            let index = SpectralIndex::build(database, settings);
+            info!(
+                "built spectral prefilter ({} hashes) in {}ms",
+                settings.hashes,
+                (Instant::now() - start).as_millis()
+            );
+            index
+        })
+    }
+
+    /// Load the ion mobility model used to filter candidate peptides, if
+    /// enabled
+    fn mobility_filter(parameters: &Search) -> anyhow::Result<Option<MobilityFilter>> {
+        let settings = match &parameters.mobility_filter {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+        let contents = sage_cloudpath::util::read_string(&settings.model)
+            .with_context(|| format!("Failed to read ion mobility model `{}`", settings.model))?;
+        let model = serde_json::from_str::<MobilityModel>(&contents)
+            .with_context(|| format!("Failed to parse ion mobility model `{}`", settings.model))?;
+        info!(
+            "- mobility filter: scoring candidates within {} 1/K0 of their predicted ion mobility (model rsq = {})",
+            settings.tolerance, model.r2
+        );
+        Ok(Some(MobilityFilter {
+            model,
+            tolerance: settings.tolerance,
+        }))
+    }
+
+    /// Resolve the spectra of the spectral library against the database, and
+    /// generate decoy spectra, if spectral library search is enabled
+    fn library_spectra(
+        parameters: &Search,
+        database: &IndexedDatabase,
+    ) -> anyhow::Result<Vec<LibrarySpectrum>> {
+        let library_search = match &parameters.library_search {
+            Some(library_search) => library_search,
+            None => return Ok(Vec::new()),
+        };
+        let path = &library_search.path;
+        let contents = sage_cloudpath::util::read_string(path)
+            .with_context(|| format!("Failed to read spectral library `{}`", path))?;
+        let records = output::parse_library_records(&contents)
+            .with_context(|| format!("Failed to parse spectral library `{}`", path))?;
+        let total = records.len();
+        let (spectra, missing) =
+            sage_core::library_search::prepare(database, records, &library_search.settings);
+        if missing > 0 {
+            log::warn!(
+                "{} of {} spectral library precursors are not contained in the database, and are not searched",
+                missing,
+                total
+            );
+        }
+        anyhow::ensure!(
+            missing < total,
+            "no precursors of spectral library `{}` are contained in the database",
+            path
+        );
+        info!(
+            "resolved {} spectral library precursors ({} spectra, including decoys)",
+            total - missing,
+            spectra.len()
+        );
+        Ok(spectra)
+    }
+
+    /// Resolve predicted fragment intensities against the database, if
+    /// rescoring with predicted intensities is enabled
+    fn predicted_spectra(
+        parameters: &Search,
+        database: &IndexedDatabase,
+    ) -> anyhow::Result<PredictedSpectra> {
+        let path = match &parameters.predicted_intensities {
+            Some(path) if !input::is_intensity_model(path) => path,
+            _ => return Ok(PredictedSpectra::default()),
+        };
+        let contents = sage_cloudpath::util::read_string(path)
+            .with_context(|| format!("Failed to read predicted intensities `{}`", path))?;
+        let records = output::parse_library_records(&contents)
+            .with_context(|| format!("Failed to parse predicted intensities `{}`", path))?;
+        let total = records.len();
+        let (spectra, missing) = PredictedSpectra::new(database, records);
+        if missing > 0 {
+            log::warn!(
+                "{} of {} precursors with predicted intensities are not contained in the database",
+                missing,
+                total
+            );
+        }
+        info!(
+            "resolved predicted intensities of {} precursors ({} spectra, including decoys)",
+            total - missing,
+            spectra.len()
+        );
+        Ok(spectra)
+    }
+
+    /// Load the model that predicts fragment intensities, if rescoring with
+    /// predicted intensities is enabled, and they aren't read from a library
+    #[cfg(feature = "onnx")]
+    fn intensity_model(parameters: &Search) -> anyhow::Result<Option<prediction::IntensityModel>> {
+        let path = match &parameters.predicted_intensities {
+            Some(path) if input::is_intensity_model(path) => path,
+            _ => return Ok(None),
+        };
+        let model = prediction::IntensityModel::load(path, parameters.prediction_collision_energy)
+            .with_context(|| format!("Failed to load intensity prediction model `{}`", path))?;
+        info!("loaded intensity prediction model `{}`", path);
+        Ok(Some(model))
+    }
+
+    /// Resolve PRM targets against the spectral library, if provided, or the
+    /// theoretical fragments of the digested database
+    fn prm_targets(
+        parameters: &Search,
+        database: &IndexedDatabase,
+    ) -> anyhow::Result<Vec<PrmTarget>> {
+        let prm = match &parameters.prm {
+            Some(prm) => prm,
+            None => return Ok(Vec::new()),
+        };
+        let library = match &prm.library {
+            Some(path) => {
+                let contents = sage_cloudpath::util::read_string(path)
+                    .with_context(|| format!("Failed to read spectral library `{}`", path))?;
+                output::parse_library(&contents, prm.settings.fragments)
+                    .with_context(|| format!("Failed to parse spectral library `{}`", path))?
+            }
+            None => Vec::new(),
+        };
+
+        let mut targets = Vec::new();
+        for target in &prm.targets {
+            let (sequence, charge) = PrmParameters::parse_target(target)?;
+            let matches = library
+                .iter()
+                .filter(|t| t.peptide == sequence && charge.map_or(true, |c| c == t.charge))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !matches.is_empty() {
+                targets.extend(matches);
+                continue;
+            }
+
+            match database
+                .peptides
+                .iter()
+                .find(|p| !p.decoy && p.to_string() == sequence)
+            {
+                Some(peptide) => {
+                    let (lo, hi) = charge.map_or(parameters.precursor_charge, |c| (c, c));
+                    targets.extend((lo..=hi).map(|charge| {
+                        PrmTarget::theoretical(
+                            peptide,
+                            charge,
+                            &database.ion_kinds,
+                            database.residue_masses,
+                            (
+                                parameters.database.fragment_min_mz,
+                                parameters.database.fragment_max_mz,
+                            ),
+                            prm.settings.fragments,
+                        )
+                    }));
+                }
+                None => log::warn!(
+                    "PRM target `{}` was not found in the spectral library or the database, skipping",
+                    target
+                ),
+            }
+        }
+        info!(
+            "resolved {} PRM target precursors ({} from the spectral library)",
+            targets.len(),
+            targets.iter().filter(|t| t.library).count()
+        );
+        Ok(targets)
+    }
+
+    fn build_database(parameters: &Search) -> anyhow::Result<IndexedDatabase> {
+        let fasta = Self::read_fasta(parameters)?;
+        Ok(parameters.database.clone().build(fasta))
+    }
+
+    fn read_fasta(parameters: &Search) -> anyhow::Result<sage_core::fasta::Fasta> {
+        Self::read_fasta_paths(&parameters.database)
+    }
+
+    fn read_fasta_paths(database: &Parameters) -> anyhow::Result<sage_core::fasta::Fasta> {
+        let mut combined: Option<sage_core::fasta::Fasta> = None;
+        for path in &database.fasta {
+            let fasta = sage_cloudpath::util::read_fasta(
+                path,
+                &database.decoy_tag,
+                database.generate_decoys,
+            )
+            .with_context(|| format!("Failed to build database from `{}`", path))?;
+            info!("read {} proteins from {}", fasta.targets.len(), path);
+            match combined.as_mut() {
+                Some(combined) => combined.extend(fasta),
+                None => combined = Some(fasta),
+            }
+        }
+        combined.context("`database.fasta` must contain at least one path")
+    }
+
+    /// Load a fragment index saved by a previous search, or build it from the
+    /// FASTA file and save it for next time if it is missing or out of date
+    fn load_or_build_database(parameters: &Search, path: &str) -> anyhow::Result<IndexedDatabase> {
+        if std::path::Path::new(path).exists() {
+            // Mapped, so that concurrent searches share the fragments
+            let database = IndexedDatabase::map(path)
+                .with_context(|| format!("Failed to load prebuilt index from `{}`", path))?;
+            if database.fingerprint == parameters.database.fingerprint() {
+                info!(
+                    "loaded prebuilt fragment index from `{}`{}",
+                    path,
+                    if database.fragments.is_mapped() {
+                        " (memory-mapped)"
+                    } else {
+                        ""
+                    }
+                );
+                return Ok(database);
+            }
+            log::warn!(
+                "prebuilt index `{}` was built with different database parameters, rebuilding",
+                path
+            );
+        }
+
+        let database = Self::build_database(parameters)?;
+        database
+            .save(path)
+            .with_context(|| format!("Failed to save prebuilt index to `{}`", path))?;
+        info!("saved fragment index to `{}`", path);
+        Ok(database)
+    }
+
+    /// MS1-dependent steps (isolation purity, LFQ, SILAC pair detection) are
+    /// skipped if no MS1 spectra were read. This is expected for MS2-only
+    /// input, e.g. converted MGF files, and must be acknowledged with `ms2_only`
+    fn check_ms1(&self, runs: &[RunMetrics]) -> bool {
+        if !self.parameters.ms2_only && runs.iter().any(|run| run.ms1_spectra > 0) {
+            return true;
+        }
+        match self.parameters.ms2_only {
+            true => info!("- MS2-only input: skipping MS1-dependent steps"),
+            false => log::warn!(
+                "no MS1 spectra found: isolation purity, LFQ and SILAC pair detection are skipped. \
+                 Set `\"ms2_only\": true` to acknowledge MS2-only input files"
+            ),
+        }
+        false
+    }
+
     fn spectrum_fdr(&self, features: &mut [Feature]) -> usize {
-        if sage_core::ml::linear_discriminant::score_psms(features, self.parameters.precursor_tol)
-            .is_none()
+        if sage_core::ml::linear_discriminant::score_psms(
+            features,
+            self.parameters.precursor_tol,
+            &self.parameters.ml,
+        )
+        .is_none()
         {
-            log::warn!("linear model fitting failed, falling back to heuristic discriminant score");
+            if self.parameters.ml.model != sage_core::ml::RescoringModel::None {
+                log::warn!(
+                    "linear model fitting failed, falling back to heuristic discriminant score"
+                );
+            }
             features.par_iter_mut().for_each(|feat| {
                 feat.discriminant_score = (-feat.poisson as f32).ln_1p() + feat.longest_y_pct / 3.0
             });
+            sage_core::ml::pep::assign(features, self.parameters.ml.pep);
         }
         features.par_sort_unstable_by(|a, b| b.discriminant_score.total_cmp(&a.discriminant_score));
-        sage_core::ml::qvalue::spectrum_q_value(features)
+        if self.parameters.wide_window {
+            sage_core::ml::qvalue::window_q_value(features)
+        } else {
+            sage_core::ml::qvalue::spectrum_q_value(features)
+        }
+    }
+
+    /// Fit mass error models to the confident PSMs of the first-pass search.
+    /// Returns whether any file was recalibrated, and must be searched again
+    fn recalibrate(&mut self, features: &mut [Feature], settings: &RecalibrationSettings) -> bool {
+        self.spectrum_fdr(features);
+        let recalibration = Recalibration::fit(
+            features,
+            self.parameters.mzml_paths.len(),
+            settings,
+            !self.parameters.open_search,
+        );
+        let describe = |model: Option<MassErrorModel>| {
+            model.map_or_else(
+                || "-".to_string(),
+                |model| format!("{:+.2} ppm (sd {:.2} ppm)", model.offset, model.sd),
+            )
+        };
+        for (path, recalibration) in self.parameters.mzml_paths.iter().zip(&recalibration) {
+            if recalibration.is_fit() {
+                info!(
+                    "- {}: precursor mass error {}, fragment mass error {}",
+                    path,
+                    describe(recalibration.precursor),
+                    describe(recalibration.fragment)
+                );
+            } else {
+                log::warn!(
+                    "- {}: fewer than {} PSMs at q <= {}, not recalibrated",
+                    path,
+                    settings.min_psms,
+                    settings.q_value
+                );
+            }
+        }
+        let recalibrated = recalibration.iter().any(Recalibration::is_fit);
+        self.recalibration = recalibration;
+        recalibrated
+    }
+
+    /// Cascaded search: MS2 spectra without a confident first-pass PSM are
+    /// searched again against the second-pass database, which then replaces
+    /// the first-pass database. Returns the confidently identified first-pass
+    /// spectra, whose first-pass PSMs are kept
+    fn cascade_search(
+        &mut self,
+        features: &mut Vec<Feature>,
+        batch_size: usize,
+    ) -> anyhow::Result<FnvHashSet<(usize, String)>> {
+        let cascade = self
+            .parameters
+            .cascade
+            .as_ref()
+            .expect("cascade search is enabled");
+        self.spectrum_fdr(features);
+        let confident = features
+            .iter()
+            .filter(|feat| feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= cascade.q_value)
+            .map(|feat| (feat.file_id, feat.spec_id.to_string()))
+            .collect::<FnvHashSet<_>>();
+
+        // The second pass always searches the database, including after a
+        // spectral library search
+        self.library_spectra.clear();
+
+        let start = Instant::now();
+        let fasta = match cascade.focused {
+            true => self.focused_fasta(features, cascade)?,
+            false => Self::read_fasta_paths(&cascade.database)?,
+        };
+        let database = cascade.database.clone().build(fasta);
+        info!(
+            "cascade: generated {} fragments, {} peptides in {}ms",
+            database.fragments.len(),
+            database.peptides.len(),
+            (Instant::now() - start).as_millis()
+        );
+
+        // Remap first-pass PSMs onto the second-pass database, which contains
+        // every first-pass peptide
+        let mut remap = features
+            .iter()
+            .map(|feat| {
+                let peptide = &self.database[feat.peptide_idx];
+                ((peptide.decoy, peptide.to_string()), None)
+            })
+            .collect::<FnvHashMap<_, Option<PeptideIx>>>();
+        for (idx, peptide) in database.peptides.iter().enumerate() {
+            if let Some(entry) = remap.get_mut(&(peptide.decoy, peptide.to_string())) {
+                entry.get_or_insert(PeptideIx(idx as u32));
+            }
+        }
+        let before = features.len();
+        let mut remapped = Vec::with_capacity(before);
+        for mut feat in features.drain(..) {
+            let peptide = &self.database[feat.peptide_idx];
+            if let Some(Some(idx)) = remap.get(&(peptide.decoy, peptide.to_string())) {
+                feat.peptide_idx = *idx;
+                remapped.push(feat);
+            }
+        }
+        if remapped.len() < before && cascade.focused {
+            info!(
+                "cascade: discarded {} first-pass PSMs of proteins outside of the focused database",
+                before - remapped.len()
+            );
+        } else if remapped.len() < before {
+            log::warn!(
+                "cascade: {} first-pass PSMs were not found in the second-pass database, and are discarded",
+                before - remapped.len()
+            );
+        }
+        *features = remapped;
+
+        let prefilter = Self::build_prefilter(&self.parameters, &database);
+        // Free the GPU memory of the first-pass fragment index first
+        self.gpu = None;
+        let gpu = Self::fragment_matcher(&self.parameters, &database);
+        let scorer = Scorer {
+            precursor_tol: cascade.precursor_tol,
+            open_search: cascade.open_search,
+            ..self.scorer(&database, prefilter.as_ref())
+        };
+        info!(
+            "cascade: re-searching spectra without a first-pass PSM at q <= {} ({} spectra identified), precursor window: {}",
+            cascade.q_value,
+            confident.len(),
+            scorer.precursor_window()
+        );
+        for (chunk_idx, chunk) in self.parameters.mzml_paths.chunks(batch_size).enumerate() {
+            let (mut spectra, _) = self.read_chunk(chunk, chunk_idx * batch_size);
+            spectra.retain(|s| s.level == 2 && !confident.contains(&(s.file_id, s.id.clone())));
+            self.mask_reporter_ions(&mut spectra);
+            features.extend(
+                self.score_spectra(&scorer, gpu.as_ref(), &spectra)
+                    .into_iter()
+                    .map(|mut feat| {
+                        feat.search_pass = 2;
+                        feat
+                    }),
+            );
+        }
+
+        self.database = database;
+        self.gpu = gpu;
+        Ok(confident)
+    }
+
+    /// Focused second-pass database: the first-pass proteins identified at a
+    /// protein-level q-value of `cascade.q_value` or lower, or by a confident
+    /// first-pass PSM, and all proteins of FASTA files only searched in the
+    /// second pass. Spectrum-level q-values must have been assigned
+    fn focused_fasta(
+        &self,
+        features: &mut [Feature],
+        cascade: &CascadeParameters,
+    ) -> anyhow::Result<sage_core::fasta::Fasta> {
+        sage_core::fdr::picked_protein(&self.database, features);
+        let mut accessions =
+            sage_core::fdr::identified_proteins(&self.database, features, cascade.q_value);
+        accessions.extend(
+            features
+                .iter()
+                .filter(|feat| {
+                    feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= cascade.q_value
+                })
+                .flat_map(|feat| self.database[feat.peptide_idx].proteins.iter().cloned()),
+        );
+        let identified = accessions.len();
+
+        let database = &self.parameters.database;
+        if !database.generate_decoys {
+            // Keep the decoy proteins of the FASTA file(s) for identified proteins
+            let decoys = accessions
+                .iter()
+                .map(|acc| Arc::new(format!("{}{}", database.decoy_tag, acc)))
+                .collect::<Vec<_>>();
+            accessions.extend(decoys);
+        }
+        let mut fasta = Self::read_fasta_paths(database)?.subset(&accessions);
+        let mut additional = cascade.database.clone();
+        additional
+            .fasta
+            .retain(|path| !database.fasta.contains(path));
+        if !additional.fasta.is_empty() {
+            fasta.extend(Self::read_fasta_paths(&additional)?);
+        }
+        info!(
+            "cascade: focused second-pass database of {} proteins identified in the first pass",
+            identified
+        );
+        Ok(fasta)
+    }
+
+    /// Estimate spectrum-level q-values separately for each search pass, then
+    /// discard first-pass PSMs of spectra that were searched again
+    fn cascade_fdr(
+        &self,
+        features: &mut Vec<Feature>,
+        confident: &FnvHashSet<(usize, String)>,
+    ) -> usize {
+        let (mut first, mut second): (Vec<_>, Vec<_>) =
+            features.drain(..).partition(|feat| feat.search_pass == 1);
+        // First-pass PSMs of proteins outside of a focused database were
+        // discarded: keep the q-values estimated over the complete first pass
+        let focused = self
+            .parameters
+            .cascade
+            .as_ref()
+            .map_or(false, |cascade| cascade.focused);
+        if !focused {
+            self.spectrum_fdr(&mut first);
+        }
+        let q_second = self.spectrum_fdr(&mut second);
+        first.retain(|feat| confident.contains(&(feat.file_id, feat.spec_id.to_string())));
+        let q_first = first.iter().filter(|feat| feat.spectrum_q <= 0.01).count();
+        log::info!(
+            "cascade: {} PSMs at 1% FDR in the first pass, {} in the second pass",
+            q_first,
+            q_second
+        );
+        features.extend(first);
+        features.extend(second);
+        q_first + q_second
     }
 
     // Create a path for `file_name` in the specified output directory, if it exists,
     // otherwise, write to current directory
     fn make_path<S: AsRef<str>>(&self, file_name: S) -> CloudPath {
-        let mut path = self.parameters.output_directory.clone();
-        path.push(file_name);
-        path
+        self.parameters.output_path(file_name)
+    }
+
+    fn score_spectra(
+        &self,
+        scorer: &Scorer,
+        gpu: Option<&FragmentMatcher>,
+        spectra: &[ProcessedSpectrum],
+    ) -> Vec<Feature> {
+        let search = SpectrumSearch::new(self, scorer, gpu);
+        let progress = Progress::new("searching spectra", spectra.len());
+        let features: Vec<_> = match search.gpu {
+            Some(_) => spectra
+                .chunks(PIPELINE_CAPACITY)
+                .flat_map(|chunk| {
+                    let chunk = chunk.iter().collect::<Vec<_>>();
+                    let features = search.score_batch(&chunk);
+                    progress.inc(chunk.len());
+                    features.into_iter().flatten()
+                })
+                .collect(),
+            None => spectra
+                .par_iter()
+                .inspect(|_| progress.inc(1))
+                .flat_map(|spec| search.score(spec))
+                .collect(),
+        };
+        progress.finish();
+        search.finish();
+        features
     }
 
     fn search_processed_spectra(
         &self,
         scorer: &Scorer,
-        spectra: Vec<ProcessedSpectrum>,
+        mut spectra: Vec<ProcessedSpectrum>,
     ) -> SageResults {
-        use std::sync::atomic::{AtomicUsize, Ordering};
-        let counter = AtomicUsize::new(0);
-        let start = Instant::now();
-
-        let features: Vec<_> = spectra
-            .par_iter()
-            .filter(|spec| spec.peaks.len() >= self.parameters.min_peaks && spec.level == 2)
-            .map(|x| {
-                let prev = counter.fetch_add(1, Ordering::Relaxed);
-                if prev > 0 && prev % 10_000 == 0 {
-                    let duration = Instant::now().duration_since(start).as_millis() as usize;
-
-                    let rate = prev * 1000 / (duration + 1);
-                    log::trace!("- searched {} spectra ({} spectra/s)", prev, rate);
-                }
-                x
-            })
-            .flat_map(|spec| scorer.score(spec))
-            .collect();
-
-        let duration = Instant::now().duration_since(start).as_millis() as usize;
-        let prev = counter.load(Ordering::Relaxed);
-        let rate = prev * 1000 / (duration + 1);
-        log::info!("- search:  {:8} ms ({} spectra/s)", duration, rate);
+        let quant = self.quantify_tmt(&spectra);
+        self.mask_reporter_ions(&mut spectra);
+        let features = self.score_spectra(scorer, self.gpu.as_ref(), &spectra);
+        self.collect_results(features, spectra, quant)
+    }
 
-        let quant = self
-            .parameters
+    /// Quantify isobaric tag reporter ions, if enabled
+    fn quantify_tmt(&self, spectra: &[ProcessedSpectrum]) -> Vec<TmtQuant> {
+        self.parameters
             .quant
             .tmt
             .as_ref()
             .map(|isobaric| {
-                let level = self.parameters.quant.tmt_settings.level;
-                if level != 2 && level != 3 {
-                    log::warn!("TMT quant level set at {}, is this correct?", level);
+                let settings = &self.parameters.quant.tmt_settings;
+                if settings.level != 2 && settings.level != 3 {
+                    log::warn!(
+                        "TMT quant level set at {}, is this correct?",
+                        settings.level
+                    );
+                }
+                let mut quant =
+                    sage_core::tmt::quantify(spectra, isobaric, settings.tolerance, settings.level);
+                if let Some(correction) = settings
+                    .impurities
+                    .as_deref()
+                    .and_then(sage_core::tmt::ImpurityCorrection::new)
+                {
+                    quant.par_iter_mut().for_each(|q| {
+                        correction.correct(&mut q.peaks);
+                        correction.correct(&mut q.signal_to_noise);
+                    });
                 }
-                sage_core::tmt::quantify(&spectra, isobaric, Tolerance::Ppm(-20.0, 20.0), level)
+                quant
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove reporter ions from MS2 spectra before scoring, for MS2-level
+    /// TMT quantification
+    fn mask_reporter_ions(&self, spectra: &mut [ProcessedSpectrum]) {
+        let settings = &self.parameters.quant.tmt_settings;
+        if let (Some(isobaric), 2) = (&self.parameters.quant.tmt, settings.level) {
+            spectra
+                .par_iter_mut()
+                .filter(|spectrum| spectrum.level == 2)
+                .for_each(|spectrum| {
+                    sage_core::tmt::mask_reporter_ions(spectrum, isobaric, settings.tolerance)
+                });
+        }
+    }
+
+    /// Search MS2 spectra for cross-linked peptide pairs
+    fn search_crosslinks(&self, spectra: &[ProcessedSpectrum]) -> Vec<CrosslinkMatch> {
+        let settings = match &self.parameters.crosslink {
+            Some(settings) => settings,
+            None => return Vec::new(),
+        };
+        let start = Instant::now();
+        let scorer = CrosslinkScorer::new(
+            &self.database,
+            settings,
+            self.parameters.precursor_tol,
+            self.parameters.fragment_tol,
+            self.parameters.precursor_charge,
+            self.parameters.max_fragment_charge,
+        );
+        let crosslinks = spectra
+            .par_iter()
+            .filter(|spec| {
+                let min_peaks = self.parameters.file_overrides[spec.file_id]
+                    .min_peaks
+                    .unwrap_or(self.parameters.min_peaks);
+                spec.peaks.len() >= min_peaks && spec.level == 2
             })
-            .unwrap_or_default();
-        let ms1 = spectra.into_iter().filter(|s| s.level == 1).collect();
+            .filter_map(|spec| scorer.score(spec))
+            .collect::<Vec<_>>();
+        let duration = Instant::now().duration_since(start).as_millis();
+        log::info!("- cross-link search: {:8} ms", duration);
+        crosslinks
+    }
+
+    /// Perform TMT quantification (if enabled) and retain MS1 spectra for LFQ
+    fn collect_results(
+        &self,
+        features: Vec<Feature>,
+        spectra: Vec<ProcessedSpectrum>,
+        mut quant: Vec<TmtQuant>,
+    ) -> SageResults {
+        if self.parameters.quant.tmt_settings.level == 3 {
+            sage_core::tmt::assign_sps_purity(
+                &mut quant,
+                &self.database,
+                &spectra,
+                &features,
+                self.parameters.fragment_tol,
+            );
+        }
+        let crosslinks = self.search_crosslinks(&spectra);
+        let prm = match &self.parameters.prm {
+            Some(prm) => sage_core::prm::extract(
+                &self.prm_targets,
+                &spectra,
+                self.parameters.fragment_tol,
+                &prm.settings,
+            ),
+            None => Vec::new(),
+        };
+        let ms1 = match self.parameters.ms2_only {
+            true => Vec::new(),
+            false => spectra.into_iter().filter(|s| s.level == 1).collect(),
+        };
 
         SageResults {
             features,
             quant,
+            crosslinks,
+            prm,
             ms1,
+            ..Default::default()
         }
     }
 
-    fn process_chunk(
-        &self,
-        scorer: &Scorer,
-        chunk: &[String],
-        chunk_idx: usize,
-        batch_size: usize,
-    ) -> SageResults {
+    fn process_chunk(&self, scorer: &Scorer, chunk: &[String], offset: usize) -> SageResults {
+        let (spectra, read) = self.read_chunk(chunk, offset);
+        let results = self.search_processed_spectra(scorer, spectra);
+        [read, results].into_iter().collect()
+    }
+
+    /// Read the spectra of `chunk`, whose file ids are numbered from `offset`.
+    /// Skipped spectra, run metrics and failed files are returned as results
+    fn read_chunk(&self, chunk: &[String], offset: usize) -> (Vec<ProcessedSpectrum>, SageResults) {
         // Read all of the spectra at once - this can help prevent memory over-consumption issues
-        info!(
-            "processing files {} .. {} ",
-            batch_size * chunk_idx,
-            batch_size * chunk_idx + chunk.len()
-        );
+        info!("processing files {} .. {} ", offset, offset + chunk.len());
         let start = Instant::now();
 
-        let sn = self
-            .parameters
-            .quant
-            .tmt_settings
-            .sn
-            .then_some(self.parameters.quant.tmt_settings.level);
-
-        let sp = SpectrumProcessor::new(
-            self.parameters.max_peaks,
-            self.parameters.database.fragment_min_mz,
-            self.parameters.database.fragment_max_mz,
-            self.parameters.deisotope,
-        );
+        let read = std::sync::Mutex::new(SageResults::default());
 
-        let bruker_extensions = [".d", ".tdf", ".tdf_bin"];
         let spectra = chunk
             .par_iter()
             .enumerate()
             .flat_map(|(idx, path)| {
-                let file_id = chunk_idx * batch_size + idx;
-
-                let path_lower = path.to_lowercase();
-                let res = if path_lower.ends_with(".mgf.gz") || path_lower.ends_with(".mgf") {
-                    sage_cloudpath::util::read_mgf(path_lower, file_id)
-                } else if bruker_extensions
-                    .iter()
-                    .any(|ext| path_lower.ends_with(ext))
-                {
-                    sage_cloudpath::util::read_tdf(path, file_id)
-                } else {
-                    sage_cloudpath::util::read_mzml(path, file_id, sn)
-                };
-
-                match res {
-                    Ok(s) => {
-                        log::trace!("- {}: read {} spectra", path, s.len());
-                        Ok(s)
+                let file_id = offset + idx;
+                let mut spectra = Vec::new();
+                match self.read_file(path, file_id, |s| spectra.push(s)) {
+                    Ok((guarded, run)) => {
+                        let mut read = read.lock().expect("poisoned lock");
+                        read.skipped += guarded;
+                        read.runs.push(run);
+                        drop(read);
+                        if !self.parameters.wide_window {
+                            Self::warn_wide_isolation_window(
+                                path,
+                                spectra
+                                    .iter()
+                                    .filter(|s| s.level == 2)
+                                    .filter_map(|s| s.isolation_width())
+                                    .collect(),
+                            );
+                        }
+                        Some(spectra)
                     }
-                    Err(e) => {
-                        log::error!("- {}: {}", path, e);
-                        Err(e)
+                    Err(error) => {
+                        read.lock().expect("poisoned lock").failed.push(error);
+                        None
                     }
                 }
             })
-            .flat_map_iter(|spectra| spectra.into_iter().map(|s| sp.process(s)))
+            .flatten()
             .collect::<Vec<_>>();
 
         let io_time = Instant::now() - start;
         info!("- file IO: {:8} ms", io_time.as_millis());
 
-        self.search_processed_spectra(scorer, spectra)
+        (spectra, read.into_inner().expect("poisoned lock"))
     }
 
-    pub fn batch_files(&self, scorer: &Scorer, batch_size: usize) -> SageResults {
-        self.parameters
-            .mzml_paths
-            .chunks(batch_size)
-            .enumerate()
-            .map(|(chunk_idx, chunk)| self.process_chunk(scorer, chunk, chunk_idx, batch_size))
-            .collect::<SageResults>()
+    /// Can MS2 spectra be dropped as soon as they are scored? Isobaric
+    /// quantification, cross-link search and PRM extraction need all spectra
+    fn can_stream(&self) -> bool {
+        self.parameters.quant.tmt.is_none()
+            && self.parameters.crosslink.is_none()
+            && self.parameters.prm.is_none()
     }
 
-    pub fn run(mut self, parallel: usize, parquet: bool) -> anyhow::Result<telemetry::Telemetry> {
-        let scorer = Scorer {
-            db: &self.database,
-            precursor_tol: self.parameters.precursor_tol,
+    /// Are MS1 spectra needed once all files are searched, for quantification,
+    /// MS1 feature detection or the PSMs of a cascade search?
+    fn keep_ms1(&self) -> bool {
+        !self.parameters.ms2_only
+            && (self.parameters.quant.lfq
+                || self.parameters.quant.silac.is_some()
+                || self.parameters.ms1_features.is_some()
+                || self.parameters.cascade.is_some())
+    }
+
+    /// Search the spectra of `paths` (numbered from `offset`) as a pipeline:
+    /// `readers` threads parse files - each taking the next unread file once
+    /// it is done with the previous one - and process their spectra, while
+    /// the thread pool scores MS2 spectra of any file as they come in. A
+    /// bounded queue sits in between, and MS2 spectra are dropped once
+    /// scored, so that memory use does not grow with the number of spectra
+    /// in a file. MS1 spectra are only kept if needed later (see
+    /// [`Runner::keep_ms1`]), otherwise the isolation purity of the PSMs of a
+    /// file is estimated once it is searched, and its MS1 spectra dropped
+    fn stream_files(
+        &self,
+        scorer: &Scorer,
+        paths: &[String],
+        offset: usize,
+        readers: usize,
+        progress: Option<&Progress>,
+    ) -> SageResults {
+        info!(
+            "processing files {} .. {} ({} at a time)",
+            offset,
+            offset + paths.len(),
+            readers.min(paths.len())
+        );
+        let search = SpectrumSearch::new(self, scorer, self.gpu.as_ref());
+        let (sender, receiver) = crossbeam_channel::bounded(PIPELINE_CAPACITY);
+        let next = AtomicUsize::new(0);
+
+        let (mut searched, mut files) = crossbeam_utils::thread::scope(|scope| {
+            let readers = (0..readers.min(paths.len()))
+                .map(|_| {
+                    let sender = sender.clone();
+                    let next = &next;
+                    scope.spawn(move |_| {
+                        let mut files = Vec::new();
+                        loop {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            let path = match paths.get(idx) {
+                                Some(path) => path,
+                                None => break files,
+                            };
+                            let file_id = offset + idx;
+                            let mut ms1 = Vec::new();
+                            let mut widths = Vec::new();
+                            let mut order = 0;
+                            let result = self.read_file(path, file_id, |spectrum| {
+                                match spectrum.level {
+                                    1 if !self.parameters.ms2_only => ms1.push(spectrum),
+                                    2 => {
+                                        widths.extend(spectrum.isolation_width());
+                                        // Only fails if scoring panicked
+                                        let _ = sender.send(Queued::Spectrum(order, spectrum));
+                                        order += 1;
+                                    }
+                                    _ => {}
+                                }
+                            });
+                            let _ = sender.send(Queued::Read(file_id, ms1));
+                            if let Some(progress) = progress {
+                                progress.inc(1);
+                            }
+                            files.push((file_id, path, result, widths));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(sender);
+
+            // Spectra are scored in batches of those queued, rather than by
+            // threads of the pool waiting on the queue: readers need the pool
+            // to decode the binary arrays of mzML files
+            let keep_ms1 = self.keep_ms1();
+            let mut scored: FnvHashMap<usize, Vec<(usize, Vec<Feature>)>> = FnvHashMap::default();
+            let mut searched = Vec::new();
+            while let Ok(first) = receiver.recv() {
+                let mut batch = Vec::new();
+                let mut read = Vec::new();
+                for queued in
+                    std::iter::once(first).chain(receiver.try_iter().take(PIPELINE_CAPACITY))
+                {
+                    match queued {
+                        Queued::Spectrum(order, spectrum) => batch.push((order, spectrum)),
+                        Queued::Read(file_id, ms1) => read.push((file_id, ms1)),
+                    }
+                }
+                let spectra = batch
+                    .iter()
+                    .map(|(_, spectrum)| spectrum)
+                    .collect::<Vec<_>>();
+                let features = search.score_batch(&spectra);
+                for ((order, spectrum), features) in batch.iter().zip(features) {
+                    if !features.is_empty() {
+                        scored
+                            .entry(spectrum.file_id)
+                            .or_default()
+                            .push((*order, features));
+                    }
+                }
+
+                // The MS2 spectra of a file are queued before its MS1 spectra,
+                // and are all scored by now
+                for (file_id, mut ms1) in read {
+                    let mut file = scored.remove(&file_id).unwrap_or_default();
+                    file.sort_unstable_by_key(|(order, _)| *order);
+                    let mut features = file
+                        .into_iter()
+                        .flat_map(|(_, features)| features)
+                        .collect::<Vec<_>>();
+                    if !keep_ms1 {
+                        sage_core::scoring::isolation_purity(
+                            &mut features,
+                            &ms1,
+                            self.parameters.ms1_tol,
+                        );
+                        ms1 = Vec::new();
+                    }
+                    searched.push((file_id, features, ms1));
+                }
+            }
+            let files = readers
+                .into_iter()
+                .flat_map(|reader| reader.join().expect("failed to read file"))
+                .collect::<Vec<_>>();
+            (searched, files)
+        })
+        .expect("failed to read file");
+        search.finish();
+
+        // Files are read, and spectra scored, out of order
+        searched.sort_unstable_by_key(|(file_id, ..)| *file_id);
+        files.sort_unstable_by_key(|(file_id, ..)| *file_id);
+        let mut results = SageResults::default();
+        for ((_, path, result, widths), (_, features, ms1)) in files.into_iter().zip(searched) {
+            match result {
+                Ok((guarded, run)) => {
+                    results.skipped += guarded;
+                    results.runs.push(run);
+                    results.features.extend(features);
+                    results.ms1.extend(ms1);
+                    if !self.parameters.wide_window {
+                        Self::warn_wide_isolation_window(path, widths);
+                    }
+                }
+                // Spectra of a file that failed partway were scored before it
+                // did: drop them, as when files are searched in batches
+                Err(error) => results.failed.push(error),
+            }
+        }
+        results
+    }
+
+    /// Read, process and guard the spectra of a file, passing each spectrum
+    /// that passes the precursor guards to `emit`. Returns the number of
+    /// spectra removed by the guards
+    fn read_file<F: FnMut(ProcessedSpectrum)>(
+        &self,
+        path: &str,
+        file_id: usize,
+        mut emit: F,
+    ) -> Result<(SkippedPrecursors, RunMetrics), FailedFile> {
+        let sn = self
+            .parameters
+            .quant
+            .tmt_settings
+            .sn
+            .then_some(self.parameters.quant.tmt_settings.level);
+        let processor = self.spectrum_processor(file_id);
+        let guards = self.parameters.precursor_guards;
+        let refinement = self
+            .parameters
+            .refine_precursors
+            .then_some(PrecursorRefinement {
+                tolerance: self.parameters.ms1_tol,
+                precursor_charge: self.parameters.precursor_charge,
+            });
+
+        let mut removed = RemovedPeaks::default();
+        let mut guarded = SkippedPrecursors::default();
+        let mut refined = RefinedPrecursors::default();
+        // MS1 survey scan preceding the current MS2 spectrum, if precursors
+        // are refined
+        let mut survey: Option<ProcessedSpectrum> = None;
+        let quality = self.parameters.quality_filter;
+        let mut run = RunMetricsBuilder::new(file_id);
+        let mut count = 0;
+        // MS2 spectra are only emitted once all of those of the file are
+        // read, if spectra of the same precursor are merged
+        let merging = self.parameters.merge_spectra;
+        let mut unmerged = Vec::new();
+        let mut process = |s: RawSpectrum| {
+            if !run.add(&s, &quality) {
+                return;
+            }
+            let (mut processed, r) = processor.process_with_cleanup(s);
+            removed += r;
+            if let Some(recalibration) = self.recalibration.get(processed.file_id) {
+                recalibration.apply(&mut processed);
+            }
+            if let Some(refinement) = &refinement {
+                match (processed.level, &survey) {
+                    (1, _) => survey = Some(processed.clone()),
+                    (_, Some(ms1)) => refinement.refine(ms1, &mut processed, &mut refined),
+                    _ => {}
+                }
+            }
+            if guards.check(&mut processed, &mut guarded) {
+                if merging.is_some() && processed.level == 2 {
+                    unmerged.push(processed);
+                } else {
+                    count += 1;
+                    emit(processed);
+                }
+            }
+        };
+
+        let window = self.parameters.scan_window(file_id);
+        if let Err(error) = read_spectra(path, file_id, sn, window, &mut process) {
+            // Keep searching the remaining files, failures are
+            // reported once all results have been written
+            log::error!("- {}: {}", path, error);
+            return Err(FailedFile {
+                path: path.into(),
+                error,
+            });
+        }
+
+        if let Some(merging) = merging {
+            let (spectra, merged) = merging.merge(unmerged, &processor);
+            if merged.groups > 0 {
+                info!(
+                    "- {}: merged {} MS2 spectra of the same precursors into {}",
+                    path, merged.spectra, merged.groups
+                );
+            }
+            count += spectra.len();
+            spectra.into_iter().for_each(&mut emit);
+        }
+
+        log::trace!("- {}: read {} spectra", path, count);
+        let run = run.build();
+        if run.filtered_spectra > 0 {
+            info!(
+                "- {}: removed {} of {} MS2 spectra failing `quality_filter`",
+                path, run.filtered_spectra, run.ms2_spectra
+            );
+        }
+        if removed.masked > 0 {
+            log::trace!(
+                "- {}: masked {} reporter ion and precursor peaks",
+                path,
+                removed.masked
+            );
+        }
+        if removed.zero_intensity + removed.duplicate_mz > 0 {
+            info!(
+                "- {}: removed {} zero-intensity and {} duplicate m/z peaks",
+                path, removed.zero_intensity, removed.duplicate_mz
+            );
+        }
+        if removed.centroided > 0 {
+            log::warn!(
+                "- {}: centroided {} profile MS2 spectra, peak picking by the vendor library (e.g. `msconvert --filter peakPicking`) is recommended",
+                path,
+                removed.centroided
+            );
+        }
+        if refined.monoisotopic + refined.charge > 0 {
+            info!(
+                "- {}: refined MS2 precursors from MS1 scans: {} monoisotopic m/z corrected, {} charges assigned",
+                path,
+                refined.monoisotopic,
+                refined.charge
+            );
+        }
+        if guarded.mass + guarded.charge > 0 {
+            info!(
+                "- {}: skipped {} MS2 spectra exceeding `max_precursor_mass` and {} exceeding `max_precursor_charge`",
+                path,
+                guarded.mass,
+                guarded.charge
+            );
+        }
+        if guarded.charge_range + guarded.reassigned > 0 {
+            info!(
+                "- {}: {} MS2 spectra with a precursor charge outside of `precursor_charge`: {} skipped, {} reassigned",
+                path,
+                guarded.charge_range + guarded.reassigned,
+                guarded.charge_range,
+                guarded.reassigned
+            );
+        }
+        Ok((guarded, run))
+    }
+
+    /// Warn about files that look like DIA runs (wide MS2 isolation windows),
+    /// which are only searched correctly in `wide_window` mode
+    fn warn_wide_isolation_window(path: &str, mut widths: Vec<f32>) {
+        if widths.is_empty() {
+            return;
+        }
+        widths.sort_by(|a, b| a.total_cmp(b));
+        let median = widths[widths.len() / 2];
+        if median > DIA_ISOLATION_WIDTH {
+            log::warn!(
+                "- {}: median MS2 isolation window is {:.1} m/z, this looks like a DIA run - \
+                 consider setting `wide_window: true`",
+                path,
+                median
+            );
+        }
+    }
+
+    /// Search the input files at positions `files` of `mzml_paths`
+    pub fn batch_files(
+        &self,
+        scorer: &Scorer,
+        batch_size: usize,
+        files: Range<usize>,
+    ) -> SageResults {
+        let paths = &self.parameters.mzml_paths[files.clone()];
+        let progress = Progress::new("searched files", paths.len()).every_update();
+        // Checkpoints are saved per batch. Otherwise, all files are searched
+        // in one pipeline: up to `batch_size` files are read at a time, and a
+        // new file is started as soon as one is read, rather than waiting for
+        // the slowest (e.g. largest) file of each batch
+        if self.checkpoint.is_none() && self.can_stream() {
+            return self.stream_files(scorer, paths, files.start, batch_size, Some(&progress));
+        }
+        paths
+            .chunks(batch_size)
+            .enumerate()
+            .map(|(chunk_idx, chunk)| {
+                let checkpoint = self.checkpoint.as_ref();
+                let start = files.start + batch_size * chunk_idx;
+                let overrides = &self.parameters.file_overrides[start..start + chunk.len()];
+                let results = match checkpoint.and_then(|c| c.load(start, chunk, overrides)) {
+                    Some(results) => {
+                        info!(
+                            "loaded files {} .. {} from checkpoint",
+                            start,
+                            start + chunk.len()
+                        );
+                        results
+                    }
+                    None => {
+                        let results = match self.can_stream() {
+                            true => self.stream_files(scorer, chunk, start, chunk.len(), None),
+                            false => self.process_chunk(scorer, chunk, start),
+                        };
+                        if let Some(checkpoint) = checkpoint {
+                            checkpoint.save(start, chunk, overrides, &results);
+                        }
+                        results
+                    }
+                };
+                progress.inc(chunk.len());
+                results
+            })
+            .collect::<SageResults>()
+    }
+
+    /// Search the input files in batches of `batch_size`: the fragment index of
+    /// each mass partition is built in turn and the spectra of the batch are
+    /// scored against it, so that only a single partition and a single batch
+    /// of spectra are held in memory at a time
+    pub fn batch_files_partitioned(
+        &self,
+        partitions: &[Range<usize>],
+        batch_size: usize,
+    ) -> SageResults {
+        let progress =
+            Progress::new("searched files", self.parameters.mzml_paths.len()).every_update();
+        self.parameters
+            .mzml_paths
+            .chunks(batch_size)
+            .enumerate()
+            .map(|(chunk_idx, chunk)| {
+                let (mut spectra, read) = self.read_chunk(chunk, chunk_idx * batch_size);
+                let quant = self.quantify_tmt(&spectra);
+                self.mask_reporter_ions(&mut spectra);
+
+                let features = partitions
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, range)| {
+                        self.search_partition(idx, partitions.len(), range, &spectra)
+                    })
+                    .collect();
+                let features = merge_partitioned_features(features, self.parameters.report_psms);
+                progress.inc(chunk.len());
+                [read, self.collect_results(features, spectra, quant)]
+                    .into_iter()
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Build the fragment index of the mass partition `range` of the database,
+    /// and score `spectra` against it
+    fn search_partition(
+        &self,
+        idx: usize,
+        count: usize,
+        range: &Range<usize>,
+        spectra: &[ProcessedSpectrum],
+    ) -> Vec<Feature> {
+        let start = Instant::now();
+        let database = self
+            .parameters
+            .database
+            .build_from_peptides(self.database.peptides[range.clone()].to_vec());
+        info!(
+            "partition {}/{}: generated {} fragments, {} peptides in {}ms",
+            idx + 1,
+            count,
+            database.fragments.len(),
+            database.peptides.len(),
+            (Instant::now() - start).as_millis()
+        );
+
+        let prefilter = Self::build_prefilter(&self.parameters, &database);
+        let gpu = Self::fragment_matcher(&self.parameters, &database);
+        let scorer = self.scorer(&database, prefilter.as_ref());
+        self.score_spectra(&scorer, gpu.as_ref(), spectra)
+            .into_iter()
+            .map(|mut feat| {
+                // Convert from partition-local to global peptide index
+                feat.peptide_idx = PeptideIx(feat.peptide_idx.0 + range.start as u32);
+                feat
+            })
+            .collect()
+    }
+
+    fn spectrum_processor(&self, file_id: usize) -> SpectrumProcessor {
+        let overrides = &self.parameters.file_overrides[file_id];
+        SpectrumProcessor {
+            deisotope_method: self.parameters.deisotope_method,
+            centroid: self.parameters.centroid,
+            rt_source: self.parameters.rt_source,
+            peak_cleanup: self.parameters.peak_cleanup,
+            peak_filter: self.parameters.peak_filter,
+            peak_mask: self.parameters.peak_mask.map(|mask| PeakMask {
+                tolerance: overrides.fragment_tol.unwrap_or(mask.tolerance),
+                ..mask
+            }),
+            ..SpectrumProcessor::new(
+                overrides.max_peaks.unwrap_or(self.parameters.max_peaks),
+                self.parameters.database.fragment_min_mz,
+                self.parameters.database.fragment_max_mz,
+                overrides.deisotope.unwrap_or(self.parameters.deisotope),
+            )
+        }
+    }
+
+    /// Apply any per-file parameter overrides to `scorer`
+    fn file_scorer<'db>(&self, scorer: &Scorer<'db>, file_id: usize) -> Scorer<'db> {
+        let overrides = &self.parameters.file_overrides[file_id];
+        let mut precursor_tol = overrides.precursor_tol.unwrap_or(scorer.precursor_tol);
+        let mut fragment_tol = overrides.fragment_tol.unwrap_or(scorer.fragment_tol);
+        if let (Some(settings), Some(recalibration)) = (
+            &self.parameters.recalibration,
+            self.recalibration.get(file_id),
+        ) {
+            if let Some(model) = recalibration.precursor {
+                precursor_tol = model.tolerance(settings.tolerance_sd, precursor_tol);
+            }
+            if let Some(model) = recalibration.fragment {
+                fragment_tol = model.tolerance(settings.tolerance_sd, fragment_tol);
+            }
+        }
+        Scorer {
+            precursor_tol,
+            fragment_tol,
+            ..*scorer
+        }
+    }
+
+    fn scorer<'db>(
+        &'db self,
+        db: &'db IndexedDatabase,
+        prefilter: Option<&'db SpectralIndex>,
+    ) -> Scorer<'db> {
+        Scorer {
+            db,
+            precursor_tol: self.parameters.precursor_tol,
             fragment_tol: self.parameters.fragment_tol,
             min_matched_peaks: self.parameters.min_matched_peaks,
+            min_fragment_coverage: self.parameters.min_fragment_coverage,
             min_isotope_err: self.parameters.isotope_errors.0,
             max_isotope_err: self.parameters.isotope_errors.1,
+            isotope_error_mode: self.parameters.isotope_error_mode,
             min_precursor_charge: self.parameters.precursor_charge.0,
             max_precursor_charge: self.parameters.precursor_charge.1,
             max_fragment_charge: self.parameters.max_fragment_charge,
@@ -255,13 +1648,201 @@ impl Runner {
             chimera: self.parameters.chimera,
             report_psms: self.parameters.report_psms,
             wide_window: self.parameters.wide_window,
-            annotate_matches: self.parameters.annotate_matches,
-        };
+            open_search: self.parameters.open_search,
+            prefilter,
+            mobility_filter: self.mobility_filter.as_ref(),
+            neutral_losses: &self.parameters.neutral_losses,
+            localize: self.parameters.localize_mods,
+            feature_hook: None,
+            // Spectral libraries are built from annotated fragment ions, and
+            // fragment mass errors are fit to those of the first-pass search,
+            // as are predicted fragment intensities compared
+            annotate_matches: self.parameters.annotate_matches
+                || self.parameters.library.is_some()
+                || self.parameters.predicted_intensities.is_some()
+                || (self.parameters.recalibration.is_some() && self.recalibration.is_empty()),
+        }
+    }
+
+    /// Use the combined results of a sharded search, instead of searching
+    /// the input files
+    pub fn merge(mut self, results: SageResults) -> Self {
+        self.merged = Some(results);
+        self
+    }
+
+    /// Search the files of one shard of a search, and save their results
+    /// for `sage merge`, without rescoring or writing any other output
+    pub fn search_shard(&self, shard: Shard, parallel: usize) -> anyhow::Result<()> {
+        let files = shard.files(self.parameters.mzml_paths.len());
+        info!(
+            "shard {}: searching files {} .. {} of {}",
+            shard,
+            files.start,
+            files.end,
+            self.parameters.mzml_paths.len()
+        );
+        let scorer = self.scorer(&self.database, self.prefilter.as_ref());
+        let outputs = self.batch_files(&scorer, parallel, files.clone());
+        let path = shard.save(&self.parameters, &outputs)?;
+        info!(
+            "shard {}: saved {} PSMs to `{}` in {}s",
+            shard,
+            outputs.features.len(),
+            path,
+            (Instant::now() - self.start).as_secs()
+        );
+        if !outputs.failed.is_empty() {
+            return Err(error::Error::InputFiles {
+                failed: outputs.failed,
+                total: files.len(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn run(mut self, parallel: usize, parquet: bool) -> anyhow::Result<telemetry::Telemetry> {
+        let merged = self.merged.take();
+        let scorer = self.scorer(&self.database, self.prefilter.as_ref());
+        info!("- precursor window: {}", scorer.precursor_window());
+        if scorer.open_search {
+            info!("- open search: matching fragment ions shifted by precursor mass offsets");
+        }
+        if !scorer.neutral_losses.is_empty() {
+            info!(
+                "- neutral losses: matching fragment ions after losing {}",
+                scorer
+                    .neutral_losses
+                    .iter()
+                    .map(|loss| format!("{} Da ({})", loss.mass, loss.residues))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if let Some(glyco) = &self.parameters.glyco {
+            info!(
+                "- glycopeptide search: {} glycans, spectra with >= {} oxonium ions",
+                glyco.glycans.len(),
+                glyco.min_oxonium_ions
+            );
+        }
+        if let Some(library_search) = &self.parameters.library_search {
+            info!(
+                "- spectral library search: {} library spectra, matching the {} most intense fragments",
+                self.library_spectra.len(),
+                library_search.settings.fragments
+            );
+        }
+        if let Some(settings) = &self.parameters.prefilter {
+            info!(
+                "- spectral prefilter: shortlisting peptides sharing >= {} of {} MinHash values",
+                settings.min_collisions, settings.hashes
+            );
+        }
 
         //Collect all results into a single container
-        let mut outputs = self.batch_files(&scorer, parallel);
+        let mut outputs = match (merged, &self.partitions) {
+            (Some(merged), _) => merged,
+            (None, Some(partitions)) => self.batch_files_partitioned(partitions, parallel),
+            (None, None) => {
+                self.batch_files(&scorer, parallel, 0..self.parameters.mzml_paths.len())
+            }
+        };
+        if outputs.failed.len() == self.parameters.mzml_paths.len() {
+            return Err(error::Error::InputFiles {
+                failed: outputs.failed,
+                total: self.parameters.mzml_paths.len(),
+            }
+            .into());
+        }
+        if let Some(settings) = self.parameters.recalibration {
+            info!(
+                "recalibration: fitting mass errors to PSMs at q <= {}",
+                settings.q_value
+            );
+            if self.recalibrate(&mut outputs.features, &settings) {
+                info!(
+                    "recalibration: searching recalibrated spectra, with tolerances of {} standard deviations",
+                    settings.tolerance_sd
+                );
+                let scorer = self.scorer(&self.database, self.prefilter.as_ref());
+                outputs = match &self.partitions {
+                    Some(partitions) => self.batch_files_partitioned(partitions, parallel),
+                    None => {
+                        self.batch_files(&scorer, parallel, 0..self.parameters.mzml_paths.len())
+                    }
+                };
+            }
+        }
+        let confident = match self.parameters.cascade.is_some() {
+            true => Some(self.cascade_search(&mut outputs.features, parallel)?),
+            false => None,
+        };
+        if self.parameters.precursor_guards.is_enabled() {
+            info!(
+                "skipped {} MS2 spectra with precursor mass > {} Da and {} with precursor charge > {}",
+                outputs.skipped.mass,
+                self.parameters
+                    .precursor_guards
+                    .max_precursor_mass
+                    .map_or_else(|| "-".to_string(), |mass| mass.to_string()),
+                outputs.skipped.charge,
+                self.parameters
+                    .precursor_guards
+                    .max_precursor_charge
+                    .map_or_else(|| "-".to_string(), |charge| charge.to_string()),
+            );
+            let (min, max) = self.parameters.precursor_charge;
+            match self.parameters.precursor_guards.charge_filter {
+                ChargeFilter::Keep => {}
+                ChargeFilter::Skip => info!(
+                    "skipped {} MS2 spectra with precursor charge outside of [{}, {}]",
+                    outputs.skipped.charge_range, min, max
+                ),
+                ChargeFilter::Reassign => info!(
+                    "searched {} MS2 spectra with precursor charge outside of [{}, {}] at all charges in the range",
+                    outputs.skipped.reassigned, min, max
+                ),
+            }
+        }
+        let ms1_available = self.check_ms1(&outputs.runs);
+        // Otherwise, isolation purity was estimated as each file was searched
+        if ms1_available && !outputs.ms1.is_empty() {
+            sage_core::scoring::isolation_purity(
+                &mut outputs.features,
+                &outputs.ms1,
+                self.parameters.ms1_tol,
+            );
+        }
+        let ms1_features = match (&self.parameters.ms1_features, ms1_available) {
+            (Some(finder), true) => {
+                progress::stage("MS1 feature detection", self.start);
+                let map = finder.detect(&outputs.ms1);
+                info!("detected {} MS1 features", map.features().len());
+                map.isolation_purity(&mut outputs.features, self.parameters.ms1_tol);
+                Some(map)
+            }
+            _ => None,
+        };
+        if !self.parameters.database.silac_labels.is_empty() {
+            sage_core::silac::assign_channels(
+                &self.database,
+                &mut outputs.features,
+                &self.parameters.database.silac_labels,
+            );
+        }
 
-        let alignments = if self.parameters.predict_rt {
+        progress::stage("rescoring", self.start);
+
+        // Converted MGF files may not report retention times at all
+        let has_rt = outputs.features.iter().any(|feat| feat.rt > 0.0);
+        if self.parameters.predict_rt && !has_rt {
+            log::warn!("no retention times reported for any PSM: skipping retention time alignment and prediction");
+        }
+
+        let mut rt_model_r2 = None;
+        let alignments = if self.parameters.predict_rt && has_rt {
             // Poisson probability is usually the best single feature for refining FDR.
             // Take our set of 1% FDR filtered PSMs, and use them to train a linear
             // regression model for predicting retention time
@@ -274,16 +1855,56 @@ impl Runner {
                 &mut outputs.features,
                 self.parameters.mzml_paths.len(),
             );
-            let _ = sage_core::ml::retention_model::predict(&self.database, &mut outputs.features);
+            rt_model_r2 = match self.parameters.faims.split_rt {
+                true => sage_core::faims::predict_rt(&self.database, &mut outputs.features),
+                false => {
+                    sage_core::ml::retention_model::predict(&self.database, &mut outputs.features)
+                }
+            };
             Some(alignments)
         } else {
             None
         };
 
-        let q_spectrum = self.spectrum_fdr(&mut outputs.features);
-        let q_peptide = sage_core::fdr::picked_peptide(&self.database, &mut outputs.features);
-        let q_protein = sage_core::fdr::picked_protein(&self.database, &mut outputs.features);
+        // Ion mobility is only reported for ion mobility spectrometry data (e.g. timsTOF)
+        let mobility_model = if outputs
+            .features
+            .iter()
+            .any(|feat| feat.ion_mobility.is_some())
+        {
+            if alignments.is_none() {
+                outputs
+                    .features
+                    .par_sort_unstable_by(|a, b| a.poisson.total_cmp(&b.poisson));
+                sage_core::ml::qvalue::spectrum_q_value(&mut outputs.features);
+            }
+            sage_core::ml::mobility_model::predict(&self.database, &mut outputs.features)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "onnx")]
+        if let Some(model) = &self.intensity_model {
+            let records = model.predict(&self.database, &outputs.features)?;
+            info!(
+                "- predicted fragment intensities of {} precursors",
+                records.len()
+            );
+            self.predicted_spectra = PredictedSpectra::new(&self.database, records).0;
+        }
+        if !self.predicted_spectra.is_empty() {
+            let scored = self.predicted_spectra.score(&mut outputs.features);
+            info!(
+                "- compared {} of {} PSMs with predicted fragment intensities",
+                scored,
+                outputs.features.len()
+            );
+        }
 
+        let q_spectrum = match &confident {
+            Some(confident) => self.cascade_fdr(&mut outputs.features, confident),
+            None => self.spectrum_fdr(&mut outputs.features),
+        };
         let filenames = self
             .parameters
             .mzml_paths
@@ -296,93 +1917,390 @@ impl Runner {
             })
             .collect::<Vec<_>>();
 
-        let areas = alignments.and_then(|alignments| {
-            if self.parameters.quant.lfq {
-                let mut areas = sage_core::lfq::build_feature_map(
-                    self.parameters.quant.lfq_settings,
-                    self.parameters.precursor_charge,
-                    &outputs.features,
-                )
-                .quantify(&self.database, &outputs.ms1, &alignments);
+        let qc = output::QcReport {
+            competition: sage_core::ml::qvalue::competition_report(&outputs.features),
+            runs: output::run_reports(&outputs.runs, &outputs.features, &filenames),
+        };
+        log::info!(
+            "estimated fraction of incorrect target PSMs (pi0): {:.3}",
+            qc.competition.pi0
+        );
+        let q_peptide = sage_core::fdr::picked_peptide(&self.database, &mut outputs.features);
+        let q_protein = sage_core::fdr::picked_protein(&self.database, &mut outputs.features);
+
+        if let Some(settings) = &self.parameters.irt {
+            let calibrations = sage_core::irt::calibrate(
+                &self.database,
+                &mut outputs.features,
+                self.parameters.mzml_paths.len(),
+                settings,
+            );
+            for (path, calibration) in self.parameters.mzml_paths.iter().zip(&calibrations) {
+                match calibration {
+                    Some(calibration) => info!(
+                        "- {}: iRT = {:.3} * rt {:+.3} ({} reference peptides, r2 = {:.3})",
+                        path,
+                        calibration.slope,
+                        calibration.intercept,
+                        calibration.peptides,
+                        calibration.r2
+                    ),
+                    None => log::warn!(
+                        "- {}: fewer than {} iRT reference peptides identified, iRT not calibrated",
+                        path,
+                        settings.min_peptides
+                    ),
+                }
+            }
+        }
+
+        let areas = alignments.and_then(|alignments| {
+            if self.parameters.quant.lfq && ms1_available {
+                progress::stage("label-free quantification", self.start);
+                let settings = self.parameters.quant.lfq_settings;
+                let mut areas = match (settings.integration, &ms1_features) {
+                    (IntegrationStrategy::Feature, Some(map)) => sage_core::lfq::quantify_features(
+                        &settings,
+                        self.parameters.precursor_charge,
+                        &outputs.features,
+                        map,
+                        &alignments,
+                    ),
+                    _ => sage_core::lfq::build_feature_map(
+                        settings,
+                        self.parameters.precursor_charge,
+                        &outputs.features,
+                        self.parameters.faims.split_quant,
+                    )
+                    .quantify(&self.database, &outputs.ms1, &alignments),
+                };
+
+                let q_precursor = sage_core::fdr::picked_precursor(&mut areas);
+
+                log::info!("discovered {} target MS1 peaks at 5% FDR", q_precursor);
+                if !self.parameters.quant.lfq_settings.mbr {
+                    sage_core::lfq::remove_transferred(&mut areas, &outputs.features);
+                }
+                Some(areas)
+            } else {
+                None
+            }
+        });
+
+        log::info!(
+            "discovered {} target peptide-spectrum matches at 1% FDR",
+            q_spectrum
+        );
+        log::info!("discovered {} target peptides at 1% FDR", q_peptide);
+        log::info!("discovered {} target proteins at 1% FDR", q_protein);
+        if let Some(method) = self.parameters.quant.tmt_settings.normalize {
+            let reference_channels = match &self.parameters.tmt_design {
+                Some(design) => design
+                    .plex_of_file
+                    .iter()
+                    .map(|plex| {
+                        plex.map(|plex| design.reference_channels[plex].clone())
+                            .unwrap_or_default()
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            sage_core::tmt::normalize(&mut outputs.quant, method, &reference_channels);
+        }
+
+        progress::stage("writing outputs", self.start);
+
+        // Write either a single parquet file, or multiple tsv files
+        if parquet {
+            log::warn!("parquet output format is currently unstable! There may be failures or schema changes!");
+
+            let bytes = sage_cloudpath::parquet::serialize_features(
+                &outputs.features,
+                &outputs.quant,
+                &filenames,
+                &self.database,
+            )?;
+
+            let path = self.make_path("results.sage.parquet");
+            path.write_bytes_sync(bytes)?;
+            self.parameters.output_paths.push(path.to_string());
+
+            if self.parameters.annotate_matches {
+                let bytes =
+                    sage_cloudpath::parquet::serialize_matched_fragments(&outputs.features)?;
+                let path = self.make_path("matched_fragments.sage.parquet");
+                path.write_bytes_sync(bytes)?;
+                self.parameters.output_paths.push(path.to_string());
+            }
+
+            if let Some(areas) = &areas {
+                let bytes =
+                    sage_cloudpath::parquet::serialize_lfq(areas, &filenames, &self.database)?;
+
+                let path = self.make_path("lfq.parquet");
+                path.write_bytes_sync(bytes)?;
+                self.parameters.output_paths.push(path.to_string());
+            }
+        } else {
+            self.parameters.output_paths.push(self.write_features(
+                &outputs.features,
+                &outputs.quant,
+                &filenames,
+            )?);
+            if self.parameters.faims.split_output {
+                let paths = self.write_features_by_compensation_voltage(
+                    &outputs.features,
+                    &outputs.quant,
+                    &filenames,
+                )?;
+                self.parameters.output_paths.extend(paths);
+            }
+
+            if self.parameters.annotate_matches {
+                self.parameters
+                    .output_paths
+                    .push(self.write_fragments(&outputs.features)?);
+            }
+
+            if !outputs.quant.is_empty() {
+                self.parameters.output_paths.push(self.write_tmt(
+                    &outputs.quant,
+                    &outputs.features,
+                    &filenames,
+                )?);
+            }
+            if let Some(areas) = &areas {
+                self.parameters
+                    .output_paths
+                    .push(self.write_lfq(areas, &filenames)?);
+            }
+        }
+
+        if let Some(design) = &self.parameters.tmt_design {
+            // Roll up signal-to-noise ratios instead of intensities, if available
+            let signal_to_noise;
+            let quant = match self.parameters.quant.tmt_settings.sn {
+                true => {
+                    signal_to_noise = outputs
+                        .quant
+                        .iter()
+                        .filter(|q| !q.signal_to_noise.is_empty())
+                        .map(|q| TmtQuant {
+                            peaks: q.signal_to_noise.clone(),
+                            ..q.clone()
+                        })
+                        .collect::<Vec<_>>();
+                    &signal_to_noise
+                }
+                false => &outputs.quant,
+            };
+            let mut proteins = sage_core::tmt::protein_rollup(
+                &self.database,
+                &outputs.features,
+                quant,
+                &design.plex_of_file,
+                design.names.len(),
+                self.parameters.quant.tmt_settings.min_isolation_purity,
+                self.parameters.quant.protein_rollup.unwrap_or_default(),
+            );
+            let quantified = proteins.len();
+            sage_core::tmt::internal_reference_scaling(&mut proteins, &design.reference_channels);
+            log::info!(
+                "internal reference scaling: {} of {} quantified proteins observed in all plexes",
+                proteins.len(),
+                quantified
+            );
+            self.parameters
+                .output_paths
+                .push(self.write_tmt_proteins(&proteins, design)?);
+        }
+
+        if let Some(map) = &ms1_features {
+            let path = self.make_path("ms1_features.tsv");
+            output::write_ms1_features(&path, map, &filenames)?;
+            self.parameters.output_paths.push(path.to_string());
+        }
+
+        if let (Some(areas), Some(method)) = (&areas, self.parameters.quant.protein_rollup) {
+            let proteins = sage_core::lfq::protein_rollup(&self.database, areas, 0.05, method);
+            log::info!("quantified {} proteins from MS1 peaks", proteins.len());
+            self.parameters
+                .output_paths
+                .push(self.write_lfq_proteins(&proteins, &filenames)?);
+        }
+
+        if let Some(settings) = self
+            .parameters
+            .quant
+            .silac
+            .as_ref()
+            .filter(|_| ms1_available)
+        {
+            let pairs = sage_core::silac::detect_pairs(
+                &self.database,
+                &outputs.features,
+                &outputs.ms1,
+                settings,
+            );
+            log::info!(
+                "found SILAC pairs for {} of {} labeled PSMs",
+                pairs.iter().filter(|pair| pair.pair_found).count(),
+                pairs.len()
+            );
+            self.parameters
+                .output_paths
+                .push(self.write_silac(&pairs, &filenames)?);
+
+            let peptides = sage_core::silac::peptide_ratios(&self.database, &pairs);
+            self.parameters
+                .output_paths
+                .push(self.write_silac_peptides(&peptides, &filenames)?);
+        }
+
+        if let Some(settings) = &self.parameters.crosslink {
+            let q_crosslink = sage_core::crosslink::q_values(&mut outputs.crosslinks);
+            log::info!(
+                "discovered {} target-target {} cross-links at 1% FDR",
+                q_crosslink,
+                settings.linker.name
+            );
+            self.parameters
+                .output_paths
+                .push(self.write_crosslinks(&outputs.crosslinks, &filenames)?);
+        }
+
+        if self.parameters.prm.is_some() {
+            log::info!(
+                "extracted {} PRM target precursors in {} files",
+                outputs
+                    .prm
+                    .iter()
+                    .filter(|result| result.area > 0.0)
+                    .count(),
+                self.parameters.mzml_paths.len()
+            );
+            self.parameters.output_paths.push(self.write_prm(
+                &self.prm_targets,
+                &outputs.prm,
+                &filenames,
+            )?);
+        }
+
+        if let Some(settings) = &self.parameters.protein_inference {
+            let groups = settings.infer(&self.database, &outputs.features);
+            log::info!(
+                "discovered {} target protein groups at 1% FDR",
+                groups
+                    .iter()
+                    .filter(|group| !group.decoy && group.q_value <= 0.01)
+                    .count()
+            );
+            self.parameters
+                .output_paths
+                .push(self.write_protein_groups(&groups)?);
+        }
 
-                let q_precursor = sage_core::fdr::picked_precursor(&mut areas);
+        if let Some(library) = &self.parameters.library {
+            let entries = sage_core::library::build(&outputs.features, &library.settings);
+            log::info!(
+                "built spectral library containing {} precursors",
+                entries.len()
+            );
+            let path = match (library.format, parquet) {
+                (LibraryFormat::Sage, _) => self.write_library(&entries)?,
+                (LibraryFormat::Dia, false) => self.write_dia_library(&entries)?,
+                (LibraryFormat::Dia, true) => {
+                    let bytes =
+                        sage_cloudpath::parquet::serialize_dia_library(&entries, &self.database)?;
+                    let path = self.make_path("library.dia.parquet");
+                    path.write_bytes_sync(bytes)?;
+                    path.to_string()
+                }
+            };
+            self.parameters.output_paths.push(path);
+        }
 
-                log::info!("discovered {} target MS1 peaks at 5% FDR", q_precursor);
-                Some(areas)
-            } else {
-                None
-            }
-        });
+        if let Some(protein_fdr) = self.parameters.export_fasta {
+            let accessions =
+                sage_core::fdr::identified_proteins(&self.database, &outputs.features, protein_fdr);
+            let fasta = Self::read_fasta(&self.parameters)?.subset(&accessions);
+            log::info!(
+                "writing {} proteins identified at {}% protein-level FDR",
+                fasta.targets.len(),
+                protein_fdr * 100.0
+            );
+            self.parameters.output_paths.push(self.write_fasta(&fasta)?);
+        }
 
-        log::info!(
-            "discovered {} target peptide-spectrum matches at 1% FDR",
-            q_spectrum
-        );
-        log::info!("discovered {} target peptides at 1% FDR", q_peptide);
-        log::info!("discovered {} target proteins at 1% FDR", q_protein);
-        log::trace!("writing outputs");
+        // Write percolator input file if requested
+        if self.parameters.write_pin {
+            self.parameters
+                .output_paths
+                .push(self.write_pin(&outputs.features, &filenames)?);
+        }
 
-        // Write either a single parquet file, or multiple tsv files
-        if parquet {
-            log::warn!("parquet output format is currently unstable! There may be failures or schema changes!");
+        if self.parameters.write_flashlfq {
+            self.parameters
+                .output_paths
+                .push(self.write_flashlfq(&outputs.features, &filenames)?);
+        }
 
-            let bytes = sage_cloudpath::parquet::serialize_features(
+        if self.parameters.write_sqlite {
+            self.parameters.output_paths.push(self.write_sqlite(
                 &outputs.features,
                 &outputs.quant,
+                areas.as_ref(),
                 &filenames,
-                &self.database,
-            )?;
-
-            let path = self.make_path("results.sage.parquet");
-            path.write_bytes_sync(bytes)?;
-            self.parameters.output_paths.push(path.to_string());
-
-            if self.parameters.annotate_matches {
-                let bytes =
-                    sage_cloudpath::parquet::serialize_matched_fragments(&outputs.features)?;
-                let path = self.make_path("matched_fragments.sage.parquet");
-                path.write_bytes_sync(bytes)?;
-                self.parameters.output_paths.push(path.to_string());
-            }
+            )?);
+        }
 
+        if self.parameters.write_msstats {
             if let Some(areas) = &areas {
-                let bytes =
-                    sage_cloudpath::parquet::serialize_lfq(areas, &filenames, &self.database)?;
-
-                let path = self.make_path("lfq.parquet");
-                path.write_bytes_sync(bytes)?;
-                self.parameters.output_paths.push(path.to_string());
-            }
-        } else {
-            self.parameters
-                .output_paths
-                .push(self.write_features(&outputs.features, &filenames)?);
-
-            if self.parameters.annotate_matches {
                 self.parameters
                     .output_paths
-                    .push(self.write_fragments(&outputs.features)?);
+                    .push(self.write_msstats(areas, &filenames)?);
             }
-
             if !outputs.quant.is_empty() {
-                self.parameters
-                    .output_paths
-                    .push(self.write_tmt(&outputs.quant, &filenames)?);
+                self.parameters.output_paths.push(self.write_msstats_tmt(
+                    &outputs.quant,
+                    &outputs.features,
+                    &filenames,
+                )?);
+            }
+            if areas.is_none() && outputs.quant.is_empty() {
+                log::warn!(
+                    "`write_msstats` is set, but neither LFQ nor TMT quantification was performed"
+                );
             }
-            if let Some(areas) = areas {
+        }
+
+        if self.parameters.ml.export_features {
+            self.parameters
+                .output_paths
+                .push(self.write_feature_matrix(&outputs.features, &filenames)?);
+        }
+
+        if self.parameters.diagnostics {
+            let diagnostics =
+                sage_core::ml::diagnostics::diagnostics(&outputs.features, rt_model_r2);
+            self.parameters
+                .output_paths
+                .push(self.write_diagnostics(&diagnostics)?);
+            if rt_model_r2.is_some() {
                 self.parameters
                     .output_paths
-                    .push(self.write_lfq(areas, &filenames)?);
+                    .push(self.write_rt_diagnostics(&outputs.features, &filenames)?);
             }
         }
 
-        // Write percolator input file if requested
-        if self.parameters.write_pin {
+        if let Some(model) = &mobility_model {
             self.parameters
                 .output_paths
-                .push(self.write_pin(&outputs.features, &filenames)?);
+                .push(self.write_mobility_model(model)?);
         }
 
+        self.parameters.output_paths.push(self.write_qc(&qc)?);
+
         let path = self.make_path("results.json");
         self.parameters.output_paths.push(path.to_string());
         println!("{}", serde_json::to_string_pretty(&self.parameters)?);
@@ -394,6 +2312,17 @@ impl Runner {
         info!("finished in {}s", run_time);
         info!("cite: \"Sage: An Open-Source Tool for Fast Proteomics Searching and Quantification at Scale\" https://doi.org/10.1021/acs.jproteome.3c00486");
 
+        if !outputs.failed.is_empty() {
+            return Err(error::Error::InputFiles {
+                failed: outputs.failed,
+                total: self.parameters.mzml_paths.len(),
+            }
+            .into());
+        }
+        if let Some(checkpoint) = self.checkpoint.take() {
+            checkpoint.finish();
+        }
+
         let telemetry = telemetry::Telemetry::new(
             self.parameters,
             self.database.peptides.len(),
@@ -406,21 +2335,158 @@ impl Runner {
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    env_logger::Builder::default()
-        .filter_level(log::LevelFilter::Error)
-        .parse_env(env_logger::Env::default().filter_or("SAGE_LOG", "error,sage=info"))
-        .init();
+/// Refuse to overwrite the results of a previous search in a local output
+/// directory, unless `--overwrite` is passed, or the previous search is
+/// resumed from its checkpoints. `results.json` is written by every search
+fn check_existing_results(parameters: &Search, overwrite: bool) -> anyhow::Result<()> {
+    if overwrite || Checkpoint::resumable(parameters) {
+        return Ok(());
+    }
+    if let CloudPath::Local(path) = parameters.output_path("results.json") {
+        anyhow::ensure!(
+            !path.exists(),
+            "`{}` already exists: pass `--overwrite` to replace the results of the previous \
+             search, or choose another `output_directory` or `output_template`",
+            path.display()
+        );
+    }
+    Ok(())
+}
 
-    let matches = Command::new("sage")
-        .version(clap::crate_version!())
-        .author("Michael Lazear <michaellazear92@gmail.com>")
-        .about("\u{1F52E} Sage \u{1F9D9} - Proteomics searching so fast it feels like magic!")
+/// Digest the FASTA file to estimate the size of the database, and print the
+/// resolved search parameters, without searching (`--dry-run`)
+fn plan_search(parameters: &Search) -> anyhow::Result<()> {
+    info!(
+        "dry run: {} spectra files, results written to `{}`",
+        parameters.mzml_paths.len(),
+        parameters.output_directory
+    );
+    let fasta = Runner::read_fasta(parameters)?;
+    let peptides = parameters.database.digest(&fasta);
+    let decoys = peptides.iter().filter(|peptide| peptide.decoy).count();
+    let (fragments, bytes) = parameters.database.estimate_index(&peptides);
+    info!(
+        "- database: {} proteins, {} peptides ({} decoys), up to {} fragments",
+        fasta.targets.len(),
+        peptides.len(),
+        decoys,
+        fragments
+    );
+    info!(
+        "- estimated fragment index memory: {:.1} MiB",
+        bytes as f64 / (1024.0 * 1024.0)
+    );
+    if let Some(limit) = parameters.database.max_index_memory_mb {
+        let partitions = parameters
+            .database
+            .partition(&peptides, limit.saturating_mul(1024 * 1024));
+        info!(
+            "- memory-bounded search: {} partitions of <= {} MiB",
+            partitions.len(),
+            limit
+        );
+    }
+    if let Some(path) = &parameters.database.prebuilt_index {
+        match std::path::Path::new(path).exists() {
+            true => info!("- prebuilt index: loaded from `{}` if up to date", path),
+            false => info!("- prebuilt index: built and saved to `{}`", path),
+        }
+    }
+    info!(
+        "- spectra of {} files are held in memory at a time",
+        parameters.batch_size.min(parameters.mzml_paths.len())
+    );
+    println!("{}", serde_json::to_string_pretty(parameters)?);
+    Ok(())
+}
+
+/// Build the fragment index of a configuration file, and save it for use as
+/// `database.prebuilt_index` by later searches
+fn build_index(matches: &ArgMatches) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let parameters = Input::database_from_arguments(matches)?;
+    let path = matches
+        .get_one::<String>("output")
+        .cloned()
+        .or_else(|| parameters.prebuilt_index.clone())
+        .context("either `-o/--output` or `database.prebuilt_index` must be set")?;
+
+    let fasta = Runner::read_fasta_paths(&parameters)?;
+    let database = parameters.build(fasta);
+    database
+        .save(&path)
+        .with_context(|| format!("Failed to save fragment index to `{}`", path))?;
+    info!(
+        "saved fragment index of {} fragments, {} peptides to `{}` in {}ms",
+        database.fragments.len(),
+        database.peptides.len(),
+        path,
+        (Instant::now() - start).as_millis()
+    );
+    Ok(())
+}
+
+/// Read the spectra of a file in any supported format, passing each to
+/// `process`
+fn read_spectra<F: FnMut(RawSpectrum)>(
+    path: &str,
+    file_id: usize,
+    sn: Option<u8>,
+    window: ScanWindow,
+    mut process: F,
+) -> Result<(), sage_cloudpath::Error> {
+    // Only mzML (and RAW) files are read up to the end of the window, spectra of other
+    // formats are all parsed
+    let mut process = |spectrum: RawSpectrum| {
+        if window.contains(&spectrum.id, spectrum.scan_start_time) {
+            process(spectrum)
+        }
+    };
+    let bruker_extensions = [".d", ".tdf", ".tdf_bin"];
+    let path_lower = path.to_lowercase();
+    if path_lower.ends_with(".mgf.gz") || path_lower.ends_with(".mgf") {
+        sage_cloudpath::util::read_mgf(path, file_id)
+            .map(|spectra| spectra.into_iter().for_each(&mut process))
+    } else if path_lower.ends_with(".mzxml.gz") || path_lower.ends_with(".mzxml") {
+        sage_cloudpath::util::read_mzxml(path, file_id)
+            .map(|spectra| spectra.into_iter().for_each(&mut process))
+    } else if bruker_extensions
+        .iter()
+        .any(|ext| path_lower.ends_with(ext))
+    {
+        sage_cloudpath::util::read_tdf(path, file_id)
+            .map(|spectra| spectra.into_iter().for_each(&mut process))
+    } else if path_lower.ends_with(".raw") {
+        // Converted to mzML, which is streamed like mzML files are
+        sage_cloudpath::util::read_raw_with(path, file_id, sn, window, &mut process).map(|_| ())
+    } else {
+        // mzML spectra are processed as they are streamed from the file, so
+        // that raw (e.g. profile) spectra are never all held in memory at once
+        sage_cloudpath::util::read_mzml_with(path, file_id, sn, window, &mut process).map(|_| ())
+    }
+}
+
+/// Search only one shard of the input files, see [`shard`]
+fn shard_arg() -> Arg {
+    Arg::new("shard")
+        .long("shard")
+        .value_name("INDEX/COUNT")
+        .value_parser(value_parser!(Shard))
+        .help(
+            "Search only the INDEX-th of COUNT blocks of the input files (e.g. 3/16), and \
+             save their results for `sage merge`, to distribute a search across machines",
+        )
+}
+
+/// Arguments of a search, accepted by `sage search` and by `sage` without a
+/// subcommand
+fn search_args(command: Command) -> Command {
+    command
         .arg(
             Arg::new("parameters")
                 .required(true)
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
-                .help("Path to configuration parameters (JSON file)")
+                .help("Path to configuration parameters (JSON, TOML or YAML file)")
                 .value_hint(ValueHint::FilePath),
         )
         .arg(
@@ -448,6 +2514,7 @@ fn main() -> anyhow::Result<()> {
             Arg::new("output_directory")
                 .short('o')
                 .long("output_directory")
+                .visible_alias("output-directory")
                 .value_parser(clap::builder::NonEmptyStringValueParser::new())
                 .help(
                     "Path where search and quant results will be written. \
@@ -455,13 +2522,95 @@ fn main() -> anyhow::Result<()> {
                 )
                 .value_hint(ValueHint::DirPath),
         )
+        .arg(
+            Arg::new("precursor-tol")
+                .long("precursor-tol")
+                .value_parser(value_parser!(Tolerance))
+                .allow_hyphen_values(true)
+                .help(
+                    "Precursor tolerance, e.g. `10ppm`, `-10,20ppm` or `0.5da`. \
+                     Overrides `precursor_tol` in the configuration file.",
+                )
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("fragment-tol")
+                .long("fragment-tol")
+                .value_parser(value_parser!(Tolerance))
+                .allow_hyphen_values(true)
+                .help(
+                    "Fragment tolerance, e.g. `10ppm`, `-10,20ppm` or `0.02da`. \
+                     Overrides `fragment_tol` in the configuration file.",
+                )
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_parser(value_parser!(u16).range(1..))
+                .help(
+                    "Number of threads (default = # of CPUs). Overrides `threads` in the \
+                     configuration file.",
+                )
+                .value_hint(ValueHint::Other),
+        )
         .arg(
             Arg::new("batch-size")
                 .long("batch-size")
                 .value_parser(value_parser!(u16).range(1..))
-                .help("Number of files to load and search in parallel (default = # of CPUs/2)")
+                .help(
+                    "Number of files to load and search in parallel (default = # of threads/2). \
+                     Overrides `batch_size` in the configuration file.",
+                )
+                .value_hint(ValueHint::Other),
+        )
+        .arg(
+            Arg::new("gpu")
+                .long("gpu")
+                .action(clap::ArgAction::SetTrue)
+                .help(
+                    "Count matched fragments on the GPU, if one is found. Sets `gpu: true`, \
+                     requires the `gpu` feature.",
+                ),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .action(clap::ArgAction::SetTrue)
+                .help(
+                    "Save the results of each batch of files, and resume an interrupted search \
+                     from them. Sets `checkpoint: true`.",
+                ),
+        )
+        .arg(
+            Arg::new("overwrite")
+                .long("overwrite")
+                .action(clap::ArgAction::SetTrue)
+                .help(
+                    "Overwrite the results of a previous search in the output directory, and \
+                     discard its checkpoints instead of resuming from them",
+                ),
+        )
+        .arg(
+            Arg::new("output-template")
+                .long("output-template")
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help(
+                    "Name of output files, e.g. `{stem}_{date}_{name}`, where `{name}` is the \
+                     default name of each file. Overrides `output_template` in the \
+                     configuration file.",
+                )
                 .value_hint(ValueHint::Other),
         )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue)
+                .help(
+                    "Validate the configuration, estimate the size of the database, and print \
+                     the resolved search parameters without searching",
+                ),
+        )
         .arg(
             Arg::new("parquet")
                 .long("parquet")
@@ -480,12 +2629,327 @@ fn main() -> anyhow::Result<()> {
                 .action(clap::ArgAction::SetTrue)
                 .help("Write percolator-compatible `.pin` output files"),
         )
+        .arg(
+            Arg::new("write-flashlfq")
+                .long("write-flashlfq")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write confident PSMs in the FlashLFQ generic input format"),
+        )
+        .arg(
+            Arg::new("write-msstats")
+                .long("write-msstats")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write LFQ or TMT intensities in MSstats/MSstatsTMT input format"),
+        )
+        .arg(
+            Arg::new("write-sqlite")
+                .long("write-sqlite")
+                .action(clap::ArgAction::SetTrue)
+                .help("Write all results into a single SQLite database"),
+        )
+        .arg(
+            Arg::new("diagnostics")
+                .long("diagnostics")
+                .action(clap::ArgAction::SetTrue)
+                .help(
+                    "Write target/decoy feature distributions and retention time model diagnostics",
+                ),
+        )
         .arg(
             Arg::new("disable-telemetry")
                 .long("disable-telemetry-i-dont-want-to-improve-sage")
                 .action(clap::ArgAction::SetFalse)
                 .help("Disable sending telemetry data"),
         )
+}
+
+fn main() {
+    if let Err(err) = try_main() {
+        eprintln!("Error: {:?}", err);
+        let code = err
+            .downcast_ref::<error::Error>()
+            .map_or(1, error::Error::exit_code);
+        std::process::exit(code);
+    }
+}
+
+fn try_main() -> anyhow::Result<()> {
+    env_logger::Builder::default()
+        .filter_level(log::LevelFilter::Error)
+        .parse_env(env_logger::Env::default().filter_or("SAGE_LOG", "error,sage=info"))
+        .init();
+
+    let mut matches = search_args(Command::new("sage"))
+        .version(clap::crate_version!())
+        .author("Michael Lazear <michaellazear92@gmail.com>")
+        .about("\u{1F52E} Sage \u{1F9D9} - Proteomics searching so fast it feels like magic!")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .arg(shard_arg())
+        .arg(
+            Arg::new("write-default-config")
+                .long("write-default-config")
+                .value_name("PATH")
+                .exclusive(true)
+                .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                .help(
+                    "Write a configuration file with the default value of every common \
+                     setting, and exit",
+                )
+                .value_hint(ValueHint::FilePath),
+        )
+        .subcommand(search_args(Command::new("search")).arg(shard_arg()).about(
+            "Search spectra against a FASTA database. This is the default, if no \
+                     subcommand is given",
+        ))
+        .subcommand(
+            ["batch-size", "checkpoint", "dry-run", "gpu"]
+                .into_iter()
+                .fold(search_args(Command::new("merge")), |command, id| {
+                    command.mut_arg(id, |arg| arg.hide(true))
+                })
+                .about(
+                    "Combine the results of a search sharded with `--shard`, read from its \
+                     `output_directory`, then rescore them and write all outputs. Without \
+                     `--shards`, pool the `.pin` files of separate searches, rescore their \
+                     PSMs together and write experiment-wide q-values to `merged.sage.tsv`",
+                )
+                .mut_arg("parameters", |arg| {
+                    arg.help(
+                        "Path to configuration parameters (JSON, TOML or YAML file), or the \
+                         first `.pin` file to pool without `--shards`",
+                    )
+                })
+                .mut_arg("mzml_paths", |arg| {
+                    arg.help(
+                        "Paths to mzML files to process, or more `.pin` files to pool \
+                         without `--shards`",
+                    )
+                })
+                .arg(
+                    Arg::new("shards")
+                        .long("shards")
+                        .value_name("COUNT")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Number of shards the search was split into"),
+                ),
+        )
+        .subcommand(
+            Command::new("index")
+                .about(
+                    "Build the fragment index of a configuration file, and save it for use as \
+                     `database.prebuilt_index` in later searches",
+                )
+                .arg(
+                    Arg::new("parameters")
+                        .required(true)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help("Path to configuration parameters (JSON, TOML or YAML file)")
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("fasta")
+                        .short('f')
+                        .long("fasta")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help(
+                            "Path to FASTA database. Overrides the FASTA file \
+                             specified in the configuration file.",
+                        )
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help(
+                            "Path where the fragment index will be written \
+                             (default = `database.prebuilt_index`)",
+                        )
+                        .value_hint(ValueHint::FilePath),
+                ),
+        )
+        .subcommand(
+            search_args(Command::new("watch"))
+                .about(
+                    "Watch directories for new spectra files, e.g. during acquisition, and \
+                     search them once they are written. The combined results of all files \
+                     are updated after each new file",
+                )
+                .mut_arg("mzml_paths", |arg| {
+                    arg.required(true)
+                        .help("Directories, or quoted glob patterns, of spectra files to watch")
+                        .value_hint(ValueHint::DirPath)
+                })
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Time between two checks for new files (default = 10)"),
+                )
+                .arg(
+                    Arg::new("settle")
+                        .long("settle")
+                        .value_name("SECONDS")
+                        .value_parser(value_parser!(u64))
+                        .help(
+                            "Time for which a new file must be unchanged before it is \
+                             searched, i.e. considered completely written (default = 30)",
+                        ),
+                )
+                .arg(
+                    Arg::new("idle-exit")
+                        .long("idle-exit")
+                        .value_name("SECONDS")
+                        .value_parser(value_parser!(u64))
+                        .help("Exit once no new files appeared for this long (default = never)"),
+                ),
+        )
+        .subcommand(
+            // Options for spectra files and outputs do not apply to serve mode
+            [
+                "mzml_paths",
+                "output_directory",
+                "batch-size",
+                "checkpoint",
+                "overwrite",
+                "output-template",
+                "dry-run",
+                "gpu",
+                "parquet",
+                "annotate-matches",
+                "write-pin",
+                "write-flashlfq",
+                "write-msstats",
+                "write-sqlite",
+                "diagnostics",
+                "disable-telemetry",
+            ]
+            .into_iter()
+            .fold(search_args(Command::new("serve")), |command, id| {
+                command.mut_arg(id, |arg| arg.hide(true))
+            })
+            .about(
+                "Build the database once, and score spectra sent over stdin or a TCP \
+                 socket, e.g. for real-time search-driven acquisition",
+            )
+            .arg(
+                Arg::new("listen")
+                    .long("listen")
+                    .value_name("ADDRESS")
+                    .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                    .help(
+                        "Accept TCP connections on this address, e.g. 127.0.0.1:7878 \
+                             (default = read spectra from stdin, and write PSMs to stdout)",
+                    ),
+            )
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .value_parser(["json", "msgpack"])
+                    .help(
+                        "Encoding of spectra and PSMs: one JSON object per line, or \
+                             length-prefixed MessagePack maps (default = json)",
+                    ),
+            ),
+        )
+        .subcommand(
+            // Spectra files are only read: options of the search and of its
+            // outputs do not apply
+            [
+                "fasta",
+                "precursor-tol",
+                "fragment-tol",
+                "checkpoint",
+                "overwrite",
+                "dry-run",
+                "gpu",
+                "parquet",
+                "annotate-matches",
+                "write-pin",
+                "write-flashlfq",
+                "write-msstats",
+                "write-sqlite",
+                "diagnostics",
+                "disable-telemetry",
+            ]
+            .into_iter()
+            .fold(search_args(Command::new("features")), |command, id| {
+                command.mut_arg(id, |arg| arg.hide(true))
+            })
+            .about(
+                "Detect MS1 features (isotopic envelopes traced over retention time) in \
+                 spectra files without searching them, and write them to `ms1_features.tsv`",
+            ),
+        )
+        .subcommand(
+            search_args(Command::new("library"))
+                .about(
+                    "Search spectra, and build a spectral library from the confidently \
+                     identified precursors of all files, e.g. to analyze DIA runs with the \
+                     results of DDA runs. Uses the `library` settings of the configuration \
+                     file, or the defaults",
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["sage", "dia"])
+                        .help(
+                            "Columns of the library: `library.sage.tsv`, which Sage can \
+                             search, or `library.dia.tsv` for DIA-NN and Spectronaut (written \
+                             as parquet with `--parquet`). Overrides `library.format`",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("report")
+                .about(
+                    "Write a self-contained HTML quality control report for each file of a \
+                     search: identifications, score distributions, mass errors, retention \
+                     time model fit and total ion current",
+                )
+                .arg(
+                    Arg::new("results")
+                        .required(true)
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help("Path to the `results.json` file of the search")
+                        .value_hint(ValueHint::FilePath),
+                )
+                .arg(
+                    Arg::new("output_directory")
+                        .short('o')
+                        .long("output_directory")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help(
+                            "Path where the reports will be written \
+                             (default = the directory of `results.json`)",
+                        )
+                        .value_hint(ValueHint::DirPath),
+                ),
+        )
+        .subcommand(Command::new("schema").about(
+            "Print a JSON Schema of the configuration file, and the columns of each output file",
+        ))
+        .subcommand(
+            Command::new("test-data")
+                .about(
+                    "Search a small bundled dataset, and check the results against known-good \
+                     values to validate the installation",
+                )
+                .arg(
+                    Arg::new("output_directory")
+                        .short('o')
+                        .long("output_directory")
+                        .value_parser(clap::builder::NonEmptyStringValueParser::new())
+                        .help(
+                            "Path where the test dataset and search results will be written \
+                             (default = a temporary directory)",
+                        )
+                        .value_hint(ValueHint::DirPath),
+                ),
+        )
         .help_template(
             "{usage-heading} {usage}\n\n\
              {about-with-newline}\n\
@@ -494,20 +2958,105 @@ fn main() -> anyhow::Result<()> {
         )
         .get_matches();
 
-    let parallel = matches
-        .get_one::<u16>("batch-size")
-        .copied()
-        .unwrap_or_else(|| num_cpus::get() as u16 / 2) as usize;
+    if let Some(path) = matches.get_one::<String>("write-default-config") {
+        let path = path.parse::<CloudPath>()?;
+        path.write_bytes_sync(input::DEFAULT_CONFIG.as_bytes().to_vec())?;
+        info!("wrote default configuration to `{}`", path);
+        return Ok(());
+    }
+
+    let (matches, merge, library) = match matches.remove_subcommand() {
+        Some((name, _)) if name == "schema" => {
+            println!("{}", serde_json::to_string_pretty(&schema::build()?)?);
+            return Ok(());
+        }
+        Some((name, matches)) if name == "test-data" => {
+            let directory = matches
+                .get_one::<String>("output_directory")
+                .map(std::path::PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join("sage-test-data"));
+            info!("test data: writing to `{}`", directory.display());
+            return test_data::run(&directory);
+        }
+        Some((name, matches)) if name == "index" => return build_index(&matches),
+        Some((name, matches)) if name == "watch" => return watch::run(matches),
+        Some((name, matches)) if name == "serve" => return serve::run(matches),
+        Some((name, matches)) if name == "features" => return features::run(matches),
+        Some((name, matches)) if name == "report" => return report::run(&matches),
+        Some((name, matches)) if name == "merge" => match matches.get_one::<u64>("shards") {
+            Some(&count) => (matches, Some(count as usize), false),
+            None => return pin_merge::run(&matches),
+        },
+        Some((name, matches)) if name == "library" => (matches, None, true),
+        Some((_, matches)) => (matches, None, false),
+        None => (matches, None, false),
+    };
 
     let parquet = matches.get_one::<bool>("parquet").copied().unwrap_or(false);
+    let overwrite = matches
+        .get_one::<bool>("overwrite")
+        .copied()
+        .unwrap_or(false);
+    let dry_run = matches.get_one::<bool>("dry-run").copied().unwrap_or(false);
     let send_telemetry = matches
         .get_one::<bool>("disable-telemetry")
         .copied()
         .unwrap_or(true);
 
-    let input = Input::from_arguments(matches)?;
+    // `merge` does not accept `--shard`
+    let shard = matches
+        .try_get_one::<Shard>("shard")
+        .ok()
+        .flatten()
+        .copied();
+
+    let input = match library {
+        true => Input::library_from_arguments(matches)?,
+        false => Input::from_arguments(matches)?,
+    };
+
+    let parameters = input.build()?;
+    let parallel = parameters.batch_size;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(parameters.threads)
+        .build_global()?;
+    info!(
+        "using {} threads, searching {} files at a time",
+        parameters.threads, parallel
+    );
+    if shard.is_some() || merge.is_some() {
+        Shard::check(&parameters)?;
+    }
+    // Shards only write their own results file, which is replaced when a
+    // failed shard is searched again
+    if shard.is_none() {
+        check_existing_results(&parameters, overwrite)?;
+    }
+    if dry_run {
+        if let Some(shard) = shard {
+            let files = shard.files(parameters.mzml_paths.len());
+            info!(
+                "dry run: shard {} searches files {} .. {}",
+                shard, files.start, files.end
+            );
+        }
+        return plan_search(&parameters);
+    }
 
-    let runner = input.build().and_then(Runner::new)?;
+    if let Some(shard) = shard {
+        return Runner::new(parameters, None)?.search_shard(shard, parallel);
+    }
+    let runner = match merge {
+        Some(count) => {
+            let merged = Shard::merge(&parameters, count)?;
+            info!("merged {} PSMs of {} shards", merged.features.len(), count);
+            Runner::new(parameters, None)?.merge(merged)
+        }
+        None => {
+            let checkpoint = Checkpoint::open(&parameters, overwrite)?;
+            Runner::new(parameters, checkpoint)?
+        }
+    };
 
     let tel = runner.run(parallel, parquet)?;
 
@@ -517,3 +3066,115 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_files_drop_truncated_file() -> anyhow::Result<()> {
+        let directory =
+            std::env::temp_dir().join(format!("sage-stream-truncated-{}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+
+        // Many copies of the bundled spectrum, cut short in the middle of the
+        // spectrum list so that some spectra are scored before reading fails
+        let mzml = include_str!("../../../tests/LQSRPAAPPAPGPGQLTLR.mzML");
+        let start = mzml.find("<spectrum ").unwrap();
+        let end = mzml.find("</spectrumList>").unwrap();
+        let spectra = (0..600)
+            .map(|scan| mzml[start..end].replace("scan=30069", &format!("scan={}", scan)))
+            .collect::<String>();
+        let truncated = format!("{}{}", &mzml[..start], spectra);
+        let truncated = &truncated[..truncated.len() * 3 / 4];
+
+        let good = directory.join("good.mzML");
+        let bad = directory.join("truncated.mzML");
+        let fasta = directory.join("Q99536.fasta");
+        std::fs::write(&good, mzml)?;
+        std::fs::write(&bad, truncated)?;
+        std::fs::write(&fasta, include_str!("../../../tests/Q99536.fasta"))?;
+
+        let mut config: serde_json::Value =
+            serde_json::from_str(include_str!("../../../tests/config-cli.json"))?;
+        config["database"]["fasta"] = fasta.to_string_lossy().into();
+        let paths = [&good, &bad]
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        config["mzml_paths"] = serde_json::json!(paths);
+        config["output_directory"] = directory.to_string_lossy().into();
+        let input: input::Input = serde_json::from_value(config)?;
+        let runner = input.build().and_then(|p| Runner::new(p, None))?;
+
+        let scorer = runner.scorer(&runner.database, runner.prefilter.as_ref());
+        let results = runner.stream_files(&scorer, &paths, 0, 2, None);
+        std::fs::remove_dir_all(&directory)?;
+
+        assert_eq!(results.failed.len(), 1);
+        assert_eq!(results.failed[0].path, paths[1]);
+        assert_eq!(results.runs.len(), 1);
+        assert!(!results.features.is_empty());
+        assert!(results.features.iter().all(|feat| feat.file_id == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn stream_files_keep_ms1_if_needed() -> anyhow::Result<()> {
+        let directory =
+            std::env::temp_dir().join(format!("sage-stream-ms1-{}", std::process::id()));
+        std::fs::create_dir_all(&directory)?;
+
+        // The bundled spectrum, preceded by an MS1 copy of itself
+        let mzml = include_str!("../../../tests/LQSRPAAPPAPGPGQLTLR.mzML");
+        let start = mzml.find("<spectrum ").unwrap();
+        let end = mzml.find("</spectrumList>").unwrap();
+        let precursors = mzml.find("<precursorList").unwrap();
+        let peaks = mzml.find("<binaryDataArrayList").unwrap();
+        let ms1 = format!("{}{}", &mzml[start..precursors], &mzml[peaks..end])
+            .replace(
+                "name=\"ms level\" value=\"2\"",
+                "name=\"ms level\" value=\"1\"",
+            )
+            .replace("scan=30069", "scan=30068");
+        let path = directory.join("ms1.mzML");
+        let fasta = directory.join("Q99536.fasta");
+        std::fs::write(
+            &path,
+            format!("{}{}{}", &mzml[..start], ms1, &mzml[start..]),
+        )?;
+        std::fs::write(&fasta, include_str!("../../../tests/Q99536.fasta"))?;
+
+        let paths = vec![path.to_string_lossy().to_string()];
+        let search = |lfq: bool| -> anyhow::Result<SageResults> {
+            let mut config: serde_json::Value =
+                serde_json::from_str(include_str!("../../../tests/config-cli.json"))?;
+            config["database"]["fasta"] = fasta.to_string_lossy().into();
+            config["mzml_paths"] = serde_json::json!(paths);
+            config["output_directory"] = directory.to_string_lossy().into();
+            config["quant"] = serde_json::json!({ "lfq": lfq });
+            let input: input::Input = serde_json::from_value(config)?;
+            let runner = input.build().and_then(|p| Runner::new(p, None))?;
+            let scorer = runner.scorer(&runner.database, runner.prefilter.as_ref());
+            Ok(runner.stream_files(&scorer, &paths, 0, 1, None))
+        };
+        let dropped = search(false);
+        let kept = search(true);
+        std::fs::remove_dir_all(&directory)?;
+        let (dropped, kept) = (dropped?, kept?);
+
+        // Without LFQ, MS1 spectra are only used for isolation purity
+        assert!(dropped.ms1.is_empty());
+        assert_eq!(kept.ms1.len(), 1);
+        assert!(!dropped.features.is_empty());
+        assert!(dropped
+            .features
+            .iter()
+            .all(|feat| feat.isolation_purity.is_some()));
+        assert!(kept
+            .features
+            .iter()
+            .all(|feat| feat.isolation_purity.is_none()));
+        Ok(())
+    }
+}
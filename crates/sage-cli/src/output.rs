@@ -5,12 +5,634 @@ use rayon::prelude::*;
 use sage_core::ion_series::Kind;
 use sage_core::scoring::Fragments;
 use sage_core::{
-    lfq::{Peak, PrecursorId},
+    crosslink::CrosslinkMatch,
+    faims::compensation_voltages,
+    fasta::Fasta,
+    inference::ProteinGroup,
+    lfq::{Peak, PrecursorId, ProteinLfqQuant},
+    library::{LibraryEntry, LibraryFragment},
+    library_search::LibraryRecord,
+    mass::PROTON,
+    ml::{
+        diagnostics::Diagnostics,
+        linear_discriminant::{feature_matrix, FEATURE_NAMES},
+        mobility_model::MobilityModel,
+        pooled::PooledPsm,
+        qvalue::CompetitionReport,
+    },
+    ms1_features::Ms1FeatureMap,
+    prm::{PrmResult, PrmTarget, TargetFragment},
+    qc::RunMetrics,
     scoring::Feature,
-    tmt::TmtQuant,
+    silac::{SilacPair, SilacPeptide},
+    tmt::{ProteinTmtQuant, TmtQuant},
 };
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
+use crate::input::TmtDesign;
+use crate::pin_merge::PinRecord;
 use crate::Runner;
+use sage_cloudpath::CloudPath;
+
+/// Name of an LC-MS run: its file name, without (compression and) file
+/// extensions
+pub(crate) fn run_name(filename: &str) -> &str {
+    let filename = filename.strip_suffix(".gz").unwrap_or(filename);
+    filename.rsplit_once('.').map_or(filename, |(stem, _)| stem)
+}
+
+fn ion_type(kind: Kind) -> &'static str {
+    match kind {
+        Kind::A => "a",
+        Kind::B => "b",
+        Kind::C => "c",
+        Kind::X => "x",
+        Kind::Y => "y",
+        Kind::Z => "z",
+    }
+}
+
+/// Columns of `results.sage.tsv`
+pub const RESULTS_COLUMNS: &[&str] = &[
+    "psm_id",
+    "peptide",
+    "proteins",
+    "num_proteins",
+    "shared_peptide",
+    "filename",
+    "scannr",
+    "rank",
+    "label",
+    "expmass",
+    "calcmass",
+    "charge",
+    "peptide_len",
+    "missed_cleavages",
+    "semi_enzymatic",
+    "isotope_error",
+    "precursor_ppm",
+    "mass_offset",
+    "fragment_ppm",
+    "hyperscore",
+    "delta_next",
+    "delta_best",
+    "rt",
+    "aligned_rt",
+    "predicted_rt",
+    "delta_rt_model",
+    "matched_peaks",
+    "longest_b",
+    "longest_y",
+    "longest_y_pct",
+    "fragment_coverage",
+    "matched_intensity_pct",
+    "scored_candidates",
+    "poisson",
+    "sage_discriminant_score",
+    "posterior_error",
+    "spectrum_q",
+    "peptide_q",
+    "protein_q",
+    "ms2_intensity",
+    "isolation_purity",
+    "localization_probability",
+    "localization_sites",
+    "silac_channel",
+    "glycan",
+    "search_pass",
+    "spectral_angle",
+    "irt",
+    "ion_mobility",
+    "predicted_mobility",
+    "delta_mobility_model",
+    "compensation_voltage",
+    "predicted_spectral_angle",
+    "predicted_correlation",
+];
+
+/// Columns of `matched_fragments.sage.tsv`
+pub const FRAGMENT_COLUMNS: &[&str] = &[
+    "psm_id",
+    "fragment_type",
+    "fragment_ordinals",
+    "fragment_charge",
+    "fragment_mz_calculated",
+    "fragment_mz_experimental",
+    "fragment_intensity",
+    "fragment_ppm_error",
+];
+
+/// Columns of `flashlfq.tsv`, the generic PSM input format of FlashLFQ
+pub const FLASHLFQ_COLUMNS: &[&str] = &[
+    "File Name",
+    "Base Sequence",
+    "Full Sequence",
+    "Peptide Monoisotopic Mass",
+    "Scan Retention Time",
+    "Precursor Charge",
+    "Protein Accession",
+];
+
+/// Columns of `msstats.tsv`, MSstats input for label-free quantification
+pub const MSSTATS_COLUMNS: &[&str] = &[
+    "ProteinName",
+    "PeptideSequence",
+    "PrecursorCharge",
+    "FragmentIon",
+    "ProductCharge",
+    "IsotopeLabelType",
+    "Condition",
+    "BioReplicate",
+    "Run",
+    "Intensity",
+];
+
+/// Columns of `msstats_tmt.tsv`, MSstatsTMT input without the annotation
+/// columns (`Mixture`, `TechRepMixture`, `Condition`, `BioReplicate`)
+pub const MSSTATS_TMT_COLUMNS: &[&str] = &[
+    "ProteinName",
+    "PeptideSequence",
+    "Charge",
+    "PSM",
+    "Run",
+    "Channel",
+    "Intensity",
+];
+
+/// Columns of `results.sage.pin`
+pub const PIN_COLUMNS: &[&str] = &[
+    "SpecId",
+    "Label",
+    "ScanNr",
+    "ExpMass",
+    "CalcMass",
+    "FileName",
+    "retentiontime",
+    "rank",
+    "z=2",
+    "z=3",
+    "z=4",
+    "z=5",
+    "z=6",
+    "z=other",
+    "peptide_len",
+    "missed_cleavages",
+    "semi_enzymatic",
+    "isotope_error",
+    "ln(precursor_ppm)",
+    "fragment_ppm",
+    "ln(hyperscore)",
+    "ln(delta_next)",
+    "ln(delta_best)",
+    "aligned_rt",
+    "predicted_rt",
+    "sqrt(delta_rt_model)",
+    "delta_mobility_model",
+    "predicted_spectral_angle",
+    "predicted_correlation",
+    "matched_peaks",
+    "longest_b",
+    "longest_y",
+    "longest_y_pct",
+    "fragment_coverage",
+    "ln(matched_intensity_pct)",
+    "scored_candidates",
+    "ln(-poisson)",
+    "posterior_error",
+    "num_proteins",
+    "shared_peptide",
+    "Peptide",
+    "Proteins",
+];
+
+/// Columns of `results.sage.pin` that are not rescoring features, and are
+/// always written: Percolator and mokapot identify PSMs and spectra by these
+pub const PIN_FIXED_COLUMNS: &[&str] = &[
+    "SpecId", "Label", "ScanNr", "ExpMass", "CalcMass", "FileName", "Peptide", "Proteins",
+];
+
+/// Indices of the `PIN_COLUMNS` to write: the fixed columns, and either the
+/// selected feature columns or all of them
+pub fn pin_columns(features: Option<&[String]>) -> Vec<usize> {
+    PIN_COLUMNS
+        .iter()
+        .enumerate()
+        .filter(|(_, column)| {
+            PIN_FIXED_COLUMNS.contains(column)
+                || features.map_or(true, |features| features.iter().any(|f| f == *column))
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// Columns of `merged.sage.tsv`, written by `sage merge` from `.pin` files
+pub const MERGED_COLUMNS: &[&str] = &[
+    "psm_id",
+    "filename",
+    "scannr",
+    "label",
+    "peptide",
+    "proteins",
+    "expmass",
+    "calcmass",
+    "sage_discriminant_score",
+    "posterior_error",
+    "spectrum_q",
+    "peptide_q",
+    "protein_q",
+];
+
+/// Leading columns of `features.sage.tsv`, followed by one column per
+/// rescoring feature and the discriminant score
+pub const FEATURE_MATRIX_COLUMNS: &[&str] = &["psm_id", "label", "filename", "scannr", "peptide"];
+
+/// Leading columns of `tmt.tsv`, followed by one column per reporter ion
+pub const TMT_COLUMNS: &[&str] = &[
+    "filename",
+    "scannr",
+    "ion_injection_time",
+    "psm_id",
+    "peptide",
+    "spectrum_q",
+    "sps_purity",
+];
+
+/// Leading columns of `tmt_proteins.tsv`, followed by one column per reporter
+/// ion for each plex
+pub const TMT_PROTEIN_COLUMNS: &[&str] = &["protein"];
+
+/// Leading columns of `lfq_proteins.tsv`, followed by one column per file
+pub const LFQ_PROTEIN_COLUMNS: &[&str] = &["protein", "precursors"];
+
+/// Leading columns of `lfq.tsv`, followed by one column per file
+pub const LFQ_COLUMNS: &[&str] = &[
+    "peptide",
+    "charge",
+    "proteins",
+    "q_value",
+    "score",
+    "spectral_angle",
+];
+
+/// Columns of `silac.tsv`
+pub const SILAC_COLUMNS: &[&str] = &[
+    "psm_id",
+    "peptide",
+    "proteins",
+    "filename",
+    "charge",
+    "channel",
+    "light_mz",
+    "heavy_mz",
+    "scans",
+    "pair_found",
+    "light_intensity",
+    "heavy_intensity",
+    "heavy_light_ratio",
+];
+
+/// Columns of `silac_peptides.tsv`
+pub const SILAC_PEPTIDE_COLUMNS: &[&str] = &[
+    "peptide",
+    "proteins",
+    "filename",
+    "psms",
+    "pairs",
+    "light_intensity",
+    "heavy_intensity",
+    "heavy_light_ratio",
+    "missing_partner",
+];
+
+/// Columns of `crosslinks.tsv`
+pub const CROSSLINK_COLUMNS: &[&str] = &[
+    "filename",
+    "scannr",
+    "charge",
+    "expmass",
+    "calcmass",
+    "rt",
+    "alpha_peptide",
+    "alpha_proteins",
+    "alpha_site",
+    "beta_peptide",
+    "beta_proteins",
+    "beta_site",
+    "alpha_matched_peaks",
+    "beta_matched_peaks",
+    "score",
+    "label",
+    "crosslink_q",
+];
+
+/// Columns of `rt_diagnostics.tsv`
+pub const RT_DIAGNOSTIC_COLUMNS: &[&str] = &[
+    "psm_id",
+    "peptide",
+    "filename",
+    "label",
+    "spectrum_q",
+    "rt",
+    "aligned_rt",
+    "predicted_rt",
+];
+
+/// Columns of `protein_groups.tsv`
+pub const PROTEIN_GROUP_COLUMNS: &[&str] = &[
+    "leading_protein",
+    "proteins",
+    "num_proteins",
+    "label",
+    "peptides",
+    "unique_peptides",
+    "razor_peptides",
+    "psms",
+    "score",
+    "protein_q",
+];
+
+/// Columns of `library.sage.tsv`
+pub const LIBRARY_COLUMNS: &[&str] = &[
+    "peptide",
+    "stripped_peptide",
+    "proteins",
+    "charge",
+    "precursor_mz",
+    "rt",
+    "replicates",
+    "psms",
+    "fragment_type",
+    "fragment_ordinal",
+    "fragment_charge",
+    "fragment_mz",
+    "relative_intensity",
+];
+
+/// Columns of `library.dia.tsv` (and `library.dia.parquet`), in the generic
+/// spectral library format of DIA-NN and Spectronaut
+pub const DIA_LIBRARY_COLUMNS: &[&str] = &[
+    "ModifiedPeptide",
+    "StrippedPeptide",
+    "PrecursorCharge",
+    "PrecursorMz",
+    "iRT",
+    "UniProtIds",
+    "FragmentType",
+    "FragmentNumber",
+    "FragmentCharge",
+    "FragmentLossType",
+    "FragmentMz",
+    "RelativeIntensity",
+];
+
+/// Columns of `prm.tsv`
+pub const PRM_COLUMNS: &[&str] = &[
+    "peptide",
+    "charge",
+    "precursor_mz",
+    "filename",
+    "reference",
+    "scans",
+    "apex_rt",
+    "start_rt",
+    "end_rt",
+    "area",
+    "fragments_detected",
+    "coelution",
+    "dot_product",
+    "fragment_areas",
+];
+
+/// Columns of `ms1_features.tsv`
+pub const MS1_FEATURE_COLUMNS: &[&str] = &[
+    "filename",
+    "mz",
+    "charge",
+    "mass",
+    "rt_start",
+    "rt_apex",
+    "rt_end",
+    "apex_intensity",
+    "area",
+    "scans",
+    "envelope_fit",
+];
+
+/// A single fragment ion of `library.sage.tsv`
+#[derive(Deserialize)]
+struct LibraryRow {
+    peptide: String,
+    charge: u8,
+    precursor_mz: f32,
+    fragment_type: Kind,
+    fragment_ordinal: i32,
+    fragment_charge: u8,
+    fragment_mz: f32,
+    relative_intensity: f32,
+}
+
+/// Peptide, charge, precursor m/z and fragment ions of a library spectrum
+type LibraryRows = (String, u8, f32, Vec<LibraryRow>);
+
+/// Group the fragment ions of a spectral library written by Sage
+/// (`library.sage.tsv`) by precursor
+fn read_library(contents: &str) -> anyhow::Result<Vec<LibraryRows>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(contents.as_bytes());
+
+    let mut spectra: Vec<LibraryRows> = Vec::new();
+    let mut index: HashMap<(String, u8), usize> = HashMap::new();
+    for row in rdr.deserialize() {
+        let row: LibraryRow = row?;
+        let idx = *index
+            .entry((row.peptide.clone(), row.charge))
+            .or_insert_with(|| {
+                spectra.push((
+                    row.peptide.clone(),
+                    row.charge,
+                    row.precursor_mz,
+                    Vec::new(),
+                ));
+                spectra.len() - 1
+            });
+        spectra[idx].3.push(row);
+    }
+    Ok(spectra)
+}
+
+/// Read the spectra of a spectral library written by Sage (`library.sage.tsv`)
+/// as PRM targets, keeping the `fragments` most intense fragment ions of each
+pub fn parse_library(contents: &str, fragments: usize) -> anyhow::Result<Vec<PrmTarget>> {
+    Ok(read_library(contents)?
+        .into_iter()
+        .map(|(peptide, charge, precursor_mz, rows)| {
+            let frags = rows
+                .into_iter()
+                .map(|row| TargetFragment {
+                    kind: row.fragment_type,
+                    ordinal: row.fragment_ordinal,
+                    charge: row.fragment_charge,
+                    mz: row.fragment_mz,
+                    intensity: row.relative_intensity,
+                })
+                .collect();
+            PrmTarget::from_library(peptide, charge, precursor_mz, frags, fragments)
+        })
+        .collect())
+}
+
+/// Read the spectra of a spectral library written by Sage (`library.sage.tsv`)
+/// for spectral library search
+pub fn parse_library_records(contents: &str) -> anyhow::Result<Vec<LibraryRecord>> {
+    Ok(read_library(contents)?
+        .into_iter()
+        .map(|(peptide, charge, _, rows)| LibraryRecord {
+            peptide,
+            charge,
+            fragments: rows
+                .into_iter()
+                .map(|row| LibraryFragment {
+                    kind: row.fragment_type,
+                    ordinal: row.fragment_ordinal,
+                    charge: row.fragment_charge as i32,
+                    mz: row.fragment_mz,
+                    intensity: row.relative_intensity,
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+#[derive(Serialize, JsonSchema)]
+/// Quality control metrics for a search, written to `qc.json`
+pub struct QcReport {
+    /// Target/decoy competition at the PSM level
+    pub competition: CompetitionReport,
+    /// Spectrum quality and identification metrics of each input file
+    pub runs: Vec<RunReport>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+/// Quality control metrics of an input file
+pub struct RunReport {
+    pub filename: String,
+    #[serde(flatten)]
+    pub spectra: RunMetrics,
+    /// Rank-1 target PSMs at 1% spectrum-level FDR
+    pub identified_spectra: usize,
+    /// Fraction of the MS2 spectra that were identified
+    pub identification_rate: f32,
+    /// Median fraction of the MS2 intensity explained by matched b and y
+    /// ions, over identified spectra
+    pub median_explained_intensity: Option<f32>,
+}
+
+/// Summarize the spectra and identifications of each file, which must have
+/// spectrum-level q-values assigned
+pub fn run_reports(
+    runs: &[RunMetrics],
+    features: &[Feature],
+    filenames: &[String],
+) -> Vec<RunReport> {
+    let mut explained = vec![Vec::new(); filenames.len()];
+    for feat in features {
+        if feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= 0.01 {
+            explained[feat.file_id].push(feat.matched_intensity_pct / 100.0);
+        }
+    }
+    let mut reports = runs
+        .iter()
+        .map(|run| {
+            let explained = &mut explained[run.file_id];
+            RunReport {
+                filename: filenames[run.file_id].clone(),
+                spectra: run.clone(),
+                identified_spectra: explained.len(),
+                identification_rate: explained.len() as f32 / run.ms2_spectra.max(1) as f32,
+                median_explained_intensity: sage_core::qc::median(explained),
+            }
+        })
+        .collect::<Vec<_>>();
+    reports.sort_by_key(|report| report.spectra.file_id);
+    reports
+}
+
+/// Write the MS1 features of all files to `path`, as `ms1_features.tsv`. This
+/// does not require a search, see `sage features`
+pub fn write_ms1_features(
+    path: &CloudPath,
+    map: &Ms1FeatureMap,
+    filenames: &[String],
+) -> anyhow::Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(vec![]);
+    wtr.write_byte_record(&ByteRecord::from(MS1_FEATURE_COLUMNS.to_vec()))?;
+    for feature in map.features() {
+        let mut record = ByteRecord::new();
+        record.push_field(filenames[feature.file_id].as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.mz).as_bytes());
+        record.push_field(itoa::Buffer::new().format(feature.charge).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.mass()).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.rt_start).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.rt_apex).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.rt_end).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.apex_intensity).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.area).as_bytes());
+        record.push_field(itoa::Buffer::new().format(feature.trace.len()).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.envelope_fit).as_bytes());
+        wtr.write_byte_record(&record)?;
+    }
+    wtr.flush()?;
+    path.write_bytes_sync(wtr.into_inner()?)?;
+    Ok(())
+}
+
+/// Write PSMs pooled from `.pin` files, by decreasing discriminant score
+pub fn write_merged_psms(
+    path: &CloudPath,
+    psms: &[PooledPsm],
+    records: &[PinRecord],
+    filenames: &[String],
+) -> anyhow::Result<()> {
+    let mut order = (0..psms.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        psms[b]
+            .discriminant_score
+            .total_cmp(&psms[a].discriminant_score)
+    });
+
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(vec![]);
+    wtr.write_byte_record(&ByteRecord::from(MERGED_COLUMNS.to_vec()))?;
+    for idx in order {
+        let (psm, pin) = (&psms[idx], &records[idx]);
+        let mut record = ByteRecord::new();
+        record.push_field(pin.psm_id.as_bytes());
+        record.push_field(filenames[psm.file_id].as_bytes());
+        record.push_field(psm.spec_id.as_bytes());
+        record.push_field(match psm.decoy {
+            true => b"-1",
+            false => b"1",
+        });
+        record.push_field(psm.peptide.as_bytes());
+        record.push_field(psm.proteins.as_bytes());
+        record.push_field(pin.expmass.as_bytes());
+        record.push_field(pin.calcmass.as_bytes());
+        record.push_field(ryu::Buffer::new().format(psm.discriminant_score).as_bytes());
+        record.push_field(ryu::Buffer::new().format(psm.posterior_error).as_bytes());
+        record.push_field(ryu::Buffer::new().format(psm.spectrum_q).as_bytes());
+        record.push_field(ryu::Buffer::new().format(psm.peptide_q).as_bytes());
+        record.push_field(ryu::Buffer::new().format(psm.protein_q).as_bytes());
+        wtr.write_byte_record(&record)?;
+    }
+    wtr.flush()?;
+    path.write_bytes_sync(wtr.into_inner()?)?;
+    Ok(())
+}
 
 impl Runner {
     pub fn serialize_feature(&self, feature: &Feature, filenames: &[String]) -> csv::ByteRecord {
@@ -30,6 +652,11 @@ impl Runner {
                 .format(peptide.proteins.len())
                 .as_bytes(),
         );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.shared() as u8)
+                .as_bytes(),
+        );
         record.push_field(filenames[feature.file_id].as_bytes());
         record.push_field(feature.spec_id.as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.rank).as_bytes());
@@ -50,6 +677,7 @@ impl Runner {
         );
         record.push_field(ryu::Buffer::new().format(feature.isotope_error).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.delta_mass).as_bytes());
+        record.push_field(ryu::Buffer::new().format(feature.mass_offset).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.average_ppm).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.hyperscore).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.delta_next).as_bytes());
@@ -62,6 +690,11 @@ impl Runner {
         record.push_field(itoa::Buffer::new().format(feature.longest_b).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_y).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.longest_y_pct).as_bytes());
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.fragment_coverage)
+                .as_bytes(),
+        );
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.matched_intensity_pct)
@@ -87,29 +720,80 @@ impl Runner {
         record.push_field(ryu::Buffer::new().format(feature.peptide_q).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.protein_q).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.ms2_intensity).as_bytes());
+        match feature.isolation_purity {
+            Some(purity) => record.push_field(ryu::Buffer::new().format(purity).as_bytes()),
+            None => record.push_field(b""),
+        }
+        match feature.localization_probability {
+            Some(probability) => {
+                record.push_field(ryu::Buffer::new().format(probability).as_bytes())
+            }
+            None => record.push_field(b""),
+        }
+        record.push_field(
+            feature
+                .localization_sites
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        match feature.silac_channel {
+            Some(channel) => record.push_field(channel.to_string().as_bytes()),
+            None => record.push_field(b""),
+        }
+        record.push_field(feature.glycan.as_deref().unwrap_or_default().as_bytes());
+        record.push_field(itoa::Buffer::new().format(feature.search_pass).as_bytes());
+        match feature.spectral_angle {
+            Some(angle) => record.push_field(ryu::Buffer::new().format(angle).as_bytes()),
+            None => record.push_field(b""),
+        }
+        match feature.irt {
+            Some(irt) => record.push_field(ryu::Buffer::new().format(irt).as_bytes()),
+            None => record.push_field(b""),
+        }
+        match feature.ion_mobility {
+            Some(mobility) => record.push_field(ryu::Buffer::new().format(mobility).as_bytes()),
+            None => record.push_field(b""),
+        }
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.predicted_mobility)
+                .as_bytes(),
+        );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.delta_mobility_model)
+                .as_bytes(),
+        );
+        match feature.compensation_voltage {
+            Some(cv) => record.push_field(ryu::Buffer::new().format(cv).as_bytes()),
+            None => record.push_field(b""),
+        }
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.predicted_spectral_angle)
+                .as_bytes(),
+        );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.predicted_correlation)
+                .as_bytes(),
+        );
         record
     }
 
     pub fn serialize_fragments(
         &self,
         psm_id: usize,
-        fragments_: &Option<Fragments>,
+        fragments_: Option<&Fragments>,
     ) -> Vec<ByteRecord> {
         let mut frag_records = vec![];
 
         if let Some(fragments) = fragments_ {
-            for id in 0..fragments.fragment_ordinals.len() {
+            for (id, ppm_error) in fragments.ppm_errors().enumerate() {
                 let mut record = ByteRecord::new();
                 record.push_field(itoa::Buffer::new().format(psm_id).as_bytes());
-                let ion_type = match fragments.kinds[id] {
-                    Kind::A => "a",
-                    Kind::B => "b",
-                    Kind::C => "c",
-                    Kind::X => "x",
-                    Kind::Y => "y",
-                    Kind::Z => "z",
-                };
-                record.push_field(ion_type.as_bytes());
+                record.push_field(ion_type(fragments.kinds[id]).as_bytes());
                 record.push_field(
                     itoa::Buffer::new()
                         .format(fragments.fragment_ordinals[id])
@@ -131,6 +815,7 @@ impl Runner {
                         .format(fragments.intensities[id])
                         .as_bytes(),
                 );
+                record.push_field(ryu::Buffer::new().format(ppm_error).as_bytes());
                 frag_records.push(record);
             }
         }
@@ -138,100 +823,118 @@ impl Runner {
         frag_records
     }
 
-    pub fn write_features(
+    /// Header and rows of `results.sage.tsv`
+    pub fn results_table(
         &self,
         features: &[Feature],
+        quant: &[TmtQuant],
         filenames: &[String],
-    ) -> anyhow::Result<String> {
-        let path = self.make_path("results.sage.tsv");
+    ) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let mut headers = csv::ByteRecord::from(RESULTS_COLUMNS.to_vec());
+        // Reporter ion intensities of each PSM's spectrum are appended, if quantified
+        let channels = match (&self.parameters.quant.tmt, quant.is_empty()) {
+            (Some(tmt), false) => {
+                headers.extend(tmt.headers());
+                tmt.reporter_masses().len()
+            }
+            _ => 0,
+        };
+        let mut reporter_ions = HashMap::new();
+        for q in quant {
+            reporter_ions
+                .entry((q.file_id, q.spec_id.as_str()))
+                .or_insert(q);
+        }
 
-        let mut wtr = csv::WriterBuilder::new()
-            .delimiter(b'\t')
-            .from_writer(vec![]);
+        let records = features
+            .into_par_iter()
+            .map(|feat| {
+                let mut record = self.serialize_feature(feat, filenames);
+                if channels > 0 {
+                    match reporter_ions.get(&(feat.file_id, &*feat.spec_id)) {
+                        Some(q) => {
+                            for peak in &q.peaks {
+                                record.push_field(ryu::Buffer::new().format(*peak).as_bytes());
+                            }
+                        }
+                        None => (0..channels).for_each(|_| record.push_field(b"")),
+                    }
+                }
+                record
+            })
+            .collect::<Vec<_>>();
+        (headers, records)
+    }
 
-        let csv_headers = vec![
-            "psm_id",
-            "peptide",
-            "proteins",
-            "num_proteins",
-            "filename",
-            "scannr",
-            "rank",
-            "label",
-            "expmass",
-            "calcmass",
-            "charge",
-            "peptide_len",
-            "missed_cleavages",
-            "semi_enzymatic",
-            "isotope_error",
-            "precursor_ppm",
-            "fragment_ppm",
-            "hyperscore",
-            "delta_next",
-            "delta_best",
-            "rt",
-            "aligned_rt",
-            "predicted_rt",
-            "delta_rt_model",
-            "matched_peaks",
-            "longest_b",
-            "longest_y",
-            "longest_y_pct",
-            "matched_intensity_pct",
-            "scored_candidates",
-            "poisson",
-            "sage_discriminant_score",
-            "posterior_error",
-            "spectrum_q",
-            "peptide_q",
-            "protein_q",
-            "ms2_intensity",
-        ];
-
-        let headers = csv::ByteRecord::from(csv_headers);
+    pub fn write_features(
+        &self,
+        features: &[Feature],
+        quant: &[TmtQuant],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let (headers, records) = self.results_table(features, quant, filenames);
+        self.write_table("results.sage.tsv", &headers, &records)
+    }
 
-        wtr.write_byte_record(&headers)?;
-        for record in features
-            .into_par_iter()
-            .map(|feat| self.serialize_feature(feat, filenames))
-            .collect::<Vec<_>>()
-        {
-            wtr.write_byte_record(&record)?;
+    /// Write the PSMs of each FAIMS compensation voltage to a separate
+    /// `results.cv{cv}.sage.tsv`, e.g. `results.cv-45.sage.tsv`
+    pub fn write_features_by_compensation_voltage(
+        &self,
+        features: &[Feature],
+        quant: &[TmtQuant],
+        filenames: &[String],
+    ) -> anyhow::Result<Vec<String>> {
+        let voltages = compensation_voltages(features);
+        if voltages.is_empty() {
+            log::warn!("`faims.split_output` is set, but no spectra report a compensation voltage");
+            return Ok(Vec::new());
         }
+        let (headers, records) = self.results_table(features, quant, filenames);
+        voltages
+            .into_iter()
+            .map(|cv| {
+                let records = features
+                    .iter()
+                    .zip(&records)
+                    .filter(|(feat, _)| feat.compensation_voltage == Some(cv))
+                    .map(|(_, record)| record.clone())
+                    .collect::<Vec<_>>();
+                self.write_table(&format!("results.cv{}.sage.tsv", cv), &headers, &records)
+            })
+            .collect()
+    }
 
-        wtr.flush()?;
-        let bytes = wtr.into_inner()?;
-        path.write_bytes_sync(bytes)?;
-        Ok(path.to_string())
+    /// Header and rows of `matched_fragments.sage.tsv`
+    pub fn fragments_table(&self, features: &[Feature]) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let headers = csv::ByteRecord::from(FRAGMENT_COLUMNS.to_vec());
+        let records = features
+            .into_par_iter()
+            .map(|feat| self.serialize_fragments(feat.psm_id, feat.fragments.as_deref()))
+            .flatten()
+            .collect::<Vec<_>>();
+        (headers, records)
     }
 
     pub fn write_fragments(&self, features: &[Feature]) -> anyhow::Result<String> {
-        let path = self.make_path("matched_fragments.sage.tsv");
+        let (headers, records) = self.fragments_table(features);
+        self.write_table("matched_fragments.sage.tsv", &headers, &records)
+    }
+
+    /// Write a tab-separated file to the output directory
+    fn write_table(
+        &self,
+        filename: &str,
+        headers: &csv::ByteRecord,
+        records: &[csv::ByteRecord],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path(filename);
 
         let mut wtr = csv::WriterBuilder::new()
             .delimiter(b'\t')
             .from_writer(vec![]);
-
-        let headers = csv::ByteRecord::from(vec![
-            "psm_id",
-            "fragment_type",
-            "fragment_ordinals",
-            "fragment_charge",
-            "fragment_mz_calculated",
-            "fragment_mz_experimental",
-            "fragment_intensity",
-        ]);
-
-        wtr.write_byte_record(&headers)?;
-
-        for record in features
-            .into_par_iter()
-            .map(|feat| self.serialize_fragments(feat.psm_id, &feat.fragments))
-            .flatten()
-            .collect::<Vec<_>>()
-        {
-            wtr.write_byte_record(&record)?;
+        wtr.write_byte_record(headers)?;
+        for record in records {
+            wtr.write_byte_record(record)?;
         }
 
         wtr.flush()?;
@@ -242,16 +945,10 @@ impl Runner {
 
     fn serialize_pin(
         &self,
-        re: &regex::Regex,
+        scannr: &str,
         feature: &Feature,
         filenames: &[String],
     ) -> csv::ByteRecord {
-        let scannr = re
-            .captures_iter(&feature.spec_id)
-            .last()
-            .and_then(|cap| cap.get(1).map(|cap| cap.as_str()))
-            .unwrap_or(&feature.spec_id);
-
         let mut record = csv::ByteRecord::new();
         let peptide = &self.database[feature.peptide_idx];
         record.push_field(itoa::Buffer::new().format(feature.psm_id).as_bytes());
@@ -336,10 +1033,30 @@ impl Runner {
                 .format(feature.delta_rt_model.clamp(0.001, 1.0).sqrt())
                 .as_bytes(),
         );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.delta_mobility_model)
+                .as_bytes(),
+        );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.predicted_spectral_angle)
+                .as_bytes(),
+        );
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.predicted_correlation)
+                .as_bytes(),
+        );
         record.push_field(itoa::Buffer::new().format(feature.matched_peaks).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_b).as_bytes());
         record.push_field(itoa::Buffer::new().format(feature.longest_y).as_bytes());
         record.push_field(ryu::Buffer::new().format(feature.longest_y_pct).as_bytes());
+        record.push_field(
+            ryu::Buffer::new()
+                .format(feature.fragment_coverage)
+                .as_bytes(),
+        );
         record.push_field(
             ryu::Buffer::new()
                 .format(feature.matched_intensity_pct.ln_1p())
@@ -360,6 +1077,16 @@ impl Runner {
                 .format(feature.posterior_error)
                 .as_bytes(),
         );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.proteins.len())
+                .as_bytes(),
+        );
+        record.push_field(
+            itoa::Buffer::new()
+                .format(peptide.shared() as u8)
+                .as_bytes(),
+        );
         record.push_field(peptide.to_string().as_bytes());
         record.push_field(
             peptide
@@ -376,51 +1103,50 @@ impl Runner {
             .delimiter(b'\t')
             .from_writer(vec![]);
 
-        let headers = csv::ByteRecord::from(vec![
-            "SpecId",
-            "Label",
-            "ScanNr",
-            "ExpMass",
-            "CalcMass",
-            "FileName",
-            "retentiontime",
-            "rank",
-            "z=2",
-            "z=3",
-            "z=4",
-            "z=5",
-            "z=6",
-            "z=other",
-            "peptide_len",
-            "missed_cleavages",
-            "semi_enzymatic",
-            "isotope_error",
-            "ln(precursor_ppm)",
-            "fragment_ppm",
-            "ln(hyperscore)",
-            "ln(delta_next)",
-            "ln(delta_best)",
-            "aligned_rt",
-            "predicted_rt",
-            "sqrt(delta_rt_model)",
-            "matched_peaks",
-            "longest_b",
-            "longest_y",
-            "longest_y_pct",
-            "ln(matched_intensity_pct)",
-            "scored_candidates",
-            "ln(-poisson)",
-            "posterior_error",
-            "Peptide",
-            "Proteins",
-        ]);
+        let columns = pin_columns(self.parameters.pin_features.as_deref());
+        let headers = columns
+            .iter()
+            .map(|&col| PIN_COLUMNS[col])
+            .collect::<csv::ByteRecord>();
 
+        // Percolator requires integer scan numbers: spectra without a
+        // `scan=` identifier are numbered in order of appearance
         let re = regex::Regex::new(r"scan=(\d+)").expect("This is valid regex");
+        let mut spectra = fnv::FnvHashMap::default();
+        let scannrs = features
+            .iter()
+            .map(|feat| {
+                match re
+                    .captures_iter(&feat.spec_id)
+                    .last()
+                    .and_then(|cap| cap.get(1))
+                {
+                    Some(cap) => cap.as_str().to_string(),
+                    None if feat.spec_id.parse::<u64>().is_ok() => feat.spec_id.to_string(),
+                    None => {
+                        let next = spectra.len() + 1;
+                        let scannr = *spectra.entry((feat.file_id, &feat.spec_id)).or_insert(next);
+                        itoa::Buffer::new().format(scannr).to_string()
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
 
         wtr.write_byte_record(&headers)?;
         for record in features
             .into_par_iter()
-            .map(|feat| self.serialize_pin(&re, feat, filenames))
+            .zip(&scannrs)
+            .map(|(feat, scannr)| {
+                let record = self.serialize_pin(scannr, feat, filenames);
+                columns
+                    .iter()
+                    .map(|&col| match &record[col] {
+                        // Non-finite feature values cannot be parsed by Percolator
+                        b"NaN" | b"inf" | b"-inf" => &b"0"[..],
+                        field => field,
+                    })
+                    .collect::<csv::ByteRecord>()
+            })
             .collect::<Vec<_>>()
         {
             wtr.write_byte_record(&record)?;
@@ -432,24 +1158,259 @@ impl Runner {
         Ok(path.to_string())
     }
 
-    pub fn write_tmt(&self, quant: &[TmtQuant], filenames: &[String]) -> anyhow::Result<String> {
-        let path = self.make_path("tmt.tsv");
+    /// Rescoring features of every PSM, as used to train the rescoring model
+    /// (including features excluded by `ml.exclude_features`)
+    pub fn write_feature_matrix(
+        &self,
+        features: &[Feature],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("features.sage.tsv");
 
         let mut wtr = csv::WriterBuilder::new()
             .delimiter(b'\t')
             .from_writer(vec![]);
-        let mut headers = csv::ByteRecord::from(vec!["filename", "scannr", "ion_injection_time"]);
-        headers.extend(
-            self.parameters
-                .quant
-                .tmt
-                .as_ref()
-                .map(|tmt| tmt.headers())
-                .expect("TMT quant cannot be performed without setting this parameter"),
-        );
+        let mut headers = csv::ByteRecord::from(FEATURE_MATRIX_COLUMNS.to_vec());
+        headers.extend(FEATURE_NAMES);
+        headers.push_field(b"sage_discriminant_score");
+        wtr.write_byte_record(&headers)?;
+
+        let re = regex::Regex::new(r"scan=(\d+)").expect("This is valid regex");
+        let matrix = feature_matrix(features, self.parameters.precursor_tol);
+        for (row, feature) in features.iter().enumerate() {
+            let scannr = re
+                .captures_iter(&feature.spec_id)
+                .last()
+                .and_then(|cap| cap.get(1).map(|cap| cap.as_str()))
+                .unwrap_or(&feature.spec_id);
+            let mut record = csv::ByteRecord::new();
+            record.push_field(itoa::Buffer::new().format(feature.psm_id).as_bytes());
+            record.push_field(itoa::Buffer::new().format(feature.label).as_bytes());
+            record.push_field(filenames[feature.file_id].as_bytes());
+            record.push_field(scannr.as_bytes());
+            record.push_field(self.database[feature.peptide_idx].to_string().as_bytes());
+            for value in matrix.row_slice(row) {
+                record.push_field(ryu::Buffer::new().format(*value).as_bytes());
+            }
+            record.push_field(
+                ryu::Buffer::new()
+                    .format(feature.discriminant_score)
+                    .as_bytes(),
+            );
+            wtr.write_byte_record(&record)?;
+        }
+        wtr.flush()?;
+
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Confidently identified PSMs (rank 1 targets at 1% spectrum- and
+    /// peptide-level FDR), for export to downstream quantification tools
+    fn confident_psms<'a>(&self, features: &'a [Feature]) -> impl Iterator<Item = &'a Feature> {
+        features.iter().filter(|feat| {
+            feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= 0.01 && feat.peptide_q <= 0.01
+        })
+    }
+
+    pub fn write_flashlfq(
+        &self,
+        features: &[Feature],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("flashlfq.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(FLASHLFQ_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for feat in self.confident_psms(features) {
+            let peptide = &self.database[feat.peptide_idx];
+            let mut record = ByteRecord::new();
+            record.push_field(run_name(&filenames[feat.file_id]).as_bytes());
+            record.push_field(&peptide.sequence);
+            record.push_field(peptide.to_string().as_bytes());
+            record.push_field(ryu::Buffer::new().format(feat.calcmass).as_bytes());
+            record.push_field(ryu::Buffer::new().format(feat.rt).as_bytes());
+            record.push_field(itoa::Buffer::new().format(feat.charge).as_bytes());
+            record.push_field(
+                peptide
+                    .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                    .as_bytes(),
+            );
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Write peptide intensities from label-free quantification, in long
+    /// format. `Condition` and `BioReplicate` are left empty, to be filled in
+    /// from the experimental design
+    pub fn write_msstats(
+        &self,
+        areas: &HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("msstats.tsv");
 
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(MSSTATS_COLUMNS.to_vec());
         wtr.write_byte_record(&headers)?;
 
+        let mut precursors = areas
+            .iter()
+            .filter(|((_, decoy), (peak, _))| !decoy && peak.q_value <= 0.05)
+            .collect::<Vec<_>>();
+        precursors.sort_by_key(|((id, _), _)| *id);
+
+        for ((id, _), (_, data)) in precursors {
+            let (peptide_ix, charge) = match *id {
+                PrecursorId::Combined(x) => (x, None),
+                PrecursorId::Charged((x, charge)) => (x, Some(charge)),
+            };
+            let peptide = &self.database[peptide_ix];
+            let proteins =
+                peptide.proteins(&self.database.decoy_tag, self.database.generate_decoys);
+            let sequence = peptide.to_string();
+            for (filename, intensity) in filenames.iter().zip(data) {
+                let mut record = ByteRecord::new();
+                record.push_field(proteins.as_bytes());
+                record.push_field(sequence.as_bytes());
+                match charge {
+                    Some(charge) => {
+                        record.push_field(itoa::Buffer::new().format(charge).as_bytes())
+                    }
+                    None => record.push_field(b"NA"),
+                }
+                record.push_field(b"NA");
+                record.push_field(b"NA");
+                record.push_field(b"L");
+                record.push_field(b"");
+                record.push_field(b"");
+                record.push_field(run_name(filename).as_bytes());
+                match *intensity > 0.0 {
+                    true => record.push_field(ryu::Buffer::new().format(*intensity).as_bytes()),
+                    false => record.push_field(b"NA"),
+                }
+                wtr.write_byte_record(&record)?;
+            }
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Write reporter ion intensities of confident PSMs, in long format. The
+    /// annotation of each `Run` and `Channel` is joined by MSstatsTMT
+    pub fn write_msstats_tmt(
+        &self,
+        quant: &[TmtQuant],
+        features: &[Feature],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("msstats_tmt.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(MSSTATS_TMT_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        let channels = self
+            .parameters
+            .quant
+            .tmt
+            .as_ref()
+            .map(|tmt| tmt.channel_names())
+            .expect("TMT quant cannot be performed without setting this parameter");
+        let sn = self.parameters.quant.tmt_settings.sn;
+        let scans = quant
+            .iter()
+            .map(|q| ((q.file_id, q.spec_id.as_str()), q))
+            .collect::<HashMap<_, _>>();
+
+        for feat in self.confident_psms(features) {
+            let q = match scans.get(&(feat.file_id, &*feat.spec_id)) {
+                Some(q) => q,
+                None => continue,
+            };
+            // Use signal-to-noise ratios if requested (and available), as in
+            // the protein-level rollup
+            let intensities = match sn {
+                true if q.signal_to_noise.is_empty() => continue,
+                true => &q.signal_to_noise,
+                false => &q.peaks,
+            };
+            let peptide = &self.database[feat.peptide_idx];
+            let proteins =
+                peptide.proteins(&self.database.decoy_tag, self.database.generate_decoys);
+            let sequence = peptide.to_string();
+            let psm = format!("{}_{}", sequence, feat.charge);
+            for (channel, intensity) in channels.iter().zip(intensities) {
+                let mut record = ByteRecord::new();
+                record.push_field(proteins.as_bytes());
+                record.push_field(sequence.as_bytes());
+                record.push_field(itoa::Buffer::new().format(feat.charge).as_bytes());
+                record.push_field(psm.as_bytes());
+                record.push_field(run_name(&filenames[feat.file_id]).as_bytes());
+                record.push_field(channel.as_bytes());
+                match *intensity > 0.0 {
+                    true => record.push_field(ryu::Buffer::new().format(*intensity).as_bytes()),
+                    false => record.push_field(b"NA"),
+                }
+                wtr.write_byte_record(&record)?;
+            }
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Header and rows of `tmt.tsv`
+    pub fn tmt_table(
+        &self,
+        quant: &[TmtQuant],
+        features: &[Feature],
+        filenames: &[String],
+    ) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let mut headers = csv::ByteRecord::from(TMT_COLUMNS.to_vec());
+        let channels = self
+            .parameters
+            .quant
+            .tmt
+            .as_ref()
+            .map(|tmt| tmt.headers())
+            .expect("TMT quant cannot be performed without setting this parameter");
+        headers.extend(channels.iter());
+        // Signal-to-noise columns follow the intensity columns
+        let sn = self.parameters.quant.tmt_settings.sn;
+        if sn {
+            headers.extend(channels.iter().map(|channel| format!("{}_sn", channel)));
+        }
+
+        // Rank-1 PSM of each quantified spectrum
+        let hits = features
+            .iter()
+            .filter(|feat| feat.rank == 1)
+            .map(|feat| ((feat.file_id, &*feat.spec_id), feat))
+            .collect::<HashMap<_, _>>();
+
         let records = quant
             .into_par_iter()
             .map(|q| {
@@ -457,15 +1418,83 @@ impl Runner {
                 record.push_field(filenames[q.file_id].as_bytes());
                 record.push_field(q.spec_id.as_bytes());
                 record.push_field(ryu::Buffer::new().format(q.ion_injection_time).as_bytes());
+                match hits.get(&(q.file_id, q.spec_id.as_str())) {
+                    Some(feat) => {
+                        record.push_field(itoa::Buffer::new().format(feat.psm_id).as_bytes());
+                        record.push_field(self.database[feat.peptide_idx].to_string().as_bytes());
+                        record.push_field(ryu::Buffer::new().format(feat.spectrum_q).as_bytes());
+                    }
+                    None => {
+                        record.push_field(b"");
+                        record.push_field(b"");
+                        record.push_field(b"");
+                    }
+                }
+                match q.sps_purity {
+                    Some(purity) => record.push_field(ryu::Buffer::new().format(purity).as_bytes()),
+                    None => record.push_field(b""),
+                }
                 for peak in &q.peaks {
                     record.push_field(ryu::Buffer::new().format(*peak).as_bytes());
                 }
+                if sn {
+                    match q.signal_to_noise.is_empty() {
+                        true => q.peaks.iter().for_each(|_| record.push_field(b"")),
+                        false => {
+                            for ratio in &q.signal_to_noise {
+                                record.push_field(ryu::Buffer::new().format(*ratio).as_bytes());
+                            }
+                        }
+                    }
+                }
                 record
             })
             .collect::<Vec<csv::ByteRecord>>();
+        (headers, records)
+    }
 
-        for record in records {
-            wtr.write_record(&record)?;
+    pub fn write_tmt(
+        &self,
+        quant: &[TmtQuant],
+        features: &[Feature],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let (headers, records) = self.tmt_table(quant, features, filenames);
+        self.write_table("tmt.tsv", &headers, &records)
+    }
+
+    pub fn write_tmt_proteins(
+        &self,
+        proteins: &[ProteinTmtQuant],
+        design: &TmtDesign,
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("tmt_proteins.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+        let channels = self
+            .parameters
+            .quant
+            .tmt
+            .as_ref()
+            .map(|tmt| tmt.headers())
+            .expect("TMT quant cannot be performed without setting this parameter");
+        let mut headers = csv::ByteRecord::from(TMT_PROTEIN_COLUMNS.to_vec());
+        for name in &design.names {
+            headers.extend(channels.iter().map(|channel| format!("{name}_{channel}")));
+        }
+        wtr.write_byte_record(&headers)?;
+
+        for protein in proteins {
+            let mut record = csv::ByteRecord::new();
+            record.push_field(protein.protein.as_bytes());
+            for plex in &protein.plexes {
+                for intensity in plex {
+                    record.push_field(ryu::Buffer::new().format(*intensity).as_bytes());
+                }
+            }
+            wtr.write_byte_record(&record)?;
         }
         wtr.flush()?;
 
@@ -474,31 +1503,48 @@ impl Runner {
         Ok(path.to_string())
     }
 
-    pub fn write_lfq(
+    pub fn write_lfq_proteins(
         &self,
-        areas: HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+        proteins: &[ProteinLfqQuant],
         filenames: &[String],
     ) -> anyhow::Result<String> {
-        let path = self.make_path("lfq.tsv");
+        let path = self.make_path("lfq_proteins.tsv");
 
         let mut wtr = csv::WriterBuilder::new()
             .delimiter(b'\t')
             .from_writer(vec![]);
-        let mut headers = csv::ByteRecord::from(vec![
-            "peptide",
-            "charge",
-            "proteins",
-            "q_value",
-            "score",
-            "spectral_angle",
-        ]);
+        let mut headers = csv::ByteRecord::from(LFQ_PROTEIN_COLUMNS.to_vec());
         headers.extend(filenames);
-
         wtr.write_byte_record(&headers)?;
 
+        for protein in proteins {
+            let mut record = csv::ByteRecord::new();
+            record.push_field(protein.protein.as_bytes());
+            record.push_field(itoa::Buffer::new().format(protein.precursors).as_bytes());
+            for intensity in &protein.intensities {
+                record.push_field(ryu::Buffer::new().format(*intensity).as_bytes());
+            }
+            wtr.write_byte_record(&record)?;
+        }
+        wtr.flush()?;
+
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Header and rows of `lfq.tsv`
+    pub fn lfq_table(
+        &self,
+        areas: &HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+        filenames: &[String],
+    ) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let mut headers = csv::ByteRecord::from(LFQ_COLUMNS.to_vec());
+        headers.extend(filenames);
+
         let records = areas
-            .into_par_iter()
-            .filter_map(|((id, decoy), (peak, data))| {
+            .par_iter()
+            .filter_map(|(&(id, decoy), (peak, data))| {
                 if decoy {
                     return None;
                 };
@@ -518,19 +1564,464 @@ impl Runner {
                 record.push_field(ryu::Buffer::new().format(peak.score).as_bytes());
                 record.push_field(ryu::Buffer::new().format(peak.spectral_angle).as_bytes());
                 for x in data {
-                    record.push_field(ryu::Buffer::new().format(x).as_bytes());
+                    record.push_field(ryu::Buffer::new().format(*x).as_bytes());
                 }
                 Some(record)
             })
             .collect::<Vec<csv::ByteRecord>>();
+        (headers, records)
+    }
 
-        for record in records {
-            wtr.write_record(&record)?;
+    pub fn write_lfq(
+        &self,
+        areas: &HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let (headers, records) = self.lfq_table(areas, filenames);
+        self.write_table("lfq.tsv", &headers, &records)
+    }
+
+    pub fn write_protein_groups(&self, groups: &[ProteinGroup]) -> anyhow::Result<String> {
+        let path = self.make_path("protein_groups.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(PROTEIN_GROUP_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for group in groups {
+            // Accessions of generated decoys are stored without the decoy tag
+            let tag = match group.decoy && self.database.generate_decoys {
+                true => self.database.decoy_tag.as_str(),
+                false => "",
+            };
+            let proteins = group
+                .proteins
+                .iter()
+                .map(|protein| format!("{}{}", tag, protein))
+                .collect::<Vec<_>>();
+            let mut record = ByteRecord::new();
+            record.push_field(proteins[0].as_bytes());
+            record.push_field(proteins.join(";").as_bytes());
+            record.push_field(itoa::Buffer::new().format(proteins.len()).as_bytes());
+            record.push_field(match group.decoy {
+                true => b"-1",
+                false => b"1",
+            });
+            record.push_field(itoa::Buffer::new().format(group.peptides).as_bytes());
+            record.push_field(itoa::Buffer::new().format(group.unique_peptides).as_bytes());
+            record.push_field(itoa::Buffer::new().format(group.razor_peptides).as_bytes());
+            record.push_field(itoa::Buffer::new().format(group.psms).as_bytes());
+            record.push_field(ryu::Buffer::new().format(group.score).as_bytes());
+            record.push_field(ryu::Buffer::new().format(group.q_value).as_bytes());
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_library(&self, library: &[LibraryEntry]) -> anyhow::Result<String> {
+        let path = self.make_path("library.sage.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(LIBRARY_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for entry in library {
+            let peptide = &self.database[entry.peptide_idx];
+            let modified = peptide.to_string();
+            let proteins =
+                peptide.proteins(&self.database.decoy_tag, self.database.generate_decoys);
+            let precursor_mz =
+                (entry.calcmass + entry.charge as f32 * PROTON) / entry.charge as f32;
+
+            for fragment in &entry.fragments {
+                let mut record = ByteRecord::new();
+                record.push_field(modified.as_bytes());
+                record.push_field(&peptide.sequence);
+                record.push_field(proteins.as_bytes());
+                record.push_field(itoa::Buffer::new().format(entry.charge).as_bytes());
+                record.push_field(ryu::Buffer::new().format(precursor_mz).as_bytes());
+                record.push_field(ryu::Buffer::new().format(entry.rt).as_bytes());
+                record.push_field(itoa::Buffer::new().format(entry.replicates).as_bytes());
+                record.push_field(itoa::Buffer::new().format(entry.psms).as_bytes());
+                record.push_field(ion_type(fragment.kind).as_bytes());
+                record.push_field(itoa::Buffer::new().format(fragment.ordinal).as_bytes());
+                record.push_field(itoa::Buffer::new().format(fragment.charge).as_bytes());
+                record.push_field(ryu::Buffer::new().format(fragment.mz).as_bytes());
+                record.push_field(ryu::Buffer::new().format(fragment.intensity).as_bytes());
+                wtr.write_byte_record(&record)?;
+            }
         }
+
         wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_dia_library(&self, library: &[LibraryEntry]) -> anyhow::Result<String> {
+        let path = self.make_path("library.dia.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(DIA_LIBRARY_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for entry in library {
+            let peptide = &self.database[entry.peptide_idx];
+            let modified = format!("_{}_", sage_core::library::unimod_sequence(peptide));
+            let proteins =
+                peptide.proteins(&self.database.decoy_tag, self.database.generate_decoys);
+            let precursor_mz =
+                (entry.calcmass + entry.charge as f32 * PROTON) / entry.charge as f32;
+
+            for fragment in &entry.fragments {
+                let mut record = ByteRecord::new();
+                record.push_field(modified.as_bytes());
+                record.push_field(&peptide.sequence);
+                record.push_field(itoa::Buffer::new().format(entry.charge).as_bytes());
+                record.push_field(ryu::Buffer::new().format(precursor_mz).as_bytes());
+                record.push_field(ryu::Buffer::new().format(entry.rt).as_bytes());
+                record.push_field(proteins.as_bytes());
+                record.push_field(ion_type(fragment.kind).as_bytes());
+                record.push_field(itoa::Buffer::new().format(fragment.ordinal).as_bytes());
+                record.push_field(itoa::Buffer::new().format(fragment.charge).as_bytes());
+                record.push_field(b"noloss");
+                record.push_field(ryu::Buffer::new().format(fragment.mz).as_bytes());
+                record.push_field(ryu::Buffer::new().format(fragment.intensity).as_bytes());
+                wtr.write_byte_record(&record)?;
+            }
+        }
 
+        wtr.flush()?;
         let bytes = wtr.into_inner()?;
         path.write_bytes_sync(bytes)?;
         Ok(path.to_string())
     }
+
+    pub fn write_silac(&self, pairs: &[SilacPair], filenames: &[String]) -> anyhow::Result<String> {
+        let path = self.make_path("silac.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(SILAC_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for pair in pairs {
+            let peptide = &self.database[pair.peptide_idx];
+            let mut record = ByteRecord::new();
+            record.push_field(itoa::Buffer::new().format(pair.psm_id).as_bytes());
+            record.push_field(peptide.to_string().as_bytes());
+            record.push_field(
+                peptide
+                    .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                    .as_bytes(),
+            );
+            record.push_field(filenames[pair.file_id].as_bytes());
+            record.push_field(itoa::Buffer::new().format(pair.charge).as_bytes());
+            record.push_field(pair.channel.to_string().as_bytes());
+            record.push_field(ryu::Buffer::new().format(pair.light_mz).as_bytes());
+            record.push_field(ryu::Buffer::new().format(pair.heavy_mz).as_bytes());
+            record.push_field(itoa::Buffer::new().format(pair.scans).as_bytes());
+            record.push_field(itoa::Buffer::new().format(pair.pair_found as u8).as_bytes());
+            record.push_field(ryu::Buffer::new().format(pair.light_intensity).as_bytes());
+            record.push_field(ryu::Buffer::new().format(pair.heavy_intensity).as_bytes());
+            match pair.ratio() {
+                Some(ratio) => record.push_field(ryu::Buffer::new().format(ratio).as_bytes()),
+                None => record.push_field(b""),
+            }
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_silac_peptides(
+        &self,
+        peptides: &[SilacPeptide],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("silac_peptides.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(SILAC_PEPTIDE_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for silac in peptides {
+            let peptide = &self.database[silac.peptide_idx];
+            let mut record = ByteRecord::new();
+            record.push_field(peptide.to_string().as_bytes());
+            record.push_field(
+                peptide
+                    .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                    .as_bytes(),
+            );
+            record.push_field(filenames[silac.file_id].as_bytes());
+            record.push_field(itoa::Buffer::new().format(silac.psms).as_bytes());
+            record.push_field(itoa::Buffer::new().format(silac.pairs).as_bytes());
+            record.push_field(ryu::Buffer::new().format(silac.light_intensity).as_bytes());
+            record.push_field(ryu::Buffer::new().format(silac.heavy_intensity).as_bytes());
+            match silac.ratio {
+                Some(ratio) => record.push_field(ryu::Buffer::new().format(ratio).as_bytes()),
+                None => record.push_field(b""),
+            }
+            match silac.missing_partner {
+                Some(channel) => record.push_field(channel.to_string().as_bytes()),
+                None => record.push_field(b""),
+            }
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_crosslinks(
+        &self,
+        crosslinks: &[CrosslinkMatch],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("crosslinks.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(CROSSLINK_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for xl in crosslinks {
+            let alpha = &self.database[xl.alpha];
+            let beta = &self.database[xl.beta];
+            let mut record = ByteRecord::new();
+            record.push_field(filenames[xl.file_id].as_bytes());
+            record.push_field(xl.spec_id.as_bytes());
+            record.push_field(itoa::Buffer::new().format(xl.charge).as_bytes());
+            record.push_field(ryu::Buffer::new().format(xl.expmass).as_bytes());
+            record.push_field(ryu::Buffer::new().format(xl.calcmass).as_bytes());
+            record.push_field(ryu::Buffer::new().format(xl.rt).as_bytes());
+            for (peptide, site) in [(alpha, xl.alpha_site), (beta, xl.beta_site)] {
+                record.push_field(peptide.to_string().as_bytes());
+                record.push_field(
+                    peptide
+                        .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                        .as_bytes(),
+                );
+                // 1-based position of the linked residue
+                record.push_field(itoa::Buffer::new().format(site + 1).as_bytes());
+            }
+            record.push_field(itoa::Buffer::new().format(xl.alpha_matched).as_bytes());
+            record.push_field(itoa::Buffer::new().format(xl.beta_matched).as_bytes());
+            record.push_field(ryu::Buffer::new().format(xl.score).as_bytes());
+            record.push_field(xl.label.to_string().as_bytes());
+            record.push_field(ryu::Buffer::new().format(xl.q_value).as_bytes());
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_prm(
+        &self,
+        targets: &[PrmTarget],
+        results: &[PrmResult],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("prm.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(PRM_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for result in results {
+            let target = &targets[result.target];
+            let mut record = ByteRecord::new();
+            record.push_field(target.peptide.as_bytes());
+            record.push_field(itoa::Buffer::new().format(target.charge).as_bytes());
+            record.push_field(ryu::Buffer::new().format(target.precursor_mz).as_bytes());
+            record.push_field(filenames[result.file_id].as_bytes());
+            record.push_field(match target.library {
+                true => b"library",
+                false => b"theoretical",
+            });
+            record.push_field(itoa::Buffer::new().format(result.scans).as_bytes());
+            record.push_field(ryu::Buffer::new().format(result.apex_rt).as_bytes());
+            record.push_field(ryu::Buffer::new().format(result.start_rt).as_bytes());
+            record.push_field(ryu::Buffer::new().format(result.end_rt).as_bytes());
+            record.push_field(ryu::Buffer::new().format(result.area).as_bytes());
+            record.push_field(
+                itoa::Buffer::new()
+                    .format(result.fragments_detected)
+                    .as_bytes(),
+            );
+            record.push_field(ryu::Buffer::new().format(result.coelution).as_bytes());
+            record.push_field(ryu::Buffer::new().format(result.dot_product).as_bytes());
+            let areas = target
+                .fragments
+                .iter()
+                .zip(&result.fragment_areas)
+                .map(|(frag, area)| {
+                    format!(
+                        "{}{}+{}:{}",
+                        ion_type(frag.kind),
+                        frag.ordinal,
+                        frag.charge,
+                        area
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(";");
+            record.push_field(areas.as_bytes());
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_fasta(&self, fasta: &Fasta) -> anyhow::Result<String> {
+        let path = self.make_path("identified_proteins.fasta");
+        path.write_bytes_sync(fasta.to_string().into_bytes())?;
+        Ok(path.to_string())
+    }
+
+    /// Ion mobility model, which can be used to filter the candidate
+    /// peptides of later searches (`mobility_filter`)
+    pub fn write_mobility_model(&self, model: &MobilityModel) -> anyhow::Result<String> {
+        let path = self.make_path("mobility_model.json");
+        let bytes = serde_json::to_vec_pretty(model)?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_diagnostics(&self, diagnostics: &Diagnostics) -> anyhow::Result<String> {
+        let path = self.make_path("diagnostics.json");
+        let bytes = serde_json::to_vec_pretty(diagnostics)?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    /// Observed vs. predicted retention times of rank 1 PSMs
+    pub fn write_rt_diagnostics(
+        &self,
+        features: &[Feature],
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("rt_diagnostics.tsv");
+
+        let mut wtr = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(vec![]);
+
+        let headers = csv::ByteRecord::from(RT_DIAGNOSTIC_COLUMNS.to_vec());
+        wtr.write_byte_record(&headers)?;
+
+        for feature in features.iter().filter(|feat| feat.rank == 1) {
+            let mut record = ByteRecord::new();
+            record.push_field(itoa::Buffer::new().format(feature.psm_id).as_bytes());
+            record.push_field(self.database[feature.peptide_idx].to_string().as_bytes());
+            record.push_field(filenames[feature.file_id].as_bytes());
+            record.push_field(itoa::Buffer::new().format(feature.label).as_bytes());
+            record.push_field(ryu::Buffer::new().format(feature.spectrum_q).as_bytes());
+            record.push_field(ryu::Buffer::new().format(feature.rt).as_bytes());
+            record.push_field(ryu::Buffer::new().format(feature.aligned_rt).as_bytes());
+            record.push_field(ryu::Buffer::new().format(feature.predicted_rt).as_bytes());
+            wtr.write_byte_record(&record)?;
+        }
+
+        wtr.flush()?;
+        let bytes = wtr.into_inner()?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+
+    pub fn write_qc(&self, report: &QcReport) -> anyhow::Result<String> {
+        let path = self.make_path("qc.json");
+        let bytes = serde_json::to_vec_pretty(report)?;
+        path.write_bytes_sync(bytes)?;
+        Ok(path.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn library_targets() -> anyhow::Result<()> {
+        let library = format!(
+            "{}\n\
+             LESLIEK\tLESLIEK\tsp|A\t2\t416.2447\t10.5\t1\t3\ty\t4\t1\t502.2977\t0.5\n\
+             LESLIEK\tLESLIEK\tsp|A\t2\t416.2447\t10.5\t1\t3\ty\t5\t1\t589.3297\t1\n\
+             LESLIEK\tLESLIEK\tsp|A\t2\t416.2447\t10.5\t1\t3\tb\t2\t1\t243.1339\t0.2\n\
+             LESLIEK\tLESLIEK\tsp|A\t3\t277.8322\t10.5\t1\t1\ty\t5\t1\t589.3297\t1\n",
+            LIBRARY_COLUMNS.join("\t")
+        );
+        let targets = parse_library(&library, 2)?;
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].peptide, "LESLIEK");
+        assert_eq!(targets[0].charge, 2);
+        assert!(targets[0].library);
+        assert_eq!(targets[0].fragments.len(), 2);
+        assert_eq!(targets[0].fragments[0].kind, Kind::Y);
+        assert_eq!(targets[0].fragments[0].ordinal, 5);
+        assert_eq!(targets[1].charge, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn selected_pin_columns() {
+        let all = pin_columns(None);
+        assert_eq!(all, (0..PIN_COLUMNS.len()).collect::<Vec<_>>());
+
+        let features = vec!["ln(hyperscore)".to_string(), "matched_peaks".to_string()];
+        let columns = pin_columns(Some(&features))
+            .into_iter()
+            .map(|col| PIN_COLUMNS[col])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            [
+                "SpecId",
+                "Label",
+                "ScanNr",
+                "ExpMass",
+                "CalcMass",
+                "FileName",
+                "ln(hyperscore)",
+                "matched_peaks",
+                "Peptide",
+                "Proteins"
+            ]
+        );
+    }
 }
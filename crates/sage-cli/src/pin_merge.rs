@@ -0,0 +1,291 @@
+//! Experiment-wide FDR over the results of separate searches
+//!
+//! `sage merge run1.sage.pin run2.sage.pin ...` pools the PSMs of the
+//! Percolator input files written by separate searches (`write_pin`), trains
+//! a single rescoring model on their feature columns, and writes the
+//! experiment-wide discriminant scores and q-values of all PSMs to
+//! `merged.sage.tsv`. Neither the configuration file, the FASTA database nor
+//! the spectra are needed.
+
+use crate::output::{write_merged_psms, PIN_FIXED_COLUMNS};
+use anyhow::{bail, ensure, Context};
+use clap::ArgMatches;
+use fnv::FnvHashMap;
+use log::info;
+use sage_cloudpath::CloudPath;
+use sage_core::ml::matrix::Matrix;
+use sage_core::ml::pooled::{rescore_pooled, PooledPsm};
+use sage_core::ml::MlSettings;
+use std::time::Instant;
+
+/// Columns of a `.pin` file that are written by each search, but are not used
+/// as rescoring features of the pooled PSMs: posterior error probabilities
+/// come from the model of a single search
+const EXCLUDED_COLUMNS: &[&str] = &["posterior_error"];
+
+/// Identifiers of a PSM that are passed through to `merged.sage.tsv`
+pub struct PinRecord {
+    pub psm_id: String,
+    pub expmass: String,
+    pub calcmass: String,
+}
+
+/// PSMs of one or more `.pin` files, with one row of `features` per PSM
+#[derive(Default)]
+struct Pooled {
+    /// Names of the feature columns, shared by all files
+    columns: Option<Vec<String>>,
+    filenames: Vec<String>,
+    file_ids: FnvHashMap<String, usize>,
+    psms: Vec<PooledPsm>,
+    records: Vec<PinRecord>,
+    features: Vec<f64>,
+}
+
+impl Pooled {
+    /// Add the PSMs of a `.pin` file. Files searched as part of another
+    /// `.pin` file are reported by their `FileName`
+    fn add(&mut self, contents: &str) -> anyhow::Result<Vec<String>> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+        let headers = rdr.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name)
+                .with_context(|| format!("missing column `{}`", name))
+        };
+        let spec_id = column("SpecId")?;
+        let label = column("Label")?;
+        let scannr = column("ScanNr")?;
+        let filename = column("FileName")?;
+        let peptide = column("Peptide")?;
+        let proteins = column("Proteins")?;
+        let (expmass, calcmass) = (column("ExpMass").ok(), column("CalcMass").ok());
+
+        let features = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, header)| {
+                !PIN_FIXED_COLUMNS.contains(header) && !EXCLUDED_COLUMNS.contains(header)
+            })
+            .collect::<Vec<_>>();
+        let names = features
+            .iter()
+            .map(|(_, header)| header.to_string())
+            .collect::<Vec<_>>();
+        match &self.columns {
+            Some(columns) => ensure!(
+                *columns == names,
+                "feature columns differ from those of the first file: {} instead of {}",
+                names.join(", "),
+                columns.join(", ")
+            ),
+            None => self.columns = Some(names),
+        }
+
+        let mut seen = Vec::new();
+        let known = self.file_ids.len();
+        for (line, record) in rdr.records().enumerate() {
+            let record = record?;
+            // Percolator accepts an optional row of default feature weights
+            if &record[spec_id] == "DefaultDirection" {
+                continue;
+            }
+            let field = |idx: Option<usize>| {
+                idx.and_then(|idx| record.get(idx))
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let decoy = match &record[label] {
+                "1" => false,
+                "-1" => true,
+                other => bail!("line {}: unexpected `Label` {}", line + 2, other),
+            };
+
+            let name = &record[filename];
+            let file_id = match self.file_ids.get(name) {
+                Some(&file_id) => file_id,
+                None => {
+                    self.file_ids.insert(name.to_string(), self.filenames.len());
+                    self.filenames.push(name.to_string());
+                    self.filenames.len() - 1
+                }
+            };
+            if file_id < known && !seen.contains(&file_id) {
+                seen.push(file_id);
+            }
+
+            for &(idx, header) in &features {
+                let value = record
+                    .get(idx)
+                    .unwrap_or_default()
+                    .parse::<f64>()
+                    .with_context(|| format!("line {}: invalid `{}`", line + 2, header))?;
+                // Non-finite features would break the model
+                self.features.push(match value.is_finite() {
+                    true => value,
+                    false => 0.0,
+                });
+            }
+            // Percolator lists proteins in tab-separated trailing columns
+            let proteins = record
+                .iter()
+                .skip(proteins)
+                .filter(|protein| !protein.is_empty())
+                .collect::<Vec<_>>()
+                .join(";");
+            self.psms.push(PooledPsm {
+                file_id,
+                spec_id: record[scannr].to_string(),
+                decoy,
+                peptide: record[peptide].to_string(),
+                proteins,
+                ..Default::default()
+            });
+            self.records.push(PinRecord {
+                psm_id: record[spec_id].to_string(),
+                expmass: field(expmass),
+                calcmass: field(calcmass),
+            });
+        }
+        Ok(seen
+            .into_iter()
+            .map(|file_id| self.filenames[file_id].clone())
+            .collect())
+    }
+}
+
+pub fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let paths = matches
+        .get_one::<String>("parameters")
+        .into_iter()
+        .chain(
+            matches
+                .get_many::<String>("mzml_paths")
+                .into_iter()
+                .flatten(),
+        )
+        .collect::<Vec<_>>();
+
+    let mut pooled = Pooled::default();
+    for path in &paths {
+        ensure!(
+            path.ends_with(".pin"),
+            "`sage merge` combines `.pin` files, or the shards of a search with `--shards`: \
+             `{}` is not a `.pin` file",
+            path
+        );
+        let contents = sage_cloudpath::util::read_string(path.as_str())
+            .with_context(|| format!("Failed to read `{}`", path))?;
+        let psms = pooled.psms.len();
+        let repeated = pooled
+            .add(&contents)
+            .with_context(|| format!("Failed to parse `{}`", path))?;
+        info!("- {}: {} PSMs", path, pooled.psms.len() - psms);
+        for filename in repeated {
+            log::warn!(
+                "PSMs of `{}` are also found in another file, and are pooled with them",
+                filename
+            );
+        }
+    }
+
+    let Pooled {
+        columns,
+        filenames,
+        mut psms,
+        records,
+        features,
+        ..
+    } = pooled;
+    let columns = columns.unwrap_or_default().len();
+    ensure!(!psms.is_empty(), "no PSMs to merge");
+    ensure!(
+        columns > 0,
+        "no rescoring feature columns to train a model on"
+    );
+    info!(
+        "merged {} PSMs of {} files from {} searches",
+        psms.len(),
+        filenames.len(),
+        paths.len()
+    );
+
+    let features = Matrix::new(features, psms.len(), columns);
+    let summary = rescore_pooled(&mut psms, &features, &MlSettings::default()).context(
+        "could not fit a rescoring model to the pooled PSMs, e.g. there are too few \
+         confident targets",
+    )?;
+    info!(
+        "discovered {} target peptide-spectrum matches at 1% FDR",
+        summary.psms
+    );
+    info!("discovered {} target peptides at 1% FDR", summary.peptides);
+    info!("discovered {} target proteins at 1% FDR", summary.proteins);
+
+    let mut path = matches
+        .get_one::<String>("output_directory")
+        .map(String::as_str)
+        .unwrap_or(".")
+        .parse::<CloudPath>()?;
+    path.mkdir()?;
+    path.push("merged.sage.tsv");
+    write_merged_psms(&path, &psms, &records, &filenames)?;
+    info!(
+        "wrote merged results to `{}` in {}s",
+        path,
+        start.elapsed().as_secs()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pool_pin_files() -> anyhow::Result<()> {
+        let mut pooled = Pooled::default();
+        let repeated = pooled.add(
+            "SpecId\tLabel\tScanNr\tExpMass\tCalcMass\tFileName\tln(hyperscore)\tposterior_error\tPeptide\tProteins\n\
+             1\t1\t10\t1000.5\t1000.5\ta.mzML\t3.5\t-2\tPEPTIDE\tsp|A\n\
+             2\t-1\t11\t800.1\t800.2\ta.mzML\t1.5\t0\tEDITPEP\trev_sp|A\n",
+        )?;
+        assert!(repeated.is_empty());
+        // Percolator's tab-separated proteins, and a run already seen
+        let repeated = pooled.add(
+            "SpecId\tLabel\tScanNr\tExpMass\tCalcMass\tFileName\tln(hyperscore)\tposterior_error\tPeptide\tProteins\n\
+             1\t1\t10\t1000.5\t1000.5\tb.mzML\t2.5\t-1\tPEPTIDE\tsp|A\tsp|B\n\
+             2\t1\t12\t900.1\t900.2\ta.mzML\tinf\t-1\tLESLIEK\tsp|C\n",
+        )?;
+        assert_eq!(repeated, vec!["a.mzML".to_string()]);
+
+        assert_eq!(pooled.filenames, vec!["a.mzML", "b.mzML"]);
+        assert_eq!(pooled.columns, Some(vec!["ln(hyperscore)".to_string()]));
+        assert_eq!(pooled.features, vec![3.5, 1.5, 2.5, 0.0]);
+        let psms = pooled
+            .psms
+            .iter()
+            .map(|psm| (psm.file_id, psm.decoy, psm.proteins.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            psms,
+            vec![
+                (0, false, "sp|A"),
+                (0, true, "rev_sp|A"),
+                (1, false, "sp|A;sp|B"),
+                (0, false, "sp|C")
+            ]
+        );
+
+        // Files with other feature columns cannot be pooled
+        assert!(pooled
+            .add("SpecId\tLabel\tScanNr\tFileName\tmatched_peaks\tPeptide\tProteins\n")
+            .is_err());
+        Ok(())
+    }
+}
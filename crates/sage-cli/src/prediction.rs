@@ -0,0 +1,426 @@
+//! Fragment intensity prediction with a deep learning model in ONNX format,
+//! run with tract. Intensities are predicted after the search, for the
+//! target peptides of the PSMs only - predicted spectra of decoy peptides
+//! are derived from those of their target peptides, as for predictions read
+//! from a spectral library (see [`PredictedSpectra::new`])
+//!
+//! Models take the inputs, and produce the output, of Prosit's intensity
+//! model:
+//! 1. peptide sequences, `[batch, 30]`: residues encoded as 1-20 in the
+//!    order `ACDEFGHIKLMNPQRSTVWY`, oxidized methionine as 21, and padded
+//!    with 0. Cysteines must be carbamidomethylated
+//! 2. precursor charges, one-hot encoded, `[batch, 6]`
+//! 3. normalized collision energies / 100, `[batch, 1]`
+//!
+//! and predict `[batch, 174]` intensities: for each of the 29 bonds, of the
+//! y1+, y2+, y3+, b1+, b2+ and b3+ ions of that ordinal. Negative
+//! intensities mark fragments that can't exist
+//!
+//! [`PredictedSpectra::new`]: sage_core::fragment_prediction::PredictedSpectra::new
+
+use anyhow::{ensure, Context};
+use fnv::FnvHashMap;
+use rayon::prelude::*;
+use sage_core::database::IndexedDatabase;
+use sage_core::ion_series::{IonSeries, Kind};
+use sage_core::library::LibraryFragment;
+use sage_core::library_search::LibraryRecord;
+use sage_core::mass::PROTON;
+use sage_core::peptide::Peptide;
+use sage_core::scoring::Feature;
+use tract_onnx::prelude::*;
+use tract_onnx::tract_hir::infer::Factoid;
+
+/// Longest peptide the model accepts
+const MAX_LENGTH: usize = 30;
+
+/// Highest precursor charge the model accepts
+const MAX_CHARGE: u8 = 6;
+
+/// Fragment ions predicted for each bond
+const IONS: [(Kind, u8); 6] = [
+    (Kind::Y, 1),
+    (Kind::Y, 2),
+    (Kind::Y, 3),
+    (Kind::B, 1),
+    (Kind::B, 2),
+    (Kind::B, 3),
+];
+
+/// Residues in the order of their encoding, starting at 1
+const ALPHABET: &[u8] = b"ACDEFGHIKLMNPQRSTVWY";
+
+/// Number of precursors predicted at once
+const BATCH_SIZE: usize = 256;
+
+/// Sequence and charge of a precursor, and its peptide and encoded residues
+type Precursor = ((String, u8), (Peptide, [i32; MAX_LENGTH]));
+
+pub struct IntensityModel {
+    model: TypedRunnableModel<TypedModel>,
+    /// Element type of each input of the model
+    inputs: [DatumType; 3],
+    /// Normalized collision energy, e.g. 30
+    collision_energy: f32,
+}
+
+impl IntensityModel {
+    pub fn load(path: &str, collision_energy: f32) -> anyhow::Result<Self> {
+        let bytes = sage_cloudpath::util::read_bytes(path)?;
+        let mut model = tract_onnx::onnx().model_for_read(&mut bytes.as_slice())?;
+        ensure!(
+            model.inputs.len() == 3 && !model.outputs.is_empty(),
+            "expected a model with 3 inputs (sequences, charges, collision energies), found {}",
+            model.inputs.len()
+        );
+
+        // Batches are of a fixed size: the last one is padded
+        let shapes = [
+            [BATCH_SIZE, MAX_LENGTH],
+            [BATCH_SIZE, MAX_CHARGE as usize],
+            [BATCH_SIZE, 1],
+        ];
+        let mut inputs = [DatumType::I32, DatumType::F32, DatumType::F32];
+        for (idx, shape) in shapes.into_iter().enumerate() {
+            if let Some(datum_type) = model.input_fact(idx)?.datum_type.concretize() {
+                inputs[idx] = datum_type;
+            }
+            model = model.with_input_fact(idx, InferenceFact::dt_shape(inputs[idx], shape))?;
+        }
+        for idx in 0..model.outputs.len() {
+            model = model.with_output_fact(idx, InferenceFact::default())?;
+        }
+        let model = model.into_optimized()?.into_runnable()?;
+        Ok(Self {
+            model,
+            inputs,
+            collision_energy,
+        })
+    }
+
+    /// Predict the fragment intensities of the target peptides of `features`,
+    /// at the charge states of the PSMs. Peptides the model can't encode
+    /// (too long, or with unsupported modifications) are skipped
+    pub fn predict(
+        &self,
+        db: &IndexedDatabase,
+        features: &[Feature],
+    ) -> anyhow::Result<Vec<LibraryRecord>> {
+        let mut precursors = FnvHashMap::default();
+        for feat in features {
+            let peptide = &db[feat.peptide_idx];
+            let target = match peptide.decoy {
+                true => peptide.reverse(),
+                false => peptide.clone(),
+            };
+            if feat.charge > 0 && feat.charge <= MAX_CHARGE {
+                if let Some(encoded) = encode(&target) {
+                    precursors
+                        .entry((target.to_string(), feat.charge))
+                        .or_insert((target, encoded));
+                }
+            }
+        }
+        let precursors = precursors.into_iter().collect::<Vec<_>>();
+
+        let records = precursors
+            .par_chunks(BATCH_SIZE)
+            .map(|batch| {
+                let intensities = self.run(batch)?;
+                Ok(batch
+                    .iter()
+                    .zip(intensities.outer_iter())
+                    .map(
+                        |(((sequence, charge), (peptide, _)), intensities)| LibraryRecord {
+                            peptide: sequence.clone(),
+                            charge: *charge,
+                            fragments: decode(
+                                peptide,
+                                *charge,
+                                intensities.as_slice().unwrap_or_default(),
+                                db,
+                            ),
+                        },
+                    )
+                    .filter(|record| !record.fragments.is_empty())
+                    .collect::<Vec<_>>())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(records.into_iter().flatten().collect())
+    }
+
+    /// Predict the intensities of a batch of (at most [`BATCH_SIZE`])
+    /// precursors, one row each
+    fn run(&self, batch: &[Precursor]) -> anyhow::Result<tract_ndarray::Array2<f32>> {
+        let mut sequences = tract_ndarray::Array2::<i32>::zeros((BATCH_SIZE, MAX_LENGTH));
+        let mut charges = tract_ndarray::Array2::<f32>::zeros((BATCH_SIZE, MAX_CHARGE as usize));
+        let energies =
+            tract_ndarray::Array2::<f32>::from_elem((BATCH_SIZE, 1), self.collision_energy / 100.0);
+        for (row, ((_, charge), (_, encoded))) in batch.iter().enumerate() {
+            sequences.row_mut(row).assign(&tract_ndarray::arr1(encoded));
+            charges[[row, *charge as usize - 1]] = 1.0;
+        }
+
+        let inputs = [
+            sequences.into_tensor(),
+            charges.into_tensor(),
+            energies.into_tensor(),
+        ]
+        .into_iter()
+        .zip(self.inputs)
+        .map(|(tensor, datum_type)| Ok(tensor.cast_to_dt(datum_type)?.into_owned().into()))
+        .collect::<anyhow::Result<TVec<TValue>>>()?;
+        let outputs = self.model.run(inputs)?;
+        let intensities = outputs[0]
+            .cast_to::<f32>()?
+            .to_array_view::<f32>()?
+            .into_dimensionality::<tract_ndarray::Ix2>()
+            .context("expected predicted intensities of shape [batch, 174]")?
+            .to_owned();
+        ensure!(
+            intensities.shape() == [BATCH_SIZE, IONS.len() * (MAX_LENGTH - 1)],
+            "expected predicted intensities of shape [batch, 174], found {:?}",
+            intensities.shape()
+        );
+        Ok(intensities)
+    }
+}
+
+/// Encode the residues of `peptide`, or `None` if the model can't predict it
+fn encode(peptide: &Peptide) -> Option<[i32; MAX_LENGTH]> {
+    const CARBAMIDOMETHYL: f32 = 57.0215;
+    const OXIDATION: f32 = 15.9949;
+    const TOLERANCE: f32 = 0.01;
+
+    if peptide.sequence.len() > MAX_LENGTH
+        || peptide.nterm.map_or(false, |m| m != 0.0)
+        || peptide.cterm.map_or(false, |m| m != 0.0)
+    {
+        return None;
+    }
+    let mut encoded = [0; MAX_LENGTH];
+    for (idx, (residue, modification)) in peptide
+        .sequence
+        .iter()
+        .zip(&peptide.modifications)
+        .enumerate()
+    {
+        encoded[idx] = match (residue, modification) {
+            (b'C', m) if (m - CARBAMIDOMETHYL).abs() <= TOLERANCE => 2,
+            (b'C', _) => return None,
+            (b'M', m) if (m - OXIDATION).abs() <= TOLERANCE => 21,
+            (r, m) if *m == 0.0 => ALPHABET.iter().position(|a| a == r)? as i32 + 1,
+            _ => return None,
+        };
+    }
+    Some(encoded)
+}
+
+/// Fragments with a positive predicted intensity, relative to the most
+/// intense one. Fragments of a higher charge than the precursor are dropped
+fn decode(
+    peptide: &Peptide,
+    charge: u8,
+    intensities: &[f32],
+    db: &IndexedDatabase,
+) -> Vec<LibraryFragment> {
+    let len = peptide.sequence.len();
+    let b = IonSeries::with_residue_masses(peptide, Kind::B, db.residue_masses).collect::<Vec<_>>();
+    let y = IonSeries::with_residue_masses(peptide, Kind::Y, db.residue_masses).collect::<Vec<_>>();
+    let mut fragments = Vec::new();
+    for ordinal in 1..len {
+        for (idx, (kind, fragment_charge)) in IONS.iter().enumerate() {
+            let intensity = intensities[(ordinal - 1) * IONS.len() + idx];
+            if *fragment_charge > charge || intensity <= 0.0 {
+                continue;
+            }
+            // See `LibrarySpectrum::decoy`
+            let mass = match kind {
+                Kind::B => b[ordinal - 1].monoisotopic_mass,
+                _ => y[len - 1 - ordinal].monoisotopic_mass,
+            };
+            let fragment_charge = *fragment_charge as f32;
+            fragments.push(LibraryFragment {
+                kind: *kind,
+                ordinal: ordinal as i32,
+                charge: fragment_charge as i32,
+                mz: (mass + fragment_charge * PROTON) / fragment_charge,
+                intensity,
+            });
+        }
+    }
+    let max = fragments
+        .iter()
+        .map(|frag| frag.intensity)
+        .fold(0.0f32, f32::max);
+    for frag in &mut fragments {
+        frag.intensity /= max;
+    }
+    fragments
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prost::Message;
+    use sage_core::database::Builder;
+    use sage_core::enzyme::Digest;
+    use sage_core::fasta::Fasta;
+    use sage_core::fragment_prediction::PredictedSpectra;
+    use tract_onnx::pb;
+
+    fn value_info(name: &str, elem_type: i32, width: i64) -> pb::ValueInfoProto {
+        use pb::tensor_shape_proto::{dimension::Value, Dimension};
+        let dim = [Value::DimParam("batch".into()), Value::DimValue(width)]
+            .into_iter()
+            .map(|value| Dimension {
+                value: Some(value),
+                ..Default::default()
+            })
+            .collect();
+        pb::ValueInfoProto {
+            name: name.into(),
+            r#type: Some(pb::TypeProto {
+                value: Some(pb::type_proto::Value::TensorType(pb::type_proto::Tensor {
+                    elem_type,
+                    shape: Some(pb::TensorShapeProto { dim }),
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Model whose predictions only depend on the precursor charge: the
+    /// one-hot encoded charges are multiplied with `weights`
+    fn model(weights: Vec<f32>) -> Vec<u8> {
+        const FLOAT: i32 = 1;
+        const INT32: i32 = 6;
+        let graph = pb::GraphProto {
+            node: vec![pb::NodeProto {
+                input: vec!["charges".into(), "weights".into()],
+                output: vec!["intensities".into()],
+                op_type: "MatMul".into(),
+                ..Default::default()
+            }],
+            initializer: vec![pb::TensorProto {
+                dims: vec![6, 174],
+                data_type: FLOAT,
+                float_data: weights,
+                name: "weights".into(),
+                ..Default::default()
+            }],
+            input: vec![
+                value_info("sequences", INT32, 30),
+                value_info("charges", FLOAT, 6),
+                value_info("collision_energies", FLOAT, 1),
+            ],
+            output: vec![value_info("intensities", FLOAT, 174)],
+            name: "intensities".into(),
+            ..Default::default()
+        };
+        pb::ModelProto {
+            ir_version: 7,
+            opset_import: vec![pb::OperatorSetIdProto {
+                domain: String::new(),
+                version: 13,
+            }],
+            graph: Some(graph),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn predict_intensities() -> anyhow::Result<()> {
+        let fasta = Fasta::parse(
+            ">sp|A\nMEWKLEQSMREQALLKAQLTQLKGAVLRLESLIEK".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let index = |sequence: &str| {
+            db.peptides
+                .iter()
+                .position(|peptide| peptide.to_string() == sequence)
+                .map(|idx| sage_core::database::PeptideIx(idx as u32))
+                .unwrap()
+        };
+        let (target, decoy) = (index("LESLIEK"), index("LEILSEK"));
+
+        // Doubly charged precursors: the ions of the first bond can't exist,
+        // and b2+ ions are the most intense
+        let mut weights = vec![0.0; 6 * 174];
+        for (idx, weight) in weights[174..2 * 174].iter_mut().enumerate() {
+            *weight = match idx < IONS.len() {
+                true => -1.0,
+                false => (idx % IONS.len()) as f32 + 1.0,
+            };
+        }
+        let path =
+            std::env::temp_dir().join(format!("sage-intensities-{}.onnx", std::process::id()));
+        std::fs::write(&path, model(weights))?;
+        let model = IntensityModel::load(&path.to_string_lossy(), 30.0);
+        std::fs::remove_file(&path)?;
+        let model = model?;
+
+        // The decoy PSM is predicted from its target peptide, and the charge
+        // state the model doesn't support is skipped
+        let features = [(target, 2), (decoy, 2), (target, 7)]
+            .into_iter()
+            .map(|(peptide_idx, charge)| Feature {
+                peptide_idx,
+                charge,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let records = model.predict(&db, &features)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peptide, "LESLIEK");
+        assert_eq!(records[0].charge, 2);
+
+        // 5 bonds, with y1+, y2+, b1+ and b2+ ions each
+        let fragments = &records[0].fragments;
+        assert_eq!(fragments.len(), 20);
+        assert!(fragments
+            .iter()
+            .all(|frag| frag.ordinal > 1 && frag.charge <= 2));
+        let y = fragments
+            .iter()
+            .find(|frag| frag.kind == Kind::Y && frag.ordinal == 2 && frag.charge == 1)
+            .unwrap();
+        assert!((y.intensity - 0.2).abs() < 1E-6);
+        // y2+ of LESLIEK: EK
+        let mass = IonSeries::new(&db[target], Kind::Y).nth(4).unwrap();
+        assert!((y.mz - (mass.monoisotopic_mass + PROTON)).abs() < 1E-3);
+        assert!(fragments.iter().any(|frag| frag.intensity == 1.0));
+
+        let (spectra, missing) = PredictedSpectra::new(&db, records);
+        assert_eq!((spectra.len(), missing), (2, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn encode_peptides() {
+        let peptide = |sequence: &str, modifications: Vec<f32>| {
+            let mut peptide = Peptide::try_from(Digest {
+                sequence: sequence.into(),
+                ..Default::default()
+            })
+            .unwrap();
+            peptide.modifications = modifications;
+            peptide
+        };
+        let encoded = encode(&peptide("ACMY", vec![0.0, 57.0215, 15.9949, 0.0])).unwrap();
+        assert_eq!(encoded[..5], [1, 2, 21, 20, 0]);
+
+        assert!(encode(&peptide("AC", vec![0.0, 0.0])).is_none());
+        assert!(encode(&peptide("AS", vec![0.0, 79.9663])).is_none());
+        assert!(encode(&peptide(&"A".repeat(31), vec![0.0; 31])).is_none());
+    }
+}
@@ -0,0 +1,130 @@
+//! Progress reporting for long-running stages of a search
+//!
+//! On a terminal, progress is drawn as a single status line on stderr that is
+//! redrawn in place. Otherwise (e.g. cluster job logs), progress is written as
+//! periodic `key=value` log lines, which are easy to grep and parse. Nothing is
+//! reported if info-level logging is disabled.
+
+use log::info;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two redraws of the terminal status line
+const TERMINAL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Minimum time between two progress log lines
+const LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Announce the start of a stage whose amount of work is not known in advance
+pub fn stage(name: &str, start: Instant) {
+    info!(
+        "progress: stage={} elapsed={}s",
+        name.replace(' ', "_"),
+        start.elapsed().as_secs()
+    );
+}
+
+/// Progress of a stage with a known amount of work, e.g. the number of
+/// spectra to search. Can be shared between threads
+pub struct Progress {
+    stage: String,
+    total: usize,
+    done: AtomicUsize,
+    start: Instant,
+    /// Time of the last report, in milliseconds since `start`
+    last: AtomicU64,
+    interval: Duration,
+    terminal: bool,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(stage: &str, total: usize) -> Self {
+        let terminal = atty::is(atty::Stream::Stderr);
+        Progress {
+            stage: stage.replace(' ', "_"),
+            total,
+            done: AtomicUsize::new(0),
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            interval: match terminal {
+                true => TERMINAL_INTERVAL,
+                false => LOG_INTERVAL,
+            },
+            terminal,
+            enabled: total > 0 && log::log_enabled!(log::Level::Info),
+        }
+    }
+
+    /// Log every update, regardless of the time since the last report, also
+    /// on a terminal. Useful for coarse-grained work, such as whole files,
+    /// which is interleaved with other log messages
+    pub fn every_update(mut self) -> Self {
+        self.interval = Duration::ZERO;
+        self.terminal = false;
+        self
+    }
+
+    /// Record `n` completed units of work, and report progress if enough
+    /// time has passed since the last report
+    pub fn inc(&self, n: usize) {
+        let done = self.done.fetch_add(n, Ordering::Relaxed) + n;
+        if !self.enabled {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let now = elapsed.as_millis() as u64;
+        let last = self.last.load(Ordering::Relaxed);
+        // Only a single thread reports each interval
+        if now.saturating_sub(last) < self.interval.as_millis() as u64
+            || self
+                .last
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        self.report(done.min(self.total), elapsed);
+    }
+
+    fn report(&self, done: usize, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let rate = match done as f64 / secs.max(1e-3) {
+            rate if rate < 10.0 => format!("{:.2}", rate),
+            rate => format!("{:.0}", rate),
+        };
+        let eta = match done {
+            0 => "?".to_string(),
+            _ => format!("{:.0}s", secs * (self.total - done) as f64 / done as f64),
+        };
+        let percent = 100.0 * done as f64 / self.total as f64;
+        if self.terminal {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(
+                stderr,
+                "\r\x1b[2K{}: {}/{} ({:.1}%), {}/s, ETA {}",
+                self.stage.replace('_', " "),
+                done,
+                self.total,
+                percent,
+                rate,
+                eta
+            );
+            let _ = stderr.flush();
+        } else {
+            info!(
+                "progress: stage={} done={} total={} percent={:.1} rate={}/s eta={}",
+                self.stage, done, self.total, percent, rate, eta
+            );
+        }
+    }
+
+    /// Clear the terminal status line, so that it doesn't mix with
+    /// subsequent log messages
+    pub fn finish(self) {
+        if self.enabled && self.terminal {
+            let _ = write!(std::io::stderr(), "\r\x1b[2K");
+        }
+    }
+}
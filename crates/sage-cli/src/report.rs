@@ -0,0 +1,581 @@
+//! Per-run HTML quality control reports
+//!
+//! `sage report results.json` reads the `results.sage.tsv` and `qc.json` files
+//! of a search, and writes a `{run}.report.html` file for each searched file.
+//! Plots are inline SVG, so reports open in any browser, without network
+//! access or other files:
+//! * identified PSMs vs. q-value threshold
+//! * discriminant score distributions of targets and decoys
+//! * precursor and fragment mass errors of identified PSMs
+//! * predicted vs. aligned retention time, i.e. the fit of the RT model
+//! * total ion current over retention time
+
+use crate::output::{run_name, RunReport};
+use anyhow::{ensure, Context};
+use clap::ArgMatches;
+use fnv::FnvHashMap;
+use log::info;
+use sage_cloudpath::CloudPath;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::time::Instant;
+
+/// Maximum number of points of the retention time scatter plot
+const MAX_RT_POINTS: usize = 2000;
+const HISTOGRAM_BINS: usize = 50;
+const TARGET_COLOR: &str = "#1f77b4";
+const DECOY_COLOR: &str = "#d62728";
+const WIDTH: f32 = 480.0;
+const HEIGHT: f32 = 320.0;
+/// Left, right, top and bottom margins of the plot area
+const MARGINS: (f32, f32, f32, f32) = (70.0, 15.0, 30.0, 45.0);
+
+/// Part of `results.json` read by the report
+#[derive(Deserialize)]
+struct Results {
+    output_paths: Vec<String>,
+}
+
+/// Part of `qc.json` read by the report
+#[derive(Deserialize)]
+struct Qc {
+    runs: Vec<RunReport>,
+}
+
+/// A rank-1 PSM of `results.sage.tsv`
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Psm {
+    decoy: bool,
+    score: f32,
+    spectrum_q: f32,
+    precursor_ppm: f32,
+    fragment_ppm: f32,
+    aligned_rt: f32,
+    predicted_rt: f32,
+}
+
+/// Read the rank-1 PSMs of `results.sage.tsv`, by filename
+fn read_psms(contents: &str) -> anyhow::Result<FnvHashMap<String, Vec<Psm>>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_reader(contents.as_bytes());
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .with_context(|| format!("missing column `{}`", name))
+    };
+    let filename = column("filename")?;
+    let rank = column("rank")?;
+    let label = column("label")?;
+    let columns = [
+        column("sage_discriminant_score")?,
+        column("spectrum_q")?,
+        column("precursor_ppm")?,
+        column("fragment_ppm")?,
+        column("aligned_rt")?,
+        column("predicted_rt")?,
+    ];
+
+    let mut psms: FnvHashMap<String, Vec<Psm>> = FnvHashMap::default();
+    for (line, record) in rdr.records().enumerate() {
+        let record = record?;
+        if &record[rank] != "1" {
+            continue;
+        }
+        let mut values = [0.0f32; 6];
+        for (value, &idx) in values.iter_mut().zip(&columns) {
+            *value = record[idx]
+                .parse()
+                .with_context(|| format!("line {}: invalid `{}`", line + 2, &headers[idx]))?;
+        }
+        let [score, spectrum_q, precursor_ppm, fragment_ppm, aligned_rt, predicted_rt] = values;
+        psms.entry(record[filename].to_string())
+            .or_default()
+            .push(Psm {
+                decoy: &record[label] == "-1",
+                score,
+                spectrum_q,
+                precursor_ppm,
+                fragment_ppm,
+                aligned_rt,
+                predicted_rt,
+            });
+    }
+    Ok(psms)
+}
+
+/// A series of points, drawn as a line, or as markers if `scatter` is set
+struct Series<'a> {
+    name: &'a str,
+    color: &'a str,
+    points: Vec<(f32, f32)>,
+    scatter: bool,
+}
+
+impl<'a> Series<'a> {
+    fn line(name: &'a str, color: &'a str, points: Vec<(f32, f32)>) -> Self {
+        Series {
+            name,
+            color,
+            points,
+            scatter: false,
+        }
+    }
+}
+
+/// At most 7 evenly spaced, round tick values within `lo..=hi`
+fn ticks(lo: f32, hi: f32) -> Vec<f32> {
+    let raw = (hi - lo) / 6.0;
+    let magnitude = 10f32.powf(raw.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * magnitude)
+        .find(|&step| step >= raw)
+        .unwrap_or(10.0 * magnitude);
+    let first = (lo / step).ceil() as i64;
+    let last = (hi / step).floor() as i64;
+    (first..=last).map(|i| i as f32 * step).collect()
+}
+
+fn format_tick(value: f32, step: f32) -> String {
+    if value != 0.0 && (value.abs() >= 1E5 || value.abs() < 1E-3) {
+        format!("{:.1e}", value)
+    } else {
+        let decimals = (-step.log10().floor()).max(0.0) as usize;
+        format!("{:.*}", decimals, value)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render `series` as an SVG plot, with axes fitted to all points
+fn plot(title: &str, x_label: &str, y_label: &str, series: &[Series]) -> String {
+    let (left, right, top, bottom) = MARGINS;
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = WIDTH,
+        h = HEIGHT
+    );
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="18" text-anchor="middle" font-weight="bold">{}</text>"#,
+        WIDTH / 2.0,
+        escape(title)
+    );
+
+    let points = series
+        .iter()
+        .flat_map(|series| series.points.iter())
+        .filter(|(x, y)| x.is_finite() && y.is_finite());
+    let (x_lo, x_hi, y_lo, y_hi) = points.fold(
+        (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+        |(x_lo, x_hi, y_lo, y_hi), &(x, y)| (x_lo.min(x), x_hi.max(x), y_lo.min(y), y_hi.max(y)),
+    );
+    if x_lo > x_hi {
+        let _ = write!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle" fill="gray">no data</text></svg>"#,
+            WIDTH / 2.0,
+            HEIGHT / 2.0
+        );
+        return svg;
+    }
+    // Avoid empty ranges, e.g. of a single point
+    let pad = |lo: f32, hi: f32| match hi - lo > 0.0 {
+        true => (lo, hi),
+        false => (lo - 0.5 * lo.abs().max(1.0), hi + 0.5 * hi.abs().max(1.0)),
+    };
+    let (x_lo, x_hi) = pad(x_lo, x_hi);
+    let (y_lo, y_hi) = pad(y_lo, y_hi);
+    let x = |value: f32| left + (value - x_lo) / (x_hi - x_lo) * (WIDTH - left - right);
+    let y = |value: f32| HEIGHT - bottom - (value - y_lo) / (y_hi - y_lo) * (HEIGHT - top - bottom);
+
+    let _ = write!(
+        svg,
+        r#"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="black"/>"#,
+        left,
+        top,
+        WIDTH - left - right,
+        HEIGHT - top - bottom
+    );
+    let x_ticks = ticks(x_lo, x_hi);
+    let x_step = x_ticks.get(1).map_or(1.0, |t| t - x_ticks[0]);
+    for &tick in &x_ticks {
+        let _ = write!(
+            svg,
+            r#"<line x1="{px:.1}" x2="{px:.1}" y1="{y0}" y2="{y1}" stroke="black"/><text x="{px:.1}" y="{ty}" text-anchor="middle" font-size="11">{}</text>"#,
+            format_tick(tick, x_step),
+            px = x(tick),
+            y0 = HEIGHT - bottom,
+            y1 = HEIGHT - bottom + 5.0,
+            ty = HEIGHT - bottom + 17.0
+        );
+    }
+    let y_ticks = ticks(y_lo, y_hi);
+    let y_step = y_ticks.get(1).map_or(1.0, |t| t - y_ticks[0]);
+    for &tick in &y_ticks {
+        let _ = write!(
+            svg,
+            r#"<line x1="{x0}" x2="{x1}" y1="{py:.1}" y2="{py:.1}" stroke="black"/><text x="{tx}" y="{py:.1}" text-anchor="end" dominant-baseline="middle" font-size="11">{}</text>"#,
+            format_tick(tick, y_step),
+            py = y(tick),
+            x0 = left - 5.0,
+            x1 = left,
+            tx = left - 7.0
+        );
+    }
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#,
+        left + (WIDTH - left - right) / 2.0,
+        HEIGHT - 8.0,
+        escape(x_label)
+    );
+    let _ = write!(
+        svg,
+        r#"<text transform="translate(14 {}) rotate(-90)" text-anchor="middle">{}</text>"#,
+        top + (HEIGHT - top - bottom) / 2.0,
+        escape(y_label)
+    );
+
+    let legend = series.len() > 1;
+    for (idx, series) in series.iter().enumerate() {
+        let points = series
+            .points
+            .iter()
+            .filter(|(x, y)| x.is_finite() && y.is_finite());
+        if series.scatter {
+            for &(px, py) in points {
+                let _ = write!(
+                    svg,
+                    r#"<circle cx="{:.1}" cy="{:.1}" r="1.5" fill="{}" fill-opacity="0.5"/>"#,
+                    x(px),
+                    y(py),
+                    series.color
+                );
+            }
+        } else {
+            svg.push_str(r#"<polyline fill="none" stroke-width="1.5" points=""#);
+            for &(px, py) in points {
+                let _ = write!(svg, "{:.1},{:.1} ", x(px), y(py));
+            }
+            let _ = write!(svg, r#"" stroke="{}"/>"#, series.color);
+        }
+        if legend {
+            let _ = write!(
+                svg,
+                r#"<text x="{}" y="{}" text-anchor="end" font-size="12" fill="{}">{}</text>"#,
+                WIDTH - right - 6.0,
+                top + 16.0 + 15.0 * idx as f32,
+                series.color,
+                escape(series.name)
+            );
+        }
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Counts of `values` in `bins` equal bins over `lo..=hi`, as the points of a
+/// step line
+fn histogram<I: Iterator<Item = f32>>(values: I, lo: f32, hi: f32, bins: usize) -> Vec<(f32, f32)> {
+    if hi <= lo {
+        return Vec::new();
+    }
+    let width = (hi - lo) / bins as f32;
+    let mut counts = vec![0usize; bins];
+    for value in values.filter(|value| value.is_finite()) {
+        let bin = ((value - lo) / width) as usize;
+        counts[bin.min(bins - 1)] += 1;
+    }
+    let mut points = vec![(lo, 0.0)];
+    for (bin, &count) in counts.iter().enumerate() {
+        let start = lo + bin as f32 * width;
+        points.push((start, count as f32));
+        points.push((start + width, count as f32));
+    }
+    points.push((hi, 0.0));
+    points
+}
+
+/// Range of the finite `values`, if any
+fn range<I: Iterator<Item = f32>>(values: I) -> (f32, f32) {
+    values
+        .filter(|value| value.is_finite())
+        .fold((f32::MAX, f32::MIN), |(lo, hi), value| {
+            (lo.min(value), hi.max(value))
+        })
+}
+
+/// Render the report of a run, and its rank-1 PSMs
+fn render(run: &RunReport, psms: &[Psm]) -> String {
+    let identified = psms
+        .iter()
+        .filter(|psm| !psm.decoy && psm.spectrum_q <= 0.01)
+        .collect::<Vec<_>>();
+
+    let mut q = psms
+        .iter()
+        .filter(|psm| !psm.decoy)
+        .map(|psm| psm.spectrum_q)
+        .collect::<Vec<_>>();
+    q.sort_by(|a, b| a.total_cmp(b));
+    let identifications = (0..=100)
+        .map(|step| {
+            let threshold = step as f32 * 0.001;
+            (threshold, q.partition_point(|&q| q <= threshold) as f32)
+        })
+        .collect();
+
+    let (lo, hi) = range(psms.iter().map(|psm| psm.score));
+    let scores = |decoy: bool| {
+        histogram(
+            psms.iter()
+                .filter(|psm| psm.decoy == decoy)
+                .map(|psm| psm.score),
+            lo,
+            hi,
+            HISTOGRAM_BINS,
+        )
+    };
+    let mass_errors = |error: fn(&Psm) -> f32| {
+        let (lo, hi) = range(identified.iter().map(|psm| error(psm)));
+        histogram(
+            identified.iter().map(|psm| error(psm)),
+            lo,
+            hi,
+            HISTOGRAM_BINS,
+        )
+    };
+    let step = (identified.len() + MAX_RT_POINTS - 1) / MAX_RT_POINTS;
+    let rt = identified
+        .iter()
+        .step_by(step.max(1))
+        .map(|psm| (psm.aligned_rt, psm.predicted_rt))
+        .collect::<Vec<_>>();
+    let (lo, hi) = range(rt.iter().flat_map(|&(x, y)| [x, y]));
+
+    let plots = [
+        plot(
+            "Identifications",
+            "spectrum-level q-value",
+            "target PSMs",
+            &[Series::line("targets", TARGET_COLOR, identifications)],
+        ),
+        plot(
+            "Discriminant scores",
+            "sage_discriminant_score",
+            "PSMs",
+            &[
+                Series::line("targets", TARGET_COLOR, scores(false)),
+                Series::line("decoys", DECOY_COLOR, scores(true)),
+            ],
+        ),
+        plot(
+            "Precursor mass error (1% FDR)",
+            "precursor error (ppm)",
+            "PSMs",
+            &[Series::line(
+                "targets",
+                TARGET_COLOR,
+                mass_errors(|psm| psm.precursor_ppm),
+            )],
+        ),
+        plot(
+            "Fragment mass error (1% FDR)",
+            "mean fragment error (ppm)",
+            "PSMs",
+            &[Series::line(
+                "targets",
+                TARGET_COLOR,
+                mass_errors(|psm| psm.fragment_ppm),
+            )],
+        ),
+        plot(
+            "Retention time model (1% FDR)",
+            "aligned retention time",
+            "predicted retention time",
+            &[
+                Series {
+                    name: "targets",
+                    color: TARGET_COLOR,
+                    points: rt,
+                    scatter: true,
+                },
+                Series::line("predicted = aligned", "gray", vec![(lo, lo), (hi, hi)]),
+            ],
+        ),
+        plot(
+            "Total ion current",
+            "retention time (min)",
+            "total ion current",
+            &[Series::line("TIC", "black", run.spectra.tic.clone())],
+        ),
+    ];
+
+    let percent = |value: f32| format!("{:.1}%", value * 100.0);
+    let rows = [
+        ("MS1 spectra", run.spectra.ms1_spectra.to_string()),
+        ("MS2 spectra", run.spectra.ms2_spectra.to_string()),
+        ("Filtered spectra", run.spectra.filtered_spectra.to_string()),
+        (
+            "Identified spectra (1% FDR)",
+            run.identified_spectra.to_string(),
+        ),
+        ("Identification rate", percent(run.identification_rate)),
+        (
+            "Median explained intensity",
+            run.median_explained_intensity
+                .map_or_else(|| "-".into(), percent),
+        ),
+        (
+            "Median MS2 total ion current",
+            format!("{:.3e}", run.spectra.median_total_ion_current),
+        ),
+        (
+            "Median MS2 base peak intensity",
+            format!("{:.3e}", run.spectra.median_base_peak_intensity),
+        ),
+        (
+            "Median MS2 ion injection time (ms)",
+            format!("{:.1}", run.spectra.median_ion_injection_time),
+        ),
+    ];
+
+    let mut html = String::new();
+    let title = escape(&run.filename);
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Sage report: {title}</title>\n\
+         <style>\n\
+         body {{ font-family: sans-serif; margin: 2em; }}\n\
+         table {{ border-collapse: collapse; margin-bottom: 1.5em; }}\n\
+         td {{ padding: 0.2em 1em; border-bottom: 1px solid #ddd; }}\n\
+         td:last-child {{ text-align: right; }}\n\
+         svg {{ margin: 0.5em; font-family: sans-serif; font-size: 13px; }}\n\
+         </style>\n</head>\n<body>\n<h1>Sage report: {title}</h1>\n<p>sage v{version}</p>\n<table>\n",
+        title = title,
+        version = clap::crate_version!()
+    );
+    for (name, value) in rows {
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", name, value);
+    }
+    html.push_str("</table>\n<div>\n");
+    for svg in plots {
+        html.push_str(&svg);
+        html.push('\n');
+    }
+    html.push_str("</div>\n</body>\n</html>\n");
+    html
+}
+
+pub fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let path = matches
+        .get_one::<String>("results")
+        .expect("required argument");
+    let results: Results = serde_json::from_str(
+        &sage_cloudpath::util::read_string(path)
+            .with_context(|| format!("Failed to read `{}`", path))?,
+    )
+    .with_context(|| format!("Failed to parse `{}`", path))?;
+    let output = |name: &str| {
+        results
+            .output_paths
+            .iter()
+            .find(|output| output.ends_with(name))
+            .with_context(|| {
+                format!(
+                    "`{}` lists no `{}` file: reports require the TSV results of a \
+                     search, i.e. one run without `--parquet`",
+                    path, name
+                )
+            })
+    };
+    let (tsv, qc) = (output("results.sage.tsv")?, output("qc.json")?);
+
+    let psms = read_psms(
+        &sage_cloudpath::util::read_string(tsv)
+            .with_context(|| format!("Failed to read `{}`", tsv))?,
+    )
+    .with_context(|| format!("Failed to parse `{}`", tsv))?;
+    let qc: Qc = serde_json::from_str(
+        &sage_cloudpath::util::read_string(qc)
+            .with_context(|| format!("Failed to read `{}`", qc))?,
+    )
+    .with_context(|| format!("Failed to parse `{}`", qc))?;
+    ensure!(!qc.runs.is_empty(), "no runs to report");
+
+    let directory = matches
+        .get_one::<String>("output_directory")
+        .map(String::as_str)
+        .unwrap_or_else(|| {
+            path.rsplit_once('/')
+                .map_or(".", |(directory, _)| directory)
+        });
+    let directory = directory.parse::<CloudPath>()?;
+    directory.mkdir()?;
+    for run in &qc.runs {
+        let html = render(run, psms.get(&run.filename).map_or(&[], Vec::as_slice));
+        let mut path = directory.clone();
+        path.push(format!("{}.report.html", run_name(&run.filename)));
+        path.write_bytes_sync(html.into_bytes())?;
+        info!("- {}: wrote report to `{}`", run.filename, path);
+    }
+    info!(
+        "wrote {} reports in {}s",
+        qc.runs.len(),
+        start.elapsed().as_secs()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_plots() -> anyhow::Result<()> {
+        let psms = read_psms(
+            "psm_id\tfilename\trank\tlabel\tsage_discriminant_score\tspectrum_q\tprecursor_ppm\tfragment_ppm\taligned_rt\tpredicted_rt\n\
+             1\ta.mzML\t1\t1\t2.5\t0.001\t1.5\t-2.0\t0.5\t0.45\n\
+             2\ta.mzML\t2\t1\t0.5\t0.5\t3.0\t-2.0\t0.5\t0.2\n\
+             3\ta.mzML\t1\t-1\t-1.0\t1\tNaN\t0\t0.7\t0.1\n\
+             4\tb.mzML\t1\t1\t1.0\t0.002\t-1.0\t1.0\t0.1\t0.1\n",
+        )?;
+        assert_eq!(psms["a.mzML"].len(), 2);
+        assert!(psms["a.mzML"][1].decoy);
+        assert_eq!(psms["b.mzML"][0].score, 1.0);
+
+        assert_eq!(ticks(0.0, 1.0).len(), 6);
+        assert_eq!(ticks(3.0, 95.0), vec![20.0, 40.0, 60.0, 80.0]);
+        assert_eq!(format_tick(0.25, 0.05), "0.25");
+        assert_eq!(format_tick(2.5E6, 1E6), "2.5e6");
+
+        assert_eq!(
+            histogram([0.0, 0.5, 1.0, f32::NAN].into_iter(), 0.0, 1.0, 2),
+            vec![
+                (0.0, 0.0),
+                (0.0, 1.0),
+                (0.5, 1.0),
+                (0.5, 2.0),
+                (1.0, 2.0),
+                (1.0, 0.0)
+            ]
+        );
+        assert!(histogram([1.0].into_iter(), 1.0, 1.0, 2).is_empty());
+
+        let svg = plot("<empty>", "x", "y", &[]);
+        assert!(svg.contains("&lt;empty&gt;") && svg.contains("no data"));
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! Machine-readable description of the configuration file and output files,
+//! printed by `sage schema`. The schema of the configuration file is also
+//! used to find misspelled or unsupported keys in parameter files
+
+use crate::input::{Input, Search};
+use crate::output::{
+    QcReport, CROSSLINK_COLUMNS, DIA_LIBRARY_COLUMNS, FEATURE_MATRIX_COLUMNS, FLASHLFQ_COLUMNS,
+    FRAGMENT_COLUMNS, LFQ_COLUMNS, LFQ_PROTEIN_COLUMNS, LIBRARY_COLUMNS, MERGED_COLUMNS,
+    MS1_FEATURE_COLUMNS, MSSTATS_COLUMNS, MSSTATS_TMT_COLUMNS, PIN_COLUMNS, PRM_COLUMNS,
+    PROTEIN_GROUP_COLUMNS, RESULTS_COLUMNS, RT_DIAGNOSTIC_COLUMNS, SILAC_COLUMNS,
+    SILAC_PEPTIDE_COLUMNS, TMT_COLUMNS, TMT_PROTEIN_COLUMNS,
+};
+use crate::sqlite::{FILE_COLUMNS, PEPTIDE_COLUMNS, PROTEIN_COLUMNS, SEARCH_COLUMNS};
+use sage_core::ml::linear_discriminant::FEATURE_NAMES;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Keys accepted by deserialization, but missing from the schema
+const ALIASES: &[&str] = &["fragment_ions"];
+
+/// Version of the output column specification. This must be incremented
+/// whenever columns are added, removed, renamed or reordered in an output file
+pub const OUTPUT_SPEC_VERSION: u32 = 23;
+
+#[derive(Serialize)]
+pub struct Schema {
+    pub version: String,
+    pub output_spec_version: u32,
+    /// JSON Schema of the configuration file
+    pub config: RootSchema,
+    /// JSON Schema of `results.json`
+    pub results_json: RootSchema,
+    /// JSON Schema of `qc.json`
+    pub qc_json: RootSchema,
+    /// JSON Schema of `diagnostics.json`
+    pub diagnostics_json: RootSchema,
+    /// Columns of tabular output files
+    pub outputs: Vec<OutputFile>,
+}
+
+#[derive(Serialize)]
+pub struct OutputFile {
+    pub filename: &'static str,
+    pub format: &'static str,
+    /// Table of an SQLite database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table: Option<&'static str>,
+    /// Fixed columns, in the order they are written
+    pub columns: Vec<String>,
+    /// Description of additional columns, whose names depend on the search
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamic_columns: Option<&'static str>,
+}
+
+impl OutputFile {
+    fn tsv(filename: &'static str, columns: &[&str]) -> Self {
+        OutputFile {
+            filename,
+            format: "tsv",
+            table: None,
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            dynamic_columns: None,
+        }
+    }
+
+    fn parquet(filename: &'static str, columns: Vec<String>) -> Self {
+        OutputFile {
+            filename,
+            format: "parquet",
+            table: None,
+            columns,
+            dynamic_columns: None,
+        }
+    }
+
+    fn sqlite(table: &'static str, columns: &[&str]) -> Self {
+        OutputFile {
+            filename: "results.sage.sqlite",
+            format: "sqlite",
+            table: Some(table),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            dynamic_columns: None,
+        }
+    }
+
+    fn with_dynamic_columns(mut self, description: &'static str) -> Self {
+        self.dynamic_columns = Some(description);
+        self
+    }
+}
+
+pub fn build() -> anyhow::Result<Schema> {
+    use sage_cloudpath::parquet::{
+        build_dia_library_schema, build_lfq_schema, build_matched_fragment_schema, build_schema,
+        column_names,
+    };
+
+    let feature_matrix_columns = FEATURE_MATRIX_COLUMNS
+        .iter()
+        .chain(FEATURE_NAMES.iter())
+        .chain(["sage_discriminant_score"].iter())
+        .copied()
+        .collect::<Vec<_>>();
+    let outputs = vec![
+        OutputFile::tsv("results.sage.tsv", RESULTS_COLUMNS).with_dynamic_columns(
+            "One column per reporter ion of `quant.tmt`, if set: reporter ion intensities of the \
+             spectrum of each PSM",
+        ),
+        OutputFile::tsv("results.cv{cv}.sage.tsv", RESULTS_COLUMNS)
+            .with_dynamic_columns("Same as `results.sage.tsv`"),
+        OutputFile::tsv("matched_fragments.sage.tsv", FRAGMENT_COLUMNS),
+        OutputFile::tsv("results.sage.pin", PIN_COLUMNS),
+        OutputFile::tsv("features.sage.tsv", &feature_matrix_columns),
+        OutputFile::tsv("flashlfq.tsv", FLASHLFQ_COLUMNS),
+        OutputFile::tsv("msstats.tsv", MSSTATS_COLUMNS),
+        OutputFile::tsv("msstats_tmt.tsv", MSSTATS_TMT_COLUMNS),
+        OutputFile::tsv("tmt.tsv", TMT_COLUMNS).with_dynamic_columns(
+            "One column per reporter ion of `quant.tmt`, followed by one `<channel>_sn` \
+                 column per reporter ion if `quant.tmt_settings.sn` is set",
+        ),
+        OutputFile::tsv("tmt_proteins.tsv", TMT_PROTEIN_COLUMNS).with_dynamic_columns(
+            "One column per reporter ion of `quant.tmt`, for each plex in \
+             `quant.tmt_settings.plexes`, named `<plex>_<channel>`",
+        ),
+        OutputFile::tsv("lfq.tsv", LFQ_COLUMNS)
+            .with_dynamic_columns("One column per file in `mzml_paths`"),
+        OutputFile::tsv("lfq_proteins.tsv", LFQ_PROTEIN_COLUMNS)
+            .with_dynamic_columns("One column per file in `mzml_paths`"),
+        OutputFile::tsv("silac.tsv", SILAC_COLUMNS),
+        OutputFile::tsv("silac_peptides.tsv", SILAC_PEPTIDE_COLUMNS),
+        OutputFile::tsv("protein_groups.tsv", PROTEIN_GROUP_COLUMNS),
+        OutputFile::tsv("library.sage.tsv", LIBRARY_COLUMNS),
+        OutputFile::tsv("library.dia.tsv", DIA_LIBRARY_COLUMNS),
+        OutputFile::tsv("crosslinks.tsv", CROSSLINK_COLUMNS),
+        OutputFile::tsv("prm.tsv", PRM_COLUMNS),
+        OutputFile::tsv("ms1_features.tsv", MS1_FEATURE_COLUMNS),
+        OutputFile::tsv("merged.sage.tsv", MERGED_COLUMNS),
+        OutputFile::tsv("rt_diagnostics.tsv", RT_DIAGNOSTIC_COLUMNS),
+        OutputFile::parquet("results.sage.parquet", column_names(&build_schema()?)),
+        OutputFile::parquet(
+            "matched_fragments.sage.parquet",
+            column_names(&build_matched_fragment_schema()?),
+        ),
+        OutputFile::parquet("lfq.parquet", column_names(&build_lfq_schema()?)),
+        OutputFile::parquet(
+            "library.dia.parquet",
+            column_names(&build_dia_library_schema()?),
+        ),
+        OutputFile::sqlite("search", SEARCH_COLUMNS),
+        OutputFile::sqlite("files", FILE_COLUMNS),
+        OutputFile::sqlite("psms", RESULTS_COLUMNS)
+            .with_dynamic_columns("Same as `results.sage.tsv`"),
+        OutputFile::sqlite("matched_fragments", FRAGMENT_COLUMNS),
+        OutputFile::sqlite("peptides", PEPTIDE_COLUMNS),
+        OutputFile::sqlite("proteins", PROTEIN_COLUMNS),
+        OutputFile::sqlite("tmt", TMT_COLUMNS).with_dynamic_columns("Same as `tmt.tsv`"),
+        OutputFile::sqlite("lfq", LFQ_COLUMNS).with_dynamic_columns("Same as `lfq.tsv`"),
+    ];
+
+    Ok(Schema {
+        version: clap::crate_version!().into(),
+        output_spec_version: OUTPUT_SPEC_VERSION,
+        config: schema_for!(Input),
+        results_json: schema_for!(Search),
+        qc_json: schema_for!(QcReport),
+        diagnostics_json: schema_for!(sage_core::ml::diagnostics::Diagnostics),
+        outputs,
+    })
+}
+
+/// `schema`, and the schemas reachable from it through references and
+/// combinators (`anyOf`, `oneOf`, `allOf`)
+fn alternatives<'a>(schema: &'a Value, definitions: &'a Value, out: &mut Vec<&'a Value>) {
+    let schema = match schema["$ref"].as_str() {
+        Some(reference) => &definitions[reference.trim_start_matches("#/definitions/")],
+        None => schema,
+    };
+    out.push(schema);
+    for combinator in ["anyOf", "oneOf", "allOf"] {
+        for subschema in schema[combinator].as_array().into_iter().flatten() {
+            alternatives(subschema, definitions, out);
+        }
+    }
+}
+
+/// Number of single-character edits between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+fn find_unknown_keys(
+    value: &Value,
+    schema: &Value,
+    definitions: &Value,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    let mut schemas = Vec::new();
+    alternatives(schema, definitions, &mut schemas);
+
+    match value {
+        Value::Object(object) => {
+            let properties = schemas
+                .iter()
+                .filter_map(|schema| schema["properties"].as_object())
+                .flatten()
+                .collect::<Vec<_>>();
+            // Maps, e.g. `static_mods`, accept any key
+            let map_values = schemas
+                .iter()
+                .map(|schema| &schema["additionalProperties"])
+                .find(|values| values.is_object());
+            for (key, value) in object {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                let property = properties.iter().find(|(name, _)| *name == key);
+                match (property.map(|(_, schema)| *schema), map_values) {
+                    (Some(schema), _) | (None, Some(schema)) => {
+                        find_unknown_keys(value, schema, definitions, &path, unknown)
+                    }
+                    (None, None) if properties.is_empty() || ALIASES.contains(&key.as_str()) => {}
+                    (None, None) => {
+                        let suggestion = properties
+                            .iter()
+                            .map(|(name, _)| (edit_distance(key, name), name.as_str()))
+                            .filter(|(distance, name)| {
+                                *distance <= 2.max(name.len() / 4)
+                                    || key.starts_with(*name)
+                                    || name.starts_with(key.as_str())
+                            })
+                            .min();
+                        unknown.push(match suggestion {
+                            Some((_, name)) => format!("`{}` (did you mean `{}`?)", path, name),
+                            None => format!("`{}`", path),
+                        });
+                    }
+                }
+            }
+        }
+        Value::Array(array) => {
+            let items = schemas
+                .iter()
+                .map(|schema| &schema["items"])
+                .filter(|items| items.is_object())
+                .collect::<Vec<_>>();
+            for (idx, value) in array.iter().enumerate() {
+                for schema in &items {
+                    let path = format!("{}[{}]", path, idx);
+                    find_unknown_keys(value, schema, definitions, &path, unknown);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Paths of the keys of a parameter file that are not part of the
+/// configuration schema, e.g. misspelled settings, which would otherwise be
+/// silently ignored
+pub fn unknown_keys(config: &Value) -> Vec<String> {
+    let schema = serde_json::to_value(schema_for!(Input)).expect("schema is valid JSON");
+    let mut unknown = Vec::new();
+    find_unknown_keys(config, &schema, &schema["definitions"], "", &mut unknown);
+    unknown
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_schema_covers_example() -> anyhow::Result<()> {
+        let schema = serde_json::to_value(build()?)?;
+        let properties = schema["config"]["properties"]
+            .as_object()
+            .expect("config schema has properties");
+
+        let example: serde_json::Value =
+            serde_json::from_str(include_str!("../../../tests/config.json"))?;
+        for key in example.as_object().expect("config is an object").keys() {
+            assert!(properties.contains_key(key), "{} missing from schema", key);
+        }
+
+        let outputs = schema["outputs"].as_array().expect("outputs");
+        let results = outputs
+            .iter()
+            .find(|output| output["filename"] == "results.sage.parquet")
+            .expect("parquet output");
+        assert!(results["columns"]
+            .as_array()
+            .expect("columns")
+            .contains(&"peptide".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn find_misspelled_keys() -> anyhow::Result<()> {
+        let mut example: Value = serde_json::from_str(include_str!("../../../tests/config.json"))?;
+        example["database"]["fragment_ions"] = serde_json::json!(["b", "y"]);
+        assert_eq!(unknown_keys(&example), Vec::<String>::new());
+
+        example["database"]["enzyme"]["cleave_att"] = "KR".into();
+        example["precursor_tolerance"] = serde_json::json!({ "ppm": [-10, 10] });
+        example["mzml_paths"] = serde_json::json!([{ "path": "a.mzML", "fragmnet_tol": {} }]);
+        assert_eq!(
+            unknown_keys(&example),
+            vec![
+                "`database.enzyme.cleave_att` (did you mean `cleave_at`?)",
+                "`mzml_paths[0].fragmnet_tol` (did you mean `fragment_tol`?)",
+                "`precursor_tolerance` (did you mean `precursor_tol`?)",
+            ]
+        );
+        Ok(())
+    }
+}
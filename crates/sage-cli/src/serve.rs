@@ -0,0 +1,402 @@
+//! Server mode: build the database once, and score spectra sent by other
+//! programs with low latency, e.g. for real-time search-driven acquisition
+//!
+//! Spectra are read from stdin, or from TCP connections if `--listen` is
+//! set, and the PSMs of each spectrum are written back in the same format:
+//! * `json`: one JSON object per line
+//! * `msgpack`: MessagePack maps, each prefixed by its length in bytes as a
+//!   4-byte big-endian integer
+//!
+//! Responses are written in the order of the requests. PSMs are scored as
+//! during a search, but FDR is not estimated: this requires the PSMs of a
+//! whole run
+
+use crate::input::{Input, Search};
+use crate::Runner;
+use clap::ArgMatches;
+use log::info;
+use sage_core::mass::Tolerance;
+use sage_core::scoring::{Feature, Scorer};
+use sage_core::spectrum::{Precursor, RawSpectrum, Representation, SpectrumProcessor};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+
+/// Encoding of requests and responses
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Msgpack,
+}
+
+/// A centroided MS2 spectrum to score
+#[derive(Deserialize)]
+struct Request {
+    /// Identifier of the spectrum, returned with its PSMs
+    #[serde(default)]
+    id: String,
+    precursor_mz: f32,
+    /// If not known, all charges of `precursor_charge` are searched
+    #[serde(default)]
+    precursor_charge: Option<u8>,
+    /// Isolation window around the precursor m/z, e.g. `{"da": [-0.7, 0.7]}`,
+    /// used by `wide_window` searches
+    #[serde(default)]
+    isolation_window: Option<Tolerance>,
+    /// Precursor inverse reduced ion mobility (1/K0), used by `mobility_filter`
+    #[serde(default)]
+    ion_mobility: Option<f32>,
+    #[serde(default)]
+    scan_start_time: f32,
+    mz: Vec<f32>,
+    intensity: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Response {
+    id: String,
+    psms: Vec<Psm>,
+    /// Why the request could not be scored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A PSM, with the same fields as `results.sage.tsv`
+#[derive(Serialize, Deserialize, Debug)]
+struct Psm {
+    peptide: String,
+    proteins: String,
+    label: i32,
+    rank: u32,
+    charge: u8,
+    expmass: f32,
+    calcmass: f32,
+    isotope_error: f32,
+    hyperscore: f64,
+    delta_next: f64,
+    matched_peaks: u32,
+    matched_intensity_pct: f32,
+    poisson: f64,
+}
+
+struct Server<'db> {
+    scorer: Scorer<'db>,
+    processor: SpectrumProcessor,
+    min_peaks: usize,
+    format: Format,
+}
+
+impl<'db> Server<'db> {
+    fn new(runner: &'db Runner, format: Format) -> Self {
+        let parameters = &runner.parameters;
+        Server {
+            scorer: runner.scorer(&runner.database, runner.prefilter.as_ref()),
+            processor: SpectrumProcessor {
+                deisotope_method: parameters.deisotope_method,
+                centroid: parameters.centroid,
+                rt_source: parameters.rt_source,
+                peak_cleanup: parameters.peak_cleanup,
+                peak_filter: parameters.peak_filter,
+                peak_mask: parameters.peak_mask,
+                ..SpectrumProcessor::new(
+                    parameters.max_peaks,
+                    parameters.database.fragment_min_mz,
+                    parameters.database.fragment_max_mz,
+                    parameters.deisotope,
+                )
+            },
+            min_peaks: parameters.min_peaks,
+            format,
+        }
+    }
+
+    fn psm(&self, feature: Feature) -> Psm {
+        let db = self.scorer.db;
+        let peptide = &db[feature.peptide_idx];
+        Psm {
+            peptide: peptide.to_string(),
+            proteins: peptide.proteins(&db.decoy_tag, db.generate_decoys),
+            label: feature.label,
+            rank: feature.rank,
+            charge: feature.charge,
+            expmass: feature.expmass,
+            calcmass: feature.calcmass,
+            isotope_error: feature.isotope_error,
+            hyperscore: feature.hyperscore,
+            delta_next: feature.delta_next,
+            matched_peaks: feature.matched_peaks,
+            matched_intensity_pct: feature.matched_intensity_pct,
+            poisson: feature.poisson,
+        }
+    }
+
+    fn score(&self, request: Request) -> Response {
+        if request.mz.len() != request.intensity.len() {
+            return Response {
+                id: request.id,
+                error: Some("`mz` and `intensity` must have the same length".into()),
+                ..Default::default()
+            };
+        }
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            id: request.id.clone(),
+            precursors: vec![Precursor {
+                mz: request.precursor_mz,
+                charge: request.precursor_charge,
+                isolation_window: request.isolation_window,
+                ion_mobility: request.ion_mobility,
+                ..Default::default()
+            }],
+            representation: Representation::Centroid,
+            scan_start_time: request.scan_start_time,
+            mz: request.mz,
+            intensity: request.intensity,
+            ..RawSpectrum::default_with_file_id(0)
+        };
+        let processed = self.processor.process(spectrum);
+        let psms = match processed.peaks.len() >= self.min_peaks {
+            true => self.scorer.score(&processed),
+            false => Vec::new(),
+        };
+        Response {
+            id: request.id,
+            psms: psms.into_iter().map(|feat| self.psm(feat)).collect(),
+            error: None,
+        }
+    }
+
+    /// Read the next request, or `None` at the end of the input. Malformed
+    /// requests are returned as errors, and skipped
+    fn read<R: BufRead>(&self, reader: &mut R) -> std::io::Result<Option<Result<Request, String>>> {
+        match self.format {
+            Format::Json => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        break;
+                    }
+                }
+                Ok(Some(serde_json::from_str(&line).map_err(|e| e.to_string())))
+            }
+            Format::Msgpack => {
+                let mut len = [0u8; 4];
+                if reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                reader.read_exact(&mut len)?;
+                let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                reader.read_exact(&mut frame)?;
+                Ok(Some(
+                    rmp_serde::from_slice(&frame).map_err(|e| e.to_string()),
+                ))
+            }
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, response: &Response) -> std::io::Result<()> {
+        match self.format {
+            Format::Json => {
+                serde_json::to_writer(&mut *writer, response)?;
+                writer.write_all(b"\n")?;
+            }
+            Format::Msgpack => {
+                let frame = rmp_serde::to_vec_named(response)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+                writer.write_all(&frame)?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Score every spectrum read from `reader`, until the end of the input.
+    /// Returns the number of scored spectra
+    fn serve<R: BufRead, W: Write>(&self, mut reader: R, mut writer: W) -> std::io::Result<usize> {
+        let mut scored = 0;
+        while let Some(request) = self.read(&mut reader)? {
+            let response = match request {
+                Ok(request) => {
+                    scored += 1;
+                    self.score(request)
+                }
+                Err(error) => Response {
+                    error: Some(format!("invalid request: {}", error)),
+                    ..Default::default()
+                },
+            };
+            self.write(&mut writer, &response)?;
+        }
+        Ok(scored)
+    }
+}
+
+/// Settings that need spectra files, or a different scoring function
+fn unsupported(parameters: &Search) -> Option<&'static str> {
+    [
+        (parameters.crosslink.is_some(), "`crosslink`"),
+        (parameters.prm.is_some(), "`prm`"),
+        (parameters.library_search.is_some(), "`library_search`"),
+        (parameters.recalibration.is_some(), "`recalibration`"),
+        (
+            parameters.database.max_index_memory_mb.is_some(),
+            "`database.max_index_memory_mb`",
+        ),
+    ]
+    .into_iter()
+    .find(|(enabled, _)| *enabled)
+    .map(|(_, name)| name)
+}
+
+/// Build the database, and score spectra from stdin or TCP connections
+/// until the input ends, or the server is stopped
+pub fn run(matches: ArgMatches) -> anyhow::Result<()> {
+    let format = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("msgpack") => Format::Msgpack,
+        _ => Format::Json,
+    };
+    let listen = matches.get_one::<String>("listen").cloned();
+
+    let mut parameters = Input::serve_from_arguments(matches)?.build()?;
+    // Spectra are scored one at a time, too few to be worth counting on a GPU
+    parameters.gpu = false;
+    if let Some(name) = unsupported(&parameters) {
+        anyhow::bail!("serve mode is not supported with {}", name);
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(parameters.threads)
+        .build_global()?;
+    let runner = Arc::new(Runner::new(parameters, None)?);
+    info!(
+        "- precursor window: {}",
+        Server::new(&runner, format).scorer.precursor_window()
+    );
+
+    let listen = match listen {
+        Some(listen) => listen,
+        None => {
+            info!("serve: ready, reading spectra from stdin");
+            let scored = Server::new(&runner, format)
+                .serve(std::io::stdin().lock(), std::io::stdout().lock())?;
+            info!("serve: scored {} spectra", scored);
+            return Ok(());
+        }
+    };
+
+    let listener = TcpListener::bind(&listen)?;
+    info!("serve: ready, listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let runner = Arc::clone(&runner);
+        std::thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            info!("serve: {} connected", peer);
+            // Responses are small: send them without waiting for more data
+            let _ = stream.set_nodelay(true);
+            let result = stream.try_clone().and_then(|writer| {
+                Server::new(&runner, format).serve(
+                    std::io::BufReader::new(stream),
+                    std::io::BufWriter::new(writer),
+                )
+            });
+            match result {
+                Ok(scored) => info!("serve: {} disconnected, scored {} spectra", peer, scored),
+                Err(e) => log::warn!("serve: {} disconnected: {}", peer, e),
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sage_core::database::Builder;
+    use sage_core::fasta::Fasta;
+    use sage_core::ion_series::{IonSeries, Kind};
+    use sage_core::mass::PROTON;
+
+    #[test]
+    fn score_requests() -> anyhow::Result<()> {
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder::default().make_parameters().build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap();
+        let mz = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| ion.monoisotopic_mass + PROTON)
+            .collect::<Vec<_>>();
+        let request = serde_json::json!({
+            "id": "scan=1",
+            "precursor_mz": peptide.monoisotopic / 2.0 + PROTON,
+            "precursor_charge": 2,
+            "mz": mz,
+            "intensity": vec![1.0; mz.len()],
+        });
+
+        let server = |format| Server {
+            scorer: Scorer::new(
+                &db,
+                Tolerance::Ppm(-10.0, 10.0),
+                Tolerance::Ppm(-10.0, 10.0),
+            ),
+            processor: SpectrumProcessor::new(150, 150.0, 2000.0, false),
+            min_peaks: 15,
+            format,
+        };
+
+        // Malformed requests are answered with an error, and skipped
+        let input = format!("{}\n\n{{\"id\": \"scan=2\"}}\n{}\n", request, request);
+        let mut output = Vec::new();
+        assert_eq!(
+            server(Format::Json).serve(input.as_bytes(), &mut output)?,
+            2
+        );
+        let responses = String::from_utf8(output)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Response>, _>>()?;
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0].id, "scan=1");
+        assert_eq!(responses[0].psms[0].peptide, "LQSRPAAPPAPGPGQLTLR");
+        assert_eq!(responses[0].psms[0].proteins, "sp|AAAA|AAAA");
+        assert!(responses[1].psms.is_empty());
+        assert!(responses[1].error.is_some());
+        assert_eq!(
+            responses[2].psms[0].matched_peaks,
+            responses[0].psms[0].matched_peaks
+        );
+
+        let frame = rmp_serde::to_vec_named(&request)?;
+        let mut input = (frame.len() as u32).to_be_bytes().to_vec();
+        input.extend(frame);
+        let mut output = Vec::new();
+        assert_eq!(
+            server(Format::Msgpack).serve(input.as_slice(), &mut output)?,
+            1
+        );
+        assert_eq!(
+            u32::from_be_bytes(output[..4].try_into()?) as usize,
+            output.len() - 4
+        );
+        let response: Response = rmp_serde::from_slice(&output[4..])?;
+        assert_eq!(response.psms[0].peptide, "LQSRPAAPPAPGPGQLTLR");
+        Ok(())
+    }
+}
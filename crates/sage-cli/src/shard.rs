@@ -0,0 +1,267 @@
+//! Searches sharded across machines
+//!
+//! `sage --shard 3/16` searches the third of 16 contiguous blocks of the
+//! input files, and saves its results - before rescoring and FDR - to
+//! `shard-3-of-16.bin` in `output_directory`. Once every shard is searched,
+//! e.g. by the jobs of a cluster job array, `sage merge --shards 16` loads
+//! them and writes the combined results of all files, rescored and filtered
+//! as if they had been searched by a single run.
+
+use crate::checkpoint::{config_hash, BatchOwned, BatchRef, Checkpoint};
+use crate::error::FailedFile;
+use crate::input::{FileOverrides, Search};
+use crate::SageResults;
+use anyhow::{ensure, Context};
+use log::warn;
+use sage_cloudpath::CloudPath;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// One of `count` shards of a search, numbered from 1
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+/// First line of a shard file, as JSON, followed by its results
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: String,
+    /// Hash of the search parameters, see [`config_hash`]
+    config_hash: String,
+    /// Position of the first file of the shard in `mzml_paths`
+    start: usize,
+    files: Vec<String>,
+    overrides: Vec<FileOverrides>,
+    /// Input files that could not be read, and why
+    failed: Vec<(String, String)>,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| "expected `INDEX/COUNT`, e.g. `3/16`".to_string())?;
+        let index = index
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| format!("invalid shard index `{}`: {}", index, e))?;
+        let count = count
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| format!("invalid shard count `{}`: {}", count, e))?;
+        match index >= 1 && index <= count {
+            true => Ok(Shard { index, count }),
+            false => Err(format!(
+                "shard index must be between 1 and the shard count, got {}/{}",
+                index, count
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// Positions in `mzml_paths` of the files searched by this shard, out of
+    /// `total` files. Shards differ by at most one file
+    pub fn files(&self, total: usize) -> Range<usize> {
+        total * (self.index - 1) / self.count..total * self.index / self.count
+    }
+
+    /// Path of the results of this shard
+    pub fn path(&self, parameters: &Search) -> CloudPath {
+        parameters.output_path(format!("shard-{}-of-{}.bin", self.index, self.count))
+    }
+
+    /// Check that a search can be sharded: its results must be computed one
+    /// file at a time
+    pub fn check(parameters: &Search) -> anyhow::Result<()> {
+        let unsupported = Checkpoint::unsupported(parameters)
+            .or_else(|| parameters.cascade.is_some().then_some("`cascade`"));
+        if let Some(name) = unsupported {
+            anyhow::bail!("sharded searches are not supported with {}", name);
+        }
+        if parameters.checkpoint {
+            warn!("`checkpoint` is not supported by sharded searches, and is disabled");
+        }
+        Ok(())
+    }
+
+    /// Save the search results of this shard
+    pub fn save(&self, parameters: &Search, results: &SageResults) -> anyhow::Result<CloudPath> {
+        let files = self.files(parameters.mzml_paths.len());
+        let header = Header {
+            version: parameters.version.clone(),
+            config_hash: config_hash(parameters)?,
+            start: files.start,
+            files: parameters.mzml_paths[files.clone()].to_vec(),
+            overrides: parameters.file_overrides[files].to_vec(),
+            failed: results
+                .failed
+                .iter()
+                .map(|file| (file.path.clone(), file.error.to_string()))
+                .collect(),
+        };
+        let mut bytes = serde_json::to_vec(&header)?;
+        bytes.push(b'\n');
+        bincode::serialize_into(&mut bytes, &BatchRef::new(results))?;
+        let path = self.path(parameters);
+        path.write_bytes_sync(bytes)
+            .with_context(|| format!("Failed to write shard results to `{}`", path))?;
+        Ok(path)
+    }
+
+    /// Load the search results of this shard, which must have been searched
+    /// with the same parameters and input files
+    fn load(&self, parameters: &Search, hash: &str) -> anyhow::Result<SageResults> {
+        let path = self.path(parameters);
+        let bytes = sage_cloudpath::util::read_bytes(path.to_string())?;
+        let split = bytes
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .context("not a shard results file")?;
+        let header = serde_json::from_slice::<Header>(&bytes[..split])?;
+        ensure!(
+            header.version == parameters.version && header.config_hash == hash,
+            "searched with different search parameters, or another version of Sage"
+        );
+        let files = self.files(parameters.mzml_paths.len());
+        ensure!(
+            header.start == files.start
+                && header.files == parameters.mzml_paths[files.clone()]
+                && header.overrides == parameters.file_overrides[files],
+            "searched with different input files, or a different shard count"
+        );
+
+        let mut results = bincode::deserialize::<BatchOwned>(&bytes[split + 1..])?.into_results();
+        results.failed = header
+            .failed
+            .into_iter()
+            .map(|(path, error)| FailedFile {
+                path,
+                error: std::io::Error::new(std::io::ErrorKind::Other, error).into(),
+            })
+            .collect();
+        Ok(results)
+    }
+
+    /// Load and combine the results of all `count` shards of a search
+    pub fn merge(parameters: &Search, count: usize) -> anyhow::Result<SageResults> {
+        let hash = config_hash(parameters)?;
+        let mut failed = Vec::new();
+        let results = (1..=count)
+            .filter_map(|index| {
+                let shard = Shard { index, count };
+                shard
+                    .load(parameters, &hash)
+                    .map_err(|e| failed.push(format!("  - `{}`: {}", shard.path(parameters), e)))
+                    .ok()
+            })
+            .collect::<SageResults>();
+        ensure!(
+            failed.is_empty(),
+            "{} of {} shards could not be merged:\n{}",
+            failed.len(),
+            count,
+            failed.join("\n")
+        );
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sage_core::scoring::Feature;
+
+    fn search(directory: &std::path::Path, files: usize) -> Search {
+        let input: crate::input::Input = serde_json::from_value(serde_json::json!({
+            "database": { "fasta": "none.fasta" },
+            "precursor_tol": { "ppm": [-10, 10] },
+            "fragment_tol": { "ppm": [-10, 10] },
+            "mzml_paths": (0..files).map(|idx| format!("{}.mzML", idx)).collect::<Vec<_>>(),
+            "output_directory": directory.display().to_string(),
+        }))
+        .unwrap();
+        input.build().unwrap()
+    }
+
+    #[test]
+    fn parse_shards() {
+        assert_eq!(
+            "3/16".parse(),
+            Ok(Shard {
+                index: 3,
+                count: 16
+            })
+        );
+        assert!("0/16".parse::<Shard>().is_err());
+        assert!("17/16".parse::<Shard>().is_err());
+        assert!("3".parse::<Shard>().is_err());
+
+        // Every file is searched by exactly one shard
+        for total in [0, 1, 5, 16, 100] {
+            let ranges = (1..=7)
+                .map(|index| Shard { index, count: 7 }.files(total))
+                .collect::<Vec<_>>();
+            assert_eq!(ranges.first().unwrap().start, 0);
+            assert_eq!(ranges.last().unwrap().end, total);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_shards() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join("sage-shard-test");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory)?;
+        let parameters = search(&directory, 5);
+
+        for index in 1..=2 {
+            let shard = Shard { index, count: 2 };
+            let files = shard.files(5);
+            let results = SageResults {
+                features: files
+                    .map(|file_id| Feature {
+                        file_id,
+                        psm_id: usize::MAX,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+            shard.save(&parameters, &results)?;
+        }
+        let merged = Shard::merge(&parameters, 2)?;
+        let mut file_ids = merged
+            .features
+            .iter()
+            .map(|feat| feat.file_id)
+            .collect::<Vec<_>>();
+        file_ids.sort_unstable();
+        assert_eq!(file_ids, vec![0, 1, 2, 3, 4]);
+        assert!(merged.features.iter().all(|feat| feat.psm_id != usize::MAX));
+
+        // Missing shards, and shards of another search, are not merged
+        assert!(Shard::merge(&parameters, 3).is_err());
+        let mut changed = search(&directory, 5);
+        changed.min_peaks += 1;
+        assert!(Shard::merge(&changed, 2).is_err());
+        assert!(Shard::merge(&search(&directory, 6), 2).is_err());
+
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
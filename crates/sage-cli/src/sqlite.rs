@@ -0,0 +1,321 @@
+//! SQLite results database (`results.sage.sqlite`)
+//!
+//! All results of a search are written to a single file, so that multi-file
+//! experiments produce one queryable artifact. The `psms`, `matched_fragments`,
+//! `tmt` and `lfq` tables have the same columns as the corresponding
+//! tab-separated output files. Peptide- and protein-level summaries of rank-1
+//! PSMs, the input files, and the search parameters (as JSON) are stored
+//! alongside.
+
+use crate::Runner;
+use fnv::{FnvHashMap, FnvHashSet};
+use rusqlite::{types::Value, Connection};
+use sage_core::database::PeptideIx;
+use sage_core::lfq::{Peak, PrecursorId};
+use sage_core::scoring::Feature;
+use sage_core::tmt::TmtQuant;
+use std::collections::HashMap;
+
+/// Integrated precursor areas of label-free quantification
+type LfqAreas = HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>;
+
+/// Columns of the `peptides` table
+pub const PEPTIDE_COLUMNS: &[&str] = &[
+    "peptide",
+    "proteins",
+    "label",
+    "psms",
+    "sage_discriminant_score",
+    "peptide_q",
+    "protein_q",
+];
+
+/// Columns of the `proteins` table
+pub const PROTEIN_COLUMNS: &[&str] = &["protein", "label", "peptides", "psms", "protein_q"];
+
+/// Columns of the `files` table
+pub const FILE_COLUMNS: &[&str] = &["file_id", "filename", "path"];
+
+/// Columns of the `search` table
+pub const SEARCH_COLUMNS: &[&str] = &["version", "parameters"];
+
+/// Indexed columns of each table
+const INDEXES: &[(&str, &str)] = &[
+    ("psms", "psm_id"),
+    ("psms", "peptide"),
+    ("psms", "filename, scannr"),
+    ("matched_fragments", "psm_id"),
+    ("peptides", "peptide"),
+    ("proteins", "protein"),
+    ("tmt", "psm_id"),
+    ("tmt", "filename, scannr"),
+    ("lfq", "peptide"),
+];
+
+/// Convert a field of a tab-separated record to an SQLite value: empty fields
+/// are NULL, and numbers are stored as integers or reals. Fields without any
+/// digits are never numbers, so that e.g. peptides "NAN" or "INF" are text
+fn value(field: &[u8]) -> Value {
+    let field = String::from_utf8_lossy(field);
+    if field.is_empty() {
+        return Value::Null;
+    }
+    if field.bytes().any(|b| b.is_ascii_digit()) {
+        if let Ok(x) = field.parse::<i64>() {
+            return Value::Integer(x);
+        }
+        if let Ok(x) = field.parse::<f64>() {
+            return Value::Real(x);
+        }
+    }
+    Value::Text(field.into_owned())
+}
+
+fn create_table(
+    conn: &Connection,
+    name: &str,
+    headers: &csv::ByteRecord,
+    records: &[csv::ByteRecord],
+) -> rusqlite::Result<()> {
+    let columns = headers
+        .iter()
+        .map(|column| {
+            format!(
+                "\"{}\"",
+                String::from_utf8_lossy(column).replace('"', "\"\"")
+            )
+        })
+        .collect::<Vec<_>>();
+    conn.execute(
+        &format!("CREATE TABLE {} ({})", name, columns.join(", ")),
+        [],
+    )?;
+
+    let placeholders = vec!["?"; columns.len()].join(", ");
+    let mut stmt = conn.prepare(&format!("INSERT INTO {} VALUES ({})", name, placeholders))?;
+    for record in records {
+        stmt.execute(rusqlite::params_from_iter(record.iter().map(value)))?;
+    }
+
+    for (_, columns) in INDEXES.iter().filter(|(table, _)| *table == name) {
+        let index = columns.replace(", ", "_");
+        conn.execute(
+            &format!("CREATE INDEX {name}_{index} ON {name} ({columns})"),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+impl Runner {
+    /// Header and rows of the `peptides` table: rank-1 PSMs, summarized by
+    /// peptide
+    fn peptides_table(&self, features: &[Feature]) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let mut peptides: FnvHashMap<PeptideIx, (usize, &Feature)> = FnvHashMap::default();
+        for feat in features.iter().filter(|feat| feat.rank == 1) {
+            let (psms, best) = peptides.entry(feat.peptide_idx).or_insert((0, feat));
+            *psms += 1;
+            if feat.discriminant_score > best.discriminant_score {
+                *best = feat;
+            }
+        }
+
+        let mut peptides = peptides.into_iter().collect::<Vec<_>>();
+        peptides.sort_by_key(|(peptide_idx, _)| *peptide_idx);
+        let records = peptides
+            .into_iter()
+            .map(|(peptide_idx, (psms, best))| {
+                let peptide = &self.database[peptide_idx];
+                let mut record = csv::ByteRecord::new();
+                record.push_field(peptide.to_string().as_bytes());
+                record.push_field(
+                    peptide
+                        .proteins(&self.database.decoy_tag, self.database.generate_decoys)
+                        .as_bytes(),
+                );
+                record.push_field(itoa::Buffer::new().format(best.label).as_bytes());
+                record.push_field(itoa::Buffer::new().format(psms).as_bytes());
+                record.push_field(
+                    ryu::Buffer::new()
+                        .format(best.discriminant_score)
+                        .as_bytes(),
+                );
+                record.push_field(ryu::Buffer::new().format(best.peptide_q).as_bytes());
+                record.push_field(ryu::Buffer::new().format(best.protein_q).as_bytes());
+                record
+            })
+            .collect();
+        (csv::ByteRecord::from(PEPTIDE_COLUMNS.to_vec()), records)
+    }
+
+    /// Header and rows of the `proteins` table: rank-1 PSMs, summarized by
+    /// protein. Shared peptides count towards each of their proteins
+    fn proteins_table(&self, features: &[Feature]) -> (csv::ByteRecord, Vec<csv::ByteRecord>) {
+        let mut proteins: HashMap<String, (i32, FnvHashSet<PeptideIx>, usize, f32)> =
+            HashMap::new();
+        for feat in features.iter().filter(|feat| feat.rank == 1) {
+            let accessions = self.database[feat.peptide_idx]
+                .proteins(&self.database.decoy_tag, self.database.generate_decoys);
+            for accession in accessions.split(';') {
+                let (label, peptides, psms, q) = proteins
+                    .entry(accession.to_string())
+                    .or_insert_with(|| (feat.label, FnvHashSet::default(), 0, 1.0));
+                peptides.insert(feat.peptide_idx);
+                *psms += 1;
+                *q = q.min(feat.protein_q);
+                *label = (*label).max(feat.label);
+            }
+        }
+
+        let mut proteins = proteins.into_iter().collect::<Vec<_>>();
+        proteins.sort_by(|a, b| a.0.cmp(&b.0));
+        let records = proteins
+            .into_iter()
+            .map(|(protein, (label, peptides, psms, q))| {
+                let mut record = csv::ByteRecord::new();
+                record.push_field(protein.as_bytes());
+                record.push_field(itoa::Buffer::new().format(label).as_bytes());
+                record.push_field(itoa::Buffer::new().format(peptides.len()).as_bytes());
+                record.push_field(itoa::Buffer::new().format(psms).as_bytes());
+                record.push_field(ryu::Buffer::new().format(q).as_bytes());
+                record
+            })
+            .collect();
+        (csv::ByteRecord::from(PROTEIN_COLUMNS.to_vec()), records)
+    }
+
+    fn populate_sqlite(
+        &self,
+        conn: &mut Connection,
+        features: &[Feature],
+        quant: &[TmtQuant],
+        areas: Option<&LfqAreas>,
+        filenames: &[String],
+    ) -> anyhow::Result<()> {
+        let tx = conn.transaction()?;
+
+        let mut search = csv::ByteRecord::new();
+        search.push_field(clap::crate_version!().as_bytes());
+        search.push_field(&serde_json::to_vec(&self.parameters)?);
+        create_table(
+            &tx,
+            "search",
+            &csv::ByteRecord::from(SEARCH_COLUMNS.to_vec()),
+            &[search],
+        )?;
+
+        let files = filenames
+            .iter()
+            .zip(&self.parameters.mzml_paths)
+            .enumerate()
+            .map(|(file_id, (filename, path))| {
+                let mut record = csv::ByteRecord::new();
+                record.push_field(itoa::Buffer::new().format(file_id).as_bytes());
+                record.push_field(filename.as_bytes());
+                record.push_field(path.as_bytes());
+                record
+            })
+            .collect::<Vec<_>>();
+        create_table(
+            &tx,
+            "files",
+            &csv::ByteRecord::from(FILE_COLUMNS.to_vec()),
+            &files,
+        )?;
+
+        let (headers, records) = self.results_table(features, quant, filenames);
+        create_table(&tx, "psms", &headers, &records)?;
+        if self.parameters.annotate_matches {
+            let (headers, records) = self.fragments_table(features);
+            create_table(&tx, "matched_fragments", &headers, &records)?;
+        }
+        let (headers, records) = self.peptides_table(features);
+        create_table(&tx, "peptides", &headers, &records)?;
+        let (headers, records) = self.proteins_table(features);
+        create_table(&tx, "proteins", &headers, &records)?;
+        if !quant.is_empty() {
+            let (headers, records) = self.tmt_table(quant, features, filenames);
+            create_table(&tx, "tmt", &headers, &records)?;
+        }
+        if let Some(areas) = areas {
+            let (headers, records) = self.lfq_table(areas, filenames);
+            create_table(&tx, "lfq", &headers, &records)?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn write_sqlite(
+        &self,
+        features: &[Feature],
+        quant: &[TmtQuant],
+        areas: Option<&LfqAreas>,
+        filenames: &[String],
+    ) -> anyhow::Result<String> {
+        let path = self.make_path("results.sage.sqlite");
+
+        // SQLite databases are built in a local file, which is then copied to
+        // the (possibly remote) output directory
+        let local =
+            std::env::temp_dir().join(format!("sage-{}-results.sage.sqlite", std::process::id()));
+        if local.exists() {
+            std::fs::remove_file(&local)?;
+        }
+        let result = Connection::open(&local)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                self.populate_sqlite(&mut conn, features, quant, areas, filenames)?;
+                conn.close().map_err(|(_, err)| err)?;
+                Ok(std::fs::read(&local)?)
+            });
+        let _ = std::fs::remove_file(&local);
+
+        path.write_bytes_sync(result?)?;
+        Ok(path.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sqlite_values() {
+        assert_eq!(value(b""), Value::Null);
+        assert_eq!(value(b"42"), Value::Integer(42));
+        assert_eq!(value(b"-1"), Value::Integer(-1));
+        assert_eq!(value(b"0.25"), Value::Real(0.25));
+        assert_eq!(value(b"1e-5"), Value::Real(1e-5));
+        assert_eq!(value(b"NAN"), Value::Text("NAN".into()));
+        assert_eq!(value(b"INF"), Value::Text("INF".into()));
+        assert_eq!(
+            value(b"PEPTIDE[+79.9663]K"),
+            Value::Text("PEPTIDE[+79.9663]K".into())
+        );
+
+        let conn = Connection::open_in_memory().unwrap();
+        let headers = csv::ByteRecord::from(vec!["psm_id", "peptide", "filename", "scannr"]);
+        let records = vec![
+            csv::ByteRecord::from(vec!["1", "NAN", "a.mzML", "scan=1"]),
+            csv::ByteRecord::from(vec!["2", "PEPTIDE", "a.mzML", ""]),
+        ];
+        create_table(&conn, "psms", &headers, &records).unwrap();
+        let (id, scannr): (i64, Option<String>) = conn
+            .query_row(
+                "SELECT psm_id, scannr FROM psms WHERE peptide = 'PEPTIDE'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((id, scannr), (2, None));
+        let indexes: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'psms'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 3);
+    }
+}
@@ -0,0 +1,126 @@
+//! Bundled end-to-end test dataset, searched by `sage test-data`: a single
+//! MS2 spectrum of LQSRPAAPPAPGPGQLTLR and the FASTA entry of its protein.
+//! The search results are checked against known-good values, to validate an
+//! installation or a custom build
+
+use crate::input::Input;
+use crate::Runner;
+use anyhow::{bail, Context};
+use log::info;
+use std::path::Path;
+
+const MZML: &str = include_str!("../../../tests/LQSRPAAPPAPGPGQLTLR.mzML");
+const FASTA: &str = include_str!("../../../tests/Q99536.fasta");
+const CONFIG: &str = include_str!("../../../tests/config-cli.json");
+
+/// Expected value of a column of `results.sage.tsv`
+enum Expected {
+    Exact(&'static str),
+    /// Expected value, and absolute tolerance
+    Within(f64, f64),
+}
+
+/// Expected top-ranked PSM
+const EXPECTED: &[(&str, Expected)] = &[
+    ("peptide", Expected::Exact("LQSRPAAPPAPGPGQLTLR")),
+    ("proteins", Expected::Exact("sp|Q99536|VAT1_HUMAN")),
+    ("rank", Expected::Exact("1")),
+    ("label", Expected::Exact("1")),
+    ("charge", Expected::Exact("3")),
+    ("matched_peaks", Expected::Exact("22")),
+    ("expmass", Expected::Within(1926.0815, 0.001)),
+    ("calcmass", Expected::Within(1926.08, 0.001)),
+    ("precursor_ppm", Expected::Within(0.824, 0.05)),
+    ("hyperscore", Expected::Within(72.27, 0.5)),
+    ("rt", Expected::Within(108.285, 0.001)),
+];
+
+impl Expected {
+    fn check(&self, value: &str) -> bool {
+        match self {
+            Expected::Exact(expected) => value == *expected,
+            Expected::Within(expected, tol) => value
+                .parse::<f64>()
+                .map_or(false, |value| (value - expected).abs() <= *tol),
+        }
+    }
+}
+
+impl std::fmt::Display for Expected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expected::Exact(expected) => write!(f, "{}", expected),
+            Expected::Within(expected, tol) => write!(f, "{} +/- {}", expected, tol),
+        }
+    }
+}
+
+/// Write the bundled dataset to `directory`, search it, and check the results.
+/// Returns an error describing every mismatched column
+pub fn run(directory: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create `{}`", directory.display()))?;
+    let mzml = directory.join("LQSRPAAPPAPGPGQLTLR.mzML");
+    let fasta = directory.join("Q99536.fasta");
+    std::fs::write(&mzml, MZML)?;
+    std::fs::write(&fasta, FASTA)?;
+
+    let mut config: serde_json::Value = serde_json::from_str(CONFIG)?;
+    config["database"]["fasta"] = fasta.to_string_lossy().into();
+    config["mzml_paths"] = serde_json::json!([mzml.to_string_lossy()]);
+    config["output_directory"] = directory.join("output").to_string_lossy().into();
+    let input: Input = serde_json::from_value(config)?;
+
+    input
+        .build()
+        .and_then(|p| Runner::new(p, None))?
+        .run(1, false)?;
+
+    let results = directory.join("output").join("results.sage.tsv");
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&results)
+        .with_context(|| format!("Failed to read `{}`", results.display()))?;
+    let headers = reader.headers()?.clone();
+    let record = match reader.records().next() {
+        Some(record) => record?,
+        None => bail!("test data: no PSMs were reported"),
+    };
+
+    let mut mismatches = Vec::new();
+    for (column, expected) in EXPECTED {
+        let value = headers
+            .iter()
+            .position(|header| header == *column)
+            .and_then(|idx| record.get(idx))
+            .with_context(|| format!("`{}` is missing from `{}`", column, results.display()))?;
+        if !expected.check(value) {
+            mismatches.push(format!(
+                "{}: expected {}, found {}",
+                column, expected, value
+            ));
+        }
+    }
+
+    if !mismatches.is_empty() {
+        bail!("test data: unexpected results\n{}", mismatches.join("\n"));
+    }
+    info!(
+        "test data: all {} checked values match the expected results",
+        EXPECTED.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bundled_dataset() -> anyhow::Result<()> {
+        let directory = std::env::temp_dir().join(format!("sage-test-data-{}", std::process::id()));
+        let result = run(&directory);
+        std::fs::remove_dir_all(&directory)?;
+        result
+    }
+}
@@ -0,0 +1,264 @@
+//! Watch mode: search spectra files as they are written, e.g. to follow a
+//! long acquisition queue
+//!
+//! Watched directories and glob patterns are polled for new spectra files. A
+//! file is searched once its size and modification time have not changed for
+//! `--settle` seconds, i.e. once the instrument or converter has finished
+//! writing it. After each batch of new files, all files found so far are
+//! rescored and quantified together, and the combined outputs are rewritten.
+//! The search results of each file are kept as checkpoints, so that every file
+//! is only searched once, also if watching is restarted.
+
+use crate::checkpoint::Checkpoint;
+use crate::error::Error;
+use crate::input::{find_spectra_files, FileOverrides, Input};
+use crate::Runner;
+use anyhow::Context;
+use clap::ArgMatches;
+use log::info;
+use sage_cloudpath::CloudPath;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size and modification time of a file, or of the files in a Bruker `.d`
+/// directory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileState {
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        if !metadata.is_dir() {
+            return Ok(FileState {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            });
+        }
+        let mut state = FileState {
+            len: 0,
+            modified: None,
+        };
+        for entry in std::fs::read_dir(path)? {
+            let metadata = entry?.metadata()?;
+            state.len += metadata.len();
+            state.modified = state.modified.max(metadata.modified().ok());
+        }
+        Ok(state)
+    }
+}
+
+/// Polls directories and glob patterns for new spectra files
+pub struct Watcher {
+    patterns: Vec<String>,
+    /// Time for which a file must be unchanged before it is searched
+    settle: Duration,
+    /// Files that may still be written, with their last observed state and
+    /// the time at which it was first observed
+    pending: HashMap<PathBuf, (FileState, Instant)>,
+    /// Files that were already returned by [`Watcher::poll`]
+    seen: HashSet<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(patterns: Vec<String>, settle: Duration) -> Self {
+        Watcher {
+            patterns,
+            settle,
+            pending: HashMap::default(),
+            seen: HashSet::default(),
+        }
+    }
+
+    /// New spectra files that have not changed for `settle` since the
+    /// previous poll(s), sorted by path
+    pub fn poll(&mut self) -> anyhow::Result<Vec<String>> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        for pattern in &self.patterns {
+            for path in find_spectra_files(pattern)?.unwrap_or_default() {
+                if self.seen.contains(&path) {
+                    continue;
+                }
+                // Files can disappear, e.g. if they are moved after writing
+                let state = match FileState::read(&path) {
+                    Ok(state) => state,
+                    Err(_) => continue,
+                };
+                match self.pending.get(&path) {
+                    Some((previous, since)) if *previous == state => {
+                        if now.duration_since(*since) >= self.settle {
+                            ready.push(path);
+                        }
+                    }
+                    _ => {
+                        self.pending.insert(path, (state, now));
+                    }
+                }
+            }
+        }
+        ready.sort();
+        ready.dedup();
+        for path in &ready {
+            self.pending.remove(path);
+            self.seen.insert(path.clone());
+        }
+        Ok(ready
+            .into_iter()
+            .map(|path| path.display().to_string())
+            .collect())
+    }
+}
+
+/// Watch directories for new spectra files, and search all files found so
+/// far whenever new files are complete. Runs until interrupted, or until no
+/// new files appeared for `--idle-exit` seconds
+pub fn run(matches: ArgMatches) -> anyhow::Result<()> {
+    let seconds = |name: &str| {
+        matches
+            .get_one::<u64>(name)
+            .copied()
+            .map(Duration::from_secs)
+    };
+    let interval = seconds("interval").unwrap_or(Duration::from_secs(10));
+    let settle = seconds("settle").unwrap_or(Duration::from_secs(30));
+    let idle_exit = seconds("idle-exit");
+    let parquet = matches.get_one::<bool>("parquet").copied().unwrap_or(false);
+    let mut overwrite = matches
+        .get_one::<bool>("overwrite")
+        .copied()
+        .unwrap_or(false);
+    let send_telemetry = matches
+        .get_one::<bool>("disable-telemetry")
+        .copied()
+        .unwrap_or(true);
+
+    let patterns = matches
+        .get_many::<String>("mzml_paths")
+        .context("no directories to watch")?
+        .cloned()
+        .collect::<Vec<_>>();
+    for pattern in &patterns {
+        anyhow::ensure!(
+            find_spectra_files(pattern)?.is_some(),
+            "`{}`: only local directories and glob patterns can be watched",
+            pattern
+        );
+    }
+
+    let mut parameters = Input::from_arguments(matches)?.build()?;
+    if let Some(name) = Checkpoint::unsupported(&parameters) {
+        anyhow::bail!("watch mode is not supported with {}", name);
+    }
+    anyhow::ensure!(
+        parameters.tmt_design.is_none(),
+        "watch mode is not supported with `quant.tmt_settings.plexes`"
+    );
+    anyhow::ensure!(
+        matches!(parameters.output_directory, CloudPath::Local(_)),
+        "watch mode requires a local `output_directory`"
+    );
+    // Each file is a separate batch, so that its results can be re-used
+    // whatever the files that appear after it
+    parameters.checkpoint = true;
+    parameters.batch_size = 1;
+    crate::check_existing_results(&parameters, overwrite)?;
+
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(parameters.threads)
+        .build_global()?;
+    info!(
+        "watch: polling {} every {}s, searching files once unchanged for {}s, using {} threads",
+        patterns.join(", "),
+        interval.as_secs(),
+        settle.as_secs(),
+        parameters.threads
+    );
+
+    let mut watcher = Watcher::new(patterns, settle);
+    let mut files: Vec<String> = Vec::new();
+    let mut telemetry = None;
+    let mut last_update = Instant::now();
+    loop {
+        let ready = watcher.poll()?;
+        if !ready.is_empty() {
+            info!("watch: {} new files: {}", ready.len(), ready.join(", "));
+            files.extend(ready);
+
+            let mut search = parameters.clone();
+            search.mzml_paths = files.clone();
+            search.file_overrides = vec![FileOverrides::default(); files.len()];
+            let checkpoint = Checkpoint::open(&search, overwrite)?.map(Checkpoint::keep);
+            overwrite = false;
+            match Runner::new(search, checkpoint).and_then(|runner| runner.run(1, parquet)) {
+                Ok(t) => telemetry = Some(t),
+                Err(e) => match e.downcast::<Error>()? {
+                    Error::InputFiles { failed, .. } => {
+                        // Results of the other files are written: unreadable
+                        // files are not retried
+                        for file in failed {
+                            log::error!("watch: ignoring {}", file);
+                            files.retain(|path| path != &file.path);
+                        }
+                    }
+                },
+            }
+            info!(
+                "watch: wrote results of {} files, waiting for new files",
+                files.len()
+            );
+            last_update = Instant::now();
+        } else if idle_exit.map_or(false, |idle| last_update.elapsed() >= idle) {
+            info!(
+                "watch: no new files for {}s, exiting",
+                last_update.elapsed().as_secs()
+            );
+            break;
+        }
+        std::thread::sleep(interval);
+    }
+
+    if let (true, Some(telemetry)) = (send_telemetry, telemetry) {
+        telemetry.send();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wait_for_unchanged_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("sage-watch-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let mut watcher = Watcher::new(vec![dir.display().to_string()], Duration::ZERO);
+        assert!(watcher.poll()?.is_empty());
+
+        // New files are only returned once unchanged between two polls
+        std::fs::write(dir.join("b.mzML"), "<mzML>")?;
+        std::fs::write(dir.join("a.mzML"), "<mzML>")?;
+        std::fs::write(dir.join("notes.txt"), "")?;
+        assert!(watcher.poll()?.is_empty());
+        std::fs::write(dir.join("b.mzML"), "<mzML></mzML>")?;
+        let ready = watcher.poll()?;
+        assert_eq!(ready, vec![dir.join("a.mzML").display().to_string()]);
+        assert_eq!(
+            watcher.poll()?,
+            vec![dir.join("b.mzML").display().to_string()]
+        );
+        assert!(watcher.poll()?.is_empty());
+
+        // Files must be unchanged for `settle`
+        let mut watcher = Watcher::new(vec![dir.display().to_string()], Duration::from_secs(60));
+        assert!(watcher.poll()?.is_empty());
+        assert!(watcher.poll()?.is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
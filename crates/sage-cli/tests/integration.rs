@@ -1,8 +1,10 @@
 use sage_core::database::Builder;
 use sage_core::enzyme::Digest;
-use sage_core::mass::Tolerance;
+use sage_core::mass::{Tolerance, VALID_AA};
+use sage_core::ml::mobility_model::{MobilityFilter, MobilityModel};
 use sage_core::peptide::Peptide;
-use sage_core::scoring::Scorer;
+use sage_core::prefilter::{PrefilterSettings, SpectralIndex};
+use sage_core::scoring::{IsotopeErrorMode, Scorer};
 use sage_core::spectrum::SpectrumProcessor;
 
 #[test]
@@ -20,26 +22,93 @@ fn integration() -> anyhow::Result<()> {
     assert!(processed.peaks.len() <= 300);
 
     let scorer = Scorer {
-        db: &database,
-        precursor_tol: Tolerance::Ppm(-50.0, 50.0),
-        fragment_tol: Tolerance::Ppm(-10.0, 10.0),
-        min_matched_peaks: 4,
         min_isotope_err: -1,
         max_isotope_err: 3,
-        min_precursor_charge: 2,
-        max_precursor_charge: 4,
         max_fragment_charge: Some(1),
         min_fragment_mass: 0.0,
         max_fragment_mass: 1500.0,
-        chimera: false,
-        report_psms: 1,
-        wide_window: false,
-        annotate_matches: false,
+        localize: false,
+        ..Scorer::new(
+            &database,
+            Tolerance::Ppm(-50.0, 50.0),
+            Tolerance::Ppm(-10.0, 10.0),
+        )
     };
 
     let psm = scorer.score(&processed);
     assert_eq!(psm.len(), 1);
     assert_eq!(psm[0].matched_peaks, 21);
 
+    let widened = Scorer {
+        isotope_error_mode: IsotopeErrorMode::Widen,
+        ..scorer
+    };
+    let psm = widened.score(&processed);
+    assert_eq!(psm.len(), 1);
+    assert_eq!(psm[0].matched_peaks, 21);
+    assert_eq!(psm[0].isotope_error, 0.0);
+
+    // Simulate an unknown +79.966 Da modification on the precursor. This is
+    // outside of the narrow precursor window, but is found by an open search
+    let mut shifted = processed.clone();
+    let charge = shifted.precursors[0].charge.unwrap_or(2) as f32;
+    shifted.precursors[0].mz += 79.966 / charge;
+    assert!(scorer.score(&shifted).is_empty());
+
+    let open = Scorer {
+        precursor_tol: Tolerance::Da(-500.0, 100.0),
+        min_isotope_err: 0,
+        max_isotope_err: 0,
+        open_search: true,
+        ..scorer
+    };
+    let psm = open.score(&shifted);
+    assert_eq!(psm.len(), 1);
+    assert_eq!(
+        database[psm[0].peptide_idx].to_string(),
+        "LQSRPAAPPAPGPGQLTLR"
+    );
+    assert!((psm[0].mass_offset - 79.966).abs() < 0.01);
+    assert!(psm[0].matched_peaks >= 21);
+
+    // Only peptides shortlisted by the approximate nearest-neighbor prefilter
+    // are scored, but exact scores are unchanged
+    let index = SpectralIndex::build(&database, PrefilterSettings::default());
+    let prefiltered = Scorer {
+        prefilter: Some(&index),
+        ..scorer
+    };
+    let psm = prefiltered.score(&processed);
+    assert_eq!(psm.len(), 1);
+    assert_eq!(
+        database[psm[0].peptide_idx].to_string(),
+        "LQSRPAAPPAPGPGQLTLR"
+    );
+    assert_eq!(psm[0].matched_peaks, 21);
+
+    // Candidates are only scored if their predicted ion mobility is close to
+    // that of the spectrum - here, a model predicting a constant 1/K0 of 1.0
+    let mut beta = vec![0.0; VALID_AA.len() + 6];
+    *beta.last_mut().unwrap() = 1.0;
+    let model: MobilityModel = serde_json::from_value(serde_json::json!({
+        "beta": beta,
+        "map": vec![0; 26],
+        "r2": 1.0,
+    }))?;
+    let filter = MobilityFilter {
+        model,
+        tolerance: 0.05,
+    };
+    let filtered = Scorer {
+        mobility_filter: Some(&filter),
+        ..scorer
+    };
+    assert_eq!(filtered.score(&processed).len(), 1);
+    let mut mobile = processed.clone();
+    mobile.ion_mobility = Some(1.02);
+    assert_eq!(filtered.score(&mobile).len(), 1);
+    mobile.ion_mobility = Some(1.2);
+    assert!(filtered.score(&mobile).is_empty());
+
     Ok(())
 }
@@ -6,8 +6,13 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWriteExt, BufReader};
 
+mod gcs;
 pub mod mgf;
 pub mod mzml;
+pub mod mzxml;
+pub mod numpress;
+mod ranged;
+pub mod raw;
 pub mod tdf;
 pub mod util;
 
@@ -26,9 +31,22 @@ async fn s3_client() -> &'static aws_sdk_s3::Client {
     }
 }
 
+static HTTP_CLIENT: once_cell::sync::Lazy<reqwest::Client> =
+    once_cell::sync::Lazy::new(reqwest::Client::new);
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum CloudPath {
-    S3 { bucket: String, key: String },
+    S3 {
+        bucket: String,
+        key: String,
+    },
+    /// A read-only Google Cloud Storage object
+    Gcs {
+        bucket: String,
+        key: String,
+    },
+    /// A read-only object, fetched over HTTP(S)
+    Http(String),
     Local(PathBuf),
 }
 
@@ -50,6 +68,16 @@ impl FromStr for CloudPath {
                     .into();
                 Ok(Self::S3 { bucket, key })
             }
+            Some("http") | Some("https") => Ok(Self::Http(s.into())),
+            Some("gs") => {
+                let bucket = uri.authority().ok_or(Error::InvalidUri)?.to_string();
+                let key = uri
+                    .path_and_query()
+                    .and_then(|path| path.path().strip_prefix('/'))
+                    .ok_or(Error::InvalidUri)?
+                    .into();
+                Ok(Self::Gcs { bucket, key })
+            }
             Some(_) => Err(Error::InvalidUri),
             None => Ok(Self::Local(s.into())),
         }
@@ -60,6 +88,8 @@ impl std::fmt::Display for CloudPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CloudPath::S3 { bucket, key } => write!(f, "s3://{}/{}", bucket, key),
+            CloudPath::Gcs { bucket, key } => write!(f, "gs://{}/{}", bucket, key),
+            CloudPath::Http(url) => write!(f, "{}", url),
             CloudPath::Local(path) => write!(f, "{}", path.display()),
         }
     }
@@ -69,17 +99,17 @@ impl CloudPath {
     /// If `self` is a local path, recursively create directories as needed
     pub fn mkdir(&self) -> std::io::Result<()> {
         match self {
-            CloudPath::S3 { .. } => Ok(()),
+            CloudPath::S3 { .. } | CloudPath::Gcs { .. } | CloudPath::Http(_) => Ok(()),
             CloudPath::Local(path) => std::fs::create_dir_all(path),
         }
     }
 
     /// Push a filename to the current path
-    /// * If `self` is an S3 path, this adds to end of the object key, using '/' as a delimiter
+    /// * If `self` is an S3 or GCS path, this adds to end of the object key, using '/' as a delimiter
     /// * If `self` is a local path, this behaves the same as [`PathBuf::push`]
     pub fn push<P: AsRef<str>>(&mut self, path: P) {
         match self {
-            CloudPath::S3 { key, .. } => {
+            CloudPath::S3 { key, .. } | CloudPath::Gcs { key, .. } | CloudPath::Http(key) => {
                 if !key.is_empty() {
                     key.push('/');
                 }
@@ -92,7 +122,8 @@ impl CloudPath {
 
     pub fn filename(&self) -> Option<&str> {
         match self {
-            CloudPath::S3 { key, .. } => key.split('/').last(),
+            CloudPath::S3 { key, .. } | CloudPath::Gcs { key, .. } => key.split('/').last(),
+            CloudPath::Http(url) => url.split(['?', '#']).next()?.rsplit('/').next(),
             CloudPath::Local(path) => path.file_name().and_then(|s| s.to_str()),
         }
     }
@@ -100,7 +131,10 @@ impl CloudPath {
     /// Does the path end in "gz" or "gzip"?
     fn gzip_heuristic(&self) -> bool {
         match &self {
-            Self::S3 { key, .. } => key.ends_with("gz") || key.ends_with("gzip"),
+            Self::S3 { .. } | Self::Gcs { .. } | Self::Http(_) => {
+                let name = self.filename().unwrap_or_default().to_ascii_lowercase();
+                name.ends_with("gz") || name.ends_with("gzip")
+            }
             Self::Local(path) => match path.extension() {
                 Some(ext) => ext.to_ascii_lowercase() == "gz" || ext.to_ascii_lowercase() == "gzip",
                 _ => false,
@@ -125,15 +159,48 @@ impl CloudPath {
 
                 Ok(BufReader::new(Box::new(object.body.into_async_read())))
             }
+            Self::Gcs { bucket, key } => {
+                let token = gcs::access_token().await?;
+                let url = format!("https://storage.googleapis.com/{}/{}", bucket, key);
+                let read = ranged::RangedRead::open(url, token.clone(), ranged::RANGE_SIZE)
+                    .await
+                    .map_err(|err| match err.status() {
+                        Some(reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::UNAUTHORIZED) if token.is_none() => {
+                            Error::Gcs(format!(
+                                "{}: no credentials were found, so only public objects can be read \
+                                 - set `GOOGLE_OAUTH_ACCESS_TOKEN` or `GOOGLE_APPLICATION_CREDENTIALS`",
+                                err
+                            ))
+                        }
+                        _ => Error::Http(err),
+                    })?;
+                Ok(Self::stream(read))
+            }
+            Self::Http(url) => {
+                let read = ranged::RangedRead::open(url.clone(), None, ranged::RANGE_SIZE).await?;
+                Ok(Self::stream(read))
+            }
             Self::Local(path) => Ok(BufReader::new(Box::new(tokio::fs::File::open(path).await?))),
         }
     }
 
+    /// Stream the object in ranges, rather than buffering it in memory
+    fn stream(read: ranged::RangedRead) -> BufReader<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        let body = futures::stream::unfold(read, |mut read| async move {
+            let chunk = read.next().await?;
+            Some((chunk, read))
+        });
+        BufReader::new(Box::new(tokio_util::io::StreamReader::new(Box::pin(body))))
+    }
+
     pub async fn read(&self) -> Result<Box<dyn AsyncBufRead + Unpin + Send + Sync>, Error> {
         let reader = self.mk_bufreader().await?;
         match self.gzip_heuristic() {
             true => {
-                let gzip = GzipDecoder::new(reader);
+                // Files compressed in parallel (e.g. with bgzip) or concatenated
+                // consist of multiple gzip members, all of which are decompressed
+                let mut gzip = GzipDecoder::new(reader);
+                gzip.multiple_members(true);
                 Ok(Box::new(BufReader::new(gzip)))
             }
             false => Ok(Box::new(reader)),
@@ -146,7 +213,8 @@ impl CloudPath {
                 let inner = Vec::with_capacity(bytes.len() / 2);
                 let mut wtr = GzipEncoder::new(inner);
                 wtr.write_all(&bytes).await?;
-                wtr.flush().await?;
+                // Shutting down the encoder writes the gzip trailer
+                wtr.shutdown().await?;
                 wtr.into_inner()
             }
             false => bytes,
@@ -158,6 +226,7 @@ impl CloudPath {
                 Ok(())
             }
             Self::S3 { bucket, key } => Ok(multipart_upload(bucket, key, bytes).await?),
+            Self::Gcs { .. } | Self::Http(_) => Err(Error::ReadOnly(self.to_string())),
         }
     }
 
@@ -249,7 +318,7 @@ where
     let path = path.as_ref().parse::<CloudPath>()?;
 
     // return an error in case we are trying to read from a bucket without a key
-    if let CloudPath::S3 { bucket: _, key } = &path {
+    if let CloudPath::S3 { key, .. } | CloudPath::Gcs { key, .. } = &path {
         if key.is_empty() {
             return Err(Error::InvalidUri);
         }
@@ -269,6 +338,12 @@ where
 pub enum Error {
     #[error("invalid uri")]
     InvalidUri,
+    #[error("HTTP(S) and GCS paths are read-only, cannot write to {0}")]
+    ReadOnly(String),
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("google cloud storage error: {0}")]
+    Gcs(String),
     #[error("s3 error: {}", DisplayErrorContext(.0))]
     S3(
         #[from]
@@ -281,10 +356,14 @@ pub enum Error {
     Json(#[from] serde_json::Error),
     #[error("MzML error: {0}")]
     MzML(#[from] mzml::MzMLError),
+    #[error("mzXML error: {0}")]
+    MzXML(#[from] mzxml::MzXMLError),
     #[error("TDF error: {0}")]
     TDF(#[from] timsrust::Error),
     #[error("MGF error: {0}")]
     MGF(#[from] mgf::MgfError),
+    #[error("RAW error: {0}")]
+    Raw(#[from] raw::RawError),
 }
 
 #[cfg(test)]
@@ -342,6 +421,52 @@ mod test {
         );
     }
 
+    #[test]
+    fn read_multiple_gzip_members() {
+        let dir = std::env::temp_dir().join(format!("sage-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut concatenated = Vec::new();
+        for (idx, member) in ["<mzML>", "</mzML>"].iter().enumerate() {
+            let path = CloudPath::Local(dir.join(format!("{}.gz", idx)));
+            path.write_bytes_sync(member.as_bytes().to_vec()).unwrap();
+            concatenated.extend(std::fs::read(dir.join(format!("{}.gz", idx))).unwrap());
+        }
+        let path = dir.join("test.mzML.gz");
+        std::fs::write(&path, concatenated).unwrap();
+
+        let contents = crate::util::read_string(path.to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(contents, "<mzML></mzML>");
+    }
+
+    #[test]
+    fn cloudpath_http() {
+        let a = "https://example.com/PXD000001/file.mzML.gz?token=abc"
+            .parse::<CloudPath>()
+            .unwrap();
+        assert_eq!(
+            a,
+            CloudPath::Http("https://example.com/PXD000001/file.mzML.gz?token=abc".into())
+        );
+        assert_eq!(a.filename(), Some("file.mzML.gz"));
+        assert!(a.gzip_heuristic());
+
+        let b = "gs://my-bucket/prefix/file.mzML"
+            .parse::<CloudPath>()
+            .unwrap();
+        assert_eq!(
+            b,
+            CloudPath::Gcs {
+                bucket: "my-bucket".into(),
+                key: "prefix/file.mzML".into()
+            }
+        );
+        assert_eq!(b.to_string(), "gs://my-bucket/prefix/file.mzML");
+        assert_eq!(b.filename(), Some("file.mzML"));
+        assert!(!b.gzip_heuristic());
+        assert!(b.write_bytes_sync(Vec::new()).is_err());
+    }
+
     #[test]
     fn invalid_file_cloudpath() {
         assert!(read_and_execute("s3://my-bucket", |_| async move { Ok(()) }).is_err())
@@ -339,7 +339,7 @@ impl MgfReader {
 
         // embedded parameters
         while !default_params.is_query_start {
-            let line = lines.next().unwrap().trim();
+            let line = lines.next().ok_or(MgfError::NoSpectra)?.trim();
             for parser in &default_parsers {
                 match parser(line, &mut default_params) {
                     Ok(true) => break,
@@ -373,6 +373,8 @@ impl MgfReader {
 pub enum MgfError {
     #[error("malformed MGF: {location}")]
     Malformed { location: Location<'static> },
+    #[error("no `BEGIN IONS` section found, is this an MGF file?")]
+    NoSpectra,
     #[error("unsupported cvParam {0}")]
     UnsupportedCV(String),
     #[error("io error: {0}")]
@@ -469,6 +471,15 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn parse_not_mgf() {
+        let content = "<?xml version=\"1.0\"?>\n<mzML>\n</mzML>\n".to_string();
+        assert!(matches!(
+            MgfReader::with_file_id(0).parse(content),
+            Err(MgfError::NoSpectra)
+        ));
+    }
+
     #[tokio::test]
     /// Example taken from https://www.matrixscience.com/help/data_file_help.html
     async fn parse_mgf_matrixscience_example_1() -> Result<(), MgfError> {
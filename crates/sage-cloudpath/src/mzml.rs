@@ -1,9 +1,12 @@
-use async_compression::tokio::bufread::ZlibDecoder;
+use crate::numpress::{Numpress, NumpressError};
+use flate2::read::ZlibDecoder;
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use sage_core::spectrum::{Precursor, Representation};
+use rayon::prelude::*;
+use sage_core::spectrum::{Precursor, Representation, ScanWindow};
 use sage_core::{mass::Tolerance, spectrum::RawSpectrum};
-use tokio::io::{AsyncBufRead, AsyncReadExt};
+use std::io::Read;
+use tokio::io::AsyncBufRead;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 /// Which tag are we inside?
@@ -21,6 +24,7 @@ enum BinaryKind {
     Intensity,
     Mz,
     Noise,
+    Mobility,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -29,14 +33,142 @@ enum Dtype {
     F64,
 }
 
+/// Decoding binary data arrays (base64, zlib, conversion to floats) takes a
+/// large fraction of the time spent reading mzML files. Arrays are kept
+/// encoded while the XML is parsed, and the arrays of up to this many
+/// spectra...
+const DECODE_BATCH_SPECTRA: usize = 256;
+/// ... or of this many bytes of encoded data, are decoded in parallel
+const DECODE_BATCH_BYTES: usize = 32 * 1024 * 1024;
+
+/// Largest expected ratio of the inflated to the compressed size of a
+/// zlib-compressed array, which bounds the buffer reserved to inflate it
+const MAX_INFLATION: usize = 8;
+
+/// A binary data array, as found in the file
+struct EncodedArray {
+    kind: BinaryKind,
+    dtype: Dtype,
+    compression: bool,
+    numpress: Option<Numpress>,
+    /// Base64-encoded data
+    data: Vec<u8>,
+}
+
+impl EncodedArray {
+    /// Decode the array, which holds `length` values (`defaultArrayLength`)
+    fn decode(&self, length: usize) -> Result<Vec<f32>, MzMLError> {
+        let decoded = base64::decode(&self.data)?;
+        let inflated;
+        let bytes = match self.compression {
+            false => decoded.as_slice(),
+            true => {
+                let width = match self.dtype {
+                    Dtype::F32 => 4,
+                    Dtype::F64 => 8,
+                };
+                // `length` is read from the file: don't trust it beyond what
+                // the compressed data could plausibly inflate to
+                let capacity = length
+                    .saturating_mul(width)
+                    .min(decoded.len().saturating_mul(MAX_INFLATION));
+                let mut buffer = Vec::with_capacity(capacity);
+                ZlibDecoder::new(decoded.as_slice()).read_to_end(&mut buffer)?;
+                inflated = buffer;
+                inflated.as_slice()
+            }
+        };
+
+        let array = match (self.numpress, self.dtype) {
+            (Some(numpress), _) => numpress
+                .decode(bytes)?
+                .into_iter()
+                .map(|x| x as f32)
+                .collect::<Vec<f32>>(),
+            (None, Dtype::F32) => bytes
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect::<Vec<f32>>(),
+            (None, Dtype::F64) => {
+                let mut buf: [u8; 8] = [0; 8];
+                bytes
+                    .chunks_exact(8)
+                    .map(|chunk| {
+                        buf.copy_from_slice(chunk);
+                        f64::from_le_bytes(buf) as f32
+                    })
+                    .collect::<Vec<f32>>()
+            }
+        };
+        Ok(array)
+    }
+}
+
+/// A parsed spectrum, whose binary data arrays have not been decoded yet
+struct PendingSpectrum {
+    spectrum: RawSpectrum,
+    arrays: Vec<EncodedArray>,
+    /// Number of values of each array
+    length: usize,
+}
+
+impl PendingSpectrum {
+    fn decode(mut self, signal_to_noise: Option<u8>) -> Result<RawSpectrum, MzMLError> {
+        let mut noise = Vec::new();
+        let mut mobility = Vec::new();
+        for array in &self.arrays {
+            let decoded = array.decode(self.length)?;
+            match array.kind {
+                BinaryKind::Intensity => self.spectrum.intensity = decoded,
+                BinaryKind::Mz => self.spectrum.mz = decoded,
+                BinaryKind::Noise => noise = decoded,
+                BinaryKind::Mobility => mobility = decoded,
+            }
+        }
+        if signal_to_noise == Some(self.spectrum.ms_level)
+            && noise.len() == self.spectrum.intensity.len()
+        {
+            self.spectrum.noise = noise;
+        }
+        if mobility.len() == self.spectrum.intensity.len() {
+            self.spectrum.mobility = mobility;
+        }
+        Ok(self.spectrum)
+    }
+}
+
+/// Decode the arrays of `pending` spectra in parallel, and pass the spectra
+/// to `f` in order. Returns the number of spectra
+fn decode_batch<F: FnMut(RawSpectrum)>(
+    pending: &mut Vec<PendingSpectrum>,
+    signal_to_noise: Option<u8>,
+    f: &mut F,
+) -> Result<usize, MzMLError> {
+    let spectra = pending
+        .par_drain(..)
+        .map(|spectrum| spectrum.decode(signal_to_noise))
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = spectra.len();
+    spectra.into_iter().for_each(f);
+    Ok(count)
+}
+
 // MUST supply only one of the following
 const ZLIB_COMPRESSION: &[u8] = b"MS:1000574";
 const NO_COMPRESSION: &[u8] = b"MS:1000576";
+const NUMPRESS_LINEAR: &[u8] = b"MS:1002312";
+const NUMPRESS_PIC: &[u8] = b"MS:1002313";
+const NUMPRESS_SLOF: &[u8] = b"MS:1002314";
+const NUMPRESS_LINEAR_ZLIB: &[u8] = b"MS:1002746";
+const NUMPRESS_PIC_ZLIB: &[u8] = b"MS:1002747";
+const NUMPRESS_SLOF_ZLIB: &[u8] = b"MS:1002748";
 
 // MUST supply only one of the following
 const INTENSITY_ARRAY: &[u8] = b"MS:1000515";
 const MZ_ARRAY: &[u8] = b"MS:1000514";
 const NOISE_ARRAY: &[u8] = b"MS:1002744";
+const MEAN_MOBILITY_ARRAY: &[u8] = b"MS:1003006";
+const RAW_MOBILITY_ARRAY: &[u8] = b"MS:1003008";
 
 // MUST supply only one of the following
 const FLOAT_64: &[u8] = b"MS:1000523";
@@ -55,15 +187,34 @@ const ION_INJECTION_TIME: &[u8] = b"MS:1000927";
 const SELECTED_ION_MZ: &[u8] = b"MS:1000744";
 const SELECTED_ION_INT: &[u8] = b"MS:1000042";
 const SELECTED_ION_CHARGE: &[u8] = b"MS:1000041";
+/// Inverse reduced ion mobility, of a selected ion or of a scan
+const SELECTED_ION_MOBILITY: &[u8] = b"MS:1002815";
+const COMPENSATION_VOLTAGE: &[u8] = b"MS:1001581";
+/// Thermo filter string, which includes the FAIMS compensation voltage (e.g.
+/// `cv=-45.00`) if it is not reported by its own term
+const FILTER_STRING: &[u8] = b"MS:1000512";
 
+const ISO_WINDOW_TARGET: &[u8] = b"MS:1000827";
 const ISO_WINDOW_LOWER: &[u8] = b"MS:1000828";
 const ISO_WINDOW_UPPER: &[u8] = b"MS:1000829";
 
+/// FAIMS compensation voltage of a Thermo filter string, e.g.
+/// `FTMS + p NSI cv=-45.00 Full ms [350.0000-1500.0000]`
+fn filter_compensation_voltage(filter: &str) -> Option<f32> {
+    filter
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix("cv="))
+        .and_then(|cv| cv.parse().ok())
+}
+
 pub struct MzMLReader {
     ms_level: Option<u8>,
     // If set to Some(level) and noise intensities are present in the MzML file,
-    // divide intensities at this MS-level by noise to calculate S/N
+    // retain noise of spectra at this MS-level, to calculate S/N
     signal_to_noise: Option<u8>,
+    // Spectra outside of this window are not decoded or returned, and the
+    // rest of the file is skipped once it has been passed
+    window: ScanWindow,
 
     file_id: usize,
 }
@@ -79,6 +230,7 @@ impl MzMLReader {
             ms_level: Some(ms_level),
             file_id,
             signal_to_noise: None,
+            window: ScanWindow::default(),
         }
     }
 
@@ -86,6 +238,7 @@ impl MzMLReader {
         Self {
             ms_level: None,
             signal_to_noise: None,
+            window: ScanWindow::default(),
             file_id,
         }
     }
@@ -100,29 +253,61 @@ impl MzMLReader {
         self
     }
 
-    /// Here be dragons -
-    /// Seriously, this kinda sucks because it's a giant imperative, stateful loop.
-    /// But I also don't want to spend any more time working on an mzML parser...
+    pub fn set_scan_window(&mut self, window: ScanWindow) -> &mut Self {
+        self.window = window;
+        self
+    }
+
+    /// Parse all spectra into memory
     pub async fn parse<B: AsyncBufRead + Unpin>(
         &self,
         b: B,
     ) -> Result<Vec<RawSpectrum>, MzMLError> {
+        let mut spectra = Vec::new();
+        self.parse_with(b, |spectrum| spectra.push(spectrum))
+            .await?;
+        Ok(spectra)
+    }
+
+    /// Stream spectra from an mzML file, passing each spectrum to `f` as soon
+    /// as it has been parsed, so that callers can process spectra without
+    /// holding the whole file in memory. Returns the number of spectra parsed
+    ///
+    /// Here be dragons -
+    /// Seriously, this kinda sucks because it's a giant imperative, stateful loop.
+    /// But I also don't want to spend any more time working on an mzML parser...
+    pub async fn parse_with<B, F>(&self, b: B, mut f: F) -> Result<usize, MzMLError>
+    where
+        B: AsyncBufRead + Unpin,
+        F: FnMut(RawSpectrum),
+    {
         let mut reader = Reader::from_reader(b);
         let mut buf = Vec::new();
 
         let mut state = None;
         let mut compression = false;
-        let mut output_buffer = Vec::with_capacity(4096);
+        let mut numpress = None;
         let mut binary_dtype = Dtype::F64;
         let mut binary_array = None;
 
         let mut spectrum = RawSpectrum::default_with_file_id(self.file_id);
         let mut precursor = Precursor::default();
+        let mut iso_window_target: Option<f32> = None;
         let mut iso_window_lo: Option<f32> = None;
         let mut iso_window_hi: Option<f32> = None;
-        let mut spectra = Vec::new();
+        let mut parsed = 0;
+        // Whether any XML element was found, to detect input that isn't mzML
+        let mut elements = false;
 
-        let mut noise_array = Vec::new();
+        let mut arrays = Vec::new();
+        let mut length = 0;
+        let mut pending = Vec::new();
+        let mut pending_bytes = 0;
+        // Whether a spectrum after the end of the scan window was found
+        let mut past = false;
+        // Whether the spectrum list was opened and not closed yet, i.e. the
+        // file is truncated if it ends
+        let mut listing = false;
 
         macro_rules! extract {
             ($ev:expr, $key:expr) => {
@@ -145,6 +330,7 @@ impl MzMLReader {
         loop {
             match reader.read_event_into_async(&mut buf).await {
                 Ok(Event::Start(ref ev)) => {
+                    elements = true;
                     // State transition into child tag
                     state = match (ev.name().into_inner(), state) {
                         (b"spectrum", _) => Some(State::Spectrum),
@@ -156,10 +342,19 @@ impl MzMLReader {
                         _ => state,
                     };
                     match ev.name().into_inner() {
+                        b"spectrumList" => listing = true,
                         b"spectrum" => {
                             let id = extract!(ev, b"id");
                             let id = std::str::from_utf8(&id)?;
                             spectrum.id = id.to_string();
+                            length = match ev.try_get_attribute(b"defaultArrayLength")? {
+                                Some(n) => std::str::from_utf8(&n.value)?.parse()?,
+                                None => 0,
+                            };
+                        }
+                        b"binaryDataArray" => {
+                            compression = false;
+                            numpress = None;
                         }
                         b"precursor" => {
                             // Not all precursor fields have a spectrumRef
@@ -177,11 +372,30 @@ impl MzMLReader {
                         match accession.as_ref() {
                             ZLIB_COMPRESSION => compression = true,
                             NO_COMPRESSION => compression = false,
+                            // MS-Numpress encoding is applied before zlib compression
+                            NUMPRESS_LINEAR => numpress = Some(Numpress::Linear),
+                            NUMPRESS_PIC => numpress = Some(Numpress::Pic),
+                            NUMPRESS_SLOF => numpress = Some(Numpress::Slof),
+                            NUMPRESS_LINEAR_ZLIB => {
+                                numpress = Some(Numpress::Linear);
+                                compression = true;
+                            }
+                            NUMPRESS_PIC_ZLIB => {
+                                numpress = Some(Numpress::Pic);
+                                compression = true;
+                            }
+                            NUMPRESS_SLOF_ZLIB => {
+                                numpress = Some(Numpress::Slof);
+                                compression = true;
+                            }
                             FLOAT_64 => binary_dtype = Dtype::F64,
                             FLOAT_32 => binary_dtype = Dtype::F32,
                             INTENSITY_ARRAY => binary_array = Some(BinaryKind::Intensity),
                             MZ_ARRAY => binary_array = Some(BinaryKind::Mz),
                             NOISE_ARRAY => binary_array = Some(BinaryKind::Noise),
+                            MEAN_MOBILITY_ARRAY | RAW_MOBILITY_ARRAY => {
+                                binary_array = Some(BinaryKind::Mobility)
+                            }
                             _ => {
                                 // Unknown CV - perhaps noise
                                 binary_array = None;
@@ -196,6 +410,7 @@ impl MzMLReader {
                                 if let Some(filter) = self.ms_level {
                                     if level != filter {
                                         spectrum = RawSpectrum::default_with_file_id(self.file_id);
+                                        arrays.clear();
                                         state = None;
                                     }
                                 }
@@ -203,11 +418,15 @@ impl MzMLReader {
                             }
                             PROFILE => spectrum.representation = Representation::Profile,
                             CENTROID => spectrum.representation = Representation::Centroid,
+                            COMPENSATION_VOLTAGE => {
+                                spectrum.compensation_voltage = Some(extract_value!(ev))
+                            }
                             TOTAL_ION_CURRENT => {
                                 let value = extract_value!(ev);
                                 if value == 0.0 {
                                     // No ion current, break out of current state
                                     spectrum = RawSpectrum::default_with_file_id(self.file_id);
+                                    arrays.clear();
                                     state = None;
                                 } else {
                                     spectrum.total_ion_current = value;
@@ -219,6 +438,7 @@ impl MzMLReader {
                     (Some(State::Precursor), b"cvParam") => {
                         let accession = extract!(ev, b"accession");
                         match accession.as_ref() {
+                            ISO_WINDOW_TARGET => iso_window_target = Some(extract_value!(ev)),
                             ISO_WINDOW_LOWER => iso_window_lo = Some(extract_value!(ev)),
                             ISO_WINDOW_UPPER => iso_window_hi = Some(extract_value!(ev)),
                             _ => {}
@@ -236,6 +456,9 @@ impl MzMLReader {
                             SELECTED_ION_INT => {
                                 precursor.intensity = Some(extract_value!(ev));
                             }
+                            SELECTED_ION_MOBILITY => {
+                                precursor.ion_mobility = Some(extract_value!(ev));
+                            }
                             _ => {}
                         }
                     }
@@ -255,6 +478,17 @@ impl MzMLReader {
                             ION_INJECTION_TIME => {
                                 spectrum.ion_injection_time = extract_value!(ev);
                             }
+                            SELECTED_ION_MOBILITY => {
+                                spectrum.ion_mobility = Some(extract_value!(ev));
+                            }
+                            COMPENSATION_VOLTAGE => {
+                                spectrum.compensation_voltage = Some(extract_value!(ev));
+                            }
+                            FILTER_STRING if spectrum.compensation_voltage.is_none() => {
+                                let filter = extract!(ev, b"value");
+                                spectrum.compensation_voltage =
+                                    filter_compensation_voltage(std::str::from_utf8(&filter)?);
+                            }
                             _ => {}
                         }
                     }
@@ -268,76 +502,64 @@ impl MzMLReader {
                                 continue;
                             }
                         }
-                        let raw = text.unescape()?;
+                        // Base64 never contains XML entities, so the text
+                        // is used as is, without unescaping it
+                        let raw = text.into_inner();
                         // There are occasionally empty binary data arrays, or unknown CVs
                         if raw.is_empty() || binary_array.is_none() {
                             continue;
                         }
-                        let decoded = base64::decode(raw.as_bytes())?;
-                        let bytes = match compression {
-                            false => &decoded,
-                            true => {
-                                let mut r = ZlibDecoder::new(decoded.as_slice());
-                                let n = r.read_to_end(&mut output_buffer).await?;
-                                &output_buffer[..n]
-                            }
-                        };
-
-                        let array = match binary_dtype {
-                            Dtype::F32 => {
-                                let mut buf: [u8; 4] = [0; 4];
-                                bytes
-                                    .chunks(4)
-                                    .filter(|chunk| chunk.len() == 4)
-                                    .map(|chunk| {
-                                        buf.copy_from_slice(chunk);
-                                        f32::from_le_bytes(buf)
-                                    })
-                                    .collect::<Vec<f32>>()
-                            }
-                            Dtype::F64 => {
-                                let mut buf: [u8; 8] = [0; 8];
-                                bytes
-                                    .chunks(8)
-                                    .map(|chunk| {
-                                        buf.copy_from_slice(chunk);
-                                        f64::from_le_bytes(buf) as f32
-                                    })
-                                    .collect::<Vec<f32>>()
-                            }
-                        };
-                        output_buffer.clear();
-
-                        match binary_array {
-                            Some(BinaryKind::Intensity) => {
-                                spectrum.intensity = array;
-                            }
-                            Some(BinaryKind::Mz) => {
-                                spectrum.mz = array;
-                            }
-                            Some(BinaryKind::Noise) => {
-                                noise_array = array;
-                            }
-                            None => {}
+                        let kind = binary_array.expect("checked above");
+                        // Noise is only kept to calculate S/N at one MS level
+                        if matches!(kind, BinaryKind::Noise)
+                            && self.signal_to_noise != Some(spectrum.ms_level)
+                        {
+                            binary_array = None;
+                            continue;
                         }
-
+                        pending_bytes += raw.len();
+                        arrays.push(EncodedArray {
+                            kind,
+                            dtype: binary_dtype,
+                            compression,
+                            numpress,
+                            data: raw.into_owned(),
+                        });
                         binary_array = None;
                     }
                 }
                 Ok(Event::End(ev)) => {
+                    if ev.name().into_inner() == b"spectrumList" {
+                        listing = false;
+                    }
                     state = match (state, ev.name().into_inner()) {
                         (Some(State::Binary), b"binary") => Some(State::BinaryDataArray),
                         (Some(State::BinaryDataArray), b"binaryDataArray") => Some(State::Spectrum),
                         (Some(State::SelectedIon), b"selectedIon") => Some(State::Precursor),
                         (Some(State::Precursor), b"precursor") => {
+                            // DIA spectra may not have a selected ion, in
+                            // which case the isolation window target is used
+                            if precursor.mz == 0.0 {
+                                precursor.mz = iso_window_target.unwrap_or_default();
+                            }
                             if precursor.mz != 0.0 {
+                                // Isolation window offsets are relative to the
+                                // target m/z, which can differ from the selected ion
+                                let shift = iso_window_target
+                                    .map(|target| target - precursor.mz)
+                                    .unwrap_or_default();
                                 precursor.isolation_window = match (iso_window_lo, iso_window_hi) {
-                                    (Some(lo), Some(hi)) => Some(Tolerance::Da(-lo, hi)),
+                                    (Some(lo), Some(hi)) => {
+                                        Some(Tolerance::Da(shift - lo, shift + hi))
+                                    }
                                     _ => None,
                                 };
                                 spectrum.precursors.push(precursor);
                                 precursor = Precursor::default();
                             }
+                            iso_window_target = None;
+                            iso_window_lo = None;
+                            iso_window_hi = None;
                             Some(State::Spectrum)
                         }
                         (Some(State::Scan), b"scan") => Some(State::Spectrum),
@@ -346,40 +568,53 @@ impl MzMLReader {
                                 .ms_level
                                 .as_ref()
                                 .map(|&level| level == spectrum.ms_level)
-                                .unwrap_or(true);
-
-                            match (allow, self.signal_to_noise) {
-                                (true, Some(level))
-                                    if level == spectrum.ms_level && !noise_array.is_empty() =>
-                                {
-                                    spectrum
-                                        .intensity
-                                        .iter_mut()
-                                        .zip(noise_array.iter())
-                                        .for_each(|(int, noise)| *int /= noise);
-                                    noise_array.clear();
-                                    spectra.push(spectrum);
-                                }
-                                (true, _) => {
-                                    spectra.push(spectrum);
-                                }
-                                (false, _) => {}
+                                .unwrap_or(true)
+                                && self.window.contains(&spectrum.id, spectrum.scan_start_time);
+                            past = self.window.is_past(&spectrum.id, spectrum.scan_start_time);
+
+                            let spectrum = std::mem::replace(
+                                &mut spectrum,
+                                RawSpectrum::default_with_file_id(self.file_id),
+                            );
+                            let arrays = std::mem::take(&mut arrays);
+                            if allow {
+                                pending.push(PendingSpectrum {
+                                    spectrum,
+                                    arrays,
+                                    length,
+                                });
+                            }
+                            if pending.len() >= DECODE_BATCH_SPECTRA
+                                || pending_bytes >= DECODE_BATCH_BYTES
+                            {
+                                parsed += decode_batch(&mut pending, self.signal_to_noise, &mut f)?;
+                                pending_bytes = 0;
                             }
-                            spectrum = RawSpectrum::default_with_file_id(self.file_id);
                             None
                         }
                         _ => state,
                     };
                 }
+                Ok(Event::Eof) if listing => return Err(MzMLError::Truncated),
                 Ok(Event::Eof) => break,
                 Ok(_) => {}
+                // The file can't be read any further, e.g. a truncated
+                // gzip stream
+                Err(err @ quick_xml::Error::Io(_)) => return Err(err.into()),
                 Err(err) => {
                     log::error!("unhandled XML error while parsing mzML: {}", err)
                 }
             }
             buf.clear();
+            if past {
+                break;
+            }
+        }
+        parsed += decode_batch(&mut pending, self.signal_to_noise, &mut f)?;
+        match elements {
+            true => Ok(parsed),
+            false => Err(MzMLError::NotMzML),
         }
-        Ok(spectra)
     }
 }
 
@@ -387,6 +622,10 @@ impl MzMLReader {
 pub enum MzMLError {
     #[error("malformed MzML")]
     Malformed,
+    #[error("no XML elements found, is this an mzML file?")]
+    NotMzML,
+    #[error("file ends before the spectrum list does, is it truncated?")]
+    Truncated,
     #[error("unsupported cvParam {0}")]
     UnsupportedCV(String),
     #[error("XML parsing error: {0}")]
@@ -401,13 +640,43 @@ pub enum MzMLError {
     IntError(#[from] std::num::ParseIntError),
     #[error("error decoding base64: {0}")]
     Base64Error(#[from] base64::DecodeError),
+    #[error("{0}")]
+    NumpressError(#[from] NumpressError),
 }
 
 #[cfg(test)]
 mod test {
-    use sage_core::{mass::Tolerance, spectrum::Representation};
+    use sage_core::{
+        mass::Tolerance,
+        spectrum::{Representation, ScanWindow},
+    };
+
+    use super::{
+        filter_compensation_voltage, BinaryKind, Dtype, EncodedArray, MzMLError, MzMLReader,
+    };
+
+    #[test]
+    fn decode_with_corrupt_array_length() -> Result<(), MzMLError> {
+        use flate2::{write::ZlibEncoder, Compression};
+        use std::io::Write;
 
-    use super::{MzMLError, MzMLReader};
+        let values = [100.0f32, 200.5, 300.25];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for value in values {
+            encoder.write_all(&value.to_le_bytes())?;
+        }
+        let array = EncodedArray {
+            kind: BinaryKind::Mz,
+            dtype: Dtype::F32,
+            compression: true,
+            numpress: None,
+            data: base64::encode(encoder.finish()?).into_bytes(),
+        };
+        // `defaultArrayLength` doesn't bound the memory reserved to decode
+        assert_eq!(array.decode(usize::MAX)?, values);
+        assert_eq!(array.decode(1 << 40)?, values);
+        Ok(())
+    }
 
     #[tokio::test]
     async fn parse_spectrum_issue_78() -> Result<(), MzMLError> {
@@ -492,4 +761,280 @@ mod test {
         assert_eq!(s.intensity.len(), s.mz.len());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn parse_not_mzml() {
+        let s = ">sp|Q99536|VAT1_HUMAN Synaptic vesicle membrane protein VAT-1 homolog\nMSDEREVAEAATGEDASSPPPK\n";
+        assert!(matches!(
+            MzMLReader::with_file_id(0).parse(s.as_bytes()).await,
+            Err(MzMLError::NotMzML)
+        ));
+    }
+
+    #[tokio::test]
+    async fn parse_dia_spectrum_without_selected_ion() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=10" index="9" defaultArrayLength="3">
+            <cvParam cvRef="MS" accession="MS:1000127" name="centroid spectrum" />
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="60.0" unitAccession="UO:0000010" unitName="second" unitCvRef="UO" />
+                </scan>
+            </scanList>
+            <precursorList count="1">
+                <precursor>
+                    <isolationWindow>
+                        <cvParam cvRef="MS" accession="MS:1000827" name="isolation window target m/z" value="612.5" />
+                        <cvParam cvRef="MS" accession="MS:1000828" name="isolation window lower offset" value="12.5" />
+                        <cvParam cvRef="MS" accession="MS:1000829" name="isolation window upper offset" value="12.5" />
+                    </isolationWindow>
+                    <activation>
+                        <cvParam cvRef="MS" accession="MS:1000422" name="beam-type collision-induced dissociation" />
+                    </activation>
+                </precursor>
+            </precursorList>
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="32">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAAAADAckAAAAAAAAB5QAAAAAAAQH9A</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="16">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAgQQAAoEEAAPBB</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 1);
+        assert_eq!(spectra[0].precursors.len(), 1);
+        assert_eq!(spectra[0].precursors[0].mz, 612.5);
+        assert_eq!(spectra[0].precursors[0].charge, None);
+        assert_eq!(
+            spectra[0].precursors[0].isolation_window,
+            Some(Tolerance::Da(-12.5, 12.5))
+        );
+        assert_eq!(spectra[0].mz, vec![300.0, 400.0, 500.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_numpress_arrays() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=11" index="10" defaultArrayLength="3">
+            <cvParam cvRef="MS" accession="MS:1000127" name="centroid spectrum" />
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="32">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1002746" name="MS-Numpress linear prediction compression followed by zlib compression" />
+                    <binary>eJxz6HdgAIEHk1kYGqTYGBoAIQ8Dpw==</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="20">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1002314" name="MS-Numpress short logged float compression" />
+                    <binary>QKdwAAAAAAAaHK4jPig=</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 1);
+        assert_eq!(spectra[0].mz, vec![300.0, 400.0, 500.0]);
+        assert_eq!(spectra[0].intensity.len(), 3);
+        for (observed, expected) in spectra[0].intensity.iter().zip([10.0, 20.0, 30.0]) {
+            assert!((observed - expected).abs() < 0.01, "{}", observed);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_ion_mobility() -> Result<(), MzMLError> {
+        let s = r#"
+        <spectrum id="scan=12" index="11" defaultArrayLength="2">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1002815" name="inverse reduced ion mobility" value="1.05" unitAccession="MS:1002814" unitName="volt-second per square centimeter" unitCvRef="MS" />
+                </scan>
+            </scanList>
+            <binaryDataArrayList count="3">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AADIQgAASEM=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAgQQAAoEE=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1003006" name="mean inverse reduced ion mobility array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>ZmZmP83MjD8=</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>
+        "#;
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 1);
+        assert_eq!(spectra[0].ion_mobility, Some(1.05));
+        assert_eq!(spectra[0].mz, vec![100.0, 200.0]);
+        assert_eq!(spectra[0].mobility, vec![0.9, 1.1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_compensation_voltage() -> Result<(), MzMLError> {
+        // Reported by its own term, or only in the filter string
+        let spectrum = r#"
+        <spectrum id="scan={id}" index="{id}" defaultArrayLength="2">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="1" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000512" name="filter string" value="FTMS + p NSI cv=-60.00 Full ms [350.0000-1500.0000]" />
+                    {cv}
+                </scan>
+            </scanList>
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AADIQgAASEM=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAgQQAAoEE=</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>"#;
+        let s = [
+            r#"<cvParam cvRef="MS" accession="MS:1001581" name="FAIMS compensation voltage" value="-45.0" unitCvRef="UO" unitAccession="UO:0000218" unitName="volt" />"#,
+            "",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(idx, cv)| {
+            spectrum
+                .replace("{id}", &idx.to_string())
+                .replace("{cv}", cv)
+        })
+        .collect::<String>();
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), 2);
+        assert_eq!(spectra[0].compensation_voltage, Some(-45.0));
+        assert_eq!(spectra[1].compensation_voltage, Some(-60.0));
+        assert_eq!(filter_compensation_voltage("FTMS + p NSI Full ms"), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_batches_in_order() -> Result<(), MzMLError> {
+        // More spectra than are decoded at once
+        let spectrum = r#"
+        <spectrum id="scan={}" index="{}" defaultArrayLength="2">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AADIQgAASEM=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="24">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000523" name="64-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000574" name="zlib compression" />
+                    <binary>eJxjYAABFQcwxWDiAAAEYADZ</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>"#;
+        let n = super::DECODE_BATCH_SPECTRA * 2 + 10;
+        let s = (0..n)
+            .map(|idx| spectrum.replace("{}", &idx.to_string()))
+            .collect::<String>();
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+
+        assert_eq!(spectra.len(), n);
+        for (idx, spectrum) in spectra.iter().enumerate() {
+            assert_eq!(spectrum.id, format!("scan={}", idx));
+            assert_eq!(spectrum.mz, vec![100.0, 200.0]);
+            assert_eq!(spectrum.intensity, vec![10.0, 20.0]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_scan_window() -> Result<(), MzMLError> {
+        let spectrum = r#"
+        <spectrum id="scan={}" index="{}" defaultArrayLength="2">
+            <cvParam cvRef="MS" accession="MS:1000511" name="ms level" value="2" />
+            <scanList count="1">
+                <scan>
+                    <cvParam cvRef="MS" accession="MS:1000016" name="scan start time" value="{}" unitCvRef="UO" unitAccession="UO:0000031" unitName="minute" />
+                </scan>
+            </scanList>
+            <binaryDataArrayList count="2">
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000514" name="m/z array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AADIQgAASEM=</binary>
+                </binaryDataArray>
+                <binaryDataArray encodedLength="12">
+                    <cvParam cvRef="MS" accession="MS:1000515" name="intensity array" />
+                    <cvParam cvRef="MS" accession="MS:1000521" name="32-bit float" />
+                    <cvParam cvRef="MS" accession="MS:1000576" name="no compression" />
+                    <binary>AAAgQQAAoEE=</binary>
+                </binaryDataArray>
+            </binaryDataArrayList>
+        </spectrum>"#;
+        let s = (0..10)
+            .map(|idx| spectrum.replace("{}", &idx.to_string()))
+            .collect::<String>();
+        let spectra = MzMLReader::with_file_id(0)
+            .set_scan_window(ScanWindow {
+                scan_range: Some((3, 8)),
+                rt_range: Some((2.0, 6.0)),
+            })
+            .parse(s.as_bytes())
+            .await?;
+
+        let ids = spectra.iter().map(|s| s.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["scan=3", "scan=4", "scan=5", "scan=6"]);
+        assert_eq!(spectra[0].mz, vec![100.0, 200.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn parse_truncated_file() -> Result<(), MzMLError> {
+        let s = include_str!("../../../tests/LQSRPAAPPAPGPGQLTLR.mzML");
+        let spectra = MzMLReader::with_file_id(0).parse(s.as_bytes()).await?;
+        assert_eq!(spectra.len(), 1);
+
+        let end = s.find("</spectrumList>").unwrap();
+        let result = MzMLReader::with_file_id(0)
+            .parse(&s.as_bytes()[..end])
+            .await;
+        assert!(matches!(result, Err(MzMLError::Truncated)));
+        Ok(())
+    }
 }
@@ -0,0 +1,386 @@
+//! mzXML parser
+//!
+//! mzXML predates mzML, and many older public datasets are only distributed
+//! in this format. Each `scan` element holds its attributes (MS level,
+//! retention time, ...), a `precursorMz` element for MSn scans, and a single
+//! `peaks` element containing interleaved m/z and intensity pairs, encoded as
+//! base64 network byte order (big-endian) floats. Older files nest MSn scans
+//! inside their parent MS1 scan.
+
+use async_compression::tokio::bufread::ZlibDecoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use sage_core::spectrum::{Precursor, Representation};
+use sage_core::{mass::Tolerance, spectrum::RawSpectrum};
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncReadExt};
+
+/// Which text-bearing tag are we inside?
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    PrecursorMz,
+    Peaks,
+}
+
+/// Encoding of a `peaks` element
+#[derive(Copy, Clone, Debug)]
+struct Peaks {
+    precision: usize,
+    big_endian: bool,
+    compression: bool,
+}
+
+impl Default for Peaks {
+    fn default() -> Self {
+        Self {
+            precision: 32,
+            big_endian: true,
+            compression: false,
+        }
+    }
+}
+
+pub struct MzXMLReader {
+    ms_level: Option<u8>,
+    file_id: usize,
+}
+
+/// Parse the value of an attribute, if present
+fn attribute<T>(ev: &BytesStart, key: &[u8]) -> Result<Option<T>, MzXMLError>
+where
+    T: FromStr,
+    MzXMLError: From<T::Err>,
+{
+    match ev.try_get_attribute(key)? {
+        Some(attr) => Ok(Some(std::str::from_utf8(&attr.value)?.trim().parse()?)),
+        None => Ok(None),
+    }
+}
+
+/// Parse an `xs:duration` retention time (e.g. "PT1503.96S" or "PT25M3.96S")
+/// into minutes
+fn parse_retention_time(s: &str) -> Result<f32, MzXMLError> {
+    let mut rest = s
+        .trim()
+        .strip_prefix("PT")
+        .ok_or_else(|| MzXMLError::Duration(s.into()))?;
+    let mut minutes = 0.0;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| c.is_ascii_alphabetic())
+            .ok_or_else(|| MzXMLError::Duration(s.into()))?;
+        let value = rest[..end].parse::<f32>()?;
+        minutes += match &rest[end..end + 1] {
+            "H" => value * 60.0,
+            "M" => value,
+            "S" => value / 60.0,
+            _ => return Err(MzXMLError::Duration(s.into())),
+        };
+        rest = &rest[end + 1..];
+    }
+    Ok(minutes)
+}
+
+impl MzXMLReader {
+    /// Create a new [`MzXMLReader`] with a minimum MS level filter
+    pub fn with_file_id_and_level_filter(file_id: usize, ms_level: u8) -> Self {
+        Self {
+            ms_level: Some(ms_level),
+            file_id,
+        }
+    }
+
+    pub fn with_file_id(file_id: usize) -> Self {
+        Self {
+            ms_level: None,
+            file_id,
+        }
+    }
+
+    pub fn set_file_id(&mut self, file_id: usize) -> &mut Self {
+        self.file_id = file_id;
+        self
+    }
+
+    /// Decode interleaved m/z and intensity pairs
+    async fn decode(
+        &self,
+        raw: &str,
+        peaks: Peaks,
+        spectrum: &mut RawSpectrum,
+    ) -> Result<(), MzXMLError> {
+        let decoded = base64::decode(raw.trim().as_bytes())?;
+        let mut output_buffer = Vec::new();
+        let bytes = match peaks.compression {
+            false => &decoded,
+            true => {
+                ZlibDecoder::new(decoded.as_slice())
+                    .read_to_end(&mut output_buffer)
+                    .await?;
+                &output_buffer
+            }
+        };
+
+        let values = match (peaks.precision, peaks.big_endian) {
+            (32, big_endian) => bytes
+                .chunks_exact(4)
+                .map(|chunk| {
+                    let buf = [chunk[0], chunk[1], chunk[2], chunk[3]];
+                    match big_endian {
+                        true => f32::from_be_bytes(buf),
+                        false => f32::from_le_bytes(buf),
+                    }
+                })
+                .collect::<Vec<f32>>(),
+            (64, big_endian) => bytes
+                .chunks_exact(8)
+                .map(|chunk| {
+                    let mut buf = [0; 8];
+                    buf.copy_from_slice(chunk);
+                    match big_endian {
+                        true => f64::from_be_bytes(buf) as f32,
+                        false => f64::from_le_bytes(buf) as f32,
+                    }
+                })
+                .collect::<Vec<f32>>(),
+            (precision, _) => return Err(MzXMLError::Precision(precision)),
+        };
+
+        spectrum.mz = values.iter().step_by(2).copied().collect();
+        spectrum.intensity = values.iter().skip(1).step_by(2).copied().collect();
+        spectrum.intensity.truncate(spectrum.mz.len());
+        spectrum.mz.truncate(spectrum.intensity.len());
+        Ok(())
+    }
+
+    pub async fn parse<B: AsyncBufRead + Unpin>(
+        &self,
+        b: B,
+    ) -> Result<Vec<RawSpectrum>, MzXMLError> {
+        let mut reader = Reader::from_reader(b);
+        let mut buf = Vec::new();
+
+        // MSn scans may be nested inside their parent scan
+        let mut scans: Vec<RawSpectrum> = Vec::new();
+        let mut state = None;
+        let mut peaks = Peaks::default();
+        let mut precursor = Precursor::default();
+        let mut spectra = Vec::new();
+
+        loop {
+            match reader.read_event_into_async(&mut buf).await {
+                Ok(Event::Start(ref ev)) => match ev.name().into_inner() {
+                    b"scan" => {
+                        let mut spectrum = RawSpectrum::default_with_file_id(self.file_id);
+                        let num: usize = attribute(ev, b"num")?.ok_or(MzXMLError::Malformed)?;
+                        spectrum.id = format!("scan={}", num);
+                        spectrum.ms_level = attribute(ev, b"msLevel")?.unwrap_or(1);
+                        if let Some(rt) = attribute::<String>(ev, b"retentionTime")? {
+                            spectrum.scan_start_time = parse_retention_time(&rt)?;
+                        }
+                        spectrum.compensation_voltage = attribute(ev, b"compensationVoltage")?;
+                        if let Some(tic) = attribute(ev, b"totIonCurrent")? {
+                            spectrum.total_ion_current = tic;
+                        }
+                        spectrum.representation = match attribute::<u8>(ev, b"centroided")? {
+                            Some(1) => Representation::Centroid,
+                            _ => Representation::Profile,
+                        };
+                        scans.push(spectrum);
+                    }
+                    b"precursorMz" => {
+                        precursor = Precursor {
+                            charge: attribute(ev, b"precursorCharge")?,
+                            intensity: attribute(ev, b"precursorIntensity")?,
+                            spectrum_ref: attribute::<usize>(ev, b"precursorScanNum")?
+                                .map(|num| format!("scan={}", num)),
+                            isolation_window: attribute::<f32>(ev, b"windowWideness")?
+                                .map(|width| Tolerance::Da(-width / 2.0, width / 2.0)),
+                            ..Default::default()
+                        };
+                        state = Some(State::PrecursorMz);
+                    }
+                    b"peaks" => {
+                        peaks = Peaks {
+                            precision: attribute(ev, b"precision")?.unwrap_or(32),
+                            big_endian: attribute::<String>(ev, b"byteOrder")?
+                                .map(|order| order != "little")
+                                .unwrap_or(true),
+                            compression: attribute::<String>(ev, b"compressionType")?
+                                .map(|kind| kind == "zlib")
+                                .unwrap_or(false),
+                        };
+                        if let Some(content) = attribute::<String>(ev, b"contentType")? {
+                            if content != "m/z-int" {
+                                return Err(MzXMLError::Unsupported(content));
+                            }
+                        }
+                        state = Some(State::Peaks);
+                    }
+                    _ => {}
+                },
+                Ok(Event::Text(text)) => {
+                    let spectrum = match (state, scans.last_mut()) {
+                        (Some(_), Some(spectrum)) => spectrum,
+                        _ => continue,
+                    };
+                    let raw = text.unescape()?;
+                    match state {
+                        Some(State::PrecursorMz) => precursor.mz = raw.trim().parse()?,
+                        Some(State::Peaks) => {
+                            let allow = self
+                                .ms_level
+                                .map(|level| level == spectrum.ms_level)
+                                .unwrap_or(true);
+                            // There are occasionally empty peak lists
+                            if allow && !raw.trim().is_empty() {
+                                self.decode(&raw, peaks, spectrum).await?;
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                Ok(Event::End(ev)) => match ev.name().into_inner() {
+                    b"precursorMz" => {
+                        state = None;
+                        let precursor = std::mem::take(&mut precursor);
+                        if precursor.mz != 0.0 {
+                            scans
+                                .last_mut()
+                                .ok_or(MzXMLError::Malformed)?
+                                .precursors
+                                .push(precursor);
+                        }
+                    }
+                    b"peaks" => state = None,
+                    b"scan" => {
+                        let spectrum = scans.pop().ok_or(MzXMLError::Malformed)?;
+                        let allow = self
+                            .ms_level
+                            .map(|level| level == spectrum.ms_level)
+                            .unwrap_or(true);
+                        if allow {
+                            spectra.push(spectrum);
+                        }
+                    }
+                    _ => {}
+                },
+                Ok(Event::Eof) => break,
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("unhandled XML error while parsing mzXML: {}", err)
+                }
+            }
+            buf.clear();
+        }
+        Ok(spectra)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MzXMLError {
+    #[error("malformed mzXML")]
+    Malformed,
+    #[error("unsupported peak content type {0}")]
+    Unsupported(String),
+    #[error("unsupported peak precision {0}")]
+    Precision(usize),
+    #[error("invalid retention time {0}")]
+    Duration(String),
+    #[error("XML parsing error: {0}")]
+    XMLError(#[from] quick_xml::Error),
+    #[error("io error: {0}")]
+    IOError(#[from] std::io::Error),
+    #[error("utf8 error: {0}")]
+    Utf8Error(#[from] std::str::Utf8Error),
+    #[error("error parsing float: {0}")]
+    FloatError(#[from] std::num::ParseFloatError),
+    #[error("error parsing int: {0}")]
+    IntError(#[from] std::num::ParseIntError),
+    #[error("error decoding base64: {0}")]
+    Base64Error(#[from] base64::DecodeError),
+}
+
+impl From<std::convert::Infallible> for MzXMLError {
+    fn from(err: std::convert::Infallible) -> Self {
+        match err {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sage_core::{mass::Tolerance, spectrum::Representation};
+
+    use super::{parse_retention_time, MzXMLError, MzXMLReader};
+
+    #[tokio::test]
+    async fn parse_nested_scans() -> Result<(), MzXMLError> {
+        let s = r#"
+        <mzXML xmlns="http://sashimi.sourceforge.net/schema_revision/mzXML_3.2">
+          <msRun scanCount="2">
+            <scan num="1" msLevel="1" peaksCount="3" polarity="+" retentionTime="PT60.0S" centroided="1" totIonCurrent="60">
+              <peaks precision="32" byteOrder="network" compressionType="none" compressedLen="0" contentType="m/z-int">Q5YAAEEgAABDyAAAQaAAAEP6AABB8AAA</peaks>
+              <scan num="2" msLevel="2" peaksCount="2" polarity="+" retentionTime="PT1M1.5S" centroided="1">
+                <precursorMz precursorScanNum="1" precursorIntensity="1500.5" precursorCharge="2" activationMethod="CID" windowWideness="2.0">445.12</precursorMz>
+                <peaks precision="64" byteOrder="network" compressionType="zlib" compressedLen="27" contentType="m/z-int">eJxzSLrAAAIO/Q4QOt8DQs+H8AFbAQSY</peaks>
+              </scan>
+            </scan>
+            <scan num="3" msLevel="2" peaksCount="0" retentionTime="PT62S" compensationVoltage="-45">
+              <precursorMz precursorScanNum="1">500.5</precursorMz>
+              <peaks precision="32" byteOrder="network" contentType="m/z-int"></peaks>
+            </scan>
+          </msRun>
+        </mzXML>
+        "#;
+        let spectra = MzXMLReader::with_file_id(3).parse(s.as_bytes()).await?;
+        assert_eq!(spectra.len(), 3);
+
+        // Nested scans are emitted as they are closed
+        let ms2 = &spectra[0];
+        assert_eq!(ms2.id, "scan=2");
+        assert_eq!(ms2.file_id, 3);
+        assert_eq!(ms2.ms_level, 2);
+        assert_eq!(ms2.representation, Representation::Centroid);
+        assert!((ms2.scan_start_time - 1.025).abs() < 1E-5);
+        assert_eq!(ms2.mz, vec![150.5, 250.25]);
+        assert_eq!(ms2.intensity, vec![1000.0, 2000.0]);
+        assert_eq!(ms2.precursors.len(), 1);
+        assert!((ms2.precursors[0].mz - 445.12).abs() < 1E-4);
+        assert_eq!(ms2.precursors[0].charge, Some(2));
+        assert_eq!(ms2.precursors[0].intensity, Some(1500.5));
+        assert_eq!(ms2.precursors[0].spectrum_ref.as_deref(), Some("scan=1"));
+        assert_eq!(
+            ms2.precursors[0].isolation_window,
+            Some(Tolerance::Da(-1.0, 1.0))
+        );
+
+        let ms1 = &spectra[1];
+        assert_eq!(ms1.id, "scan=1");
+        assert_eq!(ms1.ms_level, 1);
+        assert_eq!(ms1.scan_start_time, 1.0);
+        assert_eq!(ms1.total_ion_current, 60.0);
+        assert_eq!(ms1.mz, vec![300.0, 400.0, 500.0]);
+        assert_eq!(ms1.intensity, vec![10.0, 20.0, 30.0]);
+        assert!(ms1.precursors.is_empty());
+
+        assert_eq!(spectra[2].representation, Representation::Profile);
+        assert!(spectra[2].mz.is_empty());
+        assert_eq!(spectra[2].precursors[0].charge, None);
+        assert_eq!(spectra[2].compensation_voltage, Some(-45.0));
+        assert_eq!(ms1.compensation_voltage, None);
+
+        let spectra = MzXMLReader::with_file_id_and_level_filter(0, 2)
+            .parse(s.as_bytes())
+            .await?;
+        assert_eq!(spectra.len(), 2);
+        assert!(spectra.iter().all(|s| s.ms_level == 2));
+        Ok(())
+    }
+
+    #[test]
+    fn retention_time() {
+        assert_eq!(parse_retention_time("PT90S").unwrap(), 1.5);
+        assert_eq!(parse_retention_time("PT1H2M30S").unwrap(), 62.5);
+        assert!(parse_retention_time("90").is_err());
+    }
+}
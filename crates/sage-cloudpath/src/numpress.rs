@@ -0,0 +1,181 @@
+//! Decoding of MS-Numpress compressed binary data arrays
+//!
+//! MS-Numpress is a lossy compression for mzML binary data arrays, available
+//! as `msconvert --numpress{Linear,Pic,Slof}`. Each of the three algorithms is
+//! optionally followed by zlib compression, which must be reversed before
+//! decoding. This is a port of the decoders of the reference implementation.
+//!
+//! Teleman et al., 2014 [https://doi.org/10.1074/mcp.O114.037879]
+
+/// MS-Numpress algorithm used to encode a binary data array
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Numpress {
+    /// Linear prediction, for m/z and retention time arrays
+    Linear,
+    /// Positive integer compression, for ion counts
+    Pic,
+    /// Short logged float compression, for intensities
+    Slof,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("corrupt MS-Numpress data")]
+pub struct NumpressError;
+
+impl Numpress {
+    pub fn decode(self, data: &[u8]) -> Result<Vec<f64>, NumpressError> {
+        match self {
+            Numpress::Linear => decode_linear(data),
+            Numpress::Pic => decode_pic(data),
+            Numpress::Slof => decode_slof(data),
+        }
+    }
+}
+
+/// Fixed point scaling factors are stored as big-endian doubles
+fn decode_fixed_point(data: &[u8]) -> Result<f64, NumpressError> {
+    let bytes = data.get(..8).ok_or(NumpressError)?;
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    Ok(f64::from_be_bytes(buf))
+}
+
+/// Stream of half-bytes (most significant first), from which integers are
+/// decoded
+struct HalfBytes<'a> {
+    data: &'a [u8],
+    idx: usize,
+    half: bool,
+}
+
+impl<'a> HalfBytes<'a> {
+    fn next(&mut self) -> Result<u32, NumpressError> {
+        let byte = *self.data.get(self.idx).ok_or(NumpressError)? as u32;
+        let value = match self.half {
+            false => byte >> 4,
+            true => {
+                self.idx += 1;
+                byte & 0xf
+            }
+        };
+        self.half = !self.half;
+        Ok(value)
+    }
+
+    /// Have all integers been read? The final byte may be padded with an
+    /// empty half-byte
+    fn done(&self) -> bool {
+        self.idx >= self.data.len()
+            || (self.idx == self.data.len() - 1 && self.half && self.data[self.idx] & 0xf == 0)
+    }
+
+    /// Decode an integer, stored as a head half-byte giving the number of
+    /// leading zero (0-8) or one (9-15) half-bytes, followed by the remaining
+    /// half-bytes, least significant first
+    fn decode_int(&mut self) -> Result<u32, NumpressError> {
+        let head = self.next()?;
+        let (n, mut value) = match head {
+            0..=8 => (head, 0),
+            _ => {
+                let n = head - 8;
+                let ones = (0..n).fold(0u32, |acc, i| acc | (0xf000_0000 >> (4 * i)));
+                (n, ones)
+            }
+        };
+        for i in n..8 {
+            value |= self.next()? << ((i - n) * 4);
+        }
+        Ok(value)
+    }
+}
+
+fn decode_linear(data: &[u8]) -> Result<Vec<f64>, NumpressError> {
+    let fixed_point = decode_fixed_point(data)?;
+    let read_u32 = |offset: usize| -> Option<i64> {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64)
+    };
+
+    let mut result = Vec::new();
+    if data.len() == 8 {
+        return Ok(result);
+    }
+    let mut ints = [0i64, read_u32(8).ok_or(NumpressError)?, 0];
+    result.push(ints[1] as f64 / fixed_point);
+    if data.len() == 12 {
+        return Ok(result);
+    }
+    ints[2] = read_u32(12).ok_or(NumpressError)?;
+    result.push(ints[2] as f64 / fixed_point);
+
+    let mut stream = HalfBytes {
+        data,
+        idx: 16,
+        half: false,
+    };
+    while !stream.done() {
+        ints[0] = ints[1];
+        ints[1] = ints[2];
+        let diff = stream.decode_int()? as i32 as i64;
+        let extrapolated = ints[1] + (ints[1] - ints[0]);
+        ints[2] = extrapolated + diff;
+        result.push(ints[2] as f64 / fixed_point);
+    }
+    Ok(result)
+}
+
+fn decode_pic(data: &[u8]) -> Result<Vec<f64>, NumpressError> {
+    let mut result = Vec::new();
+    let mut stream = HalfBytes {
+        data,
+        idx: 0,
+        half: false,
+    };
+    while !stream.done() {
+        result.push(stream.decode_int()? as f64);
+    }
+    Ok(result)
+}
+
+fn decode_slof(data: &[u8]) -> Result<Vec<f64>, NumpressError> {
+    let fixed_point = decode_fixed_point(data)?;
+    if data.len() % 2 != 0 {
+        return Err(NumpressError);
+    }
+    Ok(data[8..]
+        .chunks_exact(2)
+        .map(|chunk| (u16::from_le_bytes([chunk[0], chunk[1]]) as f64 / fixed_point).exp() - 1.0)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_numpress() {
+        // Encoded with the reference implementation's algorithms
+        let linear = base64::decode("QI9AAAAAAACghgEANA8DANIdbXwLw8VJMA==").unwrap();
+        assert_eq!(
+            Numpress::Linear.decode(&linear),
+            Ok(vec![100.0, 200.5, 300.25, 400.125, 450.0, 449.0])
+        );
+
+        let pic = base64::decode("h1XCEwcRFzA=").unwrap();
+        assert_eq!(
+            Numpress::Pic.decode(&pic),
+            Ok(vec![0.0, 5.0, 300.0, 70000.0, 3.0])
+        );
+
+        let slof = base64::decode("QKdwAAAAAAAaHPZQy34=").unwrap();
+        let decoded = Numpress::Slof.decode(&slof).unwrap();
+        let expected = [10.001152059542859, 999.911803124485, 49993.4194242706];
+        assert_eq!(decoded.len(), expected.len());
+        for (a, b) in decoded.iter().zip(expected) {
+            assert!((a - b).abs() < 1E-6, "{} {}", a, b);
+        }
+
+        assert_eq!(Numpress::Linear.decode(&linear[..10]), Err(NumpressError));
+        assert_eq!(Numpress::Slof.decode(&slof[..9]), Err(NumpressError));
+    }
+}
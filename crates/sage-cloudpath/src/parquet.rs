@@ -22,9 +22,20 @@ use parquet::{
 use sage_core::database::IndexedDatabase;
 use sage_core::ion_series::Kind;
 use sage_core::lfq::{Peak, PrecursorId};
+use sage_core::library::{unimod_sequence, LibraryEntry};
+use sage_core::mass::PROTON;
 use sage_core::scoring::Feature;
 use sage_core::tmt::TmtQuant;
 
+/// Names of the top-level columns of a schema
+pub fn column_names(schema: &Type) -> Vec<String> {
+    schema
+        .get_fields()
+        .iter()
+        .map(|field| field.name().to_string())
+        .collect()
+}
+
 pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
     let msg = r#"
         message schema {
@@ -35,6 +46,7 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required byte_array stripped_peptide (utf8);
             required byte_array proteins (utf8);
             required int32 num_proteins;
+            required boolean shared_peptide;
             required int32 rank;
             required boolean is_decoy;
             required float expmass;
@@ -44,6 +56,7 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required int32 missed_cleavages;
             required float isotope_error;
             required float precursor_ppm;
+            required float mass_offset;
             required float fragment_ppm;
             required float hyperscore;
             required float delta_next;
@@ -56,6 +69,7 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required int32 longest_b;
             required int32 longest_y;
             required float longest_y_pct;
+            required float fragment_coverage;
             required float matched_intensity_pct;
             required int32 scored_candidates;
             required float poisson;
@@ -64,6 +78,20 @@ pub fn build_schema() -> Result<Type, parquet::errors::ParquetError> {
             required float spectrum_q;
             required float peptide_q;
             required float protein_q;
+            optional float isolation_purity;
+            optional float localization_probability;
+            optional byte_array localization_sites (utf8);
+            optional byte_array silac_channel (utf8);
+            optional byte_array glycan (utf8);
+            required int32 search_pass;
+            optional float spectral_angle;
+            optional float irt;
+            optional float ion_mobility;
+            required float predicted_mobility;
+            required float delta_mobility_model;
+            optional float compensation_voltage;
+            required float predicted_spectral_angle;
+            required float predicted_correlation;
             optional group reporter_ion_intensity (LIST) {
                 repeated group list {
                     optional float element;
@@ -83,7 +111,7 @@ fn write_reporter_ions(
     let mut scan_map = HashMap::new();
 
     for r in reporter_ions {
-        scan_map.entry((r.file_id, &r.spec_id)).or_insert(r);
+        scan_map.entry((r.file_id, r.spec_id.as_str())).or_insert(r);
     }
 
     // Caller guarantees `reporter_ions` is not empty
@@ -97,7 +125,7 @@ fn write_reporter_ions(
 
     let col = column.typed::<FloatType>();
     for feature in features {
-        if let Some(rs) = scan_map.get(&(feature.file_id, &feature.spec_id)) {
+        if let Some(rs) = scan_map.get(&(feature.file_id, &*feature.spec_id)) {
             col.write_batch(&rs.peaks, Some(&def_levels), Some(&rep_levels))?;
         } else {
             col.write_batch(&[], Some(&[0]), Some(&[0]))?;
@@ -167,7 +195,7 @@ pub fn serialize_features(
             |f: &Feature| filenames[f.file_id].as_str().into(),
             ByteArrayType
         );
-        write_col!(|f: &Feature| f.spec_id.as_str().into(), ByteArrayType);
+        write_col!(|f: &Feature| (*f.spec_id).into(), ByteArrayType);
         write_col!(
             |f: &Feature| database[f.peptide_idx].to_string().as_bytes().into(),
             ByteArrayType
@@ -187,6 +215,7 @@ pub fn serialize_features(
             |f: &Feature| database[f.peptide_idx].proteins.len() as i32,
             Int32Type
         );
+        write_col!(|f: &Feature| database[f.peptide_idx].shared(), BoolType);
         write_col!(rank, Int32Type);
         write_col!(|f: &Feature| f.label == -1, BoolType);
         write_col!(expmass, FloatType);
@@ -196,6 +225,7 @@ pub fn serialize_features(
         write_col!(missed_cleavages, Int32Type);
         write_col!(isotope_error, FloatType);
         write_col!(delta_mass, FloatType);
+        write_col!(mass_offset, FloatType);
         write_col!(average_ppm, FloatType);
         write_col!(hyperscore, FloatType);
         write_col!(delta_next, FloatType);
@@ -208,6 +238,7 @@ pub fn serialize_features(
         write_col!(longest_b, Int32Type);
         write_col!(longest_y, Int32Type);
         write_col!(longest_y_pct, FloatType);
+        write_col!(fragment_coverage, FloatType);
         write_col!(matched_intensity_pct, FloatType);
         write_col!(scored_candidates, Int32Type);
         write_col!(poisson, FloatType);
@@ -217,6 +248,140 @@ pub fn serialize_features(
         write_col!(peptide_q, FloatType);
         write_col!(protein_q, FloatType);
 
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.isolation_purity)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.isolation_purity.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.localization_probability)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.localization_probability.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.localization_sites.as_deref())
+                .map(ByteArray::from)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.localization_sites.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<ByteArrayType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.silac_channel)
+                .map(|channel| ByteArray::from(channel.to_string().as_str()))
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.silac_channel.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<ByteArrayType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.glycan.as_deref())
+                .map(ByteArray::from)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.glycan.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<ByteArrayType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        write_col!(search_pass, Int32Type);
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.spectral_angle)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.spectral_angle.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features.iter().filter_map(|f| f.irt).collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.irt.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.ion_mobility)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.ion_mobility.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        write_col!(predicted_mobility, FloatType);
+        write_col!(delta_mobility_model, FloatType);
+
+        if let Some(mut col) = rg.next_column()? {
+            let values = features
+                .iter()
+                .filter_map(|f| f.compensation_voltage)
+                .collect::<Vec<_>>();
+            let def_levels = features
+                .iter()
+                .map(|f| f.compensation_voltage.is_some() as i16)
+                .collect::<Vec<_>>();
+            col.typed::<FloatType>()
+                .write_batch(&values, Some(&def_levels), None)?;
+            col.close()?;
+        }
+
+        write_col!(predicted_spectral_angle, FloatType);
+        write_col!(predicted_correlation, FloatType);
+
         if let Some(col) = rg.next_column()? {
             if reporter_ions.is_empty() {
                 write_null_column(col, features.len())?;
@@ -240,6 +405,7 @@ pub fn build_matched_fragment_schema() -> parquet::errors::Result<Type> {
             required float fragment_mz_experimental;
             required float fragment_mz_calculated;
             required float fragment_intensity;
+            required float fragment_ppm_error;
         }
     "#;
 
@@ -383,12 +549,196 @@ pub fn serialize_matched_fragments(
             col.close()?;
         }
 
+        if let Some(mut col) = rg.next_column()? {
+            let fragment_ppm_error = features
+                .iter()
+                .flat_map(|f| f.fragments.as_ref().map(|fragments| fragments.ppm_errors()))
+                .flatten()
+                .collect::<Vec<_>>();
+
+            col.typed::<FloatType>()
+                .write_batch(&fragment_ppm_error, None, None)?;
+            col.close()?;
+        }
+
         rg.close()?;
     }
 
     writer.into_inner()
 }
 
+/// Spectral library in the column format of DIA-NN and Spectronaut, one row
+/// per fragment ion
+pub fn build_dia_library_schema() -> parquet::errors::Result<Type> {
+    let msg = r#"
+        message schema {
+            required byte_array ModifiedPeptide (utf8);
+            required byte_array StrippedPeptide (utf8);
+            required int32 PrecursorCharge;
+            required float PrecursorMz;
+            required float iRT;
+            required byte_array UniProtIds (utf8);
+            required byte_array FragmentType (utf8);
+            required int32 FragmentNumber;
+            required int32 FragmentCharge;
+            required byte_array FragmentLossType (utf8);
+            required float FragmentMz;
+            required float RelativeIntensity;
+        }
+    "#;
+    parquet::schema::parser::parse_message_type(msg)
+}
+
+pub fn serialize_dia_library(
+    library: &[LibraryEntry],
+    database: &IndexedDatabase,
+) -> parquet::errors::Result<Vec<u8>> {
+    let schema = build_dia_library_schema()?;
+
+    let options = WriterProperties::builder()
+        .set_compression(parquet::basic::Compression::ZSTD(ZstdLevel::try_new(3)?))
+        .build();
+
+    let buf = Vec::new();
+    let mut writer = SerializedFileWriter::new(buf, schema.into(), options.into())?;
+    let mut rg = writer.next_row_group()?;
+
+    // Values of precursor-level columns, repeated for each fragment
+    let repeat = |value: &dyn Fn(&LibraryEntry) -> ByteArray| {
+        library
+            .iter()
+            .flat_map(|entry| std::iter::repeat(value(entry)).take(entry.fragments.len()))
+            .collect::<Vec<_>>()
+    };
+    let fragments = library
+        .iter()
+        .flat_map(|entry| entry.fragments.iter())
+        .collect::<Vec<_>>();
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = repeat(&|entry| {
+            format!("_{}_", unimod_sequence(&database[entry.peptide_idx]))
+                .as_bytes()
+                .into()
+        });
+        col.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = repeat(&|entry| database[entry.peptide_idx].sequence.as_ref().into());
+        col.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = library
+            .iter()
+            .flat_map(|entry| std::iter::repeat(entry.charge as i32).take(entry.fragments.len()))
+            .collect::<Vec<_>>();
+        col.typed::<Int32Type>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = library
+            .iter()
+            .flat_map(|entry| {
+                let mz = (entry.calcmass + entry.charge as f32 * PROTON) / entry.charge as f32;
+                std::iter::repeat(mz).take(entry.fragments.len())
+            })
+            .collect::<Vec<_>>();
+        col.typed::<FloatType>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = library
+            .iter()
+            .flat_map(|entry| std::iter::repeat(entry.rt).take(entry.fragments.len()))
+            .collect::<Vec<_>>();
+        col.typed::<FloatType>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = repeat(&|entry| {
+            database[entry.peptide_idx]
+                .proteins(&database.decoy_tag, database.generate_decoys)
+                .as_str()
+                .into()
+        });
+        col.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = fragments
+            .iter()
+            .map(|fragment| match fragment.kind {
+                Kind::A => "a".as_bytes().into(),
+                Kind::B => "b".as_bytes().into(),
+                Kind::C => "c".as_bytes().into(),
+                Kind::X => "x".as_bytes().into(),
+                Kind::Y => "y".as_bytes().into(),
+                Kind::Z => "z".as_bytes().into(),
+            })
+            .collect::<Vec<_>>();
+        col.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = fragments
+            .iter()
+            .map(|fragment| fragment.ordinal)
+            .collect::<Vec<_>>();
+        col.typed::<Int32Type>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = fragments
+            .iter()
+            .map(|fragment| fragment.charge)
+            .collect::<Vec<_>>();
+        col.typed::<Int32Type>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = vec!["noloss".as_bytes().into(); fragments.len()];
+        col.typed::<ByteArrayType>()
+            .write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = fragments
+            .iter()
+            .map(|fragment| fragment.mz)
+            .collect::<Vec<_>>();
+        col.typed::<FloatType>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    if let Some(mut col) = rg.next_column()? {
+        let values = fragments
+            .iter()
+            .map(|fragment| fragment.intensity)
+            .collect::<Vec<_>>();
+        col.typed::<FloatType>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+
+    rg.close()?;
+    writer.into_inner()
+}
+
 pub fn build_lfq_schema() -> parquet::errors::Result<Type> {
     let msg = r#"
         message schema {
@@ -0,0 +1,247 @@
+//! Ranged reads of objects served over HTTP(S)
+
+use bytes::Bytes;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::{Response, StatusCode};
+
+/// Objects are requested in ranges of this many bytes, so that a read that
+/// fails partway (e.g. a dropped connection) resumes where it stopped,
+/// rather than starting over
+pub const RANGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of times in a row a range is requested again after a failed read
+const RETRIES: usize = 3;
+
+/// Sequential reader of an HTTP(S) object, in ranges of `range_size` bytes
+pub struct RangedRead {
+    url: String,
+    /// OAuth 2.0 access token, sent as a bearer token
+    token: Option<String>,
+    range_size: u64,
+    /// Position of the next byte to read
+    offset: u64,
+    /// Size of the object, if the server reported it
+    size: Option<u64>,
+    response: Option<Response>,
+    /// Whether the server ignores the `Range` header, and sent the whole object
+    whole: bool,
+    retries: usize,
+}
+
+impl RangedRead {
+    /// Request the first range of the object
+    pub async fn open(
+        url: String,
+        token: Option<String>,
+        range_size: u64,
+    ) -> reqwest::Result<Self> {
+        let mut read = RangedRead {
+            url,
+            token,
+            range_size,
+            offset: 0,
+            size: None,
+            response: None,
+            whole: false,
+            retries: 0,
+        };
+        let response = read.request().await?;
+        read.accept(response);
+        Ok(read)
+    }
+
+    /// Request the range starting at `offset`
+    async fn request(&self) -> reqwest::Result<Response> {
+        let end = self.offset + self.range_size - 1;
+        let mut request = crate::HTTP_CLIENT
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-{}", self.offset, end));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        match response.status() {
+            // Past the end of the object
+            StatusCode::RANGE_NOT_SATISFIABLE => Ok(response),
+            _ => response.error_for_status(),
+        }
+    }
+
+    /// Keep track of the response to a range request
+    fn accept(&mut self, response: Response) {
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                // e.g. `bytes 0-1023/4096`, or `bytes 0-1023/*` if unknown
+                self.size = response
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .and_then(|range| range.rsplit('/').next())
+                    .and_then(|size| size.parse().ok())
+                    .or(self.size);
+                self.response = Some(response);
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE => self.size = Some(self.offset),
+            _ => {
+                self.whole = true;
+                self.response = Some(response);
+            }
+        }
+    }
+
+    fn error<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, err)
+    }
+
+    /// Read the next chunk of the object, or `None` at its end
+    pub async fn next(&mut self) -> Option<std::io::Result<Bytes>> {
+        loop {
+            match self.response.as_mut() {
+                Some(response) => match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        self.offset += chunk.len() as u64;
+                        self.retries = 0;
+                        return Some(Ok(chunk));
+                    }
+                    Ok(None) if self.whole => return None,
+                    Ok(None) => self.response = None,
+                    Err(err) => {
+                        self.response = None;
+                        self.retries += 1;
+                        if self.whole || self.retries > RETRIES {
+                            return Some(Err(Self::error(err)));
+                        }
+                        log::warn!(
+                            "reading {} failed at byte {}, retrying: {}",
+                            self.url,
+                            self.offset,
+                            err
+                        );
+                    }
+                },
+                None => {
+                    if self.size.map_or(false, |size| self.offset >= size) {
+                        return None;
+                    }
+                    let response = match self.request().await {
+                        Ok(response) => response,
+                        Err(err) => {
+                            self.retries += 1;
+                            if self.retries > RETRIES {
+                                return Some(Err(Self::error(err)));
+                            }
+                            continue;
+                        }
+                    };
+                    // Resuming requires ranges: the server can't send the
+                    // whole object again
+                    if self.offset > 0 && response.status() == StatusCode::OK {
+                        return Some(Err(Self::error(format!(
+                            "{} can't be read from byte {}, the server ignores ranged requests",
+                            self.url, self.offset
+                        ))));
+                    }
+                    self.accept(response);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RangedRead;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Serve `body` on a local port, honoring `Range` headers if `ranges`.
+    /// The first response is cut short after `cut` bytes, as if the
+    /// connection dropped
+    fn serve(body: Vec<u8>, ranges: bool, cut: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.mzML", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (idx, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(bytes) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = bytes.split_once('-').unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end: usize = end.parse().unwrap();
+                        range = Some((start, (end + 1).min(body.len())));
+                    }
+                }
+                let (head, start, end) = match range.filter(|_| ranges) {
+                    Some((start, _)) if start >= body.len() => {
+                        let head = format!(
+                            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n",
+                            body.len()
+                        );
+                        (head, 0, 0)
+                    }
+                    Some((start, end)) => {
+                        let head = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                            start,
+                            end - 1,
+                            body.len()
+                        );
+                        (head, start, end)
+                    }
+                    None => ("HTTP/1.1 200 OK\r\n".to_string(), 0, body.len()),
+                };
+                let sent = match idx {
+                    0 => end.min(start + cut),
+                    _ => end,
+                };
+                write!(
+                    stream,
+                    "{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    head,
+                    end - start
+                )
+                .unwrap();
+                stream.write_all(&body[start..sent]).unwrap();
+            }
+        });
+        url
+    }
+
+    async fn read_all(url: String, range_size: u64) -> std::io::Result<Vec<u8>> {
+        let mut read = RangedRead::open(url, None, range_size)
+            .await
+            .map_err(RangedRead::error)?;
+        let mut bytes = Vec::new();
+        while let Some(chunk) = read.next().await {
+            bytes.extend(chunk?);
+        }
+        Ok(bytes)
+    }
+
+    #[tokio::test]
+    async fn ranged_read_resumes() {
+        let body = (0..1000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let url = serve(body.clone(), true, 37);
+        assert_eq!(read_all(url, 100).await.unwrap(), body);
+
+        // Object size is a multiple of the range size
+        let url = serve(body.clone(), true, usize::MAX);
+        assert_eq!(read_all(url, 250).await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn ranges_unsupported() {
+        let body = (0..1000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let url = serve(body.clone(), false, usize::MAX);
+        assert_eq!(read_all(url, 100).await.unwrap(), body);
+
+        // Can't resume
+        let url = serve(body, false, 37);
+        assert!(read_all(url, 100).await.is_err());
+    }
+}
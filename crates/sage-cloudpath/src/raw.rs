@@ -0,0 +1,217 @@
+//! Thermo RAW files, read through [ThermoRawFileParser], which uses Thermo's
+//! RawFileReader libraries: each file is converted to mzML (centroided by
+//! default), which is streamed through a pipe to [`MzMLReader`], so no
+//! converted copy is written to disk
+//!
+//! [ThermoRawFileParser]: https://github.com/compomics/ThermoRawFileParser
+
+use crate::mzml::{MzMLError, MzMLReader};
+use sage_core::spectrum::RawSpectrum;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+/// Environment variable overriding the command that runs
+/// ThermoRawFileParser, e.g. `mono /opt/trfp/ThermoRawFileParser.exe`
+pub const PARSER_VARIABLE: &str = "SAGE_THERMO_RAW_FILE_PARSER";
+
+/// Commands tried if [`PARSER_VARIABLE`] is not set: the executable of the
+/// release archives, and that of the bioconda package
+const PARSER_COMMANDS: [&str; 2] = ["ThermoRawFileParser", "thermorawfileparser"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum RawError {
+    #[error(
+        "ThermoRawFileParser is needed to read Thermo RAW files, but was not found: install it \
+         (e.g. `conda install -c bioconda thermorawfileparser`), or set `{}` to the command \
+         that runs it",
+        PARSER_VARIABLE
+    )]
+    NotFound,
+    #[error("Thermo RAW files must be local, cannot read {0}")]
+    NotLocal(String),
+    #[error("ThermoRawFileParser failed ({status}): {message}")]
+    Failed { status: ExitStatus, message: String },
+    #[error(transparent)]
+    MzML(#[from] MzMLError),
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+}
+
+pub struct RawFileParser {
+    /// Candidate commands (program and leading arguments), tried in order
+    commands: Vec<Vec<String>>,
+}
+
+impl RawFileParser {
+    /// Run the command set in [`PARSER_VARIABLE`], or else the first of
+    /// [`PARSER_COMMANDS`] found
+    pub fn from_env() -> Self {
+        let commands = match std::env::var(PARSER_VARIABLE) {
+            Ok(command) => vec![command.split_whitespace().map(String::from).collect()],
+            Err(_) => PARSER_COMMANDS
+                .iter()
+                .map(|command| vec![command.to_string()])
+                .collect(),
+        };
+        Self { commands }
+    }
+
+    fn spawn(&self, path: &Path) -> Result<tokio::process::Child, RawError> {
+        for command in &self.commands {
+            let (program, args) = match command.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            let child = tokio::process::Command::new(program)
+                .args(args)
+                .arg("--input")
+                .arg(path)
+                // mzML, written to stdout
+                .arg("--format=1")
+                .arg("--stdout")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn();
+            match child {
+                Ok(child) => return Ok(child),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Err(RawError::NotFound)
+    }
+
+    /// Convert the RAW file at `path` to mzML, and stream its spectra to `f`
+    /// with `reader`, see [`MzMLReader::parse_with`]. Returns the number of
+    /// spectra parsed
+    pub async fn parse_with<F>(
+        &self,
+        reader: &MzMLReader,
+        path: &Path,
+        f: F,
+    ) -> Result<usize, RawError>
+    where
+        F: FnMut(RawSpectrum),
+    {
+        let mut child = self.spawn(path)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        // Read concurrently, so that the parser never blocks on a full pipe
+        let messages = tokio::spawn(async move {
+            let mut messages = String::new();
+            let _ = stderr.read_to_string(&mut messages).await;
+            messages
+        });
+
+        let mut stdout = BufReader::new(stdout);
+        let parsed = reader.parse_with(&mut stdout, f).await;
+        // The reader stops early past the end of the scan window: the rest of
+        // the conversion is not needed
+        if parsed.is_ok() && !stdout.fill_buf().await?.is_empty() {
+            let _ = child.kill().await;
+            return Ok(parsed?);
+        }
+
+        // The output is likely cut short if conversion failed
+        let status = child.wait().await?;
+        let messages = messages.await.unwrap_or_default();
+        match status.success() {
+            true => Ok(parsed?),
+            false => Err(RawError::Failed {
+                status,
+                message: messages.trim().to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use sage_core::spectrum::ScanWindow;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Stand-in for ThermoRawFileParser: a script that runs `body`
+    fn parser(dir: &Path, body: &str) -> RawFileParser {
+        let script = dir.join("ThermoRawFileParser");
+        std::fs::write(&script, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        RawFileParser {
+            commands: vec![
+                vec!["sage-no-such-parser".into()],
+                vec![script.to_string_lossy().to_string()],
+            ],
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_raw_file() {
+        let dir = std::env::temp_dir().join(format!("sage-raw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mzml = dir.join("file.mzML");
+        std::fs::write(
+            &mzml,
+            include_str!("../../../tests/LQSRPAAPPAPGPGQLTLR.mzML"),
+        )
+        .unwrap();
+        let raw = dir.join("file.raw");
+        let reader = MzMLReader::with_file_id(0);
+
+        // The converted mzML is read from stdout
+        let convert = parser(
+            &dir,
+            &format!(
+                "[ \"$1 $2 $3 $4\" = \"--input {} --format=1 --stdout\" ] || exit 2\ncat {}",
+                raw.display(),
+                mzml.display()
+            ),
+        );
+        let mut ids = Vec::new();
+        let parsed = convert
+            .parse_with(&reader, &raw, |spectrum| ids.push(spectrum.id))
+            .await
+            .unwrap();
+        assert_eq!(parsed, 1);
+        assert_eq!(ids, ["controllerType=0 controllerNumber=1 scan=30069"]);
+
+        // Conversion is stopped once past the end of the scan window
+        let slow = parser(&dir, &format!("cat {}\nexec sleep 60", mzml.display()));
+        let mut windowed = MzMLReader::with_file_id(0);
+        windowed.set_scan_window(ScanWindow {
+            scan_range: Some((1, 100)),
+            rt_range: None,
+        });
+        let start = std::time::Instant::now();
+        let parsed = slow.parse_with(&windowed, &raw, |_| {}).await.unwrap();
+        assert_eq!(parsed, 0);
+        assert!(start.elapsed().as_secs() < 30);
+
+        // Conversion fails partway
+        let fail = parser(
+            &dir,
+            &format!(
+                "head -c 4000 {}\necho 'file.raw: unable to open' >&2\nexit 1",
+                mzml.display()
+            ),
+        );
+        let result = fail.parse_with(&reader, &raw, |_| {}).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        match result {
+            Err(RawError::Failed { message, .. }) => {
+                assert_eq!(message, "file.raw: unable to open")
+            }
+            other => panic!("expected a conversion failure, found {:?}", other),
+        }
+
+        let missing = RawFileParser {
+            commands: vec![vec!["sage-no-such-parser".into()]],
+        };
+        assert!(matches!(
+            missing.parse_with(&reader, &raw, |_| {}).await,
+            Err(RawError::NotFound)
+        ));
+    }
+}
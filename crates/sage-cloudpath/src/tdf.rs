@@ -13,14 +13,22 @@ impl TdfReader {
             timsrust::FileReader::new(path_name.as_ref())?.read_all_spectra();
         let spectra: Vec<RawSpectrum> = (0..dda_spectra.len())
             .into_par_iter()
-            .map(|index| {
+            .filter_map(|index| {
                 let dda_spectrum = &dda_spectra[index];
                 let mut precursor: Precursor = Precursor::default();
-                let dda_precursor: timsrust::Precursor =
-                    dda_spectrum.precursor.unwrap_as_precursor();
+                // MS2 spectra without a precursor cannot be searched
+                let dda_precursor: timsrust::Precursor = match dda_spectrum.precursor {
+                    timsrust::PrecursorType::Precursor(precursor) => precursor,
+                    timsrust::PrecursorType::None => return None,
+                };
                 precursor.mz = dda_precursor.mz as f32;
-                precursor.charge = Option::from(dda_precursor.charge as u8);
-                // precursor.ion_mobility = Option::from(dda_precursor.im as f32);
+                // Precursors without an assigned charge state are reported as
+                // charge 0 - leave these to be searched at multiple charges
+                precursor.charge = match dda_precursor.charge {
+                    0 => None,
+                    charge => Some(charge as u8),
+                };
+                precursor.ion_mobility = Option::from(dda_precursor.im as f32);
                 precursor.intensity = Option::from(dda_precursor.intensity as f32);
                 precursor.spectrum_ref = Option::from(dda_precursor.frame_index.to_string());
                 let spectrum: RawSpectrum = RawSpectrum {
@@ -28,7 +36,8 @@ impl TdfReader {
                     precursors: vec![precursor],
                     representation: Representation::Centroid,
                     scan_start_time: dda_precursor.rt as f32 / 60.0,
-                    ion_injection_time: dda_precursor.rt as f32,
+                    // Not reported by timsrust
+                    ion_injection_time: 0.0,
                     total_ion_current: 0.0,
                     mz: dda_spectrum.mz_values.iter().map(|&x| x as f32).collect(),
                     ms_level: 2,
@@ -36,8 +45,12 @@ impl TdfReader {
                     // precursor_id: dda_precursor.index as u32,
                     // frame_id: dda_precursor.frame_index as u32,
                     intensity: dda_spectrum.intensities.iter().map(|&x| x as f32).collect(),
+                    noise: Vec::new(),
+                    ion_mobility: None,
+                    mobility: Vec::new(),
+                    compensation_voltage: None,
                 };
-                spectrum
+                Some(spectrum)
             })
             .collect();
         Ok(spectra)
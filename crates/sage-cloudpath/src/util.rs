@@ -1,5 +1,6 @@
-use crate::{read_and_execute, Error};
-use sage_core::spectrum::RawSpectrum;
+use crate::raw::{RawError, RawFileParser};
+use crate::{read_and_execute, CloudPath, Error};
+use sage_core::spectrum::{RawSpectrum, ScanWindow};
 use serde::Serialize;
 use tokio::io::AsyncReadExt;
 
@@ -16,6 +17,64 @@ pub fn read_mzml<S: AsRef<str>>(
     })
 }
 
+pub fn read_mzxml<S: AsRef<str>>(s: S, file_id: usize) -> Result<Vec<RawSpectrum>, Error> {
+    read_and_execute(s, |bf| async move {
+        Ok(crate::mzxml::MzXMLReader::with_file_id(file_id)
+            .parse(bf)
+            .await?)
+    })
+}
+
+/// Stream spectra from an mzML file, passing each to `f` as soon as it has
+/// been parsed. Only spectra inside of `window` are read. Returns the number
+/// of spectra read
+pub fn read_mzml_with<S, F>(
+    s: S,
+    file_id: usize,
+    signal_to_noise: Option<u8>,
+    window: ScanWindow,
+    f: F,
+) -> Result<usize, Error>
+where
+    S: AsRef<str>,
+    F: FnMut(RawSpectrum),
+{
+    read_and_execute(s, |bf| async move {
+        Ok(crate::mzml::MzMLReader::with_file_id(file_id)
+            .set_signal_to_noise(signal_to_noise)
+            .set_scan_window(window)
+            .parse_with(bf, f)
+            .await?)
+    })
+}
+
+/// Read a local Thermo RAW file through ThermoRawFileParser, see [`crate::raw`]
+pub fn read_raw_with<S, F>(
+    s: S,
+    file_id: usize,
+    signal_to_noise: Option<u8>,
+    window: ScanWindow,
+    f: F,
+) -> Result<usize, Error>
+where
+    S: AsRef<str>,
+    F: FnMut(RawSpectrum),
+{
+    let path = match s.as_ref().parse::<CloudPath>()? {
+        CloudPath::Local(path) => path,
+        path => return Err(RawError::NotLocal(path.to_string()).into()),
+    };
+    let mut reader = crate::mzml::MzMLReader::with_file_id(file_id);
+    reader
+        .set_signal_to_noise(signal_to_noise)
+        .set_scan_window(window);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    Ok(rt.block_on(RawFileParser::from_env().parse_with(&reader, &path, f))?)
+}
+
 pub fn read_tdf<S: AsRef<str>>(s: S, file_id: usize) -> Result<Vec<RawSpectrum>, Error> {
     let res = crate::tdf::TdfReader.parse(s, file_id);
     match res {
@@ -59,6 +118,22 @@ where
     })
 }
 
+pub fn read_string<S: AsRef<str>>(path: S) -> Result<String, Error> {
+    read_and_execute(path, |mut bf| async move {
+        let mut contents = String::new();
+        bf.read_to_string(&mut contents).await?;
+        Ok(contents)
+    })
+}
+
+pub fn read_bytes<S: AsRef<str>>(path: S) -> Result<Vec<u8>, Error> {
+    read_and_execute(path, |mut bf| async move {
+        let mut contents = Vec::new();
+        bf.read_to_end(&mut contents).await?;
+        Ok(contents)
+    })
+}
+
 pub fn read_json<S, T>(path: S) -> Result<T, Error>
 where
     S: AsRef<str>,
@@ -0,0 +1,132 @@
+/* Generated with cbindgen from crates/sage-ffi/src/lib.rs - do not edit */
+
+#ifndef SAGE_H
+#define SAGE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Fragment index of a digested FASTA database
+typedef struct SageDatabase SageDatabase;
+
+// Scores MS2 spectra against a [`SageDatabase`]
+typedef struct SageScorer SageScorer;
+
+// A centroided MS2 spectrum
+typedef struct SageSpectrum {
+  // Peak m/z values, `n_peaks` long
+  const float *mz;
+  // Peak intensities, `n_peaks` long
+  const float *intensity;
+  size_t n_peaks;
+  float precursor_mz;
+  // Precursor charge, or 0 if unknown: all charges of `precursor_charge`
+  // are then searched
+  uint8_t precursor_charge;
+  // Retention time in minutes
+  float scan_start_time;
+} SageSpectrum;
+
+// A peptide-spectrum match. Sequences and proteins can be looked up with
+// [`sage_database_peptide`] and [`sage_database_proteins`]
+typedef struct SagePsm {
+  uint32_t peptide_idx;
+  // 1 for target, -1 for decoy peptides
+  int32_t label;
+  uint32_t rank;
+  uint8_t charge;
+  float expmass;
+  float calcmass;
+  float isotope_error;
+  double hyperscore;
+  double delta_next;
+  uint32_t matched_peaks;
+  float matched_intensity_pct;
+  double poisson;
+} SagePsm;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Message of the last error on the calling thread, or `NULL`. The string is
+// valid until the next call to a Sage function on the same thread
+const char *sage_last_error(void);
+
+// Build the fragment index of `fasta`, the contents of a FASTA file.
+// `parameters` is the `database` section of a Sage configuration file as a
+// JSON string, or `NULL` for the defaults. Returns `NULL` on error
+//
+// # Safety
+// `fasta` and `parameters` must be NULL or NUL-terminated strings
+SageDatabase *sage_database_new(const char *fasta, const char *parameters);
+
+// # Safety
+// `db` must be NULL or returned by [`sage_database_new`], and not be used
+// by any scorer afterwards
+void sage_database_free(SageDatabase *db);
+
+// Number of (target and decoy) peptides in the database
+//
+// # Safety
+// `db` must be returned by [`sage_database_new`]
+size_t sage_database_peptides(const SageDatabase *db);
+
+// Write the modified sequence of a peptide (e.g. `PEPTIDEM[+15.9949]K`) to
+// `buf`, truncated to `len` bytes including the terminating NUL. Returns the
+// length of the sequence, or 0 if `peptide_idx` is out of range
+//
+// # Safety
+// `db` must be returned by [`sage_database_new`], and `buf` must be NULL or
+// point to at least `len` bytes
+size_t sage_database_peptide(const SageDatabase *db, uint32_t peptide_idx, char *buf, size_t len);
+
+// Write the accessions of the proteins containing a peptide, separated by
+// `;`, to `buf`, like [`sage_database_peptide`]
+//
+// # Safety
+// `db` must be returned by [`sage_database_new`], and `buf` must be NULL or
+// point to at least `len` bytes
+size_t sage_database_proteins(const SageDatabase *db, uint32_t peptide_idx, char *buf, size_t len);
+
+// Create a scorer for `db`. `settings` is a JSON object with the scoring
+// settings of a Sage configuration file: `precursor_tol` and `fragment_tol`
+// are required, and `precursor_charge`, `isotope_errors`,
+// `min_matched_peaks`, `max_fragment_charge`, `report_psms`, `chimera`,
+// `wide_window`, `deisotope`, `max_peaks` and `min_peaks` are optional.
+// Returns `NULL` on error
+//
+// # Safety
+// `db` must be returned by [`sage_database_new`], and outlive the scorer.
+// `settings` must be a NUL-terminated string
+SageScorer *sage_scorer_new(const SageDatabase *db, const char *settings);
+
+// # Safety
+// `scorer` must be NULL or returned by [`sage_scorer_new`]
+void sage_scorer_free(SageScorer *scorer);
+
+// Score a spectrum. On success, returns 0 and stores the PSMs (best first)
+// in `*psms` and their number in `*n_psms`; `*psms` must be freed with
+// [`sage_psms_free`]. Returns -1 on error
+//
+// # Safety
+// `scorer` must be returned by [`sage_scorer_new`], the arrays of
+// `spectrum` must contain `n_peaks` values, and `psms` and `n_psms` must be
+// valid pointers
+int sage_score(const SageScorer *scorer,
+               const SageSpectrum *spectrum,
+               SagePsm **psms,
+               size_t *n_psms);
+
+// # Safety
+// `psms` and `n_psms` must be returned by [`sage_score`]
+void sage_psms_free(SagePsm *psms, size_t n_psms);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SAGE_H */
@@ -0,0 +1,491 @@
+//! C interface to Sage's scoring core, for calling Sage from instrument
+//! control software or C/C++ pipelines. See `include/sage.h`
+//!
+//! ```c
+//! SageDatabase *db = sage_database_new(fasta, "{\"enzyme\": {\"missed_cleavages\": 2}}");
+//! SageScorer *scorer = sage_scorer_new(db, "{\"precursor_tol\": {\"ppm\": [-10, 10]}, \"fragment_tol\": {\"ppm\": [-10, 10]}}");
+//! SageSpectrum spectrum = { mz, intensity, n_peaks, precursor_mz, 2, 0.0f };
+//! SagePsm *psms;
+//! size_t n_psms;
+//! if (sage_score(scorer, &spectrum, &psms, &n_psms) == 0) {
+//!     ...
+//!     sage_psms_free(psms, n_psms);
+//! }
+//! sage_scorer_free(scorer);
+//! sage_database_free(db);
+//! ```
+//!
+//! Functions that fail return `NULL` or `-1`, and [`sage_last_error`]
+//! describes the error. Databases and scorers are immutable once created, and
+//! can be used from several threads at once.
+
+use sage_core::database::{Builder, IndexedDatabase};
+use sage_core::fasta::Fasta;
+use sage_core::mass::Tolerance;
+use sage_core::scoring::{Feature, Scorer};
+use sage_core::spectrum::{Precursor, RawSpectrum, Representation, SpectrumProcessor};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(error: String) {
+    let error = CString::new(error.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+}
+
+/// Read a NUL-terminated UTF-8 string
+unsafe fn read_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("`{}` is NULL", name));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| format!("`{}` is not valid UTF-8", name))
+}
+
+/// Copy `s` into `buf` as a NUL-terminated string, truncated to `len` bytes,
+/// and return the length of `s`, like `snprintf`
+unsafe fn write_str(s: &str, buf: *mut c_char, len: usize) -> usize {
+    if !buf.is_null() && len > 0 {
+        let n = s.len().min(len - 1);
+        std::ptr::copy_nonoverlapping(s.as_ptr(), buf as *mut u8, n);
+        *buf.add(n) = 0;
+    }
+    s.len()
+}
+
+/// Message of the last error on the calling thread, or `NULL`. The string is
+/// valid until the next call to a Sage function on the same thread
+#[no_mangle]
+pub extern "C" fn sage_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |error| error.as_ptr())
+    })
+}
+
+/// Fragment index of a digested FASTA database
+pub struct SageDatabase {
+    inner: IndexedDatabase,
+    fragment_min_mz: f32,
+    fragment_max_mz: f32,
+}
+
+/// Build the fragment index of `fasta`, the contents of a FASTA file.
+/// `parameters` is the `database` section of a Sage configuration file as a
+/// JSON string, or `NULL` for the defaults. Returns `NULL` on error
+///
+/// # Safety
+/// `fasta` and `parameters` must be NULL or NUL-terminated strings
+#[no_mangle]
+pub unsafe extern "C" fn sage_database_new(
+    fasta: *const c_char,
+    parameters: *const c_char,
+) -> *mut SageDatabase {
+    let build = || -> Result<SageDatabase, String> {
+        let fasta = read_str(fasta, "fasta")?;
+        let builder = match parameters.is_null() {
+            true => Builder::default(),
+            false => serde_json::from_str::<Builder>(read_str(parameters, "parameters")?)
+                .map_err(|e| format!("invalid database parameters: {}", e))?,
+        };
+        let parameters = builder.make_parameters();
+        let (fragment_min_mz, fragment_max_mz) =
+            (parameters.fragment_min_mz, parameters.fragment_max_mz);
+        let fasta = Fasta::parse(
+            fasta.into(),
+            parameters.decoy_tag.as_str(),
+            parameters.generate_decoys,
+        );
+        Ok(SageDatabase {
+            inner: parameters.build(fasta),
+            fragment_min_mz,
+            fragment_max_mz,
+        })
+    };
+    match build() {
+        Ok(db) => Box::into_raw(Box::new(db)),
+        Err(e) => {
+            set_error(e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// # Safety
+/// `db` must be NULL or returned by [`sage_database_new`], and not be used
+/// by any scorer afterwards
+#[no_mangle]
+pub unsafe extern "C" fn sage_database_free(db: *mut SageDatabase) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// Number of (target and decoy) peptides in the database
+///
+/// # Safety
+/// `db` must be returned by [`sage_database_new`]
+#[no_mangle]
+pub unsafe extern "C" fn sage_database_peptides(db: *const SageDatabase) -> usize {
+    let db = &(*db).inner;
+    db.peptides.len()
+}
+
+/// Write the modified sequence of a peptide (e.g. `PEPTIDEM[+15.9949]K`) to
+/// `buf`, truncated to `len` bytes including the terminating NUL. Returns the
+/// length of the sequence, or 0 if `peptide_idx` is out of range
+///
+/// # Safety
+/// `db` must be returned by [`sage_database_new`], and `buf` must be NULL or
+/// point to at least `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn sage_database_peptide(
+    db: *const SageDatabase,
+    peptide_idx: u32,
+    buf: *mut c_char,
+    len: usize,
+) -> usize {
+    let db = &(*db).inner;
+    match db.peptides.get(peptide_idx as usize) {
+        Some(peptide) => write_str(&peptide.to_string(), buf, len),
+        None => 0,
+    }
+}
+
+/// Write the accessions of the proteins containing a peptide, separated by
+/// `;`, to `buf`, like [`sage_database_peptide`]
+///
+/// # Safety
+/// `db` must be returned by [`sage_database_new`], and `buf` must be NULL or
+/// point to at least `len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn sage_database_proteins(
+    db: *const SageDatabase,
+    peptide_idx: u32,
+    buf: *mut c_char,
+    len: usize,
+) -> usize {
+    let db = &(*db).inner;
+    match db.peptides.get(peptide_idx as usize) {
+        Some(peptide) => write_str(
+            &peptide.proteins(&db.decoy_tag, db.generate_decoys),
+            buf,
+            len,
+        ),
+        None => 0,
+    }
+}
+
+/// Search settings of a [`SageScorer`], with the same names, meaning and
+/// defaults as in a Sage configuration file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    precursor_tol: Tolerance,
+    fragment_tol: Tolerance,
+    precursor_charge: Option<(u8, u8)>,
+    isotope_errors: Option<(i8, i8)>,
+    min_matched_peaks: Option<u16>,
+    max_fragment_charge: Option<u8>,
+    report_psms: Option<usize>,
+    chimera: Option<bool>,
+    wide_window: Option<bool>,
+    deisotope: Option<bool>,
+    max_peaks: Option<usize>,
+    min_peaks: Option<usize>,
+}
+
+/// Scores MS2 spectra against a [`SageDatabase`]
+pub struct SageScorer {
+    db: *const SageDatabase,
+    settings: Settings,
+}
+
+/// Create a scorer for `db`. `settings` is a JSON object with the scoring
+/// settings of a Sage configuration file: `precursor_tol` and `fragment_tol`
+/// are required, and `precursor_charge`, `isotope_errors`,
+/// `min_matched_peaks`, `max_fragment_charge`, `report_psms`, `chimera`,
+/// `wide_window`, `deisotope`, `max_peaks` and `min_peaks` are optional.
+/// Returns `NULL` on error
+///
+/// # Safety
+/// `db` must be returned by [`sage_database_new`], and outlive the scorer.
+/// `settings` must be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn sage_scorer_new(
+    db: *const SageDatabase,
+    settings: *const c_char,
+) -> *mut SageScorer {
+    let settings = read_str(settings, "settings").and_then(|settings| {
+        serde_json::from_str::<Settings>(settings)
+            .map_err(|e| format!("invalid scorer settings: {}", e))
+    });
+    match (db.is_null(), settings) {
+        (true, _) => set_error("`db` is NULL".into()),
+        (false, Ok(settings)) => return Box::into_raw(Box::new(SageScorer { db, settings })),
+        (false, Err(e)) => set_error(e),
+    }
+    std::ptr::null_mut()
+}
+
+/// # Safety
+/// `scorer` must be NULL or returned by [`sage_scorer_new`]
+#[no_mangle]
+pub unsafe extern "C" fn sage_scorer_free(scorer: *mut SageScorer) {
+    if !scorer.is_null() {
+        drop(Box::from_raw(scorer));
+    }
+}
+
+/// A centroided MS2 spectrum
+#[repr(C)]
+pub struct SageSpectrum {
+    /// Peak m/z values, `n_peaks` long
+    pub mz: *const f32,
+    /// Peak intensities, `n_peaks` long
+    pub intensity: *const f32,
+    pub n_peaks: usize,
+    pub precursor_mz: f32,
+    /// Precursor charge, or 0 if unknown: all charges of `precursor_charge`
+    /// are then searched
+    pub precursor_charge: u8,
+    /// Retention time in minutes
+    pub scan_start_time: f32,
+}
+
+/// A peptide-spectrum match. Sequences and proteins can be looked up with
+/// [`sage_database_peptide`] and [`sage_database_proteins`]
+#[repr(C)]
+pub struct SagePsm {
+    pub peptide_idx: u32,
+    /// 1 for target, -1 for decoy peptides
+    pub label: i32,
+    pub rank: u32,
+    pub charge: u8,
+    pub expmass: f32,
+    pub calcmass: f32,
+    pub isotope_error: f32,
+    pub hyperscore: f64,
+    pub delta_next: f64,
+    pub matched_peaks: u32,
+    pub matched_intensity_pct: f32,
+    pub poisson: f64,
+}
+
+impl From<Feature> for SagePsm {
+    fn from(feature: Feature) -> Self {
+        SagePsm {
+            peptide_idx: feature.peptide_idx.0,
+            label: feature.label,
+            rank: feature.rank,
+            charge: feature.charge,
+            expmass: feature.expmass,
+            calcmass: feature.calcmass,
+            isotope_error: feature.isotope_error,
+            hyperscore: feature.hyperscore,
+            delta_next: feature.delta_next,
+            matched_peaks: feature.matched_peaks,
+            matched_intensity_pct: feature.matched_intensity_pct,
+            poisson: feature.poisson,
+        }
+    }
+}
+
+/// Score a spectrum. On success, returns 0 and stores the PSMs (best first)
+/// in `*psms` and their number in `*n_psms`; `*psms` must be freed with
+/// [`sage_psms_free`]. Returns -1 on error
+///
+/// # Safety
+/// `scorer` must be returned by [`sage_scorer_new`], the arrays of
+/// `spectrum` must contain `n_peaks` values, and `psms` and `n_psms` must be
+/// valid pointers
+#[no_mangle]
+pub unsafe extern "C" fn sage_score(
+    scorer: *const SageScorer,
+    spectrum: *const SageSpectrum,
+    psms: *mut *mut SagePsm,
+    n_psms: *mut usize,
+) -> c_int {
+    if scorer.is_null() || spectrum.is_null() || psms.is_null() || n_psms.is_null() {
+        set_error("NULL argument to `sage_score`".into());
+        return -1;
+    }
+    let (scorer, spectrum) = (&*scorer, &*spectrum);
+    if spectrum.n_peaks > 0 && (spectrum.mz.is_null() || spectrum.intensity.is_null()) {
+        set_error("`mz` or `intensity` is NULL".into());
+        return -1;
+    }
+    let peaks = |ptr: *const f32| match spectrum.n_peaks {
+        0 => Vec::new(),
+        n => std::slice::from_raw_parts(ptr, n).to_vec(),
+    };
+    let db = &*scorer.db;
+    let settings = &scorer.settings;
+
+    let raw = RawSpectrum {
+        ms_level: 2,
+        precursors: vec![Precursor {
+            mz: spectrum.precursor_mz,
+            charge: Some(spectrum.precursor_charge).filter(|&z| z > 0),
+            ..Default::default()
+        }],
+        representation: Representation::Centroid,
+        scan_start_time: spectrum.scan_start_time,
+        mz: peaks(spectrum.mz),
+        intensity: peaks(spectrum.intensity),
+        ..RawSpectrum::default_with_file_id(0)
+    };
+    let processed = SpectrumProcessor::new(
+        settings.max_peaks.unwrap_or(150),
+        db.fragment_min_mz,
+        db.fragment_max_mz,
+        settings.deisotope.unwrap_or(true),
+    )
+    .process(raw);
+
+    let features = match processed.peaks.len() >= settings.min_peaks.unwrap_or(15) {
+        true => {
+            let (min_precursor_charge, max_precursor_charge) =
+                settings.precursor_charge.unwrap_or((2, 4));
+            let (min_isotope_err, max_isotope_err) = settings.isotope_errors.unwrap_or((0, 0));
+            let wide_window = settings.wide_window.unwrap_or(false);
+            Scorer {
+                min_matched_peaks: settings.min_matched_peaks.unwrap_or(4),
+                min_isotope_err,
+                max_isotope_err,
+                min_precursor_charge,
+                max_precursor_charge,
+                max_fragment_charge: settings.max_fragment_charge,
+                min_fragment_mass: db.fragment_min_mz,
+                max_fragment_mass: db.fragment_max_mz,
+                chimera: settings.chimera.unwrap_or(wide_window),
+                report_psms: settings
+                    .report_psms
+                    .unwrap_or(if wide_window { 5 } else { 1 }),
+                wide_window,
+                ..Scorer::new(&db.inner, settings.precursor_tol, settings.fragment_tol)
+            }
+            .score(&processed)
+        }
+        false => Vec::new(),
+    };
+
+    let boxed = features
+        .into_iter()
+        .map(SagePsm::from)
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+    *n_psms = boxed.len();
+    *psms = Box::into_raw(boxed) as *mut SagePsm;
+    0
+}
+
+/// # Safety
+/// `psms` and `n_psms` must be returned by [`sage_score`]
+#[no_mangle]
+pub unsafe extern "C" fn sage_psms_free(psms: *mut SagePsm, n_psms: usize) {
+    if !psms.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            psms, n_psms,
+        )));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sage_core::ion_series::{IonSeries, Kind};
+    use sage_core::mass::PROTON;
+
+    #[test]
+    fn score_from_c() {
+        let fasta = CString::new(">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n").unwrap();
+        let settings = CString::new(
+            r#"{"precursor_tol": {"ppm": [-10, 10]}, "fragment_tol": {"ppm": [-10, 10]}}"#,
+        )
+        .unwrap();
+        unsafe {
+            let db = sage_database_new(fasta.as_ptr(), std::ptr::null());
+            assert!(!db.is_null());
+            let scorer = sage_scorer_new(db, settings.as_ptr());
+            assert!(!scorer.is_null());
+
+            let peptide = (*db)
+                .inner
+                .peptides
+                .iter()
+                .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+                .unwrap();
+            let mz = [Kind::B, Kind::Y]
+                .into_iter()
+                .flat_map(|kind| IonSeries::new(peptide, kind))
+                .map(|ion| ion.monoisotopic_mass + PROTON)
+                .collect::<Vec<_>>();
+            let intensity = vec![1.0; mz.len()];
+            let spectrum = SageSpectrum {
+                mz: mz.as_ptr(),
+                intensity: intensity.as_ptr(),
+                n_peaks: mz.len(),
+                precursor_mz: peptide.monoisotopic / 2.0 + PROTON,
+                precursor_charge: 2,
+                scan_start_time: 0.0,
+            };
+
+            let mut psms = std::ptr::null_mut();
+            let mut n_psms = 0;
+            assert_eq!(sage_score(scorer, &spectrum, &mut psms, &mut n_psms), 0);
+            assert_eq!(n_psms, 1);
+            let psm = &*psms;
+            assert_eq!(psm.label, 1);
+
+            let mut buf = [0 as c_char; 64];
+            let len = sage_database_peptide(db, psm.peptide_idx, buf.as_mut_ptr(), buf.len());
+            assert_eq!(len, 19);
+            assert_eq!(
+                CStr::from_ptr(buf.as_ptr()).to_str(),
+                Ok("LQSRPAAPPAPGPGQLTLR")
+            );
+            // Truncated, like snprintf
+            sage_database_proteins(db, psm.peptide_idx, buf.as_mut_ptr(), 5);
+            assert_eq!(CStr::from_ptr(buf.as_ptr()).to_str(), Ok("sp|A"));
+            sage_psms_free(psms, n_psms);
+
+            let invalid = CString::new(r#"{"precursor_tol": {"ppm": [-10, 10]}}"#).unwrap();
+            assert!(sage_scorer_new(db, invalid.as_ptr()).is_null());
+            let error = CStr::from_ptr(sage_last_error()).to_str().unwrap();
+            assert!(error.contains("fragment_tol"), "{}", error);
+
+            sage_scorer_free(scorer);
+            sage_database_free(db);
+        }
+    }
+
+    #[test]
+    fn header_declares_functions() {
+        let header = include_str!("../include/sage.h");
+        let source = include_str!("lib.rs");
+        let exported = source
+            .lines()
+            .filter_map(|line| {
+                line.strip_prefix("pub unsafe extern \"C\" fn ")
+                    .or_else(|| line.strip_prefix("pub extern \"C\" fn "))
+            })
+            .filter_map(|s| s.split('(').next())
+            .collect::<Vec<_>>();
+        assert!(exported.len() >= 10);
+        for name in exported {
+            assert!(
+                header.contains(&format!(" {}(", name)) || header.contains(&format!("*{}(", name)),
+                "`{}` is missing from include/sage.h",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Python bindings for Sage: build a fragment index, score spectra and
+//! estimate false discovery rates from Python, e.g. in notebooks
+//!
+//! ```python
+//! import sage_py
+//!
+//! db = sage_py.Database(open("proteins.fasta").read(), '{"enzyme": {"missed_cleavages": 2}}')
+//! scorer = sage_py.Scorer(db, precursor_tol={"ppm": (-10, 10)}, fragment_tol={"ppm": (-10, 10)})
+//! psms = [psm for scan in scans for psm in scorer.score(scan.mz, scan.intensity, scan.precursor_mz, scan.charge)]
+//! psms = sage_py.assign_q_values(db, psms, precursor_tol={"ppm": (-10, 10)})
+//! table = sage_py.to_columns(db, psms)  # e.g. pandas.DataFrame(table)
+//! ```
+
+use numpy::{IntoPyArray, PyReadonlyArray1};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use sage_core::database::{Builder, IndexedDatabase};
+use sage_core::fasta::Fasta;
+use sage_core::mass::Tolerance;
+use sage_core::ml::MlSettings;
+use sage_core::scoring::{Feature, Scorer as CoreScorer};
+use sage_core::spectrum::{Precursor, RawSpectrum, Representation, SpectrumProcessor};
+use std::collections::HashMap;
+
+/// Convert a tolerance written as in a Sage configuration file, e.g.
+/// `{"ppm": (-10, 10)}` or `{"da": (-0.5, 0.5)}`
+fn tolerance(tol: HashMap<String, (f32, f32)>) -> PyResult<Tolerance> {
+    match tol.into_iter().collect::<Vec<_>>().as_slice() {
+        [(unit, (lo, hi))] if unit == "ppm" => Ok(Tolerance::Ppm(*lo, *hi)),
+        [(unit, (lo, hi))] if unit == "da" => Ok(Tolerance::Da(*lo, *hi)),
+        _ => Err(PyValueError::new_err(
+            "tolerance must be either {\"ppm\": (lo, hi)} or {\"da\": (lo, hi)}",
+        )),
+    }
+}
+
+/// Fragment index of a digested FASTA database
+///
+/// `fasta` is the contents of a FASTA file, and `parameters` the `database`
+/// section of a Sage configuration file as a JSON string (enzyme,
+/// modifications, fragment ions, ...). Unset parameters use Sage's defaults
+#[pyclass(frozen, module = "sage_py")]
+struct Database {
+    inner: IndexedDatabase,
+    fragment_min_mz: f32,
+    fragment_max_mz: f32,
+}
+
+#[pymethods]
+impl Database {
+    #[new]
+    #[pyo3(signature = (fasta, parameters = None))]
+    fn new(py: Python<'_>, fasta: String, parameters: Option<&str>) -> PyResult<Self> {
+        let builder = match parameters {
+            Some(json) => serde_json::from_str::<Builder>(json)
+                .map_err(|e| PyValueError::new_err(format!("invalid parameters: {}", e)))?,
+            None => Builder::default(),
+        };
+        let parameters = builder.make_parameters();
+        let (fragment_min_mz, fragment_max_mz) =
+            (parameters.fragment_min_mz, parameters.fragment_max_mz);
+        let inner = py.detach(|| {
+            let fasta = Fasta::parse(
+                fasta,
+                parameters.decoy_tag.as_str(),
+                parameters.generate_decoys,
+            );
+            parameters.build(fasta)
+        });
+        Ok(Database {
+            inner,
+            fragment_min_mz,
+            fragment_max_mz,
+        })
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.peptides.len()
+    }
+
+    /// Modified sequence of a peptide, e.g. `PEPTIDEM[+15.9949]K`
+    fn peptide(&self, peptide_idx: u32) -> PyResult<String> {
+        Ok(self.get(peptide_idx)?.to_string())
+    }
+
+    /// Accessions of the proteins containing a peptide
+    fn proteins(&self, peptide_idx: u32) -> PyResult<Vec<String>> {
+        Ok(self
+            .get(peptide_idx)?
+            .proteins
+            .iter()
+            .map(|protein| protein.to_string())
+            .collect())
+    }
+
+    fn is_decoy(&self, peptide_idx: u32) -> PyResult<bool> {
+        Ok(self.get(peptide_idx)?.decoy)
+    }
+}
+
+impl Database {
+    fn get(&self, peptide_idx: u32) -> PyResult<&sage_core::peptide::Peptide> {
+        self.inner
+            .peptides
+            .get(peptide_idx as usize)
+            .ok_or_else(|| PyValueError::new_err("peptide index out of range"))
+    }
+}
+
+/// A peptide-spectrum match. Q-values are only set by [`assign_q_values`]
+#[pyclass(frozen, module = "sage_py")]
+#[derive(Clone)]
+struct Psm {
+    inner: Feature,
+}
+
+#[pymethods]
+impl Psm {
+    #[getter]
+    fn peptide_idx(&self) -> u32 {
+        self.inner.peptide_idx.0
+    }
+    #[getter]
+    fn spec_id(&self) -> &str {
+        &self.inner.spec_id
+    }
+    #[getter]
+    fn file_id(&self) -> usize {
+        self.inner.file_id
+    }
+    #[getter]
+    fn rank(&self) -> u32 {
+        self.inner.rank
+    }
+    #[getter]
+    fn is_decoy(&self) -> bool {
+        self.inner.label == -1
+    }
+    #[getter]
+    fn charge(&self) -> u8 {
+        self.inner.charge
+    }
+    #[getter]
+    fn expmass(&self) -> f32 {
+        self.inner.expmass
+    }
+    #[getter]
+    fn calcmass(&self) -> f32 {
+        self.inner.calcmass
+    }
+    #[getter]
+    fn isotope_error(&self) -> f32 {
+        self.inner.isotope_error
+    }
+    #[getter]
+    fn rt(&self) -> f32 {
+        self.inner.rt
+    }
+    #[getter]
+    fn hyperscore(&self) -> f64 {
+        self.inner.hyperscore
+    }
+    #[getter]
+    fn delta_next(&self) -> f64 {
+        self.inner.delta_next
+    }
+    #[getter]
+    fn matched_peaks(&self) -> u32 {
+        self.inner.matched_peaks
+    }
+    #[getter]
+    fn matched_intensity_pct(&self) -> f32 {
+        self.inner.matched_intensity_pct
+    }
+    #[getter]
+    fn poisson(&self) -> f64 {
+        self.inner.poisson
+    }
+    #[getter]
+    fn discriminant_score(&self) -> f32 {
+        self.inner.discriminant_score
+    }
+    #[getter]
+    fn posterior_error(&self) -> f32 {
+        self.inner.posterior_error
+    }
+    #[getter]
+    fn spectrum_q(&self) -> f32 {
+        self.inner.spectrum_q
+    }
+    #[getter]
+    fn peptide_q(&self) -> f32 {
+        self.inner.peptide_q
+    }
+    #[getter]
+    fn protein_q(&self) -> f32 {
+        self.inner.protein_q
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Psm(spec_id={:?}, peptide_idx={}, charge={}, hyperscore={:.3})",
+            self.inner.spec_id, self.inner.peptide_idx.0, self.inner.charge, self.inner.hyperscore
+        )
+    }
+}
+
+/// Scores MS2 spectra against a [`Database`]. Parameters have the same
+/// meaning and defaults as in a Sage configuration file
+#[pyclass(frozen, module = "sage_py")]
+struct Scorer {
+    db: Py<Database>,
+    precursor_tol: Tolerance,
+    fragment_tol: Tolerance,
+    precursor_charge: (u8, u8),
+    isotope_errors: (i8, i8),
+    min_matched_peaks: u16,
+    max_fragment_charge: Option<u8>,
+    report_psms: usize,
+    chimera: bool,
+    wide_window: bool,
+    deisotope: bool,
+    max_peaks: usize,
+}
+
+#[pymethods]
+impl Scorer {
+    #[new]
+    #[pyo3(signature = (
+        db,
+        precursor_tol,
+        fragment_tol,
+        precursor_charge = (2, 4),
+        isotope_errors = (0, 0),
+        min_matched_peaks = 4,
+        max_fragment_charge = None,
+        report_psms = 1,
+        chimera = false,
+        wide_window = false,
+        deisotope = true,
+        max_peaks = 150,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        db: Py<Database>,
+        precursor_tol: HashMap<String, (f32, f32)>,
+        fragment_tol: HashMap<String, (f32, f32)>,
+        precursor_charge: (u8, u8),
+        isotope_errors: (i8, i8),
+        min_matched_peaks: u16,
+        max_fragment_charge: Option<u8>,
+        report_psms: usize,
+        chimera: bool,
+        wide_window: bool,
+        deisotope: bool,
+        max_peaks: usize,
+    ) -> PyResult<Self> {
+        Ok(Scorer {
+            db,
+            precursor_tol: tolerance(precursor_tol)?,
+            fragment_tol: tolerance(fragment_tol)?,
+            precursor_charge,
+            isotope_errors,
+            min_matched_peaks,
+            max_fragment_charge,
+            report_psms,
+            chimera,
+            wide_window,
+            deisotope,
+            max_peaks,
+        })
+    }
+
+    /// Score a centroided MS2 spectrum. `mz` and `intensity` are 1-D float32
+    /// arrays; if `precursor_charge` is not known, charges in the
+    /// `precursor_charge` range of the [`Scorer`] are tried
+    #[pyo3(signature = (
+        mz,
+        intensity,
+        precursor_mz,
+        precursor_charge = None,
+        spec_id = String::new(),
+        scan_start_time = 0.0,
+        file_id = 0,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn score(
+        &self,
+        py: Python<'_>,
+        mz: PyReadonlyArray1<'_, f32>,
+        intensity: PyReadonlyArray1<'_, f32>,
+        precursor_mz: f32,
+        precursor_charge: Option<u8>,
+        spec_id: String,
+        scan_start_time: f32,
+        file_id: usize,
+    ) -> PyResult<Vec<Psm>> {
+        let mz = mz.as_slice()?.to_vec();
+        let intensity = intensity.as_slice()?.to_vec();
+        if mz.len() != intensity.len() {
+            return Err(PyValueError::new_err(
+                "`mz` and `intensity` must have the same length",
+            ));
+        }
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            id: spec_id,
+            precursors: vec![Precursor {
+                mz: precursor_mz,
+                charge: precursor_charge,
+                ..Default::default()
+            }],
+            representation: Representation::Centroid,
+            scan_start_time,
+            mz,
+            intensity,
+            ..RawSpectrum::default_with_file_id(file_id)
+        };
+
+        let db = self.db.get();
+        let features = py.detach(|| {
+            let processor = SpectrumProcessor::new(
+                self.max_peaks,
+                db.fragment_min_mz,
+                db.fragment_max_mz,
+                self.deisotope,
+            );
+            let scorer = CoreScorer {
+                min_matched_peaks: self.min_matched_peaks,
+                min_isotope_err: self.isotope_errors.0,
+                max_isotope_err: self.isotope_errors.1,
+                min_precursor_charge: self.precursor_charge.0,
+                max_precursor_charge: self.precursor_charge.1,
+                max_fragment_charge: self.max_fragment_charge,
+                min_fragment_mass: db.fragment_min_mz,
+                max_fragment_mass: db.fragment_max_mz,
+                chimera: self.chimera,
+                report_psms: self.report_psms,
+                wide_window: self.wide_window,
+                ..CoreScorer::new(&db.inner, self.precursor_tol, self.fragment_tol)
+            };
+            scorer.score(&processor.process(spectrum))
+        });
+        Ok(features.into_iter().map(|inner| Psm { inner }).collect())
+    }
+}
+
+/// Rescore PSMs with Sage's linear discriminant model, and assign spectrum-,
+/// peptide- and protein-level q-values using target-decoy competition.
+/// PSMs of all spectra should be passed together. Returns new PSMs, sorted by
+/// descending discriminant score
+#[pyfunction]
+fn assign_q_values(
+    py: Python<'_>,
+    db: Py<Database>,
+    psms: Vec<Psm>,
+    precursor_tol: HashMap<String, (f32, f32)>,
+) -> PyResult<Vec<Psm>> {
+    let precursor_tol = tolerance(precursor_tol)?;
+    let db = db.get();
+    let mut features = psms.into_iter().map(|psm| psm.inner).collect::<Vec<_>>();
+    py.detach(|| {
+        let settings = MlSettings::default();
+        if sage_core::ml::linear_discriminant::score_psms(&mut features, precursor_tol, &settings)
+            .is_none()
+        {
+            // Too few PSMs to fit a model, see `Runner::spectrum_fdr`
+            for feat in features.iter_mut() {
+                feat.discriminant_score = (-feat.poisson as f32).ln_1p() + feat.longest_y_pct / 3.0
+            }
+            sage_core::ml::pep::assign(&mut features, settings.pep);
+        }
+        features.sort_unstable_by(|a, b| b.discriminant_score.total_cmp(&a.discriminant_score));
+        sage_core::ml::qvalue::spectrum_q_value(&mut features);
+        sage_core::fdr::picked_peptide(&db.inner, &mut features);
+        sage_core::fdr::picked_protein(&db.inner, &mut features);
+    });
+    Ok(features.into_iter().map(|inner| Psm { inner }).collect())
+}
+
+/// Columns of a list of PSMs as numpy arrays (and a list of peptide
+/// sequences), e.g. for building a `pandas.DataFrame`
+#[pyfunction]
+fn to_columns<'py>(
+    py: Python<'py>,
+    db: Py<Database>,
+    psms: Vec<Psm>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let db = db.get();
+    let columns = PyDict::new(py);
+    macro_rules! column {
+        ($name:literal, $value:expr) => {
+            columns.set_item(
+                $name,
+                psms.iter()
+                    .map(|psm| $value(&psm.inner))
+                    .collect::<Vec<_>>()
+                    .into_pyarray(py),
+            )?;
+        };
+    }
+    columns.set_item(
+        "peptide",
+        psms.iter()
+            .map(|psm| db.inner[psm.inner.peptide_idx].to_string())
+            .collect::<Vec<_>>(),
+    )?;
+    columns.set_item(
+        "spec_id",
+        psms.iter()
+            .map(|psm| &*psm.inner.spec_id)
+            .collect::<Vec<_>>(),
+    )?;
+    column!("peptide_idx", |f: &Feature| f.peptide_idx.0);
+    column!("is_decoy", |f: &Feature| f.label == -1);
+    column!("file_id", |f: &Feature| f.file_id as u64);
+    column!("rank", |f: &Feature| f.rank);
+    column!("charge", |f: &Feature| f.charge);
+    column!("expmass", |f: &Feature| f.expmass);
+    column!("calcmass", |f: &Feature| f.calcmass);
+    column!("rt", |f: &Feature| f.rt);
+    column!("hyperscore", |f: &Feature| f.hyperscore);
+    column!("delta_next", |f: &Feature| f.delta_next);
+    column!("matched_peaks", |f: &Feature| f.matched_peaks);
+    column!("poisson", |f: &Feature| f.poisson);
+    column!("discriminant_score", |f: &Feature| f.discriminant_score);
+    column!("posterior_error", |f: &Feature| f.posterior_error);
+    column!("spectrum_q", |f: &Feature| f.spectrum_q);
+    column!("peptide_q", |f: &Feature| f.peptide_q);
+    column!("protein_q", |f: &Feature| f.protein_q);
+    Ok(columns)
+}
+
+#[pymodule]
+fn sage_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Database>()?;
+    m.add_class::<Scorer>()?;
+    m.add_class::<Psm>()?;
+    m.add_function(wrap_pyfunction!(assign_q_values, m)?)?;
+    m.add_function(wrap_pyfunction!(to_columns, m)?)?;
+    Ok(())
+}
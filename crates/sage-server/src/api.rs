@@ -0,0 +1,196 @@
+//! HTTP API
+//!
+//! * `GET /health`: number of running and queued jobs
+//! * `POST /jobs`: submit a search, the body being a `sage` JSON configuration
+//! * `GET /jobs`: status of all jobs
+//! * `GET /jobs/{id}`: status of a job
+//! * `DELETE /jobs/{id}`: cancel a queued or running job
+//! * `GET /jobs/{id}/log`: output of `sage`
+//! * `GET /jobs/{id}/results`: names and sizes of the result files
+//! * `GET /jobs/{id}/results/{name}`: download a result file
+//!
+//! Errors are returned as `{"error": "..."}`.
+
+use crate::jobs::{is_file_name, CancelError, Jobs, SubmitError};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+/// Requests larger than this are rejected
+pub const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Json(u16, Value),
+    /// Contents of a file, with its content type
+    File(PathBuf, &'static str),
+}
+
+fn error(status: u16, message: impl Into<String>) -> Reply {
+    Reply::Json(status, json!({ "error": message.into() }))
+}
+
+fn not_found() -> Reply {
+    error(404, "not found")
+}
+
+fn file(path: PathBuf, content_type: &'static str) -> Reply {
+    match path.is_file() {
+        true => Reply::File(path, content_type),
+        false => not_found(),
+    }
+}
+
+/// Answer the request `method url` with body `body`
+pub fn handle(jobs: &Jobs, method: &str, url: &str, body: &[u8]) -> Reply {
+    // Query strings are not used
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    let (id, rest) = match segments.as_slice() {
+        ["health"] if method == "GET" => {
+            let (running, queued) = jobs.load();
+            return Reply::Json(
+                200,
+                json!({ "status": "ok", "running": running, "queued": queued }),
+            );
+        }
+        ["jobs"] => {
+            return match method {
+                "GET" => Reply::Json(200, json!(jobs.list())),
+                "POST" => submit(jobs, body),
+                _ => error(405, "method not allowed"),
+            }
+        }
+        ["jobs", id, rest @ ..] => match id.parse::<u64>() {
+            Ok(id) if jobs.get(id).is_some() => (id, rest),
+            _ => return not_found(),
+        },
+        _ => return not_found(),
+    };
+
+    match (method, rest) {
+        ("GET", []) => Reply::Json(200, json!(jobs.get(id))),
+        ("DELETE", []) => match jobs.cancel(id) {
+            Ok(job) => Reply::Json(200, json!(job)),
+            Err(CancelError::NotFound) => not_found(),
+            Err(CancelError::Finished(state)) => {
+                error(409, format!("job {} already {}", id, state.as_str()))
+            }
+        },
+        ("GET", ["log"]) => file(jobs.log(id), "text/plain; charset=utf-8"),
+        ("GET", ["results"]) => Reply::Json(200, json!(results(jobs, id))),
+        ("GET", ["results", name]) if is_file_name(name) => {
+            file(jobs.results(id).join(name), "application/octet-stream")
+        }
+        (method, [] | ["log"] | ["results"] | ["results", _]) if method != "GET" => {
+            error(405, "method not allowed")
+        }
+        _ => not_found(),
+    }
+}
+
+fn submit(jobs: &Jobs, body: &[u8]) -> Reply {
+    let config = match serde_json::from_slice::<Value>(body) {
+        Ok(config) => config,
+        Err(e) => return error(400, format!("invalid JSON: {}", e)),
+    };
+    match jobs.submit(&config) {
+        Ok(job) => Reply::Json(201, json!(job)),
+        Err(SubmitError::Invalid(e)) => error(400, e),
+        Err(SubmitError::QueueFull) => error(503, "too many queued jobs, retry later"),
+        Err(SubmitError::Io(e)) => error(500, e),
+    }
+}
+
+/// Result files of a job, which are written once the job finishes
+fn results(jobs: &Jobs, id: u64) -> Vec<Value> {
+    let mut files = std::fs::read_dir(jobs.results(id))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+            Some((
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata.len(),
+            ))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+        .into_iter()
+        .map(|(name, size)| json!({ "name": name, "size": size }))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::jobs::Settings;
+
+    fn status(reply: &Reply) -> u16 {
+        match reply {
+            Reply::Json(status, _) => *status,
+            Reply::File(..) => 200,
+        }
+    }
+
+    #[test]
+    fn routes() {
+        let directory = std::env::temp_dir().join("sage-server-api");
+        let _ = std::fs::remove_dir_all(&directory);
+        let jobs = Jobs::new(Settings {
+            sage: "true".into(),
+            directory,
+            max_jobs: 0,
+            max_queued: 1,
+            threads: None,
+        })
+        .unwrap();
+
+        let reply = handle(&jobs, "POST", "/jobs", br#"{"mzml_paths": ["a.mzML"]}"#);
+        assert_eq!(reply, Reply::Json(201, json!(jobs.get(1))));
+        assert_eq!(status(&handle(&jobs, "POST", "/jobs", b"{}")), 503);
+        assert_eq!(status(&handle(&jobs, "POST", "/jobs", b"{")), 400);
+        assert_eq!(status(&handle(&jobs, "POST", "/jobs", b"[]")), 400);
+        assert_eq!(
+            handle(&jobs, "GET", "/health", b""),
+            Reply::Json(200, json!({ "status": "ok", "running": 0, "queued": 1 }))
+        );
+
+        assert_eq!(status(&handle(&jobs, "GET", "/jobs/1", b"")), 200);
+        assert_eq!(status(&handle(&jobs, "GET", "/jobs/2", b"")), 404);
+        assert_eq!(status(&handle(&jobs, "GET", "/jobs/x", b"")), 404);
+        assert_eq!(status(&handle(&jobs, "PUT", "/jobs/1", b"")), 405);
+        assert_eq!(status(&handle(&jobs, "GET", "/jobs/1/log", b"")), 404);
+
+        std::fs::create_dir_all(jobs.results(1)).unwrap();
+        std::fs::write(jobs.results(1).join("results.sage.tsv"), "peptide\n").unwrap();
+        assert_eq!(
+            handle(&jobs, "GET", "/jobs/1/results", b""),
+            Reply::Json(200, json!([{ "name": "results.sage.tsv", "size": 8 }]))
+        );
+        assert_eq!(
+            handle(&jobs, "GET", "/jobs/1/results/results.sage.tsv", b""),
+            Reply::File(
+                jobs.results(1).join("results.sage.tsv"),
+                "application/octet-stream"
+            )
+        );
+        assert_eq!(
+            status(&handle(&jobs, "GET", "/jobs/1/results/..", b"")),
+            404
+        );
+
+        let reply = handle(&jobs, "DELETE", "/jobs/1", b"");
+        assert!(matches!(reply, Reply::Json(200, job) if job["state"] == "cancelled"));
+        assert_eq!(status(&handle(&jobs, "DELETE", "/jobs/1", b"")), 409);
+        assert_eq!(
+            handle(&jobs, "GET", "/jobs", b""),
+            Reply::Json(200, json!(jobs.list()))
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Job queue: search jobs are run by a fixed number of workers, in order of
+//! submission, each as a `sage` process
+//!
+//! Every job has a directory below the jobs directory, containing its
+//! configuration (`config.json`), the output of `sage` (`sage.log`) and the
+//! search results (`results/`).
+
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// `sage` executable running the searches
+    pub sage: PathBuf,
+    /// Directory containing a subdirectory per job
+    pub directory: PathBuf,
+    /// Number of jobs that are run at the same time
+    pub max_jobs: usize,
+    /// Number of jobs that can wait for a worker, further jobs are rejected
+    pub max_queued: usize,
+    /// Threads used by each job, all available threads if not set
+    pub threads: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Succeeded => "succeeded",
+            State::Failed => "failed",
+            State::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Status of a job, as returned by the API. Times are in seconds since the
+/// Unix epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub state: State,
+    /// Number of jobs that will be started before this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    pub submitted: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    /// Exit code of `sage`
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    cancel: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    /// The configuration is not a JSON object
+    Invalid(String),
+    /// `max_queued` jobs are already waiting
+    QueueFull,
+    Io(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CancelError {
+    NotFound,
+    /// The job already finished
+    Finished(State),
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: BTreeMap<u64, Job>,
+    queue: VecDeque<u64>,
+    next_id: u64,
+}
+
+pub struct Jobs {
+    settings: Settings,
+    inner: Mutex<Inner>,
+    queued: Condvar,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Jobs {
+    /// Create the queue. Jobs are only run once [`Jobs::start`] is called.
+    /// Job ids continue after those of existing job directories, so that
+    /// results of a previous run are not overwritten
+    pub fn new(settings: Settings) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&settings.directory)?;
+        let mut next_id = 1;
+        for entry in std::fs::read_dir(&settings.directory)? {
+            if let Some(id) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<u64>().ok())
+            {
+                next_id = next_id.max(id + 1);
+            }
+        }
+        Ok(Jobs {
+            settings,
+            inner: Mutex::new(Inner {
+                next_id,
+                ..Default::default()
+            }),
+            queued: Condvar::new(),
+        })
+    }
+
+    /// Start `max_jobs` workers
+    pub fn start(self: &Arc<Self>) {
+        for _ in 0..self.settings.max_jobs {
+            let jobs = Arc::clone(self);
+            std::thread::spawn(move || jobs.work());
+        }
+    }
+
+    pub fn directory(&self, id: u64) -> PathBuf {
+        self.settings.directory.join(id.to_string())
+    }
+
+    pub fn results(&self, id: u64) -> PathBuf {
+        self.directory(id).join("results")
+    }
+
+    pub fn log(&self, id: u64) -> PathBuf {
+        self.directory(id).join("sage.log")
+    }
+
+    /// Queue a search with the configuration `config`, i.e. the contents of
+    /// a `sage` configuration file. Results are always written to the job
+    /// directory, overriding `output_directory`
+    pub fn submit(&self, config: &serde_json::Value) -> Result<Job, SubmitError> {
+        if !config.is_object() {
+            return Err(SubmitError::Invalid(
+                "the job must be a JSON object containing search parameters".into(),
+            ));
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.queue.len() >= self.settings.max_queued {
+            return Err(SubmitError::QueueFull);
+        }
+        let id = inner.next_id;
+        let directory = self.directory(id);
+        std::fs::create_dir_all(&directory)
+            .and_then(|_| {
+                std::fs::write(
+                    directory.join("config.json"),
+                    serde_json::to_vec_pretty(config)?,
+                )
+            })
+            .map_err(|e| SubmitError::Io(e.to_string()))?;
+
+        inner.next_id += 1;
+        inner.queue.push_back(id);
+        inner.jobs.insert(
+            id,
+            Job {
+                id,
+                state: State::Queued,
+                queue_position: None,
+                submitted: now(),
+                started: None,
+                finished: None,
+                exit_code: None,
+                error: None,
+                cancel: false,
+            },
+        );
+        info!("job {}: queued", id);
+        self.queued.notify_one();
+        Ok(Inner::status(&inner, id).expect("job was just inserted"))
+    }
+
+    pub fn get(&self, id: u64) -> Option<Job> {
+        Inner::status(&self.inner.lock().unwrap(), id)
+    }
+
+    /// All jobs, in order of submission
+    pub fn list(&self) -> Vec<Job> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .jobs
+            .keys()
+            .filter_map(|id| Inner::status(&inner, *id))
+            .collect()
+    }
+
+    /// Number of running and queued jobs
+    pub fn load(&self) -> (usize, usize) {
+        let inner = self.inner.lock().unwrap();
+        let running = inner
+            .jobs
+            .values()
+            .filter(|job| job.state == State::Running)
+            .count();
+        (running, inner.queue.len())
+    }
+
+    /// Remove a queued job from the queue, or stop a running job
+    pub fn cancel(&self, id: u64) -> Result<Job, CancelError> {
+        let mut inner = self.inner.lock().unwrap();
+        let job = inner.jobs.get_mut(&id).ok_or(CancelError::NotFound)?;
+        match job.state {
+            State::Queued => {
+                job.state = State::Cancelled;
+                job.finished = Some(now());
+                inner.queue.retain(|queued| *queued != id);
+                info!("job {}: cancelled", id);
+            }
+            // The worker stops the process
+            State::Running => job.cancel = true,
+            state => return Err(CancelError::Finished(state)),
+        }
+        Ok(Inner::status(&inner, id).expect("job exists"))
+    }
+
+    fn work(&self) {
+        loop {
+            let id = {
+                let mut inner = self.inner.lock().unwrap();
+                let id = loop {
+                    match inner.queue.pop_front() {
+                        Some(id) => break id,
+                        None => inner = self.queued.wait(inner).unwrap(),
+                    }
+                };
+                let job = inner.jobs.get_mut(&id).expect("queued jobs exist");
+                job.state = State::Running;
+                job.started = Some(now());
+                id
+            };
+            info!("job {}: running", id);
+
+            let (state, exit_code, error) = match self.run(id) {
+                Ok(Some(0)) => (State::Succeeded, Some(0), None),
+                Ok(Some(code)) => (
+                    State::Failed,
+                    Some(code),
+                    Some(format!("sage exited with code {}, see the job log", code)),
+                ),
+                Ok(None) => (State::Cancelled, None, None),
+                Err(e) => (
+                    State::Failed,
+                    None,
+                    Some(format!("failed to run sage: {}", e)),
+                ),
+            };
+            match &error {
+                Some(error) => warn!("job {}: {}", id, error),
+                None => info!("job {}: {}", id, state.as_str()),
+            }
+
+            let mut inner = self.inner.lock().unwrap();
+            let job = inner.jobs.get_mut(&id).expect("running jobs exist");
+            job.state = state;
+            job.finished = Some(now());
+            job.exit_code = exit_code;
+            job.error = error;
+        }
+    }
+
+    /// Run `sage` for job `id`, and return its exit code, or `None` if the
+    /// job was cancelled
+    fn run(&self, id: u64) -> std::io::Result<Option<i32>> {
+        let directory = self.directory(id);
+        let log = std::fs::File::create(self.log(id))?;
+        let mut command = Command::new(&self.settings.sage);
+        command
+            .arg(directory.join("config.json"))
+            .arg("--output_directory")
+            .arg(self.results(id))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        if let Some(threads) = self.settings.threads {
+            command.arg("--threads").arg(threads.to_string());
+        }
+
+        let mut child = command.spawn()?;
+        loop {
+            if let Some(status) = child.try_wait()? {
+                // No exit code if `sage` was killed by a signal
+                return Ok(Some(status.code().unwrap_or(-1)));
+            }
+            if self.cancelled(id) {
+                child.kill()?;
+                child.wait()?;
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    fn cancelled(&self, id: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.get(&id).map_or(false, |job| job.cancel)
+    }
+}
+
+impl Inner {
+    fn status(&self, id: u64) -> Option<Job> {
+        let mut job = self.jobs.get(&id)?.clone();
+        job.queue_position = self.queue.iter().position(|queued| *queued == id);
+        Some(job)
+    }
+}
+
+/// A file name in a job directory, without path components
+pub fn is_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && Path::new(name).file_name() == Some(name.as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queue(name: &str, sage: &str, max_jobs: usize) -> Arc<Jobs> {
+        let directory = std::env::temp_dir().join(format!("sage-server-{}", name));
+        let _ = std::fs::remove_dir_all(&directory);
+        Arc::new(
+            Jobs::new(Settings {
+                sage: sage.into(),
+                directory,
+                max_jobs,
+                max_queued: 2,
+                threads: None,
+            })
+            .unwrap(),
+        )
+    }
+
+    fn wait(jobs: &Jobs, id: u64) -> Job {
+        for _ in 0..100 {
+            let job = jobs.get(id).unwrap();
+            if !matches!(job.state, State::Queued | State::Running) {
+                return job;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("job {} did not finish", id);
+    }
+
+    #[test]
+    fn queue_and_cancel() {
+        let jobs = queue("queue", "true", 0);
+        let config = serde_json::json!({ "mzml_paths": ["a.mzML"] });
+        assert!(matches!(
+            jobs.submit(&serde_json::json!([])),
+            Err(SubmitError::Invalid(_))
+        ));
+
+        let a = jobs.submit(&config).unwrap();
+        let b = jobs.submit(&config).unwrap();
+        assert_eq!((a.id, b.id), (1, 2));
+        assert_eq!(b.queue_position, Some(1));
+        assert_eq!(jobs.submit(&config), Err(SubmitError::QueueFull));
+        assert!(jobs.directory(1).join("config.json").exists());
+
+        let a = jobs.cancel(1).unwrap();
+        assert_eq!(a.state, State::Cancelled);
+        assert_eq!(jobs.get(2).unwrap().queue_position, Some(0));
+        assert_eq!(jobs.cancel(1), Err(CancelError::Finished(State::Cancelled)));
+        assert_eq!(jobs.cancel(3), Err(CancelError::NotFound));
+        assert_eq!(jobs.load(), (0, 1));
+
+        // Ids continue after existing job directories
+        let restarted = Jobs::new(jobs.settings.clone()).unwrap();
+        assert_eq!(restarted.submit(&config).unwrap().id, 3);
+    }
+
+    #[test]
+    fn run_jobs() {
+        let config = serde_json::json!({});
+        let jobs = queue("run", "true", 1);
+        jobs.start();
+        let id = jobs.submit(&config).unwrap().id;
+        let job = wait(&jobs, id);
+        assert_eq!(job.state, State::Succeeded);
+        assert_eq!(job.exit_code, Some(0));
+        assert!(job.started.is_some());
+
+        let jobs = queue("fail", "false", 1);
+        jobs.start();
+        let id = jobs.submit(&config).unwrap().id;
+        let job = wait(&jobs, id);
+        assert_eq!(job.state, State::Failed);
+        assert_eq!(job.exit_code, Some(1));
+        assert!(jobs.log(id).exists());
+    }
+
+    #[test]
+    fn file_names() {
+        assert!(is_file_name("results.sage.tsv"));
+        assert!(!is_file_name("../config.json"));
+        assert!(!is_file_name("a/b"));
+        assert!(!is_file_name(".."));
+        assert!(!is_file_name(""));
+    }
+}
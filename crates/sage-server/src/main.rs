@@ -0,0 +1,162 @@
+//! `sage-server`: run Sage searches submitted over HTTP, e.g. as the backend
+//! of a search portal
+//!
+//! Jobs are queued and run by `--max-jobs` workers, each job running the
+//! `sage` executable with the submitted configuration. See [`api`] for the
+//! endpoints.
+
+mod api;
+mod jobs;
+
+use api::Reply;
+use clap::{value_parser, Arg, Command, ValueHint};
+use jobs::{Jobs, Settings};
+use log::{info, warn};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tiny_http::{Header, Response, Server};
+
+/// `sage` in the directory of this executable if it exists, as when both are
+/// installed together, otherwise `sage` on the `PATH`
+fn default_sage() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            let sage = exe.with_file_name(format!("sage{}", std::env::consts::EXE_SUFFIX));
+            sage.is_file().then_some(sage)
+        })
+        .unwrap_or_else(|| "sage".into())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn respond(jobs: &Jobs, mut request: tiny_http::Request) -> std::io::Result<()> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(api::MAX_BODY_BYTES as u64 + 1)
+        .read_to_end(&mut body)?;
+    let reply = match body.len() > api::MAX_BODY_BYTES {
+        true => Reply::Json(413, serde_json::json!({ "error": "request too large" })),
+        false => api::handle(jobs, request.method().as_str(), request.url(), &body),
+    };
+
+    match reply {
+        Reply::Json(status, value) => {
+            let body = serde_json::to_vec_pretty(&value)?;
+            request.respond(
+                Response::from_data(body)
+                    .with_status_code(status)
+                    .with_header(header("Content-Type", "application/json")),
+            )
+        }
+        Reply::File(path, content_type) => request.respond(
+            Response::from_file(std::fs::File::open(path)?)
+                .with_header(header("Content-Type", content_type)),
+        ),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::default()
+        .filter_level(log::LevelFilter::Error)
+        .parse_env(env_logger::Env::default().filter_or("SAGE_LOG", "error,sage_server=info"))
+        .init();
+
+    let matches = Command::new("sage-server")
+        .version(clap::crate_version!())
+        .about("Run Sage searches submitted over HTTP")
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .default_value("127.0.0.1:8080")
+                .help("Address to listen on"),
+        )
+        .arg(
+            Arg::new("jobs-dir")
+                .long("jobs-dir")
+                .default_value("sage-jobs")
+                .help("Directory where the configuration, log and results of each job are stored")
+                .value_hint(ValueHint::DirPath),
+        )
+        .arg(
+            Arg::new("sage")
+                .long("sage")
+                .help(
+                    "Path to the `sage` executable. Defaults to `sage` next to this \
+                     executable, or on the PATH",
+                )
+                .value_hint(ValueHint::ExecutablePath),
+        )
+        .arg(
+            Arg::new("max-jobs")
+                .long("max-jobs")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("1")
+                .help("Number of searches that run at the same time"),
+        )
+        .arg(
+            Arg::new("max-queued")
+                .long("max-queued")
+                .value_parser(value_parser!(usize))
+                .default_value("100")
+                .help("Number of searches that can wait in the queue, further submissions are rejected"),
+        )
+        .arg(
+            Arg::new("threads")
+                .long("threads")
+                .value_parser(value_parser!(u16).range(1..))
+                .help(
+                    "Threads used by each search. Defaults to the number of \
+                     available threads divided by `--max-jobs`",
+                ),
+        )
+        .get_matches();
+
+    let max_jobs = *matches.get_one::<u16>("max-jobs").expect("default") as usize;
+    let threads = match matches.get_one::<u16>("threads") {
+        Some(threads) => *threads as usize,
+        None => (num_cpus::get() / max_jobs).max(1),
+    };
+    let settings = Settings {
+        sage: matches
+            .get_one::<String>("sage")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_sage),
+        directory: matches
+            .get_one::<String>("jobs-dir")
+            .expect("default")
+            .into(),
+        max_jobs,
+        max_queued: *matches.get_one::<usize>("max-queued").expect("default"),
+        threads: Some(threads),
+    };
+
+    let listen = matches.get_one::<String>("listen").expect("default");
+    let server = Server::http(listen).map_err(|e| anyhow::anyhow!("{}: {}", listen, e))?;
+    info!(
+        "sage-server: listening on {}, running up to {} jobs of {} threads with {}, storing jobs in {}",
+        listen,
+        settings.max_jobs,
+        threads,
+        settings.sage.display(),
+        settings.directory.display()
+    );
+    let jobs = Arc::new(Jobs::new(settings)?);
+    jobs.start();
+
+    for request in server.incoming_requests() {
+        let jobs = Arc::clone(&jobs);
+        // Downloads of large result files should not block other requests
+        std::thread::spawn(move || {
+            let description = format!("{} {}", request.method(), request.url());
+            if let Err(e) = respond(&jobs, request) {
+                warn!("{}: {}", description, e);
+            }
+        });
+    }
+    Ok(())
+}
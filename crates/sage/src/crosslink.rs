@@ -0,0 +1,602 @@
+//! Cross-linked peptide (XL-MS) search
+//!
+//! A cross-linked precursor consists of two peptides (alpha and beta),
+//! covalently joined by a linker between two reactive residues. Searching all
+//! peptide pairs is quadratic in the size of the database, so pairs are built
+//! from the fragment index instead:
+//!
+//! 1) Fragment ions of either peptide that do not contain the linked residue
+//!    are unaffected by the cross-link. Each spectrum is searched against all
+//!    linkable peptides lighter than the precursor, and the best-matching
+//!    peptides are kept as alpha candidates
+//! 2) For each alpha candidate, the beta peptide mass is fixed by the
+//!    precursor mass: `precursor - alpha - linker`
+//! 3) Every linkable site combination of each pair is scored: fragments
+//!    containing the linked residue are shifted by the mass of the partner
+//!    peptide plus linker. For MS-cleavable linkers (e.g. DSSO, DSBU),
+//!    fragments carrying one of the linker remnants ("stubs") and the stub
+//!    signature ions of both intact peptides are matched as well
+//!
+//! Cross-link FDR is estimated from target-decoy (TD) and decoy-decoy (DD)
+//! pairs, as (TD - DD) / TT (Walzthoeni et al., https://pubmed.ncbi.nlm.nih.gov/23064544/)
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::enzyme::Position;
+use crate::ion_series::{IonSeries, Kind};
+use crate::mass::{Tolerance, PROTON};
+use crate::peptide::Peptide;
+use crate::scoring::{lnfact, max_fragment_charge};
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Linker {
+    pub name: String,
+    /// Mass added to the two peptides by the intact linker
+    pub mass: f32,
+    /// Residues the linker reacts with, `[` denotes the protein N-terminus
+    pub residues: String,
+    /// Masses of the remnants left on each peptide after cleavage of an
+    /// MS-cleavable linker - empty for non-cleavable linkers
+    #[serde(default)]
+    pub stubs: Vec<f32>,
+}
+
+impl Linker {
+    /// Commonly used linkers: "DSS"/"BS3" (non-cleavable), "DSSO" and "DSBU"
+    /// (MS-cleavable). All react with lysine and protein N-termini
+    pub fn preset(name: &str) -> Option<Linker> {
+        let (mass, stubs) = match name.to_ascii_uppercase().as_str() {
+            "DSS" | "BS3" => (138.06808, vec![]),
+            // Alkene, sulfenic acid and thiol remnants
+            "DSSO" => (158.00376, vec![54.01056, 103.9932, 85.98264]),
+            // Butyl amine and butyl isocyanate remnants
+            "DSBU" => (196.0848, vec![85.05276, 111.03203]),
+            _ => return None,
+        };
+        Some(Linker {
+            name: name.to_ascii_uppercase(),
+            mass,
+            residues: "K[".into(),
+            stubs,
+        })
+    }
+
+    /// Positions of `peptide` that can carry this linker. A linked residue
+    /// blocks proteolytic cleavage, so the peptide C-terminus is excluded
+    /// unless it is also the protein C-terminus
+    pub fn sites(&self, peptide: &Peptide) -> Vec<usize> {
+        let protein_nterm = matches!(peptide.position, Position::Nterm | Position::Full);
+        let protein_cterm = matches!(peptide.position, Position::Cterm | Position::Full);
+        let last = peptide.sequence.len().saturating_sub(1);
+        peptide
+            .sequence
+            .iter()
+            .enumerate()
+            .filter(|(idx, residue)| {
+                (self.residues.as_bytes().contains(residue) && (*idx < last || protein_cterm))
+                    || (*idx == 0 && protein_nterm && self.residues.contains('['))
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CrosslinkSettings {
+    pub linker: Linker,
+    /// Number of alpha peptide candidates paired with partner peptides for
+    /// each spectrum
+    pub alpha_candidates: usize,
+    /// Minimum number of matched fragments for each of the two peptides
+    pub min_matched_peaks: u16,
+}
+
+impl Default for CrosslinkSettings {
+    fn default() -> Self {
+        Self {
+            linker: Linker::preset("DSS").expect("DSS is a linker preset"),
+            alpha_candidates: 25,
+            min_matched_peaks: 2,
+        }
+    }
+}
+
+/// Target/decoy status of a cross-linked pair
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum CrosslinkLabel {
+    TargetTarget,
+    TargetDecoy,
+    DecoyDecoy,
+}
+
+impl std::fmt::Display for CrosslinkLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CrosslinkLabel::TargetTarget => f.write_str("TT"),
+            CrosslinkLabel::TargetDecoy => f.write_str("TD"),
+            CrosslinkLabel::DecoyDecoy => f.write_str("DD"),
+        }
+    }
+}
+
+/// Best-scoring cross-linked peptide pair for a spectrum
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrosslinkMatch {
+    pub spec_id: String,
+    pub file_id: usize,
+    pub rt: f32,
+    pub charge: u8,
+    pub expmass: f32,
+    pub calcmass: f32,
+    /// The peptide with more matched fragments
+    pub alpha: PeptideIx,
+    /// Linked position of the alpha peptide (0-based)
+    pub alpha_site: usize,
+    pub beta: PeptideIx,
+    pub beta_site: usize,
+    pub alpha_matched: u16,
+    pub beta_matched: u16,
+    /// X!Tandem-style hyperscore over the fragments of both peptides
+    pub score: f64,
+    pub label: CrosslinkLabel,
+    pub q_value: f32,
+}
+
+/// Matched fragments of one peptide of a pair
+#[derive(Copy, Clone, Default)]
+struct Matched {
+    n_term: u16,
+    c_term: u16,
+    summed_n_term: f32,
+    summed_c_term: f32,
+}
+
+impl Matched {
+    fn total(&self) -> u16 {
+        self.n_term + self.c_term
+    }
+}
+
+pub struct CrosslinkScorer<'db> {
+    pub db: &'db IndexedDatabase,
+    pub settings: &'db CrosslinkSettings,
+    pub precursor_tol: Tolerance,
+    pub fragment_tol: Tolerance,
+    pub min_precursor_charge: u8,
+    pub max_precursor_charge: u8,
+    pub max_fragment_charge: Option<u8>,
+    /// Linkable sites of each peptide in the database
+    sites: Vec<Vec<usize>>,
+}
+
+impl<'db> CrosslinkScorer<'db> {
+    pub fn new(
+        db: &'db IndexedDatabase,
+        settings: &'db CrosslinkSettings,
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+        precursor_charge: (u8, u8),
+        max_fragment_charge: Option<u8>,
+    ) -> Self {
+        let sites = db
+            .peptides
+            .iter()
+            .map(|peptide| settings.linker.sites(peptide))
+            .collect();
+        Self {
+            db,
+            settings,
+            precursor_tol,
+            fragment_tol,
+            min_precursor_charge: precursor_charge.0,
+            max_precursor_charge: precursor_charge.1,
+            max_fragment_charge,
+            sites,
+        }
+    }
+
+    /// Search `query` for cross-linked peptide pairs, returning the best pair
+    pub fn score(&self, query: &ProcessedSpectrum) -> Option<CrosslinkMatch> {
+        let precursor = query.precursors.first()?;
+        let charges = match precursor.charge {
+            Some(charge) => charge..=charge,
+            None => self.min_precursor_charge..=self.max_precursor_charge,
+        };
+        charges
+            .filter_map(|charge| {
+                let expmass = (precursor.mz - PROTON) * charge as f32;
+                self.score_charge(query, expmass, charge)
+            })
+            .max_by(|a, b| a.score.total_cmp(&b.score))
+    }
+
+    fn score_charge(
+        &self,
+        query: &ProcessedSpectrum,
+        expmass: f32,
+        charge: u8,
+    ) -> Option<CrosslinkMatch> {
+        let linker = &self.settings.linker;
+        let max_fragment_charge = max_fragment_charge(self.max_fragment_charge, charge);
+
+        // Alpha candidates: linkable peptides matching unshifted fragments,
+        // light enough to leave room for the linker and a partner peptide
+        let min_mass = self.db.peptides.first()?.monoisotopic;
+        let max_mass = expmass - linker.mass - min_mass;
+        if max_mass < min_mass {
+            return None;
+        }
+        let candidates = self.db.query(
+            expmass,
+            Tolerance::Da(min_mass - expmass, max_mass - expmass),
+            self.fragment_tol,
+        );
+        let mut matched: FnvHashMap<PeptideIx, u16> = FnvHashMap::default();
+        for peak in &query.peaks {
+            for fragment_charge in 1..max_fragment_charge {
+                candidates.for_each_match(peak.mass * fragment_charge as f32, |frag| {
+                    if !self.sites[frag.peptide_index.0 as usize].is_empty() {
+                        *matched.entry(frag.peptide_index).or_default() += 1;
+                    }
+                });
+            }
+        }
+        let mut alphas = matched
+            .into_iter()
+            .filter(|(_, n)| *n >= self.settings.min_matched_peaks)
+            .collect::<Vec<_>>();
+        alphas.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        alphas.truncate(self.settings.alpha_candidates);
+
+        // Pair each alpha candidate with partner peptides of the remaining mass
+        let (lo, hi) = self.precursor_tol.bounds(expmass);
+        let mut scored = FnvHashSet::default();
+        let mut best: Option<CrosslinkMatch> = None;
+        for (alpha, _) in alphas {
+            let remainder = expmass - self.db[alpha].monoisotopic - linker.mass;
+            let start = self
+                .db
+                .peptides
+                .partition_point(|p| p.monoisotopic < remainder + (lo - expmass));
+            for (offset, beta) in self.db.peptides[start..].iter().enumerate() {
+                if beta.monoisotopic > remainder + (hi - expmass) {
+                    break;
+                }
+                let beta = PeptideIx((start + offset) as u32);
+                if self.sites[beta.0 as usize].is_empty()
+                    || !scored.insert((alpha.min(beta), alpha.max(beta)))
+                {
+                    continue;
+                }
+                if let Some(pair) =
+                    self.score_pair(query, alpha, beta, expmass, charge, max_fragment_charge)
+                {
+                    if best.as_ref().map_or(true, |best| pair.score > best.score) {
+                        best = Some(pair);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// Score every site combination of a peptide pair, keeping the best
+    fn score_pair(
+        &self,
+        query: &ProcessedSpectrum,
+        a: PeptideIx,
+        b: PeptideIx,
+        expmass: f32,
+        charge: u8,
+        max_fragment_charge: u8,
+    ) -> Option<CrosslinkMatch> {
+        let linker = &self.settings.linker;
+        let (pa, pb) = (&self.db[a], &self.db[b]);
+        let mut best: Option<(f64, usize, usize, Matched, Matched)> = None;
+        for &site_a in &self.sites[a.0 as usize] {
+            let ma = self.matched(
+                query,
+                pa,
+                site_a,
+                pb.monoisotopic,
+                charge,
+                max_fragment_charge,
+            );
+            for &site_b in &self.sites[b.0 as usize] {
+                let mb = self.matched(
+                    query,
+                    pb,
+                    site_b,
+                    pa.monoisotopic,
+                    charge,
+                    max_fragment_charge,
+                );
+                if ma.total() < self.settings.min_matched_peaks
+                    || mb.total() < self.settings.min_matched_peaks
+                {
+                    continue;
+                }
+                let intensity = (ma.summed_n_term + mb.summed_n_term + 1.0) as f64
+                    * (ma.summed_c_term + mb.summed_c_term + 1.0) as f64;
+                let score =
+                    intensity.ln() + lnfact(ma.n_term + mb.n_term) + lnfact(ma.c_term + mb.c_term);
+                if best.map_or(true, |(best, ..)| score > best) {
+                    best = Some((score, site_a, site_b, ma, mb));
+                }
+            }
+        }
+
+        let (score, site_a, site_b, ma, mb) = best?;
+        let label = match (pa.decoy, pb.decoy) {
+            (false, false) => CrosslinkLabel::TargetTarget,
+            (true, true) => CrosslinkLabel::DecoyDecoy,
+            _ => CrosslinkLabel::TargetDecoy,
+        };
+        // The alpha peptide is the better-supported one
+        let ((alpha, alpha_site, ma), (beta, beta_site, mb)) = match ma.total() >= mb.total() {
+            true => ((a, site_a, ma), (b, site_b, mb)),
+            false => ((b, site_b, mb), (a, site_a, ma)),
+        };
+        Some(CrosslinkMatch {
+            spec_id: query.id.clone(),
+            file_id: query.file_id,
+            rt: query.scan_start_time,
+            charge,
+            expmass,
+            calcmass: pa.monoisotopic + pb.monoisotopic + linker.mass,
+            alpha,
+            alpha_site,
+            beta,
+            beta_site,
+            alpha_matched: ma.total(),
+            beta_matched: mb.total(),
+            score,
+            label,
+            q_value: 1.0,
+        })
+    }
+
+    /// Match the fragments of `peptide`, linked at `site` to a partner of
+    /// mass `partner`
+    fn matched(
+        &self,
+        query: &ProcessedSpectrum,
+        peptide: &Peptide,
+        site: usize,
+        partner: f32,
+        charge: u8,
+        max_fragment_charge: u8,
+    ) -> Matched {
+        let linker = &self.settings.linker;
+        let mut matched = Matched::default();
+        let mut count = |n_term: bool, mass: f32, fragment_charge: u8| {
+            let mz = mass / fragment_charge as f32;
+            if let Some(peak) = select_most_intense_peak(&query.peaks, mz, self.fragment_tol, None)
+            {
+                match n_term {
+                    true => {
+                        matched.n_term += 1;
+                        matched.summed_n_term += peak.intensity;
+                    }
+                    false => {
+                        matched.c_term += 1;
+                        matched.summed_c_term += peak.intensity;
+                    }
+                }
+                true
+            } else {
+                false
+            }
+        };
+
+        for kind in &self.db.ion_kinds {
+            let n_term = matches!(kind, Kind::A | Kind::B | Kind::C);
+            for (idx, ion) in
+                IonSeries::with_residue_masses(peptide, *kind, self.db.residue_masses).enumerate()
+            {
+                let linked = match n_term {
+                    true => idx >= site,
+                    false => idx < site,
+                };
+                for fragment_charge in 1..max_fragment_charge {
+                    if !linked {
+                        count(n_term, ion.monoisotopic_mass, fragment_charge);
+                        continue;
+                    }
+                    let intact = ion.monoisotopic_mass + partner + linker.mass;
+                    if !count(n_term, intact, fragment_charge) {
+                        for stub in &linker.stubs {
+                            if count(n_term, ion.monoisotopic_mass + stub, fragment_charge) {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Signature ions of MS-cleavable linkers: intact peptide + stub
+        for stub in &linker.stubs {
+            for stub_charge in 1..charge {
+                let mz = (peptide.monoisotopic + stub) / stub_charge as f32;
+                if let Some(peak) =
+                    select_most_intense_peak(&query.peaks, mz, self.fragment_tol, None)
+                {
+                    matched.c_term += 1;
+                    matched.summed_c_term += peak.intensity;
+                }
+            }
+        }
+        matched
+    }
+}
+
+/// Assign cross-link q-values, estimating FDR as (TD - DD) / TT. Returns the
+/// number of target-target matches at 1% FDR
+pub fn q_values(matches: &mut [CrosslinkMatch]) -> usize {
+    matches.sort_unstable_by(|a, b| b.score.total_cmp(&a.score));
+    let (mut tt, mut td, mut dd) = (0usize, 0usize, 0usize);
+    for m in matches.iter_mut() {
+        match m.label {
+            CrosslinkLabel::TargetTarget => tt += 1,
+            CrosslinkLabel::TargetDecoy => td += 1,
+            CrosslinkLabel::DecoyDecoy => dd += 1,
+        }
+        m.q_value = (td.saturating_sub(dd) as f32 / tt.max(1) as f32).min(1.0);
+    }
+    let mut q_min = 1.0f32;
+    for m in matches.iter_mut().rev() {
+        q_min = q_min.min(m.q_value);
+        m.q_value = q_min;
+    }
+    matches
+        .iter()
+        .filter(|m| m.label == CrosslinkLabel::TargetTarget && m.q_value <= 0.01)
+        .count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Builder, EnzymeBuilder};
+    use crate::fasta::Fasta;
+    use crate::spectrum::Peak;
+
+    #[test]
+    fn linker_sites() {
+        let linker = Linker::preset("dsso").unwrap();
+        assert_eq!(linker.name, "DSSO");
+        assert_eq!(linker.stubs.len(), 3);
+        assert!(Linker::preset("foo").is_none());
+
+        let peptide = Peptide {
+            decoy: false,
+            sequence: b"MKAKEK".to_vec().into(),
+            modifications: vec![0.0; 6],
+            nterm: None,
+            cterm: None,
+            monoisotopic: 0.0,
+            missed_cleavages: 0,
+            semi_enzymatic: false,
+            position: Position::Nterm,
+            proteins: Vec::new(),
+        };
+        // Protein N-terminus, internal lysines, but not the C-terminal lysine
+        assert_eq!(linker.sites(&peptide), vec![0, 1, 3]);
+    }
+
+    #[test]
+    fn crosslinked_pair() {
+        let fasta = Fasta::parse(
+            ">sp|A\nMEWKLEQSMREQALLKAQLTQLK\n>sp|B\nGAVLRVSGPDKLTPEWKDAR".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(1),
+                min_len: Some(5),
+                ..Default::default()
+            }),
+            fragment_min_mz: Some(100.0),
+            peptide_min_mass: Some(400.0),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let settings = CrosslinkSettings::default();
+        let scorer = CrosslinkScorer::new(
+            &db,
+            &settings,
+            Tolerance::Ppm(-10.0, 10.0),
+            Tolerance::Ppm(-10.0, 10.0),
+            (3, 4),
+            Some(1),
+        );
+
+        let find = |sequence: &str| {
+            let idx = db
+                .peptides
+                .iter()
+                .position(|p| p.to_string() == sequence)
+                .unwrap();
+            PeptideIx(idx as u32)
+        };
+        // EQALLKAQLTQLK (K6) linked to VSGPDKLTPEWK (K6)
+        let (a, b) = (find("EQALLKAQLTQLK"), find("VSGPDKLTPEWK"));
+        let (pa, pb) = (&db[a], &db[b]);
+        let mut peaks = Vec::new();
+        for (peptide, site, partner) in [(pa, 5, pb.monoisotopic), (pb, 5, pa.monoisotopic)] {
+            for kind in [Kind::B, Kind::Y] {
+                for (idx, ion) in IonSeries::new(peptide, kind).enumerate() {
+                    let linked = match kind {
+                        Kind::B => idx >= site,
+                        _ => idx < site,
+                    };
+                    let mass = match linked {
+                        true => ion.monoisotopic_mass + partner + settings.linker.mass,
+                        false => ion.monoisotopic_mass,
+                    };
+                    peaks.push(Peak {
+                        mass,
+                        intensity: 1.0,
+                    });
+                }
+            }
+        }
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let calcmass = pa.monoisotopic + pb.monoisotopic + settings.linker.mass;
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "xl".into(),
+            precursors: vec![crate::spectrum::Precursor {
+                mz: calcmass / 3.0 + PROTON,
+                charge: Some(3),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let xl = scorer.score(&query).unwrap();
+        let mut pair = [
+            (db[xl.alpha].to_string(), xl.alpha_site),
+            (db[xl.beta].to_string(), xl.beta_site),
+        ];
+        pair.sort();
+        assert_eq!(
+            pair,
+            [
+                ("EQALLKAQLTQLK".to_string(), 5),
+                ("VSGPDKLTPEWK".to_string(), 5)
+            ]
+        );
+        assert_eq!(xl.label, CrosslinkLabel::TargetTarget);
+        assert!((xl.calcmass - calcmass).abs() < 1E-3);
+
+        let mut matches = vec![
+            xl.clone(),
+            CrosslinkMatch {
+                score: xl.score - 1.0,
+                label: CrosslinkLabel::TargetDecoy,
+                ..xl.clone()
+            },
+            CrosslinkMatch {
+                score: xl.score - 2.0,
+                ..xl
+            },
+        ];
+        assert_eq!(q_values(&mut matches), 1);
+        assert_eq!(
+            matches.iter().map(|m| m.q_value).collect::<Vec<_>>(),
+            vec![0.0, 0.5, 0.5]
+        );
+    }
+}
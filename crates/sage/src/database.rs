@@ -1,18 +1,24 @@
-use crate::enzyme::{Enzyme, EnzymeParameters};
+use crate::enzyme::{Enzyme, EnzymeParameters, Position};
 use crate::fasta::Fasta;
-use crate::ion_series::{IonSeries, Kind};
-use crate::mass::Tolerance;
+use crate::ion_series::{Ion, IonSeries, Kind};
+use crate::mass::{ResidueMasses, Tolerance};
 use crate::modification::{validate_mods, validate_var_mods, ModificationSpecificity};
+use crate::par::prelude::*;
 use crate::peptide::Peptide;
+use crate::silac::SilacLabels;
 use dashmap::DashSet;
-use fnv::FnvBuildHasher;
-use rayon::prelude::*;
+use fnv::{FnvBuildHasher, FnvHashMap, FnvHasher};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct EnzymeBuilder {
     /// How many missed cleavages to use
     pub missed_cleavages: Option<u8>,
@@ -24,6 +30,9 @@ pub struct EnzymeBuilder {
     pub restrict: Option<char>,
     pub c_terminal: Option<bool>,
     pub semi_enzymatic: Option<bool>,
+    /// Also generate peptides with the initiator methionine removed from
+    /// protein N-termini
+    pub clip_nterm_methionine: Option<bool>,
 }
 
 impl Default for EnzymeBuilder {
@@ -36,6 +45,7 @@ impl Default for EnzymeBuilder {
             restrict: Some('P'),
             c_terminal: Some(true),
             semi_enzymatic: Some(false),
+            clip_nterm_methionine: Some(false),
         }
     }
 }
@@ -46,6 +56,7 @@ impl From<EnzymeBuilder> for EnzymeParameters {
             missed_cleavages: en.missed_cleavages.unwrap_or(1),
             min_len: en.min_len.unwrap_or(5),
             max_len: en.max_len.unwrap_or(50),
+            clip_nterm_methionine: en.clip_nterm_methionine.unwrap_or(false),
             enyzme: Enzyme::new(
                 &en.cleave_at.unwrap_or_else(|| "KR".into()),
                 en.restrict,
@@ -56,10 +67,42 @@ impl From<EnzymeBuilder> for EnzymeParameters {
     }
 }
 
+/// One or more FASTA files, given either as a single path or a list of paths
+#[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum FastaPaths {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<String> for FastaPaths {
+    fn from(path: String) -> Self {
+        FastaPaths::Single(path)
+    }
+}
+
+impl From<&str> for FastaPaths {
+    fn from(path: &str) -> Self {
+        FastaPaths::Single(path.into())
+    }
+}
+
+impl From<FastaPaths> for Vec<String> {
+    fn from(paths: FastaPaths) -> Self {
+        match paths {
+            FastaPaths::Single(path) => vec![path],
+            FastaPaths::Multiple(paths) => paths,
+        }
+    }
+}
+
 #[derive(Deserialize, Default)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 /// Parameters used for generating the fragment database
 pub struct Builder {
-    /// This parameter allows tuning of the internal search structure
+    /// This parameter allows tuning of the internal search structure. By
+    /// default, it is chosen from the fragment density and search tolerances
     pub bucket_size: Option<usize>,
 
     pub enzyme: Option<EnzymeBuilder>,
@@ -71,54 +114,83 @@ pub struct Builder {
     pub peptide_min_mass: Option<f32>,
     /// Maximum peptide monoisotopic mass that will be fragmented
     pub peptide_max_mass: Option<f32>,
-    /// Which kind of fragment ions to generate (a, b, c, x, y, z)
+    /// Which kind of fragment ions to generate (a, b, c, x, y, z), e.g.
+    /// c/z for ETD or b/y/c/z for EThcD data
+    #[serde(alias = "fragment_ions")]
     pub ion_kinds: Option<Vec<Kind>>,
     /// Minimum ion index to be generated: 1 will remove b1/y1 ions
     /// 2 will remove b1/b2/y1/y2 ions, etc
     pub min_ion_index: Option<usize>,
+    /// Override the monoisotopic mass of residues, or add non-standard residues
+    pub residue_masses: Option<HashMap<String, f32>>,
     /// Static modifications to add to matching amino acids
     pub static_mods: Option<HashMap<String, f32>>,
     /// Variable modifications to add to matching amino acids
     pub variable_mods: Option<HashMap<String, crate::modification::ValueOrVec>>,
     /// Limit number of variable modifications on a peptide
     pub max_variable_mods: Option<usize>,
+    /// SILAC labels: search every peptide containing labeled residues in
+    /// both its light and heavy form. Either a labeling scheme (e.g. "K8R10"),
+    /// or the mass offset of each labeled residue
+    pub silac_labels: Option<SilacLabels>,
     /// Use this prefix for decoy proteins
     pub decoy_tag: Option<String>,
 
     pub generate_decoys: Option<bool>,
-    /// Path to fasta database
-    pub fasta: Option<String>,
+    /// Treat isoleucine and leucine as equivalent when deduplicating peptides
+    /// and mapping them back to proteins
+    pub equate_il: Option<bool>,
+    /// Path(s) to fasta database(s) - gzipped files are decompressed. Only
+    /// used by the command line tool: [`Parameters::build`] takes an already
+    /// parsed [`Fasta`]
+    pub fasta: Option<FastaPaths>,
+    /// Limit memory used by the fragment index (in MiB) by building and searching
+    /// it in mass-partitioned slices
+    pub max_index_memory_mb: Option<usize>,
+    /// Path to a fragment index saved with [`IndexedDatabase::save`].
+    /// If the file exists it is loaded instead of digesting the FASTA file,
+    /// otherwise the index is built and saved to this path
+    pub prebuilt_index: Option<String>,
 }
 
 impl Builder {
+    /// Fill in default values for all unset parameters
     pub fn make_parameters(self) -> Parameters {
-        let bucket_size = self.bucket_size.unwrap_or(8192).next_power_of_two();
         Parameters {
-            bucket_size,
+            bucket_size: self.bucket_size.map(usize::next_power_of_two),
             fragment_min_mz: self.fragment_min_mz.unwrap_or(150.0),
             fragment_max_mz: self.fragment_max_mz.unwrap_or(2000.0),
             peptide_min_mass: self.peptide_min_mass.unwrap_or(500.0),
             peptide_max_mass: self.peptide_max_mass.unwrap_or(5000.0),
-            ion_kinds: self.ion_kinds.unwrap_or(vec![Kind::B, Kind::Y]),
+            ion_kinds: validate_ion_kinds(self.ion_kinds),
             min_ion_index: self.min_ion_index.unwrap_or(2),
             decoy_tag: self.decoy_tag.unwrap_or_else(|| "rev_".into()),
             enzyme: self.enzyme.unwrap_or_default(),
+            residue_masses: validate_residue_masses(self.residue_masses),
             static_mods: validate_mods(self.static_mods),
             variable_mods: validate_var_mods(self.variable_mods),
             max_variable_mods: self.max_variable_mods.map(|x| x.max(1)).unwrap_or(2),
+            silac_labels: validate_silac_labels(self.silac_labels),
             generate_decoys: self.generate_decoys.unwrap_or(true),
-            fasta: self.fasta.expect("A fasta file must be provided!"),
+            equate_il: self.equate_il.unwrap_or(false),
+            fasta: self.fasta.map(Into::into).unwrap_or_default(),
+            max_index_memory_mb: self.max_index_memory_mb,
+            prebuilt_index: self.prebuilt_index,
+            tolerances: None,
         }
     }
 
     pub fn update_fasta(&mut self, fasta: String) {
-        self.fasta = Some(fasta)
+        self.fasta = Some(fasta.into())
     }
 }
 
 #[derive(Serialize, Clone, Debug)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct Parameters {
-    pub bucket_size: usize,
+    /// Number of fragments per bucket, or `None` to choose it when the index
+    /// is built
+    pub bucket_size: Option<usize>,
     pub enzyme: EnzymeBuilder,
     pub fragment_min_mz: f32,
     pub fragment_max_mz: f32,
@@ -126,18 +198,145 @@ pub struct Parameters {
     pub peptide_max_mass: f32,
     pub ion_kinds: Vec<Kind>,
     pub min_ion_index: usize,
+    pub residue_masses: HashMap<char, f32>,
     pub static_mods: HashMap<ModificationSpecificity, f32>,
     pub variable_mods: HashMap<ModificationSpecificity, Vec<f32>>,
     pub max_variable_mods: usize,
+    pub silac_labels: HashMap<char, f32>,
     pub decoy_tag: String,
     pub generate_decoys: bool,
-    pub fasta: String,
+    pub equate_il: bool,
+    pub fasta: Vec<String>,
+    pub max_index_memory_mb: Option<usize>,
+    pub prebuilt_index: Option<String>,
+    /// Precursor and fragment tolerances of the search, used to choose the
+    /// bucket size if it is not set
+    #[serde(skip)]
+    pub tolerances: Option<(Tolerance, Tolerance)>,
+}
+
+/// Remove duplicate ion kinds, which would otherwise be indexed and matched
+/// twice, keeping the configured order
+fn validate_ion_kinds(ion_kinds: Option<Vec<Kind>>) -> Vec<Kind> {
+    let mut kinds: Vec<Kind> = Vec::new();
+    for kind in ion_kinds.unwrap_or_else(|| vec![Kind::B, Kind::Y]) {
+        if kinds.contains(&kind) {
+            log::warn!(
+                "fragment ion kind `{:?}` was specified more than once",
+                kind
+            );
+        } else {
+            kinds.push(kind);
+        }
+    }
+    if kinds.is_empty() {
+        log::warn!("no fragment ion kinds specified, defaulting to b/y ions");
+        kinds = vec![Kind::B, Kind::Y];
+    }
+    kinds
+}
+
+/// Parse user-supplied residue masses, skipping any invalid residues
+fn validate_residue_masses(input: Option<HashMap<String, f32>>) -> HashMap<char, f32> {
+    let mut output = HashMap::new();
+    for (residue, mass) in input.unwrap_or_default() {
+        let mut chars = residue.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_uppercase() && mass > 0.0 => {
+                output.insert(c, mass);
+            }
+            _ => log::error!(
+                "Skipping invalid residue mass: `{}` ({}). Residues must be a single uppercase letter, with a positive mass",
+                residue,
+                mass
+            ),
+        }
+    }
+    output
+}
+
+/// Resolve SILAC labels, skipping any invalid residues
+fn validate_silac_labels(input: Option<SilacLabels>) -> HashMap<char, f32> {
+    let mut output = HashMap::new();
+    for (residue, mass) in input.map(|labels| labels.labels()).unwrap_or_default() {
+        match residue.is_ascii_uppercase() && mass != 0.0 {
+            true => {
+                output.insert(residue, mass);
+            }
+            false => log::error!(
+                "Skipping invalid SILAC label: `{}` ({}). Residues must be an uppercase letter, with a non-zero mass offset",
+                residue,
+                mass
+            ),
+        }
+    }
+    output
+}
+
+/// Replace isoleucine with leucine, so that I/L-ambiguous sequences are identical
+fn equate_il(sequence: &[u8]) -> Vec<u8> {
+    sequence
+        .iter()
+        .map(|&residue| if residue == b'I' { b'L' } else { residue })
+        .collect()
+}
+
+/// Compare two sequences, treating isoleucine and leucine as equivalent
+fn cmp_equate_il(a: &[u8], b: &[u8]) -> Ordering {
+    let il = |&residue: &u8| if residue == b'I' { b'L' } else { residue };
+    a.iter().map(il).cmp(b.iter().map(il))
 }
 
 impl Parameters {
+    /// Residue masses, including any user-supplied overrides
+    pub fn masses(&self) -> ResidueMasses {
+        let mut masses = ResidueMasses::default();
+        for (residue, mass) in &self.residue_masses {
+            masses.set(*residue as u8, *mass);
+        }
+        masses
+    }
+
+    /// Stable hash of all parameters that affect the contents of the fragment
+    /// index - used to detect stale prebuilt indices
+    pub fn fingerprint(&self) -> u64 {
+        let mut static_mods = self.static_mods.iter().collect::<Vec<_>>();
+        static_mods.sort_by_key(|(spec, _)| **spec);
+        let mut variable_mods = self.variable_mods.iter().collect::<Vec<_>>();
+        variable_mods.sort_by_key(|(spec, _)| **spec);
+        let residue_masses = self.masses().overrides().collect::<Vec<_>>();
+        let mut silac_labels = self.silac_labels.iter().collect::<Vec<_>>();
+        silac_labels.sort_by_key(|(residue, _)| **residue);
+
+        let repr = format!(
+            "{} {:?} {} {} {} {} {:?} {} {:?} {:?} {:?} {} {:?} {} {} {} {:?}",
+            self.bucket_size.unwrap_or(0),
+            self.enzyme,
+            self.fragment_min_mz,
+            self.fragment_max_mz,
+            self.peptide_min_mass,
+            self.peptide_max_mass,
+            self.ion_kinds,
+            self.min_ion_index,
+            residue_masses,
+            static_mods,
+            variable_mods,
+            self.max_variable_mods,
+            silac_labels,
+            self.decoy_tag,
+            self.generate_decoys,
+            self.equate_il,
+            self.fasta,
+        );
+        let mut hasher = FnvHasher::default();
+        repr.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn digest(&self, fasta: &Fasta) -> Vec<Peptide> {
         log::trace!("digesting fasta");
         let enzyme = self.enzyme.clone().into();
+        let masses = self.masses();
         // Generate all tryptic peptide sequences, including reversed (decoy)
         // and missed cleavages, if applicable.
         let digests = fasta.digest(&enzyme);
@@ -152,19 +351,28 @@ impl Parameters {
         digests
             .par_iter()
             .filter(|digest| !digest.decoy)
-            .for_each(|digest| {
-                targets.insert(digest.sequence.clone().into_bytes());
+            .for_each(|digest| match self.equate_il {
+                true => {
+                    targets.insert(equate_il(digest.sequence.as_bytes()));
+                }
+                false => {
+                    targets.insert(digest.sequence.clone().into_bytes());
+                }
             });
 
         log::trace!("modifying peptides");
         let mut target_decoys = digests
             .into_par_iter()
-            .map(Peptide::try_from)
+            .map(|digest| Peptide::from_digest(digest, &masses))
             .filter_map(Result::ok)
             .flat_map_iter(|peptide| {
                 peptide
                     .apply(&mods, &self.static_mods, self.max_variable_mods)
                     .into_iter()
+                    .flat_map(|peptide| {
+                        let heavy = crate::silac::heavy(&peptide, &self.silac_labels);
+                        std::iter::once(peptide).chain(heavy)
+                    })
                     .filter(|peptide| {
                         peptide.monoisotopic >= self.peptide_min_mass
                             && peptide.monoisotopic <= self.peptide_max_mass
@@ -176,20 +384,41 @@ impl Parameters {
                             vec![peptide].into_iter()
                         }
                     })
-                    .filter(|peptide| !peptide.decoy || !targets.contains(&(peptide.sequence[..])))
+                    .filter(|peptide| match (peptide.decoy, self.equate_il) {
+                        (false, _) => true,
+                        (true, false) => !targets.contains(&(peptide.sequence[..])),
+                        (true, true) => !targets.contains(&equate_il(&peptide.sequence)),
+                    })
             })
             .collect::<Vec<_>>();
 
         log::trace!("sorting and deduplicating peptides");
 
         // This is equivalent to a stable sort
+        // If I/L are equivalent, ambiguous sequences must be adjacent after sorting:
+        // identically modified forms are grouped before the raw sequence is compared
         target_decoys.par_sort_unstable_by(|a, b| {
             a.monoisotopic
                 .total_cmp(&b.monoisotopic)
+                .then_with(|| match self.equate_il {
+                    true => cmp_equate_il(&a.sequence, &b.sequence)
+                        .then_with(|| {
+                            a.modifications
+                                .partial_cmp(&b.modifications)
+                                .unwrap_or(Ordering::Equal)
+                        })
+                        .then_with(|| a.nterm.partial_cmp(&b.nterm).unwrap_or(Ordering::Equal))
+                        .then_with(|| a.cterm.partial_cmp(&b.cterm).unwrap_or(Ordering::Equal)),
+                    false => Ordering::Equal,
+                })
                 .then_with(|| a.initial_sort(b))
         });
         target_decoys.dedup_by(|remove, keep| {
-            if remove.sequence == keep.sequence
+            let same_sequence = match self.equate_il {
+                true => cmp_equate_il(&remove.sequence, &keep.sequence) == Ordering::Equal,
+                false => remove.sequence == keep.sequence,
+            };
+            if same_sequence
                 && remove.modifications == keep.modifications
                 && remove.nterm == keep.nterm
                 && remove.cterm == keep.cterm
@@ -201,9 +430,11 @@ impl Parameters {
             }
         });
 
-        target_decoys
-            .par_iter_mut()
-            .for_each(|peptide| peptide.proteins.sort_unstable());
+        // The same protein may be present in more than one FASTA file
+        target_decoys.par_iter_mut().for_each(|peptide| {
+            peptide.proteins.sort_unstable();
+            peptide.proteins.dedup();
+        });
 
         target_decoys
     }
@@ -211,7 +442,64 @@ impl Parameters {
     // pub fn build(self) -> Result<IndexedDatabase, Box<dyn std::error::Error + Send + Sync + 'static>> {
     pub fn build(self, fasta: Fasta) -> IndexedDatabase {
         let target_decoys = self.digest(&fasta);
+        self.build_from_peptides(target_decoys)
+    }
+
+    /// Upper bound on the number of theoretical fragments of `peptide` in
+    /// an [`IndexedDatabase`], before fragment m/z bounds are applied
+    fn estimated_fragments(&self, peptide: &Peptide) -> usize {
+        self.ion_kinds.len() * peptide.sequence.len().saturating_sub(1)
+    }
+
+    /// Estimated number of bytes used by `peptide` and its theoretical
+    /// fragments in an [`IndexedDatabase`]
+    fn estimated_size(&self, peptide: &Peptide) -> usize {
+        std::mem::size_of::<Peptide>()
+            + peptide.sequence.len()
+            + peptide.modifications.len() * std::mem::size_of::<f32>()
+            + peptide.proteins.len() * std::mem::size_of::<Arc<String>>()
+            + self.estimated_fragments(peptide) * std::mem::size_of::<Theoretical>()
+    }
+
+    /// Estimated number of theoretical fragments, and bytes of memory, of the
+    /// [`IndexedDatabase`] built from `peptides`, without building it
+    pub fn estimate_index(&self, peptides: &[Peptide]) -> (usize, usize) {
+        peptides.iter().fold((0, 0), |(fragments, bytes), peptide| {
+            (
+                fragments + self.estimated_fragments(peptide),
+                bytes + self.estimated_size(peptide),
+            )
+        })
+    }
+
+    /// Split `peptides` (as returned by [`Parameters::digest`], sorted by
+    /// monoisotopic mass) into contiguous mass-partitioned ranges, such that
+    /// the fragment index for each range is estimated to need at most
+    /// `max_bytes` of memory. Every range contains at least one peptide.
+    pub fn partition(&self, peptides: &[Peptide], max_bytes: usize) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        let mut size = 0;
+        for (idx, peptide) in peptides.iter().enumerate() {
+            let bytes = self.estimated_size(peptide);
+            if size + bytes > max_bytes && idx > start {
+                ranges.push(start..idx);
+                start = idx;
+                size = 0;
+            }
+            size += bytes;
+        }
+        if start < peptides.len() {
+            ranges.push(start..peptides.len());
+        }
+        ranges
+    }
+
+    /// Build an [`IndexedDatabase`] from already digested peptides, which
+    /// must be sorted by monoisotopic mass (see [`Parameters::digest`])
+    pub fn build_from_peptides(&self, target_decoys: Vec<Peptide>) -> IndexedDatabase {
         log::trace!("generating fragments");
+        let residue_masses = self.masses();
 
         // Finally, perform in silico digest for our target sequences
         // Note that multiple charge states are actually handled by
@@ -225,7 +513,9 @@ impl Parameters {
                 // theoretical fragments are within the search space
                 self.ion_kinds
                     .iter()
-                    .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate())
+                    .flat_map(|kind| {
+                        IonSeries::with_residue_masses(peptide, *kind, residue_masses).enumerate()
+                    })
                     .filter(|(ion_idx, ion)| {
                         // Don't store b1, b2, y1, y2 ions for preliminary scoring
                         let ion_idx_filter = match ion.kind {
@@ -284,8 +574,19 @@ impl Parameters {
         // and within Bucket 1, we can perform another binary search to find fragments
         // matching our desired precursor m/z tolerance
 
+        let bucket_size = self.bucket_size.unwrap_or_else(|| {
+            let bucket_size = match self.tolerances {
+                Some((precursor_tol, fragment_tol)) => {
+                    choose_bucket_size(&target_decoys, &fragments, precursor_tol, fragment_tol)
+                }
+                None => DEFAULT_BUCKET_SIZE,
+            };
+            log::info!("using a fragment index bucket size of {}", bucket_size);
+            bucket_size
+        });
+
         let min_value = fragments
-            .par_chunks_mut(self.bucket_size)
+            .par_chunks_mut(bucket_size)
             .map(|chunk| {
                 // There should always be at least one item in the chunk!
                 //  we know the chunk is already sorted by fragment_mz too, so this is minimum value
@@ -301,20 +602,74 @@ impl Parameters {
             .flat_map(|(a, b)| b.iter().map(|b| (*a, *b)))
             .collect::<Vec<(ModificationSpecificity, f32)>>();
 
+        let fingerprint = self.fingerprint();
         IndexedDatabase {
             peptides: target_decoys,
-            fragments,
+            fragments: fragments.into(),
             min_value,
-            bucket_size: self.bucket_size,
-            ion_kinds: self.ion_kinds,
+            bucket_size,
+            ion_kinds: self.ion_kinds.clone(),
             generate_decoys: self.generate_decoys,
             potential_mods,
-            decoy_tag: self.decoy_tag,
+            residue_masses,
+            decoy_tag: self.decoy_tag.clone(),
+            fingerprint,
         }
     }
 }
 
-#[derive(Hash, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+/// Bucket size used when it is not set, and there are no search tolerances
+/// to choose one from
+const DEFAULT_BUCKET_SIZE: usize = 8192;
+
+/// Choose the bucket size minimizing the estimated cost of matching a single
+/// peak against the index.
+///
+/// Each bucket overlapping a peak's fragment tolerance window is binary searched
+/// for candidate precursors, and every fragment of those buckets within the
+/// precursor tolerance is then scored. Small buckets make the latter cheap,
+/// which matters when many peptides fall within the precursor tolerance (open
+/// searches), while large buckets require fewer binary searches when a window
+/// spans many fragments (wide fragment tolerances or dense databases)
+fn choose_bucket_size(
+    peptides: &[Peptide],
+    fragments: &[Theoretical],
+    precursor_tol: Tolerance,
+    fragment_tol: Tolerance,
+) -> usize {
+    if peptides.is_empty() || fragments.is_empty() {
+        return DEFAULT_BUCKET_SIZE;
+    }
+
+    // Number of fragments within the tolerance of a typical peak...
+    let mz = fragments[fragments.len() / 2].fragment_mz;
+    let (lo, hi) = fragment_tol.bounds(mz);
+    let window = fragments.partition_point(|frag| frag.fragment_mz <= hi)
+        - fragments.partition_point(|frag| frag.fragment_mz < lo);
+
+    // ... and fraction of peptides within the tolerance of a typical precursor
+    let mass = peptides[peptides.len() / 2].monoisotopic;
+    let (lo, hi) = precursor_tol.bounds(mass);
+    let candidates = peptides.partition_point(|peptide| peptide.monoisotopic <= hi)
+        - peptides.partition_point(|peptide| peptide.monoisotopic < lo);
+    let fraction = candidates as f64 / peptides.len() as f64;
+
+    // Relative cost of a binary search step, compared to scoring a fragment
+    const SEARCH_COST: f64 = 100.0;
+    let cost = |bucket_size: usize| {
+        let buckets = window as f64 / bucket_size as f64 + 1.0;
+        let scored = fraction * (window + bucket_size) as f64;
+        SEARCH_COST * buckets * (bucket_size as f64).log2() + scored
+    };
+
+    (8..=16)
+        .map(|shift| 1usize << shift)
+        .min_by(|a, b| cost(*a).total_cmp(&cost(*b)))
+        .unwrap_or(DEFAULT_BUCKET_SIZE)
+        .min(fragments.len().next_power_of_two())
+}
+
+#[derive(Hash, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 #[repr(transparent)]
 pub struct PeptideIx(pub u32);
 
@@ -326,23 +681,112 @@ impl Default for PeptideIx {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+// Fixed layout, so that fragments can be loaded into SIMD registers
+#[repr(C)]
 pub struct Theoretical {
     pub peptide_index: PeptideIx,
     pub fragment_mz: f32,
 }
 
+/// Theoretical fragments of an [`IndexedDatabase`]: either built in memory, or
+/// memory-mapped from an index file (see [`IndexedDatabase::map`]), in which
+/// case concurrent processes share a single copy in the page cache
+pub struct Fragments(FragmentStorage);
+
+enum FragmentStorage {
+    Owned(Vec<Theoretical>),
+    #[cfg(all(feature = "mmap", target_endian = "little"))]
+    Mapped {
+        map: memmap2::Mmap,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Fragments {
+    /// Are the fragments memory-mapped from an index file?
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.0, FragmentStorage::Owned(_))
+    }
+}
+
+impl From<Vec<Theoretical>> for Fragments {
+    fn from(fragments: Vec<Theoretical>) -> Self {
+        Fragments(FragmentStorage::Owned(fragments))
+    }
+}
+
+impl std::ops::Deref for Fragments {
+    type Target = [Theoretical];
+
+    fn deref(&self) -> &[Theoretical] {
+        match &self.0 {
+            FragmentStorage::Owned(fragments) => fragments,
+            #[cfg(all(feature = "mmap", target_endian = "little"))]
+            FragmentStorage::Mapped { map, offset, len } => {
+                // SAFETY: `IndexedDatabase::map` checked that the map holds
+                // `len` fragments at `offset`, a multiple of 8 bytes from the
+                // (page-aligned) start of the map. Fragments are `#[repr(C)]`
+                // pairs of little-endian u32 and f32, valid for any bytes
+                unsafe {
+                    std::slice::from_raw_parts(
+                        map.as_ptr().add(*offset) as *const Theoretical,
+                        *len,
+                    )
+                }
+            }
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Fragments {
+    type Item = &'a Theoretical;
+    type IntoIter = std::slice::Iter<'a, Theoretical>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl PartialEq for Fragments {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl std::fmt::Debug for Fragments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fragments")
+            .field("len", &self.len())
+            .field("mapped", &self.is_mapped())
+            .finish()
+    }
+}
+
 pub struct IndexedDatabase {
     pub peptides: Vec<Peptide>,
-    pub fragments: Vec<Theoretical>,
+    pub fragments: Fragments,
     pub ion_kinds: Vec<Kind>,
     pub min_value: Vec<f32>,
     /// Keep a list of potential (AA, mass) modifications for RT prediction
     pub potential_mods: Vec<(ModificationSpecificity, f32)>,
+    /// Residue masses used to generate peptide and fragment masses
+    pub residue_masses: ResidueMasses,
     pub bucket_size: usize,
     pub generate_decoys: bool,
     pub decoy_tag: String,
+    /// [`Parameters::fingerprint`] of the parameters used to build this database
+    pub fingerprint: u64,
 }
 
+/// Magic bytes & format version for [`IndexedDatabase::save`]
+const INDEX_MAGIC: &[u8; 8] = b"SAGEIDX\0";
+const INDEX_VERSION: u32 = 3;
+/// Fragments are stored last, at an offset that is a multiple of this many
+/// bytes, so that they can be memory-mapped
+const FRAGMENT_ALIGNMENT: u64 = 8;
+const _: () = assert!(std::mem::size_of::<Theoretical>() == 8);
+
 impl IndexedDatabase {
     /// Create a new [`IndexedQuery`] for a specific [`ProcessedSpectrum`]
     ///
@@ -363,16 +807,67 @@ impl IndexedDatabase {
             precursor_hi,
         );
 
+        // Exact range of peptides within the precursor tolerance, so that
+        // fragments can be filtered without looking up their peptide's mass
+        let peptide_lo = self
+            .peptides
+            .partition_point(|p| p.monoisotopic < precursor_lo) as u32;
+        let peptide_hi = self
+            .peptides
+            .partition_point(|p| p.monoisotopic <= precursor_hi) as u32;
         IndexedQuery {
             db: self,
-            precursor_mass,
-            precursor_tol,
             fragment_tol,
             pre_idx_lo,
             pre_idx_hi,
+            peptide_lo,
+            peptide_hi,
         }
     }
 
+    /// Is there at least one peptide within `precursor_tol` of `precursor_mass`?
+    ///
+    /// This only searches the (mass-sorted) peptide list, and is much cheaper
+    /// than [`IndexedDatabase::query`] followed by fragment matching
+    pub fn contains_precursor(&self, precursor_mass: f32, precursor_tol: Tolerance) -> bool {
+        let (precursor_lo, precursor_hi) = precursor_tol.bounds(precursor_mass);
+        let idx = self
+            .peptides
+            .partition_point(|p| p.monoisotopic < precursor_lo);
+        self.peptides
+            .get(idx)
+            .map(|p| p.monoisotopic <= precursor_hi)
+            .unwrap_or(false)
+    }
+
+    /// Peptides with a monoisotopic mass within `precursor_tol` of
+    /// `precursor_mass`, in order of increasing mass
+    pub fn candidates(
+        &self,
+        precursor_mass: f32,
+        precursor_tol: Tolerance,
+    ) -> impl Iterator<Item = (PeptideIx, &Peptide)> + '_ {
+        let (precursor_lo, precursor_hi) = precursor_tol.bounds(precursor_mass);
+        let start = self
+            .peptides
+            .partition_point(|p| p.monoisotopic < precursor_lo);
+        self.peptides[start..]
+            .iter()
+            .take_while(move |p| p.monoisotopic <= precursor_hi)
+            .enumerate()
+            .map(move |(idx, p)| (PeptideIx((start + idx) as u32), p))
+    }
+
+    /// All theoretical fragments of a peptide, for each of the database's ion
+    /// kinds. The fragment index itself only stores the fragments within the
+    /// fragment m/z range, excluding the first `min_ion_index` ions
+    pub fn peptide_fragments(&self, peptide: PeptideIx) -> impl Iterator<Item = Ion> + '_ {
+        let peptide = &self[peptide];
+        self.ion_kinds.iter().flat_map(move |kind| {
+            IonSeries::with_residue_masses(peptide, *kind, self.residue_masses)
+        })
+    }
+
     pub fn size(&self) -> usize {
         self.fragments.len()
     }
@@ -407,6 +902,408 @@ impl IndexedDatabase {
     }
 }
 
+impl IndexedDatabase {
+    /// Write the fragment index to `path` using a versioned binary format,
+    /// so that it can be reloaded with [`IndexedDatabase::load`] or
+    /// [`IndexedDatabase::map`]
+    ///
+    /// The index is written to a temporary file that then replaces `path`, so
+    /// that processes which have mapped a previous index are not affected
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        let result = self
+            .write(std::fs::File::create(&tmp)?)
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    fn write(&self, file: std::fs::File) -> std::io::Result<()> {
+        let mut wtr = IndexWriter(std::io::BufWriter::new(file));
+        wtr.0.write_all(INDEX_MAGIC)?;
+        wtr.u32(INDEX_VERSION)?;
+        wtr.u64(self.fingerprint)?;
+        wtr.u64(self.bucket_size as u64)?;
+        wtr.bool(self.generate_decoys)?;
+        wtr.bytes(self.decoy_tag.as_bytes())?;
+
+        wtr.u64(self.ion_kinds.len() as u64)?;
+        for kind in &self.ion_kinds {
+            wtr.u8(*kind as u8)?;
+        }
+
+        wtr.u64(self.potential_mods.len() as u64)?;
+        for (spec, mass) in &self.potential_mods {
+            let (tag, residue) = match spec {
+                ModificationSpecificity::PeptideN(r) => (0, *r),
+                ModificationSpecificity::PeptideC(r) => (1, *r),
+                ModificationSpecificity::ProteinN(r) => (2, *r),
+                ModificationSpecificity::ProteinC(r) => (3, *r),
+                ModificationSpecificity::Residue(r) => (4, Some(*r)),
+            };
+            wtr.u8(tag)?;
+            wtr.option_u8(residue)?;
+            wtr.f32(*mass)?;
+        }
+
+        let residue_masses = self.residue_masses.overrides().collect::<Vec<_>>();
+        wtr.u64(residue_masses.len() as u64)?;
+        for (residue, mass) in residue_masses {
+            wtr.u8(residue)?;
+            wtr.f32(mass)?;
+        }
+
+        wtr.u64(self.min_value.len() as u64)?;
+        for min in &self.min_value {
+            wtr.f32(*min)?;
+        }
+
+        // Protein accessions are shared between many peptides, so they are
+        // stored once and referenced by index
+        let mut protein_ids: FnvHashMap<&str, u32> = FnvHashMap::default();
+        let mut proteins = Vec::new();
+        for peptide in &self.peptides {
+            for protein in &peptide.proteins {
+                protein_ids.entry(protein.as_str()).or_insert_with(|| {
+                    proteins.push(protein.as_str());
+                    proteins.len() as u32 - 1
+                });
+            }
+        }
+        wtr.u64(proteins.len() as u64)?;
+        for protein in proteins {
+            wtr.bytes(protein.as_bytes())?;
+        }
+
+        wtr.u64(self.peptides.len() as u64)?;
+        for peptide in &self.peptides {
+            wtr.bool(peptide.decoy)?;
+            wtr.bytes(&peptide.sequence)?;
+            wtr.u64(peptide.modifications.len() as u64)?;
+            for m in &peptide.modifications {
+                wtr.f32(*m)?;
+            }
+            wtr.option_f32(peptide.nterm)?;
+            wtr.option_f32(peptide.cterm)?;
+            wtr.f32(peptide.monoisotopic)?;
+            wtr.u8(peptide.missed_cleavages)?;
+            wtr.bool(peptide.semi_enzymatic)?;
+            wtr.u8(peptide.position as u8)?;
+            wtr.u64(peptide.proteins.len() as u64)?;
+            for protein in &peptide.proteins {
+                wtr.u32(protein_ids[protein.as_str()])?;
+            }
+        }
+
+        wtr.u64(self.fragments.len() as u64)?;
+        let position = wtr.0.stream_position()?;
+        for _ in position..fragment_offset(position) {
+            wtr.u8(0)?;
+        }
+        for fragment in &self.fragments {
+            wtr.u32(fragment.peptide_index.0)?;
+            wtr.f32(fragment.fragment_mz)?;
+        }
+        wtr.0.flush()
+    }
+
+    /// Read a fragment index previously written by [`IndexedDatabase::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::read(path.as_ref(), false)
+    }
+
+    /// Read a fragment index previously written by [`IndexedDatabase::save`],
+    /// memory-mapping its fragments - the bulk of the index - rather than
+    /// reading them into memory. Concurrent (or repeated) searches against
+    /// the same index then share a single copy of the fragments in the page
+    /// cache.
+    ///
+    /// The index file must not be modified while it is mapped ([`save`]
+    /// replaces the file instead). Without the `mmap` feature, or on
+    /// big-endian targets, this is the same as [`IndexedDatabase::load`]
+    ///
+    /// [`save`]: IndexedDatabase::save
+    pub fn map<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::read(path.as_ref(), true)
+    }
+
+    #[cfg_attr(
+        not(all(feature = "mmap", target_endian = "little")),
+        allow(unused_variables)
+    )]
+    fn read(path: &Path, map: bool) -> std::io::Result<Self> {
+        let mut rdr = IndexReader(std::io::BufReader::new(std::fs::File::open(path)?));
+        let mut magic = [0u8; 8];
+        rdr.0.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(invalid_data("not a sage fragment index"));
+        }
+        let version = rdr.u32()?;
+        if version != INDEX_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported fragment index version {} (expected {})",
+                version, INDEX_VERSION
+            )));
+        }
+        let fingerprint = rdr.u64()?;
+        let bucket_size = rdr.u64()? as usize;
+        let generate_decoys = rdr.bool()?;
+        let decoy_tag = rdr.string()?;
+
+        let ion_kinds = (0..rdr.u64()?)
+            .map(|_| match rdr.u8()? {
+                0 => Ok(Kind::A),
+                1 => Ok(Kind::B),
+                2 => Ok(Kind::C),
+                3 => Ok(Kind::X),
+                4 => Ok(Kind::Y),
+                5 => Ok(Kind::Z),
+                x => Err(invalid_data(format!("invalid ion kind: {}", x))),
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let potential_mods = (0..rdr.u64()?)
+            .map(|_| {
+                let tag = rdr.u8()?;
+                let residue = rdr.option_u8()?;
+                let spec = match (tag, residue) {
+                    (0, r) => ModificationSpecificity::PeptideN(r),
+                    (1, r) => ModificationSpecificity::PeptideC(r),
+                    (2, r) => ModificationSpecificity::ProteinN(r),
+                    (3, r) => ModificationSpecificity::ProteinC(r),
+                    (4, Some(r)) => ModificationSpecificity::Residue(r),
+                    _ => return Err(invalid_data("invalid modification specificity")),
+                };
+                Ok((spec, rdr.f32()?))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let mut residue_masses = ResidueMasses::default();
+        for _ in 0..rdr.u64()? {
+            let residue = rdr.u8()?;
+            if !residue_masses.set(residue, rdr.f32()?) {
+                return Err(invalid_data("invalid residue mass"));
+            }
+        }
+
+        let min_value = (0..rdr.u64()?)
+            .map(|_| rdr.f32())
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let proteins = (0..rdr.u64()?)
+            .map(|_| rdr.string().map(Arc::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let peptides = (0..rdr.u64()?)
+            .map(|_| {
+                let decoy = rdr.bool()?;
+                let sequence = rdr.bytes()?.into();
+                let modifications = (0..rdr.u64()?)
+                    .map(|_| rdr.f32())
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                let nterm = rdr.option_f32()?;
+                let cterm = rdr.option_f32()?;
+                let monoisotopic = rdr.f32()?;
+                let missed_cleavages = rdr.u8()?;
+                let semi_enzymatic = rdr.bool()?;
+                let position = match rdr.u8()? {
+                    0 => Position::Nterm,
+                    1 => Position::Cterm,
+                    2 => Position::Full,
+                    3 => Position::Internal,
+                    x => return Err(invalid_data(format!("invalid peptide position: {}", x))),
+                };
+                let proteins = (0..rdr.u64()?)
+                    .map(|_| {
+                        proteins
+                            .get(rdr.u32()? as usize)
+                            .cloned()
+                            .ok_or_else(|| invalid_data("invalid protein index"))
+                    })
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                Ok(Peptide {
+                    decoy,
+                    sequence,
+                    modifications,
+                    nterm,
+                    cterm,
+                    monoisotopic,
+                    missed_cleavages,
+                    semi_enzymatic,
+                    position,
+                    proteins,
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let len = rdr.u64()?;
+        let position = rdr.0.stream_position()?;
+        let offset = fragment_offset(position);
+        for _ in position..offset {
+            rdr.u8()?;
+        }
+        let fragments: Fragments = match map {
+            #[cfg(all(feature = "mmap", target_endian = "little"))]
+            true => {
+                // SAFETY: undefined behavior if the file is modified while
+                // mapped, see the documentation of `map`
+                let map = unsafe { memmap2::Mmap::map(rdr.0.get_ref())? };
+                let end = len
+                    .checked_mul(FRAGMENT_ALIGNMENT)
+                    .and_then(|bytes| bytes.checked_add(offset));
+                if end.map_or(true, |end| end > map.len() as u64) {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                Fragments(FragmentStorage::Mapped {
+                    map,
+                    offset: offset as usize,
+                    len: len as usize,
+                })
+            }
+            _ => (0..len)
+                .map(|_| {
+                    Ok(Theoretical {
+                        peptide_index: PeptideIx(rdr.u32()?),
+                        fragment_mz: rdr.f32()?,
+                    })
+                })
+                .collect::<std::io::Result<Vec<_>>>()?
+                .into(),
+        };
+
+        if fragments
+            .iter()
+            .any(|frag| frag.peptide_index.0 as usize >= peptides.len())
+        {
+            return Err(invalid_data("fragment references a missing peptide"));
+        }
+
+        Ok(IndexedDatabase {
+            peptides,
+            fragments,
+            ion_kinds,
+            min_value,
+            potential_mods,
+            residue_masses,
+            bucket_size,
+            generate_decoys,
+            decoy_tag,
+            fingerprint,
+        })
+    }
+}
+
+/// Offset of the fragments of an index file, the first multiple of
+/// [`FRAGMENT_ALIGNMENT`] at or after `position`
+fn fragment_offset(position: u64) -> u64 {
+    (position + FRAGMENT_ALIGNMENT - 1) / FRAGMENT_ALIGNMENT * FRAGMENT_ALIGNMENT
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+/// Little-endian writer for the binary fragment index format
+struct IndexWriter<W: Write>(W);
+
+impl<W: Write> IndexWriter<W> {
+    fn u8(&mut self, value: u8) -> std::io::Result<()> {
+        self.0.write_all(&[value])
+    }
+
+    fn bool(&mut self, value: bool) -> std::io::Result<()> {
+        self.u8(value as u8)
+    }
+
+    fn u32(&mut self, value: u32) -> std::io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> std::io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn f32(&mut self, value: f32) -> std::io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn option_u8(&mut self, value: Option<u8>) -> std::io::Result<()> {
+        self.bool(value.is_some())?;
+        self.u8(value.unwrap_or_default())
+    }
+
+    fn option_f32(&mut self, value: Option<f32>) -> std::io::Result<()> {
+        self.bool(value.is_some())?;
+        self.f32(value.unwrap_or_default())
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> std::io::Result<()> {
+        self.u64(value.len() as u64)?;
+        self.0.write_all(value)
+    }
+}
+
+/// Little-endian reader for the binary fragment index format
+struct IndexReader<R: Read>(R);
+
+impl<R: Read> IndexReader<R> {
+    fn array<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> std::io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> std::io::Result<f32> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn option_u8(&mut self) -> std::io::Result<Option<u8>> {
+        let some = self.bool()?;
+        let value = self.u8()?;
+        Ok(some.then_some(value))
+    }
+
+    fn option_f32(&mut self) -> std::io::Result<Option<f32>> {
+        let some = self.bool()?;
+        let value = self.f32()?;
+        Ok(some.then_some(value))
+    }
+
+    fn bytes(&mut self) -> std::io::Result<Vec<u8>> {
+        let len = self.u64()? as usize;
+        let mut buf = Vec::new();
+        (&mut self.0).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(invalid_data)
+    }
+}
+
 impl std::ops::Index<PeptideIx> for IndexedDatabase {
     type Output = Peptide;
 
@@ -417,40 +1314,171 @@ impl std::ops::Index<PeptideIx> for IndexedDatabase {
 
 pub struct IndexedQuery<'d> {
     db: &'d IndexedDatabase,
-    precursor_mass: f32,
-    precursor_tol: Tolerance,
     fragment_tol: Tolerance,
     pub pre_idx_lo: usize,
     pub pre_idx_hi: usize,
+    /// Matching fragments have `peptide_lo <= peptide_index < peptide_hi`
+    peptide_lo: u32,
+    peptide_hi: u32,
+}
+
+/// Peptide index and fragment m/z bounds of a fragment query
+#[derive(Copy, Clone)]
+struct FragmentBounds {
+    peptide_lo: u32,
+    peptide_hi: u32,
+    fragment_lo: f32,
+    fragment_hi: f32,
+}
+
+impl FragmentBounds {
+    /// Non short-circuiting, so that the comparisons compile to branch-free
+    /// (and vectorizable) code
+    #[inline(always)]
+    fn contains(&self, frag: &Theoretical) -> bool {
+        (frag.peptide_index.0 >= self.peptide_lo)
+            & (frag.peptide_index.0 < self.peptide_hi)
+            & (frag.fragment_mz >= self.fragment_lo)
+            & (frag.fragment_mz <= self.fragment_hi)
+    }
+
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    #[inline]
+    fn for_each_match<'a, F: FnMut(&'a Theoretical)>(
+        &self,
+        fragments: &'a [Theoretical],
+        f: &mut F,
+    ) {
+        for frag in fragments {
+            if self.contains(frag) {
+                f(frag);
+            }
+        }
+    }
+
+    /// Compare two fragments at a time with SSE2, which every x86_64 CPU has.
+    /// The lanes of a register alternate between peptide index and fragment m/z
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[inline]
+    fn for_each_match<'a, F: FnMut(&'a Theoretical)>(
+        &self,
+        fragments: &'a [Theoretical],
+        f: &mut F,
+    ) {
+        use std::arch::x86_64::*;
+
+        let chunks = fragments.chunks_exact(2);
+        let remainder = chunks.remainder();
+        // SAFETY: SSE2 is part of the x86_64 baseline, and each chunk is 16
+        // bytes of `#[repr(C)]` fragments, read with an unaligned load
+        unsafe {
+            // Unsigned comparison of peptide indices, using signed comparisons
+            // of values offset by i32::MIN
+            let offset = _mm_set1_epi32(i32::MIN);
+            let peptide_lo = _mm_set1_epi32((self.peptide_lo ^ 0x8000_0000) as i32);
+            let peptide_hi = _mm_set1_epi32((self.peptide_hi ^ 0x8000_0000) as i32);
+            let fragment_lo = _mm_set1_ps(self.fragment_lo);
+            let fragment_hi = _mm_set1_ps(self.fragment_hi);
+            let index_lanes = _mm_setr_epi32(-1, 0, -1, 0);
+
+            for chunk in chunks {
+                let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+                let index = _mm_xor_si128(v, offset);
+                let index_ok = _mm_andnot_si128(
+                    _mm_cmpgt_epi32(peptide_lo, index),
+                    _mm_cmpgt_epi32(peptide_hi, index),
+                );
+                let mz = _mm_castsi128_ps(v);
+                let mz_ok = _mm_castps_si128(_mm_and_ps(
+                    _mm_cmpge_ps(mz, fragment_lo),
+                    _mm_cmple_ps(mz, fragment_hi),
+                ));
+                let ok = _mm_or_si128(
+                    _mm_and_si128(index_ok, index_lanes),
+                    _mm_andnot_si128(index_lanes, mz_ok),
+                );
+                let mask = _mm_movemask_ps(_mm_castsi128_ps(ok));
+                if mask & 0b0011 == 0b0011 {
+                    f(&chunk[0]);
+                }
+                if mask & 0b1100 == 0b1100 {
+                    f(&chunk[1]);
+                }
+            }
+        }
+        for frag in remainder {
+            if self.contains(frag) {
+                f(frag);
+            }
+        }
+    }
 }
 
 impl<'d> IndexedQuery<'d> {
+    fn bounds(&self, fragment_mz: f32) -> FragmentBounds {
+        let (fragment_lo, fragment_hi) = self.fragment_tol.bounds(fragment_mz);
+        FragmentBounds {
+            peptide_lo: self.peptide_lo,
+            peptide_hi: self.peptide_hi,
+            fragment_lo,
+            fragment_hi,
+        }
+    }
+
+    /// Peptide indices of matching fragments
+    #[cfg(feature = "gpu")]
+    pub(crate) fn peptide_range(&self) -> std::ops::Range<u32> {
+        self.peptide_lo..self.peptide_hi
+    }
+
+    /// Lowest and highest m/z of fragments matching `fragment_mz`
+    #[cfg(feature = "gpu")]
+    pub(crate) fn fragment_bounds(&self, fragment_mz: f32) -> (f32, f32) {
+        let bounds = self.bounds(fragment_mz);
+        (bounds.fragment_lo, bounds.fragment_hi)
+    }
+
     /// Search for a specified `fragment_mz` within the database
     pub fn page_search(&self, fragment_mz: f32) -> impl Iterator<Item = &Theoretical> {
-        let (fragment_lo, fragment_hi) = self.fragment_tol.bounds(fragment_mz);
-        let (precursor_lo, precursor_hi) = self.precursor_tol.bounds(self.precursor_mass);
+        let bounds = self.bounds(fragment_mz);
+        self.pages(&bounds)
+            .flat_map(move |page| page.iter().filter(move |frag| bounds.contains(frag)))
+    }
+
+    /// Call `f` with each fragment matching `fragment_mz`: the same fragments,
+    /// in the same order, as [`IndexedQuery::page_search`], but the
+    /// comparisons are vectorized when the `simd` feature is enabled
+    pub fn for_each_match<F: FnMut(&'d Theoretical)>(&self, fragment_mz: f32, mut f: F) {
+        let bounds = self.bounds(fragment_mz);
+        for page in self.pages(&bounds) {
+            bounds.for_each_match(page, &mut f);
+        }
+    }
 
+    /// Slices of the pages that may contain fragments within `bounds`
+    fn pages(&self, bounds: &FragmentBounds) -> impl Iterator<Item = &'d [Theoretical]> + '_ {
+        let db = self.db;
         // Locate the left and right page indices that contain matching fragments
         // Note that we need to multiply by `bucket_size` to transform these into
         // indices that can be used with `self.db.fragments`
         let (left_idx, right_idx) = binary_search_slice(
-            &self.db.min_value,
+            &db.min_value,
             |min, bounds| min.total_cmp(bounds),
-            fragment_lo,
-            fragment_hi,
+            bounds.fragment_lo,
+            bounds.fragment_hi,
         );
 
         // It is absolutely critical that we do not cross page boundaries!
         // If we do, we can no longer rely on total ordering of peptide_index (precursor m/z)
-        (left_idx..right_idx).flat_map(move |page| {
-            let left_idx = page * self.db.bucket_size;
+        (left_idx..right_idx).map(move |page| {
+            let left_idx = page * db.bucket_size;
             // Last chunk not guaranted to be modulo bucket size, make sure we don't
             // accidentally go out of bounds!
-            let right_idx = ((page + 1) * self.db.bucket_size).min(self.db.fragments.len());
+            let right_idx = ((page + 1) * db.bucket_size).min(db.fragments.len());
 
             // Narrow down into our region of interest, then perform another binary
             // search to further refine down to the slice of matching precursor mzs
-            let slice = &&self.db.fragments[left_idx..right_idx];
+            let slice = &db.fragments[left_idx..right_idx];
 
             let (inner_left, inner_right) = binary_search_slice(
                 slice,
@@ -459,28 +1487,16 @@ impl<'d> IndexedQuery<'d> {
                 self.pre_idx_hi,
             );
 
-            // Finally, filter down our slice into exact matches only
-            slice[inner_left..inner_right].iter().filter(move |frag| {
-                // This looks somewhat complicated, but it's a consequence of
-                // how the `binary_search_slice` function works - it will return
-                // the set of indices that maximally cover the desired range - the exact
-                // `left` and `right` indices may be valid, or just outside of the range.
-                // Anything interior of `left` and `right` is guaranteed to be within the
-                // precursor tolerance, so we just need to check the edge cases
-                //
-                // Previously, a direct lookup to check the mass of the current fragment was
-                // performed, but the pointer indirection + float comparison can slow down
-                // open searches by as much as 2x!!
-                // e.g. used to be `self.db[frag.peptide_index].monoisotopic >= precursor_lo`
-                (frag.peptide_index.0 > self.pre_idx_lo as u32
-                    || (frag.peptide_index.0 == self.pre_idx_lo as u32
-                        && self.db[frag.peptide_index].monoisotopic >= precursor_lo))
-                    && (frag.peptide_index.0 < self.pre_idx_hi as u32
-                        || (frag.peptide_index.0 == self.pre_idx_hi as u32
-                            && self.db[frag.peptide_index].monoisotopic <= precursor_hi))
-                    && frag.fragment_mz >= fragment_lo
-                    && frag.fragment_mz <= fragment_hi
-            })
+            // The slice still needs to be filtered down into exact matches:
+            // `binary_search_slice` returns the set of indices that maximally
+            // cover the desired range - the exact `left` and `right` indices
+            // may be valid, or just outside of the range.
+            //
+            // Matches are filtered on the exact peptide index range computed by
+            // [`IndexedDatabase::query`]: looking up the mass of each fragment's
+            // peptide instead (pointer indirection + float comparison) can slow
+            // down open searches by as much as 2x!!
+            &slice[inner_left..inner_right]
         })
     }
 }
@@ -523,9 +1539,8 @@ where
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
-
     use super::*;
+    use crate::mass::{monoisotopic, H2O};
 
     #[test]
     fn binary_search_slice_smoke() {
@@ -580,7 +1595,7 @@ mod test {
         );
 
         let params = Parameters {
-            bucket_size: 128,
+            bucket_size: Some(128),
             enzyme: EnzymeBuilder {
                 missed_cleavages: Some(1),
                 min_len: Some(6),
@@ -593,6 +1608,7 @@ mod test {
             peptide_max_mass: 5000.0,
             ion_kinds: vec![Kind::B, Kind::Y],
             min_ion_index: 2,
+            residue_masses: HashMap::default(),
             static_mods: HashMap::default(),
             variable_mods: [(ModificationSpecificity::ProteinN(None), vec![42.0])]
                 .into_iter()
@@ -600,7 +1616,12 @@ mod test {
             max_variable_mods: 2,
             decoy_tag: "rev_".into(),
             generate_decoys: false,
-            fasta: "none".into(),
+            equate_il: false,
+            fasta: vec!["none".into()],
+            max_index_memory_mb: None,
+            prebuilt_index: None,
+            tolerances: None,
+            silac_labels: HashMap::default(),
         };
 
         let peptides = params.digest(&fasta);
@@ -622,11 +1643,405 @@ mod test {
         // All peptides are shared except for the protein N-term mod
         for peptide in &peptides[..4] {
             assert_eq!(peptide.proteins.len(), 2);
+            assert!(peptide.shared());
         }
+        assert!(!peptides.last().unwrap().shared());
         // Ensure that this mod is uniquely called as the first protein
         assert_eq!(
             peptides.last().unwrap().proteins,
             vec!["sp|AAAAA".to_string().into()]
         );
+
+        // Proteins present in more than one fasta file are only reported once
+        let mut combined = fasta.clone();
+        combined.extend(fasta);
+        let peptides = params.digest(&combined);
+        assert_eq!(expected.len(), peptides.len());
+        for peptide in &peptides[..4] {
+            assert_eq!(peptide.proteins.len(), 2);
+        }
+    }
+
+    #[test]
+    fn equate_isoleucine_leucine() {
+        let fasta = r#"
+        >sp|AAAAA
+        LEQSMRAQLTQLK
+        >sp|BBBBB
+        IEQSMRAQLTQIK
+        "#;
+        let fasta = Fasta::parse(fasta.into(), "rev_", false);
+
+        let builder = |equate_il| Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(6),
+                ..Default::default()
+            }),
+            generate_decoys: Some(false),
+            equate_il: Some(equate_il),
+            fasta: Some("none".into()),
+            ..Default::default()
+        };
+
+        let peptides = builder(false).make_parameters().digest(&fasta);
+        assert_eq!(peptides.len(), 4);
+        assert!(peptides.iter().all(|p| p.proteins.len() == 1));
+
+        let peptides = builder(true).make_parameters().digest(&fasta);
+        assert_eq!(peptides.len(), 2);
+        assert!(peptides.iter().all(|p| p.proteins.len() == 2));
+    }
+
+    #[test]
+    fn equate_isoleucine_leucine_modified() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nPEPTIDEK\n>sp|BBBBB\nPEPTLDEK".into(),
+            "rev_",
+            false,
+        );
+        let builder = |equate_il| {
+            let mut params = Builder {
+                generate_decoys: Some(false),
+                equate_il: Some(equate_il),
+                fasta: Some("none".into()),
+                ..Default::default()
+            }
+            .make_parameters();
+            params.variable_mods = [(ModificationSpecificity::Residue(b'E'), vec![16.0])]
+                .into_iter()
+                .collect();
+            params
+        };
+
+        // Unmodified, modified at either glutamate, and modified at both
+        let peptides = builder(false).digest(&fasta);
+        assert_eq!(peptides.len(), 8);
+
+        // Positional isomers of the I and L forms must not split each other
+        let peptides = builder(true).digest(&fasta);
+        assert_eq!(peptides.len(), 4);
+        assert!(peptides.iter().all(|p| p.proteins.len() == 2));
+    }
+
+    #[test]
+    fn custom_residue_masses() {
+        let fasta = Fasta::parse(">sp|AAAAA\nLEQSMRAQLTQXK".into(), "rev_", false);
+        let builder = |residue_masses: &[(&str, f32)]| Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(6),
+                ..Default::default()
+            }),
+            generate_decoys: Some(false),
+            residue_masses: Some(
+                residue_masses
+                    .iter()
+                    .map(|(residue, mass)| (residue.to_string(), *mass))
+                    .collect(),
+            ),
+            fasta: Some("none".into()),
+            ..Default::default()
+        };
+
+        // `X` has no mass by default, so the peptide is skipped
+        let peptides = builder(&[]).make_parameters().digest(&fasta);
+        assert_eq!(peptides.len(), 1);
+        let light = peptides[0].monoisotopic;
+
+        let params = builder(&[("X", 150.0), ("K", 136.10916), ("xx", 1.0)]).make_parameters();
+        assert_eq!(params.residue_masses.len(), 2);
+        let peptides = params.digest(&fasta);
+        assert_eq!(peptides.len(), 2);
+        let unchanged = peptides
+            .iter()
+            .find(|p| p.sequence.as_ref() == b"LEQSMR")
+            .unwrap();
+        assert_eq!(unchanged.monoisotopic, light);
+        let heavy = peptides
+            .iter()
+            .find(|p| p.sequence.as_ref() == b"AQLTQXK")
+            .unwrap();
+        let expected =
+            H2O + b"AQLTQ".iter().map(|c| monoisotopic(*c)).sum::<f32>() + 150.0 + 136.10916;
+        assert!((heavy.monoisotopic - expected).abs() < 1E-3);
+    }
+
+    #[test]
+    fn clip_nterm_methionine_acetylation() {
+        let fasta = Fasta::parse(">sp|AAAAA\nMEWKLEQSMR".into(), "rev_", false);
+        let mut params = Builder {
+            enzyme: Some(EnzymeBuilder {
+                min_len: Some(3),
+                clip_nterm_methionine: Some(true),
+                ..Default::default()
+            }),
+            peptide_min_mass: Some(100.0),
+            generate_decoys: Some(false),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters();
+        params.variable_mods = [(ModificationSpecificity::ProteinN(None), vec![42.0])]
+            .into_iter()
+            .collect();
+
+        let sequences = params
+            .digest(&fasta)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        for expected in ["MEWK", "[+42]-MEWK", "EWK", "[+42]-EWK", "LEQSMR"] {
+            assert!(sequences.contains(&expected.to_string()), "{}", expected);
+        }
+        assert!(!sequences.contains(&"[+42]-LEQSMR".to_string()));
+    }
+
+    #[test]
+    fn save_and_load_index() -> std::io::Result<()> {
+        let fasta = r#"
+        >sp|AAAAA
+        MEWKLEQSMREQALLKAQLTQLK
+        >sp|BBBBB
+        RMEWKLEQSMREQALLKAQLTQLK
+        "#;
+        let fasta = Fasta::parse(fasta.into(), "rev_", true);
+
+        let params = Parameters {
+            bucket_size: Some(128),
+            enzyme: EnzymeBuilder {
+                missed_cleavages: Some(1),
+                min_len: Some(6),
+                max_len: Some(10),
+                ..Default::default()
+            },
+            fragment_min_mz: 100.0,
+            fragment_max_mz: 1000.0,
+            peptide_min_mass: 150.0,
+            peptide_max_mass: 5000.0,
+            ion_kinds: vec![Kind::B, Kind::Y],
+            min_ion_index: 2,
+            residue_masses: [('K', 136.10916)].into_iter().collect(),
+            static_mods: [(ModificationSpecificity::Residue(b'C'), 57.0215)]
+                .into_iter()
+                .collect(),
+            variable_mods: [(ModificationSpecificity::ProteinN(None), vec![42.0])]
+                .into_iter()
+                .collect(),
+            max_variable_mods: 2,
+            decoy_tag: "rev_".into(),
+            generate_decoys: true,
+            equate_il: false,
+            fasta: vec!["none".into()],
+            max_index_memory_mb: None,
+            prebuilt_index: None,
+            tolerances: None,
+            silac_labels: HashMap::default(),
+        };
+        let fingerprint = params.fingerprint();
+        let db = params.build(fasta);
+
+        let path = std::env::temp_dir().join(format!("sage-index-{}.bin", std::process::id()));
+        db.save(&path)?;
+        let loaded = IndexedDatabase::load(&path);
+        std::fs::remove_file(&path)?;
+        let loaded = loaded?;
+
+        assert_eq!(loaded.fingerprint, fingerprint);
+        assert_eq!(loaded.peptides, db.peptides);
+        assert_eq!(loaded.fragments, db.fragments);
+        assert_eq!(loaded.min_value, db.min_value);
+        assert_eq!(loaded.potential_mods, db.potential_mods);
+        assert_eq!(loaded.residue_masses, db.residue_masses);
+        assert_eq!(loaded.ion_kinds, db.ion_kinds);
+        assert_eq!(loaded.bucket_size, db.bucket_size);
+        assert_eq!(loaded.decoy_tag, db.decoy_tag);
+        assert_eq!(loaded.generate_decoys, db.generate_decoys);
+        assert!(!loaded.fragments.is_mapped());
+
+        // Replaced rather than overwritten, so the mapped index stays valid
+        db.save(&path)?;
+        let mapped = IndexedDatabase::map(&path)?;
+        db.save(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(mapped.fragments.is_mapped(), cfg!(feature = "mmap"));
+        assert_eq!(mapped.fragments, db.fragments);
+        assert_eq!(mapped.peptides, db.peptides);
+        assert_eq!(mapped.min_value, db.min_value);
+        Ok(())
+    }
+
+    #[test]
+    fn candidates_and_fragments() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKLEQSMREQALLK\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder::default().make_parameters().build(fasta);
+        let tol = Tolerance::Ppm(-50.0, 50.0);
+
+        for (idx, peptide) in db.peptides.iter().enumerate() {
+            let candidates = db
+                .candidates(peptide.monoisotopic, tol)
+                .map(|(ix, _)| ix)
+                .collect::<Vec<_>>();
+            let (lo, hi) = tol.bounds(peptide.monoisotopic);
+            let expected = (0..db.peptides.len())
+                .filter(|&ix| (lo..=hi).contains(&db.peptides[ix].monoisotopic))
+                .map(|ix| PeptideIx(ix as u32))
+                .collect::<Vec<_>>();
+            assert_eq!(candidates, expected);
+            assert!(candidates.contains(&PeptideIx(idx as u32)));
+        }
+        assert_eq!(db.candidates(1.0, tol).count(), 0);
+
+        let ix = PeptideIx(0);
+        let fragments = db.peptide_fragments(ix).collect::<Vec<_>>();
+        assert_eq!(
+            fragments.len(),
+            db.ion_kinds.len() * (db[ix].sequence.len() - 1)
+        );
+        for theoretical in db.fragments.iter().filter(|f| f.peptide_index == ix) {
+            assert!(fragments
+                .iter()
+                .any(|ion| ion.monoisotopic_mass == theoretical.fragment_mz));
+        }
+    }
+
+    #[test]
+    fn fragment_matches() {
+        let fasta = Fasta::parse(
+            ">sp|AAAAA\nMEWKLEQSMREQALLKAQLTQLKLEQSMREQALLKPEPTIDERGGSTWKAAAGGGLLLR\n".into(),
+            "rev_",
+            true,
+        );
+        let mut params = Builder::default().make_parameters();
+        params.bucket_size = Some(7);
+        let db = params.build(fasta);
+
+        let fragment_tol = Tolerance::Da(-0.5, 0.5);
+        let mut total = 0;
+        for precursor_tol in [Tolerance::Da(-0.1, 0.1), Tolerance::Da(-500.0, 100.0)] {
+            for peptide in &db.peptides {
+                let query = db.query(peptide.monoisotopic, precursor_tol, fragment_tol);
+                let (precursor_lo, precursor_hi) = precursor_tol.bounds(peptide.monoisotopic);
+                for fragment in db.fragments.iter().step_by(3) {
+                    let mz = fragment.fragment_mz + 0.3;
+                    let (lo, hi) = fragment_tol.bounds(mz);
+                    let mut expected = db
+                        .fragments
+                        .iter()
+                        .filter(|frag| {
+                            let mass = db[frag.peptide_index].monoisotopic;
+                            (precursor_lo..=precursor_hi).contains(&mass)
+                                && (lo..=hi).contains(&frag.fragment_mz)
+                        })
+                        .collect::<Vec<_>>();
+
+                    let searched = query.page_search(mz).collect::<Vec<_>>();
+                    let mut matched = Vec::new();
+                    query.for_each_match(mz, |frag| matched.push(frag));
+                    assert_eq!(searched, matched);
+
+                    let key =
+                        |frag: &&Theoretical| (frag.peptide_index, frag.fragment_mz.to_bits());
+                    matched.sort_by_key(key);
+                    expected.sort_by_key(key);
+                    assert_eq!(matched, expected);
+                    total += matched.len();
+                }
+            }
+        }
+        assert!(total > 0);
+    }
+
+    #[test]
+    fn partition_by_memory() {
+        let fasta = r#"
+        >sp|AAAAA
+        MEWKLEQSMREQALLKAQLTQLK
+        >sp|BBBBB
+        RMEWKLEQSMREQALLKAQLTQLKPEPTIDERGGGGGGGGGGK
+        "#;
+        let fasta = Fasta::parse(fasta.into(), "rev_", true);
+        let mut builder = Builder::default();
+        builder.update_fasta("none".into());
+        let params = builder.make_parameters();
+        let peptides = params.digest(&fasta);
+        assert!(peptides.len() > 4);
+
+        // Everything fits into a single slice
+        assert_eq!(
+            params.partition(&peptides, usize::MAX),
+            vec![0..peptides.len()]
+        );
+
+        // Each peptide is too large for the limit, but all slices are non-empty
+        let ranges = params.partition(&peptides, 1);
+        assert_eq!(ranges.len(), peptides.len());
+
+        let limit = 2 * params.estimated_size(&peptides[0]);
+        let ranges = params.partition(&peptides, limit);
+        assert!(ranges.len() > 1);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, peptides.len());
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+
+        // Each slice indexes the same fragments as the full database
+        let full = params.build_from_peptides(peptides.clone());
+        let fragments = ranges
+            .iter()
+            .map(|range| params.build_from_peptides(peptides[range.clone()].to_vec()))
+            .map(|db| db.fragments.len())
+            .sum::<usize>();
+        assert_eq!(fragments, full.fragments.len());
+
+        let (estimated_fragments, estimated_bytes) = params.estimate_index(&peptides);
+        assert!(estimated_fragments >= full.fragments.len());
+        assert_eq!(
+            estimated_bytes,
+            peptides
+                .iter()
+                .map(|peptide| params.estimated_size(peptide))
+                .sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn bucket_size_heuristic() {
+        let fasta = Fasta::parse(">sp|AAAAA\nLEQSMRAQLTQLK".into(), "rev_", false);
+        let params = Builder::default().make_parameters();
+        let peptide = params.digest(&fasta).remove(0);
+        let peptides = (0..10_000)
+            .map(|idx| Peptide {
+                monoisotopic: 500.0 + idx as f32 * 0.01,
+                ..peptide.clone()
+            })
+            .collect::<Vec<_>>();
+        let fragments = (0..1_000_000)
+            .map(|idx| Theoretical {
+                peptide_index: PeptideIx(idx % 10_000),
+                fragment_mz: 150.0 + idx as f32 * 0.00185,
+            })
+            .collect::<Vec<_>>();
+
+        let narrow = Tolerance::Ppm(-10.0, 10.0);
+        let wide = Tolerance::Da(-0.5, 0.5);
+        let open = Tolerance::Da(-100.0, 100.0);
+        let size = |precursor_tol, fragment_tol, fragments: &[Theoretical]| {
+            choose_bucket_size(&peptides, fragments, precursor_tol, fragment_tol)
+        };
+
+        // Every candidate precursor is scored: keep buckets small
+        assert_eq!(size(open, narrow, &fragments), 256);
+        // Tolerance windows span many fragments: use fewer, larger buckets
+        assert!(size(narrow, wide, &fragments) > size(narrow, narrow, &fragments));
+        assert!(size(narrow, wide, &fragments) > size(open, wide, &fragments));
+        // Never larger than the index itself
+        assert_eq!(size(narrow, wide, &fragments[..100]), 128);
+        assert_eq!(size(narrow, wide, &[]), DEFAULT_BUCKET_SIZE);
     }
 }
@@ -86,6 +86,8 @@ pub struct EnzymeParameters {
     pub min_len: usize,
     /// Inclusive
     pub max_len: usize,
+    /// Also generate protein N-terminal peptides with the initiator methionine removed
+    pub clip_nterm_methionine: bool,
     pub enyzme: Option<Enzyme>,
 }
 
@@ -276,6 +278,20 @@ impl EnzymeParameters {
             true => self.semi_enzymatic_sites(&mut sites),
         };
 
+        // Peptides starting after the initiator methionine are protein N-terminal
+        let clip_methionine = self.clip_nterm_methionine && sequence.starts_with('M');
+        if clip_methionine {
+            let clipped = sites
+                .iter()
+                .filter(|site| site.site.start == 0 && site.site.end > 1)
+                .map(|site| DigestSite {
+                    site: 1..site.site.end,
+                    ..site.clone()
+                })
+                .collect::<Vec<_>>();
+            sites.extend(clipped);
+        }
+
         // Keep a set of peptides that have been digested from this sequence
         // - handles cases where the same peptide occurs multiple times in a protein
         let mut seen = FnvHashSet::default();
@@ -291,7 +307,8 @@ impl EnzymeParameters {
 
             let len = sequence.len();
 
-            let position = match (start == 0, end == n) {
+            let nterm = start == 0 || (clip_methionine && start == 1);
+            let position = match (nterm, end == n) {
                 (true, true) => Position::Full,
                 (true, false) => Position::Nterm,
                 (false, true) => Position::Cterm,
@@ -384,6 +401,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -396,6 +414,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn clip_nterm_methionine() {
+        let sequence = "MADEEKLPPGWEKRMSR";
+        let mut tryp = EnzymeParameters {
+            min_len: 2,
+            max_len: 50,
+            missed_cleavages: 1,
+            clip_nterm_methionine: false,
+            enyzme: Enzyme::new("KR", Some('P'), true, false),
+        };
+
+        let digest = |tryp: &EnzymeParameters| {
+            tryp.digest(sequence, Arc::default())
+                .into_iter()
+                .map(|d| (d.sequence, d.position))
+                .collect::<Vec<_>>()
+        };
+
+        let digests = digest(&tryp);
+        assert!(digests.contains(&("MADEEK".into(), Position::Nterm)));
+        assert!(!digests.iter().any(|(seq, _)| seq == "ADEEK"));
+
+        tryp.clip_nterm_methionine = true;
+        let digests = digest(&tryp);
+        assert!(digests.contains(&("MADEEK".into(), Position::Nterm)));
+        assert!(digests.contains(&("ADEEK".into(), Position::Nterm)));
+        assert!(digests.contains(&("ADEEKLPPGWEK".into(), Position::Nterm)));
+        // Internal methionines are never clipped
+        assert!(!digests.iter().any(|(seq, _)| seq == "SR"));
+    }
+
     #[test]
     fn trypsin_missed_cleavage() {
         let sequence = "MADEEKLPPGWEKRMSRSSGRVYYFNHITNASQWERPSGN";
@@ -417,6 +466,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 1,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -454,6 +504,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 2,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -482,6 +533,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", None, true, false),
         };
 
@@ -503,6 +555,7 @@ mod test {
             min_len: 1,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("D", None, false, false),
         };
 
@@ -532,6 +585,7 @@ mod test {
             min_len: 1,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("FYWL", None, true, false),
         };
 
@@ -558,6 +612,7 @@ mod test {
             min_len: 5,
             max_len: 5,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: None,
         };
 
@@ -587,6 +642,7 @@ mod test {
             min_len: 5,
             max_len: 7,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("", None, true, false),
         };
 
@@ -608,6 +664,7 @@ mod test {
             min_len: 0,
             max_len: usize::MAX,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("$", None, true, false),
         };
 
@@ -629,6 +686,7 @@ mod test {
             min_len: 2,
             max_len: usize::MAX,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", None, true, false),
         };
 
@@ -652,6 +710,7 @@ mod test {
             min_len: 2,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
@@ -702,6 +761,7 @@ mod test {
             min_len: 3,
             max_len: 50,
             missed_cleavages: 1,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
@@ -758,6 +818,7 @@ mod test {
             min_len: 3,
             max_len: 50,
             missed_cleavages: 2,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", None, true, true),
         };
 
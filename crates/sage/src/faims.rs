@@ -0,0 +1,150 @@
+//! FAIMS compensation voltage aware processing
+//!
+//! FAIMS (high-field asymmetric waveform ion mobility spectrometry) devices
+//! transmit only ions of a certain differential mobility at each compensation
+//! voltage (CV), and runs commonly cycle through several CVs. Spectra of the
+//! CVs of a run are interleaved, but sample different populations of
+//! peptides: a peptide's MS1 signal is only found in the MS1 spectra acquired
+//! at its CV, and the retention times of each population are best fit by
+//! separate models.
+
+use crate::database::IndexedDatabase;
+use crate::ml::retention_model::{self, RetentionModel};
+use crate::scoring::Feature;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Settings of the processing of runs acquired at several FAIMS compensation
+/// voltages. These have no effect on spectra without a reported CV
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FaimsSettings {
+    /// Fit a separate retention time model to the PSMs of each CV
+    pub split_rt: bool,
+    /// Integrate the MS1 peaks of a precursor only in MS1 spectra acquired
+    /// at the CV of its most confident PSM
+    pub split_quant: bool,
+    /// Write the PSMs of each CV to a separate results file, in addition to
+    /// the results of all CVs
+    pub split_output: bool,
+}
+
+impl Default for FaimsSettings {
+    fn default() -> Self {
+        FaimsSettings {
+            split_rt: true,
+            split_quant: false,
+            split_output: false,
+        }
+    }
+}
+
+/// Distinct compensation voltages reported for `features`, in ascending order
+pub fn compensation_voltages(features: &[Feature]) -> Vec<f32> {
+    let mut voltages = features
+        .iter()
+        .filter_map(|feat| feat.compensation_voltage)
+        .collect::<Vec<_>>();
+    voltages.sort_by(|a, b| a.total_cmp(b));
+    voltages.dedup();
+    voltages
+}
+
+/// Sort `features` by compensation voltage, keeping the relative order of the
+/// PSMs of each CV, and return the range of the PSMs of each CV. PSMs without
+/// a CV come first
+pub fn partition(features: &mut [Feature]) -> Vec<(Option<f32>, Range<usize>)> {
+    features.sort_by(
+        |a, b| match (a.compensation_voltage, b.compensation_voltage) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        },
+    );
+
+    let mut groups: Vec<(Option<f32>, Range<usize>)> = Vec::new();
+    for (idx, feat) in features.iter().enumerate() {
+        match groups.last_mut() {
+            Some((cv, range)) if *cv == feat.compensation_voltage => range.end = idx + 1,
+            _ => groups.push((feat.compensation_voltage, idx..idx + 1)),
+        }
+    }
+    groups
+}
+
+/// Fit a separate retention time model to the PSMs of each compensation
+/// voltage (see [`retention_model::predict`]), returning the mean r-squared of
+/// the models, weighted by the number of PSMs of each CV.
+///
+/// PSMs of a CV with too few confident PSMs to fit its own model are assigned
+/// the predictions of a single model fit to the PSMs of all CVs. `features`
+/// are left sorted by CV (see [`partition`])
+pub fn predict_rt(db: &IndexedDatabase, features: &mut [Feature]) -> Option<f64> {
+    let groups = partition(features);
+    if groups.len() < 2 {
+        return retention_model::predict(db, features);
+    }
+
+    let mut fit = Vec::new();
+    let mut failed = Vec::new();
+    for (cv, range) in groups {
+        match cv {
+            Some(cv) => log::info!("- compensation voltage {} V: {} PSMs", cv, range.len()),
+            None => log::info!("- no compensation voltage: {} PSMs", range.len()),
+        }
+        match retention_model::predict(db, &mut features[range.clone()]) {
+            Some(r2) => fit.push((r2, range.len())),
+            None => failed.push(range),
+        }
+    }
+
+    if !failed.is_empty() {
+        log::warn!(
+            "retention time models could not be fit for {} compensation voltages, using a model fit to all PSMs",
+            failed.len()
+        );
+        match RetentionModel::fit(db, features) {
+            Some(model) => {
+                for range in failed {
+                    model.apply(db, &mut features[range.clone()]);
+                    fit.push((model.r2, range.len()));
+                }
+            }
+            None => log::warn!(
+                "retention time model could not be fit to the PSMs of all compensation voltages"
+            ),
+        }
+    }
+
+    let psms = fit.iter().map(|(_, n)| n).sum::<usize>();
+    match psms {
+        0 => None,
+        _ => Some(fit.iter().map(|(r2, n)| r2 * *n as f64).sum::<f64>() / psms as f64),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partition_by_compensation_voltage() {
+        let mut features = [Some(-45.0), None, Some(-60.0), Some(-45.0), None]
+            .iter()
+            .enumerate()
+            .map(|(idx, &compensation_voltage)| Feature {
+                psm_id: idx,
+                compensation_voltage,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(compensation_voltages(&features), vec![-60.0, -45.0]);
+
+        let groups = partition(&mut features);
+        assert_eq!(
+            groups,
+            vec![(None, 0..2), (Some(-60.0), 2..3), (Some(-45.0), 3..5)]
+        );
+        let order = features.iter().map(|feat| feat.psm_id).collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 4, 2, 0, 3]);
+    }
+}
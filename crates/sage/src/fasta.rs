@@ -1,7 +1,11 @@
 use crate::enzyme::{Digest, EnzymeParameters};
-use rayon::prelude::*;
+use crate::par::prelude::*;
+use fnv::FnvHashSet;
 use std::sync::Arc;
 
+/// Length of sequence lines when writing FASTA files
+const LINE_WIDTH: usize = 60;
+
 #[derive(Clone)]
 pub struct Fasta {
     pub targets: Vec<(Arc<String>, String)>,
@@ -55,6 +59,27 @@ impl Fasta {
         }
     }
 
+    /// Append the proteins of another fasta database, e.g. when searching
+    /// against several fasta files
+    pub fn extend(&mut self, other: Fasta) {
+        self.targets.extend(other.targets);
+    }
+
+    /// Restrict the database to proteins with an accession in `accessions`,
+    /// e.g. to build a focused database for a follow-up search
+    pub fn subset(&self, accessions: &FnvHashSet<Arc<String>>) -> Fasta {
+        Fasta {
+            targets: self
+                .targets
+                .iter()
+                .filter(|(acc, _)| accessions.contains(acc))
+                .cloned()
+                .collect(),
+            decoy_tag: self.decoy_tag.clone(),
+            generate_decoys: self.generate_decoys,
+        }
+    }
+
     pub fn digest(&self, enzyme: &EnzymeParameters) -> Vec<Digest> {
         self.targets
             .par_iter()
@@ -78,3 +103,47 @@ impl Fasta {
             .collect()
     }
 }
+
+/// Formats the database as a FASTA file, with sequences wrapped at 60 residues
+impl std::fmt::Display for Fasta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (acc, sequence) in &self.targets {
+            writeln!(f, ">{}", acc)?;
+            for line in sequence.as_bytes().chunks(LINE_WIDTH) {
+                writeln!(f, "{}", String::from_utf8_lossy(line))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subset_roundtrip() {
+        let sequence = "M".repeat(LINE_WIDTH + 5);
+        let contents = format!(
+            ">sp|A|A_HUMAN Protein A\n{}\n>sp|B|B_HUMAN\nPEPTIDEK\n>rev_sp|A|A_HUMAN\nKEDITPEP",
+            sequence
+        );
+        let fasta = Fasta::parse(contents, "rev_", true);
+        assert_eq!(fasta.targets.len(), 2);
+
+        let accessions = [Arc::new("sp|A|A_HUMAN".to_string())].into_iter().collect();
+        let subset = fasta.subset(&accessions);
+        let written = subset.to_string();
+        assert_eq!(
+            written,
+            format!(
+                ">sp|A|A_HUMAN\n{}\n{}\n",
+                &sequence[..LINE_WIDTH],
+                &sequence[LINE_WIDTH..]
+            )
+        );
+
+        let parsed = Fasta::parse(written, "rev_", true);
+        assert_eq!(parsed.targets, subset.targets);
+    }
+}
@@ -7,11 +7,12 @@
 use crate::database::{IndexedDatabase, PeptideIx};
 use crate::lfq::PrecursorId;
 use crate::ml::kde::Estimator;
+use crate::par::prelude::*;
 use crate::scoring::Feature;
-use fnv::FnvHashMap;
-use rayon::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
 use std::collections::HashMap;
 use std::hash::BuildHasher;
+use std::sync::Arc;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Competition<Ix> {
@@ -180,6 +181,21 @@ pub fn picked_protein(db: &IndexedDatabase, features: &mut [Feature]) -> usize {
     passing
 }
 
+/// Accessions of all target proteins identified by a PSM passing
+/// `protein_fdr`. Protein-level q-values must have been assigned. Peptides
+/// shared between several proteins contribute all of them
+pub fn identified_proteins(
+    db: &IndexedDatabase,
+    features: &[Feature],
+    protein_fdr: f32,
+) -> FnvHashSet<Arc<String>> {
+    features
+        .iter()
+        .filter(|feat| feat.label == 1 && feat.protein_q <= protein_fdr)
+        .flat_map(|feat| db[feat.peptide_idx].proteins.iter().cloned())
+        .collect()
+}
+
 pub fn picked_precursor(
     peaks: &mut FnvHashMap<(PrecursorId, bool), (crate::lfq::Peak, Vec<f64>)>,
 ) -> usize {
@@ -0,0 +1,171 @@
+//! Rescoring with predicted fragment intensities
+//!
+//! Fragment intensities predicted by deep learning models (e.g. Prosit or
+//! MS2PIP) are read from a spectral library in Sage's format, and compared
+//! with the intensities of the fragment ions annotated for each PSM
+//! ([`Feature::fragments`]). Predicted spectra of decoy peptides are derived
+//! from those of their target peptides, as for spectral library search (see
+//! [`crate::library_search::prepare`]), so that target and decoy PSMs are
+//! scored alike.
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::ion_series::Kind;
+use crate::library::LibraryFragment;
+use crate::library_search::{self, spectral_angle, LibraryRecord, LibrarySearchSettings};
+use crate::par::prelude::*;
+use crate::scoring::{Feature, Fragments};
+use fnv::FnvHashMap;
+
+/// Predicted fragment intensities of each precursor (peptide & charge state)
+#[derive(Default)]
+pub struct PredictedSpectra {
+    spectra: FnvHashMap<(PeptideIx, u8), Vec<LibraryFragment>>,
+}
+
+impl PredictedSpectra {
+    /// Resolve predicted spectra against the database, and generate the
+    /// predicted spectra of decoy peptides. Returns the number of predicted
+    /// precursors that were not found in the database
+    pub fn new(db: &IndexedDatabase, records: Vec<LibraryRecord>) -> (Self, usize) {
+        let settings = LibrarySearchSettings {
+            fragments: usize::MAX,
+            ..Default::default()
+        };
+        let (spectra, missing) = library_search::prepare(db, records, &settings);
+        let spectra = spectra
+            .into_iter()
+            .map(|spectrum| ((spectrum.peptide_idx, spectrum.charge), spectrum.fragments))
+            .collect();
+        (Self { spectra }, missing)
+    }
+
+    /// Number of predicted spectra, including decoys
+    pub fn len(&self) -> usize {
+        self.spectra.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spectra.is_empty()
+    }
+
+    /// Assign [`Feature::predicted_spectral_angle`] and
+    /// [`Feature::predicted_correlation`] to every PSM with a predicted
+    /// spectrum and annotated fragment ions, returning the number of such PSMs
+    pub fn score(&self, features: &mut [Feature]) -> usize {
+        features
+            .par_iter_mut()
+            .filter_map(|feat| {
+                let predicted = self.spectra.get(&(feat.peptide_idx, feat.charge))?;
+                let (angle, correlation) = similarity(predicted, feat.fragments.as_ref()?);
+                feat.predicted_spectral_angle = angle;
+                feat.predicted_correlation = correlation;
+                Some(())
+            })
+            .count()
+    }
+}
+
+/// Normalized spectral contrast angle and Pearson correlation between the
+/// (square-root transformed) predicted and observed intensities of the
+/// predicted fragment ions. Predicted fragments that were not matched have an
+/// observed intensity of 0
+pub fn similarity(predicted: &[LibraryFragment], fragments: &Fragments) -> (f32, f32) {
+    let mut observed: FnvHashMap<(Kind, i32, i32), f32> = FnvHashMap::default();
+    for idx in 0..fragments.intensities.len() {
+        let key = (
+            fragments.kinds[idx],
+            fragments.fragment_ordinals[idx],
+            fragments.charges[idx],
+        );
+        let intensity = observed.entry(key).or_default();
+        *intensity = intensity.max(fragments.intensities[idx]);
+    }
+    let reference = predicted
+        .iter()
+        .map(|frag| frag.intensity)
+        .collect::<Vec<_>>();
+    let observed = predicted
+        .iter()
+        .map(|frag| {
+            observed
+                .get(&(frag.kind, frag.ordinal, frag.charge))
+                .copied()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    (
+        spectral_angle(&reference, &observed),
+        correlation(&reference, &observed),
+    )
+}
+
+/// Pearson correlation between square-root transformed intensities, or 0 if
+/// either has no variance
+fn correlation(reference: &[f32], observed: &[f32]) -> f32 {
+    let n = reference.len() as f32;
+    if n < 2.0 {
+        return 0.0;
+    }
+    let r_mean = reference.iter().map(|r| r.sqrt()).sum::<f32>() / n;
+    let o_mean = observed.iter().map(|o| o.sqrt()).sum::<f32>() / n;
+    let (mut cov, mut r_var, mut o_var) = (0.0f32, 0.0f32, 0.0f32);
+    for (r, o) in reference.iter().zip(observed) {
+        let (r, o) = (r.sqrt() - r_mean, o.sqrt() - o_mean);
+        cov += r * o;
+        r_var += r * r;
+        o_var += o * o;
+    }
+    if r_var == 0.0 || o_var == 0.0 {
+        return 0.0;
+    }
+    (cov / (r_var.sqrt() * o_var.sqrt())).clamp(-1.0, 1.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn predicted_similarity() {
+        let fragment = |kind, ordinal, intensity| LibraryFragment {
+            kind,
+            ordinal,
+            charge: 1,
+            mz: 0.0,
+            intensity,
+        };
+        let predicted = [
+            fragment(Kind::Y, 3, 1.0),
+            fragment(Kind::Y, 4, 0.25),
+            fragment(Kind::B, 2, 0.5),
+            fragment(Kind::B, 3, 0.1),
+        ];
+        // Observed intensities proportional to the predicted ones, except for
+        // an unmatched fragment
+        let fragments = Fragments {
+            charges: vec![1, 1, 1, 2],
+            kinds: vec![Kind::Y, Kind::Y, Kind::B, Kind::Y],
+            fragment_ordinals: vec![3, 4, 2, 3],
+            intensities: vec![4000.0, 1000.0, 2000.0, 500.0],
+            mz_calculated: vec![0.0; 4],
+            mz_experimental: vec![0.0; 4],
+        };
+        let (angle, correlation) = similarity(&predicted, &fragments);
+        assert!(angle > 0.8 && angle < 1.0, "{}", angle);
+        assert!(correlation > 0.8 && correlation < 1.0, "{}", correlation);
+
+        let fragments = Fragments {
+            charges: vec![1; 4],
+            kinds: vec![Kind::Y, Kind::Y, Kind::B, Kind::B],
+            fragment_ordinals: vec![3, 4, 2, 3],
+            intensities: vec![4000.0, 1000.0, 2000.0, 400.0],
+            mz_calculated: vec![0.0; 4],
+            mz_experimental: vec![0.0; 4],
+        };
+        let (angle, correlation) = similarity(&predicted, &fragments);
+        assert!((angle - 1.0).abs() < 1E-3, "{}", angle);
+        assert!((correlation - 1.0).abs() < 1E-3, "{}", correlation);
+
+        assert_eq!(similarity(&predicted, &Fragments::default()), (0.0, 0.0));
+    }
+}
@@ -0,0 +1,355 @@
+//! Glycopeptide search
+//!
+//! In HCD spectra, glycopeptides fragment mostly along the glycan: the
+//! spectrum contains intense oxonium ions (charged glycan fragments, e.g.
+//! HexNAc at m/z 204.087), and peptide backbone fragments that have lost
+//! (almost) the entire glycan. Glycopeptides are therefore searched as
+//! unmodified peptides, with the precursor mass reduced by the mass of each
+//! glycan composition in the glycan database:
+//!
+//! 1) Spectra without enough oxonium ions are not considered glycopeptides
+//! 2) For each glycan, the peptide backbone is searched against the
+//!    precursor mass minus the glycan mass
+//! 3) Backbone PSMs must contain a glycosylation site: a N-X-S/T sequon
+//!    (X != P) for N-glycans, or a S/T residue for O-glycans
+//!
+//! Glycan PSMs compete with the regular PSMs of the same spectrum.
+
+use crate::mass::{Tolerance, PROTON};
+use crate::peptide::Peptide;
+use crate::scoring::{merge_partitioned_features, Feature, Scorer};
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Monosaccharide residue masses
+const MONOSACCHARIDES: [(&str, f32); 5] = [
+    ("HexNAc", 203.07937),
+    ("Hex", 162.05282),
+    ("Fuc", 146.0579),
+    ("NeuAc", 291.09542),
+    ("NeuGc", 307.09033),
+];
+
+/// Common oxonium ions (m/z): HexNAc fragments, HexNAc, Hex, NeuAc-H2O, NeuAc,
+/// HexHexNAc and HexHexNAcFuc
+const OXONIUM_IONS: [f32; 10] = [
+    138.05496, 144.06552, 168.06552, 186.07608, 204.08665, 163.06009, 274.0922, 292.10275,
+    366.13947, 512.1974,
+];
+
+/// Common N-glycan compositions: paucimannose, high-mannose, hybrid and
+/// (fucosylated, sialylated) complex glycans
+const N_GLYCANS: [&str; 22] = [
+    "HexNAc(2)Hex(3)",
+    "HexNAc(2)Hex(3)Fuc(1)",
+    "HexNAc(2)Hex(4)",
+    "HexNAc(2)Hex(5)",
+    "HexNAc(2)Hex(6)",
+    "HexNAc(2)Hex(7)",
+    "HexNAc(2)Hex(8)",
+    "HexNAc(2)Hex(9)",
+    "HexNAc(2)Hex(10)",
+    "HexNAc(3)Hex(3)",
+    "HexNAc(3)Hex(4)",
+    "HexNAc(3)Hex(5)",
+    "HexNAc(3)Hex(6)",
+    "HexNAc(4)Hex(3)",
+    "HexNAc(4)Hex(3)Fuc(1)",
+    "HexNAc(4)Hex(4)",
+    "HexNAc(4)Hex(5)",
+    "HexNAc(4)Hex(5)Fuc(1)",
+    "HexNAc(4)Hex(5)NeuAc(1)",
+    "HexNAc(4)Hex(5)NeuAc(2)",
+    "HexNAc(4)Hex(5)Fuc(1)NeuAc(1)",
+    "HexNAc(4)Hex(5)Fuc(1)NeuAc(2)",
+];
+
+/// Common mucin-type O-glycan compositions
+const O_GLYCANS: [&str; 7] = [
+    "HexNAc(1)",
+    "HexNAc(1)Hex(1)",
+    "HexNAc(1)NeuAc(1)",
+    "HexNAc(1)Hex(1)NeuAc(1)",
+    "HexNAc(1)Hex(1)NeuAc(2)",
+    "HexNAc(2)Hex(1)",
+    "HexNAc(2)Hex(2)",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum GlycoMode {
+    /// N-linked glycans, on the asparagine of N-X-S/T sequons
+    N,
+    /// O-linked glycans, on serine or threonine
+    O,
+}
+
+impl GlycoMode {
+    /// Default glycan database for this glycosylation type
+    pub fn glycans(&self) -> Vec<Glycan> {
+        let compositions: &[&str] = match self {
+            GlycoMode::N => &N_GLYCANS,
+            GlycoMode::O => &O_GLYCANS,
+        };
+        compositions
+            .iter()
+            .map(|s| s.parse().expect("valid glycan composition"))
+            .collect()
+    }
+
+    /// Does `peptide` contain a glycosylation site?
+    pub fn has_site(&self, peptide: &Peptide) -> bool {
+        match self {
+            GlycoMode::N => peptide
+                .sequence
+                .windows(3)
+                .any(|w| w[0] == b'N' && w[1] != b'P' && (w[2] == b'S' || w[2] == b'T')),
+            GlycoMode::O => peptide
+                .sequence
+                .iter()
+                .any(|residue| *residue == b'S' || *residue == b'T'),
+        }
+    }
+}
+
+/// A glycan composition, e.g. `HexNAc(4)Hex(5)Fuc(1)`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Glycan {
+    pub composition: String,
+    pub mass: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidGlycan(pub String);
+
+impl std::fmt::Display for InvalidGlycan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid glycan composition `{}`, expected e.g. `HexNAc(2)Hex(5)` using {}",
+            self.0,
+            MONOSACCHARIDES
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidGlycan {}
+
+impl FromStr for Glycan {
+    type Err = InvalidGlycan;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidGlycan(s.into());
+        let rest = s.trim().strip_suffix(')').ok_or_else(invalid)?;
+        let mut mass = 0.0;
+        for unit in rest.split(')') {
+            let (name, count) = unit.split_once('(').ok_or_else(invalid)?;
+            let count = count.parse::<u8>().map_err(|_| invalid())?;
+            let (_, residue) = MONOSACCHARIDES
+                .iter()
+                .find(|(monosaccharide, _)| *monosaccharide == name)
+                .ok_or_else(invalid)?;
+            mass += residue * count as f32;
+        }
+        Ok(Glycan {
+            composition: s.trim().into(),
+            mass,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GlycoSettings {
+    pub mode: GlycoMode,
+    pub glycans: Vec<Glycan>,
+    /// Oxonium ion m/z values used to recognize glycopeptide spectra
+    pub oxonium_ions: Vec<f32>,
+    /// Minimum number of matched oxonium ions for a spectrum to be searched
+    /// for glycopeptides
+    pub min_oxonium_ions: usize,
+}
+
+impl GlycoSettings {
+    pub fn new(mode: GlycoMode) -> Self {
+        Self {
+            mode,
+            glycans: mode.glycans(),
+            oxonium_ions: OXONIUM_IONS.to_vec(),
+            min_oxonium_ions: 2,
+        }
+    }
+
+    /// Number of oxonium ions found in `query`
+    pub fn oxonium_ions(&self, query: &ProcessedSpectrum, tolerance: Tolerance) -> usize {
+        self.oxonium_ions
+            .iter()
+            .filter(|mz| {
+                select_most_intense_peak(&query.peaks, *mz - PROTON, tolerance, None).is_some()
+            })
+            .count()
+    }
+
+    /// Is `query` likely to be a glycopeptide spectrum?
+    pub fn is_glyco(&self, query: &ProcessedSpectrum, tolerance: Tolerance) -> bool {
+        query.level == 2 && self.oxonium_ions(query, tolerance) >= self.min_oxonium_ions
+    }
+
+    /// Score `query` as both a regular and - if it contains oxonium ions - a
+    /// glycopeptide spectrum, returning the best `scorer.report_psms` PSMs
+    pub fn score(&self, scorer: &Scorer, query: &ProcessedSpectrum) -> Vec<Feature> {
+        let mut features = scorer.score(query);
+        if !self.is_glyco(query, scorer.fragment_tol) {
+            return features;
+        }
+        let precursor = match query.precursors.first() {
+            Some(precursor) => precursor,
+            None => return features,
+        };
+        let charges = match precursor.charge {
+            Some(charge) => charge..=charge,
+            None => scorer.min_precursor_charge..=scorer.max_precursor_charge,
+        };
+
+        for charge in charges {
+            for glycan in &self.glycans {
+                // Search the peptide backbone: the precursor without glycan
+                let mut backbone = query.clone();
+                backbone.precursors[0].mz = precursor.mz - glycan.mass / charge as f32;
+                backbone.precursors[0].charge = Some(charge);
+                features.extend(
+                    scorer
+                        .score(&backbone)
+                        .into_iter()
+                        .filter(|feat| self.mode.has_site(&scorer.db[feat.peptide_idx]))
+                        .map(|mut feat| {
+                            feat.expmass += glycan.mass;
+                            feat.calcmass += glycan.mass;
+                            feat.glycan = Some(glycan.composition.clone());
+                            feat
+                        }),
+                );
+            }
+        }
+        // Glycan and regular PSMs compete, exactly like PSMs of different
+        // database partitions
+        merge_partitioned_features(features, scorer.report_psms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::ion_series::{IonSeries, Kind};
+    use crate::scoring::IsotopeErrorMode;
+    use crate::spectrum::{Peak, Precursor};
+
+    #[test]
+    fn glycan_composition() {
+        let glycan: Glycan = "HexNAc(2)Hex(5)".parse().unwrap();
+        assert!((glycan.mass - 1216.4229).abs() < 1E-3);
+        let glycan: Glycan = "HexNAc(4)Hex(5)Fuc(1)NeuAc(2)".parse().unwrap();
+        assert!((glycan.mass - 2350.8304).abs() < 1E-3);
+        assert!("HexNAc(2)Foo(1)".parse::<Glycan>().is_err());
+        assert!("HexNAc2".parse::<Glycan>().is_err());
+        assert_eq!(GlycoMode::N.glycans().len(), N_GLYCANS.len());
+        assert_eq!(GlycoMode::O.glycans().len(), O_GLYCANS.len());
+    }
+
+    #[test]
+    fn glycopeptide() {
+        let fasta = Fasta::parse(
+            ">sp|A\nMEWKLEQSMREQALLKAQLTQLKGAVLRVNGTDLTPEWKDAR".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "VNGTDLTPEWK")
+            .unwrap();
+        assert!(GlycoMode::N.has_site(peptide));
+        assert!(!GlycoMode::N.has_site(
+            db.peptides
+                .iter()
+                .find(|p| p.to_string() == "EQALLK")
+                .unwrap()
+        ));
+
+        let settings = GlycoSettings::new(GlycoMode::N);
+        let glycan: Glycan = "HexNAc(4)Hex(5)Fuc(1)".parse().unwrap();
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| ion.monoisotopic_mass)
+            .chain(settings.oxonium_ions.iter().map(|mz| mz - PROTON))
+            .map(|mass| Peak {
+                mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "glyco".into(),
+            precursors: vec![Precursor {
+                mz: (peptide.monoisotopic + glycan.mass) / 3.0 + PROTON,
+                charge: Some(3),
+                ..Default::default()
+            }],
+            total_ion_current: peaks.len() as f32,
+            peaks,
+            ..Default::default()
+        };
+        assert!(settings.is_glyco(&query, Tolerance::Ppm(-10.0, 10.0)));
+
+        let scorer = Scorer {
+            db: &db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 4,
+            min_fragment_coverage: 0.0,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            isotope_error_mode: IsotopeErrorMode::Discrete,
+            min_precursor_charge: 2,
+            max_precursor_charge: 4,
+            max_fragment_charge: Some(1),
+            min_fragment_mass: 0.0,
+            max_fragment_mass: 2000.0,
+            chimera: false,
+            report_psms: 1,
+            wide_window: false,
+            annotate_matches: false,
+            open_search: false,
+            prefilter: None,
+            mobility_filter: None,
+            neutral_losses: &[],
+            localize: false,
+            feature_hook: None,
+        };
+        // Without the glycan, the precursor matches no peptide
+        assert!(scorer.score(&query).is_empty());
+
+        let features = settings.score(&scorer, &query);
+        assert_eq!(features.len(), 1);
+        assert_eq!(db[features[0].peptide_idx].to_string(), "VNGTDLTPEWK");
+        assert_eq!(features[0].glycan.as_deref(), Some("HexNAc(4)Hex(5)Fuc(1)"));
+        assert!((features[0].calcmass - features[0].expmass).abs() < 1E-2);
+    }
+}
@@ -0,0 +1,751 @@
+//! Preliminary scoring on the GPU, with [wgpu](https://wgpu.rs) (Vulkan,
+//! Metal, DirectX 12 or OpenGL)
+//!
+//! Counting the fragments of each candidate peptide that match the peaks of a
+//! spectrum dominates the runtime of open searches and searches of large
+//! databases. A [`FragmentMatcher`] uploads the fragment index to the GPU once,
+//! and counts matches for batches of spectra: each GPU thread searches the
+//! fragment buckets for one peak (at one fragment charge) of one precursor
+//! window. Everything else - candidate selection, full scoring of the best
+//! candidates - runs on the CPU, exactly as [`Scorer::score`] does, so that
+//! both report the same PSMs.
+//!
+//! Precursor windows with more candidate peptides than fit in a batch, and
+//! batches that fail on the GPU, are searched on the CPU instead.
+
+use crate::database::{IndexedDatabase, IndexedQuery, PeptideIx};
+use crate::par::prelude::*;
+use crate::scoring::{Feature, Matching, Scorer};
+use crate::spectrum::ProcessedSpectrum;
+use std::borrow::{Borrow, Cow};
+use std::sync::atomic::{AtomicBool, Ordering};
+use wgpu::util::DeviceExt;
+
+/// Spectra whose precursor windows are recorded, counted and scored together
+const SPECTRA_BATCH: usize = 4096;
+
+/// Most counters (candidate peptides of all windows) in a batch
+const MAX_COUNTERS: u64 = 1 << 24;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Count the fragments matching each fragment query, for each candidate
+/// peptide of its precursor window. Fragments are `(peptide index, m/z bits)`
+const COUNT_SHADER: &str = r#"
+struct Query {
+    lo: f32,
+    hi: f32,
+    window: u32,
+    _pad: u32,
+}
+
+struct Window {
+    peptide_lo: u32,
+    peptide_hi: u32,
+    offset: u32,
+    _pad: u32,
+}
+
+struct Params {
+    queries: u32,
+    pages: u32,
+    bucket_size: u32,
+    fragments: u32,
+}
+
+@group(0) @binding(0) var<storage, read> fragments: array<vec2<u32>>;
+@group(0) @binding(1) var<storage, read> min_value: array<f32>;
+@group(0) @binding(2) var<storage, read> queries: array<Query>;
+@group(0) @binding(3) var<storage, read> windows: array<Window>;
+@group(0) @binding(4) var<storage, read_write> counts: array<atomic<u32>>;
+@group(0) @binding(5) var<uniform> params: Params;
+
+// Number of pages whose lowest m/z is below `mz` (or equal to it, if `inclusive`)
+fn pages_below(mz: f32, inclusive: bool) -> u32 {
+    var lo = 0u;
+    var hi = params.pages;
+    while (lo < hi) {
+        let mid = (lo + hi) / 2u;
+        let value = min_value[mid];
+        if (value < mz || (inclusive && value == mz)) {
+            lo = mid + 1u;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let idx = id.y * groups.x * 64u + id.x;
+    if (idx >= params.queries) {
+        return;
+    }
+    let query = queries[idx];
+    let window = windows[query.window];
+
+    // Fragments are sorted by m/z across pages: the page before the first one
+    // with a lowest m/z within bounds can also hold matches
+    let first = max(pages_below(query.lo, false), 1u) - 1u;
+    let last = pages_below(query.hi, true);
+    for (var page = first; page < last; page++) {
+        let start = page * params.bucket_size;
+        let end = min(start + params.bucket_size, params.fragments);
+
+        // ... and by peptide index within a page
+        var lo = start;
+        var hi = end;
+        while (lo < hi) {
+            let mid = (lo + hi) / 2u;
+            if (fragments[mid].x < window.peptide_lo) {
+                lo = mid + 1u;
+            } else {
+                hi = mid;
+            }
+        }
+        for (var i = lo; i < end; i++) {
+            let fragment = fragments[i];
+            if (fragment.x >= window.peptide_hi) {
+                break;
+            }
+            let mz = bitcast<f32>(fragment.y);
+            if (mz >= query.lo && mz <= query.hi) {
+                atomicAdd(&counts[window.offset + fragment.x - window.peptide_lo], 1u);
+            }
+        }
+    }
+}
+"#;
+
+/// Gather the non-zero counters, as `(counter, count)` pairs in any order
+const COMPACT_SHADER: &str = r#"
+@group(0) @binding(0) var<storage, read> counts: array<u32>;
+@group(0) @binding(1) var<storage, read_write> total: atomic<u32>;
+@group(0) @binding(2) var<storage, read_write> matches: array<vec2<u32>>;
+@group(0) @binding(3) var<uniform> counters: vec4<u32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>, @builtin(num_workgroups) groups: vec3<u32>) {
+    let idx = id.y * groups.x * 64u + id.x;
+    if (idx >= counters.x) {
+        return;
+    }
+    let count = counts[idx];
+    if (count > 0u) {
+        matches[atomicAdd(&total, 1u)] = vec2<u32>(idx, count);
+    }
+}
+"#;
+
+/// Fragment queries of a precursor window of a spectrum
+pub(crate) struct Window {
+    /// Matching fragments have `peptide_lo <= peptide_index < peptide_hi`
+    peptide_lo: u32,
+    peptide_hi: u32,
+    /// m/z bounds of the fragments matching each peak, at each fragment charge
+    bounds: Vec<(f32, f32)>,
+}
+
+impl Window {
+    pub(crate) fn new(
+        query: &ProcessedSpectrum,
+        candidates: &IndexedQuery,
+        max_fragment_charge: u8,
+    ) -> Self {
+        let peptides = candidates.peptide_range();
+        let bounds = query
+            .peaks
+            .iter()
+            .flat_map(|peak| {
+                (1..max_fragment_charge)
+                    .map(move |charge| candidates.fragment_bounds(peak.mass * charge as f32))
+            })
+            .collect();
+        Window {
+            peptide_lo: peptides.start,
+            peptide_hi: peptides.end.max(peptides.start),
+            bounds,
+        }
+    }
+
+    fn peptides(&self) -> u64 {
+        (self.peptide_hi - self.peptide_lo) as u64
+    }
+}
+
+/// Matched fragments of a [`Window`]
+pub(crate) enum Counts {
+    /// Number of matched fragments of each candidate peptide with a match
+    Gpu(Vec<(PeptideIx, u16)>),
+    /// Not counted on the GPU: search the fragment index on the CPU
+    Cpu,
+}
+
+/// Fragment index of an [`IndexedDatabase`], uploaded to a GPU
+pub struct FragmentMatcher {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    name: String,
+    count: wgpu::ComputePipeline,
+    compact: wgpu::ComputePipeline,
+    fragments: wgpu::Buffer,
+    min_value: wgpu::Buffer,
+    /// Number of fragments and buckets, and the bucket size
+    len: u32,
+    pages: u32,
+    bucket_size: u32,
+    /// Address and length of the uploaded fragments, and the database
+    /// fingerprint, so that the matcher is only used with its own database
+    database: (usize, usize, u64),
+    max_counters: u64,
+    max_queries: u64,
+    max_workgroups: u32,
+    /// Set once a batch failed on the GPU, to only warn once
+    failed: AtomicBool,
+}
+
+impl FragmentMatcher {
+    /// Upload the fragment index of `db` to the highest performance GPU
+    /// found. Software adapters, e.g. llvmpipe, are only used if `software`
+    /// is set, as they are slower than searching on the CPU.
+    ///
+    /// Returns `None` if there is no (suitable) GPU, or the index is too large
+    /// for a single buffer of the GPU
+    pub fn new(db: &IndexedDatabase, software: bool) -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        }))
+        .or_else(|| {
+            log::info!("gpu: no adapter found");
+            None
+        })?;
+        let info = adapter.get_info();
+        let name = format!("{} ({:?})", info.name, info.backend);
+        if info.device_type == wgpu::DeviceType::Cpu && !software {
+            log::info!("gpu: skipping software adapter {}", name);
+            return None;
+        }
+
+        let limits = adapter.limits();
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("sage"),
+                required_features: wgpu::Features::empty(),
+                required_limits: limits.clone(),
+            },
+            None,
+        ))
+        .map_err(|err| log::warn!("gpu: failed to open {}: {}", name, err))
+        .ok()?;
+
+        let max_binding =
+            (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        let size = (db.fragments.len() * std::mem::size_of::<[u32; 2]>()) as u64;
+        if size > max_binding || db.fragments.len() > u32::MAX as usize {
+            log::warn!(
+                "gpu: the fragment index ({} MiB) exceeds the largest buffer of {} ({} MiB)",
+                size >> 20,
+                name,
+                max_binding >> 20
+            );
+            return None;
+        }
+
+        // SAFETY: fragments are `#[repr(C)]` pairs of u32 and f32, which the
+        // shader reads as pairs of u32
+        let fragments: &[u8] = unsafe {
+            std::slice::from_raw_parts(db.fragments.as_ptr() as *const u8, size as usize)
+        };
+        let fragments = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("fragments"),
+            contents: non_empty(fragments),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let min_value = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("min_value"),
+            contents: non_empty(bytemuck::cast_slice(&db.min_value)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let pipeline = |label, source: &'static str| {
+            let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(label),
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(source)),
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: None,
+                module: &module,
+                entry_point: "main",
+            })
+        };
+        let count = pipeline("count", COUNT_SHADER);
+        let compact = pipeline("compact", COMPACT_SHADER);
+
+        log::info!(
+            "gpu: counting fragment matches on {}, {} MiB fragment index",
+            name,
+            size >> 20
+        );
+        Some(FragmentMatcher {
+            name,
+            count,
+            compact,
+            fragments,
+            min_value,
+            len: db.fragments.len() as u32,
+            pages: db.min_value.len() as u32,
+            bucket_size: db.bucket_size as u32,
+            database: (
+                db.fragments.as_ptr() as usize,
+                db.fragments.len(),
+                db.fingerprint,
+            ),
+            // Matches are pairs of u32
+            max_counters: MAX_COUNTERS.min(max_binding / 8),
+            max_queries: (limits.max_compute_workgroups_per_dimension as u64
+                * WORKGROUP_SIZE as u64)
+                .min(max_binding / 16),
+            max_workgroups: limits.max_compute_workgroups_per_dimension,
+            failed: AtomicBool::new(false),
+            device,
+            queue,
+        })
+    }
+
+    /// Name and backend of the GPU
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Was the fragment index of `db` uploaded by this matcher?
+    pub fn matches(&self, db: &IndexedDatabase) -> bool {
+        self.database
+            == (
+                db.fragments.as_ptr() as usize,
+                db.fragments.len(),
+                db.fingerprint,
+            )
+    }
+
+    /// Score each of `queries` with `scorer`, counting matched fragments on the
+    /// GPU: the same PSMs as [`Scorer::score`], in the same order. Queries are
+    /// scored on the CPU if `scorer` searches a different database
+    ///
+    /// # Panics
+    /// If a query is not an MS2 spectrum
+    pub fn score<Q>(&self, scorer: &Scorer, queries: &[Q]) -> Vec<Vec<Feature>>
+    where
+        Q: Borrow<ProcessedSpectrum> + Sync,
+    {
+        if !self.matches(scorer.db) {
+            return queries
+                .par_iter()
+                .map(|query| scorer.score(query.borrow()))
+                .collect();
+        }
+
+        let mut features = Vec::with_capacity(queries.len());
+        for queries in queries.chunks(SPECTRA_BATCH) {
+            let windows = queries
+                .par_iter()
+                .map(|query| scorer.record(query.borrow()))
+                .collect::<Vec<_>>();
+            let mut counts = self.count(windows.iter().flatten()).into_iter();
+            let counts = windows
+                .iter()
+                .map(|windows| counts.by_ref().take(windows.len()).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            features.par_extend(queries.par_iter().zip(counts).map(|(query, counts)| {
+                scorer.score_with(query.borrow(), &mut Matching::Replay(counts.into_iter()))
+            }));
+        }
+        features
+    }
+
+    /// Count the matched fragments of each window, in batches
+    fn count<'a, I>(&self, windows: I) -> Vec<Counts>
+    where
+        I: Iterator<Item = &'a Window>,
+    {
+        let mut counts = Vec::new();
+        let mut batch: Vec<(usize, &Window)> = Vec::new();
+        let (mut counters, mut queries) = (0, 0);
+        for window in windows {
+            let (peptides, bounds) = (window.peptides(), window.bounds.len() as u64);
+            if peptides == 0 || bounds == 0 {
+                counts.push(Counts::Gpu(Vec::new()));
+                continue;
+            }
+            if peptides > self.max_counters || bounds > self.max_queries {
+                counts.push(Counts::Cpu);
+                continue;
+            }
+            if counters + peptides > self.max_counters || queries + bounds > self.max_queries {
+                self.count_batch(&batch, &mut counts);
+                batch.clear();
+                counters = 0;
+                queries = 0;
+            }
+            batch.push((counts.len(), window));
+            counts.push(Counts::Cpu);
+            counters += peptides;
+            queries += bounds;
+        }
+        self.count_batch(&batch, &mut counts);
+        counts
+    }
+
+    /// Count the matched fragments of a batch of `(index in counts, window)`,
+    /// or leave them to be counted on the CPU, if the GPU failed
+    fn count_batch(&self, batch: &[(usize, &Window)], counts: &mut [Counts]) {
+        if batch.is_empty() {
+            return;
+        }
+        self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let matches = self.dispatch(batch);
+        let validation = pollster::block_on(self.device.pop_error_scope());
+        let memory = pollster::block_on(self.device.pop_error_scope());
+        let matches = match (matches, validation.or(memory)) {
+            (Some(matches), None) => matches,
+            (_, error) => {
+                if !self.failed.swap(true, Ordering::Relaxed) {
+                    let error = error.map_or_else(|| "device lost".into(), |e| e.to_string());
+                    log::warn!(
+                        "gpu: counting matches on {} failed, falling back to the CPU: {}",
+                        self.name,
+                        error
+                    );
+                }
+                return;
+            }
+        };
+
+        // Counters of each window are contiguous, and windows are in order
+        let mut offsets = Vec::with_capacity(batch.len());
+        let mut offset = 0;
+        for (_, window) in batch {
+            offsets.push(offset);
+            offset += window.peptides() as u32;
+        }
+        let mut matched = vec![Vec::new(); batch.len()];
+        for (counter, count) in matches {
+            let idx = offsets.partition_point(|&offset| offset <= counter) - 1;
+            let peptide = batch[idx].1.peptide_lo + counter - offsets[idx];
+            matched[idx].push((PeptideIx(peptide), count.min(u16::MAX as u32) as u16));
+        }
+        for ((idx, _), mut matched) in batch.iter().zip(matched) {
+            matched.sort_unstable();
+            counts[*idx] = Counts::Gpu(matched);
+        }
+    }
+
+    /// Run the shaders on a batch of windows, and read back the non-zero
+    /// `(counter, count)` pairs
+    fn dispatch(&self, batch: &[(usize, &Window)]) -> Option<Vec<(u32, u32)>> {
+        let mut windows = Vec::with_capacity(batch.len());
+        let mut queries = Vec::new();
+        let mut offset = 0;
+        for (idx, (_, window)) in batch.iter().enumerate() {
+            windows.push([window.peptide_lo, window.peptide_hi, offset, 0]);
+            offset += window.peptides() as u32;
+            queries.extend(
+                window
+                    .bounds
+                    .iter()
+                    .map(|(lo, hi)| [lo.to_bits(), hi.to_bits(), idx as u32, 0]),
+            );
+        }
+        let counters = offset as u64;
+
+        let buffer = |label, contents: &[u8], usage| {
+            self.device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents,
+                    usage,
+                })
+        };
+        let empty = |label, size: u64, usage| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        use wgpu::BufferUsages as Usage;
+        let query_buffer = buffer("queries", bytemuck::cast_slice(&queries), Usage::STORAGE);
+        let window_buffer = buffer("windows", bytemuck::cast_slice(&windows), Usage::STORAGE);
+        let params = [queries.len() as u32, self.pages, self.bucket_size, self.len];
+        let params = buffer("params", bytemuck::cast_slice(&params), Usage::UNIFORM);
+        let count_buffer = empty("counts", counters * 4, Usage::STORAGE | Usage::COPY_DST);
+        let total = empty(
+            "total",
+            4,
+            Usage::STORAGE | Usage::COPY_SRC | Usage::COPY_DST,
+        );
+        let match_buffer = empty("matches", counters * 8, Usage::STORAGE | Usage::COPY_SRC);
+        let limit = [counters as u32, 0, 0, 0];
+        let limit = buffer("counters", bytemuck::cast_slice(&limit), Usage::UNIFORM);
+
+        let bind = |pipeline: &wgpu::ComputePipeline, buffers: &[&wgpu::Buffer]| {
+            let entries = buffers
+                .iter()
+                .enumerate()
+                .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                    binding: binding as u32,
+                    resource: buffer.as_entire_binding(),
+                })
+                .collect::<Vec<_>>();
+            self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &entries,
+            })
+        };
+        let count_group = bind(
+            &self.count,
+            &[
+                &self.fragments,
+                &self.min_value,
+                &query_buffer,
+                &window_buffer,
+                &count_buffer,
+                &params,
+            ],
+        );
+        let compact_group = bind(
+            &self.compact,
+            &[&count_buffer, &total, &match_buffer, &limit],
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.clear_buffer(&count_buffer, 0, None);
+        encoder.clear_buffer(&total, 0, None);
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+            pass.set_pipeline(&self.count);
+            pass.set_bind_group(0, &count_group, &[]);
+            let (x, y) = self.workgroups(queries.len() as u64);
+            pass.dispatch_workgroups(x, y, 1);
+            pass.set_pipeline(&self.compact);
+            pass.set_bind_group(0, &compact_group, &[]);
+            let (x, y) = self.workgroups(counters);
+            pass.dispatch_workgroups(x, y, 1);
+        }
+        self.queue.submit(Some(encoder.finish()));
+
+        // Only read back as many matches as were found
+        let total = u32::from_le_bytes(self.read(&total, 4)?.try_into().ok()?) as u64;
+        if total == 0 {
+            return Some(Vec::new());
+        }
+        let matches = self.read(&match_buffer, total * 8)?;
+        Some(
+            bytemuck::cast_slice::<u8, u32>(&matches)
+                .chunks_exact(2)
+                .map(|pair| (pair[0], pair[1]))
+                .collect(),
+        )
+    }
+
+    /// Workgroups to dispatch for `invocations` invocations, as a 2D grid if
+    /// there are more than the largest dimension allows
+    fn workgroups(&self, invocations: u64) -> (u32, u32) {
+        let groups = ((invocations + WORKGROUP_SIZE as u64 - 1) / WORKGROUP_SIZE as u64).max(1);
+        let x = groups.min(self.max_workgroups as u64);
+        (x as u32, ((groups + x - 1) / x) as u32)
+    }
+
+    /// Copy the first `size` bytes of `buffer` back from the GPU
+    fn read(&self, buffer: &wgpu::Buffer, size: u64) -> Option<Vec<u8>> {
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("staging"),
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        staging
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let _ = sender.send(result);
+            });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver.recv().ok()?.ok()?;
+        let bytes = staging.slice(..).get_mapped_range().to_vec();
+        staging.unmap();
+        Some(bytes)
+    }
+}
+
+/// wgpu does not allow empty buffers
+fn non_empty(bytes: &[u8]) -> &[u8] {
+    match bytes.is_empty() {
+        true => &[0; 8],
+        false => bytes,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Builder, EnzymeBuilder};
+    use crate::fasta::Fasta;
+    use crate::ion_series::{IonSeries, Kind};
+    use crate::mass::{Tolerance, PROTON};
+    use crate::scoring::IsotopeErrorMode;
+    use crate::spectrum::{Peak, Precursor};
+
+    /// Spectra of b and y ions of some peptides of `db`, with noise peaks
+    fn spectra(db: &IndexedDatabase) -> Vec<ProcessedSpectrum> {
+        let mut seed = 17u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            (seed % 10_000) as f32 / 10_000.0
+        };
+        db.peptides
+            .iter()
+            .filter(|peptide| !peptide.decoy)
+            .step_by(3)
+            .enumerate()
+            .map(|(idx, peptide)| {
+                let mut masses = [Kind::B, Kind::Y]
+                    .into_iter()
+                    .flat_map(|kind| IonSeries::new(peptide, kind))
+                    .map(|ion| ion.monoisotopic_mass)
+                    .collect::<Vec<_>>();
+                masses.retain(|_| random() < 0.7);
+                masses.extend((0..30).map(|_| 150.0 + random() * 1500.0));
+                let mut peaks = masses
+                    .into_iter()
+                    .map(|mass| Peak {
+                        mass,
+                        intensity: 1.0 + random(),
+                    })
+                    .collect::<Vec<_>>();
+                peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+                ProcessedSpectrum {
+                    level: 2,
+                    id: format!("scan={}", idx),
+                    file_id: 0,
+                    scan_start_time: 0.0,
+                    ion_injection_time: 0.0,
+                    precursors: vec![Precursor {
+                        mz: peptide.monoisotopic / 3.0 + PROTON,
+                        charge: (idx % 2 == 0).then_some(3),
+                        ..Default::default()
+                    }],
+                    peaks,
+                    total_ion_current: 0.0,
+                    noise: Vec::new(),
+                    ion_mobility: None,
+                    compensation_voltage: None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gpu_matches_cpu() {
+        let fasta = Fasta::parse(
+            include_str!("../../../tests/Q99536.fasta").into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(2),
+                semi_enzymatic: Some(true),
+                ..Default::default()
+            }),
+            bucket_size: Some(64),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let matcher = match FragmentMatcher::new(&db, true) {
+            Some(matcher) => matcher,
+            None => {
+                eprintln!("no GPU adapter, skipping");
+                return;
+            }
+        };
+        let spectra = spectra(&db);
+
+        let closed = Scorer {
+            report_psms: 3,
+            min_matched_peaks: 2,
+            ..Scorer::new(
+                &db,
+                Tolerance::Ppm(-20.0, 20.0),
+                Tolerance::Ppm(-10.0, 10.0),
+            )
+        };
+        let isotopes = Scorer {
+            min_isotope_err: -1,
+            max_isotope_err: 2,
+            ..closed
+        };
+        let widened = Scorer {
+            isotope_error_mode: IsotopeErrorMode::Widen,
+            ..isotopes
+        };
+        let open = Scorer {
+            precursor_tol: Tolerance::Da(-500.0, 100.0),
+            fragment_tol: Tolerance::Da(-0.02, 0.02),
+            open_search: true,
+            ..closed
+        };
+        let chimera = Scorer {
+            chimera: true,
+            wide_window: true,
+            ..closed
+        };
+        // PSM ids are unique across searches
+        let psms = |features: Vec<Vec<Feature>>| {
+            features
+                .into_iter()
+                .flatten()
+                .map(|feature| {
+                    format!(
+                        "{:?}",
+                        Feature {
+                            psm_id: 0,
+                            ..feature
+                        }
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        for scorer in [closed, isotopes, widened, open, chimera] {
+            let cpu = spectra
+                .iter()
+                .map(|query| scorer.score(query))
+                .collect::<Vec<_>>();
+            let gpu = matcher.score(&scorer, &spectra);
+            assert_eq!(gpu.len(), cpu.len());
+            let (cpu, gpu) = (psms(cpu), psms(gpu));
+            assert!(!cpu.is_empty());
+            assert_eq!(gpu, cpu);
+        }
+        assert!(!matcher.failed.load(Ordering::Relaxed));
+    }
+}
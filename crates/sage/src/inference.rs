@@ -0,0 +1,292 @@
+//! Protein inference by parsimony
+//!
+//! Peptides identified at the configured FDR thresholds are explained by as
+//! few protein groups as possible (Occam's razor), found with a greedy set
+//! cover: the group explaining the most peptides not yet explained by another
+//! group is selected first.
+//!
+//! - Proteins identified by exactly the same peptides are indistinguishable,
+//!   and reported together as a single group
+//! - Proteins whose peptides are all explained by selected groups are not
+//!   reported
+//! - A peptide shared by several groups is a razor peptide of the group that
+//!   was selected first, i.e. the one with the most evidence
+//!
+//! Targets and decoys are grouped separately, and protein groups are assigned
+//! q-values by target-decoy competition on the score of their best PSM
+
+use crate::database::IndexedDatabase;
+use crate::scoring::Feature;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ProteinInferenceSettings {
+    /// Maximum spectrum-level q-value of PSMs used as evidence
+    pub spectrum_fdr: f32,
+    /// Maximum peptide-level q-value of PSMs used as evidence
+    pub peptide_fdr: f32,
+}
+
+impl Default for ProteinInferenceSettings {
+    fn default() -> Self {
+        Self {
+            spectrum_fdr: 0.01,
+            peptide_fdr: 0.01,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProteinGroup {
+    /// Indistinguishable proteins, sorted by accession: the first one is the
+    /// leading protein of the group
+    pub proteins: Vec<Arc<String>>,
+    pub decoy: bool,
+    /// Number of distinct peptide sequences of the proteins of this group
+    pub peptides: usize,
+    /// Number of peptides not shared with any protein outside this group
+    pub unique_peptides: usize,
+    /// Number of peptides attributed to this group: its unique peptides, and
+    /// the shared peptides it explains best (razor peptides)
+    pub razor_peptides: usize,
+    /// Number of PSMs of unique and razor peptides
+    pub psms: usize,
+    /// Best discriminant score of the PSMs of unique and razor peptides
+    pub score: f32,
+    pub q_value: f32,
+}
+
+/// A distinct peptide sequence, and its passing PSMs
+struct Evidence {
+    proteins: Vec<Arc<String>>,
+    score: f32,
+    psms: usize,
+}
+
+impl ProteinInferenceSettings {
+    /// Group the proteins of rank-1 PSMs passing the spectrum- and peptide-level FDR
+    /// thresholds, and assign q-values to these groups. Peptide-level q-values
+    /// must have been assigned.
+    ///
+    /// Groups are returned by decreasing score
+    pub fn infer(&self, db: &IndexedDatabase, features: &[Feature]) -> Vec<ProteinGroup> {
+        let mut peptides: FnvHashMap<(&[u8], bool), Evidence> = FnvHashMap::default();
+        for feat in features
+            .iter()
+            .filter(|feat| feat.rank == 1)
+            .filter(|feat| feat.spectrum_q <= self.spectrum_fdr)
+            .filter(|feat| feat.peptide_q <= self.peptide_fdr)
+        {
+            let peptide = &db[feat.peptide_idx];
+            let evidence = peptides
+                .entry((&peptide.sequence, peptide.decoy))
+                .or_insert_with(|| Evidence {
+                    proteins: peptide.proteins.clone(),
+                    score: f32::MIN,
+                    psms: 0,
+                });
+            evidence.score = evidence.score.max(feat.discriminant_score);
+            evidence.psms += 1;
+        }
+
+        // Sort peptides, so that groups don't depend on hashing order
+        let mut peptides = peptides.into_iter().collect::<Vec<_>>();
+        peptides.sort_by_key(|(key, _)| *key);
+        let (decoys, targets): (Vec<_>, Vec<_>) =
+            peptides.into_iter().partition(|((_, decoy), _)| *decoy);
+
+        let mut groups = parsimony(targets.into_iter().map(|(_, e)| e).collect(), false);
+        groups.extend(parsimony(
+            decoys.into_iter().map(|(_, e)| e).collect(),
+            true,
+        ));
+        q_values(&mut groups);
+        groups
+    }
+}
+
+/// Greedy set cover of `peptides` by groups of indistinguishable proteins
+fn parsimony(peptides: Vec<Evidence>, decoy: bool) -> Vec<ProteinGroup> {
+    let mut proteins: FnvHashMap<&Arc<String>, Vec<usize>> = FnvHashMap::default();
+    for (idx, peptide) in peptides.iter().enumerate() {
+        for protein in &peptide.proteins {
+            let ids = proteins.entry(protein).or_default();
+            if ids.last() != Some(&idx) {
+                ids.push(idx);
+            }
+        }
+    }
+
+    let mut indistinguishable: FnvHashMap<Vec<usize>, Vec<Arc<String>>> = FnvHashMap::default();
+    for (protein, ids) in proteins {
+        indistinguishable
+            .entry(ids)
+            .or_default()
+            .push(protein.clone());
+    }
+
+    // Candidate groups, by decreasing evidence - ties of the set cover are
+    // resolved in this order
+    let mut candidates = indistinguishable
+        .into_iter()
+        .map(|(ids, mut members)| {
+            members.sort();
+            let score: f32 = ids.iter().map(|&idx| peptides[idx].score).sum();
+            (ids, members, score)
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| {
+        b.0.len()
+            .cmp(&a.0.len())
+            .then_with(|| b.2.total_cmp(&a.2))
+            .then_with(|| a.1.cmp(&b.1))
+    });
+
+    // Lazy greedy set cover: the number of peptides a candidate would newly
+    // explain can only decrease, so the count stored in the heap is an upper
+    // bound, which is updated when the candidate reaches the top
+    let mut explained = vec![false; peptides.len()];
+    let mut heap = candidates
+        .iter()
+        .enumerate()
+        .map(|(idx, (ids, _, _))| (ids.len(), Reverse(idx)))
+        .collect::<BinaryHeap<_>>();
+    let mut groups = Vec::new();
+    while let Some((count, Reverse(idx))) = heap.pop() {
+        let (ids, members, _) = &candidates[idx];
+        let razor = ids
+            .iter()
+            .copied()
+            .filter(|&id| !explained[id])
+            .collect::<Vec<_>>();
+        if razor.is_empty() {
+            continue;
+        }
+        if razor.len() < count {
+            heap.push((razor.len(), Reverse(idx)));
+            continue;
+        }
+
+        let unique_peptides = ids
+            .iter()
+            .filter(|&&id| {
+                peptides[id]
+                    .proteins
+                    .iter()
+                    .all(|protein| members.binary_search(protein).is_ok())
+            })
+            .count();
+        for &id in &razor {
+            explained[id] = true;
+        }
+        groups.push(ProteinGroup {
+            proteins: members.clone(),
+            decoy,
+            peptides: ids.len(),
+            unique_peptides,
+            razor_peptides: razor.len(),
+            psms: razor.iter().map(|&id| peptides[id].psms).sum(),
+            score: razor
+                .iter()
+                .map(|&id| peptides[id].score)
+                .fold(f32::MIN, f32::max),
+            q_value: 1.0,
+        });
+    }
+    groups
+}
+
+/// Assign q-values to protein groups by target-decoy competition, sorting them
+/// by decreasing score. Returns the number of target groups at 1% FDR
+pub fn q_values(groups: &mut [ProteinGroup]) -> usize {
+    groups.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let (mut targets, mut decoys) = (0usize, 0usize);
+    for group in groups.iter_mut() {
+        match group.decoy {
+            true => decoys += 1,
+            false => targets += 1,
+        }
+        group.q_value = (decoys as f32 / targets.max(1) as f32).min(1.0);
+    }
+    let mut q_min = 1.0f32;
+    for group in groups.iter_mut().rev() {
+        q_min = q_min.min(group.q_value);
+        group.q_value = q_min;
+    }
+    groups
+        .iter()
+        .filter(|group| !group.decoy && group.q_value <= 0.01)
+        .count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn evidence(proteins: &[&str], score: f32) -> Evidence {
+        Evidence {
+            proteins: proteins.iter().map(|&p| Arc::new(p.to_string())).collect(),
+            score,
+            psms: 2,
+        }
+    }
+
+    #[test]
+    fn parsimonious_groups() {
+        let peptides = vec![
+            // A and B are indistinguishable, C only has shared peptides, and D
+            // has a single unique peptide
+            evidence(&["A", "B"], 3.0),
+            evidence(&["A", "B", "C"], 2.0),
+            evidence(&["A", "B", "C"], 1.0),
+            evidence(&["C", "D"], 1.0),
+            evidence(&["D"], 0.5),
+            evidence(&["E"], 0.1),
+        ];
+        let mut groups = parsimony(peptides, false);
+        groups.push(ProteinGroup {
+            proteins: vec![Arc::new("rev_F".into())],
+            decoy: true,
+            peptides: 1,
+            unique_peptides: 1,
+            razor_peptides: 1,
+            psms: 1,
+            score: 0.2,
+            q_value: 1.0,
+        });
+        assert_eq!(q_values(&mut groups), 2);
+
+        let summary = groups
+            .iter()
+            .map(|group| {
+                (
+                    group
+                        .proteins
+                        .iter()
+                        .map(|p| p.as_str())
+                        .collect::<Vec<_>>(),
+                    group.peptides,
+                    group.unique_peptides,
+                    group.razor_peptides,
+                    group.psms,
+                    group.score,
+                    group.q_value,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (vec!["A", "B"], 3, 1, 3, 6, 3.0, 0.0),
+                (vec!["D"], 2, 1, 2, 4, 1.0, 0.0),
+                (vec!["rev_F"], 1, 1, 1, 1, 0.2, 1.0 / 3.0),
+                (vec!["E"], 1, 1, 1, 2, 0.1, 1.0 / 3.0),
+            ]
+        );
+    }
+}
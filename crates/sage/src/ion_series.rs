@@ -1,9 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-use crate::mass::monoisotopic;
+use crate::mass::ResidueMasses;
 use crate::peptide::Peptide;
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 pub enum Kind {
     A,
@@ -28,12 +29,18 @@ pub struct IonSeries<'p> {
     pub kind: Kind,
     cumulative_mass: f32,
     peptide: &'p Peptide,
+    masses: ResidueMasses,
     idx: usize,
 }
 
 impl<'p> IonSeries<'p> {
     /// Create a new [`IonSeries`] iterator for a specified peptide
     pub fn new(peptide: &'p Peptide, kind: Kind) -> Self {
+        Self::with_residue_masses(peptide, kind, ResidueMasses::default())
+    }
+
+    /// Create a new [`IonSeries`] iterator, using custom residue masses
+    pub fn with_residue_masses(peptide: &'p Peptide, kind: Kind, masses: ResidueMasses) -> Self {
         const C: f32 = 12.0;
         const O: f32 = 15.994914;
         const H: f32 = 1.007825;
@@ -55,6 +62,7 @@ impl<'p> IonSeries<'p> {
             kind,
             cumulative_mass,
             peptide,
+            masses,
             idx: 0,
         }
     }
@@ -73,8 +81,8 @@ impl<'p> Iterator for IonSeries<'p> {
         let m = self.peptide.modifications[self.idx];
 
         self.cumulative_mass += match self.kind {
-            Kind::A | Kind::B | Kind::C => monoisotopic(r) + m,
-            Kind::X | Kind::Y | Kind::Z => -(monoisotopic(r) + m),
+            Kind::A | Kind::B | Kind::C => self.masses.monoisotopic(r) + m,
+            Kind::X | Kind::Y | Kind::Z => -(self.masses.monoisotopic(r) + m),
         };
         self.idx += 1;
 
@@ -85,6 +93,56 @@ impl<'p> Iterator for IonSeries<'p> {
     }
 }
 
+/// A neutral loss from fragment ions, e.g. H3PO4 from phosphorylated residues
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct NeutralLoss {
+    /// Monoisotopic mass lost from the fragment ion
+    pub mass: f32,
+    /// Residues that can lose `mass`. Fragment ions are only matched after
+    /// the loss if they contain at least one of these residues
+    pub residues: String,
+    /// If set, residues must also carry a modification of this mass, e.g.
+    /// 79.966331 for the loss of H3PO4 from phosphorylated residues
+    pub modification: Option<f32>,
+}
+
+impl NeutralLoss {
+    /// Maximum difference (Da) between a residue modification and
+    /// [`NeutralLoss::modification`]
+    const MODIFICATION_TOLERANCE: f32 = 0.01;
+
+    /// Cumulative number of residues of `peptide` that can lose this mass:
+    /// element `i` counts residues `0..i`
+    pub fn eligible_residues(&self, peptide: &Peptide) -> Vec<u16> {
+        let mut counts = Vec::with_capacity(peptide.sequence.len() + 1);
+        let mut count = 0;
+        counts.push(count);
+        for (residue, modification) in peptide.sequence.iter().zip(&peptide.modifications) {
+            let eligible = self.residues.as_bytes().contains(residue)
+                && self
+                    .modification
+                    .map(|m| (m - modification).abs() <= Self::MODIFICATION_TOLERANCE)
+                    .unwrap_or(true);
+            count += eligible as u16;
+            counts.push(count);
+        }
+        counts
+    }
+}
+
+/// Number of residues contained in fragment ion `idx` (as enumerated by
+/// [`IonSeries`]) that can lose a neutral mass, given the output of
+/// [`NeutralLoss::eligible_residues`]
+pub fn eligible_in_fragment(eligible: &[u16], kind: Kind, idx: usize) -> u16 {
+    let total = eligible[eligible.len() - 1];
+    match kind {
+        // N-terminal ion `idx` contains residues `0..=idx`
+        Kind::A | Kind::B | Kind::C => eligible[idx + 1],
+        Kind::X | Kind::Y | Kind::Z => total - eligible[idx + 1],
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -326,4 +384,35 @@ mod test {
         check_within(ions!(&peptide, Kind::B, 1.0), &expected_b);
         check_within(ions!(&peptide, Kind::Y, 1.0), &expected_y);
     }
+
+    #[test]
+    fn neutral_loss_eligibility() {
+        let mut peptide = peptide("PESTK");
+        peptide.modifications[2] = 79.9663;
+
+        let water = NeutralLoss {
+            mass: 18.0106,
+            residues: "STED".into(),
+            modification: None,
+        };
+        let eligible = water.eligible_residues(&peptide);
+        assert_eq!(eligible, vec![0, 0, 1, 2, 3, 3]);
+        // b1 = P, y4 = ESTK
+        assert_eq!(eligible_in_fragment(&eligible, Kind::B, 0), 0);
+        assert_eq!(eligible_in_fragment(&eligible, Kind::Y, 0), 3);
+        // b4 = PEST, y1 = K
+        assert_eq!(eligible_in_fragment(&eligible, Kind::B, 3), 3);
+        assert_eq!(eligible_in_fragment(&eligible, Kind::Y, 3), 0);
+
+        let phospho = NeutralLoss {
+            mass: 97.9769,
+            residues: "STY".into(),
+            modification: Some(79.9663),
+        };
+        let eligible = phospho.eligible_residues(&peptide);
+        assert_eq!(eligible, vec![0, 0, 0, 1, 1, 1]);
+        assert_eq!(eligible_in_fragment(&eligible, Kind::B, 1), 0);
+        assert_eq!(eligible_in_fragment(&eligible, Kind::B, 2), 1);
+        assert_eq!(eligible_in_fragment(&eligible, Kind::Y, 2), 0);
+    }
 }
@@ -0,0 +1,180 @@
+//! Calibration of retention times to the iRT (indexed retention time) scale.
+//!
+//! Reference peptides with known iRT values - by default, the Biognosys iRT
+//! kit - are identified in each file, and a linear model of iRT ~ retention
+//! time is fit to the retention times of their best PSMs. Calibrated iRT
+//! values are comparable across gradients and instruments, e.g. for building
+//! spectral libraries.
+
+use crate::database::IndexedDatabase;
+use crate::scoring::Feature;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Peptides of the Biognosys iRT kit, and their iRT values
+pub const BIOGNOSYS_IRT: [(&str, f32); 11] = [
+    ("LGGNEQVTR", -24.92),
+    ("GAGSSEPVTGLDAK", 0.00),
+    ("VEATFGVDESNAK", 12.39),
+    ("YILAGVENSK", 19.79),
+    ("TPVISGGPYEYR", 28.71),
+    ("TPVITGAPYEYR", 33.38),
+    ("DGLDAASYYAPVR", 42.26),
+    ("ADVTPADFSEWSK", 54.62),
+    ("GTFIIDPGGVIR", 70.52),
+    ("GTFIIDPAAVIR", 87.23),
+    ("LFLQFGAQGSPFLK", 100.00),
+];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IrtSettings {
+    /// Reference peptide sequences (without modifications), and their iRT
+    pub peptides: BTreeMap<String, f32>,
+    /// Maximum spectrum- and peptide-level q-value of reference peptide PSMs
+    pub q_value: f32,
+    /// Minimum number of reference peptides required to calibrate a file
+    pub min_peptides: usize,
+}
+
+impl Default for IrtSettings {
+    fn default() -> Self {
+        Self {
+            peptides: BIOGNOSYS_IRT
+                .iter()
+                .map(|(sequence, irt)| (sequence.to_string(), *irt))
+                .collect(),
+            q_value: 0.01,
+            min_peptides: 3,
+        }
+    }
+}
+
+/// Linear calibration of the retention times of a single file to iRT
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct IrtCalibration {
+    pub slope: f64,
+    pub intercept: f64,
+    /// Coefficient of determination of the fit
+    pub r2: f64,
+    /// Number of reference peptides the calibration was fit to
+    pub peptides: usize,
+}
+
+impl IrtCalibration {
+    /// Least-squares fit to (retention time, iRT) pairs. Requires at least 2
+    /// distinct retention times
+    pub fn fit(points: &[(f64, f64)]) -> Option<Self> {
+        if points.len() < 2 {
+            return None;
+        }
+        let n = points.len() as f64;
+        let rt_mean = points.iter().map(|(rt, _)| rt).sum::<f64>() / n;
+        let irt_mean = points.iter().map(|(_, irt)| irt).sum::<f64>() / n;
+        let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+        for (rt, irt) in points {
+            sxx += (rt - rt_mean).powi(2);
+            sxy += (rt - rt_mean) * (irt - irt_mean);
+            syy += (irt - irt_mean).powi(2);
+        }
+        if sxx <= 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let r2 = match syy > 0.0 {
+            true => sxy.powi(2) / (sxx * syy),
+            false => 1.0,
+        };
+        Some(IrtCalibration {
+            slope,
+            intercept: irt_mean - slope * rt_mean,
+            r2,
+            peptides: points.len(),
+        })
+    }
+
+    pub fn irt(&self, rt: f32) -> f32 {
+        (self.intercept + self.slope * rt as f64) as f32
+    }
+}
+
+/// Calibrate the retention times of each of `files` files to iRT, and assign
+/// [`Feature::irt`] to every PSM of the calibrated files. Q-values must have
+/// been assigned.
+///
+/// The retention time of a reference peptide is that of its highest-scoring
+/// rank 1 target PSM in the file. Files with fewer than
+/// `settings.min_peptides` identified reference peptides are not calibrated
+pub fn calibrate(
+    db: &IndexedDatabase,
+    features: &mut [Feature],
+    files: usize,
+    settings: &IrtSettings,
+) -> Vec<Option<IrtCalibration>> {
+    // Best PSM of each reference peptide in each file: score, rt, and iRT
+    let mut best: BTreeMap<(usize, &str), (f32, f32, f32)> = BTreeMap::new();
+    for feat in features.iter() {
+        if feat.label != 1
+            || feat.rank != 1
+            || feat.spectrum_q > settings.q_value
+            || feat.peptide_q > settings.q_value
+        {
+            continue;
+        }
+        let sequence = std::str::from_utf8(&db[feat.peptide_idx].sequence).unwrap_or_default();
+        if let Some((sequence, irt)) = settings.peptides.get_key_value(sequence) {
+            let entry =
+                best.entry((feat.file_id, sequence.as_str()))
+                    .or_insert((f32::MIN, feat.rt, *irt));
+            if feat.discriminant_score > entry.0 {
+                *entry = (feat.discriminant_score, feat.rt, *irt);
+            }
+        }
+    }
+
+    let mut points = vec![Vec::new(); files];
+    for ((file_id, _), (_, rt, irt)) in best {
+        if let Some(points) = points.get_mut(file_id) {
+            points.push((rt as f64, irt as f64));
+        }
+    }
+    let calibrations = points
+        .iter()
+        .map(
+            |points| match points.len() >= settings.min_peptides.max(2) {
+                true => IrtCalibration::fit(points),
+                false => None,
+            },
+        )
+        .collect::<Vec<_>>();
+
+    for feat in features.iter_mut() {
+        feat.irt = calibrations
+            .get(feat.file_id)
+            .copied()
+            .flatten()
+            .map(|calibration| calibration.irt(feat.rt));
+    }
+    calibrations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn irt_calibration() {
+        // iRT = 2 * rt - 50
+        let points = [(10.0, -30.0), (25.0, 0.0), (40.0, 30.0), (75.0, 100.0)];
+        let calibration = IrtCalibration::fit(&points).unwrap();
+        assert!((calibration.slope - 2.0).abs() < 1E-9);
+        assert!((calibration.intercept + 50.0).abs() < 1E-9);
+        assert!((calibration.r2 - 1.0).abs() < 1E-9);
+        assert_eq!(calibration.peptides, 4);
+        assert!((calibration.irt(30.0) - 10.0).abs() < 1E-5);
+
+        assert!(IrtCalibration::fit(&points[..1]).is_none());
+        assert!(IrtCalibration::fit(&[(10.0, 0.0), (10.0, 50.0)]).is_none());
+        assert_eq!(IrtSettings::default().peptides["GAGSSEPVTGLDAK"], 0.0);
+    }
+}
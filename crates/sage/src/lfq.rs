@@ -1,28 +1,29 @@
 use crate::database::{binary_search_slice, IndexedDatabase, PeptideIx};
-use crate::mass::{composition, Composition, Tolerance, NEUTRON};
+use crate::mass::{composition, Composition, Tolerance, NEUTRON, PROTON};
 use crate::ml::{matrix::Matrix, retention_alignment::Alignment};
+use crate::ms1_features::Ms1FeatureMap;
+use crate::par::prelude::*;
+use crate::rollup::ProteinRollup;
 use crate::scoring::Feature;
 use crate::spectrum::ProcessedSpectrum;
 use dashmap::DashMap;
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Minimum normalized spectral angle required to integrate a peak
 // const MIN_SPECTRAL_ANGLE: f64 = 0.70;
-/// Retention time tolerance, in fraction of total run length, to search for
-/// precursor ions
-const RT_TOL: f32 = 0.0050;
 /// Width of gaussian kernel used for smoothing intensities
 const K_WIDTH: usize = 10;
 /// Mass tolerance, in ppm, to seach for precursor ions
 // const PPM_TOL: f32 = 5.0;
-/// Number of equally spaced bins that will be used to integrate ions in (-RT_TOL, +RT_TOL)
+/// Number of equally spaced bins that will be used to integrate ions in
+/// (-rt_tolerance, +rt_tolerance)
 const GRID_SIZE: usize = 100;
 /// Number of isotopes to search for
 const N_ISOTOPES: usize = 3;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum PeakScoringStrategy {
     RetentionTime,
     SpectralAngle,
@@ -31,9 +32,13 @@ pub enum PeakScoringStrategy {
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum IntegrationStrategy {
     Apex,
     Sum,
+    /// Area of the matching feature detected by [`crate::ms1_features`], see
+    /// [`quantify_features`]
+    Feature,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -43,12 +48,19 @@ pub enum PrecursorId {
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct LfqSettings {
     pub peak_scoring: PeakScoringStrategy,
     pub integration: IntegrationStrategy,
     pub spectral_angle: f64,
     pub ppm_tolerance: f32,
+    /// Retention time tolerance, in fraction of total run length (aligned
+    /// retention time), to search for precursor ions
+    pub rt_tolerance: f32,
     pub combine_charge_states: bool,
+    /// Match-between-runs: report intensities of peptides in files where they
+    /// were not identified
+    pub mbr: bool,
 }
 
 impl Default for LfqSettings {
@@ -58,7 +70,9 @@ impl Default for LfqSettings {
             integration: IntegrationStrategy::Sum,
             spectral_angle: 0.70,
             ppm_tolerance: 5.0,
+            rt_tolerance: 0.0050,
             combine_charge_states: true,
+            mbr: true,
         }
     }
 }
@@ -73,6 +87,9 @@ pub struct PrecursorRange {
     pub peptide: PeptideIx,
     pub file_id: usize,
     pub decoy: bool,
+    /// FAIMS compensation voltage of the most confident PSM of the peptide,
+    /// if MS1 peaks are only integrated in spectra acquired at this CV
+    pub compensation_voltage: Option<f32>,
 }
 
 /// Create a data structure analogous to [`IndexedDatabase`] - instaed of
@@ -85,10 +102,13 @@ pub struct FeatureMap {
     pub settings: LfqSettings,
 }
 
+/// If `split_faims` is set, precursors are only traced in MS1 spectra
+/// acquired at the FAIMS compensation voltage of their most confident PSM
 pub fn build_feature_map(
     settings: LfqSettings,
     precursor_charge: (u8, u8),
     features: &[Feature],
+    split_faims: bool,
 ) -> FeatureMap {
     let map: DashMap<PeptideIx, PrecursorRange, fnv::FnvBuildHasher> = DashMap::default();
     features
@@ -113,6 +133,7 @@ pub fn build_feature_map(
                         isotope: 0,
                         file_id: feat.file_id,
                         decoy: false,
+                        compensation_voltage: feat.compensation_voltage.filter(|_| split_faims),
                     },
                 );
             }
@@ -143,7 +164,7 @@ pub fn build_feature_map(
                             .bounds(mass + 11.06);
 
                     let rev = PrecursorRange {
-                        rt: (fwd.rt - RT_TOL * 2.0).max(0.0),
+                        rt: (fwd.rt - settings.rt_tolerance * 2.0).max(0.0),
                         mass_lo,
                         mass_hi,
                         decoy: true,
@@ -222,10 +243,15 @@ impl FeatureMap {
             .for_each(|spectrum| {
                 let a = alignments[spectrum.file_id];
                 let rt = (spectrum.scan_start_time / a.max_rt) * a.slope + a.intercept;
-                let query = self.rt_slice(rt, RT_TOL);
+                let query = self.rt_slice(rt, self.settings.rt_tolerance);
 
                 for peak in &spectrum.peaks {
                     for entry in query.mass_lookup(peak.mass) {
+                        if entry.compensation_voltage.is_some()
+                            && entry.compensation_voltage != spectrum.compensation_voltage
+                        {
+                            continue;
+                        }
                         let id = match self.settings.combine_charge_states {
                             true => PrecursorId::Combined(entry.peptide),
                             false => PrecursorId::Charged((entry.peptide, entry.charge)),
@@ -242,7 +268,13 @@ impl FeatureMap {
                                 composition.carbon,
                                 composition.sulfur,
                             );
-                            Grid::new(entry, RT_TOL, dist, alignments.len(), GRID_SIZE)
+                            Grid::new(
+                                entry,
+                                self.settings.rt_tolerance,
+                                dist,
+                                alignments.len(),
+                                GRID_SIZE,
+                            )
                         });
 
                         grid.add_entry(rt, entry.isotope, spectrum.file_id, peak.intensity);
@@ -286,6 +318,160 @@ impl FeatureMap {
     }
 }
 
+/// Label-free quantification from detected MS1 features
+/// ([`IntegrationStrategy::Feature`]): in each file, the area of the feature
+/// with the monoisotopic m/z and charge of a precursor whose apex is closest
+/// to the aligned retention time of the peptide, within `rt_tolerance`.
+///
+/// As in [`build_feature_map`], peptides are located by their most confident
+/// PSM, so features of files in which a peptide was not identified are
+/// matched between runs, and decoy precursors are shifted in mass and
+/// retention time. Peaks are scored by their fit-weighted area
+pub fn quantify_features(
+    settings: &LfqSettings,
+    precursor_charge: (u8, u8),
+    features: &[Feature],
+    map: &Ms1FeatureMap,
+    alignments: &[Alignment],
+) -> HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher> {
+    // `features` is sorted by confidence, so just take the first entry
+    let mut best: HashMap<PeptideIx, &Feature, fnv::FnvBuildHasher> = HashMap::default();
+    for feat in features
+        .iter()
+        .filter(|feat| feat.peptide_q <= 0.01 && feat.label == 1)
+    {
+        best.entry(feat.peptide_idx).or_insert(feat);
+    }
+    let tolerance = Tolerance::Ppm(-settings.ppm_tolerance, settings.ppm_tolerance);
+
+    best.into_par_iter()
+        .flat_map_iter(|(peptide, feat)| {
+            let mut peaks: HashMap<(PrecursorId, bool), (Vec<f64>, f64), fnv::FnvBuildHasher> =
+                HashMap::default();
+            for decoy in [false, true] {
+                let (mass, rt) = match decoy {
+                    false => (feat.calcmass, feat.aligned_rt),
+                    true => (
+                        feat.calcmass + 11.06,
+                        (feat.aligned_rt - settings.rt_tolerance * 2.0).max(0.0),
+                    ),
+                };
+                for charge in precursor_charge.0..=precursor_charge.1 {
+                    let id = match settings.combine_charge_states {
+                        true => PrecursorId::Combined(peptide),
+                        false => PrecursorId::Charged((peptide, charge)),
+                    };
+                    let (lo, hi) = tolerance.bounds(mass / charge as f32 + PROTON);
+                    for a in alignments {
+                        let aligned = |rt: f32| (rt / a.max_rt) * a.slope + a.intercept;
+                        let matched = map
+                            .window(a.file_id, lo, hi)
+                            .iter()
+                            .filter(|f| f.charge == charge)
+                            .map(|f| (f, (aligned(f.rt_apex) - rt).abs()))
+                            .filter(|(_, delta)| *delta <= settings.rt_tolerance)
+                            .min_by(|a, b| a.1.total_cmp(&b.1));
+                        if let Some((f, _)) = matched {
+                            let (areas, fit) = peaks
+                                .entry((id, decoy))
+                                .or_insert_with(|| (vec![0.0; alignments.len()], 0.0));
+                            areas[a.file_id] += f.area as f64;
+                            *fit += (f.area * f.envelope_fit) as f64;
+                        }
+                    }
+                }
+            }
+            peaks.into_iter().map(|(key, (areas, fit))| {
+                let total = areas.iter().sum::<f64>();
+                let peak = Peak {
+                    score: fit,
+                    spectral_angle: fit / total.max(f64::MIN_POSITIVE),
+                    ..Default::default()
+                };
+                (key, (peak, areas))
+            })
+        })
+        .collect()
+}
+
+/// Set the intensities of target precursors to 0 in files where they were not
+/// identified (by a target PSM at a peptide-level q-value of 0.01 or lower),
+/// disabling match-between-runs. Decoy precursors are left unchanged
+pub fn remove_transferred(
+    peaks: &mut HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+    features: &[Feature],
+) {
+    let identified = features
+        .iter()
+        .filter(|feat| feat.peptide_q <= 0.01 && feat.label == 1)
+        .flat_map(|feat| {
+            [
+                (PrecursorId::Combined(feat.peptide_idx), feat.file_id),
+                (
+                    PrecursorId::Charged((feat.peptide_idx, feat.charge)),
+                    feat.file_id,
+                ),
+            ]
+        })
+        .collect::<std::collections::HashSet<_>>();
+
+    peaks
+        .par_iter_mut()
+        .filter(|((_, decoy), _)| !decoy)
+        .for_each(|((id, _), (_, intensities))| {
+            for (file_id, intensity) in intensities.iter_mut().enumerate() {
+                if !identified.contains(&(*id, file_id)) {
+                    *intensity = 0.0;
+                }
+            }
+        });
+}
+
+/// Precursor intensities of a single protein, summarized across peptides
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProteinLfqQuant {
+    pub protein: String,
+    /// Number of precursors the protein was summarized from
+    pub precursors: usize,
+    /// Protein intensity in each file
+    pub intensities: Vec<f64>,
+}
+
+/// Summarize target precursor intensities to the protein level, using
+/// `method`. Only precursors with a q-value at or below `q_value` are used, and
+/// shared peptides are ignored
+pub fn protein_rollup(
+    db: &IndexedDatabase,
+    peaks: &HashMap<(PrecursorId, bool), (Peak, Vec<f64>), fnv::FnvBuildHasher>,
+    q_value: f32,
+    method: ProteinRollup,
+) -> Vec<ProteinLfqQuant> {
+    let mut proteins: fnv::FnvHashMap<&str, Vec<Vec<f64>>> = fnv::FnvHashMap::default();
+    for ((id, decoy), (peak, intensities)) in peaks {
+        let peptide = match id {
+            PrecursorId::Combined(ix) | PrecursorId::Charged((ix, _)) => &db[*ix],
+        };
+        if *decoy || peak.q_value > q_value || peptide.shared() {
+            continue;
+        }
+        proteins
+            .entry(peptide.proteins[0].as_str())
+            .or_default()
+            .push(intensities.clone());
+    }
+
+    let mut proteins = proteins
+        .into_iter()
+        .map(|(protein, rows)| ProteinLfqQuant {
+            protein: protein.to_string(),
+            precursors: rows.len(),
+            intensities: method.summarize(&rows),
+        })
+        .collect::<Vec<_>>();
+    proteins.sort_by(|a, b| a.protein.cmp(&b.protein));
+    proteins
+}
+
 pub struct Grid {
     rt_min: f32,
     rt_step: f32,
@@ -467,9 +653,12 @@ impl Traces {
         let mut areas = Vec::with_capacity(self.dot_product.rows);
         for file in 0..self.dot_product.rows {
             let area = match settings.integration {
-                IntegrationStrategy::Sum => self.dot_product.row_slice(file)[left..right]
-                    .iter()
-                    .sum::<f64>(),
+                // Feature areas are not integrated from traces
+                IntegrationStrategy::Sum | IntegrationStrategy::Feature => {
+                    self.dot_product.row_slice(file)[left..right]
+                        .iter()
+                        .sum::<f64>()
+                }
                 IntegrationStrategy::Apex => self.dot_product.row_slice(file)[best.rt],
             };
 
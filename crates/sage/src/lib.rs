@@ -1,15 +1,101 @@
+//! Proteomics database search: the core of the Sage search engine
+//!
+//! Besides the `sage` command line tool, this crate can be used to score
+//! spectra from other software, e.g. during acquisition:
+//!
+//! 1. Build a fragment index with [`database::Builder`] and
+//!    [`database::Parameters::build`], from a [`fasta::Fasta`] database
+//! 2. Create a [`scoring::Scorer`] for the index and search tolerances
+//! 3. Turn each centroided [`spectrum::RawSpectrum`] into a
+//!    [`spectrum::ProcessedSpectrum`] using a [`spectrum::SpectrumProcessor`]
+//! 4. Score it with [`scoring::Scorer::score`], which returns candidate PSMs
+//!    as [`scoring::Feature`]s
+//!
+//! [`search::SearchBuilder`] configures steps 1-3 with typed, validated
+//! settings and the defaults of the `sage` configuration file.
+//!
+//! False discovery rates are estimated across many PSMs, see [`fdr`] and
+//! [`ml`].
+//!
+//! ```
+//! use sage_core::database::Builder;
+//! use sage_core::fasta::Fasta;
+//! use sage_core::ion_series::{IonSeries, Kind};
+//! use sage_core::mass::{Tolerance, PROTON};
+//! use sage_core::scoring::Scorer;
+//! use sage_core::spectrum::{Precursor, RawSpectrum, Representation, SpectrumProcessor};
+//!
+//! let fasta = Fasta::parse(
+//!     ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+//!     "rev_",
+//!     true,
+//! );
+//! let db = Builder::default().make_parameters().build(fasta);
+//!
+//! // A synthetic MS2 spectrum of singly charged b and y ions
+//! let peptide = db
+//!     .peptides
+//!     .iter()
+//!     .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+//!     .unwrap();
+//! let mz = [Kind::B, Kind::Y]
+//!     .into_iter()
+//!     .flat_map(|kind| IonSeries::new(peptide, kind))
+//!     .map(|ion| ion.monoisotopic_mass + PROTON)
+//!     .collect::<Vec<_>>();
+//! let spectrum = RawSpectrum {
+//!     ms_level: 2,
+//!     id: "scan=1".into(),
+//!     precursors: vec![Precursor {
+//!         mz: peptide.monoisotopic / 2.0 + PROTON,
+//!         charge: Some(2),
+//!         ..Default::default()
+//!     }],
+//!     representation: Representation::Centroid,
+//!     intensity: vec![1.0; mz.len()],
+//!     mz,
+//!     ..RawSpectrum::default_with_file_id(0)
+//! };
+//!
+//! let processor = SpectrumProcessor::new(150, 150.0, 2000.0, false);
+//! let scorer = Scorer::new(&db, Tolerance::Ppm(-10.0, 10.0), Tolerance::Ppm(-10.0, 10.0));
+//! let psms = scorer.score(&processor.process(spectrum));
+//! assert_eq!(db[psms[0].peptide_idx].to_string(), "LQSRPAAPPAPGPGQLTLR");
+//! ```
+
+pub mod crosslink;
 pub mod database;
 pub mod enzyme;
+pub mod faims;
 pub mod fasta;
 pub mod fdr;
+pub mod fragment_prediction;
+pub mod glyco;
+#[cfg(feature = "gpu")]
+pub mod gpu;
 pub mod heap;
+pub mod inference;
 pub mod ion_series;
+pub mod irt;
 pub mod isotopes;
 pub mod lfq;
+pub mod library;
+pub mod library_search;
+pub mod localization;
 pub mod mass;
+pub mod merge;
 pub mod ml;
 pub mod modification;
+pub mod ms1_features;
+pub mod par;
 pub mod peptide;
+pub mod prefilter;
+pub mod prm;
+pub mod qc;
+pub mod recalibration;
+pub mod rollup;
 pub mod scoring;
+pub mod search;
+pub mod silac;
 pub mod spectrum;
 pub mod tmt;
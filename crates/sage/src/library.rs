@@ -0,0 +1,313 @@
+//! Spectral library generation from annotated peptide-spectrum matches
+
+use crate::database::PeptideIx;
+use crate::ion_series::Kind;
+use crate::peptide::Peptide;
+use crate::scoring::Feature;
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// UniMod accessions of common modifications, by monoisotopic mass delta
+const UNIMOD: &[(f32, u32)] = &[
+    (57.0215, 4),     // Carbamidomethyl
+    (15.9949, 35),    // Oxidation
+    (42.0106, 1),     // Acetyl
+    (79.9663, 21),    // Phospho
+    (0.9840, 7),      // Deamidated
+    (14.0157, 34),    // Methyl
+    (114.0429, 121),  // GG
+    (229.1629, 737),  // TMT6plex
+    (304.2071, 2016), // TMTpro
+    (-17.0265, 28),   // Gln->pyro-Glu
+    (-18.0106, 27),   // Glu->pyro-Glu
+    (8.0142, 259),    // Label:13C(6)15N(2)
+    (10.0083, 267),   // Label:13C(6)15N(4)
+];
+
+/// Maximum difference, in Da, between a modification and a [`UNIMOD`] mass
+const UNIMOD_TOLERANCE: f32 = 0.002;
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LibrarySettings {
+    /// Maximum spectrum-level q-value of PSMs used to build the library
+    pub spectrum_fdr: f32,
+    /// Maximum peptide-level q-value of PSMs used to build the library
+    pub peptide_fdr: f32,
+    /// Minimum number of files a precursor must be identified in
+    pub min_replicates: usize,
+    /// Minimum fraction of a precursor's PSMs that a fragment ion must be
+    /// matched in, to be included in the consensus spectrum
+    pub min_fragment_frequency: f32,
+}
+
+impl Default for LibrarySettings {
+    fn default() -> Self {
+        Self {
+            spectrum_fdr: 0.01,
+            peptide_fdr: 0.01,
+            min_replicates: 1,
+            min_fragment_frequency: 0.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LibraryFragment {
+    pub kind: Kind,
+    pub ordinal: i32,
+    pub charge: i32,
+    pub mz: f32,
+    /// Consensus intensity, relative to the most intense fragment
+    pub intensity: f32,
+}
+
+/// Consensus spectrum for a single precursor (peptide & charge state)
+#[derive(Clone, Debug)]
+pub struct LibraryEntry {
+    pub peptide_idx: PeptideIx,
+    pub charge: u8,
+    /// Calculated (neutral) precursor mass
+    pub calcmass: f32,
+    /// Median iRT of all PSMs, if every PSM used to build the library has an
+    /// iRT ([`Feature::irt`]), otherwise median aligned retention time
+    pub rt: f32,
+    /// Number of files this precursor was identified in
+    pub replicates: usize,
+    /// Number of PSMs contributing to the consensus spectrum
+    pub psms: usize,
+    pub fragments: Vec<LibraryFragment>,
+}
+
+/// Build a consensus spectrum for every precursor passing spectrum- and
+/// peptide-level FDR thresholds, and identified in at least
+/// `settings.min_replicates` files.
+///
+/// Requires that fragment ions have been annotated ([`Feature::fragments`]),
+/// and that q-values have been assigned.
+pub fn build(features: &[Feature], settings: &LibrarySettings) -> Vec<LibraryEntry> {
+    let mut precursors: FnvHashMap<(PeptideIx, u8), Vec<&Feature>> = FnvHashMap::default();
+    for feat in features {
+        if feat.label == 1
+            && feat.rank == 1
+            && feat.spectrum_q <= settings.spectrum_fdr
+            && feat.peptide_q <= settings.peptide_fdr
+            && feat.fragments.is_some()
+        {
+            precursors
+                .entry((feat.peptide_idx, feat.charge))
+                .or_default()
+                .push(feat);
+        }
+    }
+
+    // Only use iRT if it is available for every precursor, so that all
+    // library retention times are on the same scale
+    let irt = precursors.values().flatten().all(|feat| feat.irt.is_some());
+
+    let mut entries = precursors
+        .into_iter()
+        .filter_map(|((peptide_idx, charge), psms)| {
+            let replicates = psms
+                .iter()
+                .map(|feat| feat.file_id)
+                .collect::<FnvHashSet<_>>()
+                .len();
+            if replicates < settings.min_replicates {
+                return None;
+            }
+
+            let fragments = consensus(&psms, settings.min_fragment_frequency);
+            if fragments.is_empty() {
+                return None;
+            }
+
+            let mut rts = psms
+                .iter()
+                .map(|feat| match irt {
+                    true => feat.irt.unwrap_or_default(),
+                    false => feat.aligned_rt,
+                })
+                .collect::<Vec<_>>();
+            rts.sort_by(|a, b| a.total_cmp(b));
+
+            Some(LibraryEntry {
+                peptide_idx,
+                charge,
+                calcmass: psms[0].calcmass,
+                rt: rts[rts.len() / 2],
+                replicates,
+                psms: psms.len(),
+                fragments,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_by_key(|entry| (entry.peptide_idx, entry.charge));
+    entries
+}
+
+/// Write `peptide` with modifications in UniMod notation, as read by DIA-NN,
+/// e.g. `(UniMod:1)PEPTIDEM(UniMod:35)K`. Modifications that are not in
+/// [`UNIMOD`] are written as mass deltas, e.g. `S[+79.9]`
+pub fn unimod_sequence(peptide: &Peptide) -> String {
+    let mut sequence = String::with_capacity(peptide.sequence.len() * 2);
+    let push = |sequence: &mut String, m: f32| {
+        match UNIMOD
+            .iter()
+            .find(|(mass, _)| (mass - m).abs() <= UNIMOD_TOLERANCE)
+        {
+            Some((_, id)) => write!(sequence, "(UniMod:{})", id),
+            None => write!(sequence, "[{:+}]", m),
+        }
+        .expect("writing to a String never fails");
+    };
+    if let Some(m) = peptide.nterm {
+        push(&mut sequence, m);
+    }
+    for (&residue, &m) in peptide.sequence.iter().zip(&peptide.modifications) {
+        sequence.push(residue as char);
+        if m != 0.0 {
+            push(&mut sequence, m);
+        }
+    }
+    if let Some(m) = peptide.cterm {
+        push(&mut sequence, m);
+    }
+    sequence
+}
+
+/// Average the max-normalized fragment intensities of all PSMs, keeping only
+/// fragments that are matched in at least `min_frequency` of PSMs
+fn consensus(psms: &[&Feature], min_frequency: f32) -> Vec<LibraryFragment> {
+    // (summed normalized intensity, # of PSMs matched, m/z)
+    let mut ions: FnvHashMap<(Kind, i32, i32), (f32, usize, f32)> = FnvHashMap::default();
+    for fragments in psms.iter().filter_map(|feat| feat.fragments.as_ref()) {
+        let max = fragments
+            .intensities
+            .iter()
+            .fold(0.0f32, |acc, x| acc.max(*x));
+        if max <= 0.0 {
+            continue;
+        }
+        for idx in 0..fragments.intensities.len() {
+            let key = (
+                fragments.kinds[idx],
+                fragments.fragment_ordinals[idx],
+                fragments.charges[idx],
+            );
+            let ion = ions
+                .entry(key)
+                .or_insert((0.0, 0, fragments.mz_calculated[idx]));
+            ion.0 += fragments.intensities[idx] / max;
+            ion.1 += 1;
+        }
+    }
+
+    let n = psms.len() as f32;
+    let mut fragments = ions
+        .into_iter()
+        .filter(|(_, (_, count, _))| *count as f32 / n >= min_frequency)
+        .map(
+            |((kind, ordinal, charge), (intensity, _, mz))| LibraryFragment {
+                kind,
+                ordinal,
+                charge,
+                mz,
+                intensity: intensity / n,
+            },
+        )
+        .collect::<Vec<_>>();
+
+    let max = fragments.iter().fold(0.0f32, |acc, f| acc.max(f.intensity));
+    for fragment in fragments.iter_mut() {
+        fragment.intensity /= max;
+    }
+    fragments.sort_by(|a, b| a.mz.total_cmp(&b.mz));
+    fragments
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scoring::Fragments;
+
+    fn psm(peptide: u32, file_id: usize, peptide_q: f32, ions: &[(i32, f32)]) -> Feature {
+        Feature {
+            peptide_idx: PeptideIx(peptide),
+            file_id,
+            rank: 1,
+            label: 1,
+            charge: 2,
+            peptide_q,
+            fragments: Some(Box::new(Fragments {
+                charges: vec![1; ions.len()],
+                kinds: vec![Kind::Y; ions.len()],
+                fragment_ordinals: ions.iter().map(|(ordinal, _)| *ordinal).collect(),
+                intensities: ions.iter().map(|(_, intensity)| *intensity).collect(),
+                mz_calculated: ions
+                    .iter()
+                    .map(|(ordinal, _)| 100.0 * *ordinal as f32)
+                    .collect(),
+                mz_experimental: ions
+                    .iter()
+                    .map(|(ordinal, _)| 100.0 * *ordinal as f32)
+                    .collect(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn consensus_library() {
+        let features = vec![
+            psm(0, 0, 0.001, &[(1, 10.0), (2, 20.0), (3, 5.0)]),
+            psm(0, 1, 0.001, &[(1, 30.0), (2, 60.0)]),
+            // Fails peptide-level FDR
+            psm(1, 0, 0.05, &[(1, 10.0)]),
+            // Only identified in a single file
+            psm(2, 0, 0.001, &[(1, 10.0)]),
+            psm(2, 0, 0.001, &[(1, 10.0)]),
+        ];
+
+        let settings = LibrarySettings {
+            min_replicates: 2,
+            min_fragment_frequency: 0.6,
+            ..Default::default()
+        };
+        let entries = build(&features, &settings);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].peptide_idx, PeptideIx(0));
+        assert_eq!(entries[0].replicates, 2);
+        assert_eq!(entries[0].psms, 2);
+
+        // y3 is only matched in one out of two PSMs
+        let ions = entries[0]
+            .fragments
+            .iter()
+            .map(|f| (f.ordinal, f.intensity))
+            .collect::<Vec<_>>();
+        assert_eq!(ions, vec![(1, 0.5), (2, 1.0)]);
+    }
+
+    #[test]
+    fn unimod_notation() {
+        let peptide = Peptide {
+            sequence: b"PEPTMCSK".to_vec().into(),
+            modifications: vec![0.0, 0.0, 0.0, 0.0, 15.9949, 57.0216, 1.5, 0.0],
+            nterm: Some(42.0106),
+            cterm: None,
+            decoy: false,
+            monoisotopic: 0.0,
+            missed_cleavages: 0,
+            semi_enzymatic: false,
+            position: crate::enzyme::Position::Internal,
+            proteins: Vec::new(),
+        };
+        assert_eq!(
+            unimod_sequence(&peptide),
+            "(UniMod:1)PEPTM(UniMod:35)C(UniMod:4)S[+1.5]K"
+        );
+    }
+}
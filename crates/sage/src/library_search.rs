@@ -0,0 +1,491 @@
+//! Spectral library search: MS2 spectra are scored against the reference
+//! spectra of a spectral library, rather than against theoretical fragments
+//!
+//! 1) Library precursors within the precursor tolerance (and with the same
+//!    charge) are selected as candidates
+//! 2) The most intense peak within the fragment tolerance of each library
+//!    fragment is matched, and the square-root transformed intensities are
+//!    compared by normalized spectral contrast angle
+//! 3) Candidates are ranked by spectral angle
+//!
+//! Decoy spectra are generated from the reversed decoy peptide of each
+//! library peptide: the same fragment ions (type, ordinal and charge) with the
+//! same relative intensities, at the m/z of the decoy peptide. Library PSMs are
+//! reported as [`Feature`]s, sharing FDR estimation and outputs with the
+//! database search
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::ion_series::{IonSeries, Kind};
+use crate::library::LibraryFragment;
+use crate::mass::{Tolerance, PROTON};
+use crate::scoring::{increment_psm_counter, lnfact, Feature, SpecId};
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LibrarySearchSettings {
+    /// Number of most intense library fragments matched for each precursor
+    pub fragments: usize,
+    /// Minimum number of matched library fragments to report a PSM
+    pub min_matched_peaks: u16,
+}
+
+impl Default for LibrarySearchSettings {
+    fn default() -> Self {
+        Self {
+            fragments: 12,
+            min_matched_peaks: 4,
+        }
+    }
+}
+
+/// Reference spectrum of a single precursor, resolved against the database
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibrarySpectrum {
+    pub peptide_idx: PeptideIx,
+    pub charge: u8,
+    /// Calculated (neutral) precursor mass
+    pub calcmass: f32,
+    pub fragments: Vec<LibraryFragment>,
+}
+
+impl LibrarySpectrum {
+    /// Build the decoy spectrum of this spectrum, whose peptide is `decoy_idx`
+    fn decoy(&self, db: &IndexedDatabase, decoy_idx: PeptideIx) -> Self {
+        let peptide = &db[decoy_idx];
+        let len = peptide.sequence.len() as i32;
+        let fragments = self
+            .fragments
+            .iter()
+            .filter_map(|frag| {
+                let idx = match frag.kind {
+                    Kind::A | Kind::B | Kind::C => frag.ordinal - 1,
+                    Kind::X | Kind::Y | Kind::Z => len - 1 - frag.ordinal,
+                };
+                let ion = IonSeries::with_residue_masses(peptide, frag.kind, db.residue_masses)
+                    .nth(usize::try_from(idx).ok()?)?;
+                Some(LibraryFragment {
+                    mz: (ion.monoisotopic_mass + frag.charge as f32 * PROTON) / frag.charge as f32,
+                    ..*frag
+                })
+            })
+            .collect();
+        LibrarySpectrum {
+            peptide_idx: decoy_idx,
+            charge: self.charge,
+            calcmass: peptide.monoisotopic,
+            fragments,
+        }
+    }
+}
+
+/// A library spectrum, before it is resolved against the database
+pub struct LibraryRecord {
+    /// Modified peptide sequence, as written by Sage
+    pub peptide: String,
+    pub charge: u8,
+    pub fragments: Vec<LibraryFragment>,
+}
+
+/// Resolve library spectra against the database, keeping the `fragments`
+/// most intense fragments of each, and add a decoy spectrum for every target
+/// spectrum. Returns the spectra (sorted by precursor mass), and the number of
+/// library peptides that were not found in the database
+pub fn prepare(
+    db: &IndexedDatabase,
+    entries: Vec<LibraryRecord>,
+    settings: &LibrarySearchSettings,
+) -> (Vec<LibrarySpectrum>, usize) {
+    let index = db
+        .peptides
+        .iter()
+        .enumerate()
+        .map(|(idx, peptide)| ((peptide.decoy, peptide.to_string()), PeptideIx(idx as u32)))
+        .collect::<FnvHashMap<_, _>>();
+
+    let mut missing = 0;
+    let mut spectra = Vec::with_capacity(entries.len() * 2);
+    for mut entry in entries {
+        let peptide_idx = match index.get(&(false, entry.peptide)) {
+            Some(idx) => *idx,
+            None => {
+                missing += 1;
+                continue;
+            }
+        };
+        entry
+            .fragments
+            .sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+        entry.fragments.truncate(settings.fragments);
+        let target = LibrarySpectrum {
+            peptide_idx,
+            charge: entry.charge,
+            calcmass: db[peptide_idx].monoisotopic,
+            fragments: entry.fragments,
+        };
+        let decoy = db[peptide_idx].reverse();
+        if let Some(decoy_idx) = index.get(&(true, decoy.to_string())) {
+            spectra.push(target.decoy(db, *decoy_idx));
+        }
+        spectra.push(target);
+    }
+    spectra.sort_by(|a, b| a.calcmass.total_cmp(&b.calcmass));
+    (spectra, missing)
+}
+
+/// Match between a query spectrum and a library spectrum
+struct Match<'a> {
+    spectrum: &'a LibrarySpectrum,
+    spectral_angle: f32,
+    hyperscore: f64,
+    matched_b: u16,
+    matched_y: u16,
+    longest_b: u32,
+    longest_y: u32,
+    fragment_coverage: f32,
+    matched_intensity: f32,
+    average_ppm: f32,
+}
+
+pub struct LibraryScorer<'a> {
+    pub db: &'a IndexedDatabase,
+    /// Library spectra, sorted by precursor mass (see [`prepare`])
+    pub spectra: &'a [LibrarySpectrum],
+    pub settings: LibrarySearchSettings,
+    pub precursor_tol: Tolerance,
+    pub fragment_tol: Tolerance,
+    pub min_precursor_charge: u8,
+    pub max_precursor_charge: u8,
+    pub report_psms: usize,
+}
+
+/// Normalized spectral contrast angle between square-root transformed
+/// intensities, in [0, 1]
+pub(crate) fn spectral_angle(reference: &[f32], observed: &[f32]) -> f32 {
+    let (mut dot, mut ref_norm, mut obs_norm) = (0.0f32, 0.0f32, 0.0f32);
+    for (r, o) in reference.iter().zip(observed) {
+        let (r, o) = (r.sqrt(), o.sqrt());
+        dot += r * o;
+        ref_norm += r * r;
+        obs_norm += o * o;
+    }
+    if ref_norm == 0.0 || obs_norm == 0.0 {
+        return 0.0;
+    }
+    let cosine = (dot / (ref_norm.sqrt() * obs_norm.sqrt())).clamp(-1.0, 1.0);
+    1.0 - 2.0 * cosine.acos() / std::f32::consts::PI
+}
+
+impl<'a> LibraryScorer<'a> {
+    fn score_candidate(
+        &self,
+        query: &ProcessedSpectrum,
+        spectrum: &'a LibrarySpectrum,
+    ) -> Option<Match<'a>> {
+        let mut observed = Vec::with_capacity(spectrum.fragments.len());
+        let mut ppm = 0.0;
+        let (mut matched_b, mut matched_y) = (0u16, 0u16);
+        let (mut summed_b, mut summed_y) = (0.0f32, 0.0f32);
+        let mut ordinals_b = Vec::new();
+        let mut ordinals_y = Vec::new();
+
+        for frag in &spectrum.fragments {
+            let mass = frag.mz - PROTON;
+            match select_most_intense_peak(&query.peaks, mass, self.fragment_tol, None) {
+                Some(peak) => {
+                    ppm += (peak.mass - mass).abs() * 2E6 / (peak.mass + mass);
+                    match frag.kind {
+                        Kind::A | Kind::B | Kind::C => {
+                            matched_b += 1;
+                            summed_b += peak.intensity;
+                            ordinals_b.push(frag.ordinal);
+                        }
+                        Kind::X | Kind::Y | Kind::Z => {
+                            matched_y += 1;
+                            summed_y += peak.intensity;
+                            ordinals_y.push(frag.ordinal);
+                        }
+                    }
+                    observed.push(peak.intensity);
+                }
+                None => observed.push(0.0),
+            }
+        }
+
+        let matched = matched_b + matched_y;
+        if matched < self.settings.min_matched_peaks {
+            return None;
+        }
+        let reference = spectrum
+            .fragments
+            .iter()
+            .map(|frag| frag.intensity)
+            .collect::<Vec<_>>();
+
+        // Peptide bonds explained by a matched fragment ion of either series
+        let len = self.db[spectrum.peptide_idx].sequence.len() as i32;
+        let mut bonds = ordinals_b
+            .iter()
+            .map(|ordinal| ordinal - 1)
+            .chain(ordinals_y.iter().map(|ordinal| len - 1 - ordinal))
+            .filter(|bond| (0..len - 1).contains(bond))
+            .collect::<Vec<_>>();
+        bonds.sort_unstable();
+        bonds.dedup();
+
+        let hyperscore = ((summed_b + 1.0) as f64 * (summed_y + 1.0) as f64).ln()
+            + lnfact(matched_b)
+            + lnfact(matched_y);
+
+        Some(Match {
+            spectrum,
+            spectral_angle: spectral_angle(&reference, &observed),
+            hyperscore,
+            matched_b,
+            matched_y,
+            longest_b: longest_series(&mut ordinals_b),
+            longest_y: longest_series(&mut ordinals_y),
+            fragment_coverage: bonds.len() as f32 / (len - 1).max(1) as f32,
+            matched_intensity: summed_b + summed_y,
+            average_ppm: ppm / matched as f32,
+        })
+    }
+
+    /// Score `query` against all library spectra within the precursor
+    /// tolerance, returning up to `report_psms` PSMs ranked by spectral angle
+    pub fn score(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+        let precursor = match query.precursors.first() {
+            Some(precursor) => precursor,
+            None => return Vec::new(),
+        };
+        let (min_charge, max_charge) = match precursor.charge {
+            Some(charge) => (charge, charge),
+            None => (self.min_precursor_charge, self.max_precursor_charge),
+        };
+
+        let mut matches = Vec::new();
+        let mut scored_candidates = 0;
+        for charge in min_charge..=max_charge {
+            let precursor_mass = (precursor.mz - PROTON) * charge as f32;
+            let (lo, hi) = self.precursor_tol.bounds(precursor_mass);
+            let start = self.spectra.partition_point(|s| s.calcmass < lo);
+            for spectrum in self.spectra[start..]
+                .iter()
+                .take_while(|s| s.calcmass <= hi)
+                .filter(|s| s.charge == charge)
+            {
+                scored_candidates += 1;
+                if let Some(m) = self.score_candidate(query, spectrum) {
+                    matches.push((precursor_mass, m));
+                }
+            }
+        }
+        matches.sort_by(|a, b| b.1.spectral_angle.total_cmp(&a.1.spectral_angle));
+
+        let best = matches.first().map(|(_, m)| m.spectral_angle);
+        // Expected value for poisson distribution
+        // (average # of matched fragments/library candidate)
+        let lambda = matches
+            .iter()
+            .map(|(_, m)| (m.matched_b + m.matched_y) as f64)
+            .sum::<f64>()
+            / scored_candidates.max(1) as f64;
+        let spec_id = SpecId::from(query.id.as_str());
+        matches
+            .iter()
+            .take(self.report_psms)
+            .enumerate()
+            .map(|(idx, (precursor_mass, m))| {
+                let peptide = &self.db[m.spectrum.peptide_idx];
+                let next = matches
+                    .get(idx + 1)
+                    .map(|(_, m)| m.spectral_angle)
+                    .unwrap_or_default();
+                let delta_mass = (precursor_mass - peptide.monoisotopic).abs() * 2E6
+                    / (precursor_mass + peptide.monoisotopic);
+                let k = m.matched_b + m.matched_y;
+                let mut poisson = lambda.powi(k as i32) * f64::exp(-lambda) / lnfact(k).exp();
+                if poisson.is_infinite() {
+                    poisson = 1E-325;
+                }
+                Feature {
+                    psm_id: increment_psm_counter(),
+                    peptide_idx: m.spectrum.peptide_idx,
+                    peptide_len: peptide.sequence.len(),
+                    spec_id: spec_id.clone(),
+                    file_id: query.file_id,
+                    rank: idx as u32 + 1,
+                    label: peptide.label(),
+                    expmass: *precursor_mass,
+                    calcmass: peptide.monoisotopic,
+                    charge: m.spectrum.charge,
+                    isolation_window: precursor
+                        .isolation_window
+                        .map(|window| window.bounds(precursor.mz)),
+                    rt: query.scan_start_time,
+                    aligned_rt: query.scan_start_time,
+                    delta_rt_model: 0.999,
+                    ion_mobility: precursor.ion_mobility.or(query.ion_mobility),
+                    delta_mass,
+                    mass_offset: precursor_mass - peptide.monoisotopic,
+                    average_ppm: m.average_ppm,
+                    hyperscore: m.hyperscore,
+                    delta_next: (m.spectral_angle - next) as f64,
+                    delta_best: (best.unwrap_or_default() - m.spectral_angle) as f64,
+                    matched_peaks: k as u32,
+                    longest_b: m.longest_b,
+                    longest_y: m.longest_y,
+                    longest_y_pct: m.longest_y as f32 / peptide.sequence.len() as f32,
+                    fragment_coverage: m.fragment_coverage,
+                    missed_cleavages: peptide.missed_cleavages,
+                    matched_intensity_pct: 100.0 * m.matched_intensity / query.total_ion_current,
+                    scored_candidates,
+                    poisson: poisson.log10(),
+                    posterior_error: 1.0,
+                    spectrum_q: 1.0,
+                    peptide_q: 1.0,
+                    protein_q: 1.0,
+                    ms2_intensity: m.matched_intensity,
+                    spectral_angle: Some(m.spectral_angle),
+                    irt: None,
+                    search_pass: 1,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Length of the longest run of consecutive fragment ordinals
+fn longest_series(ordinals: &mut [i32]) -> u32 {
+    ordinals.sort_unstable();
+    let (mut longest, mut current) = (0, 0);
+    let mut previous = None;
+    for &ordinal in ordinals.iter() {
+        current = match previous {
+            Some(prev) if ordinal == prev + 1 => current + 1,
+            Some(prev) if ordinal == prev => current,
+            _ => 1,
+        };
+        longest = longest.max(current);
+        previous = Some(ordinal);
+    }
+    longest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::spectrum::{Peak, Precursor};
+
+    #[test]
+    fn library_match() {
+        let fasta = Fasta::parse(
+            ">sp|A\nMEWKLEQSMREQALLKAQLTQLKGAVLRLESLIEK".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let target = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LESLIEK")
+            .unwrap();
+        let len = target.sequence.len() as i32;
+        // Reference spectrum: y ions decreasing in intensity, b ions weak
+        let fragments = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| {
+                IonSeries::new(target, kind)
+                    .enumerate()
+                    .map(move |(idx, ion)| (kind, idx as i32, ion))
+            })
+            .map(|(kind, idx, ion)| LibraryFragment {
+                kind,
+                ordinal: match kind {
+                    Kind::B => idx + 1,
+                    _ => len - 1 - idx,
+                },
+                charge: 1,
+                mz: ion.monoisotopic_mass + PROTON,
+                intensity: match kind {
+                    Kind::B => 0.1,
+                    _ => 1.0 / (idx + 1) as f32,
+                },
+            })
+            .collect::<Vec<_>>();
+        let entries = vec![
+            LibraryRecord {
+                peptide: "LESLIEK".into(),
+                charge: 2,
+                fragments: fragments.clone(),
+            },
+            LibraryRecord {
+                peptide: "PEPTIDEK".into(),
+                charge: 2,
+                fragments: Vec::new(),
+            },
+        ];
+        let settings = LibrarySearchSettings::default();
+        let (spectra, missing) = prepare(&db, entries, &settings);
+        assert_eq!(missing, 1);
+        assert_eq!(spectra.len(), 2);
+        let decoy = spectra.iter().find(|s| db[s.peptide_idx].decoy).unwrap();
+        assert_eq!(db[decoy.peptide_idx].to_string(), "LEILSEK");
+        assert_eq!(
+            decoy.fragments.len(),
+            settings.fragments.min(fragments.len())
+        );
+
+        // Query: the library spectrum itself, with scaled intensities
+        let mut peaks = fragments
+            .iter()
+            .map(|frag| Peak {
+                mass: frag.mz - PROTON,
+                intensity: frag.intensity * 1000.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "library".into(),
+            precursors: vec![Precursor {
+                mz: target.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            total_ion_current: peaks.iter().map(|p| p.intensity).sum(),
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = LibraryScorer {
+            db: &db,
+            spectra: &spectra,
+            settings,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_precursor_charge: 2,
+            max_precursor_charge: 4,
+            report_psms: 2,
+        };
+        let psms = scorer.score(&query);
+        assert_eq!(psms.len(), 2);
+        assert_eq!(db[psms[0].peptide_idx].to_string(), "LESLIEK");
+        assert_eq!(psms[0].label, 1);
+        assert!((psms[0].spectral_angle.unwrap() - 1.0).abs() < 1E-3);
+        assert_eq!(psms[0].fragment_coverage, 1.0);
+        assert!(psms[1].spectral_angle < psms[0].spectral_angle);
+        assert_eq!(psms[1].label, -1);
+    }
+}
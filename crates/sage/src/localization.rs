@@ -0,0 +1,327 @@
+//! Site localization of variable modifications
+//!
+//! A PSM only tells us that a peptide carries some number of modifications -
+//! the exact positions are frequently ambiguous (e.g. a phosphorylation on
+//! one of several adjacent S/T/Y residues). For each PSM with residue-specific
+//! variable modifications, all positional isomers (the same modifications,
+//! distributed over all eligible residues) are rescored against the spectrum.
+//!
+//! Similar to the MaxQuant PTM score, each isomer is scored by the binomial
+//! probability of matching at least as many fragment ions by chance, given the
+//! peak density of the spectrum. Isomer scores are normalized into
+//! probabilities, and the localization probability of a site is the summed
+//! probability of all isomers modified at that site.
+
+use crate::ion_series::IonSeries;
+use crate::modification::ModificationSpecificity;
+use crate::peptide::Peptide;
+use crate::scoring::Scorer;
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use itertools::Itertools;
+
+/// Maximum number of positional isomers scored for each modification mass.
+/// PSMs with more isomers are not localized
+const MAX_ISOMERS: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Localization {
+    /// Lowest localization probability of any of the modified sites of the
+    /// PSM's peptide
+    pub probability: f32,
+    /// Localization probability of every eligible site, e.g.
+    /// `S3[+79.96633]:0.981;T5[+79.96633]:0.019` (1-based positions)
+    pub sites: String,
+}
+
+/// Localize the residue-specific variable modifications of `peptide`
+/// (identified with `precursor_charge`) against `query`. Returns `None` if the
+/// peptide has no such modifications, or too many positional isomers
+pub fn localize(
+    scorer: &Scorer,
+    peptide: &Peptide,
+    precursor_charge: u8,
+    query: &ProcessedSpectrum,
+) -> Option<Localization> {
+    // Group residue-specific variable modifications by mass, e.g. phospho on
+    // S, T and Y are localized together
+    let mut groups: Vec<(f32, Vec<u8>)> = Vec::new();
+    for (specificity, mass) in &scorer.db.potential_mods {
+        if let ModificationSpecificity::Residue(residue) = specificity {
+            match groups.iter_mut().find(|(m, _)| m == mass) {
+                Some((_, residues)) => residues.push(*residue),
+                None => groups.push((*mass, vec![*residue])),
+            }
+        }
+    }
+
+    let p = random_match_probability(scorer, query);
+    let max_fragment_charge =
+        crate::scoring::max_fragment_charge(scorer.max_fragment_charge, precursor_charge);
+
+    let mut sites = Vec::new();
+    let mut probability = 1.0f32;
+    for (mass, residues) in groups {
+        let eligible = peptide
+            .sequence
+            .iter()
+            .zip(&peptide.modifications)
+            .enumerate()
+            .filter(|(_, (residue, m))| residues.contains(residue) && (**m == 0.0 || **m == mass))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let modified = eligible
+            .iter()
+            .filter(|&&idx| peptide.modifications[idx] == mass)
+            .count();
+        if modified == 0 {
+            continue;
+        }
+        if binomial(eligible.len(), modified) > MAX_ISOMERS {
+            return None;
+        }
+
+        // Score all positional isomers, and convert scores to probabilities
+        let mut isomer = peptide.clone();
+        let isomers = eligible
+            .iter()
+            .copied()
+            .combinations(modified)
+            .map(|positions| {
+                for idx in &eligible {
+                    isomer.modifications[*idx] = 0.0;
+                }
+                for idx in &positions {
+                    isomer.modifications[*idx] = mass;
+                }
+                let (n, k) = matched_fragments(scorer, &isomer, max_fragment_charge, query);
+                (positions, -ln_binomial_tail(n, k, p))
+            })
+            .collect::<Vec<_>>();
+        let best = isomers
+            .iter()
+            .map(|(_, score)| *score)
+            .fold(f64::MIN, f64::max);
+        let weights = isomers
+            .iter()
+            .map(|(_, score)| (score - best).exp())
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<f64>();
+
+        for idx in &eligible {
+            let site = isomers
+                .iter()
+                .zip(&weights)
+                .filter(|((positions, _), _)| positions.contains(idx))
+                .map(|(_, weight)| weight)
+                .sum::<f64>()
+                / total;
+            let site = site as f32;
+            if peptide.modifications[*idx] == mass {
+                probability = probability.min(site);
+            }
+            sites.push((*idx, mass, site));
+        }
+    }
+
+    if sites.is_empty() {
+        return None;
+    }
+    sites.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.total_cmp(&b.1)));
+    let sites = sites
+        .into_iter()
+        .map(|(idx, mass, site)| {
+            format!(
+                "{}{}[{:+}]:{:.3}",
+                peptide.sequence[idx] as char,
+                idx + 1,
+                mass,
+                site
+            )
+        })
+        .join(";");
+
+    Some(Localization { probability, sites })
+}
+
+/// Probability that a theoretical fragment matches a peak in `query` by
+/// chance: the fraction of the spectrum's mass range covered by fragment
+/// tolerance windows around its peaks
+fn random_match_probability(scorer: &Scorer, query: &ProcessedSpectrum) -> f64 {
+    let (first, last) = match (query.peaks.first(), query.peaks.last()) {
+        (Some(first), Some(last)) => (first.mass, last.mass),
+        _ => return 0.5,
+    };
+    let (lo, hi) = scorer.fragment_tol.bounds((first + last) / 2.0);
+    let covered = query.peaks.len() as f64 * (hi - lo) as f64;
+    (covered / (last - first).max(1.0) as f64).clamp(1E-6, 0.5)
+}
+
+/// Number of theoretical fragments of `peptide` within the fragment mass
+/// range, and the number of them matching a peak in `query`
+fn matched_fragments(
+    scorer: &Scorer,
+    peptide: &Peptide,
+    max_fragment_charge: u8,
+    query: &ProcessedSpectrum,
+) -> (u32, u32) {
+    let mut n = 0;
+    let mut k = 0;
+    for kind in &scorer.db.ion_kinds {
+        for ion in IonSeries::with_residue_masses(peptide, *kind, scorer.db.residue_masses) {
+            for charge in 1..max_fragment_charge {
+                let mz = ion.monoisotopic_mass / charge as f32;
+                if mz < scorer.min_fragment_mass || mz > scorer.max_fragment_mass {
+                    continue;
+                }
+                n += 1;
+                if select_most_intense_peak(&query.peaks, mz, scorer.fragment_tol, None).is_some() {
+                    k += 1;
+                }
+            }
+        }
+    }
+    (n, k)
+}
+
+/// Number of ways of choosing `k` of `n` items, saturating at `usize::MAX`
+fn binomial(n: usize, k: usize) -> usize {
+    let mut acc = 1usize;
+    for i in 0..k.min(n - k) {
+        acc = match acc.checked_mul(n - i) {
+            Some(x) => x / (i + 1),
+            None => return usize::MAX,
+        };
+    }
+    acc
+}
+
+/// Natural log of the binomial survival function, P(X >= k) for X ~ B(n, p)
+fn ln_binomial_tail(n: u32, k: u32, p: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+    let ln_choose = |j: u32| -> f64 {
+        (1..=j)
+            .map(|i| ((n - j + i) as f64 / i as f64).ln())
+            .sum::<f64>()
+    };
+    let terms = (k..=n)
+        .map(|j| ln_choose(j) + j as f64 * p.ln() + (n - j) as f64 * (1.0 - p).ln())
+        .collect::<Vec<_>>();
+    let max = terms.iter().copied().fold(f64::MIN, f64::max);
+    max + terms.iter().map(|t| (t - max).exp()).sum::<f64>().ln()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::ion_series::Kind;
+    use crate::mass::Tolerance;
+    use crate::scoring::IsotopeErrorMode;
+    use crate::spectrum::Peak;
+
+    #[test]
+    fn phosphosite() {
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let mut db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        for residue in [b'S', b'T'] {
+            db.potential_mods
+                .push((ModificationSpecificity::Residue(residue), 79.96633));
+        }
+
+        let mut peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap()
+            .clone();
+        peptide.modifications[2] = 79.96633;
+
+        // Spectrum of the S3-phosphorylated isomer
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(&peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "phospho".into(),
+            file_id: 0,
+            scan_start_time: 0.0,
+            ion_injection_time: 0.0,
+            precursors: Vec::new(),
+            peaks,
+            total_ion_current: 0.0,
+            noise: Vec::new(),
+            ion_mobility: None,
+            compensation_voltage: None,
+        };
+
+        let scorer = Scorer {
+            db: &db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 4,
+            min_fragment_coverage: 0.0,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            isotope_error_mode: IsotopeErrorMode::Discrete,
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            max_fragment_charge: Some(1),
+            min_fragment_mass: 0.0,
+            max_fragment_mass: 2000.0,
+            chimera: false,
+            report_psms: 1,
+            wide_window: false,
+            annotate_matches: false,
+            open_search: false,
+            prefilter: None,
+            mobility_filter: None,
+            neutral_losses: &[],
+            localize: true,
+            feature_hook: None,
+        };
+
+        let correct = localize(&scorer, &peptide, 2, &query).unwrap();
+        assert!(correct.probability > 0.99);
+        assert_eq!(correct.sites, "S3[+79.96633]:1.000;T17[+79.96633]:0.000");
+
+        // The same spectrum, identified with the phosphate on the wrong site
+        peptide.modifications[2] = 0.0;
+        peptide.modifications[16] = 79.96633;
+        let wrong = localize(&scorer, &peptide, 2, &query).unwrap();
+        assert!(wrong.probability < 0.01);
+
+        peptide.modifications[16] = 0.0;
+        assert_eq!(localize(&scorer, &peptide, 2, &query), None);
+    }
+
+    #[test]
+    fn binomial_tail() {
+        assert_eq!(binomial(5, 2), 10);
+        assert_eq!(binomial(4, 4), 1);
+        assert_eq!(binomial(200, 100), usize::MAX);
+
+        // P(X >= 1), X ~ B(2, 0.5) = 0.75
+        assert!((ln_binomial_tail(2, 1, 0.5) - 0.75f64.ln()).abs() < 1E-9);
+        // P(X >= 3), X ~ B(3, 0.1) = 0.001
+        assert!((ln_binomial_tail(3, 3, 0.1) - 0.001f64.ln()).abs() < 1E-9);
+        assert_eq!(ln_binomial_tail(10, 0, 0.1), 0.0);
+    }
+}
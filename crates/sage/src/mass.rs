@@ -1,4 +1,4 @@
-use std::{iter::Sum, ops::Mul};
+use std::{iter::Sum, ops::Mul, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +8,7 @@ pub const NEUTRON: f32 = 1.00335;
 pub const NH3: f32 = 17.026548;
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(rename_all = "lowercase")]
 pub enum Tolerance {
     Ppm(f32, f32),
@@ -38,6 +39,52 @@ impl Tolerance {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidTolerance(pub String);
+
+impl std::fmt::Display for InvalidTolerance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid tolerance `{}`, expected e.g. `10ppm`, `-10,20ppm` or `0.5da`",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidTolerance {}
+
+/// Parse a tolerance such as `10ppm` (symmetric, i.e. -10 to 10 ppm),
+/// `-10,20ppm` or `0.5da`
+impl FromStr for Tolerance {
+    type Err = InvalidTolerance;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidTolerance(s.into());
+        let lower = s.trim().to_ascii_lowercase();
+        let (values, ppm) = match (lower.strip_suffix("ppm"), lower.strip_suffix("da")) {
+            (Some(values), _) => (values, true),
+            (_, Some(values)) => (values, false),
+            _ => return Err(invalid()),
+        };
+        let parse = |value: &str| value.trim().parse::<f32>().map_err(|_| invalid());
+        let (lo, hi) = match values.split_once(',') {
+            Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+            None => {
+                let value = parse(values)?.abs();
+                (-value, value)
+            }
+        };
+        if !lo.is_finite() || !hi.is_finite() || lo > hi {
+            return Err(invalid());
+        }
+        Ok(match ppm {
+            true => Tolerance::Ppm(lo, hi),
+            false => Tolerance::Da(lo, hi),
+        })
+    }
+}
+
 impl Mul<f32> for Tolerance {
     type Output = Tolerance;
 
@@ -68,6 +115,49 @@ pub const fn monoisotopic(aa: u8) -> f32 {
     }
 }
 
+/// Monoisotopic residue masses used for peptide and fragment ion masses.
+///
+/// Defaults to [`MONOISOTOPIC_MASSES`], but the mass of individual residues can
+/// be overridden (e.g. heavy-labeled residues), or non-standard residues added
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ResidueMasses([f32; 26]);
+
+impl Default for ResidueMasses {
+    fn default() -> Self {
+        Self(MONOISOTOPIC_MASSES)
+    }
+}
+
+impl ResidueMasses {
+    /// Set the monoisotopic mass of residue `aa`. Returns false if `aa` is not
+    /// an uppercase ASCII letter
+    pub fn set(&mut self, aa: u8, mass: f32) -> bool {
+        if aa.is_ascii_uppercase() {
+            self.0[(aa - b'A') as usize] = mass;
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    pub fn monoisotopic(&self, aa: u8) -> f32 {
+        if aa.is_ascii_uppercase() {
+            self.0[(aa - b'A') as usize]
+        } else {
+            0.0
+        }
+    }
+
+    /// Residues with a mass different from the default, as (residue, mass)
+    pub fn overrides(&self) -> impl Iterator<Item = (u8, f32)> + '_ {
+        (b'A'..=b'Z')
+            .zip(self.0.iter().zip(MONOISOTOPIC_MASSES.iter()))
+            .filter(|(_, (mass, default))| mass != default)
+            .map(|(aa, (mass, _))| (aa, *mass))
+    }
+}
+
 pub const fn composition(aa: u8) -> Composition {
     match aa {
         b'A' => Composition::new(3, 2, 0),
@@ -124,7 +214,7 @@ impl Sum for Composition {
 mod test {
     use crate::mass::monoisotopic;
 
-    use super::{Tolerance, VALID_AA};
+    use super::{ResidueMasses, Tolerance, VALID_AA};
 
     #[test]
     fn smoke() {
@@ -133,6 +223,24 @@ mod test {
         }
     }
 
+    #[test]
+    fn residue_masses() {
+        let mut masses = ResidueMasses::default();
+        for ch in VALID_AA {
+            assert_eq!(masses.monoisotopic(ch), monoisotopic(ch));
+        }
+        assert_eq!(masses.overrides().count(), 0);
+
+        assert!(masses.set(b'K', 136.10916));
+        assert!(masses.set(b'X', 150.0));
+        assert!(!masses.set(b'[', 1.0));
+        assert_eq!(masses.monoisotopic(b'X'), 150.0);
+        assert_eq!(
+            masses.overrides().collect::<Vec<_>>(),
+            vec![(b'K', 136.10916), (b'X', 150.0)]
+        );
+    }
+
     #[test]
     fn tolerances() {
         assert_eq!(
@@ -148,4 +256,15 @@ mod test {
             (999.95, 1000.05)
         );
     }
+
+    #[test]
+    fn parse_tolerances() {
+        assert_eq!("10ppm".parse(), Ok(Tolerance::Ppm(-10.0, 10.0)));
+        assert_eq!("-10, 20 PPM".parse(), Ok(Tolerance::Ppm(-10.0, 20.0)));
+        assert_eq!("0.5da".parse(), Ok(Tolerance::Da(-0.5, 0.5)));
+        assert_eq!("-0.5,1.25Da".parse(), Ok(Tolerance::Da(-0.5, 1.25)));
+        for invalid in ["10", "ppm", "10,ppm", "20,10ppm", "nanda", "10mda"] {
+            assert!(invalid.parse::<Tolerance>().is_err(), "{}", invalid);
+        }
+    }
 }
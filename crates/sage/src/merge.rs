@@ -0,0 +1,226 @@
+//! Merging of MS2 spectra repeatedly acquired on the same precursor
+//!
+//! Instruments often select a precursor several times as it elutes, e.g.
+//! with a short dynamic exclusion. For a low-abundance precursor, each of
+//! these spectra contains only a few fragment ions above noise: summing them
+//! reinforces fragment peaks, which are found in every spectrum, over noise
+//! peaks, which are not
+
+use crate::spectrum::{Peak, ProcessedSpectrum, SpectrumProcessor};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Settings of the merging of MS2 spectra of the same precursor
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SpectrumMerging {
+    /// Maximum retention time difference, in minutes, between the first
+    /// spectrum of a group and any other
+    pub rt_window: f32,
+    /// Tolerance, in ppm, of the precursor m/z of spectra of a group
+    pub precursor_ppm: f32,
+    /// Tolerance, in ppm, of fragment peaks summed into a single peak
+    pub fragment_ppm: f32,
+}
+
+impl Default for SpectrumMerging {
+    fn default() -> Self {
+        SpectrumMerging {
+            rt_window: 0.5,
+            precursor_ppm: 10.0,
+            fragment_ppm: 10.0,
+        }
+    }
+}
+
+/// Number of MS2 spectra merged by [`SpectrumMerging`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergedSpectra {
+    /// Groups of two or more spectra, each replaced by a single spectrum
+    pub groups: usize,
+    /// Spectra in these groups
+    pub spectra: usize,
+}
+
+impl SpectrumMerging {
+    /// Merge the MS2 spectra of a single file whose only precursor has the
+    /// same charge and m/z, and that were acquired within `rt_window` of each
+    /// other. Each group is replaced by one spectrum, with the identifier,
+    /// precursor and retention time of its most intense member, and the
+    /// summed peaks of all members - selected again by `processor`, as the
+    /// peaks of a single spectrum would be.
+    ///
+    /// Spectra are returned in retention time order
+    pub fn merge(
+        &self,
+        mut spectra: Vec<ProcessedSpectrum>,
+        processor: &SpectrumProcessor,
+    ) -> (Vec<ProcessedSpectrum>, MergedSpectra) {
+        spectra.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+
+        // Indices of the spectra of each group, and the groups that spectra
+        // can still be added to: (group, retention time, m/z and charge of
+        // its first spectrum)
+        let mut groups: Vec<Vec<usize>> = Vec::with_capacity(spectra.len());
+        let mut open: VecDeque<(usize, f32, f32, Option<u8>)> = VecDeque::new();
+        for (idx, spectrum) in spectra.iter().enumerate() {
+            let precursor = match (spectrum.level, spectrum.precursors.as_slice()) {
+                (2, [precursor]) => precursor,
+                _ => {
+                    groups.push(vec![idx]);
+                    continue;
+                }
+            };
+            let rt = spectrum.scan_start_time;
+            while let Some(&(_, start, _, _)) = open.front() {
+                if rt - start <= self.rt_window {
+                    break;
+                }
+                open.pop_front();
+            }
+            let group = open.iter().find(|&&(_, _, mz, charge)| {
+                charge == precursor.charge
+                    && (precursor.mz - mz).abs() <= mz * self.precursor_ppm / 1E6
+            });
+            match group {
+                Some(&(group, ..)) => groups[group].push(idx),
+                None => {
+                    open.push_back((groups.len(), rt, precursor.mz, precursor.charge));
+                    groups.push(vec![idx]);
+                }
+            }
+        }
+
+        let mut merged = MergedSpectra::default();
+        let mut spectra = spectra.into_iter().map(Some).collect::<Vec<_>>();
+        let mut output = groups
+            .into_iter()
+            .map(|group| {
+                let mut members = group
+                    .iter()
+                    .filter_map(|&idx| spectra[idx].take())
+                    .collect::<Vec<_>>();
+                if members.len() > 1 {
+                    merged.groups += 1;
+                    merged.spectra += members.len();
+                    self.merge_group(members, processor)
+                } else {
+                    members.swap_remove(0)
+                }
+            })
+            .collect::<Vec<_>>();
+        output.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+        (output, merged)
+    }
+
+    fn merge_group(
+        &self,
+        mut members: Vec<ProcessedSpectrum>,
+        processor: &SpectrumProcessor,
+    ) -> ProcessedSpectrum {
+        let mut peaks = members
+            .iter()
+            .flat_map(|spectrum| spectrum.peaks.iter().copied())
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        // Peaks within `fragment_ppm` of the first peak of a cluster are
+        // summed, at their intensity-weighted mean mass
+        let mut summed: Vec<Peak> = Vec::with_capacity(peaks.len());
+        let mut start = 0.0;
+        for peak in peaks {
+            match summed.last_mut() {
+                Some(last) if peak.mass - start <= start * self.fragment_ppm / 1E6 => {
+                    let intensity = last.intensity + peak.intensity;
+                    if intensity > 0.0 {
+                        last.mass =
+                            (last.mass * last.intensity + peak.mass * peak.intensity) / intensity;
+                    }
+                    last.intensity = intensity;
+                }
+                _ => {
+                    start = peak.mass;
+                    summed.push(peak);
+                }
+            }
+        }
+        processor.select_peaks(&mut summed);
+        summed.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let ion_injection_time = members.iter().map(|s| s.ion_injection_time).sum();
+        let representative = members
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_ion_current.total_cmp(&b.total_ion_current))
+            .map(|(idx, _)| idx)
+            .unwrap_or_default();
+        let mut spectrum = members.swap_remove(representative);
+        spectrum.total_ion_current = summed.iter().map(|peak| peak.intensity).sum();
+        spectrum.peaks = summed;
+        spectrum.ion_injection_time = ion_injection_time;
+        spectrum
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spectrum::Precursor;
+
+    fn spectrum(id: &str, rt: f32, mz: f32, peaks: &[(f32, f32)]) -> ProcessedSpectrum {
+        let peaks = peaks
+            .iter()
+            .map(|&(mass, intensity)| Peak { mass, intensity })
+            .collect::<Vec<_>>();
+        ProcessedSpectrum {
+            level: 2,
+            id: id.into(),
+            scan_start_time: rt,
+            ion_injection_time: 10.0,
+            precursors: vec![Precursor {
+                mz,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            total_ion_current: peaks.iter().map(|peak| peak.intensity).sum(),
+            peaks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_replicate_spectra() {
+        let processor = SpectrumProcessor::new(3, 0.0, 2000.0, false);
+        let spectra = vec![
+            spectrum("scan=3", 10.2, 500.0025, &[(300.0, 5.0), (400.001, 30.0)]),
+            spectrum(
+                "scan=1",
+                10.0,
+                500.0,
+                &[(300.0, 10.0), (400.0, 10.0), (450.0, 1.0)],
+            ),
+            // Other precursor m/z, and too late
+            spectrum("scan=2", 10.1, 500.1, &[(300.0, 10.0)]),
+            spectrum("scan=4", 11.0, 500.0, &[(300.0, 10.0)]),
+        ];
+        let (spectra, merged) = SpectrumMerging::default().merge(spectra, &processor);
+        assert_eq!(
+            merged,
+            MergedSpectra {
+                groups: 1,
+                spectra: 2
+            }
+        );
+
+        let ids = spectra.iter().map(|s| s.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["scan=2", "scan=3", "scan=4"]);
+        let merged = &spectra[1];
+        assert_eq!(merged.scan_start_time, 10.2);
+        assert_eq!(merged.ion_injection_time, 20.0);
+        assert_eq!(merged.peaks.len(), 3);
+        assert_eq!(merged.peaks[0].intensity, 15.0);
+        assert_eq!(merged.peaks[1].intensity, 40.0);
+        assert!((merged.peaks[1].mass - 400.00075).abs() < 1E-4);
+        assert_eq!(merged.total_ion_current, 56.0);
+    }
+}
@@ -0,0 +1,196 @@
+//! Diagnostics for the machine learning stage of a search
+//!
+//! Linear discriminant analysis and retention time prediction are both
+//! trained on the search results themselves, and can misbehave on unusual
+//! datasets (e.g. very few confident identifications, non-tryptic digests,
+//! or non-standard chromatography). Comparing the distributions of each
+//! feature between targets and decoys, and the observed vs. predicted
+//! retention times, is a quick way to check that the models behaved sensibly
+//! before trusting the resulting q-values.
+
+use crate::scoring::Feature;
+use serde::Serialize;
+
+/// Number of bins in each [`FeatureDistribution`]
+const BINS: usize = 50;
+
+type Accessor = fn(&Feature) -> f32;
+
+/// Features whose target and decoy distributions are reported
+const DISTRIBUTIONS: [(&str, Accessor); 20] = [
+    ("hyperscore", |f| f.hyperscore as f32),
+    ("delta_next", |f| f.delta_next as f32),
+    ("delta_best", |f| f.delta_best as f32),
+    ("delta_mass", |f| f.delta_mass),
+    ("isotope_error", |f| f.isotope_error),
+    ("average_ppm", |f| f.average_ppm),
+    ("poisson", |f| f.poisson as f32),
+    ("matched_peaks", |f| f.matched_peaks as f32),
+    ("matched_intensity_pct", |f| f.matched_intensity_pct),
+    ("longest_b", |f| f.longest_b as f32),
+    ("longest_y", |f| f.longest_y as f32),
+    ("longest_y_pct", |f| f.longest_y_pct),
+    ("fragment_coverage", |f| f.fragment_coverage),
+    ("aligned_rt", |f| f.aligned_rt),
+    ("delta_rt_model", |f| f.delta_rt_model),
+    ("delta_mobility_model", |f| f.delta_mobility_model),
+    ("predicted_spectral_angle", |f| f.predicted_spectral_angle),
+    ("predicted_correlation", |f| f.predicted_correlation),
+    ("discriminant_score", |f| f.discriminant_score),
+    ("posterior_error", |f| f.posterior_error),
+];
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Diagnostics {
+    /// Target and decoy distributions of each PSM feature
+    pub features: Vec<FeatureDistribution>,
+    /// Retention time model performance, if a model was fit
+    pub retention_time: Option<RetentionTimeDiagnostics>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FeatureDistribution {
+    pub feature: String,
+    /// Lower edge of each bin. All bins have equal width, and the last bin
+    /// also includes the maximum value
+    pub bins: Vec<f32>,
+    pub targets: Vec<usize>,
+    pub decoys: Vec<usize>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RetentionTimeDiagnostics {
+    /// Coefficient of determination of the model on its training set
+    pub r2: f64,
+    /// Median absolute difference between aligned and predicted retention
+    /// times of target PSMs with spectrum-level q <= 0.01
+    pub median_error_targets: Option<f32>,
+    /// Median absolute difference between aligned and predicted retention
+    /// times of decoy PSMs
+    pub median_error_decoys: Option<f32>,
+}
+
+/// Build [`Diagnostics`] from PSMs with assigned discriminant scores and
+/// q-values. `rt_model_r2` is the fit of the retention time model, if any
+pub fn diagnostics(features: &[Feature], rt_model_r2: Option<f64>) -> Diagnostics {
+    let distributions = DISTRIBUTIONS
+        .iter()
+        .map(|(name, value)| histogram(name, features, *value))
+        .collect();
+
+    let retention_time = rt_model_r2.map(|r2| RetentionTimeDiagnostics {
+        r2,
+        median_error_targets: median_error(
+            features
+                .iter()
+                .filter(|f| f.label == 1 && f.spectrum_q <= 0.01),
+        ),
+        median_error_decoys: median_error(features.iter().filter(|f| f.label == -1)),
+    });
+
+    Diagnostics {
+        features: distributions,
+        retention_time,
+    }
+}
+
+fn histogram(name: &str, features: &[Feature], value: Accessor) -> FeatureDistribution {
+    let (lo, hi) = features
+        .iter()
+        .map(value)
+        .filter(|x| x.is_finite())
+        .fold((f32::MAX, f32::MIN), |(lo, hi), x| (lo.min(x), hi.max(x)));
+
+    if lo > hi {
+        // No finite values
+        return FeatureDistribution {
+            feature: name.into(),
+            bins: Vec::new(),
+            targets: Vec::new(),
+            decoys: Vec::new(),
+        };
+    }
+
+    let mut targets = vec![0; BINS];
+    let mut decoys = vec![0; BINS];
+    let width = (hi - lo) / BINS as f32;
+    for feat in features {
+        let x = value(feat);
+        if !x.is_finite() {
+            continue;
+        }
+        let bin = match width > 0.0 {
+            true => (((x - lo) / width) as usize).min(BINS - 1),
+            false => 0,
+        };
+        match feat.label == -1 {
+            true => decoys[bin] += 1,
+            false => targets[bin] += 1,
+        }
+    }
+
+    FeatureDistribution {
+        feature: name.into(),
+        bins: (0..BINS).map(|bin| lo + width * bin as f32).collect(),
+        targets,
+        decoys,
+    }
+}
+
+fn median_error<'a, I: Iterator<Item = &'a Feature>>(features: I) -> Option<f32> {
+    let mut errors = features
+        .map(|f| (f.aligned_rt - f.predicted_rt).abs())
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        return None;
+    }
+    errors.sort_unstable_by(|a, b| a.total_cmp(b));
+    Some(errors[errors.len() / 2])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn target_decoy_distributions() {
+        let feature = |label, hyperscore, aligned_rt, predicted_rt| Feature {
+            label,
+            hyperscore,
+            aligned_rt,
+            predicted_rt,
+            spectrum_q: 0.001,
+            ..Default::default()
+        };
+        let features = vec![
+            feature(1, 50.0, 0.5, 0.5),
+            feature(1, 40.0, 0.2, 0.3),
+            feature(1, 30.0, 0.7, 0.6),
+            feature(-1, 10.0, 0.1, 0.9),
+            feature(-1, 0.0, 0.9, 0.1),
+        ];
+
+        let diagnostics = diagnostics(&features, Some(0.9));
+        let hyperscore = diagnostics
+            .features
+            .iter()
+            .find(|d| d.feature == "hyperscore")
+            .unwrap();
+        assert_eq!(hyperscore.bins.len(), BINS);
+        assert_eq!(hyperscore.bins[0], 0.0);
+        assert_eq!(hyperscore.targets.iter().sum::<usize>(), 3);
+        assert_eq!(hyperscore.decoys.iter().sum::<usize>(), 2);
+        // Maximum value falls into the last bin
+        assert_eq!(hyperscore.targets[BINS - 1], 1);
+        assert_eq!(hyperscore.decoys[0], 1);
+
+        let rt = diagnostics.retention_time.unwrap();
+        assert!((rt.median_error_targets.unwrap() - 0.1).abs() < 1E-6);
+        assert!((rt.median_error_decoys.unwrap() - 0.8).abs() < 1E-6);
+
+        assert!(super::diagnostics(&features, None).retention_time.is_none());
+    }
+}
@@ -0,0 +1,295 @@
+//! Gradient-boosted decision trees for non-linear PSM rescoring
+//!
+//! A small, histogram-based implementation of gradient boosting with a
+//! logistic loss: each feature is discretized into quantile bins, and shallow
+//! regression trees are fit to the gradients of the loss, one after another.
+//! Unlike a linear discriminant, trees capture interactions between features
+//! (e.g. a large mass error is only suspicious for PSMs with few matched
+//! peaks).
+
+use super::matrix::Matrix;
+use crate::par::prelude::*;
+
+/// Number of boosting rounds (trees)
+const TREES: usize = 50;
+/// Maximum depth of each tree
+const MAX_DEPTH: usize = 3;
+/// Shrinkage applied to the output of each tree
+const LEARNING_RATE: f64 = 0.1;
+/// Maximum number of bins each feature is discretized into
+const BINS: usize = 32;
+/// Minimum number of training examples in each leaf
+const MIN_LEAF: usize = 20;
+/// L2 regularization of leaf values
+const LAMBDA: f64 = 1.0;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Leaf(f64),
+    /// Examples with `feature <= threshold` go to `left`, others to `right`
+    Split {
+        feature: usize,
+        threshold: f64,
+        left: usize,
+        right: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    fn predict(&self, row: &[f64]) -> f64 {
+        let mut idx = 0;
+        loop {
+            match self.nodes[idx] {
+                Node::Leaf(value) => return value,
+                Node::Split {
+                    feature,
+                    threshold,
+                    left,
+                    right,
+                } => {
+                    idx = if row[feature] <= threshold {
+                        left
+                    } else {
+                        right
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sum of gradients, hessians, and number of examples in a bin or node
+#[derive(Copy, Clone, Default)]
+struct Stats {
+    gradient: f64,
+    hessian: f64,
+    count: usize,
+}
+
+impl Stats {
+    fn add(&mut self, rhs: &Stats) {
+        self.gradient += rhs.gradient;
+        self.hessian += rhs.hessian;
+        self.count += rhs.count;
+    }
+
+    fn sub(&self, rhs: &Stats) -> Stats {
+        Stats {
+            gradient: self.gradient - rhs.gradient,
+            hessian: self.hessian - rhs.hessian,
+            count: self.count - rhs.count,
+        }
+    }
+
+    fn score(&self) -> f64 {
+        self.gradient.powi(2) / (self.hessian + LAMBDA)
+    }
+
+    fn leaf(&self) -> f64 {
+        -self.gradient / (self.hessian + LAMBDA)
+    }
+}
+
+/// Training data, with features discretized into bins
+struct Binned {
+    /// Upper edge of each bin, for each feature
+    edges: Vec<Vec<f64>>,
+    /// Bin of each example, for each feature (column-major)
+    bins: Vec<Vec<u8>>,
+    /// Gradient and hessian of the loss, for each example
+    gradients: Vec<(f64, f64)>,
+}
+
+impl Binned {
+    fn histogram(&self, feature: usize, rows: &[usize]) -> Vec<Stats> {
+        let mut histogram = vec![Stats::default(); self.edges[feature].len() + 1];
+        for &row in rows {
+            let stats = &mut histogram[self.bins[feature][row] as usize];
+            stats.gradient += self.gradients[row].0;
+            stats.hessian += self.gradients[row].1;
+            stats.count += 1;
+        }
+        histogram
+    }
+
+    /// Best split of `rows`: feature, bin, and gain
+    fn best_split(&self, rows: &[usize], total: &Stats) -> Option<(usize, usize, f64)> {
+        (0..self.edges.len())
+            .into_par_iter()
+            .filter_map(|feature| {
+                let histogram = self.histogram(feature, rows);
+                let mut left = Stats::default();
+                let mut best: Option<(usize, usize, f64)> = None;
+                // The last bin holds values above every edge, and cannot be split
+                for (bin, stats) in histogram.iter().enumerate().take(histogram.len() - 1) {
+                    left.add(stats);
+                    let right = total.sub(&left);
+                    if left.count < MIN_LEAF || right.count < MIN_LEAF {
+                        continue;
+                    }
+                    let gain = left.score() + right.score() - total.score();
+                    if best.map_or(true, |(_, _, g)| gain > g) {
+                        best = Some((feature, bin, gain));
+                    }
+                }
+                best
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2).then_with(|| b.0.cmp(&a.0)))
+            .filter(|(_, _, gain)| *gain > 0.0)
+    }
+
+    fn grow(&self, rows: Vec<usize>, depth: usize, nodes: &mut Vec<Node>) -> usize {
+        let mut total = Stats::default();
+        for &row in &rows {
+            total.gradient += self.gradients[row].0;
+            total.hessian += self.gradients[row].1;
+            total.count += 1;
+        }
+
+        let idx = nodes.len();
+        nodes.push(Node::Leaf(total.leaf() * LEARNING_RATE));
+        if depth == MAX_DEPTH {
+            return idx;
+        }
+        if let Some((feature, bin, _)) = self.best_split(&rows, &total) {
+            let (left, right): (Vec<usize>, Vec<usize>) = rows
+                .into_iter()
+                .partition(|&row| self.bins[feature][row] as usize <= bin);
+            let left = self.grow(left, depth + 1, nodes);
+            let right = self.grow(right, depth + 1, nodes);
+            nodes[idx] = Node::Split {
+                feature,
+                threshold: self.edges[feature][bin],
+                left,
+                right,
+            };
+        }
+        idx
+    }
+}
+
+pub struct GradientBoosting {
+    /// Initial log-odds of the target class
+    bias: f64,
+    trees: Vec<Tree>,
+}
+
+impl GradientBoosting {
+    /// Fit a model discriminating targets (higher scores) from decoys
+    pub fn train(features: &Matrix, decoy: &[bool]) -> Option<GradientBoosting> {
+        assert_eq!(features.rows, decoy.len());
+        let decoys = decoy.iter().filter(|&&d| d).count();
+        if decoys == 0 || decoys == decoy.len() {
+            return None;
+        }
+
+        let edges = (0..features.cols)
+            .into_par_iter()
+            .map(|col| {
+                let mut values = features.col(col).collect::<Vec<_>>();
+                values.sort_unstable_by(|a, b| a.total_cmp(b));
+                let mut edges = (1..BINS)
+                    .map(|bin| values[bin * (values.len() - 1) / BINS])
+                    .collect::<Vec<_>>();
+                edges.dedup();
+                edges
+            })
+            .collect::<Vec<_>>();
+        let bins = edges
+            .par_iter()
+            .enumerate()
+            .map(|(col, edges)| {
+                features
+                    .col(col)
+                    .map(|x| edges.partition_point(|edge| *edge < x) as u8)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut binned = Binned {
+            edges,
+            bins,
+            gradients: Vec::new(),
+        };
+
+        let targets = (decoy.len() - decoys) as f64;
+        let bias = (targets / decoys as f64).ln();
+        let mut predictions = vec![bias; features.rows];
+        let mut trees = Vec::with_capacity(TREES);
+        for _ in 0..TREES {
+            binned.gradients = predictions
+                .par_iter()
+                .zip(decoy)
+                .map(|(prediction, decoy)| {
+                    let p = 1.0 / (1.0 + (-prediction).exp());
+                    let y = if *decoy { 0.0 } else { 1.0 };
+                    (p - y, (p * (1.0 - p)).max(1E-6))
+                })
+                .collect::<Vec<_>>();
+            let mut nodes = Vec::new();
+            binned.grow((0..features.rows).collect(), 0, &mut nodes);
+            let tree = Tree { nodes };
+            predictions
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(row, prediction)| *prediction += tree.predict(features.row_slice(row)));
+            trees.push(tree);
+        }
+
+        log::trace!(
+            "- gradient boosting fit with {} trees ({} splits)",
+            trees.len(),
+            trees
+                .iter()
+                .flat_map(|tree| &tree.nodes)
+                .filter(|node| matches!(node, Node::Split { .. }))
+                .count()
+        );
+        Some(GradientBoosting { bias, trees })
+    }
+
+    /// Log-odds of each row of `features` being a target
+    pub fn score(&self, features: &Matrix) -> Vec<f64> {
+        (0..features.rows)
+            .into_par_iter()
+            .map(|row| {
+                let row = features.row_slice(row);
+                self.bias + self.trees.iter().map(|tree| tree.predict(row)).sum::<f64>()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gradient_boosting() {
+        // Targets lie inside a square, decoys outside - not linearly separable
+        let mut data = Vec::new();
+        let mut decoy = Vec::new();
+        for x in 0..40 {
+            for y in 0..40 {
+                data.extend([x as f64, y as f64]);
+                decoy.push(!((10..30).contains(&x) && (10..30).contains(&y)));
+            }
+        }
+        let features = Matrix::new(data, 1600, 2);
+        let model = GradientBoosting::train(&features, &decoy).expect("failed to train");
+        let scores = model.score(&features);
+
+        let correct = scores
+            .iter()
+            .zip(&decoy)
+            .filter(|(score, decoy)| (**score < 0.0) == **decoy)
+            .count();
+        assert!(correct > 1550, "{}", correct);
+
+        assert!(GradientBoosting::train(&features, &vec![true; 1600]).is_none());
+    }
+}
@@ -9,7 +9,7 @@
 use std::convert::identity;
 
 use super::*;
-use rayon::prelude::*;
+use crate::par::prelude::*;
 
 pub struct Kde<'a> {
     sample: &'a [f64],
@@ -41,7 +41,7 @@ impl<'a> Kde<'a> {
         let sum = self
             .sample
             .par_iter()
-            .fold(|| 0.0, |acc, xi| acc + self.kernel((x - xi) / h))
+            .map(|xi| self.kernel((x - xi) / h))
             .sum::<f64>();
 
         sum / self.constant
@@ -7,17 +7,35 @@
 //! first principles - we are going to implement a basic linear algebra system
 //! (complete with Gauss-Jordan elimination and eigenvector calculation) from scratch
 //! to enable LDA.
+//!
+//! PSMs are rescored with a semi-supervised, iterative procedure (as in
+//! Percolator): starting from the single feature that best separates targets
+//! from decoys, a linear model is repeatedly trained to discriminate confident
+//! targets from decoys, and used to re-rank the PSMs. Models are trained with
+//! k-fold cross-validation, so that no PSM is scored by a model that was
+//! trained on it. Gradient-boosted trees can be used in place of the linear
+//! model (see [`RescoringModel`]).
+//!
+//! A single [`LinearDiscriminantAnalysis`] can also be fit on one dataset
+//! with [`LinearDiscriminantAnalysis::fit`], serialized, and applied to the
+//! PSMs of another run with [`LinearDiscriminantAnalysis::apply`] - e.g. to
+//! rescore small runs with a model trained on a larger one.
 
 use super::gauss::Gauss;
+use super::gradient_boosting::GradientBoosting;
 use super::matrix::Matrix;
-use rayon::prelude::*;
+use super::{MlSettings, RescoringModel};
+use crate::par::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
 
 use crate::mass::Tolerance;
 use crate::scoring::Feature;
 
 // Declare, so that we have compile time checking of matrix dimensions
-const FEATURES: usize = 18;
-const FEATURE_NAMES: [&str; FEATURES] = [
+const FEATURES: usize = 22;
+/// Names of the rescoring features, in the order of [`feature_matrix`] columns
+pub const FEATURE_NAMES: [&str; FEATURES] = [
     "rank",
     "charge",
     "ln1p(hyperscore)",
@@ -32,12 +50,28 @@ const FEATURE_NAMES: [&str; FEATURES] = [
     "ln1p(longest_b)",
     "ln1p(longest_y)",
     "longest_y_pct",
+    "fragment_coverage",
     "ln1p(peptide_len)",
     "missed_cleavages",
     "rt",
     "sqrt(delta_rt_model)",
+    "delta_mobility_model",
+    "predicted_spectral_angle",
+    "predicted_correlation",
 ];
 
+/// Number of cross-validation folds
+pub(crate) const FOLDS: usize = 3;
+/// Number of training iterations within each fold
+const ITERATIONS: usize = 10;
+/// Number of training iterations within each fold, for gradient-boosted trees
+const TREE_ITERATIONS: usize = 3;
+/// Targets at or below this q-value (within the training set) are used as
+/// positive training examples
+const TRAIN_FDR: f64 = 0.01;
+/// Minimum number of positive training examples required to fit a model
+const MIN_POSITIVES: usize = 2 * FEATURES;
+
 struct Features<'a>(&'a [f64]);
 
 impl<'a> std::fmt::Debug for Features<'a> {
@@ -48,11 +82,72 @@ impl<'a> std::fmt::Debug for Features<'a> {
     }
 }
 
+/// Linear model: the discriminant score of a PSM is the dot product of its
+/// features and the eigenvector
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LinearDiscriminantAnalysis {
     eigenvector: Vec<f64>,
 }
 
 impl LinearDiscriminantAnalysis {
+    /// Fit a linear model to `scores` with the same semi-supervised procedure
+    /// as [`score_psms`], but trained on all PSMs rather than cross-validated,
+    /// so that it can be applied to other runs. Returns `None` if a model
+    /// could not be fit, e.g. there are too few confident targets
+    pub fn fit(
+        scores: &[Feature],
+        precursor_tol: Tolerance,
+        settings: &MlSettings,
+    ) -> Option<Self> {
+        let features = rescoring_features(scores, precursor_tol, settings);
+        let decoys = scores
+            .par_iter()
+            .map(|sc| sc.label == -1)
+            .collect::<Vec<_>>();
+        let rows = (0..features.rows).collect::<Vec<_>>();
+        let initial = initial_scores(&features, &decoys)?;
+        match FoldModel::train(RescoringModel::Linear, &features, &decoys, &rows, &initial) {
+            Some(FoldModel {
+                classifier: Classifier::Linear(lda),
+                ..
+            }) => Some(lda),
+            _ => None,
+        }
+    }
+
+    /// Coefficients of the model, one for each column of [`feature_matrix`]:
+    /// [`FEATURE_NAMES`], followed by custom features if the model was fit
+    /// on PSMs that have them
+    pub fn coefficients(&self) -> &[f64] {
+        &self.eigenvector
+    }
+
+    /// Assign discriminant scores and posterior error probabilities to
+    /// `scores`. Returns `None`, leaving `scores` unchanged, if their
+    /// features do not match the columns the model was fit on
+    pub fn apply(
+        &self,
+        scores: &mut [Feature],
+        precursor_tol: Tolerance,
+        settings: &MlSettings,
+    ) -> Option<()> {
+        let features = rescoring_features(scores, precursor_tol, settings);
+        if features.cols != self.eigenvector.len() {
+            log::warn!(
+                "linear model has {} coefficients, but PSMs have {} features",
+                self.eigenvector.len(),
+                features.cols
+            );
+            return None;
+        }
+        scores
+            .par_iter_mut()
+            .zip(&self.score(&features))
+            .for_each(|(perc, score)| perc.discriminant_score = *score as f32);
+        super::pep::assign(scores, settings.pep);
+        Some(())
+    }
+
     pub fn train(features: &Matrix, decoy: &[bool]) -> Option<LinearDiscriminantAnalysis> {
         assert_eq!(features.rows, decoy.len());
 
@@ -120,8 +215,246 @@ impl LinearDiscriminantAnalysis {
     }
 }
 
-pub fn score_psms(scores: &mut [Feature], precursor_tol: Tolerance) -> Option<()> {
-    log::trace!("fitting linear discriminant model...");
+/// Number of targets at or below `fdr` when ranked by descending score, and
+/// the lowest score of a passing target (infinite if there are none)
+fn passing_targets(scores: &[f64], decoy: &[bool], fdr: f64) -> (usize, f64) {
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_unstable_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+
+    let (mut targets, mut decoys) = (0, 0);
+    let mut passing = (0, f64::INFINITY);
+    for idx in order {
+        match decoy[idx] {
+            true => decoys += 1,
+            false => {
+                targets += 1;
+                if decoys as f64 / targets as f64 <= fdr {
+                    passing = (targets, scores[idx]);
+                }
+            }
+        }
+    }
+    passing
+}
+
+enum Classifier {
+    Linear(LinearDiscriminantAnalysis),
+    Trees(GradientBoosting),
+}
+
+impl Classifier {
+    fn train(model: RescoringModel, features: &Matrix, decoy: &[bool]) -> Option<Self> {
+        match model {
+            RescoringModel::Linear => LinearDiscriminantAnalysis::train(features, decoy)
+                .filter(|lda| lda.eigenvector.iter().all(|f| f.is_finite()))
+                .map(Classifier::Linear),
+            RescoringModel::Trees => {
+                GradientBoosting::train(features, decoy).map(Classifier::Trees)
+            }
+            RescoringModel::None => None,
+        }
+    }
+
+    fn score(&self, features: &Matrix) -> Vec<f64> {
+        match self {
+            Classifier::Linear(lda) => lda.score(features),
+            Classifier::Trees(trees) => trees.score(features),
+        }
+    }
+}
+
+/// Model trained on a single cross-validation fold, and the scores used to
+/// put models of different folds on the same scale
+struct FoldModel {
+    classifier: Classifier,
+    /// Lowest score of a target passing `TRAIN_FDR` in the training set
+    threshold: f64,
+    /// Median decoy score in the training set
+    decoy_median: f64,
+}
+
+impl FoldModel {
+    /// Iteratively train a model on the `train` rows of `features`, starting
+    /// from the `initial` scores
+    fn train(
+        model: RescoringModel,
+        features: &Matrix,
+        decoy: &[bool],
+        train: &[usize],
+        initial: &[f64],
+    ) -> Option<Self> {
+        let data = train
+            .iter()
+            .flat_map(|&row| features.row(row))
+            .collect::<Vec<_>>();
+        let features = Matrix::new(data, train.len(), features.cols);
+        let decoy = train.iter().map(|&row| decoy[row]).collect::<Vec<_>>();
+        let mut scores = train.iter().map(|&row| initial[row]).collect::<Vec<_>>();
+
+        let iterations = match model {
+            RescoringModel::Linear => ITERATIONS,
+            RescoringModel::Trees => TREE_ITERATIONS,
+            RescoringModel::None => return None,
+        };
+        let mut classifier = None;
+        for _ in 0..iterations {
+            let (positives, threshold) = passing_targets(&scores, &decoy, TRAIN_FDR);
+            if positives < MIN_POSITIVES {
+                return None;
+            }
+            // Confident targets, and all decoys
+            let rows = (0..features.rows)
+                .filter(|&row| decoy[row] || scores[row] >= threshold)
+                .collect::<Vec<_>>();
+            let data = rows
+                .iter()
+                .flat_map(|&row| features.row(row))
+                .collect::<Vec<_>>();
+            let fit = Classifier::train(
+                model,
+                &Matrix::new(data, rows.len(), features.cols),
+                &rows.iter().map(|&row| decoy[row]).collect::<Vec<_>>(),
+            )?;
+            scores = fit.score(&features);
+            classifier = Some(fit);
+        }
+
+        let (_, threshold) = passing_targets(&scores, &decoy, TRAIN_FDR);
+        let mut decoy_scores = scores
+            .iter()
+            .zip(&decoy)
+            .filter(|(_, &decoy)| decoy)
+            .map(|(score, _)| *score)
+            .collect::<Vec<_>>();
+        decoy_scores.sort_unstable_by(|a, b| a.total_cmp(b));
+        let decoy_median = *decoy_scores.get(decoy_scores.len() / 2)?;
+        if !threshold.is_finite() || threshold <= decoy_median {
+            return None;
+        }
+        Some(FoldModel {
+            classifier: classifier?,
+            threshold,
+            decoy_median,
+        })
+    }
+
+    /// Score `features`, such that the `TRAIN_FDR` threshold is 0 and the
+    /// median decoy is -1
+    fn score(&self, features: &Matrix) -> Vec<f64> {
+        self.classifier
+            .score(features)
+            .into_iter()
+            .map(|score| (score - self.threshold) / (self.threshold - self.decoy_median))
+            .collect()
+    }
+}
+
+/// Assign PSMs to cross-validation folds. Models are trained on the PSMs of
+/// all files pooled together, with files as a blocking factor: the spectra of
+/// each file are spread evenly across folds (in a pseudo-random order), so
+/// that short runs with few PSMs are represented in every fold. All PSMs of a
+/// spectrum are assigned to the same fold
+pub(crate) fn cross_validation_folds(features: &[Feature]) -> Vec<usize> {
+    let spectra = features
+        .iter()
+        .map(|feat| (feat.file_id, &*feat.spec_id))
+        .collect::<Vec<_>>();
+    spectrum_folds(&spectra)
+}
+
+/// [`cross_validation_folds`] of PSMs identified by the file and the
+/// identifier of their spectrum
+pub fn spectrum_folds(psms: &[(usize, &str)]) -> Vec<usize> {
+    let spectrum_hash = |file_id: usize, spec_id: &str| {
+        let mut hasher = fnv::FnvHasher::default();
+        (file_id, spec_id).hash(&mut hasher);
+        hasher.finish()
+    };
+
+    let mut spectra = psms
+        .iter()
+        .map(|&(file_id, spec_id)| (file_id, spectrum_hash(file_id, spec_id), spec_id))
+        .collect::<Vec<_>>();
+    spectra.par_sort_unstable();
+    spectra.dedup();
+
+    let mut folds = fnv::FnvHashMap::default();
+    let mut previous_file = None;
+    let mut idx = 0;
+    for (file_id, _, spec_id) in spectra {
+        if previous_file != Some(file_id) {
+            previous_file = Some(file_id);
+            idx = 0;
+        }
+        folds.insert((file_id, spec_id), idx % FOLDS);
+        idx += 1;
+    }
+    psms.iter().map(|psm| folds[psm]).collect()
+}
+
+/// Semi-supervised, cross-validated scoring of PSMs. `fold` assigns each row
+/// of `features` to a cross-validation fold, and must be less than `FOLDS`.
+/// Returns `None` if a model could not be trained on any fold - e.g. there are
+/// too few confident targets
+fn semi_supervised(
+    model: RescoringModel,
+    features: &Matrix,
+    decoy: &[bool],
+    fold: &[usize],
+) -> Option<Vec<f64>> {
+    let initial = initial_scores(features, decoy)?;
+
+    let models = (0..FOLDS)
+        .into_par_iter()
+        .map(|k| {
+            let train = (0..features.rows)
+                .filter(|&row| fold[row] != k)
+                .collect::<Vec<_>>();
+            FoldModel::train(model, features, decoy, &train, &initial)
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let scores = models
+        .iter()
+        .map(|model| model.score(features))
+        .collect::<Vec<_>>();
+    Some(
+        fold.iter()
+            .enumerate()
+            .map(|(row, &k)| scores[k][row])
+            .collect(),
+    )
+}
+
+/// Initial direction of semi-supervised training: the single feature (or its
+/// negation) that separates the most targets from decoys
+fn initial_scores(features: &Matrix, decoy: &[bool]) -> Option<Vec<f64>> {
+    let (col, sign, _) = (0..features.cols)
+        .into_par_iter()
+        .flat_map_iter(|col| [(col, 1.0), (col, -1.0)])
+        .map(|(col, sign)| {
+            let scores = features.col(col).map(|x| sign * x).collect::<Vec<_>>();
+            (col, sign, passing_targets(&scores, decoy, TRAIN_FDR).0)
+        })
+        .max_by_key(|(col, sign, passing)| (*passing, features.cols - col, *sign > 0.0))?;
+    log::trace!(
+        "- initial semi-supervised direction: {}{}",
+        if sign < 0.0 { "-" } else { "" },
+        FEATURE_NAMES.get(col).unwrap_or(&"?")
+    );
+    Some(features.col(col).map(|x| sign * x).collect())
+}
+
+/// Rescoring features of each PSM (rows), as used to train the rescoring
+/// model. Columns are named by [`FEATURE_NAMES`], followed by the
+/// [`Feature::custom_features`] of the PSMs, if any
+pub fn feature_matrix(scores: &[Feature], precursor_tol: Tolerance) -> Matrix {
+    let custom = scores
+        .iter()
+        .map(|feat| feat.custom_features.len())
+        .max()
+        .unwrap_or(0);
+
     let decoys = scores
         .par_iter()
         .map(|sc| sc.label == -1)
@@ -146,7 +479,7 @@ pub fn score_psms(scores: &mut [Feature], precursor_tol: Tolerance) -> Option<()
         .build(&delta_mass, &decoys);
 
     let features = scores
-        .into_par_iter()
+        .par_iter()
         .flat_map_iter(|perc| {
             let poisson = match (-perc.poisson).ln_1p() {
                 x if x.is_finite() => x,
@@ -171,51 +504,110 @@ pub fn score_psms(scores: &mut [Feature], precursor_tol: Tolerance) -> Option<()
                 (perc.longest_b as f64).ln_1p(),
                 (perc.longest_y as f64).ln_1p(),
                 (perc.longest_y as f64 / perc.peptide_len as f64),
+                (perc.fragment_coverage as f64),
                 (perc.peptide_len as f64).ln_1p(),
                 (perc.missed_cleavages as f64),
                 (perc.aligned_rt as f64),
                 (perc.delta_rt_model as f64).clamp(0.001, 0.999).sqrt(),
+                (perc.delta_mobility_model as f64),
+                (perc.predicted_spectral_angle as f64),
+                (perc.predicted_correlation as f64),
             ];
-            x
+            // Missing and non-finite custom features would break the model
+            let custom = (0..custom).map(|idx| match perc.custom_features.get(idx) {
+                Some(x) if x.is_finite() => *x,
+                _ => 0.0,
+            });
+            x.into_iter().chain(custom)
         })
         .collect::<Vec<_>>();
 
-    let features = Matrix::new(features, scores.len(), FEATURES);
-    let lda = LinearDiscriminantAnalysis::train(&features, &decoys)?;
-    if !lda.eigenvector.iter().all(|f| f.is_finite()) {
-        log::error!(
-            "linear model eigenvector includes NaN: this likely indicates a bug, please report!"
-        );
-        for row in 0..features.rows {
-            if features.row(row).any(|f| !f.is_finite()) {
-                let row = features.row(row).collect::<Vec<_>>();
-                log::error!("example feature vector with NaN: {:?}", row);
-                break;
+    Matrix::new(features, scores.len(), FEATURES + custom)
+}
+
+/// [`feature_matrix`], with the features excluded by `settings` set to 0
+fn rescoring_features(
+    scores: &[Feature],
+    precursor_tol: Tolerance,
+    settings: &MlSettings,
+) -> Matrix {
+    let mut features = feature_matrix(scores, precursor_tol);
+    // Excluded features are constant, and do not contribute to either model
+    for (col, name) in FEATURE_NAMES.iter().enumerate() {
+        if settings.exclude_features.iter().any(|f| f == name) {
+            log::trace!("- excluding rescoring feature {}", name);
+            for row in 0..features.rows {
+                features[(row, col)] = 0.0;
             }
         }
+    }
+    features
+}
+
+pub fn score_psms(
+    scores: &mut [Feature],
+    precursor_tol: Tolerance,
+    settings: &MlSettings,
+) -> Option<()> {
+    let model = settings.model;
+    if model == RescoringModel::None {
         return None;
     }
-    let discriminants = lda.score(&features);
+    log::trace!("fitting {:?} rescoring model...", model);
+    let decoys = scores
+        .par_iter()
+        .map(|sc| sc.label == -1)
+        .collect::<Vec<_>>();
 
-    log::trace!("- fitting non-parametric model for posterior error probabilities");
-    let kde = super::kde::Builder::default().build(&discriminants, &decoys);
+    let folds = cross_validation_folds(scores);
+    let features = rescoring_features(scores, precursor_tol, settings);
+    let discriminants = rescore(model, &features, &decoys, &folds)?;
 
     scores
         .par_iter_mut()
         .zip(&discriminants)
-        .for_each(|(perc, score)| {
-            perc.discriminant_score = *score as f32;
-            perc.posterior_error = kde.posterior_error(*score).log10() as f32;
-            if perc.posterior_error.is_infinite() {
-                // This is approximately the log10 of the smallest positive
-                // non-zero f64
-                perc.posterior_error = -324.0;
-            }
-        });
+        .for_each(|(perc, score)| perc.discriminant_score = *score as f32);
+    super::pep::assign(scores, settings.pep);
 
     Some(())
 }
 
+/// Discriminant scores of the PSMs (rows) of an arbitrary feature matrix, as
+/// assigned by [`score_psms`]: semi-supervised and cross-validated over
+/// `folds` (see [`spectrum_folds`]), or by a single linear model if this
+/// fails. Returns `None` if no model could be fit
+pub fn rescore(
+    model: RescoringModel,
+    features: &Matrix,
+    decoys: &[bool],
+    folds: &[usize],
+) -> Option<Vec<f64>> {
+    if model == RescoringModel::None {
+        return None;
+    }
+    match semi_supervised(model, features, decoys, folds) {
+        Some(discriminants) => Some(discriminants),
+        None => {
+            log::debug!("semi-supervised training failed, fitting a single linear model");
+            let lda = LinearDiscriminantAnalysis::train(features, decoys)?;
+            if !lda.eigenvector.iter().all(|f| f.is_finite()) {
+                log::error!(
+                    "linear model eigenvector includes NaN: this likely indicates a bug, please report!"
+                );
+                for row in 0..features.rows {
+                    if features.row(row).any(|f| !f.is_finite()) {
+                        let row = features.row(row).collect::<Vec<_>>();
+                        log::error!("example feature vector with NaN: {:?}", row);
+                        break;
+                    }
+                }
+                return None;
+            }
+            Some(lda.score(features))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -275,4 +667,115 @@ mod test {
             expected
         );
     }
+
+    #[test]
+    fn semi_supervised_scoring() {
+        // Deterministic pseudo-random noise in [-0.5, 0.5)
+        let mut state = 42u64;
+        let mut noise = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (state >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+        };
+
+        // Decoys, incorrect targets (distributed like decoys), and correct
+        // targets. The first feature separates correct targets weakly, the
+        // second strongly, and the third is uninformative
+        let mut data = Vec::new();
+        let mut decoy = Vec::new();
+        for i in 0..900 {
+            let correct = i >= 600;
+            let shift = if correct { 1.0 } else { 0.0 };
+            data.extend([shift * 0.6 + noise(), shift * 2.0 + noise(), noise()]);
+            decoy.push(i < 300);
+        }
+        let features = Matrix::new(data, 900, 3);
+
+        assert_eq!(
+            passing_targets(&[3.0, 2.0, 1.0], &[false, true, false], 0.5),
+            (2, 1.0)
+        );
+        assert_eq!(passing_targets(&[3.0, 2.0], &[true, false], 0.5).0, 0);
+
+        let fold = (0..900).map(|i| i % FOLDS).collect::<Vec<_>>();
+        for model in [RescoringModel::Linear, RescoringModel::Trees] {
+            let scores = semi_supervised(model, &features, &decoy, &fold).expect("failed to train");
+            let (passing, threshold) = passing_targets(&scores, &decoy, 0.01);
+            assert!(passing >= 290, "{:?}: {}", model, passing);
+            assert!(threshold.abs() < 1.0, "{:?}: {}", model, threshold);
+
+            // Too few confident targets to train on
+            assert!(semi_supervised(model, &features, &vec![true; 900], &fold).is_none());
+        }
+    }
+
+    #[test]
+    fn apply_serialized_model() {
+        let hyperscore = FEATURE_NAMES.iter().position(|f| *f == "ln1p(hyperscore)");
+        let lda = LinearDiscriminantAnalysis {
+            eigenvector: (0..FEATURES)
+                .map(|col| (Some(col) == hyperscore) as u8 as f64)
+                .collect(),
+        };
+        let json = serde_json::to_string(&lda).unwrap();
+        let lda: LinearDiscriminantAnalysis = serde_json::from_str(&json).unwrap();
+        assert_eq!(lda.coefficients().len(), FEATURES);
+
+        let tol = Tolerance::Ppm(-10.0, 10.0);
+        let settings = MlSettings::default();
+        let mut features = (0..4)
+            .map(|idx| Feature {
+                hyperscore: idx as f64,
+                label: if idx % 2 == 0 { 1 } else { -1 },
+                peptide_len: 10,
+                delta_mass: idx as f32,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lda.apply(&mut features, tol, &settings), Some(()));
+        for (idx, feat) in features.iter().enumerate() {
+            assert_eq!(feat.discriminant_score, (idx as f64).ln_1p() as f32);
+        }
+
+        // Features do not match the model
+        features[0].custom_features = vec![1.0];
+        features[0].discriminant_score = -1.0;
+        assert_eq!(lda.apply(&mut features, tol, &settings), None);
+        assert_eq!(features[0].discriminant_score, -1.0);
+    }
+
+    #[test]
+    fn folds_blocked_by_file() {
+        // One short file with few spectra, and one longer file; two PSMs
+        // (rank 1 and 2) per spectrum
+        let mut features = Vec::new();
+        for (file_id, spectra) in [(0, 4), (1, 31)] {
+            for scan in 0..spectra {
+                for rank in 1..=2 {
+                    features.push(Feature {
+                        file_id,
+                        spec_id: format!("scan={}", scan).into(),
+                        rank,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        let folds = cross_validation_folds(&features);
+        assert!(folds.iter().all(|&fold| fold < FOLDS));
+        for pair in folds.chunks(2) {
+            assert_eq!(pair[0], pair[1]);
+        }
+        for (file_id, spectra) in [(0, 4), (1, 31)] {
+            let mut counts = [0; FOLDS];
+            for (feat, fold) in features.iter().zip(&folds) {
+                if feat.file_id == file_id && feat.rank == 1 {
+                    counts[*fold] += 1;
+                }
+            }
+            assert_eq!(counts.iter().sum::<usize>(), spectra);
+            assert!(counts
+                .iter()
+                .all(|&n| n >= spectra / FOLDS && n <= spectra / FOLDS + 1));
+        }
+    }
 }
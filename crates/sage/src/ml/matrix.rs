@@ -1,5 +1,5 @@
 use super::norm;
-use rayon::prelude::*;
+use crate::par::prelude::*;
 use std::fmt::{self, Debug};
 use std::marker::PhantomData;
 use std::ops::{Add, AddAssign, Div, Index, IndexMut};
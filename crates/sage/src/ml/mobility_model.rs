@@ -0,0 +1,250 @@
+//! Ion mobility (1/K0) prediction using linear regression
+//!
+//! Analogous to [`super::retention_model`]: the inverse reduced ion mobility
+//! of a precursor is regressed on its amino acid composition, length, and
+//! terms of its m/z and charge. Collisional cross sections grow roughly with
+//! mass^(2/3), and 1/K0 is proportional to the cross section divided by the
+//! charge, which is included as a feature alongside a quadratic function of
+//! m/z.
+//!
+//! A model fit on a previous search can be used to remove candidate peptides
+//! whose predicted ion mobility is incompatible with that of a spectrum
+//! before they are scored, see [`MobilityFilter`].
+
+use super::{gauss::Gauss, matrix::Matrix};
+use crate::database::IndexedDatabase;
+use crate::mass::{PROTON, VALID_AA};
+use crate::par::prelude::*;
+use crate::peptide::Peptide;
+use crate::scoring::Feature;
+use serde::{Deserialize, Serialize};
+
+/// Minimum number of confident PSMs with an ion mobility required to fit a
+/// model
+const MIN_PSMS: usize = 50;
+/// Ridge penalty, relative to the mean diagonal of the normal equations, so
+/// that collinear features (e.g. a single precursor charge) do not prevent
+/// fitting
+const RIDGE: f64 = 1E-8;
+
+const FEATURES: usize = VALID_AA.len() + 6;
+const PEPTIDE_LEN: usize = VALID_AA.len();
+const MZ: usize = VALID_AA.len() + 1;
+const MZ_SQUARED: usize = VALID_AA.len() + 2;
+const CROSS_SECTION: usize = VALID_AA.len() + 3;
+const CHARGE: usize = VALID_AA.len() + 4;
+const INTERCEPT: usize = FEATURES - 1;
+
+/// Try to fit an ion mobility prediction model, returning it if successful.
+/// Only PSMs with an ion mobility ([`Feature::ion_mobility`]) are assigned a
+/// predicted ion mobility and error
+pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<MobilityModel> {
+    let model = MobilityModel::fit(db, features)?;
+    features.par_iter_mut().for_each(|feat| {
+        if let Some(mobility) = feat.ion_mobility {
+            let predicted = model.predict_peptide(db, feat).max(0.0) as f32;
+            feat.predicted_mobility = predicted;
+            feat.delta_mobility_model = (mobility - predicted).abs();
+        }
+    });
+    Some(model)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MobilityModel {
+    beta: Vec<f64>,
+    map: [usize; 26],
+    pub r2: f64,
+}
+
+fn residue_map() -> [usize; 26] {
+    let mut map = [0; 26];
+    for (idx, aa) in VALID_AA.iter().enumerate() {
+        map[(aa - b'A') as usize] = idx;
+    }
+    map
+}
+
+impl MobilityModel {
+    fn embed(sequence: &[u8], calcmass: f32, charge: u8, map: &[usize; 26]) -> [f64; FEATURES] {
+        let charge = charge.max(1) as f64;
+        let mz = (calcmass as f64 / charge + PROTON as f64) / 1000.0;
+
+        let mut embedding = [0.0; FEATURES];
+        for residue in sequence {
+            embedding[map[(residue - b'A') as usize]] += 1.0;
+        }
+        embedding[PEPTIDE_LEN] = sequence.len() as f64;
+        embedding[MZ] = mz;
+        embedding[MZ_SQUARED] = mz.powi(2);
+        embedding[CROSS_SECTION] = (calcmass as f64 / 1000.0).powf(2.0 / 3.0) / charge;
+        embedding[CHARGE] = charge;
+        embedding[INTERCEPT] = 1.0;
+        embedding
+    }
+
+    /// Attempt to fit a linear regression model: precursor ~ ion mobility
+    pub fn fit(db: &IndexedDatabase, training_set: &[Feature]) -> Option<Self> {
+        let map = residue_map();
+        let (embeddings, mobility): (Vec<_>, Vec<_>) = training_set
+            .par_iter()
+            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01)
+            .filter_map(|feat| {
+                let mobility = feat.ion_mobility? as f64;
+                let sequence = &db[feat.peptide_idx].sequence;
+                let embedding = Self::embed(sequence, feat.calcmass, feat.charge, &map);
+                Some((embedding, mobility))
+            })
+            .unzip();
+        Self::regress(embeddings, mobility, map)
+    }
+
+    fn regress(
+        embeddings: Vec<[f64; FEATURES]>,
+        mobility: Vec<f64>,
+        map: [usize; 26],
+    ) -> Option<Self> {
+        if mobility.len() < MIN_PSMS {
+            return None;
+        }
+        let mean = mobility.iter().sum::<f64>() / mobility.len() as f64;
+        let var = mobility.iter().map(|x| (x - mean).powi(2)).sum::<f64>();
+
+        let rows = embeddings.len();
+        let features = Matrix::new(embeddings.concat(), rows, FEATURES);
+        let mobility = Matrix::col_vector(mobility);
+
+        let f_t = features.transpose();
+        let mut cov = f_t.dot(&features);
+        let penalty = RIDGE * (0..FEATURES).map(|i| cov[(i, i)]).sum::<f64>() / FEATURES as f64;
+        for i in 0..FEATURES {
+            cov[(i, i)] += penalty;
+        }
+        let b = f_t.dot(&mobility);
+        let beta = Gauss::solve(cov, b)?;
+
+        let predicted = features.dot(&beta).take();
+        let sum_squared_error = predicted
+            .iter()
+            .zip(mobility.take())
+            .map(|(pred, act)| (pred - act).powi(2))
+            .sum::<f64>();
+        let r2 = 1.0 - (sum_squared_error / var);
+        if !r2.is_finite() {
+            return None;
+        }
+        log::info!("- fit ion mobility model, rsq = {}", r2);
+        Some(Self {
+            beta: beta.take(),
+            map,
+            r2,
+        })
+    }
+
+    fn predict_precursor(&self, sequence: &[u8], calcmass: f32, charge: u8) -> f64 {
+        Self::embed(sequence, calcmass, charge, &self.map)
+            .into_iter()
+            .zip(&self.beta)
+            .fold(0.0f64, |sum, (x, y)| sum + x * y)
+    }
+
+    /// Predict the ion mobility of a peptide precursor at charge `charge`
+    pub fn predict(&self, peptide: &Peptide, charge: u8) -> f64 {
+        self.predict_precursor(&peptide.sequence, peptide.monoisotopic, charge)
+    }
+
+    /// Predict the ion mobility of a PSM's precursor
+    pub fn predict_peptide(&self, db: &IndexedDatabase, psm: &Feature) -> f64 {
+        let sequence = &db[psm.peptide_idx].sequence;
+        self.predict_precursor(sequence, psm.calcmass, psm.charge)
+    }
+}
+
+/// Removal of candidate peptides whose predicted ion mobility differs from
+/// the observed ion mobility of a spectrum by more than `tolerance`. Spectra
+/// without an ion mobility are not filtered
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MobilityFilter {
+    /// Ion mobility model, fit on a previous search of similar data
+    pub model: MobilityModel,
+    /// Maximum absolute difference between predicted and observed 1/K0
+    pub tolerance: f32,
+}
+
+impl MobilityFilter {
+    /// Can `peptide`, at precursor charge `charge`, have been observed at
+    /// ion mobility `observed`?
+    pub fn accepts(&self, peptide: &Peptide, charge: u8, observed: Option<f32>) -> bool {
+        match observed {
+            Some(observed) => {
+                let predicted = self.model.predict(peptide, charge).max(0.0) as f32;
+                (predicted - observed).abs() <= self.tolerance
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mobility_model() {
+        // Mobility of each peptide is a linear function of its embedding
+        let map = residue_map();
+        let (mut embeddings, mut mobility) = (Vec::new(), Vec::new());
+        for (idx, sequence) in ["PEPTIDEK", "LESLIEK", "AAGGKR", "MYPEPTIDER", "VVSSQQNR"]
+            .iter()
+            .enumerate()
+        {
+            for charge in 2..=4u8 {
+                for offset in 0..5 {
+                    let calcmass = 800.0 + 150.0 * idx as f32 + 10.0 * offset as f32;
+                    let embedding =
+                        MobilityModel::embed(sequence.as_bytes(), calcmass, charge, &map);
+                    mobility.push(
+                        0.6 + 0.4 * embedding[CROSS_SECTION] + 0.3 * embedding[MZ]
+                            - 0.02 * embedding[CHARGE],
+                    );
+                    embeddings.push(embedding);
+                }
+            }
+        }
+        let test = MobilityModel::embed(b"LESLIEK", 900.0, 2, &map);
+        let expected = 0.6 + 0.4 * test[CROSS_SECTION] + 0.3 * test[MZ] - 0.02 * test[CHARGE];
+
+        let model = MobilityModel::regress(embeddings.clone(), mobility.clone(), map).unwrap();
+        assert!(model.r2 > 0.99, "{}", model.r2);
+        let predicted = test
+            .iter()
+            .zip(&model.beta)
+            .map(|(x, b)| x * b)
+            .sum::<f64>();
+        assert!(
+            (predicted - expected).abs() < 1E-3,
+            "{} {}",
+            predicted,
+            expected
+        );
+
+        // Candidates are filtered by their predicted ion mobility
+        let filter = MobilityFilter {
+            model: model.clone(),
+            tolerance: 0.05,
+        };
+        let peptide = Peptide::try_from(crate::enzyme::Digest {
+            sequence: "LESLIEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let predicted = model.predict(&peptide, 2) as f32;
+        assert!(filter.accepts(&peptide, 2, Some(predicted + 0.04)));
+        assert!(!filter.accepts(&peptide, 2, Some(predicted - 0.06)));
+        assert!(filter.accepts(&peptide, 2, None));
+
+        embeddings.truncate(MIN_PSMS - 1);
+        mobility.truncate(MIN_PSMS - 1);
+        assert!(MobilityModel::regress(embeddings, mobility, map).is_none());
+    }
+}
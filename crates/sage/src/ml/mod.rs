@@ -1,13 +1,51 @@
 //! Linear Algebra, Machine Learning & FDR refinement
 
+pub mod diagnostics;
 pub mod gauss;
+pub mod gradient_boosting;
 pub mod kde;
 pub mod linear_discriminant;
 pub mod matrix;
+pub mod mobility_model;
+pub mod pep;
+pub mod pooled;
 pub mod qvalue;
 pub mod retention_alignment;
 pub mod retention_model;
 
+use pep::PepEstimator;
+use serde::{Deserialize, Serialize};
+
+/// Model used to rescore PSMs
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum RescoringModel {
+    /// Linear discriminant analysis
+    #[default]
+    Linear,
+    /// Gradient-boosted decision trees, which capture non-linear interactions
+    /// between features, at the cost of longer training
+    Trees,
+    /// No rescoring: PSMs are scored by a fixed heuristic, e.g. when
+    /// rescoring is done by external tools such as Percolator or mokapot
+    None,
+}
+
+/// Settings of the PSM rescoring model
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct MlSettings {
+    pub model: RescoringModel,
+    /// Posterior error probability estimator
+    pub pep: PepEstimator,
+    /// Rescoring features (see [`linear_discriminant::FEATURE_NAMES`]) that
+    /// are not used to train the rescoring model
+    pub exclude_features: Vec<String>,
+    /// Write the rescoring features of every PSM to `features.sage.tsv`
+    pub export_features: bool,
+}
+
 #[allow(dead_code)]
 fn all_close(lhs: &[f64], rhs: &[f64], eps: f64) -> bool {
     lhs.iter()
@@ -0,0 +1,158 @@
+//! Posterior error probabilities (PEPs) of PSMs, estimated from the
+//! discriminant score distributions of targets and decoys
+//!
+//! Two estimators are available: kernel density estimates of the target and
+//! decoy score distributions (see [`super::kde`]), or an isotonic regression
+//! of the fraction of decoys on the discriminant score, as in qvality and
+//! mokapot. The isotonic fit makes no assumption about the shape of the score
+//! distributions, and its PEPs are guaranteed to be monotonic.
+//!
+//! Käll, 2009 [https://doi.org/10.1093/bioinformatics/btp021]
+
+use crate::par::prelude::*;
+use crate::scoring::Feature;
+use serde::{Deserialize, Serialize};
+
+/// Method used to estimate posterior error probabilities
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum PepEstimator {
+    /// Kernel density estimation of target and decoy score distributions
+    #[default]
+    Kde,
+    /// Isotonic regression of the fraction of decoys on the score
+    Isotonic,
+}
+
+/// Isotonic (pool adjacent violators) fit of the fraction of decoys, which
+/// must not decrease as scores decrease. Returns the fitted fraction for each
+/// score, in the order of `scores`
+fn decoy_fraction(scores: &[f64], decoys: &[bool]) -> Vec<f64> {
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+
+    // Blocks of consecutive PSMs (by decreasing score): sum of decoy
+    // indicators, and number of PSMs
+    let mut blocks: Vec<(f64, usize)> = Vec::with_capacity(scores.len());
+    for &idx in &order {
+        blocks.push((decoys[idx] as u8 as f64, 1));
+        while blocks.len() > 1 {
+            let (sum, n) = blocks[blocks.len() - 1];
+            let (prev_sum, prev_n) = blocks[blocks.len() - 2];
+            if prev_sum / prev_n as f64 <= sum / n as f64 {
+                break;
+            }
+            blocks.pop();
+            *blocks.last_mut().expect("at least one block") = (prev_sum + sum, prev_n + n);
+        }
+    }
+
+    let mut fraction = vec![0.0; scores.len()];
+    let mut order = order.into_iter();
+    for (sum, n) in blocks {
+        let value = sum / n as f64;
+        for idx in order.by_ref().take(n) {
+            fraction[idx] = value;
+        }
+    }
+    fraction
+}
+
+/// Isotonic estimate of the posterior error probability of each PSM: the
+/// ratio of decoys to targets at its score, assuming that incorrect target
+/// PSMs are distributed like decoys
+pub fn isotonic(scores: &[f64], decoys: &[bool]) -> Vec<f64> {
+    decoy_fraction(scores, decoys)
+        .into_iter()
+        .map(|p| match p < 1.0 {
+            true => (p / (1.0 - p)).min(1.0),
+            false => 1.0,
+        })
+        .collect()
+}
+
+/// Estimate posterior error probabilities from [`Feature::discriminant_score`],
+/// and assign their log10 to [`Feature::posterior_error`]
+pub fn assign(features: &mut [Feature], estimator: PepEstimator) {
+    log::trace!(
+        "- fitting {:?} model for posterior error probabilities",
+        estimator
+    );
+    let scores = features
+        .par_iter()
+        .map(|feat| feat.discriminant_score as f64)
+        .collect::<Vec<_>>();
+    let decoys = features
+        .par_iter()
+        .map(|feat| feat.label == -1)
+        .collect::<Vec<_>>();
+
+    features
+        .par_iter_mut()
+        .zip(log10_posterior_errors(&scores, &decoys, estimator))
+        .for_each(|(feat, pep)| feat.posterior_error = pep);
+}
+
+/// Log10 of the posterior error probability of each score, in the order of
+/// `scores`
+pub fn log10_posterior_errors(
+    scores: &[f64],
+    decoys: &[bool],
+    estimator: PepEstimator,
+) -> Vec<f32> {
+    let peps = match estimator {
+        PepEstimator::Kde => {
+            let kde = super::kde::Builder::default().build(scores, decoys);
+            scores
+                .par_iter()
+                .map(|score| kde.posterior_error(*score))
+                .collect::<Vec<_>>()
+        }
+        PepEstimator::Isotonic => isotonic(scores, decoys),
+    };
+
+    peps.into_par_iter()
+        .map(|pep| match pep.log10() as f32 {
+            // This is approximately the log10 of the smallest positive
+            // non-zero f64
+            pep if pep.is_infinite() => -324.0,
+            pep => pep,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn isotonic_pep() {
+        let scores = [10.0, 9.0, 8.0, 7.0, 6.0, 5.0, 4.0, 3.0];
+        let decoys = [false, false, true, false, false, true, true, false];
+        let fraction = decoy_fraction(&scores, &decoys);
+        assert_eq!(
+            fraction,
+            vec![
+                0.0,
+                0.0,
+                1.0 / 3.0,
+                1.0 / 3.0,
+                1.0 / 3.0,
+                2.0 / 3.0,
+                2.0 / 3.0,
+                2.0 / 3.0
+            ]
+        );
+
+        let pep = isotonic(&scores, &decoys);
+        assert_eq!(pep[0], 0.0);
+        assert!((pep[3] - 0.5).abs() < 1E-12);
+        assert_eq!(pep[7], 1.0);
+        assert!(pep.windows(2).all(|w| w[0] <= w[1]));
+
+        // Unsorted scores are fit in score order
+        let fraction = decoy_fraction(&[1.0, 2.0], &[true, false]);
+        assert_eq!(fraction, vec![1.0, 0.0]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Experiment-wide rescoring of PSMs pooled from separately searched runs
+//!
+//! Runs searched one at a time each get their own rescoring model and
+//! q-values, which are not valid once their results are combined: a 1% FDR
+//! threshold in each of ten runs does not amount to a 1% FDR over all of
+//! them. Here, a single model is trained on the rescoring features of the
+//! PSMs of all runs, and PSM-, peptide- and protein-level q-values are
+//! estimated over the whole experiment.
+//!
+//! PSMs carry no link to a peptide database, so peptides and proteins are
+//! identified by name, and q-values are estimated by target-decoy competition
+//! on the best PSM of each peptide, or of each set of proteins, rather than by
+//! picked competition (see [`crate::fdr`])
+
+use super::linear_discriminant::{rescore, spectrum_folds};
+use super::matrix::Matrix;
+use super::pep::log10_posterior_errors;
+use super::MlSettings;
+use crate::par::prelude::*;
+use fnv::FnvHashMap;
+
+/// A PSM read from the results of a single run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PooledPsm {
+    /// Index of the file the spectrum was acquired in, across all runs
+    pub file_id: usize,
+    pub spec_id: String,
+    pub decoy: bool,
+    pub peptide: String,
+    pub proteins: String,
+    pub discriminant_score: f32,
+    /// Log10 of the posterior error probability
+    pub posterior_error: f32,
+    pub spectrum_q: f32,
+    pub peptide_q: f32,
+    pub protein_q: f32,
+}
+
+/// Number of target PSMs, peptides and proteins at 1% FDR
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PooledSummary {
+    pub psms: usize,
+    pub peptides: usize,
+    pub proteins: usize,
+}
+
+/// Train a single rescoring model on the `features` of all `psms` (one row
+/// per PSM), and assign experiment-wide discriminant scores, posterior error
+/// probabilities and q-values. Returns `None`, leaving `psms` unchanged, if
+/// no model could be fit
+pub fn rescore_pooled(
+    psms: &mut [PooledPsm],
+    features: &Matrix,
+    settings: &MlSettings,
+) -> Option<PooledSummary> {
+    assert_eq!(psms.len(), features.rows);
+    let decoys = psms.iter().map(|psm| psm.decoy).collect::<Vec<_>>();
+    let spectra = psms
+        .iter()
+        .map(|psm| (psm.file_id, psm.spec_id.as_str()))
+        .collect::<Vec<_>>();
+    let folds = spectrum_folds(&spectra);
+    let scores = rescore(settings.model, features, &decoys, &folds)?;
+
+    let peps = log10_posterior_errors(&scores, &decoys, settings.pep);
+    psms.par_iter_mut()
+        .zip(scores.par_iter().zip(peps))
+        .for_each(|(psm, (&score, pep))| {
+            psm.discriminant_score = score as f32;
+            psm.posterior_error = pep;
+        });
+
+    let scores = psms
+        .iter()
+        .map(|psm| psm.discriminant_score)
+        .collect::<Vec<_>>();
+    let spectrum_q = q_values(&scores, &decoys);
+    let peptide_q = grouped_q_values(psms, |psm| &psm.peptide);
+    let protein_q = grouped_q_values(psms, |psm| &psm.proteins);
+    for (idx, psm) in psms.iter_mut().enumerate() {
+        psm.spectrum_q = spectrum_q[idx];
+        psm.peptide_q = peptide_q[idx];
+        psm.protein_q = protein_q[idx];
+    }
+
+    Some(PooledSummary {
+        psms: psms
+            .iter()
+            .filter(|psm| !psm.decoy && psm.spectrum_q <= 0.01)
+            .count(),
+        peptides: passing_targets(psms, |psm| &psm.peptide, |psm| psm.peptide_q),
+        proteins: passing_targets(psms, |psm| &psm.proteins, |psm| psm.protein_q),
+    })
+}
+
+/// Target-decoy q-value of each score, in the order of `scores`
+fn q_values(scores: &[f32], decoys: &[bool]) -> Vec<f32> {
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+
+    let mut q = vec![1.0; scores.len()];
+    let (mut decoy, mut target) = (1, 0);
+    for &idx in &order {
+        match decoys[idx] {
+            true => decoy += 1,
+            false => target += 1,
+        }
+        q[idx] = (decoy as f32 / target as f32).min(1.0);
+    }
+    let mut q_min = 1.0f32;
+    for &idx in order.iter().rev() {
+        q_min = q_min.min(q[idx]);
+        q[idx] = q_min;
+    }
+    q
+}
+
+/// Q-value of the best PSM of each group of PSMs with the same `key` (and
+/// label), assigned to every PSM of the group
+fn grouped_q_values<F>(psms: &[PooledPsm], key: F) -> Vec<f32>
+where
+    F: Fn(&PooledPsm) -> &String,
+{
+    let mut groups: FnvHashMap<(&String, bool), usize> = FnvHashMap::default();
+    let mut best: Vec<(f32, bool)> = Vec::new();
+    let group = psms
+        .iter()
+        .map(|psm| {
+            let idx = *groups.entry((key(psm), psm.decoy)).or_insert_with(|| {
+                best.push((f32::MIN, psm.decoy));
+                best.len() - 1
+            });
+            best[idx].0 = best[idx].0.max(psm.discriminant_score);
+            idx
+        })
+        .collect::<Vec<_>>();
+
+    let (scores, decoys): (Vec<f32>, Vec<bool>) = best.into_iter().unzip();
+    let q = q_values(&scores, &decoys);
+    group.into_iter().map(|idx| q[idx]).collect()
+}
+
+/// Number of distinct target `key`s with a q-value at or below 1%
+fn passing_targets<K, Q>(psms: &[PooledPsm], key: K, q: Q) -> usize
+where
+    K: Fn(&PooledPsm) -> &String,
+    Q: Fn(&PooledPsm) -> f32,
+{
+    psms.iter()
+        .filter(|psm| !psm.decoy && q(psm) <= 0.01)
+        .map(key)
+        .collect::<fnv::FnvHashSet<_>>()
+        .len()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pooled_q_values() {
+        let scores = [5.0, 4.0, 3.0, 2.0, 1.0];
+        let decoys = [false, false, true, false, true];
+        assert_eq!(
+            q_values(&scores, &decoys),
+            vec![0.5, 0.5, 2.0 / 3.0, 2.0 / 3.0, 1.0]
+        );
+
+        // Two runs, with a peptide identified by a PSM in each
+        let psm = |file_id, peptide: &str, decoy, score| PooledPsm {
+            file_id,
+            spec_id: format!("{}", score),
+            decoy,
+            peptide: peptide.into(),
+            proteins: format!("sp|{}", peptide),
+            discriminant_score: score,
+            ..Default::default()
+        };
+        let psms = vec![
+            psm(0, "PEPTIDE", false, 5.0),
+            psm(1, "PEPTIDE", false, 1.0),
+            psm(1, "EDITPEP", true, 0.5),
+            psm(0, "LESLIEK", false, 3.0),
+        ];
+        let q = grouped_q_values(&psms, |psm| &psm.peptide);
+        assert_eq!(q, vec![0.5, 0.5, 1.0, 0.5]);
+    }
+}
@@ -1,11 +1,14 @@
 use crate::scoring::Feature;
+use fnv::FnvHashMap;
+use serde::Serialize;
+use std::borrow::BorrowMut;
 
 /// Assign q_values in place to a set of PSMs, returning the number of PSMs
 /// q <= 0.01
 ///
 /// # Invariants
 /// * `scores` must be sorted in descending order (e.g. best PSM is first)
-pub fn spectrum_q_value(scores: &mut [Feature]) -> usize {
+pub fn spectrum_q_value<F: BorrowMut<Feature>>(scores: &mut [F]) -> usize {
     // FDR Calculation:
     // * Sort by score, descending
     // * Estimate FDR
@@ -15,6 +18,7 @@ pub fn spectrum_q_value(scores: &mut [Feature]) -> usize {
     let mut target = 0;
 
     for score in scores.iter_mut() {
+        let score = score.borrow_mut();
         match score.label == -1 {
             true => decoy += 1,
             false => target += 1,
@@ -26,6 +30,7 @@ pub fn spectrum_q_value(scores: &mut [Feature]) -> usize {
     let mut q_min = 1.0f32;
     let mut passing = 0;
     for score in scores.iter_mut().rev() {
+        let score = score.borrow_mut();
         q_min = q_min.min(score.spectrum_q);
         score.spectrum_q = q_min;
         if q_min <= 0.01 {
@@ -34,3 +39,218 @@ pub fn spectrum_q_value(scores: &mut [Feature]) -> usize {
     }
     passing
 }
+
+/// Assign q_values in place to a set of PSMs, performing target-decoy
+/// competition separately within each precursor isolation window
+/// ([`Feature::isolation_window`]), and returning the number of PSMs q <= 0.01
+///
+/// In DIA data, isolation windows differ in how many peptides are
+/// co-isolated, and thus in the number of incorrect matches. PSMs without an
+/// isolation window are treated as a single group.
+///
+/// # Invariants
+/// * `scores` must be sorted in descending order (e.g. best PSM is first)
+pub fn window_q_value(scores: &mut [Feature]) -> usize {
+    let mut windows: FnvHashMap<Option<(u32, u32)>, Vec<&mut Feature>> = FnvHashMap::default();
+    for score in scores.iter_mut() {
+        let key = score
+            .isolation_window
+            .map(|(lo, hi)| (lo.to_bits(), hi.to_bits()));
+        windows.entry(key).or_default().push(score);
+    }
+    windows
+        .values_mut()
+        .map(|window| spectrum_q_value(window))
+        .sum()
+}
+
+/// Summary of target/decoy competition for a set of scored PSMs, useful for
+/// judging the stability of FDR estimates for a given dataset
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CompetitionReport {
+    pub targets: usize,
+    pub decoys: usize,
+    /// Estimated fraction of incorrect target PSMs (Storey's pi0, with
+    /// p-values calculated from the empirical decoy score distribution)
+    pub pi0: f32,
+    /// Cumulative number of targets and decoys scoring at or above each
+    /// threshold, for evenly spaced score thresholds
+    pub curve: Vec<CompetitionPoint>,
+    /// Discriminant score threshold required to reach common FDR levels
+    pub thresholds: Vec<FdrThreshold>,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CompetitionPoint {
+    pub score: f32,
+    pub targets: usize,
+    pub decoys: usize,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FdrThreshold {
+    pub fdr: f32,
+    /// Lowest discriminant score with q <= `fdr`, if any PSMs pass
+    pub score: Option<f32>,
+    pub targets: usize,
+}
+
+/// FDR levels reported in [`CompetitionReport::thresholds`]
+const FDR_LEVELS: [f32; 4] = [0.001, 0.01, 0.05, 0.1];
+
+/// Number of points in [`CompetitionReport::curve`]
+const CURVE_POINTS: usize = 50;
+
+/// Storey's lambda parameter for pi0 estimation
+const PI0_LAMBDA: f32 = 0.5;
+
+/// Estimate pi0 from target/decoy labels (`true` is decoy) of PSMs sorted by
+/// score in descending order.
+///
+/// The p-value of each target is the fraction of decoys scoring at least as
+/// well, and pi0 = #{p > lambda} / ((1 - lambda) * #targets)
+fn estimate_pi0<I: Iterator<Item = bool> + Clone>(labels: I) -> f32 {
+    let decoys = labels.clone().filter(|&decoy| decoy).count();
+    let mut seen_decoys = 0;
+    let mut targets = 0;
+    let mut null_targets = 0;
+    for decoy in labels {
+        if decoy {
+            seen_decoys += 1;
+        } else {
+            targets += 1;
+            let p = (seen_decoys + 1) as f32 / (decoys + 1) as f32;
+            if p > PI0_LAMBDA {
+                null_targets += 1;
+            }
+        }
+    }
+    match targets {
+        0 => 1.0,
+        _ => (null_targets as f32 / ((1.0 - PI0_LAMBDA) * targets as f32)).min(1.0),
+    }
+}
+
+/// Build a [`CompetitionReport`] from PSMs with assigned `discriminant_score`
+/// and `spectrum_q` values
+///
+/// # Invariants
+/// * `scores` must be sorted in descending order (e.g. best PSM is first)
+pub fn competition_report(scores: &[Feature]) -> CompetitionReport {
+    let targets = scores.iter().filter(|s| s.label != -1).count();
+    let decoys = scores.len() - targets;
+
+    let pi0 = estimate_pi0(scores.iter().map(|s| s.label == -1));
+
+    let curve = match (scores.first(), scores.last()) {
+        (Some(hi), Some(lo)) => {
+            let (hi, lo) = (hi.discriminant_score, lo.discriminant_score);
+            let step = (hi - lo) / (CURVE_POINTS - 1) as f32;
+            let mut idx = 0;
+            let (mut t, mut d) = (0, 0);
+            (0..CURVE_POINTS)
+                .map(|point| {
+                    let threshold = match point {
+                        p if p == CURVE_POINTS - 1 => lo,
+                        p => hi - step * p as f32,
+                    };
+                    while idx < scores.len() && scores[idx].discriminant_score >= threshold {
+                        match scores[idx].label == -1 {
+                            true => d += 1,
+                            false => t += 1,
+                        }
+                        idx += 1;
+                    }
+                    CompetitionPoint {
+                        score: threshold,
+                        targets: t,
+                        decoys: d,
+                    }
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    let thresholds = FDR_LEVELS
+        .iter()
+        .map(|&fdr| {
+            let passing = scores.iter().filter(|s| s.spectrum_q <= fdr).fold(
+                (None, 0),
+                |(min, targets), s| {
+                    let min = Some(s.discriminant_score.min(min.unwrap_or(f32::MAX)));
+                    (min, targets + (s.label != -1) as usize)
+                },
+            );
+            FdrThreshold {
+                fdr,
+                score: passing.0,
+                targets: passing.1,
+            }
+        })
+        .collect();
+
+    CompetitionReport {
+        targets,
+        decoys,
+        pi0,
+        curve,
+        thresholds,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pi0() {
+        // All targets score better than all decoys: no incorrect targets
+        let labels = [false; 10].into_iter().chain([true; 10]);
+        assert_eq!(estimate_pi0(labels), 0.0);
+
+        // Targets and decoys are perfectly interleaved: all targets incorrect
+        let labels = (0..1000).map(|i| i % 2 == 0);
+        assert!((estimate_pi0(labels) - 1.0).abs() < 0.01);
+
+        // Half of the targets are correct
+        let labels = [false; 500]
+            .into_iter()
+            .chain((0..1000).map(|i| i % 2 == 0));
+        assert!((estimate_pi0(labels) - 0.5).abs() < 0.01);
+
+        assert_eq!(estimate_pi0(std::iter::empty()), 1.0);
+    }
+
+    #[test]
+    fn window_q_values() {
+        let psm = |label, isolation_window| Feature {
+            label,
+            isolation_window,
+            ..Default::default()
+        };
+        let a = Some((400.0, 425.0));
+        let b = Some((425.0, 450.0));
+
+        // Sorted by score: the decoy in window `b` does not affect window `a`
+        let mut scores = vec![
+            psm(1, a),
+            psm(1, b),
+            psm(-1, b),
+            psm(1, a),
+            psm(1, a),
+            psm(1, b),
+        ];
+        window_q_value(&mut scores);
+        let q = scores.iter().map(|s| s.spectrum_q).collect::<Vec<_>>();
+        let third = 1.0 / 3.0;
+        assert_eq!(q, vec![third, 1.0, 1.0, third, third, 1.0]);
+
+        // Pooled competition is penalized by the decoy in window `b`
+        spectrum_q_value(&mut scores);
+        assert!(scores.iter().all(|s| s.spectrum_q > third));
+    }
+}
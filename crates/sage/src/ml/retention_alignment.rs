@@ -16,10 +16,10 @@ use std::sync::atomic::AtomicU32;
 
 use super::matrix::Matrix;
 use crate::database::PeptideIx;
+use crate::par::prelude::*;
 use crate::scoring::Feature;
 use dashmap::DashMap;
 use fnv::FnvHasher;
-use rayon::prelude::*;
 
 type FnvDashMap<K, V> = DashMap<K, V, BuildHasherDefault<FnvHasher>>;
 
@@ -33,9 +33,11 @@ fn max_rt_by_file(features: &[Feature], n_files: usize) -> Vec<f64> {
         max_rt[feat.file_id].fetch_max(feat.rt.ceil() as u32, std::sync::atomic::Ordering::SeqCst);
     });
 
+    // Files without retention times (e.g. converted MGF files) would otherwise
+    // produce NaN aligned retention times
     max_rt
         .into_iter()
-        .map(|v| v.load(std::sync::atomic::Ordering::Acquire) as f64)
+        .map(|v| v.load(std::sync::atomic::Ordering::Acquire).max(1) as f64)
         .collect()
 }
 
@@ -171,3 +173,27 @@ pub fn global_alignment(features: &mut [Feature], n_files: usize) -> Vec<Alignme
 
     alignments
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_retention_times() {
+        // File 1 does not report retention times
+        let mut features = (0..4)
+            .map(|idx| Feature {
+                peptide_idx: PeptideIx(idx / 2),
+                file_id: (idx % 2) as usize,
+                label: 1,
+                spectrum_q: 0.001,
+                rt: if idx % 2 == 0 { 10.0 * idx as f32 } else { 0.0 },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let alignments = global_alignment(&mut features, 2);
+        assert_eq!(alignments[1].max_rt, 1.0);
+        assert!(features.iter().all(|feat| feat.aligned_rt.is_finite()));
+    }
+}
@@ -2,30 +2,146 @@
 //!
 //! See Klammer et al., Anal. Chem. 2007, 79, 16, 6111–6118
 //! https://doi.org/10.1021/ac070262k
+//!
+//! A [`RetentionModel`] can be serialized, and applied to the PSMs of
+//! another run with [`RetentionModel::apply`], e.g. when a run has too few
+//! confident PSMs to fit its own model.
 
+use super::linear_discriminant::{cross_validation_folds, FOLDS};
 use super::{gauss::Gauss, matrix::Matrix};
 use crate::database::IndexedDatabase;
 use crate::mass::VALID_AA;
+use crate::par::prelude::*;
 use crate::peptide::Peptide;
 use crate::scoring::Feature;
-use rayon::prelude::*;
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
+
+/// Try to fit a retention time prediction model, returning its r-squared.
+///
+/// Models are cross-validated, with the confident PSMs of all files pooled:
+/// the retention time of each PSM is predicted by a model trained on the other
+/// folds (see [`cross_validation_folds`]), and the reported r-squared is the
+/// mean of the fold models. If a model cannot be fit for every fold, a single
+/// model is trained on all confident PSMs instead
+pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<f64> {
+    let folds = cross_validation_folds(features);
+    let cross_validated = (0..FOLDS)
+        .map(|fold| {
+            let training = features
+                .iter()
+                .zip(&folds)
+                .filter(|(feat, f)| **f != fold && is_training_psm(feat))
+                .map(|(feat, _)| feat)
+                .collect::<Vec<_>>();
+            RetentionModel::fit_psms(db, &training)
+        })
+        .collect::<Option<Vec<_>>>();
 
-/// Try to fit a retention time prediction model
-pub fn predict(db: &IndexedDatabase, features: &mut [Feature]) -> Option<()> {
     // Training LR might fail - not enough values, or r-squared is < 0.7
-    let lr = RetentionModel::fit(db, features)?;
-    features.par_iter_mut().for_each(|feat| {
-        // LR can sometimes predict crazy values - clamp predicted RT
-        let rt = lr.predict_peptide(db, feat);
-        let bounded = rt.clamp(0.0, 1.0) as f32;
-        feat.predicted_rt = bounded;
-        feat.delta_rt_model = (feat.aligned_rt - bounded).abs();
-    });
-    Some(())
+    let models = match cross_validated {
+        Some(models) => models,
+        None => {
+            log::debug!(
+                "cross-validated retention time models could not be fit, fitting a single model"
+            );
+            vec![RetentionModel::fit(db, features)?]
+        }
+    };
+    let r2 = models.iter().map(|lr| lr.r2).sum::<f64>() / models.len() as f64;
+    log::info!(
+        "- fit retention time model ({} folds), rsq = {}",
+        models.len(),
+        r2
+    );
+
+    features
+        .par_iter_mut()
+        .zip(&folds)
+        .for_each(|(feat, fold)| models[fold % models.len()].assign(db, feat));
+    Some(r2)
+}
+
+/// Confident target PSMs, used to train retention time models
+fn is_training_psm(feat: &Feature) -> bool {
+    feat.label == 1 && feat.spectrum_q <= 0.01
 }
+/// Robust standard deviation of residuals, from their median absolute value
+fn robust_sd(residuals: &[f64]) -> f64 {
+    let mut abs = residuals.iter().map(|r| r.abs()).collect::<Vec<_>>();
+    abs.sort_by(|a, b| a.total_cmp(b));
+    1.4826 * abs.get(abs.len() / 2).copied().unwrap_or_default()
+}
+
+/// Solve the weighted normal equations: (X^T W X) beta = X^T W y
+fn weighted_least_squares(features: &Matrix, y: &[f64], weights: &[f64]) -> Option<Vec<f64>> {
+    let mut weighted = features.clone();
+    for (row, w) in weights.iter().enumerate() {
+        weighted.row_slice_mut(row).iter_mut().for_each(|x| *x *= w);
+    }
+    let f_t = weighted.transpose();
+    let cov = f_t.dot(features);
+    let b = f_t.dot(&Matrix::col_vector(y.to_vec()));
+    Gauss::solve(cov, b).map(|beta| beta.take())
+}
+
+/// Linear regression with a Huber loss, fit by iteratively reweighted least
+/// squares: residuals beyond [`HUBER_K`] robust standard deviations are
+/// down-weighted, so that a few misidentified PSMs do not skew the fit.
+/// Returns the coefficients, and whether each row is an inlier (residual
+/// within [`OUTLIER_SD`] robust standard deviations)
+fn huber_regression(features: &Matrix, y: &[f64]) -> Option<(Vec<f64>, Vec<bool>)> {
+    let mut weights = vec![1.0; y.len()];
+    let mut beta = weighted_least_squares(features, y, &weights)?;
+    for _ in 0..HUBER_ITERATIONS {
+        let residuals = features
+            .dotv(&beta)
+            .iter()
+            .zip(y)
+            .map(|(pred, act)| act - pred)
+            .collect::<Vec<_>>();
+        let k = HUBER_K * robust_sd(&residuals);
+        if k <= 0.0 {
+            break;
+        }
+        weights = residuals
+            .iter()
+            .map(|r| match r.abs() <= k {
+                true => 1.0,
+                false => k / r.abs(),
+            })
+            .collect();
+        let next = weighted_least_squares(features, y, &weights)?;
+        let change = next
+            .iter()
+            .zip(&beta)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        beta = next;
+        if change < 1E-6 {
+            break;
+        }
+    }
+
+    let residuals = features
+        .dotv(&beta)
+        .iter()
+        .zip(y)
+        .map(|(pred, act)| act - pred)
+        .collect::<Vec<_>>();
+    // Residuals of an exact fit are only rounding errors
+    let threshold = (OUTLIER_SD * robust_sd(&residuals)).max(1E-9);
+    let inliers = residuals.iter().map(|r| r.abs() <= threshold).collect();
+    Some((beta, inliers))
+}
+
+/// Linear regression of (aligned) retention time on the peptide sequence and
+/// its variable modifications
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RetentionModel {
     beta: Vec<f64>,
     map: [usize; 26],
+    modifications: ModificationTerms,
     pub r2: f64,
 }
 
@@ -36,11 +152,117 @@ const PEPTIDE_LEN: usize = FEATURES - 3;
 const PEPTIDE_MASS: usize = FEATURES - 2;
 const INTERCEPT: usize = FEATURES - 1;
 
+/// Minimum number of training PSMs carrying a modified residue, for it to be
+/// embedded
+const MIN_MODIFIED_PSMS: usize = 10;
+/// Residue used for modifications of the peptide N-terminus
+const N_TERMINUS: u8 = b'^';
+/// Residue used for modifications of the peptide C-terminus
+const C_TERMINUS: u8 = b'$';
+
+/// Maximum number of iteratively reweighted least squares iterations
+const HUBER_ITERATIONS: usize = 10;
+/// Huber loss threshold, in robust standard deviations of the residuals
+const HUBER_K: f64 = 1.345;
+/// Training PSMs with residuals larger than this many robust standard
+/// deviations are rejected as outliers
+const OUTLIER_SD: f64 = 3.0;
+
+/// Modification mass, rounded to 0.01 Da
+fn mass_key(mass: f32) -> i32 {
+    (mass * 100.0).round() as i32
+}
+
+/// Residues (and termini) of a peptide, and their modification, if any
+fn residue_states(peptide: &Peptide) -> impl Iterator<Item = (u8, Option<i32>)> + '_ {
+    let state = |mass: f32| (mass != 0.0).then(|| mass_key(mass));
+    peptide
+        .sequence
+        .iter()
+        .zip(&peptide.modifications)
+        .map(move |(residue, mass)| (*residue, state(*mass)))
+        .chain([
+            (N_TERMINUS, peptide.nterm.and_then(state)),
+            (C_TERMINUS, peptide.cterm.and_then(state)),
+        ])
+}
+
+/// Variable modifications of the training set, embedded after the sequence
+/// features: the number of residues carrying each modification mass, and for
+/// modifications observed on several residues, the number of modified
+/// residues of each type (except the most common one, which is the reference).
+///
+/// Static modifications - carried by every occurrence of a residue - are
+/// collinear with the residue counts and are not embedded
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct ModificationTerms {
+    masses: Vec<i32>,
+    residues: Vec<(u8, i32)>,
+}
+
+impl ModificationTerms {
+    fn fit<'a>(peptides: impl Iterator<Item = &'a Peptide>) -> Self {
+        let mut states: FnvHashMap<u8, FnvHashSet<Option<i32>>> = FnvHashMap::default();
+        let mut psms: FnvHashMap<(u8, i32), usize> = FnvHashMap::default();
+        for peptide in peptides {
+            let mut modified = FnvHashSet::default();
+            for (residue, state) in residue_states(peptide) {
+                states.entry(residue).or_default().insert(state);
+                if let Some(mass) = state {
+                    modified.insert((residue, mass));
+                }
+            }
+            for key in modified {
+                *psms.entry(key).or_default() += 1;
+            }
+        }
+
+        let mut variable = psms
+            .into_iter()
+            .filter(|((residue, _), count)| {
+                *count >= MIN_MODIFIED_PSMS && states[residue].len() > 1
+            })
+            .collect::<Vec<_>>();
+        // Most common residue of each modification first
+        variable.sort_by(|((r1, m1), c1), ((r2, m2), c2)| {
+            m1.cmp(m2).then_with(|| c2.cmp(c1)).then_with(|| r1.cmp(r2))
+        });
+
+        let mut terms = ModificationTerms::default();
+        for ((residue, mass), _) in variable {
+            match terms.masses.last() {
+                Some(last) if *last == mass => terms.residues.push((residue, mass)),
+                _ => terms.masses.push(mass),
+            }
+        }
+        terms
+    }
+
+    fn len(&self) -> usize {
+        self.masses.len() + self.residues.len()
+    }
+
+    fn embed(&self, peptide: &Peptide, embedding: &mut [f64]) {
+        for (residue, mass) in residue_states(peptide) {
+            let mass = match mass {
+                Some(mass) => mass,
+                None => continue,
+            };
+            if let Some(idx) = self.masses.iter().position(|m| *m == mass) {
+                embedding[idx] += 1.0;
+            }
+            if let Some(idx) = self.residues.iter().position(|r| *r == (residue, mass)) {
+                embedding[self.masses.len() + idx] += 1.0;
+            }
+        }
+    }
+}
+
 impl RetentionModel {
-    /// One-hot encoding of peptide sequences into feature vector
-    /// Note that this currently does not take into account any modifications
-    fn embed(peptide: &Peptide, map: &[usize; 26]) -> [f64; FEATURES] {
-        let mut embedding = [0.0; FEATURES];
+    /// One-hot encoding of peptide sequences into feature vector, followed by
+    /// the embedding of variable modifications
+    fn embed(peptide: &Peptide, map: &[usize; 26], modifications: &ModificationTerms) -> Vec<f64> {
+        let mut embedding = vec![0.0; FEATURES + modifications.len()];
         let cterm = peptide.sequence.len().saturating_sub(3);
         for (aa_idx, residue) in peptide.sequence.iter().enumerate() {
             let idx = map[(residue - b'A') as usize];
@@ -55,64 +277,229 @@ impl RetentionModel {
         embedding[PEPTIDE_LEN] = peptide.sequence.len() as f64;
         embedding[PEPTIDE_MASS] = (peptide.monoisotopic as f64).ln_1p();
         embedding[INTERCEPT] = 1.0;
+        modifications.embed(peptide, &mut embedding[FEATURES..]);
         embedding
     }
 
-    /// Attempt to fit a linear regression model: peptide sequence ~ retention time
+    /// Attempt to fit a linear regression model: peptide sequence ~ retention time,
+    /// to the confident target PSMs of `training_set`
     pub fn fit(db: &IndexedDatabase, training_set: &[Feature]) -> Option<Self> {
+        let training = training_set
+            .par_iter()
+            .filter(|feat| is_training_psm(feat))
+            .collect::<Vec<_>>();
+        Self::fit_psms(db, &training)
+    }
+
+    fn fit_psms(db: &IndexedDatabase, training: &[&Feature]) -> Option<Self> {
         // Create a mapping from amino acid character to vector embedding
         let mut map = [0; 26];
         for (idx, aa) in VALID_AA.iter().enumerate() {
             map[(aa - b'A') as usize] = idx;
         }
 
-        let rt = training_set
-            .par_iter()
-            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01)
+        let rt = training
+            .iter()
             .map(|psm| psm.aligned_rt as f64)
             .collect::<Vec<f64>>();
 
-        let rt_mean = rt.iter().sum::<f64>() / rt.len() as f64;
-        let rt_var = rt.iter().map(|rt| (rt - rt_mean).powi(2)).sum::<f64>();
-
-        let rt = Matrix::col_vector(rt);
+        let modifications = ModificationTerms::fit(training.iter().map(|psm| &db[psm.peptide_idx]));
+        log::debug!(
+            "- embedding {} modification terms in retention time model",
+            modifications.len()
+        );
 
-        let features = training_set
+        let features = training
             .par_iter()
-            .filter(|feat| feat.label == 1 && feat.spectrum_q <= 0.01)
-            .flat_map_iter(|psm| Self::embed(&db[psm.peptide_idx], &map))
+            .flat_map_iter(|psm| Self::embed(&db[psm.peptide_idx], &map, &modifications))
             .collect::<Vec<_>>();
 
-        let rows = features.len() / FEATURES;
-        let features = Matrix::new(features, rows, FEATURES);
-
-        let f_t = features.transpose();
-        let cov = f_t.dot(&features);
-        let b = f_t.dot(&rt);
+        let cols = FEATURES + modifications.len();
+        let rows = features.len() / cols;
+        let features = Matrix::new(features, rows, cols);
 
-        let beta = Gauss::solve(cov, b)?;
+        let (beta, inliers) = huber_regression(&features, &rt)?;
 
-        let predicted_rt = features.dot(&beta).take();
-        let sum_squared_error = predicted_rt
+        // Misidentified PSMs are rejected as outliers, and do not count
+        // towards the goodness of fit
+        let predicted_rt = features.dotv(&beta);
+        let rejected = inliers.iter().filter(|inlier| !**inlier).count();
+        for (idx, psm) in training.iter().enumerate() {
+            if !inliers[idx] {
+                log::debug!(
+                    "- rejected retention time outlier: {} (file {}), {}, observed {:.4}, predicted {:.4}",
+                    psm.spec_id,
+                    psm.file_id,
+                    db[psm.peptide_idx],
+                    rt[idx],
+                    predicted_rt[idx]
+                );
+            }
+        }
+        let (observed, predicted): (Vec<f64>, Vec<f64>) = rt
+            .iter()
+            .zip(&predicted_rt)
+            .zip(&inliers)
+            .filter(|(_, inlier)| **inlier)
+            .map(|((rt, pred), _)| (*rt, *pred))
+            .unzip();
+        let rt_mean = observed.iter().sum::<f64>() / observed.len() as f64;
+        let rt_var = observed
             .iter()
-            .zip(rt.take())
+            .map(|rt| (rt - rt_mean).powi(2))
+            .sum::<f64>();
+        let sum_squared_error = predicted
+            .iter()
+            .zip(&observed)
             .map(|(pred, act)| (pred - act).powi(2))
             .sum::<f64>();
 
         let r2 = 1.0 - (sum_squared_error / rt_var);
-        log::info!("- fit retention time model, rsq = {}", r2);
+        log::debug!(
+            "- fit retention time model, rsq = {} ({} of {} training PSMs rejected as outliers)",
+            r2,
+            rejected,
+            rows
+        );
         Some(Self {
-            beta: beta.take(),
+            beta,
             map,
+            modifications,
             r2,
         })
     }
 
-    /// Predict retention times for a collection of PSMs
-    pub fn predict_peptide(&self, db: &IndexedDatabase, psm: &Feature) -> f64 {
-        let v = Self::embed(&db[psm.peptide_idx], &self.map);
+    /// Coefficients of the model: the count of each amino acid (in the order
+    /// of [`VALID_AA`]), at the N-terminus, at the C-terminus, peptide length,
+    /// peptide mass, intercept, and modification terms
+    pub fn coefficients(&self) -> &[f64] {
+        &self.beta
+    }
+
+    /// Predict the retention time of a peptide
+    pub fn predict(&self, peptide: &Peptide) -> f64 {
+        let v = Self::embed(peptide, &self.map, &self.modifications);
         v.into_iter()
             .zip(&self.beta)
             .fold(0.0f64, |sum, (x, y)| sum + x * y)
     }
+
+    /// Predict the retention time of the peptide of a PSM
+    pub fn predict_peptide(&self, db: &IndexedDatabase, psm: &Feature) -> f64 {
+        self.predict(&db[psm.peptide_idx])
+    }
+
+    /// Assign predicted retention times to `features`, which may come from
+    /// another run than the one the model was fit on
+    pub fn apply(&self, db: &IndexedDatabase, features: &mut [Feature]) {
+        features
+            .par_iter_mut()
+            .for_each(|feat| self.assign(db, feat));
+    }
+
+    fn assign(&self, db: &IndexedDatabase, feat: &mut Feature) {
+        // LR can sometimes predict crazy values - clamp predicted RT
+        let rt = self.predict_peptide(db, feat);
+        let bounded = rt.clamp(0.0, 1.0) as f32;
+        feat.predicted_rt = bounded;
+        feat.delta_rt_model = (feat.aligned_rt - bounded).abs();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::enzyme::Digest;
+
+    fn peptide(sequence: &str, modifications: &[f32]) -> Peptide {
+        let mut peptide = Peptide::try_from(Digest {
+            sequence: sequence.into(),
+            ..Default::default()
+        })
+        .unwrap();
+        peptide.modifications = modifications.to_vec();
+        peptide
+    }
+
+    #[test]
+    fn huber_regression_rejects_outliers() {
+        // rt = 0.5 * x + 0.1, with a little noise and 3 gross outliers
+        let (mut data, mut rt) = (Vec::new(), Vec::new());
+        for i in 0..100 {
+            let x = i as f64 / 100.0;
+            data.extend([x, 1.0]);
+            let noise = if i % 2 == 0 { 0.001 } else { -0.001 };
+            rt.push(0.5 * x + 0.1 + noise);
+        }
+        for i in [10, 50, 90] {
+            rt[i] += 0.8;
+        }
+        let features = Matrix::new(data, 100, 2);
+
+        let (beta, inliers) = huber_regression(&features, &rt).unwrap();
+        assert!((beta[0] - 0.5).abs() < 0.01, "{:?}", beta);
+        assert!((beta[1] - 0.1).abs() < 0.01, "{:?}", beta);
+        let rejected = inliers
+            .iter()
+            .enumerate()
+            .filter(|(_, inlier)| !**inlier)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, vec![10, 50, 90]);
+    }
+
+    #[test]
+    fn modification_terms() {
+        let mut peptides = Vec::new();
+        for _ in 0..10 {
+            // Static carbamidomethylation, variable oxidation
+            peptides.push(peptide("CMK", &[57.0215, 15.9949, 0.0]));
+            peptides.push(peptide("CMK", &[57.0215, 0.0, 0.0]));
+            // Phosphorylation, more common on S than on T
+            peptides.push(peptide("STK", &[79.9663, 0.0, 0.0]));
+            peptides.push(peptide("STK", &[79.9663, 0.0, 0.0]));
+            peptides.push(peptide("STK", &[0.0, 79.9663, 0.0]));
+        }
+        // Too rare to be embedded
+        peptides.push(peptide("CMK", &[57.0215, 0.0, 42.0106]));
+
+        let terms = ModificationTerms::fit(peptides.iter());
+        assert_eq!(terms.masses, vec![1599, 7997]);
+        assert_eq!(terms.residues, vec![(b'T', 7997)]);
+
+        let mut embedding = vec![0.0; terms.len()];
+        terms.embed(
+            &peptide("SMTK", &[0.0, 15.9949, 79.9663, 0.0]),
+            &mut embedding,
+        );
+        assert_eq!(embedding, vec![1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn serialized_model() {
+        let mut map = [0; 26];
+        for (idx, aa) in VALID_AA.iter().enumerate() {
+            map[(aa - b'A') as usize] = idx;
+        }
+        let modifications = ModificationTerms {
+            masses: vec![1599],
+            residues: Vec::new(),
+        };
+        let mut beta = vec![0.0; FEATURES + modifications.len()];
+        beta[PEPTIDE_LEN] = 0.1;
+        beta[INTERCEPT] = 0.05;
+        beta[FEATURES] = -0.02;
+        let model = RetentionModel {
+            beta,
+            map,
+            modifications,
+            r2: 0.9,
+        };
+
+        let json = serde_json::to_string(&model).unwrap();
+        let model: RetentionModel = serde_json::from_str(&json).unwrap();
+        assert_eq!(model.coefficients()[INTERCEPT], 0.05);
+        let predicted = model.predict(&peptide("CMK", &[0.0, 15.994915, 0.0]));
+        assert!((predicted - 0.33).abs() < 1E-9, "{}", predicted);
+    }
 }
@@ -159,6 +159,18 @@ pub struct ValueOrVec {
     data: Vec<f32>,
 }
 
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for ValueOrVec {
+    fn schema_name() -> String {
+        "ValueOrVec".into()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        // Single values are deprecated, so only advertise the list form
+        <Vec<f32>>::json_schema(gen)
+    }
+}
+
 impl<'de> Deserialize<'de> for ValueOrVec {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -0,0 +1,481 @@
+//! Detection of peptide features in MS1 scans, independently of any search
+//!
+//! Isotopic envelopes are detected in each MS1 scan by fitting the averagine
+//! isotope distribution, and envelopes of the same charge and monoisotopic m/z
+//! in consecutive scans are traced over retention time. The feature map of
+//! each run backs label-free quantification ([`crate::lfq::quantify_features`])
+//! and the estimation of isolation window purity, and can be written without
+//! searching, by `sage features`
+
+use crate::mass::{Tolerance, NEUTRON, PROTON};
+use crate::par::prelude::*;
+use crate::scoring::Feature;
+use crate::spectrum::{
+    averagine_isotopes, deisotope_averagine, ProcessedSpectrum, AVERAGINE_ISOTOPES,
+};
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+/// Number of isotope peaks (including the monoisotopic peak) of a feature
+/// that may fall within an isolation window
+const PURITY_ISOTOPES: usize = 4;
+
+/// Settings of MS1 feature detection
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FeatureFinder {
+    /// Tolerance, in ppm, of isotopic peaks within a scan, and of the
+    /// monoisotopic m/z of an envelope across scans
+    pub ppm_tolerance: f32,
+    pub min_charge: u8,
+    pub max_charge: u8,
+    /// Minimum number of MS1 scans in which a feature is detected
+    pub min_scans: usize,
+    /// Maximum number of consecutive MS1 scans in which a feature may be
+    /// missing before its trace ends
+    pub max_gap: usize,
+}
+
+impl Default for FeatureFinder {
+    fn default() -> Self {
+        FeatureFinder {
+            ppm_tolerance: 10.0,
+            min_charge: 2,
+            max_charge: 4,
+            min_scans: 3,
+            max_gap: 1,
+        }
+    }
+}
+
+/// An isotopic envelope traced over consecutive MS1 scans of a run
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ms1Feature {
+    pub file_id: usize,
+    /// Monoisotopic m/z, intensity-weighted mean over all scans
+    pub mz: f32,
+    pub charge: u8,
+    pub rt_start: f32,
+    pub rt_apex: f32,
+    pub rt_end: f32,
+    /// Summed intensity of the isotopic envelope at the apex
+    pub apex_intensity: f32,
+    /// Envelope intensity integrated over retention time
+    pub area: f32,
+    /// Cosine similarity between the envelope and the averagine isotope
+    /// distribution, intensity-weighted mean over all scans
+    pub envelope_fit: f32,
+    /// Retention time and envelope intensity of each scan in which the
+    /// feature was detected
+    pub trace: Vec<(f32, f32)>,
+}
+
+impl Ms1Feature {
+    /// Neutral monoisotopic mass
+    pub fn mass(&self) -> f32 {
+        (self.mz - PROTON) * self.charge as f32
+    }
+
+    /// Envelope intensity at retention time `rt`, interpolated between the
+    /// scans of the trace, and 0 outside of it
+    pub fn intensity_at(&self, rt: f32) -> f32 {
+        let idx = self.trace.partition_point(|&(t, _)| t < rt);
+        match (idx.checked_sub(1), self.trace.get(idx)) {
+            (_, Some(&(t, intensity))) if t == rt => intensity,
+            (Some(prev), Some(&(t1, i1))) => {
+                let (t0, i0) = self.trace[prev];
+                i0 + (i1 - i0) * (rt - t0) / (t1 - t0)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// An isotopic envelope detected in a single MS1 scan
+struct Envelope {
+    mz: f32,
+    charge: u8,
+    intensity: f32,
+    fit: f32,
+}
+
+/// A feature that is still being traced
+struct Trace {
+    mz: f32,
+    charge: u8,
+    /// Index of the last scan in which the envelope was detected
+    last: usize,
+    /// Intensity-weighted sums of m/z and envelope fit
+    mz_sum: f32,
+    fit_sum: f32,
+    weight: f32,
+    points: Vec<(f32, f32)>,
+}
+
+impl Trace {
+    fn new(scan: usize, rt: f32, envelope: &Envelope) -> Self {
+        let mut trace = Trace {
+            mz: envelope.mz,
+            charge: envelope.charge,
+            last: scan,
+            mz_sum: 0.0,
+            fit_sum: 0.0,
+            weight: 0.0,
+            points: Vec::new(),
+        };
+        trace.add(scan, rt, envelope);
+        trace
+    }
+
+    fn add(&mut self, scan: usize, rt: f32, envelope: &Envelope) {
+        self.last = scan;
+        self.mz_sum += envelope.mz * envelope.intensity;
+        self.fit_sum += envelope.fit * envelope.intensity;
+        self.weight += envelope.intensity;
+        self.points.push((rt, envelope.intensity));
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b))
+}
+
+impl FeatureFinder {
+    /// Detect the features of each run in `spectra`. Spectra other than MS1
+    /// scans are ignored
+    pub fn detect(&self, spectra: &[ProcessedSpectrum]) -> Ms1FeatureMap {
+        let mut runs: FnvHashMap<usize, Vec<&ProcessedSpectrum>> = FnvHashMap::default();
+        for spectrum in spectra.iter().filter(|s| s.level == 1) {
+            runs.entry(spectrum.file_id).or_default().push(spectrum);
+        }
+        let features = runs
+            .into_par_iter()
+            .flat_map_iter(|(file_id, mut scans)| {
+                scans.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+                self.trace(file_id, &scans)
+            })
+            .collect::<Vec<_>>();
+        Ms1FeatureMap::new(features)
+    }
+
+    /// Isotopic envelopes of charge `min_charge` to `max_charge` in a scan
+    fn envelopes(&self, scan: &ProcessedSpectrum) -> Vec<Envelope> {
+        // MS1 peaks are stored as [M]/z, without a proton
+        let mz = scan
+            .peaks
+            .iter()
+            .map(|peak| peak.mass + PROTON)
+            .collect::<Vec<_>>();
+        let int = scan
+            .peaks
+            .iter()
+            .map(|peak| peak.intensity)
+            .collect::<Vec<_>>();
+        let peaks = deisotope_averagine(&mz, &int, self.max_charge, self.ppm_tolerance);
+
+        // Observed isotope intensities of each envelope, by monoisotopic peak
+        let mut observed: FnvHashMap<usize, [f32; AVERAGINE_ISOTOPES]> = FnvHashMap::default();
+        for (idx, peak) in peaks.iter().enumerate() {
+            let (mono, charge) = match (peak.envelope, peak.charge) {
+                (Some(mono), Some(charge)) => (mono, charge),
+                (None, Some(charge)) => (idx, charge),
+                _ => continue,
+            };
+            let isotope = ((mz[idx] - mz[mono]) * charge as f32 / NEUTRON).round() as usize;
+            if let Some(slot) = observed.entry(mono).or_default().get_mut(isotope) {
+                *slot = int[idx];
+            }
+        }
+
+        let mut envelopes = observed
+            .into_iter()
+            .filter_map(|(mono, observed)| {
+                let charge = peaks[mono].charge.filter(|&c| c >= self.min_charge)?;
+                let theoretical = averagine_isotopes((mz[mono] - PROTON) * charge as f32);
+                Some(Envelope {
+                    mz: mz[mono],
+                    charge,
+                    intensity: peaks[mono].intensity,
+                    fit: cosine(&observed, &theoretical),
+                })
+            })
+            .collect::<Vec<_>>();
+        envelopes.sort_by(|a, b| {
+            b.intensity
+                .total_cmp(&a.intensity)
+                .then_with(|| a.mz.total_cmp(&b.mz))
+        });
+        envelopes
+    }
+
+    /// Trace envelopes across the MS1 `scans` of a run, sorted by retention
+    /// time. Each envelope extends the trace of the same charge and closest
+    /// monoisotopic m/z, from most to least intense envelope
+    fn trace(&self, file_id: usize, scans: &[&ProcessedSpectrum]) -> Vec<Ms1Feature> {
+        let mut features = Vec::new();
+        // Traces that may still be extended, sorted by m/z
+        let mut active: Vec<Trace> = Vec::new();
+        for (idx, scan) in scans.iter().enumerate() {
+            let mut extended = vec![false; active.len()];
+            let mut started = Vec::new();
+            for envelope in self.envelopes(scan) {
+                let tol = Tolerance::ppm_to_delta_mass(envelope.mz, self.ppm_tolerance);
+                let start = active.partition_point(|trace| trace.mz < envelope.mz - tol);
+                let closest = (start..active.len())
+                    .take_while(|&t| active[t].mz <= envelope.mz + tol)
+                    .filter(|&t| !extended[t] && active[t].charge == envelope.charge)
+                    .min_by(|&a, &b| {
+                        let delta = |t: usize| (active[t].mz - envelope.mz).abs();
+                        delta(a).total_cmp(&delta(b))
+                    });
+                match closest {
+                    Some(t) => {
+                        extended[t] = true;
+                        active[t].add(idx, scan.scan_start_time, &envelope);
+                    }
+                    None => started.push(Trace::new(idx, scan.scan_start_time, &envelope)),
+                }
+            }
+
+            // End traces that were missing from more than `max_gap` scans
+            let (ended, mut kept): (Vec<_>, Vec<_>) = active
+                .into_iter()
+                .partition(|trace| idx - trace.last > self.max_gap);
+            features.extend(ended.into_iter().filter_map(|t| self.finish(file_id, t)));
+            kept.extend(started);
+            kept.iter_mut()
+                .for_each(|trace| trace.mz = trace.mz_sum / trace.weight);
+            kept.sort_by(|a, b| a.mz.total_cmp(&b.mz));
+            active = kept;
+        }
+        features.extend(active.into_iter().filter_map(|t| self.finish(file_id, t)));
+        features
+    }
+
+    fn finish(&self, file_id: usize, trace: Trace) -> Option<Ms1Feature> {
+        if trace.points.len() < self.min_scans {
+            return None;
+        }
+        let &(rt_apex, apex_intensity) = trace.points.iter().max_by(|a, b| a.1.total_cmp(&b.1))?;
+        // Trapezoidal integration
+        let area = trace
+            .points
+            .windows(2)
+            .map(|w| (w[1].0 - w[0].0) * (w[0].1 + w[1].1) / 2.0)
+            .sum();
+        Some(Ms1Feature {
+            file_id,
+            mz: trace.mz_sum / trace.weight,
+            charge: trace.charge,
+            rt_start: trace.points[0].0,
+            rt_apex,
+            rt_end: trace.points[trace.points.len() - 1].0,
+            apex_intensity,
+            area,
+            envelope_fit: trace.fit_sum / trace.weight,
+            trace: trace.points,
+        })
+    }
+}
+
+/// MS1 features of one or more runs
+#[derive(Clone, Debug, Default)]
+pub struct Ms1FeatureMap {
+    /// Sorted by file, then monoisotopic m/z
+    features: Vec<Ms1Feature>,
+}
+
+impl Ms1FeatureMap {
+    pub fn new(mut features: Vec<Ms1Feature>) -> Self {
+        features.sort_by(|a, b| {
+            a.file_id
+                .cmp(&b.file_id)
+                .then_with(|| a.mz.total_cmp(&b.mz))
+        });
+        Ms1FeatureMap { features }
+    }
+
+    pub fn features(&self) -> &[Ms1Feature] {
+        &self.features
+    }
+
+    pub fn into_features(self) -> Vec<Ms1Feature> {
+        self.features
+    }
+
+    /// Features of run `file_id` with a monoisotopic m/z between `lo` and `hi`
+    pub fn window(&self, file_id: usize, lo: f32, hi: f32) -> &[Ms1Feature] {
+        let start = self
+            .features
+            .partition_point(|f| f.file_id < file_id || (f.file_id == file_id && f.mz < lo));
+        let end = self
+            .features
+            .partition_point(|f| f.file_id < file_id || (f.file_id == file_id && f.mz <= hi));
+        &self.features[start..end.max(start)]
+    }
+
+    /// The feature of run `file_id` with charge `charge` and a monoisotopic
+    /// m/z within `tolerance` of `mz` that is most intense at retention time
+    /// `rt`, if any elutes at `rt`
+    pub fn find(
+        &self,
+        file_id: usize,
+        mz: f32,
+        charge: u8,
+        rt: f32,
+        tolerance: Tolerance,
+    ) -> Option<&Ms1Feature> {
+        let (lo, hi) = tolerance.bounds(mz);
+        self.window(file_id, lo, hi)
+            .iter()
+            .filter(|f| f.charge == charge && f.rt_start <= rt && f.rt_end >= rt)
+            .max_by(|a, b| a.intensity_at(rt).total_cmp(&b.intensity_at(rt)))
+    }
+
+    /// Estimate the isolation window purity of each PSM from features: the
+    /// fraction of the intensity of all features with an isotope within the
+    /// isolation window, at the retention time of the MS2 scan, that belongs
+    /// to the feature of the PSM's (calculated) precursor.
+    ///
+    /// PSMs whose precursor was not detected as a feature keep their previous
+    /// estimate, see [`crate::scoring::isolation_purity`]
+    pub fn isolation_purity(&self, features: &mut [Feature], tolerance: Tolerance) {
+        features.par_iter_mut().for_each(|feat| {
+            let (lo, hi) = match feat.isolation_window {
+                Some(window) => window,
+                None => return,
+            };
+            let charge = feat.charge.max(1);
+            let mz = feat.calcmass / charge as f32 + PROTON;
+            let precursor = match self.find(feat.file_id, mz, charge, feat.rt, tolerance) {
+                Some(precursor) => precursor.intensity_at(feat.rt),
+                None => return,
+            };
+
+            let total = self
+                .window(
+                    feat.file_id,
+                    lo - (PURITY_ISOTOPES - 1) as f32 * NEUTRON,
+                    hi,
+                )
+                .iter()
+                .filter(|f| {
+                    (0..PURITY_ISOTOPES).any(|isotope| {
+                        let mz = f.mz + isotope as f32 * NEUTRON / f.charge as f32;
+                        mz >= lo && mz <= hi
+                    })
+                })
+                .map(|f| f.intensity_at(feat.rt))
+                .sum::<f32>();
+            if total > 0.0 {
+                feat.isolation_purity = Some((precursor / total).min(1.0));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spectrum::Peak;
+
+    /// MS1 scan at `rt` containing the averagine envelopes of `(mass, charge,
+    /// intensity)` precursors
+    fn scan(rt: f32, precursors: &[(f32, u8, f32)]) -> ProcessedSpectrum {
+        let mut peaks = precursors
+            .iter()
+            .flat_map(|&(mass, charge, intensity)| {
+                averagine_isotopes(mass)
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(n, rel)| Peak {
+                        mass: (mass + n as f32 * NEUTRON) / charge as f32,
+                        intensity: rel * intensity,
+                    })
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        ProcessedSpectrum {
+            level: 1,
+            scan_start_time: rt,
+            peaks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detect_features() {
+        // A 1500 Da precursor at charge 2 eluting over 5 scans, missing from
+        // one, a 2000 Da precursor at charge 3 detected in only 2 scans, and
+        // a singly charged contaminant
+        let elution = [1.0, 4.0, 0.0, 4.0, 1.0];
+        let spectra = elution
+            .iter()
+            .enumerate()
+            .map(|(idx, &intensity)| {
+                let mut precursors = vec![(800.0, 1, 1000.0)];
+                if intensity > 0.0 {
+                    precursors.push((1500.0, 2, intensity * 100.0));
+                }
+                if idx < 2 {
+                    precursors.push((2000.0, 3, 100.0));
+                }
+                scan(idx as f32, &precursors)
+            })
+            .collect::<Vec<_>>();
+
+        let map = FeatureFinder::default().detect(&spectra);
+        assert_eq!(map.features().len(), 1);
+        let feature = &map.features()[0];
+        assert_eq!(feature.charge, 2);
+        assert!((feature.mass() - 1500.0).abs() < 0.01, "{:?}", feature);
+        assert_eq!(feature.trace.len(), 4);
+        assert_eq!((feature.rt_start, feature.rt_end), (0.0, 4.0));
+        assert!(feature.envelope_fit > 0.99);
+
+        // Envelope intensities are summed over isotopes
+        let envelope = averagine_isotopes(1500.0).iter().sum::<f32>() * 100.0;
+        assert!((feature.apex_intensity - 4.0 * envelope).abs() < 1.0);
+        assert!((feature.intensity_at(2.0) - 4.0 * envelope).abs() < 1.0);
+        assert!((feature.intensity_at(3.5) - 2.5 * envelope).abs() < 1.0);
+        assert_eq!(feature.intensity_at(5.0), 0.0);
+        assert!((feature.area - 13.0 * envelope).abs() < 1.0);
+
+        let mz = 1500.0 / 2.0 + PROTON;
+        let tolerance = Tolerance::Ppm(-10.0, 10.0);
+        assert!(map.find(0, mz, 2, 2.0, tolerance).is_some());
+        assert!(map.find(0, mz, 3, 2.0, tolerance).is_none());
+        assert!(map.find(0, mz, 2, 5.0, tolerance).is_none());
+        assert!(map.find(1, mz, 2, 2.0, tolerance).is_none());
+    }
+
+    #[test]
+    fn feature_isolation_purity() {
+        // Two co-eluting precursors of equal intensity within the same window
+        let spectra = (0..3)
+            .map(|idx| scan(idx as f32, &[(1500.0, 2, 100.0), (1502.5, 2, 100.0)]))
+            .collect::<Vec<_>>();
+        let map = FeatureFinder::default().detect(&spectra);
+        assert_eq!(map.features().len(), 2);
+
+        let psm = |calcmass| Feature {
+            calcmass,
+            charge: 2,
+            rt: 1.5,
+            isolation_window: Some((749.0, 753.0)),
+            ..Default::default()
+        };
+        let mut features = vec![psm(1500.0), psm(1502.5), psm(1600.0)];
+        map.isolation_purity(&mut features, Tolerance::Ppm(-10.0, 10.0));
+        let purity = features
+            .iter()
+            .map(|feat| feat.isolation_purity)
+            .collect::<Vec<_>>();
+        assert!((purity[0].unwrap() - 0.5).abs() < 0.05, "{:?}", purity);
+        assert!((purity[1].unwrap() - 0.5).abs() < 0.05, "{:?}", purity);
+        // Precursors without a feature keep their estimate
+        assert_eq!(purity[2], None);
+    }
+}
@@ -0,0 +1,125 @@
+//! Data parallelism, with a sequential fallback
+//!
+//! With the `parallel` feature (enabled by default), this re-exports the
+//! [rayon](https://docs.rs/rayon) prelude. Without it, the same methods are
+//! provided by extension traits on the standard library iterators and slices,
+//! which run on the calling thread - e.g. when compiling to `wasm32`, where
+//! threads are generally not available.
+//!
+//! Modules use `use crate::par::prelude::*;` in place of
+//! `use rayon::prelude::*;`, and only call the subset of rayon's API that is
+//! mirrored here.
+
+#[cfg(feature = "parallel")]
+pub mod prelude {
+    pub use rayon::prelude::*;
+}
+
+#[cfg(not(feature = "parallel"))]
+pub mod prelude {
+    use std::cmp::Ordering;
+
+    /// Sequential counterpart of `rayon::iter::IntoParallelIterator`
+    pub trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<T: IntoIterator> IntoParallelIterator for T {}
+
+    /// Sequential counterpart of `rayon::iter::IntoParallelRefIterator`
+    pub trait IntoParallelRefIterator<'a> {
+        type Iter: Iterator;
+        fn par_iter(&'a self) -> Self::Iter;
+    }
+
+    impl<'a, T: 'a + ?Sized> IntoParallelRefIterator<'a> for T
+    where
+        &'a T: IntoIterator,
+    {
+        type Iter = <&'a T as IntoIterator>::IntoIter;
+
+        fn par_iter(&'a self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    /// Sequential counterpart of `rayon::iter::IntoParallelRefMutIterator`
+    pub trait IntoParallelRefMutIterator<'a> {
+        type Iter: Iterator;
+        fn par_iter_mut(&'a mut self) -> Self::Iter;
+    }
+
+    impl<'a, T: 'a + ?Sized> IntoParallelRefMutIterator<'a> for T
+    where
+        &'a mut T: IntoIterator,
+    {
+        type Iter = <&'a mut T as IntoIterator>::IntoIter;
+
+        fn par_iter_mut(&'a mut self) -> Self::Iter {
+            self.into_iter()
+        }
+    }
+
+    /// Methods of `rayon::iter::ParallelIterator` that are not shared with
+    /// [`Iterator`]
+    pub trait ParallelIterator: Iterator + Sized {
+        fn flat_map_iter<U, F>(self, f: F) -> std::iter::FlatMap<Self, U, F>
+        where
+            U: IntoIterator,
+            F: FnMut(Self::Item) -> U,
+        {
+            self.flat_map(f)
+        }
+    }
+
+    impl<I: Iterator> ParallelIterator for I {}
+
+    /// Sequential counterpart of `rayon::slice::ParallelSlice`
+    pub trait ParallelSlice<T> {
+        fn par_windows(&self, size: usize) -> std::slice::Windows<'_, T>;
+        fn par_chunks(&self, size: usize) -> std::slice::Chunks<'_, T>;
+    }
+
+    impl<T> ParallelSlice<T> for [T] {
+        fn par_windows(&self, size: usize) -> std::slice::Windows<'_, T> {
+            self.windows(size)
+        }
+
+        fn par_chunks(&self, size: usize) -> std::slice::Chunks<'_, T> {
+            self.chunks(size)
+        }
+    }
+
+    /// Sequential counterpart of `rayon::slice::ParallelSliceMut`
+    pub trait ParallelSliceMut<T> {
+        fn par_chunks_mut(&mut self, size: usize) -> std::slice::ChunksMut<'_, T>;
+        fn par_sort_by<F: FnMut(&T, &T) -> Ordering>(&mut self, compare: F);
+        fn par_sort_unstable(&mut self)
+        where
+            T: Ord;
+        fn par_sort_unstable_by<F: FnMut(&T, &T) -> Ordering>(&mut self, compare: F);
+    }
+
+    impl<T> ParallelSliceMut<T> for [T] {
+        fn par_chunks_mut(&mut self, size: usize) -> std::slice::ChunksMut<'_, T> {
+            self.chunks_mut(size)
+        }
+
+        fn par_sort_by<F: FnMut(&T, &T) -> Ordering>(&mut self, compare: F) {
+            self.sort_by(compare)
+        }
+
+        fn par_sort_unstable(&mut self)
+        where
+            T: Ord,
+        {
+            self.sort_unstable()
+        }
+
+        fn par_sort_unstable_by<F: FnMut(&T, &T) -> Ordering>(&mut self, compare: F) {
+            self.sort_unstable_by(compare)
+        }
+    }
+}
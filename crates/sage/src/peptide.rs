@@ -4,7 +4,7 @@ use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use crate::modification::ModificationSpecificity;
 use crate::{
     enzyme::{Digest, Position},
-    mass::{monoisotopic, H2O},
+    mass::{ResidueMasses, H2O},
 };
 use fnv::FnvHashSet;
 use itertools::Itertools;
@@ -78,6 +78,11 @@ impl Peptide {
         }
     }
 
+    /// Is this peptide sequence found in more than one protein?
+    pub fn shared(&self) -> bool {
+        self.proteins.len() > 1
+    }
+
     pub fn proteins(&self, decoy_tag: &str, generate_decoys: bool) -> String {
         if self.decoy {
             self.proteins
@@ -348,6 +353,14 @@ impl TryFrom<Digest> for Peptide {
     type Error = PeptideError;
 
     fn try_from(value: Digest) -> Result<Self, Self::Error> {
+        Peptide::from_digest(value, &ResidueMasses::default())
+    }
+}
+
+impl Peptide {
+    /// Convert a [`Digest`] into a [`Peptide`], using `masses` to calculate the
+    /// peptide mass. Residues without a mass are invalid
+    pub fn from_digest(value: Digest, masses: &ResidueMasses) -> Result<Self, PeptideError> {
         let mut mass = H2O;
         // This is an important invariant to enforce, that ensures safety
         // while reversing peptide sequences
@@ -355,7 +368,7 @@ impl TryFrom<Digest> for Peptide {
             return Err(PeptideError::InvalidSequence(value.sequence));
         }
         for c in value.sequence.as_bytes() {
-            let mono = monoisotopic(*c);
+            let mono = masses.monoisotopic(*c);
             if mono == 0.0 {
                 return Err(PeptideError::InvalidSequence(value.sequence));
             }
@@ -423,6 +436,7 @@ mod test {
             min_len: 0,
             max_len: 50,
             missed_cleavages: 0,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -615,6 +629,7 @@ mod test {
             missed_cleavages: 0,
             min_len: 3,
             max_len: 30,
+            clip_nterm_methionine: false,
             enyzme: Enzyme::new("KR", Some('P'), true, false),
         };
 
@@ -0,0 +1,231 @@
+//! Approximate nearest-neighbor prefilter over binned spectra.
+//!
+//! Each theoretical spectrum in the database is reduced to a set of fragment
+//! mass bins, and summarized by a MinHash signature. Two spectra with similar
+//! sets of bins (high Jaccard similarity) are likely to share MinHash values,
+//! so a query spectrum can be compared against millions of peptides by looking
+//! up each of its MinHash values in a sorted table, rather than by matching
+//! every peak against the fragment index. Peptides sharing at least
+//! `min_collisions` MinHash values with a query are shortlisted for exact
+//! scoring.
+//!
+//! This is an approximate method: true matches with few matched fragment ions
+//! may be missed, in exchange for much faster searches against very large
+//! candidate spaces (e.g. open or non-specific searches).
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::par::prelude::*;
+use crate::spectrum::ProcessedSpectrum;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PrefilterSettings {
+    /// Width of fragment mass bins, in Da
+    pub bin_width: f32,
+    /// Number of MinHash functions (and lookup tables)
+    pub hashes: usize,
+    /// Minimum number of MinHash values a peptide must share with a query
+    /// spectrum to be shortlisted
+    pub min_collisions: usize,
+    /// Number of most intense peaks of each query spectrum to hash
+    pub query_peaks: usize,
+}
+
+impl Default for PrefilterSettings {
+    fn default() -> Self {
+        Self {
+            bin_width: 1.000508,
+            hashes: 32,
+            min_collisions: 3,
+            query_peaks: 50,
+        }
+    }
+}
+
+/// MinHash lookup tables over the theoretical spectra of an [`IndexedDatabase`]
+pub struct SpectralIndex {
+    settings: PrefilterSettings,
+    /// For each hash function, (MinHash value, peptide) sorted by value
+    tables: Vec<Vec<(u32, PeptideIx)>>,
+}
+
+impl SpectralIndex {
+    /// Build MinHash tables from the fragment ions of `db`. Only fragments
+    /// that were indexed (i.e. within the configured fragment m/z range and ion
+    /// index) contribute to each peptide's signature
+    pub fn build(db: &IndexedDatabase, settings: PrefilterSettings) -> Self {
+        let mut bins = db
+            .fragments
+            .par_iter()
+            .map(|frag| {
+                (
+                    frag.peptide_index,
+                    bin(frag.fragment_mz, settings.bin_width),
+                )
+            })
+            .collect::<Vec<_>>();
+        bins.par_sort_unstable();
+        bins.dedup();
+
+        // Split into runs of bins belonging to the same peptide
+        let mut bounds = (1..bins.len())
+            .filter(|&idx| bins[idx].0 != bins[idx - 1].0)
+            .collect::<Vec<_>>();
+        bounds.insert(0, 0);
+        bounds.push(bins.len());
+
+        // Signature of each peptide with at least one indexed fragment
+        let signatures = bounds
+            .par_windows(2)
+            .filter(|w| w[0] < w[1])
+            .map(|w| {
+                let chunk = &bins[w[0]..w[1]];
+                let signature = minhash(chunk.iter().map(|(_, bin)| *bin), settings.hashes);
+                (chunk[0].0, signature)
+            })
+            .collect::<Vec<_>>();
+
+        let tables = (0..settings.hashes)
+            .into_par_iter()
+            .map(|hash| {
+                let mut table = signatures
+                    .iter()
+                    .map(|(peptide, signature)| (signature[hash], *peptide))
+                    .collect::<Vec<_>>();
+                table.sort_unstable();
+                table
+            })
+            .collect();
+
+        Self { settings, tables }
+    }
+
+    pub fn settings(&self) -> &PrefilterSettings {
+        &self.settings
+    }
+
+    /// Shortlist candidate peptides for `query`: all peptides sharing at
+    /// least `min_collisions` MinHash values with the binned query spectrum,
+    /// and the number of shared values
+    pub fn shortlist(&self, query: &ProcessedSpectrum) -> Vec<(PeptideIx, u16)> {
+        let mut peaks = query.peaks.iter().collect::<Vec<_>>();
+        peaks.sort_unstable_by(|a, b| b.intensity.total_cmp(&a.intensity));
+        peaks.truncate(self.settings.query_peaks);
+        if peaks.is_empty() {
+            return Vec::new();
+        }
+
+        let signature = minhash(
+            peaks
+                .iter()
+                .map(|peak| bin(peak.mass, self.settings.bin_width)),
+            self.settings.hashes,
+        );
+
+        let mut collisions: FnvHashMap<PeptideIx, u16> = FnvHashMap::default();
+        for (table, value) in self.tables.iter().zip(signature) {
+            let lo = table.partition_point(|(v, _)| *v < value);
+            for (_, peptide) in table[lo..].iter().take_while(|(v, _)| *v == value) {
+                *collisions.entry(*peptide).or_default() += 1;
+            }
+        }
+
+        let mut shortlist = collisions
+            .into_iter()
+            .filter(|(_, count)| *count as usize >= self.settings.min_collisions)
+            .collect::<Vec<_>>();
+        shortlist.sort_unstable();
+        shortlist
+    }
+}
+
+#[inline]
+fn bin(mass: f32, bin_width: f32) -> u32 {
+    (mass / bin_width).round() as u32
+}
+
+/// SplitMix64 finalizer, used to derive independent hash functions by
+/// seeding with the hash function index
+#[inline]
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn minhash<I: Iterator<Item = u32> + Clone>(bins: I, hashes: usize) -> Vec<u32> {
+    (0..hashes as u64)
+        .map(|seed| {
+            let seed = mix(seed.wrapping_add(0x9e3779b97f4a7c15));
+            bins.clone()
+                .map(|bin| (mix(bin as u64 ^ seed) >> 32) as u32)
+                .min()
+                .unwrap_or(u32::MAX)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+    use crate::ion_series::{IonSeries, Kind};
+    use crate::spectrum::{Peak, Precursor};
+
+    #[test]
+    fn shortlist_theoretical_spectrum() {
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n>sp|BBBB|BBBB\nPEPTIDERDEVIANCEK\n"
+                .into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let index = SpectralIndex::build(&db, PrefilterSettings::default());
+
+        let (target, peptide) = db
+            .peptides
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .expect("peptide in database");
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "query".into(),
+            file_id: 0,
+            scan_start_time: 0.0,
+            ion_injection_time: 0.0,
+            precursors: vec![Precursor::default()],
+            peaks,
+            total_ion_current: 0.0,
+            noise: Vec::new(),
+            ion_mobility: None,
+            compensation_voltage: None,
+        };
+
+        let shortlist = index.shortlist(&query);
+        let best = shortlist
+            .iter()
+            .max_by_key(|(_, collisions)| *collisions)
+            .expect("non-empty shortlist");
+        assert_eq!(best.0, PeptideIx(target as u32));
+    }
+}
@@ -0,0 +1,487 @@
+//! Peptide-centric extraction of fragment ion chromatograms from targeted
+//! (PRM) runs
+//!
+//! In parallel reaction monitoring, a predefined list of precursors is
+//! isolated and fragmented repeatedly across the chromatographic run. Rather
+//! than identifying each spectrum, every target precursor is quantified
+//! directly:
+//!
+//! 1) All MS2 spectra isolating the target's precursor m/z are collected,
+//!    and the intensity of each target fragment ion is extracted from them,
+//!    forming one extracted ion chromatogram (XIC) per fragment
+//! 2) The chromatographic peak is located at the apex of the summed XIC, and
+//!    extends in both directions until the summed intensity falls below 5%
+//!    of the apex
+//! 3) Fragment XICs are integrated within the peak, and the peak is scored
+//!    by the co-elution of its fragments, and by the similarity of the
+//!    integrated fragment areas to a reference (library or theoretical)
+//!    spectrum
+
+use crate::ion_series::{IonSeries, Kind};
+use crate::mass::{ResidueMasses, Tolerance, PROTON};
+use crate::peptide::Peptide;
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use serde::{Deserialize, Serialize};
+
+/// Peak boundaries are placed where the summed fragment intensity falls
+/// below this fraction of the apex intensity
+const PEAK_BOUNDARY: f32 = 0.05;
+
+/// Precursor isolation tolerance for spectra that do not report an
+/// isolation window
+const DEFAULT_ISOLATION: Tolerance = Tolerance::Da(-0.5, 0.5);
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PrmSettings {
+    /// Number of fragment ions extracted for each target
+    pub fragments: usize,
+    /// Minimum number of fragments detected at the apex for a peak to be
+    /// reported, rather than an empty result
+    pub min_fragments: usize,
+}
+
+impl Default for PrmSettings {
+    fn default() -> Self {
+        Self {
+            fragments: 6,
+            min_fragments: 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TargetFragment {
+    pub kind: Kind,
+    pub ordinal: i32,
+    pub charge: u8,
+    pub mz: f32,
+    /// Reference intensity, relative to the most intense fragment
+    pub intensity: f32,
+}
+
+/// A target precursor and the fragment ions to extract
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrmTarget {
+    /// Modified peptide sequence
+    pub peptide: String,
+    pub charge: u8,
+    pub precursor_mz: f32,
+    pub fragments: Vec<TargetFragment>,
+    /// Whether the reference intensities come from a spectral library
+    pub library: bool,
+}
+
+impl PrmTarget {
+    /// Build a target from the theoretical fragments of `peptide`: the
+    /// longest `count` singly charged fragment ions within `[min_mz, max_mz]`,
+    /// all with equal reference intensity
+    pub fn theoretical(
+        peptide: &Peptide,
+        charge: u8,
+        kinds: &[Kind],
+        masses: ResidueMasses,
+        (min_mz, max_mz): (f32, f32),
+        count: usize,
+    ) -> Self {
+        let len = peptide.sequence.len() as i32;
+        let mut fragments = kinds
+            .iter()
+            .flat_map(|kind| IonSeries::with_residue_masses(peptide, *kind, masses).enumerate())
+            .map(|(idx, ion)| TargetFragment {
+                kind: ion.kind,
+                ordinal: match ion.kind {
+                    Kind::A | Kind::B | Kind::C => idx as i32 + 1,
+                    Kind::X | Kind::Y | Kind::Z => len - 1 - idx as i32,
+                },
+                charge: 1,
+                mz: ion.monoisotopic_mass + PROTON,
+                intensity: 1.0,
+            })
+            .filter(|frag| frag.mz >= min_mz && frag.mz <= max_mz)
+            .collect::<Vec<_>>();
+        // Longer fragments are more specific, prefer them
+        fragments.sort_by(|a, b| b.ordinal.cmp(&a.ordinal).then(a.mz.total_cmp(&b.mz)));
+        fragments.truncate(count);
+
+        PrmTarget {
+            peptide: peptide.to_string(),
+            charge,
+            precursor_mz: (peptide.monoisotopic + charge as f32 * PROTON) / charge as f32,
+            fragments,
+            library: false,
+        }
+    }
+
+    /// Build a target from a library spectrum, using its `count` most intense
+    /// fragment ions
+    pub fn from_library(
+        peptide: String,
+        charge: u8,
+        precursor_mz: f32,
+        mut fragments: Vec<TargetFragment>,
+        count: usize,
+    ) -> Self {
+        fragments.sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+        fragments.truncate(count);
+        PrmTarget {
+            peptide,
+            charge,
+            precursor_mz,
+            fragments,
+            library: true,
+        }
+    }
+}
+
+/// Extraction result for a single target in a single file
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrmResult {
+    /// Index of the target in the list passed to [`extract`]
+    pub target: usize,
+    pub file_id: usize,
+    /// Number of MS2 spectra isolating the target precursor
+    pub scans: usize,
+    pub apex_rt: f32,
+    pub start_rt: f32,
+    pub end_rt: f32,
+    /// Number of target fragments detected in the apex spectrum
+    pub fragments_detected: usize,
+    /// Integrated intensity of each target fragment, in target order
+    pub fragment_areas: Vec<f32>,
+    /// Summed integrated intensity of all target fragments
+    pub area: f32,
+    /// Mean pairwise Pearson correlation of the fragment XICs within the peak
+    pub coelution: f32,
+    /// Normalized dot product of the (square root) fragment areas and the
+    /// reference intensities
+    pub dot_product: f32,
+}
+
+/// Extract and score the fragment XICs of every target in every file.
+/// Targets without a peak (fewer than `settings.min_fragments` detected
+/// fragments at the apex) are reported with zero areas and scores, as long
+/// as at least one spectrum isolated their precursor
+pub fn extract(
+    targets: &[PrmTarget],
+    spectra: &[ProcessedSpectrum],
+    fragment_tol: Tolerance,
+    settings: &PrmSettings,
+) -> Vec<PrmResult> {
+    let mut ms2 = spectra
+        .iter()
+        .filter(|s| s.level == 2 && !s.precursors.is_empty())
+        .collect::<Vec<_>>();
+    ms2.sort_by(|a, b| {
+        a.file_id
+            .cmp(&b.file_id)
+            .then(a.scan_start_time.total_cmp(&b.scan_start_time))
+    });
+
+    let mut files: Vec<Vec<&ProcessedSpectrum>> = Vec::new();
+    for spectrum in ms2 {
+        match files.last_mut() {
+            Some(file) if file[0].file_id == spectrum.file_id => file.push(spectrum),
+            _ => files.push(vec![spectrum]),
+        }
+    }
+
+    let mut results = Vec::new();
+    for (idx, target) in targets.iter().enumerate() {
+        for file in &files {
+            let scans = file
+                .iter()
+                .filter(|s| {
+                    let precursor = &s.precursors[0];
+                    let (lo, hi) = precursor
+                        .isolation_window
+                        .unwrap_or(DEFAULT_ISOLATION)
+                        .bounds(precursor.mz);
+                    target.precursor_mz >= lo && target.precursor_mz <= hi
+                })
+                .copied()
+                .collect::<Vec<_>>();
+            if scans.is_empty() {
+                continue;
+            }
+            results.push(score_target(idx, target, &scans, fragment_tol, settings));
+        }
+    }
+    results
+}
+
+fn score_target(
+    idx: usize,
+    target: &PrmTarget,
+    scans: &[&ProcessedSpectrum],
+    fragment_tol: Tolerance,
+    settings: &PrmSettings,
+) -> PrmResult {
+    // One XIC per fragment, over all scans isolating the precursor
+    let xics = target
+        .fragments
+        .iter()
+        .map(|frag| {
+            scans
+                .iter()
+                .map(|s| {
+                    select_most_intense_peak(&s.peaks, frag.mz - PROTON, fragment_tol, None)
+                        .map(|peak| peak.intensity)
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let summed = (0..scans.len())
+        .map(|scan| xics.iter().map(|xic| xic[scan]).sum::<f32>())
+        .collect::<Vec<_>>();
+
+    let apex = summed
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(apex, _)| apex)
+        .unwrap_or_default();
+    let fragments_detected = xics.iter().filter(|xic| xic[apex] > 0.0).count();
+
+    let mut result = PrmResult {
+        target: idx,
+        file_id: scans[0].file_id,
+        scans: scans.len(),
+        apex_rt: scans[apex].scan_start_time,
+        start_rt: scans[apex].scan_start_time,
+        end_rt: scans[apex].scan_start_time,
+        fragments_detected,
+        fragment_areas: vec![0.0; target.fragments.len()],
+        area: 0.0,
+        coelution: 0.0,
+        dot_product: 0.0,
+    };
+    if fragments_detected < settings.min_fragments.max(1) {
+        return result;
+    }
+
+    let threshold = summed[apex] * PEAK_BOUNDARY;
+    let mut start = apex;
+    while start > 0 && summed[start - 1] > threshold {
+        start -= 1;
+    }
+    let mut end = apex;
+    while end + 1 < scans.len() && summed[end + 1] > threshold {
+        end += 1;
+    }
+    let rts = scans[start..=end]
+        .iter()
+        .map(|s| s.scan_start_time)
+        .collect::<Vec<_>>();
+
+    result.start_rt = rts[0];
+    result.end_rt = rts[rts.len() - 1];
+    result.fragment_areas = xics
+        .iter()
+        .map(|xic| integrate(&rts, &xic[start..=end]))
+        .collect();
+    result.area = result.fragment_areas.iter().sum();
+    result.coelution = coelution(
+        &xics
+            .iter()
+            .map(|xic| &xic[start..=end])
+            .filter(|xic| xic.iter().any(|x| *x > 0.0))
+            .collect::<Vec<_>>(),
+    );
+    result.dot_product = dot_product(
+        &result.fragment_areas,
+        &target
+            .fragments
+            .iter()
+            .map(|frag| frag.intensity)
+            .collect::<Vec<_>>(),
+    );
+    result
+}
+
+/// Trapezoidal integration of `intensities` over `rts`. Peaks consisting of a
+/// single scan are integrated as their intensity
+fn integrate(rts: &[f32], intensities: &[f32]) -> f32 {
+    if rts.len() == 1 {
+        return intensities[0];
+    }
+    rts.windows(2)
+        .zip(intensities.windows(2))
+        .map(|(rt, int)| (rt[1] - rt[0]) * (int[0] + int[1]) / 2.0)
+        .sum()
+}
+
+fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+/// Mean pairwise Pearson correlation of fragment XICs. Peaks spanning fewer
+/// than 3 scans, or with fewer than 2 fragments, have no co-elution score
+fn coelution(xics: &[&[f32]]) -> f32 {
+    if xics.len() < 2 || xics[0].len() < 3 {
+        return 0.0;
+    }
+    let mut total = 0.0;
+    let mut pairs = 0;
+    for i in 0..xics.len() {
+        for j in i + 1..xics.len() {
+            total += pearson(xics[i], xics[j]);
+            pairs += 1;
+        }
+    }
+    total / pairs as f32
+}
+
+/// Normalized dot product of square root-transformed intensities
+fn dot_product(observed: &[f32], reference: &[f32]) -> f32 {
+    let (mut dot, mut norm_o, mut norm_r) = (0.0, 0.0, 0.0);
+    for (o, r) in observed.iter().zip(reference) {
+        let (o, r) = (o.sqrt(), r.sqrt());
+        dot += o * r;
+        norm_o += o * o;
+        norm_r += r * r;
+    }
+    if norm_o == 0.0 || norm_r == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_o * norm_r).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spectrum::{Peak, Precursor};
+
+    fn fragment(mz: f32, intensity: f32) -> TargetFragment {
+        TargetFragment {
+            kind: Kind::Y,
+            ordinal: 1,
+            charge: 1,
+            mz,
+            intensity,
+        }
+    }
+
+    #[test]
+    fn extract_peak() {
+        let target = PrmTarget::from_library(
+            "PEPTIDE".into(),
+            2,
+            500.0,
+            vec![
+                fragment(300.0, 0.5),
+                fragment(400.0, 1.0),
+                fragment(600.0, 0.25),
+                fragment(700.0, 0.1),
+            ],
+            3,
+        );
+        assert_eq!(target.fragments.len(), 3);
+        assert_eq!(target.fragments[0].mz, 400.0);
+
+        // Gaussian elution profile around rt = 10, fragments in library ratios
+        let profile = [0.0, 0.01, 0.2, 0.7, 1.0, 0.6, 0.2, 0.01, 0.0];
+        let mut spectra = profile
+            .iter()
+            .enumerate()
+            .map(|(scan, scale)| ProcessedSpectrum {
+                level: 2,
+                id: scan.to_string(),
+                scan_start_time: 6.0 + scan as f32,
+                precursors: vec![Precursor {
+                    mz: 500.0,
+                    isolation_window: Some(Tolerance::Da(-0.7, 0.7)),
+                    ..Default::default()
+                }],
+                peaks: [(300.0, 0.5), (400.0, 1.0), (600.0, 0.25)]
+                    .iter()
+                    .map(|(mz, intensity)| Peak {
+                        mass: mz - PROTON,
+                        intensity: intensity * scale * 1000.0,
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        // A scan isolating a different precursor
+        spectra.push(ProcessedSpectrum {
+            level: 2,
+            precursors: vec![Precursor {
+                mz: 600.0,
+                ..Default::default()
+            }],
+            ..spectra[4].clone()
+        });
+
+        let results = extract(
+            &[target],
+            &spectra,
+            Tolerance::Ppm(-10.0, 10.0),
+            &PrmSettings::default(),
+        );
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!(result.scans, 9);
+        assert_eq!(result.apex_rt, 10.0);
+        assert_eq!((result.start_rt, result.end_rt), (8.0, 12.0));
+        assert_eq!(result.fragments_detected, 3);
+        assert!(result.coelution > 0.999);
+        assert!(result.dot_product > 0.999);
+        // 1000 * 1.75 * (0.2 + 0.7 + 1.0 + 0.6 + 0.2 - (0.2 + 0.2) / 2)
+        assert!((result.area - 1750.0 * 2.5).abs() < 1.0);
+
+        // A flat reference spectrum matches less well
+        let flat = PrmTarget {
+            fragments: flat_fragments(&[400.0, 300.0, 600.0]),
+            ..PrmTarget::from_library("PEPTIDE".into(), 2, 500.0, Vec::new(), 0)
+        };
+        let results = extract(
+            &[flat],
+            &spectra,
+            Tolerance::Ppm(-10.0, 10.0),
+            &PrmSettings::default(),
+        );
+        assert!(results[0].dot_product < 0.99);
+    }
+
+    fn flat_fragments(mzs: &[f32]) -> Vec<TargetFragment> {
+        mzs.iter().map(|mz| fragment(*mz, 1.0)).collect()
+    }
+
+    #[test]
+    fn theoretical_target() {
+        let peptide = Peptide::try_from(crate::enzyme::Digest {
+            sequence: "LESLIEK".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        let target = PrmTarget::theoretical(
+            &peptide,
+            2,
+            &[Kind::B, Kind::Y],
+            ResidueMasses::default(),
+            (200.0, 2000.0),
+            4,
+        );
+        assert_eq!(target.peptide, "LESLIEK");
+        assert!(!target.library);
+        assert_eq!(target.fragments.len(), 4);
+        // Longest fragments first
+        assert_eq!(target.fragments[0].ordinal, 6);
+        assert!(target.fragments.iter().all(|frag| frag.mz >= 200.0));
+        assert!((target.precursor_mz - 416.2447).abs() < 1E-3);
+    }
+}
@@ -0,0 +1,244 @@
+//! Quality control metrics of spectra and runs
+//!
+//! Metrics are computed from raw spectra as they are read, before any
+//! processing, so that empty or noise-only scans can be removed before
+//! scoring, and summarized for each run so that bad acquisitions stand out
+
+use crate::spectrum::RawSpectrum;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of retention time bins of [`RunMetrics::tic`]
+pub const TIC_BINS: usize = 200;
+
+/// Quality metrics of a single spectrum
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SpectrumMetrics {
+    /// Reported total ion current, or the summed intensity of all peaks
+    pub total_ion_current: f32,
+    pub base_peak_intensity: f32,
+    /// Ion injection time in milliseconds
+    pub ion_injection_time: f32,
+    /// Ratio of the base peak intensity to the median peak intensity: close
+    /// to 1 for scans containing only noise
+    pub dynamic_range: f32,
+}
+
+impl SpectrumMetrics {
+    pub fn new(spectrum: &RawSpectrum) -> Self {
+        let base_peak_intensity = spectrum.intensity.iter().fold(0.0f32, |max, &x| max.max(x));
+        let total_ion_current = match spectrum.total_ion_current > 0.0 {
+            true => spectrum.total_ion_current,
+            false => spectrum.intensity.iter().sum(),
+        };
+        let dynamic_range = match median(&mut spectrum.intensity.clone()) {
+            Some(median) if median > 0.0 => base_peak_intensity / median,
+            _ => 0.0,
+        };
+        SpectrumMetrics {
+            total_ion_current,
+            base_peak_intensity,
+            ion_injection_time: spectrum.ion_injection_time,
+            dynamic_range,
+        }
+    }
+}
+
+/// Removal of empty or noise-only MS2 spectra before scoring. The default
+/// thresholds do not remove any spectrum
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct QualityFilter {
+    /// Minimum total ion current
+    pub min_total_ion_current: f32,
+    /// Minimum ratio of the base peak intensity to the median peak intensity
+    pub min_dynamic_range: f32,
+}
+
+impl QualityFilter {
+    /// Should a spectrum with these `metrics` be searched?
+    pub fn check(&self, metrics: &SpectrumMetrics) -> bool {
+        metrics.total_ion_current >= self.min_total_ion_current
+            && metrics.dynamic_range >= self.min_dynamic_range
+    }
+}
+
+/// Summary of the spectra of a run
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RunMetrics {
+    pub file_id: usize,
+    pub ms1_spectra: usize,
+    pub ms2_spectra: usize,
+    /// MS2 spectra removed by the [`QualityFilter`]
+    pub filtered_spectra: usize,
+    /// Medians over all MS2 spectra
+    pub median_total_ion_current: f32,
+    pub median_base_peak_intensity: f32,
+    pub median_ion_injection_time: f32,
+    /// Total ion current chromatogram: summed total ion current of the MS1
+    /// spectra (or MS2 spectra, without MS1 spectra) in up to [`TIC_BINS`]
+    /// equal retention time bins, as (bin center, total ion current) pairs
+    #[serde(default)]
+    pub tic: Vec<(f32, f32)>,
+}
+
+/// Accumulates the metrics of the spectra of a run, as they are read
+#[derive(Default)]
+pub struct RunMetricsBuilder {
+    file_id: usize,
+    ms1_spectra: usize,
+    filtered_spectra: usize,
+    ms2: Vec<SpectrumMetrics>,
+    /// Retention time and total ion current of each MS1 and MS2 spectrum
+    ms1_tic: Vec<(f32, f32)>,
+    ms2_tic: Vec<(f32, f32)>,
+}
+
+impl RunMetricsBuilder {
+    pub fn new(file_id: usize) -> Self {
+        RunMetricsBuilder {
+            file_id,
+            ..Default::default()
+        }
+    }
+
+    /// Record a spectrum, returning `false` if it is an MS2 spectrum that
+    /// should be removed by `filter`
+    pub fn add(&mut self, spectrum: &RawSpectrum, filter: &QualityFilter) -> bool {
+        match spectrum.ms_level {
+            1 => {
+                self.ms1_spectra += 1;
+                let metrics = SpectrumMetrics::new(spectrum);
+                self.ms1_tic
+                    .push((spectrum.scan_start_time, metrics.total_ion_current));
+            }
+            2 => {
+                let metrics = SpectrumMetrics::new(spectrum);
+                self.ms2.push(metrics);
+                self.ms2_tic
+                    .push((spectrum.scan_start_time, metrics.total_ion_current));
+                if !filter.check(&metrics) {
+                    self.filtered_spectra += 1;
+                    return false;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn build(self) -> RunMetrics {
+        let median_of = |f: fn(&SpectrumMetrics) -> f32| {
+            median(&mut self.ms2.iter().map(f).collect::<Vec<_>>()).unwrap_or_default()
+        };
+        let tic = match self.ms1_tic.is_empty() {
+            true => chromatogram(&self.ms2_tic),
+            false => chromatogram(&self.ms1_tic),
+        };
+        RunMetrics {
+            file_id: self.file_id,
+            ms1_spectra: self.ms1_spectra,
+            ms2_spectra: self.ms2.len(),
+            filtered_spectra: self.filtered_spectra,
+            median_total_ion_current: median_of(|m| m.total_ion_current),
+            median_base_peak_intensity: median_of(|m| m.base_peak_intensity),
+            median_ion_injection_time: median_of(|m| m.ion_injection_time),
+            tic,
+        }
+    }
+}
+
+/// Sum the total ion current of `spectra` (retention time, total ion
+/// current) in up to [`TIC_BINS`] equal retention time bins
+fn chromatogram(spectra: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let (lo, hi) = spectra
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(lo, hi), &(rt, _)| {
+            (lo.min(rt), hi.max(rt))
+        });
+    if spectra.is_empty() {
+        return Vec::new();
+    }
+    let bins = TIC_BINS.min(spectra.len());
+    let width = (hi - lo) / bins as f32;
+    let mut tic = vec![0.0; bins];
+    for &(rt, intensity) in spectra {
+        let bin = match width > 0.0 {
+            true => (((rt - lo) / width) as usize).min(bins - 1),
+            false => 0,
+        };
+        tic[bin] += intensity;
+    }
+    tic.into_iter()
+        .enumerate()
+        .map(|(bin, intensity)| (lo + (bin as f32 + 0.5) * width, intensity))
+        .collect()
+}
+
+/// Median of `values`, which are reordered
+pub fn median(values: &mut [f32]) -> Option<f32> {
+    let len = values.len();
+    if len == 0 {
+        return None;
+    }
+    let (below, &mut mid, _) = values.select_nth_unstable_by(len / 2, |a, b| a.total_cmp(b));
+    match len % 2 {
+        0 => {
+            let lower = below.iter().fold(f32::MIN, |max, &x| max.max(x));
+            Some((lower + mid) / 2.0)
+        }
+        _ => Some(mid),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_metrics() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), Some(2.0));
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), Some(2.5));
+
+        let spectrum = |level, intensity: Vec<f32>| RawSpectrum {
+            ms_level: level,
+            scan_start_time: 10.0,
+            ion_injection_time: 20.0,
+            intensity,
+            ..Default::default()
+        };
+        let filter = QualityFilter {
+            min_total_ion_current: 10.0,
+            min_dynamic_range: 5.0,
+        };
+        let mut run = RunMetricsBuilder::new(3);
+        assert!(run.add(&spectrum(1, vec![1.0]), &filter));
+        assert!(run.add(&spectrum(2, vec![100.0, 10.0, 2.0, 1.0, 1.0]), &filter));
+        // Empty, and noise-only scans
+        assert!(!run.add(&spectrum(2, vec![]), &filter));
+        assert!(!run.add(&spectrum(2, vec![4.0, 5.0, 6.0]), &filter));
+
+        let metrics = run.build();
+        assert_eq!(
+            metrics,
+            RunMetrics {
+                file_id: 3,
+                ms1_spectra: 1,
+                ms2_spectra: 3,
+                filtered_spectra: 2,
+                median_total_ion_current: 15.0,
+                median_base_peak_intensity: 6.0,
+                median_ion_injection_time: 20.0,
+                tic: vec![(10.0, 1.0)],
+            }
+        );
+
+        let tic = chromatogram(&[(1.0, 1.0), (2.0, 2.0), (1.5, 3.0), (3.0, 4.0)]);
+        assert_eq!(tic.len(), 4);
+        assert_eq!(tic[0], (1.25, 1.0));
+        assert_eq!(tic[1], (1.75, 3.0));
+        assert_eq!(tic[2], (2.25, 2.0));
+        assert_eq!(tic[3], (2.75, 4.0));
+    }
+}
@@ -0,0 +1,210 @@
+//! Mass recalibration from confident PSMs of a first-pass search.
+//!
+//! Systematic precursor and fragment mass errors are estimated separately for
+//! each file, as the median ppm error of rank-1 target PSMs passing a
+//! spectrum-level q-value threshold. Spectra are then corrected for the
+//! systematic error, and searched again with tolerances tightened to a
+//! multiple of the (robust) standard deviation of the remaining errors.
+
+use crate::mass::Tolerance;
+use crate::scoring::Feature;
+use crate::spectrum::ProcessedSpectrum;
+use serde::{Deserialize, Serialize};
+
+/// Tightened tolerances are never narrower than +/- this (ppm)
+const MIN_TOLERANCE_PPM: f32 = 2.0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RecalibrationSettings {
+    /// Maximum spectrum-level q-value of first-pass PSMs used to fit mass
+    /// error models
+    pub q_value: f32,
+    /// Minimum number of confident PSMs required to recalibrate a file
+    pub min_psms: usize,
+    /// Width of the tightened tolerances, in robust standard deviations of
+    /// the recalibrated mass errors
+    pub tolerance_sd: f32,
+}
+
+impl Default for RecalibrationSettings {
+    fn default() -> Self {
+        Self {
+            q_value: 0.01,
+            min_psms: 100,
+            tolerance_sd: 4.0,
+        }
+    }
+}
+
+/// Systematic mass error of a single file
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct MassErrorModel {
+    /// Median mass error (ppm)
+    pub offset: f32,
+    /// Robust standard deviation of the mass errors (ppm), estimated from the
+    /// median absolute deviation
+    pub sd: f32,
+    /// Number of mass errors the model was fit to
+    pub n: usize,
+}
+
+impl MassErrorModel {
+    /// Fit a model to mass errors (ppm), if there are at least `min` of them
+    pub fn fit(mut errors: Vec<f32>, min: usize) -> Option<Self> {
+        if errors.is_empty() || errors.len() < min {
+            return None;
+        }
+        let offset = median(&mut errors);
+        let mut deviations = errors
+            .iter()
+            .map(|error| (error - offset).abs())
+            .collect::<Vec<_>>();
+        let sd = 1.4826 * median(&mut deviations);
+        Some(Self {
+            offset,
+            sd,
+            n: errors.len(),
+        })
+    }
+
+    /// Remove the systematic error from an observed mass
+    pub fn correct(&self, mass: f32) -> f32 {
+        mass / (1.0 + self.offset * 1E-6)
+    }
+
+    /// Symmetric tolerance of `width` standard deviations, but never wider
+    /// than `tol`. Tolerances in Da are returned unchanged
+    pub fn tolerance(&self, width: f32, tol: Tolerance) -> Tolerance {
+        match tol {
+            Tolerance::Ppm(lo, hi) => {
+                let ppm = (width * self.sd).max(MIN_TOLERANCE_PPM);
+                Tolerance::Ppm(lo.max(-ppm), hi.min(ppm))
+            }
+            Tolerance::Da(_, _) => tol,
+        }
+    }
+}
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Mass recalibration of the spectra of a single file
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Recalibration {
+    /// Precursor (and MS1) mass errors
+    pub precursor: Option<MassErrorModel>,
+    /// Fragment (MSn) mass errors
+    pub fragment: Option<MassErrorModel>,
+}
+
+impl Recalibration {
+    /// Fit mass error models for each of `files` files, from the rank-1 target
+    /// PSMs at or below `settings.q_value` (q-values must have been assigned).
+    ///
+    /// Precursor errors are only fit if `precursor` is set - they are not
+    /// meaningful in open searches. Fragment errors are fit from annotated
+    /// fragment ions ([`Feature::fragments`])
+    pub fn fit(
+        features: &[Feature],
+        files: usize,
+        settings: &RecalibrationSettings,
+        precursor: bool,
+    ) -> Vec<Recalibration> {
+        let mut precursor_errors = vec![Vec::new(); files];
+        let mut fragment_errors = vec![Vec::new(); files];
+        let mut psms = vec![0; files];
+        for feat in features
+            .iter()
+            .filter(|feat| feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= settings.q_value)
+        {
+            psms[feat.file_id] += 1;
+            if precursor {
+                precursor_errors[feat.file_id].push(feat.mass_offset * 1E6 / feat.calcmass);
+            }
+            if let Some(fragments) = &feat.fragments {
+                fragment_errors[feat.file_id].extend(fragments.ppm_errors());
+            }
+        }
+
+        psms.into_iter()
+            .zip(precursor_errors)
+            .zip(fragment_errors)
+            .map(
+                |((psms, precursor), fragment)| match psms >= settings.min_psms {
+                    true => Recalibration {
+                        precursor: MassErrorModel::fit(precursor, settings.min_psms),
+                        fragment: MassErrorModel::fit(fragment, settings.min_psms),
+                    },
+                    false => Recalibration::default(),
+                },
+            )
+            .collect()
+    }
+
+    /// Correct the precursor m/z and peaks of `spectrum`. MS1 peaks are
+    /// corrected with the precursor model, MSn peaks with the fragment model
+    pub fn apply(&self, spectrum: &mut ProcessedSpectrum) {
+        if let Some(model) = &self.precursor {
+            for precursor in &mut spectrum.precursors {
+                precursor.mz = model.correct(precursor.mz);
+            }
+        }
+        let peaks = match spectrum.level {
+            1 => &self.precursor,
+            _ => &self.fragment,
+        };
+        if let Some(model) = peaks {
+            for peak in &mut spectrum.peaks {
+                peak.mass = model.correct(peak.mass);
+            }
+        }
+    }
+
+    /// Whether any mass error model could be fit
+    pub fn is_fit(&self) -> bool {
+        self.precursor.is_some() || self.fragment.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mass_error_model() {
+        let errors = vec![4.0, 5.0, 6.0, 5.5, 4.5, 5.0, 100.0];
+        let model = MassErrorModel::fit(errors.clone(), 5).unwrap();
+        assert!((model.offset - 5.0).abs() < 1E-6);
+        assert!((model.sd - 1.4826 * 0.5).abs() < 1E-5);
+        assert_eq!(model.n, 7);
+        assert!(MassErrorModel::fit(errors, 10).is_none());
+
+        // A mass measured 5 ppm too high is corrected back
+        let mass = 1000.0 * (1.0 + 5E-6);
+        assert!((model.correct(mass) - 1000.0).abs() < 1E-4);
+
+        match model.tolerance(4.0, Tolerance::Ppm(-20.0, 20.0)) {
+            Tolerance::Ppm(lo, hi) => {
+                assert!((lo + 2.9652).abs() < 1E-4);
+                assert!((hi - 2.9652).abs() < 1E-4);
+            }
+            tol => panic!("unexpected tolerance {:?}", tol),
+        }
+        assert_eq!(
+            model.tolerance(1.0, Tolerance::Ppm(-20.0, 1.0)),
+            Tolerance::Ppm(-2.0, 1.0)
+        );
+        assert_eq!(
+            model.tolerance(4.0, Tolerance::Da(-0.5, 0.5)),
+            Tolerance::Da(-0.5, 0.5)
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Protein-level summarization of peptide (or PSM) quantities
+//!
+//! Each protein is summarized from a matrix of quantities, with one row per
+//! peptide (or PSM), and one column per sample - LC-MS runs for label-free
+//! quantification, or reporter ion channels for isobaric labeling. Quantities
+//! of 0 are treated as missing values.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ProteinRollup {
+    /// Sum of all peptide quantities
+    #[default]
+    Sum,
+    /// Mean of the 3 most intense peptides of each sample
+    Top3,
+    /// Tukey's median polish of log2-transformed quantities, robust to
+    /// outlying peptides and missing values
+    MedianPolish,
+}
+
+/// Maximum number of median polish iterations
+const MEDIAN_POLISH_ITERATIONS: usize = 10;
+
+impl ProteinRollup {
+    /// Summarize the quantities of a protein (`rows`: peptides, columns:
+    /// samples) to a single quantity per sample. Samples without any
+    /// quantified peptide are 0
+    pub fn summarize(&self, rows: &[Vec<f64>]) -> Vec<f64> {
+        let samples = rows.iter().map(Vec::len).max().unwrap_or_default();
+        let column = |sample: usize| {
+            rows.iter()
+                .filter_map(move |row| row.get(sample).copied())
+                .filter(|x| *x > 0.0)
+        };
+        match self {
+            ProteinRollup::Sum => (0..samples).map(|s| column(s).sum()).collect(),
+            ProteinRollup::Top3 => (0..samples)
+                .map(|s| {
+                    let mut xs = column(s).collect::<Vec<_>>();
+                    xs.sort_by(|a, b| b.total_cmp(a));
+                    xs.truncate(3);
+                    match xs.is_empty() {
+                        true => 0.0,
+                        false => xs.iter().sum::<f64>() / xs.len() as f64,
+                    }
+                })
+                .collect(),
+            ProteinRollup::MedianPolish => median_polish(rows, samples),
+        }
+    }
+}
+
+fn median(mut xs: Vec<f64>) -> Option<f64> {
+    xs.sort_by(|a, b| a.total_cmp(b));
+    match xs.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((xs[n / 2 - 1] + xs[n / 2]) / 2.0),
+        n => Some(xs[n / 2]),
+    }
+}
+
+/// Tukey's median polish on log2 quantities: the protein quantity of each
+/// sample is `2^(overall + column effect)`, as in MSstats
+fn median_polish(rows: &[Vec<f64>], samples: usize) -> Vec<f64> {
+    let mut residuals = rows
+        .iter()
+        .map(|row| {
+            (0..samples)
+                .map(|s| row.get(s).filter(|x| **x > 0.0).map(|x| x.log2()))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let mut overall = 0.0;
+    let mut columns = vec![0.0; samples];
+
+    for _ in 0..MEDIAN_POLISH_ITERATIONS {
+        let mut change = 0.0;
+        // Sweep row medians
+        for row in residuals.iter_mut() {
+            if let Some(m) = median(row.iter().flatten().copied().collect()) {
+                row.iter_mut().flatten().for_each(|x| *x -= m);
+                change += m.abs();
+            }
+        }
+        if let Some(m) = median(columns.clone()) {
+            columns.iter_mut().for_each(|c| *c -= m);
+            overall += m;
+        }
+        // Sweep column medians
+        for (s, effect) in columns.iter_mut().enumerate() {
+            if let Some(m) = median(residuals.iter().filter_map(|row| row[s]).collect()) {
+                residuals
+                    .iter_mut()
+                    .filter_map(|row| row[s].as_mut())
+                    .for_each(|x| *x -= m);
+                *effect += m;
+                change += m.abs();
+            }
+        }
+        if change < 1E-6 {
+            break;
+        }
+    }
+
+    (0..samples)
+        .map(|s| match residuals.iter().any(|row| row[s].is_some()) {
+            true => (overall + columns[s]).exp2(),
+            false => 0.0,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protein_rollup() {
+        let rows = vec![
+            vec![100.0, 200.0, 0.0],
+            vec![10.0, 20.0, 40.0],
+            vec![1.0, 2.0, 4.0],
+            vec![1000.0, 0.0, 0.0],
+        ];
+        assert_eq!(
+            ProteinRollup::Sum.summarize(&rows),
+            vec![1111.0, 222.0, 44.0]
+        );
+        assert_eq!(
+            ProteinRollup::Top3.summarize(&rows),
+            vec![370.0, 74.0, 22.0]
+        );
+
+        // Peptides differ by a constant factor: median polish recovers the
+        // 2-fold changes between samples
+        let rows = vec![
+            vec![8.0, 16.0, 32.0],
+            vec![2.0, 4.0, 0.0],
+            vec![1.0, 2.0, 4.0],
+        ];
+        let polished = ProteinRollup::MedianPolish.summarize(&rows);
+        assert!(
+            (polished[1] / polished[0] - 2.0).abs() < 1E-6,
+            "{:?}",
+            polished
+        );
+        assert!(
+            (polished[2] / polished[1] - 2.0).abs() < 1E-6,
+            "{:?}",
+            polished
+        );
+        assert_eq!(
+            ProteinRollup::MedianPolish.summarize(&[vec![0.0, 1.0]])[0],
+            0.0
+        );
+    }
+}
@@ -1,11 +1,17 @@
-use crate::database::{IndexedDatabase, PeptideIx};
+use crate::database::{IndexedDatabase, IndexedQuery, PeptideIx};
 use crate::heap::bounded_min_heapify;
-use crate::ion_series::{IonSeries, Kind};
+use crate::ion_series::{eligible_in_fragment, IonSeries, Kind, NeutralLoss};
 use crate::mass::{Tolerance, NEUTRON, PROTON};
+use crate::ml::mobility_model::MobilityFilter;
+use crate::peptide::Peptide;
+use crate::prefilter::SpectralIndex;
+use crate::silac::Channel;
 use crate::spectrum::{Precursor, ProcessedSpectrum};
-use serde::Serialize;
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
 use std::ops::AddAssign;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 /// Structure to hold temporary scores
 #[derive(Copy, Clone, Default, Debug)]
@@ -17,6 +23,7 @@ struct Score {
     summed_y: f32,
     longest_b: usize,
     longest_y: usize,
+    fragment_coverage: f32,
     hyperscore: f64,
     ppm_difference: f32,
     precursor_charge: u8,
@@ -50,16 +57,72 @@ impl AddAssign<InitialHits> for InitialHits {
     }
 }
 
-#[derive(Serialize, Clone, Debug)]
+/// How fragment matches are counted during preliminary scoring
+pub(crate) enum Matching {
+    /// Search the fragment index
+    Cpu,
+    /// Only record the fragment queries of each precursor window, so that
+    /// they can be counted on the GPU, see [`crate::gpu`]
+    #[cfg(feature = "gpu")]
+    Record(Vec<crate::gpu::Window>),
+    /// Replay the matches counted for the recorded windows, in order
+    #[cfg(feature = "gpu")]
+    Replay(std::vec::IntoIter<crate::gpu::Counts>),
+}
+
+/// Spectrum identifier of a PSM. The PSMs of a spectrum share a single copy
+/// of the identifier, rather than each holding its own string
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(transparent)]
+pub struct SpecId(Arc<str>);
+
+impl Default for SpecId {
+    fn default() -> Self {
+        Self(Arc::from(""))
+    }
+}
+
+impl std::ops::Deref for SpecId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::borrow::Borrow<str> for SpecId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for SpecId {
+    fn from(id: &str) -> Self {
+        Self(Arc::from(id))
+    }
+}
+
+impl From<String> for SpecId {
+    fn from(id: String) -> Self {
+        Self(Arc::from(id))
+    }
+}
+
+impl std::fmt::Display for SpecId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 /// Features of a candidate peptide spectrum match
 pub struct Feature {
-    #[serde(skip_serializing)]
     pub peptide_idx: PeptideIx,
     // psm_id help to match with matched fragments table.
     pub psm_id: usize,
     pub peptide_len: usize,
     /// Spectrum id
-    pub spec_id: String,
+    pub spec_id: SpecId,
     /// File identifier
     pub file_id: usize,
     /// PSM rank
@@ -72,6 +135,8 @@ pub struct Feature {
     pub calcmass: f32,
     /// Reported precursor charge
     pub charge: u8,
+    /// Precursor isolation window (m/z bounds), if reported for the spectrum
+    pub isolation_window: Option<(f32, f32)>,
     /// Retention time
     pub rt: f32,
     /// Globally aligned retention time
@@ -80,8 +145,20 @@ pub struct Feature {
     pub predicted_rt: f32,
     /// Difference between predicted & observed RT
     pub delta_rt_model: f32,
+    /// Precursor inverse reduced ion mobility (1/K0), if reported, or else
+    /// that of the spectrum, see [`ProcessedSpectrum::ion_mobility`]
+    pub ion_mobility: Option<f32>,
+    /// FAIMS compensation voltage of the spectrum, if reported
+    pub compensation_voltage: Option<f32>,
+    /// Predicted ion mobility, if enabled
+    pub predicted_mobility: f32,
+    /// Difference between predicted & observed ion mobility
+    pub delta_mobility_model: f32,
     /// Difference between expmass and calcmass
     pub delta_mass: f32,
+    /// Difference between expmass and calcmass in Da, after correcting for
+    /// isotope errors. In open searches, this is the observed mass offset
+    pub mass_offset: f32,
     /// C13 isotope error
     pub isotope_error: f32,
     /// Average ppm delta mass for matched fragments
@@ -100,6 +177,9 @@ pub struct Feature {
     pub longest_y: u32,
     /// Longest y-ion series, divided by peptide length
     pub longest_y_pct: f32,
+    /// Fraction of peptide bonds explained by a matched fragment ion of
+    /// either series
+    pub fragment_coverage: f32,
     /// Number of missed cleavages
     pub missed_cleavages: u8,
     /// Fraction of matched MS2 intensity
@@ -118,14 +198,64 @@ pub struct Feature {
     pub protein_q: f32,
 
     pub ms2_intensity: f32,
+    /// Fraction of MS1 intensity within the isolation window that belongs to
+    /// this PSM's precursor, see [`isolation_purity`]
+    pub isolation_purity: Option<f32>,
+    /// Lowest localization probability of the variable modification sites,
+    /// see [`crate::localization`]
+    pub localization_probability: Option<f32>,
+    /// Localization probability of every eligible variable modification site
+    pub localization_sites: Option<String>,
+    /// SILAC channel matched by this PSM, see [`crate::silac::assign_channels`]
+    pub silac_channel: Option<Channel>,
+    /// Glycan composition of a glycopeptide PSM, see [`crate::glyco`]
+    pub glycan: Option<String>,
+    /// Search pass that reported this PSM: 1, or 2 for spectra re-searched
+    /// by a cascaded search
+    pub search_pass: u8,
+    /// Normalized spectral contrast angle to the library spectrum, for PSMs
+    /// of a spectral library search, see [`crate::library_search`]
+    pub spectral_angle: Option<f32>,
+    /// Retention time calibrated to the iRT scale, if enabled and the file
+    /// could be calibrated, see [`crate::irt`]
+    pub irt: Option<f32>,
+    /// Normalized spectral contrast angle to the predicted fragment
+    /// intensities, if enabled, see [`crate::fragment_prediction`]
+    pub predicted_spectral_angle: f32,
+    /// Pearson correlation with the predicted fragment intensities, if enabled
+    pub predicted_correlation: f32,
+    /// Features computed by the [`FeatureHook`] of the [`Scorer`], if any.
+    /// They are used by the rescoring model, after the built-in features
+    #[serde(default)]
+    pub custom_features: Vec<f64>,
+
+    /// Matched fragments, if `annotate_matches` is set. Boxed, as most
+    /// PSMs are not annotated
+    pub fragments: Option<Box<Fragments>>,
+}
 
-    pub fragments: Option<Fragments>,
+/// Computes custom features of candidate PSMs, e.g. using an in-house
+/// retention time predictor, without changes to Sage. Set it as
+/// [`Scorer::feature_hook`]: it is called for every PSM returned by
+/// [`Scorer::score`], and the features are stored in
+/// [`Feature::custom_features`]. They are then used by the rescoring model
+/// ([`crate::ml::linear_discriminant::score_psms`]) in addition to the
+/// built-in features.
+///
+/// Non-finite values are treated as 0 by the rescoring model.
+pub trait FeatureHook: Send + Sync {
+    /// Names of the features, in the order returned by
+    /// [`FeatureHook::features`], e.g. for output columns
+    fn names(&self) -> Vec<String>;
+
+    /// Features of `psm`, a candidate match of `peptide` to `spectrum`. All
+    /// matching statistics of `psm` are set, but it is not rescored yet
+    fn features(&self, spectrum: &ProcessedSpectrum, peptide: &Peptide, psm: &Feature) -> Vec<f64>;
 }
 
 /// Matching Fragment details
-#[derive(Serialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Fragments {
-    #[serde(skip_serializing)]
     pub charges: Vec<i32>,
     pub kinds: Vec<Kind>,
     pub fragment_ordinals: Vec<i32>,
@@ -134,14 +264,26 @@ pub struct Fragments {
     pub mz_experimental: Vec<f32>,
 }
 
+impl Fragments {
+    /// Mass error (ppm) of each matched fragment ion: experimental relative
+    /// to calculated m/z
+    pub fn ppm_errors(&self) -> impl Iterator<Item = f32> + '_ {
+        self.mz_calculated
+            .iter()
+            .zip(&self.mz_experimental)
+            .map(|(calc, exp)| (exp - calc) * 1E6 / calc)
+    }
+}
+
 static PSM_COUNTER: AtomicUsize = AtomicUsize::new(1);
 
-fn increment_psm_counter() -> usize {
+/// Next unique PSM identifier, see [`Feature::psm_id`]
+pub fn increment_psm_counter() -> usize {
     PSM_COUNTER.fetch_add(1, Ordering::Relaxed)
 }
 
 /// Stirling's approximation for log factorial
-fn lnfact(n: u16) -> f64 {
+pub(crate) fn lnfact(n: u16) -> f64 {
     if n == 0 {
         1.0
     } else {
@@ -164,16 +306,123 @@ impl Score {
     }
 }
 
+/// Controls how precursor isotope errors (`min_isotope_err..=max_isotope_err`)
+/// are searched
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum IsotopeErrorMode {
+    /// Perform a separate precursor search for each isotope error, using
+    /// the configured precursor tolerance
+    #[default]
+    Discrete,
+    /// Perform a single precursor search with a window widened to cover
+    /// every isotope error - the isotope error of each candidate is then
+    /// inferred from its mass difference
+    Widen,
+}
+
+/// Effective precursor window used by a [`Scorer`]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct PrecursorWindow {
+    pub tolerance: Tolerance,
+    pub isotope_errors: (i8, i8),
+    pub isotope_error_mode: IsotopeErrorMode,
+    /// Lowest and highest mass offset (Da) applied to the precursor tolerance
+    /// by isotope errors. Negative isotope errors extend the upper bound.
+    pub isotope_offset: (f32, f32),
+}
+
+impl PrecursorWindow {
+    pub fn new(tolerance: Tolerance, isotope_errors: (i8, i8), mode: IsotopeErrorMode) -> Self {
+        Self {
+            tolerance,
+            isotope_errors,
+            isotope_error_mode: mode,
+            isotope_offset: isotope_offset(isotope_errors.0, isotope_errors.1),
+        }
+    }
+}
+
+impl std::fmt::Display for PrecursorWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (lo, hi) = self.isotope_errors;
+        let unit = match self.tolerance {
+            Tolerance::Ppm(_, _) => "ppm",
+            Tolerance::Da(_, _) => "Da",
+        };
+        let (tol_lo, tol_hi) = match self.tolerance {
+            Tolerance::Ppm(lo, hi) | Tolerance::Da(lo, hi) => (lo, hi),
+        };
+        if lo == hi && lo == 0 {
+            return write!(f, "[{}, {}] {}", tol_lo, tol_hi, unit);
+        }
+        match self.isotope_error_mode {
+            IsotopeErrorMode::Discrete => write!(
+                f,
+                "[{}, {}] {}, searched separately at isotope errors {}..={}",
+                tol_lo, tol_hi, unit, lo, hi
+            ),
+            IsotopeErrorMode::Widen => write!(
+                f,
+                "[{}, {}] {}, widened by [{:.4}, {:.4}] Da to cover isotope errors {}..={}",
+                tol_lo, tol_hi, unit, self.isotope_offset.0, self.isotope_offset.1, lo, hi
+            ),
+        }
+    }
+}
+
+/// Mass offsets (lower, upper) spanned by isotope errors `min..=max`.
+///
+/// Candidates are searched at `precursor_mass - isotope_error * NEUTRON`, so
+/// positive isotope errors shift the window down, and negative isotope errors
+/// shift it up. An isotope error of 0 is only included if it is in the range.
+fn isotope_offset(min_isotope_err: i8, max_isotope_err: i8) -> (f32, f32) {
+    (
+        -(max_isotope_err as f32) * NEUTRON,
+        -(min_isotope_err as f32) * NEUTRON,
+    )
+}
+
+/// Infer the isotope error of a candidate from the precursor mass difference,
+/// clamped to the configured isotope error range
+fn infer_isotope_error(
+    precursor_mass: f32,
+    calculated_mass: f32,
+    min_isotope_err: i8,
+    max_isotope_err: i8,
+) -> i8 {
+    ((precursor_mass - calculated_mass) / NEUTRON)
+        .round()
+        .clamp(min_isotope_err as f32, max_isotope_err as f32) as i8
+}
+
+/// Scores spectra against an [`IndexedDatabase`]
+///
+/// Use [`Scorer::new`] for default search settings, and override individual
+/// fields with struct update syntax:
+///
+/// ```ignore
+/// let scorer = Scorer {
+///     report_psms: 5,
+///     ..Scorer::new(&db, precursor_tol, fragment_tol)
+/// };
+/// ```
 pub struct Scorer<'db> {
     pub db: &'db IndexedDatabase,
     pub precursor_tol: Tolerance,
     pub fragment_tol: Tolerance,
     /// What is the minimum number of matched b and y ion peaks to report PSMs for?
     pub min_matched_peaks: u16,
+    /// Minimum fraction of peptide bonds explained by matched fragment ions
+    /// to report PSMs for, see [`Feature::fragment_coverage`]
+    pub min_fragment_coverage: f32,
     /// Precursor isotope error lower bounds (e.g. -1)
     pub min_isotope_err: i8,
     /// Precursor isotope error upper bounds (e.g. 3)
     pub max_isotope_err: i8,
+    /// How isotope errors are searched - see [`IsotopeErrorMode`]
+    pub isotope_error_mode: IsotopeErrorMode,
     pub min_precursor_charge: u8,
     pub max_precursor_charge: u8,
     pub max_fragment_charge: Option<u8>,
@@ -186,6 +435,34 @@ pub struct Scorer<'db> {
     // the precursor tolerance window based on MS2 isolation window and charge
     pub wide_window: bool,
     pub annotate_matches: bool,
+
+    /// Open (mass-offset) search: the difference between the experimental
+    /// and calculated precursor mass is assumed to be an unknown modification
+    /// on a single residue, so fragment ions are also matched after shifting
+    /// them by this mass offset
+    pub open_search: bool,
+
+    /// Approximate nearest-neighbor prefilter: if set, only peptides
+    /// shortlisted by the [`SpectralIndex`] are scored, rather than all
+    /// peptides with fragments matching the query in the fragment index
+    pub prefilter: Option<&'db SpectralIndex>,
+
+    /// Ion mobility filter: if set, candidate peptides whose predicted ion
+    /// mobility is incompatible with [`ProcessedSpectrum::ion_mobility`] are
+    /// not scored
+    pub mobility_filter: Option<&'db MobilityFilter>,
+
+    /// Neutral losses from fragment ions: during full scoring, fragments
+    /// containing an eligible residue are also matched after subtracting each
+    /// loss, and matched peaks count towards the hyperscore
+    pub neutral_losses: &'db [NeutralLoss],
+
+    /// Localize residue-specific variable modifications of each reported
+    /// PSM, see [`crate::localization`]
+    pub localize: bool,
+
+    /// Custom features computed for each reported PSM, see [`FeatureHook`]
+    pub feature_hook: Option<&'db dyn FeatureHook>,
 }
 
 #[inline(always)]
@@ -193,7 +470,7 @@ pub struct Scorer<'db> {
 /// searching fragment ions (1..N)
 /// If user has configured max_fragment_charge, potentially override precursor
 /// charge
-fn max_fragment_charge(max_fragment_charge: Option<u8>, precursor_charge: u8) -> u8 {
+pub(crate) fn max_fragment_charge(max_fragment_charge: Option<u8>, precursor_charge: u8) -> u8 {
     precursor_charge
         .min(
             max_fragment_charge
@@ -204,14 +481,96 @@ fn max_fragment_charge(max_fragment_charge: Option<u8>, precursor_charge: u8) ->
 }
 
 impl<'db> Scorer<'db> {
+    /// Create a new [`Scorer`] with the same defaults as a search
+    /// configuration that only sets the tolerances: precursor charges 2 to 4,
+    /// no isotope errors, at least 4 matched peaks, one reported PSM per
+    /// spectrum, and fragments of 150 to 2000 m/z (the defaults of
+    /// [`crate::database::Builder`])
+    pub fn new(
+        db: &'db IndexedDatabase,
+        precursor_tol: Tolerance,
+        fragment_tol: Tolerance,
+    ) -> Self {
+        Scorer {
+            db,
+            precursor_tol,
+            fragment_tol,
+            min_matched_peaks: 4,
+            min_fragment_coverage: 0.0,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            isotope_error_mode: IsotopeErrorMode::default(),
+            min_precursor_charge: 2,
+            max_precursor_charge: 4,
+            max_fragment_charge: None,
+            min_fragment_mass: 150.0,
+            max_fragment_mass: 2000.0,
+            chimera: false,
+            report_psms: 1,
+            wide_window: false,
+            annotate_matches: false,
+            open_search: false,
+            prefilter: None,
+            mobility_filter: None,
+            neutral_losses: &[],
+            localize: true,
+            feature_hook: None,
+        }
+    }
+
+    /// Report the effective precursor window used by this [`Scorer`]
+    pub fn precursor_window(&self) -> PrecursorWindow {
+        PrecursorWindow::new(
+            self.precursor_tol,
+            (self.min_isotope_err, self.max_isotope_err),
+            self.isotope_error_mode,
+        )
+    }
+
+    /// Score a processed MS2 spectrum, returning up to `report_psms`
+    /// candidate PSMs, best first. The spectrum's peaks must be sorted by
+    /// neutral mass, as done by [`crate::spectrum::SpectrumProcessor`]
+    ///
+    /// # Panics
+    /// If `query` is not an MS2 spectrum
     pub fn score(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+        self.score_with(query, &mut Matching::Cpu)
+    }
+
+    /// [`Scorer::score`], counting fragment matches as set by `matching`
+    pub(crate) fn score_with(
+        &self,
+        query: &ProcessedSpectrum,
+        matching: &mut Matching,
+    ) -> Vec<Feature> {
         assert_eq!(
             query.level, 2,
             "internal bug, trying to score a non-MS2 scan!"
         );
-        match self.chimera {
-            true => self.score_chimera_fast(query),
-            false => self.score_standard(query),
+        let mut features = match self.chimera {
+            true => self.score_chimera_with(query, matching),
+            false => self.score_standard_with(query, matching),
+        };
+        if let Some(hook) = self.feature_hook {
+            for feat in &mut features {
+                let custom = hook.features(query, &self.db[feat.peptide_idx], feat);
+                feat.custom_features = custom;
+            }
+        }
+        features
+    }
+
+    /// Record the fragment queries of the precursor windows of `query`,
+    /// without counting matches, see [`crate::gpu`]
+    #[cfg(feature = "gpu")]
+    pub(crate) fn record(&self, query: &ProcessedSpectrum) -> Vec<crate::gpu::Window> {
+        let mut matching = Matching::Record(Vec::new());
+        if let Some(precursor) = query.precursors.first() {
+            self.initial_hits(query, precursor, &mut matching);
+        }
+        match matching {
+            Matching::Record(windows) => windows,
+            _ => unreachable!("recording fragment queries"),
         }
     }
 
@@ -239,6 +598,7 @@ impl<'db> Scorer<'db> {
     /// Returned hits are guaranteed to be the top-K hits (see above comment)
     /// from among all potential candidates, but the returned vector is not
     /// in sorted order.
+    #[allow(clippy::too_many_arguments)]
     fn matched_peaks_with_isotope(
         &self,
         query: &ProcessedSpectrum,
@@ -246,7 +606,20 @@ impl<'db> Scorer<'db> {
         precursor_charge: u8,
         precursor_tol: Tolerance,
         isotope_error: i8,
+        shortlist: Option<&[(PeptideIx, u16)]>,
+        matching: &mut Matching,
     ) -> InitialHits {
+        if let Some(shortlist) = shortlist {
+            return self.matched_peaks_shortlist(
+                query,
+                shortlist,
+                precursor_mass - isotope_error as f32 * NEUTRON,
+                precursor_charge,
+                precursor_tol,
+                isotope_error,
+            );
+        }
+
         let candidates = self.db.query(
             precursor_mass - isotope_error as f32 * NEUTRON,
             precursor_tol,
@@ -254,6 +627,18 @@ impl<'db> Scorer<'db> {
         );
 
         let max_fragment_charge = max_fragment_charge(self.max_fragment_charge, precursor_charge);
+
+        if self.open_search {
+            return self.matched_peaks_sparse(
+                query,
+                &candidates,
+                precursor_charge,
+                max_fragment_charge,
+                isotope_error,
+                matching,
+            );
+        }
+
         // Allocate space for all potential candidates - many potential candidates
         let potential = candidates.pre_idx_hi - candidates.pre_idx_lo + 1;
         let mut hits = InitialHits {
@@ -262,23 +647,174 @@ impl<'db> Scorer<'db> {
             preliminary: vec![PreScore::default(); potential],
         };
 
+        // Only count matches in the inner loop, which dominates the runtime
+        // of wide searches - candidates are filled in afterwards
+        self.count_matches(
+            query,
+            &candidates,
+            max_fragment_charge,
+            matching,
+            |peptide, matched| {
+                let idx = peptide.0 as usize - candidates.pre_idx_lo;
+                hits.preliminary[idx].matched += matched;
+                hits.matched_peaks += matched as usize;
+            },
+        );
+        if hits.matched_peaks == 0 {
+            return hits;
+        }
+
+        for (idx, sc) in hits.preliminary.iter_mut().enumerate() {
+            if sc.matched > 0 {
+                let peptide = PeptideIx((candidates.pre_idx_lo + idx) as u32);
+                if !self.mobility_compatible(query, peptide, precursor_charge) {
+                    hits.matched_peaks -= sc.matched as usize;
+                    sc.matched = 0;
+                    continue;
+                }
+                hits.scored_candidates += 1;
+                sc.precursor_charge = precursor_charge;
+                sc.peptide = peptide;
+                sc.isotope_error = isotope_error;
+            }
+        }
+
+        self.trim_hits(&mut hits);
+        hits
+    }
+
+    /// Preliminary score for open searches. Wide precursor windows can contain
+    /// a large fraction of the database, so rather than allocating a score for
+    /// every peptide in the window, only peptides with a matched fragment are
+    /// tracked
+    fn matched_peaks_sparse(
+        &self,
+        query: &ProcessedSpectrum,
+        candidates: &IndexedQuery,
+        precursor_charge: u8,
+        max_fragment_charge: u8,
+        isotope_error: i8,
+        matching: &mut Matching,
+    ) -> InitialHits {
+        let mut matched: FnvHashMap<PeptideIx, u16> = FnvHashMap::default();
+        let mut hits = InitialHits::default();
+
+        self.count_matches(
+            query,
+            candidates,
+            max_fragment_charge,
+            matching,
+            |peptide, count| {
+                *matched.entry(peptide).or_default() += count;
+                hits.matched_peaks += count as usize;
+            },
+        );
+        if self.mobility_filter.is_some() {
+            matched.retain(|&peptide, count| {
+                let compatible = self.mobility_compatible(query, peptide, precursor_charge);
+                if !compatible {
+                    hits.matched_peaks -= *count as usize;
+                }
+                compatible
+            });
+        }
+        if hits.matched_peaks == 0 {
+            return hits;
+        }
+
+        hits.scored_candidates = matched.len();
+        hits.preliminary = matched
+            .into_iter()
+            .map(|(peptide, matched)| PreScore {
+                matched,
+                peptide,
+                precursor_charge,
+                isotope_error,
+            })
+            .collect();
+        // In peptide order, as in the dense case, so that ties are not broken
+        // by the iteration order of the map
+        hits.preliminary.sort_unstable_by_key(|score| score.peptide);
+
+        self.trim_hits(&mut hits);
+        hits
+    }
+
+    /// Call `f` with the peptide of each fragment of `candidates` matching a
+    /// peak of `query`, and the number of matches. On the CPU, this is called
+    /// once per matched fragment
+    #[inline]
+    fn count_matches<F: FnMut(PeptideIx, u16)>(
+        &self,
+        query: &ProcessedSpectrum,
+        candidates: &IndexedQuery,
+        max_fragment_charge: u8,
+        matching: &mut Matching,
+        mut f: F,
+    ) {
+        match matching {
+            Matching::Cpu => {}
+            #[cfg(feature = "gpu")]
+            Matching::Record(windows) => {
+                windows.push(crate::gpu::Window::new(
+                    query,
+                    candidates,
+                    max_fragment_charge,
+                ));
+                return;
+            }
+            #[cfg(feature = "gpu")]
+            Matching::Replay(counts) => {
+                // Windows too large to be counted on the GPU are searched here
+                let counts = counts
+                    .next()
+                    .expect("internal bug: window was not recorded");
+                if let crate::gpu::Counts::Gpu(matches) = counts {
+                    for (peptide, count) in matches {
+                        f(peptide, count);
+                    }
+                    return;
+                }
+            }
+        }
         for peak in query.peaks.iter() {
             for charge in 1..max_fragment_charge {
                 let mass = peak.mass * charge as f32;
-                for frag in candidates.page_search(mass) {
-                    let idx = frag.peptide_index.0 as usize - candidates.pre_idx_lo;
-                    let sc = &mut hits.preliminary[idx];
-                    if sc.matched == 0 {
-                        hits.scored_candidates += 1;
-                        sc.precursor_charge = precursor_charge;
-                        sc.peptide = frag.peptide_index;
-                        sc.isotope_error = isotope_error;
-                    }
-                    sc.matched += 1;
-                    hits.matched_peaks += 1;
-                }
+                candidates.for_each_match(mass, |frag| f(frag.peptide_index, 1));
+            }
+        }
+    }
+
+    /// Preliminary score for searches using the approximate nearest-neighbor
+    /// prefilter: shortlisted peptides within the precursor window are scored
+    /// by the number of MinHash values shared with the query spectrum
+    fn matched_peaks_shortlist(
+        &self,
+        query: &ProcessedSpectrum,
+        shortlist: &[(PeptideIx, u16)],
+        precursor_mass: f32,
+        precursor_charge: u8,
+        precursor_tol: Tolerance,
+        isotope_error: i8,
+    ) -> InitialHits {
+        let (lo, hi) = precursor_tol.bounds(precursor_mass);
+        let mut hits = InitialHits::default();
+        for &(peptide, matched) in shortlist {
+            let mass = self.db[peptide].monoisotopic;
+            if mass >= lo
+                && mass <= hi
+                && self.mobility_compatible(query, peptide, precursor_charge)
+            {
+                hits.matched_peaks += matched as usize;
+                hits.preliminary.push(PreScore {
+                    matched,
+                    peptide,
+                    precursor_charge,
+                    isotope_error,
+                });
             }
         }
+        hits.scored_candidates = hits.preliminary.len();
         if hits.matched_peaks == 0 {
             return hits;
         }
@@ -287,14 +823,98 @@ impl<'db> Scorer<'db> {
         hits
     }
 
+    /// Can `peptide`, at precursor charge `charge`, have been observed at the
+    /// ion mobility of `query`? Always true without a mobility filter
+    fn mobility_compatible(
+        &self,
+        query: &ProcessedSpectrum,
+        peptide: PeptideIx,
+        charge: u8,
+    ) -> bool {
+        self.mobility_filter.map_or(true, |filter| {
+            filter.accepts(&self.db[peptide], charge, query.ion_mobility)
+        })
+    }
+
+    /// Widen the precursor window explicitly, using Da so that both positive and
+    /// negative isotope errors are covered regardless of tolerance units
+    fn widened_tolerance(&self, precursor_mass: f32, precursor_tol: Tolerance) -> Tolerance {
+        let (lo, hi) = precursor_tol.bounds(precursor_mass);
+        let (offset_lo, offset_hi) = isotope_offset(self.min_isotope_err, self.max_isotope_err);
+        Tolerance::Da(
+            lo - precursor_mass + offset_lo,
+            hi - precursor_mass + offset_hi,
+        )
+    }
+
+    /// Does any peptide fall within the precursor window (including isotope errors)?
+    fn precursor_candidates(&self, precursor_mass: f32, precursor_tol: Tolerance) -> bool {
+        if self.min_isotope_err == self.max_isotope_err {
+            self.db.contains_precursor(precursor_mass, precursor_tol)
+        } else if self.isotope_error_mode == IsotopeErrorMode::Widen {
+            let widened = self.widened_tolerance(precursor_mass, precursor_tol);
+            self.db.contains_precursor(precursor_mass, widened)
+        } else {
+            (self.min_isotope_err..=self.max_isotope_err).any(|isotope| {
+                self.db
+                    .contains_precursor(precursor_mass - isotope as f32 * NEUTRON, precursor_tol)
+            })
+        }
+    }
+
+    /// Precursor-only prefilter: cheaply determine whether `query` has at
+    /// least one candidate peptide, using the same precursor windows (charge
+    /// states, isotope errors, wide window) as [`Scorer::score`], but without
+    /// touching the fragment index. Spectra without candidates can never
+    /// produce a PSM, and can be skipped entirely.
+    pub fn has_candidates(&self, query: &ProcessedSpectrum) -> bool {
+        let precursor = match query.precursors.first() {
+            Some(precursor) => precursor,
+            // Let `score` deal with (and report) missing precursors
+            None => return true,
+        };
+        let mz = precursor.mz - PROTON;
+
+        if self.wide_window {
+            (self.min_precursor_charge..=self.max_precursor_charge).any(|precursor_charge| {
+                let precursor_tol = precursor
+                    .isolation_window
+                    .unwrap_or(Tolerance::Da(-2.4, 2.4))
+                    * precursor_charge as f32;
+                self.precursor_candidates(mz * precursor_charge as f32, precursor_tol)
+            })
+        } else if let Some(charge) = precursor.charge {
+            self.precursor_candidates(mz * charge as f32, self.precursor_tol)
+        } else {
+            (self.min_precursor_charge..=self.max_precursor_charge).any(|precursor_charge| {
+                self.precursor_candidates(mz * precursor_charge as f32, self.precursor_tol)
+            })
+        }
+    }
+
     fn matched_peaks(
         &self,
         query: &ProcessedSpectrum,
         precursor_mass: f32,
         precursor_charge: u8,
         precursor_tol: Tolerance,
+        shortlist: Option<&[(PeptideIx, u16)]>,
+        matching: &mut Matching,
     ) -> InitialHits {
-        if self.min_isotope_err != self.max_isotope_err {
+        if self.min_isotope_err != self.max_isotope_err
+            && self.isotope_error_mode == IsotopeErrorMode::Widen
+        {
+            let widened = self.widened_tolerance(precursor_mass, precursor_tol);
+            self.matched_peaks_with_isotope(
+                query,
+                precursor_mass,
+                precursor_charge,
+                widened,
+                0,
+                shortlist,
+                matching,
+            )
+        } else if self.min_isotope_err != self.max_isotope_err {
             let mut hits = (self.min_isotope_err..=self.max_isotope_err).fold(
                 InitialHits::default(),
                 |mut hits, isotope| {
@@ -304,6 +924,8 @@ impl<'db> Scorer<'db> {
                         precursor_charge,
                         precursor_tol,
                         isotope,
+                        shortlist,
+                        matching,
                     );
                     hits
                 },
@@ -317,14 +939,25 @@ impl<'db> Scorer<'db> {
                 precursor_charge,
                 precursor_tol,
                 0,
+                shortlist,
+                matching,
             )
         }
     }
 
-    fn initial_hits(&self, query: &ProcessedSpectrum, precursor: &Precursor) -> InitialHits {
+    fn initial_hits(
+        &self,
+        query: &ProcessedSpectrum,
+        precursor: &Precursor,
+        matching: &mut Matching,
+    ) -> InitialHits {
         // Sage operates on masses without protons; [M] instead of [MH+]
         let mz = precursor.mz - PROTON;
 
+        // Shortlist candidates once per query, rather than per precursor window
+        let shortlist = self.prefilter.map(|prefilter| prefilter.shortlist(query));
+        let shortlist = shortlist.as_deref();
+
         // Search in wide-window/DIA mode
         if self.wide_window {
             let mut hits = (self.min_precursor_charge..=self.max_precursor_charge).fold(
@@ -335,8 +968,14 @@ impl<'db> Scorer<'db> {
                         .isolation_window
                         .unwrap_or(Tolerance::Da(-2.4, 2.4))
                         * precursor_charge as f32;
-                    hits +=
-                        self.matched_peaks(query, precursor_mass, precursor_charge, precursor_tol);
+                    hits += self.matched_peaks(
+                        query,
+                        precursor_mass,
+                        precursor_charge,
+                        precursor_tol,
+                        shortlist,
+                        matching,
+                    );
                     hits
                 },
             );
@@ -345,7 +984,14 @@ impl<'db> Scorer<'db> {
         } else if let Some(charge) = precursor.charge {
             // Charge state is already annotated for this precusor, only search once
             let precursor_mass = mz * charge as f32;
-            self.matched_peaks(query, precursor_mass, charge, self.precursor_tol)
+            self.matched_peaks(
+                query,
+                precursor_mass,
+                charge,
+                self.precursor_tol,
+                shortlist,
+                matching,
+            )
         } else {
             // Not all selected ion precursors have charge states annotated -
             // assume it could be z=2, z=3, z=4 and search all three
@@ -358,6 +1004,8 @@ impl<'db> Scorer<'db> {
                         precursor_mass,
                         precursor_charge,
                         self.precursor_tol,
+                        shortlist,
+                        matching,
                     );
                     hits
                 },
@@ -369,11 +1017,19 @@ impl<'db> Scorer<'db> {
 
     /// Score a single [`ProcessedSpectrum`] against the database
     pub fn score_standard(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+        self.score_standard_with(query, &mut Matching::Cpu)
+    }
+
+    fn score_standard_with(
+        &self,
+        query: &ProcessedSpectrum,
+        matching: &mut Matching,
+    ) -> Vec<Feature> {
         let precursor = query.precursors.get(0).unwrap_or_else(|| {
             panic!("missing MS1 precursor for {}", query.id);
         });
 
-        let hits = self.initial_hits(query, precursor);
+        let hits = self.initial_hits(query, precursor, matching);
         let mut features = Vec::with_capacity(self.report_psms);
         self.build_features(query, precursor, &hits, self.report_psms, &mut features);
         features
@@ -395,6 +1051,7 @@ impl<'db> Scorer<'db> {
             .filter(|score| score.peptide != PeptideIx::default())
             .map(|pre| self.score_candidate(query, pre))
             .filter(|s| (s.0.matched_b + s.0.matched_y) >= self.min_matched_peaks)
+            .filter(|s| s.0.fragment_coverage >= self.min_fragment_coverage)
             .collect::<Vec<_>>();
 
         // Hyperscore is our primary score function for PSMs
@@ -407,9 +1064,10 @@ impl<'db> Scorer<'db> {
         // Sage operates on masses without protons; [M] instead of [MH+]
         let mz = precursor.mz - PROTON;
 
+        let spec_id = SpecId::from(query.id.as_str());
         for idx in 0..report_psms.min(score_vector.len()) {
             let score = score_vector[idx].0;
-            let fragments: Option<Fragments> = score_vector[idx].1.take();
+            let fragments = score_vector[idx].1.take().map(Box::new);
             let psm_id = increment_psm_counter();
 
             let peptide = &self.db[score.peptide];
@@ -434,17 +1092,36 @@ impl<'db> Scorer<'db> {
                 poisson = 1E-325;
             }
 
-            let isotope_error = score.isotope_error as f32 * NEUTRON;
+            let isotope_error = match self.isotope_error_mode {
+                IsotopeErrorMode::Discrete => score.isotope_error,
+                IsotopeErrorMode::Widen => infer_isotope_error(
+                    precursor_mass,
+                    peptide.monoisotopic,
+                    self.min_isotope_err,
+                    self.max_isotope_err,
+                ),
+            };
+            let isotope_error = isotope_error as f32 * NEUTRON;
+            let mass_offset = precursor_mass - isotope_error - peptide.monoisotopic;
             let delta_mass = (precursor_mass - peptide.monoisotopic - isotope_error).abs() * 2E6
                 / (precursor_mass - isotope_error + peptide.monoisotopic);
 
             // let (num_proteins, proteins) = self.db.assign_proteins(peptide);
 
+            let isolation_window = precursor
+                .isolation_window
+                .map(|window| window.bounds(precursor.mz));
+
+            let localization = match self.localize {
+                true => crate::localization::localize(self, peptide, score.precursor_charge, query),
+                false => None,
+            };
+
             features.push(Feature {
                 // Identifiers
                 psm_id,
                 peptide_idx: score.peptide,
-                spec_id: query.id.clone(),
+                spec_id: spec_id.clone(),
                 file_id: query.file_id,
                 rank: idx as u32 + 1,
                 label: peptide.label(),
@@ -452,8 +1129,10 @@ impl<'db> Scorer<'db> {
                 calcmass: peptide.monoisotopic,
                 // Features
                 charge: score.precursor_charge,
+                isolation_window,
                 rt: query.scan_start_time,
                 delta_mass,
+                mass_offset,
                 isotope_error,
                 average_ppm: score.ppm_difference,
                 hyperscore: score.hyperscore,
@@ -466,6 +1145,7 @@ impl<'db> Scorer<'db> {
                 longest_b: score.longest_b as u32,
                 longest_y: score.longest_y as u32,
                 longest_y_pct: score.longest_y as f32 / (peptide.sequence.len() as f32),
+                fragment_coverage: score.fragment_coverage,
                 peptide_len: peptide.sequence.len(),
                 scored_candidates: hits.scored_candidates as u32,
                 missed_cleavages: peptide.missed_cleavages,
@@ -479,7 +1159,22 @@ impl<'db> Scorer<'db> {
                 predicted_rt: 0.0,
                 aligned_rt: query.scan_start_time,
                 delta_rt_model: 0.999,
+                ion_mobility: precursor.ion_mobility.or(query.ion_mobility),
+                compensation_voltage: query.compensation_voltage,
+                predicted_mobility: 0.0,
+                delta_mobility_model: 0.0,
                 ms2_intensity: score.summed_b + score.summed_y,
+                isolation_purity: None,
+                localization_probability: localization.as_ref().map(|l| l.probability),
+                localization_sites: localization.map(|l| l.sites),
+                silac_channel: None,
+                glycan: None,
+                search_pass: 1,
+                spectral_angle: None,
+                irt: None,
+                predicted_spectral_angle: 0.0,
+                predicted_correlation: 0.0,
+                custom_features: Vec::new(),
 
                 //Fragments
                 fragments,
@@ -490,11 +1185,9 @@ impl<'db> Scorer<'db> {
     /// Remove peaks matching a PSM from a query spectrum
     fn remove_matched_peaks(&self, query: &mut ProcessedSpectrum, psm: &Feature) {
         let peptide = &self.db[psm.peptide_idx];
-        let fragments = self
-            .db
-            .ion_kinds
-            .iter()
-            .flat_map(|kind| IonSeries::new(peptide, *kind));
+        let fragments = self.db.ion_kinds.iter().flat_map(|kind| {
+            IonSeries::with_residue_masses(peptide, *kind, self.db.residue_masses)
+        });
 
         let max_fragment_charge = max_fragment_charge(self.max_fragment_charge, psm.charge);
 
@@ -525,12 +1218,20 @@ impl<'db> Scorer<'db> {
     /// Return multiple PSMs for each spectra - first is the best match, second PSM is the best match
     /// after all theoretical peaks assigned to the best match are removed, etc
     pub fn score_chimera_fast(&self, query: &ProcessedSpectrum) -> Vec<Feature> {
+        self.score_chimera_with(query, &mut Matching::Cpu)
+    }
+
+    fn score_chimera_with(
+        &self,
+        query: &ProcessedSpectrum,
+        matching: &mut Matching,
+    ) -> Vec<Feature> {
         let precursor = query.precursors.get(0).unwrap_or_else(|| {
             panic!("missing MS1 precursor for {}", query.id);
         });
 
         let mut query = query.clone();
-        let hits = self.initial_hits(&query, precursor);
+        let mut hits = self.initial_hits(&query, precursor, matching);
 
         let mut candidates: Vec<Feature> = Vec::with_capacity(self.report_psms);
 
@@ -541,6 +1242,9 @@ impl<'db> Scorer<'db> {
                 if let Some(feat) = candidates.get_mut(prev) {
                     self.remove_matched_peaks(&mut query, feat);
                     feat.rank = prev as u32 + 1;
+                    // Each co-isolated peptide is only reported once
+                    hits.preliminary
+                        .retain(|score| score.peptide != feat.peptide_idx);
                 }
                 prev = candidates.len()
             } else {
@@ -566,46 +1270,103 @@ impl<'db> Scorer<'db> {
         let max_fragment_charge =
             max_fragment_charge(self.max_fragment_charge, score.precursor_charge);
 
+        // Unknown mass offset, located somewhere on the peptide - each fragment
+        // ion either contains it, or it doesn't
+        let mass_offset = match (self.open_search, query.precursors.first()) {
+            (true, Some(precursor)) => {
+                (precursor.mz - PROTON) * score.precursor_charge as f32
+                    - score.isotope_error as f32 * NEUTRON
+                    - peptide.monoisotopic
+            }
+            _ => 0.0,
+        };
+
         // Regenerate theoretical ions - initial database search might be
         // using only a subset of all possible ions (e.g. no b1/b2/y1/y2)
         // so we need to completely re-score this candidate
-        let fragments = self
-            .db
-            .ion_kinds
-            .iter()
-            .flat_map(|kind| IonSeries::new(peptide, *kind).enumerate());
+        let fragments = self.db.ion_kinds.iter().flat_map(|kind| {
+            IonSeries::with_residue_masses(peptide, *kind, self.db.residue_masses).enumerate()
+        });
 
         let mut b_run = Run::default();
         let mut y_run = Run::default();
+        // Peptide bonds explained by a matched fragment: the `idx`th ion of
+        // each series results from cleavage of the `idx`th bond
+        let mut covered = vec![false; peptide.sequence.len().saturating_sub(1)];
 
         let mut fragments_details = Fragments::default();
 
+        let eligible = self
+            .neutral_losses
+            .iter()
+            .map(|loss| loss.eligible_residues(peptide))
+            .collect::<Vec<_>>();
+
         for (idx, frag) in fragments {
             for charge in 1..max_fragment_charge {
                 // Experimental peaks are multipled by charge, therefore theoretical are divided
                 let mz = frag.monoisotopic_mass / charge as f32;
-                if let Some(peak) = crate::spectrum::select_most_intense_peak(
+                let shifted = (frag.monoisotopic_mass + mass_offset) / charge as f32;
+                let matched = crate::spectrum::select_most_intense_peak(
                     &query.peaks,
                     mz,
                     self.fragment_tol,
                     None,
-                ) {
+                )
+                .map(|peak| (peak, mz))
+                .or_else(|| match self.open_search {
+                    true => crate::spectrum::select_most_intense_peak(
+                        &query.peaks,
+                        shifted,
+                        self.fragment_tol,
+                        None,
+                    )
+                    .map(|peak| (peak, shifted)),
+                    false => None,
+                });
+
+                let losses = self
+                    .neutral_losses
+                    .iter()
+                    .zip(&eligible)
+                    .filter(|(_, eligible)| eligible_in_fragment(eligible, frag.kind, idx) > 0)
+                    .filter_map(|(loss, _)| {
+                        let mz = (frag.monoisotopic_mass - loss.mass) / charge as f32;
+                        crate::spectrum::select_most_intense_peak(
+                            &query.peaks,
+                            mz,
+                            self.fragment_tol,
+                            None,
+                        )
+                        .map(|peak| (peak, mz, true))
+                    });
+
+                let matched = matched.map(|(peak, mz)| (peak, mz, false));
+                for (peak, mz, neutral_loss) in matched.into_iter().chain(losses) {
                     score.ppm_difference +=
                         peak.intensity * (mz - peak.mass).abs() * 2E6 / (mz + peak.mass);
 
                     let exp_mz = peak.mass + PROTON;
                     let calc_mz = mz + PROTON;
 
+                    // Neutral loss peaks don't extend ion series
+                    if !neutral_loss {
+                        covered[idx] = true;
+                    }
                     match frag.kind {
                         Kind::A | Kind::B | Kind::C => {
                             score.matched_b += 1;
                             score.summed_b += peak.intensity;
-                            b_run.matched(idx);
+                            if !neutral_loss {
+                                b_run.matched(idx);
+                            }
                         }
                         Kind::X | Kind::Y | Kind::Z => {
                             score.matched_y += 1;
                             score.summed_y += peak.intensity;
-                            y_run.matched(idx);
+                            if !neutral_loss {
+                                y_run.matched(idx);
+                            }
                         }
                     }
 
@@ -630,6 +1391,8 @@ impl<'db> Scorer<'db> {
         score.hyperscore = score.hyperscore();
         score.longest_b = b_run.longest;
         score.longest_y = y_run.longest;
+        score.fragment_coverage =
+            covered.iter().filter(|&&bond| bond).count() as f32 / covered.len().max(1) as f32;
         score.ppm_difference /= score.summed_b + score.summed_y;
 
         if self.annotate_matches {
@@ -641,6 +1404,111 @@ impl<'db> Scorer<'db> {
     }
 }
 
+/// Merge PSMs for the same spectrum that were scored against different slices
+/// of a mass-partitioned database (see [`crate::database::Parameters::partition`]).
+///
+/// For each spectrum, PSMs are re-ranked by hyperscore and truncated to the
+/// top `report_psms`, and `delta_next`, `delta_best` and `scored_candidates`
+/// are recalculated across all slices.
+pub fn merge_partitioned_features(mut features: Vec<Feature>, report_psms: usize) -> Vec<Feature> {
+    features.sort_by(|a, b| {
+        a.file_id
+            .cmp(&b.file_id)
+            .then_with(|| a.spec_id.cmp(&b.spec_id))
+            .then_with(|| b.hyperscore.total_cmp(&a.hyperscore))
+    });
+
+    let mut merged = Vec::with_capacity(features.len());
+    let mut iter = features.into_iter().peekable();
+    while let Some(first) = iter.next() {
+        let mut group = vec![first];
+        while let Some(next) = iter
+            .next_if(|feat| feat.file_id == group[0].file_id && feat.spec_id == group[0].spec_id)
+        {
+            group.push(next);
+        }
+
+        // Each slice reports exactly one rank 1 PSM per spectrum, along with
+        // the number of candidates scored within that slice
+        let scored_candidates = group
+            .iter()
+            .filter(|feat| feat.rank == 1)
+            .map(|feat| feat.scored_candidates)
+            .sum();
+
+        let best = group[0].hyperscore;
+        let hyperscores = group.iter().map(|feat| feat.hyperscore).collect::<Vec<_>>();
+        for (idx, mut feat) in group.into_iter().take(report_psms).enumerate() {
+            let next = hyperscores.get(idx + 1).copied().unwrap_or_default();
+            feat.rank = idx as u32 + 1;
+            feat.delta_next = feat.hyperscore - next;
+            feat.delta_best = best - feat.hyperscore;
+            feat.scored_candidates = scored_candidates;
+            merged.push(feat);
+        }
+    }
+    merged
+}
+
+/// Number of isotope peaks (including the monoisotopic peak) attributed to a
+/// precursor when estimating isolation window purity
+const PURITY_ISOTOPES: usize = 4;
+
+/// Estimate the isolation window purity of each PSM: the fraction of MS1
+/// intensity within the precursor isolation window that belongs to the
+/// isotopic envelope of the PSM's (calculated) precursor, using the closest
+/// MS1 scan preceding the MS2 scan.
+///
+/// Co-isolated peptides of a chimeric spectrum each receive their own
+/// estimate. PSMs without an isolation window or a preceding MS1 scan are
+/// left unchanged.
+pub fn isolation_purity(features: &mut [Feature], ms1: &[ProcessedSpectrum], tolerance: Tolerance) {
+    // MS1 scans of each file, sorted by retention time
+    let mut scans: FnvHashMap<usize, Vec<&ProcessedSpectrum>> = FnvHashMap::default();
+    for spectrum in ms1 {
+        scans.entry(spectrum.file_id).or_default().push(spectrum);
+    }
+    for file in scans.values_mut() {
+        file.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+    }
+
+    for feat in features.iter_mut() {
+        let ((lo, hi), file) = match (feat.isolation_window, scans.get(&feat.file_id)) {
+            (Some(window), Some(file)) => (window, file),
+            _ => continue,
+        };
+        let idx = file.partition_point(|s| s.scan_start_time <= feat.rt);
+        let scan = match idx.checked_sub(1) {
+            Some(idx) => file[idx],
+            None => continue,
+        };
+
+        // MS1 peaks are stored as [M]/z, without a proton
+        let (lo, hi) = (lo - PROTON, hi - PROTON);
+        let total = scan
+            .peaks
+            .iter()
+            .filter(|peak| peak.mass >= lo && peak.mass <= hi)
+            .map(|peak| peak.intensity)
+            .sum::<f32>();
+
+        let charge = feat.charge.max(1) as f32;
+        let precursor = (0..PURITY_ISOTOPES)
+            .map(|isotope| (feat.calcmass + isotope as f32 * NEUTRON) / charge)
+            .filter(|mass| *mass >= lo && *mass <= hi)
+            .filter_map(|mass| {
+                crate::spectrum::select_most_intense_peak(&scan.peaks, mass, tolerance, None)
+            })
+            .map(|peak| peak.intensity)
+            .sum::<f32>();
+
+        feat.isolation_purity = match total > 0.0 {
+            true => Some((precursor / total).min(1.0)),
+            false => Some(0.0),
+        };
+    }
+}
+
 /// Maintain information about the longest continous ion ladder for a series
 #[derive(Default)]
 struct Run {
@@ -702,4 +1570,566 @@ mod tests {
         assert_eq!(max_fragment_charge(Some(2), 4), 3);
         assert_eq!(max_fragment_charge(Some(4), 1), 2);
     }
+
+    #[test]
+    fn isotope_offsets() {
+        assert_eq!(isotope_offset(0, 0), (0.0, 0.0));
+        assert_eq!(isotope_offset(0, 2), (-2.0 * NEUTRON, 0.0));
+        assert_eq!(isotope_offset(-1, 3), (-3.0 * NEUTRON, NEUTRON));
+        assert_eq!(isotope_offset(-2, -1), (NEUTRON, 2.0 * NEUTRON));
+        assert_eq!(isotope_offset(1, 3), (-3.0 * NEUTRON, -NEUTRON));
+    }
+
+    #[test]
+    fn infer_isotope_errors() {
+        let mass = 1500.0;
+        assert_eq!(infer_isotope_error(mass, mass, -1, 3), 0);
+        assert_eq!(infer_isotope_error(mass + NEUTRON, mass, -1, 3), 1);
+        assert_eq!(infer_isotope_error(mass - NEUTRON, mass, -1, 3), -1);
+        assert_eq!(infer_isotope_error(mass - 2.0 * NEUTRON, mass, -1, 3), -1);
+        assert_eq!(infer_isotope_error(mass + 5.0 * NEUTRON, mass, -1, 3), 3);
+    }
+
+    fn feature(spec_id: &str, hyperscore: f64, rank: u32, scored_candidates: u32) -> Feature {
+        Feature {
+            spec_id: spec_id.into(),
+            hyperscore,
+            rank,
+            scored_candidates,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_partitions() {
+        let features = vec![
+            feature("a", 10.0, 1, 5),
+            feature("a", 8.0, 2, 5),
+            feature("b", 12.0, 1, 3),
+            feature("a", 15.0, 1, 7),
+            feature("a", 9.0, 2, 7),
+        ];
+        let merged = merge_partitioned_features(features, 2);
+        assert_eq!(merged.len(), 3);
+
+        assert_eq!(&*merged[0].spec_id, "a");
+        assert_eq!(merged[0].hyperscore, 15.0);
+        assert_eq!(merged[0].rank, 1);
+        assert_eq!(merged[0].delta_next, 5.0);
+        assert_eq!(merged[0].delta_best, 0.0);
+        assert_eq!(merged[0].scored_candidates, 12);
+
+        assert_eq!(merged[1].hyperscore, 10.0);
+        assert_eq!(merged[1].rank, 2);
+        assert_eq!(merged[1].delta_next, 1.0);
+        assert_eq!(merged[1].delta_best, 5.0);
+
+        assert_eq!(&*merged[2].spec_id, "b");
+        assert_eq!(merged[2].rank, 1);
+        assert_eq!(merged[2].delta_next, 12.0);
+        assert_eq!(merged[2].scored_candidates, 3);
+    }
+
+    #[test]
+    fn spec_id_serialization() {
+        // Shared spectrum ids are serialized as plain strings, as before
+        let feat = feature("scan=1", 10.0, 1, 5);
+        let json = serde_json::to_value(&feat).unwrap();
+        assert_eq!(json["spec_id"], "scan=1");
+        let feat: Feature = serde_json::from_value(json).unwrap();
+        assert_eq!(&*feat.spec_id, "scan=1");
+        assert_eq!(&*Feature::default().spec_id, "");
+    }
+
+    #[test]
+    fn purity() {
+        use crate::spectrum::Peak;
+
+        // Precursor at 500 m/z (z=2), isolated in a window of 499-502 m/z
+        let calcmass = (500.0 - PROTON) * 2.0;
+        let peak = |mz: f32, intensity| Peak {
+            mass: mz - PROTON,
+            intensity,
+        };
+        let ms1 = ProcessedSpectrum {
+            level: 1,
+            id: "ms1".into(),
+            file_id: 0,
+            scan_start_time: 9.9,
+            ion_injection_time: 0.0,
+            precursors: Vec::new(),
+            peaks: vec![
+                peak(495.0, 1000.0),
+                peak(499.5, 200.0),
+                peak(500.0, 400.0),
+                peak(500.0 + NEUTRON / 2.0, 200.0),
+                peak(501.2, 200.0),
+            ],
+            total_ion_current: 0.0,
+            noise: Vec::new(),
+            ion_mobility: None,
+            compensation_voltage: None,
+        };
+
+        let mut features = vec![
+            Feature {
+                calcmass,
+                charge: 2,
+                rt: 10.0,
+                isolation_window: Some((499.0, 502.0)),
+                ..feature("a", 10.0, 1, 1)
+            },
+            // No preceding MS1 scan
+            Feature {
+                calcmass,
+                charge: 2,
+                rt: 9.0,
+                isolation_window: Some((499.0, 502.0)),
+                ..feature("b", 10.0, 1, 1)
+            },
+        ];
+        isolation_purity(&mut features, &[ms1], Tolerance::Ppm(-10.0, 10.0));
+        assert_eq!(features[0].isolation_purity, Some(0.6));
+        assert_eq!(features[1].isolation_purity, None);
+    }
+
+    #[test]
+    fn etd_ions() {
+        use crate::database::Builder;
+        use crate::fasta::Fasta;
+        use crate::spectrum::{Peak, Precursor};
+
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            ion_kinds: Some(vec![Kind::C, Kind::Z, Kind::C]),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        assert_eq!(db.ion_kinds, vec![Kind::C, Kind::Z]);
+
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap();
+        let mut peaks = [Kind::C, Kind::Z]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .filter(|ion| ion.monoisotopic_mass >= 150.0)
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let expected = peaks.len() as u32;
+
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "etd".into(),
+            file_id: 0,
+            scan_start_time: 0.0,
+            ion_injection_time: 0.0,
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            total_ion_current: 0.0,
+            noise: Vec::new(),
+            ion_mobility: None,
+            compensation_voltage: None,
+        };
+
+        let scorer = Scorer {
+            db: &db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 4,
+            min_fragment_coverage: 0.0,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            isotope_error_mode: IsotopeErrorMode::Discrete,
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            max_fragment_charge: Some(1),
+            min_fragment_mass: 0.0,
+            max_fragment_mass: 2000.0,
+            chimera: false,
+            report_psms: 1,
+            wide_window: false,
+            annotate_matches: false,
+            open_search: false,
+            prefilter: None,
+            mobility_filter: None,
+            neutral_losses: &[],
+            localize: false,
+            feature_hook: None,
+        };
+        let psm = scorer.score(&query);
+        assert_eq!(psm.len(), 1);
+        assert_eq!(db[psm[0].peptide_idx].to_string(), "LQSRPAAPPAPGPGQLTLR");
+        assert_eq!(psm[0].matched_peaks, expected);
+    }
+
+    #[test]
+    fn neutral_losses() {
+        use crate::database::Builder;
+        use crate::fasta::Fasta;
+        use crate::spectrum::{Peak, Precursor};
+
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            static_mods: Some([("S".to_string(), 79.9663)].into_iter().collect()),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let phospho = [NeutralLoss {
+            mass: 97.9769,
+            residues: "STY".into(),
+            modification: Some(79.9663),
+        }];
+
+        // Every fragment containing the phosphoserine is only observed after
+        // losing H3PO4
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string().starts_with("LQS"))
+            .unwrap();
+        let eligible = phospho[0].eligible_residues(peptide);
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind).enumerate())
+            .map(
+                |(idx, ion)| match eligible_in_fragment(&eligible, ion.kind, idx) {
+                    0 => ion.monoisotopic_mass,
+                    _ => ion.monoisotopic_mass - phospho[0].mass,
+                },
+            )
+            .filter(|&mass| mass >= 150.0)
+            .map(|mass| Peak {
+                mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let expected = peaks.len() as u32;
+
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "phospho".into(),
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = Scorer {
+            db: &db,
+            precursor_tol: Tolerance::Ppm(-10.0, 10.0),
+            fragment_tol: Tolerance::Ppm(-10.0, 10.0),
+            min_matched_peaks: 4,
+            min_fragment_coverage: 0.0,
+            min_isotope_err: 0,
+            max_isotope_err: 0,
+            isotope_error_mode: IsotopeErrorMode::Discrete,
+            min_precursor_charge: 2,
+            max_precursor_charge: 2,
+            max_fragment_charge: Some(1),
+            min_fragment_mass: 0.0,
+            max_fragment_mass: 2000.0,
+            chimera: false,
+            report_psms: 1,
+            wide_window: false,
+            annotate_matches: false,
+            open_search: false,
+            prefilter: None,
+            mobility_filter: None,
+            neutral_losses: &[],
+            localize: false,
+            feature_hook: None,
+        };
+        let without = scorer.score(&query);
+        let with = Scorer {
+            neutral_losses: &phospho,
+            ..scorer
+        }
+        .score(&query);
+        assert_eq!(with.len(), 1);
+        assert_eq!(with[0].peptide_idx, without[0].peptide_idx);
+        assert_eq!(with[0].matched_peaks, expected);
+        assert!(without[0].matched_peaks < expected);
+        assert!(with[0].hyperscore > without[0].hyperscore);
+    }
+
+    #[test]
+    fn isotope_error_correction() {
+        use crate::database::Builder;
+        use crate::fasta::Fasta;
+        use crate::spectrum::{Peak, Precursor};
+
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap();
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        // The first isotope of the precursor was selected
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "isotope".into(),
+            precursors: vec![Precursor {
+                mz: (peptide.monoisotopic + NEUTRON) / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        for mode in [IsotopeErrorMode::Discrete, IsotopeErrorMode::Widen] {
+            let psms = Scorer {
+                min_isotope_err: -1,
+                max_isotope_err: 3,
+                isotope_error_mode: mode,
+                ..Scorer::new(
+                    &db,
+                    Tolerance::Ppm(-10.0, 10.0),
+                    Tolerance::Ppm(-10.0, 10.0),
+                )
+            }
+            .score(&query);
+            assert_eq!(db[psms[0].peptide_idx].to_string(), "LQSRPAAPPAPGPGQLTLR");
+            // The precursor mass error is reported after correcting for the
+            // isotope offset, which is reported on its own
+            assert_eq!(psms[0].isotope_error, NEUTRON);
+            assert!(psms[0].delta_mass < 1.0, "{:?}", mode);
+            assert!(psms[0].mass_offset.abs() < 0.01);
+            assert!((psms[0].expmass - psms[0].calcmass - NEUTRON).abs() < 0.01);
+        }
+
+        // Isotope errors that exclude 0 are searched the same way in both
+        // modes: the monoisotopic precursor is not a candidate
+        let monoisotopic = ProcessedSpectrum {
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            ..query.clone()
+        };
+        let search = |mode, query: &ProcessedSpectrum| {
+            Scorer {
+                min_isotope_err: 1,
+                max_isotope_err: 3,
+                isotope_error_mode: mode,
+                ..Scorer::new(
+                    &db,
+                    Tolerance::Ppm(-10.0, 10.0),
+                    Tolerance::Ppm(-10.0, 10.0),
+                )
+            }
+            .score(query)
+            .into_iter()
+            .map(|psm| (db[psm.peptide_idx].to_string(), psm.isotope_error))
+            .collect::<Vec<_>>()
+        };
+        for query in [&query, &monoisotopic] {
+            assert_eq!(
+                search(IsotopeErrorMode::Discrete, query),
+                search(IsotopeErrorMode::Widen, query)
+            );
+        }
+        assert_eq!(
+            search(IsotopeErrorMode::Widen, &query)[0],
+            ("LQSRPAAPPAPGPGQLTLR".to_string(), NEUTRON)
+        );
+        assert!(search(IsotopeErrorMode::Widen, &monoisotopic)
+            .iter()
+            .all(|(peptide, _)| peptide != "LQSRPAAPPAPGPGQLTLR"));
+    }
+
+    #[test]
+    fn fragment_coverage() {
+        use crate::database::Builder;
+        use crate::fasta::Fasta;
+        use crate::spectrum::{Peak, Precursor};
+
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap();
+
+        // b and y ions resulting from cleavage of the first 9 of 18 bonds:
+        // twice as many matched ions as bonds, but only half of the bonds
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind).take(9))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let query = ProcessedSpectrum {
+            level: 2,
+            id: "coverage".into(),
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = Scorer::new(
+            &db,
+            Tolerance::Ppm(-10.0, 10.0),
+            Tolerance::Ppm(-10.0, 10.0),
+        );
+        let psms = scorer.score(&query);
+        assert_eq!(db[psms[0].peptide_idx].to_string(), "LQSRPAAPPAPGPGQLTLR");
+        assert_eq!(psms[0].matched_peaks, 18);
+        assert_eq!(psms[0].fragment_coverage, 0.5);
+
+        let gated = Scorer {
+            min_fragment_coverage: 0.6,
+            ..scorer
+        };
+        assert!(gated.score(&query).is_empty());
+    }
+
+    #[test]
+    fn feature_hook() {
+        use crate::database::Builder;
+        use crate::fasta::Fasta;
+        use crate::ml::linear_discriminant::{feature_matrix, FEATURE_NAMES};
+        use crate::spectrum::{Peak, Precursor};
+
+        /// Peptide length, and the number of peaks of the spectrum
+        struct Lengths;
+
+        impl FeatureHook for Lengths {
+            fn names(&self) -> Vec<String> {
+                vec!["length".into(), "peaks".into()]
+            }
+
+            fn features(
+                &self,
+                spectrum: &ProcessedSpectrum,
+                peptide: &Peptide,
+                _: &Feature,
+            ) -> Vec<f64> {
+                vec![peptide.sequence.len() as f64, spectrum.peaks.len() as f64]
+            }
+        }
+
+        let fasta = Fasta::parse(
+            ">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(),
+            "rev_",
+            false,
+        );
+        let db = Builder::default().make_parameters().build(fasta);
+        let peptide = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LQSRPAAPPAPGPGQLTLR")
+            .unwrap();
+        let mut peaks = [Kind::B, Kind::Y]
+            .into_iter()
+            .flat_map(|kind| IonSeries::new(peptide, kind))
+            .map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 1.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+        let query = ProcessedSpectrum {
+            level: 2,
+            precursors: vec![Precursor {
+                mz: peptide.monoisotopic / 2.0 + PROTON,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            peaks,
+            ..Default::default()
+        };
+
+        let scorer = Scorer::new(
+            &db,
+            Tolerance::Ppm(-10.0, 10.0),
+            Tolerance::Ppm(-10.0, 10.0),
+        );
+        let without = scorer.score(&query);
+        assert!(without[0].custom_features.is_empty());
+
+        let hook = Lengths;
+        let with = Scorer {
+            feature_hook: Some(&hook),
+            ..scorer
+        }
+        .score(&query);
+        assert_eq!(
+            with[0].custom_features,
+            vec![19.0, query.peaks.len() as f64]
+        );
+
+        let features = feature_matrix(&with, Tolerance::Ppm(-10.0, 10.0));
+        assert_eq!(features.cols, FEATURE_NAMES.len() + 2);
+        assert_eq!(features[(0, FEATURE_NAMES.len())], 19.0);
+        assert_eq!(
+            feature_matrix(&without, Tolerance::Ppm(-10.0, 10.0)).cols,
+            FEATURE_NAMES.len()
+        );
+    }
 }
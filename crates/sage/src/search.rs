@@ -0,0 +1,596 @@
+//! Typed configuration of a search, for programs embedding Sage
+//!
+//! [`SearchBuilder`] covers the settings of the JSON configuration file that
+//! determine how spectra are scored: the database, tolerances, precursor
+//! charges and isotope errors, and spectrum processing. Settings are
+//! validated by [`SearchBuilder::build`], and unset settings take the same
+//! defaults as in a configuration file.
+//!
+//! ```
+//! use sage_core::fasta::Fasta;
+//! use sage_core::mass::Tolerance;
+//! use sage_core::search::{Protease, SearchBuilder};
+//!
+//! let search = SearchBuilder::default()
+//!     .precursor_tol(Tolerance::Ppm(-10.0, 10.0))
+//!     .fragment_tol(Tolerance::Da(-0.02, 0.02))
+//!     .enzyme(Protease::Trypsin)
+//!     .missed_cleavages(2)
+//!     .static_mod("C", 57.0215)
+//!     .variable_mod("M", 15.9949)
+//!     .precursor_charge(2, 3)
+//!     .build()
+//!     .unwrap();
+//!
+//! let fasta = Fasta::parse(">sp|AAAA|AAAA\nLQSRPAAPPAPGPGQLTLRMEWKSGAVLR\n".into(), "rev_", true);
+//! let db = search.database(fasta);
+//! let scorer = search.scorer(&db);
+//! let processor = search.processor();
+//! # let _ = (scorer, processor);
+//!
+//! assert!(SearchBuilder::default().precursor_charge(3, 2).build().is_err());
+//! ```
+
+use crate::database::{Builder, EnzymeBuilder, IndexedDatabase, Parameters};
+use crate::fasta::Fasta;
+use crate::ion_series::Kind;
+use crate::mass::{Tolerance, VALID_AA};
+use crate::modification::{InvalidModification, ModificationSpecificity};
+use crate::scoring::{IsotopeErrorMode, Scorer};
+use crate::spectrum::{DeisotopeMethod, SpectrumProcessor};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Common proteases, see [`SearchBuilder::enzyme`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protease {
+    /// C-terminal to K and R, except before P
+    Trypsin,
+    /// C-terminal to K and R, also before P
+    TrypsinP,
+    /// C-terminal to K, except before P
+    LysC,
+    /// N-terminal to K
+    LysN,
+    /// C-terminal to R, except before P
+    ArgC,
+    /// N-terminal to D
+    AspN,
+    /// C-terminal to E, except before P
+    GluC,
+    /// C-terminal to F, W, Y and L, except before P
+    Chymotrypsin,
+    /// Every subsequence within the peptide length limits
+    NonSpecific,
+    /// Whole proteins, e.g. for top-down data or pre-digested databases
+    NoCleavage,
+}
+
+impl Protease {
+    /// Cleavage residues, restriction and whether cleavage is C-terminal, as
+    /// in the `database.enzyme` section of a configuration file
+    fn rule(self) -> (&'static str, Option<char>, bool) {
+        match self {
+            Protease::Trypsin => ("KR", Some('P'), true),
+            Protease::TrypsinP => ("KR", None, true),
+            Protease::LysC => ("K", Some('P'), true),
+            Protease::LysN => ("K", None, false),
+            Protease::ArgC => ("R", Some('P'), true),
+            Protease::AspN => ("D", None, false),
+            Protease::GluC => ("E", Some('P'), true),
+            Protease::Chymotrypsin => ("FWYL", Some('P'), true),
+            Protease::NonSpecific => ("", None, true),
+            Protease::NoCleavage => ("$", None, true),
+        }
+    }
+}
+
+/// Settings passed to [`SearchBuilder`] that cannot be searched, e.g. a
+/// minimum precursor charge above the maximum
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidSearch(pub String);
+
+impl std::fmt::Display for InvalidSearch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid search settings: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSearch {}
+
+/// Builds validated [`Search`] settings, see the [module documentation](self)
+#[derive(Clone, Debug, Default)]
+pub struct SearchBuilder {
+    enzyme: EnzymeBuilder,
+    peptide_mass: Option<(f32, f32)>,
+    fragment_mz: Option<(f32, f32)>,
+    ion_kinds: Option<Vec<Kind>>,
+    static_mods: HashMap<String, f32>,
+    variable_mods: HashMap<String, Vec<f32>>,
+    max_variable_mods: Option<usize>,
+    decoy_tag: Option<String>,
+    generate_decoys: Option<bool>,
+    equate_il: Option<bool>,
+    precursor_tol: Option<Tolerance>,
+    fragment_tol: Option<Tolerance>,
+    precursor_charge: Option<(u8, u8)>,
+    isotope_errors: Option<(i8, i8)>,
+    isotope_error_mode: Option<IsotopeErrorMode>,
+    min_matched_peaks: Option<u16>,
+    min_fragment_coverage: Option<f32>,
+    max_fragment_charge: Option<u8>,
+    chimera: Option<bool>,
+    wide_window: Option<bool>,
+    report_psms: Option<usize>,
+    deisotope: Option<bool>,
+    deisotope_method: Option<DeisotopeMethod>,
+    peaks: Option<(usize, usize)>,
+}
+
+impl SearchBuilder {
+    /// Precursor tolerance (`precursor_tol`), required
+    pub fn precursor_tol(mut self, tolerance: Tolerance) -> Self {
+        self.precursor_tol = Some(tolerance);
+        self
+    }
+
+    /// Fragment tolerance (`fragment_tol`), required
+    pub fn fragment_tol(mut self, tolerance: Tolerance) -> Self {
+        self.fragment_tol = Some(tolerance);
+        self
+    }
+
+    /// Digest proteins with `protease` (default: trypsin)
+    pub fn enzyme(mut self, protease: Protease) -> Self {
+        let (cleave_at, restrict, c_terminal) = protease.rule();
+        self.enzyme.cleave_at = Some(cleave_at.into());
+        self.enzyme.restrict = restrict;
+        self.enzyme.c_terminal = Some(c_terminal);
+        self
+    }
+
+    /// Digest proteins with custom cleavage rules, replacing all settings of
+    /// the `database.enzyme` section
+    pub fn custom_enzyme(mut self, enzyme: EnzymeBuilder) -> Self {
+        self.enzyme = enzyme;
+        self
+    }
+
+    pub fn missed_cleavages(mut self, missed_cleavages: u8) -> Self {
+        self.enzyme.missed_cleavages = Some(missed_cleavages);
+        self
+    }
+
+    /// Minimum and maximum peptide length (default: 5 to 50 residues)
+    pub fn peptide_length(mut self, min: usize, max: usize) -> Self {
+        self.enzyme.min_len = Some(min);
+        self.enzyme.max_len = Some(max);
+        self
+    }
+
+    pub fn semi_enzymatic(mut self, semi_enzymatic: bool) -> Self {
+        self.enzyme.semi_enzymatic = Some(semi_enzymatic);
+        self
+    }
+
+    /// Minimum and maximum peptide monoisotopic mass (default: 500 to 5000 Da)
+    pub fn peptide_mass(mut self, min: f32, max: f32) -> Self {
+        self.peptide_mass = Some((min, max));
+        self
+    }
+
+    /// Minimum and maximum fragment m/z that is indexed and matched
+    /// (default: 150 to 2000)
+    pub fn fragment_mz(mut self, min: f32, max: f32) -> Self {
+        self.fragment_mz = Some((min, max));
+        self
+    }
+
+    /// Fragment ion kinds to generate (default: b and y)
+    pub fn ion_kinds(mut self, kinds: &[Kind]) -> Self {
+        self.ion_kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Add a static modification, e.g. `("C", 57.0215)`. `target` uses the
+    /// syntax of `database.static_mods`, e.g. `^` for peptide N-termini
+    pub fn static_mod(mut self, target: &str, mass: f32) -> Self {
+        self.static_mods.insert(target.into(), mass);
+        self
+    }
+
+    /// Add a variable modification, e.g. `("M", 15.9949)`. Can be called
+    /// several times for the same `target`
+    pub fn variable_mod(mut self, target: &str, mass: f32) -> Self {
+        self.variable_mods
+            .entry(target.into())
+            .or_default()
+            .push(mass);
+        self
+    }
+
+    /// Maximum number of variable modifications per peptide (default: 2)
+    pub fn max_variable_mods(mut self, max_variable_mods: usize) -> Self {
+        self.max_variable_mods = Some(max_variable_mods);
+        self
+    }
+
+    /// Prefix of decoy protein accessions (default: `rev_`), and whether
+    /// decoys are generated by reversing peptides (default: true), or are
+    /// already part of the FASTA database
+    pub fn decoys(mut self, tag: &str, generate: bool) -> Self {
+        self.decoy_tag = Some(tag.into());
+        self.generate_decoys = Some(generate);
+        self
+    }
+
+    pub fn equate_il(mut self, equate_il: bool) -> Self {
+        self.equate_il = Some(equate_il);
+        self
+    }
+
+    /// Precursor charges searched if a spectrum does not report one
+    /// (default: 2 to 4)
+    pub fn precursor_charge(mut self, min: u8, max: u8) -> Self {
+        self.precursor_charge = Some((min, max));
+        self
+    }
+
+    /// Precursor isotope errors (default: 0 to 0)
+    pub fn isotope_errors(mut self, min: i8, max: i8) -> Self {
+        self.isotope_errors = Some((min, max));
+        self
+    }
+
+    pub fn isotope_error_mode(mut self, mode: IsotopeErrorMode) -> Self {
+        self.isotope_error_mode = Some(mode);
+        self
+    }
+
+    /// Minimum number of matched fragments of a PSM (default: 4)
+    pub fn min_matched_peaks(mut self, min_matched_peaks: u16) -> Self {
+        self.min_matched_peaks = Some(min_matched_peaks);
+        self
+    }
+
+    /// Minimum fraction of peptide bonds explained by matched fragments of a
+    /// PSM, between 0 and 1 (default: 0)
+    pub fn min_fragment_coverage(mut self, min_fragment_coverage: f32) -> Self {
+        self.min_fragment_coverage = Some(min_fragment_coverage);
+        self
+    }
+
+    /// Maximum fragment charge (default: precursor charge - 1)
+    pub fn max_fragment_charge(mut self, max_fragment_charge: u8) -> Self {
+        self.max_fragment_charge = Some(max_fragment_charge);
+        self
+    }
+
+    /// Search for co-fragmenting peptides (default: only in wide-window mode)
+    pub fn chimera(mut self, chimera: bool) -> Self {
+        self.chimera = Some(chimera);
+        self
+    }
+
+    /// Search the isolation window of DIA spectra, instead of the precursor
+    /// tolerance
+    pub fn wide_window(mut self, wide_window: bool) -> Self {
+        self.wide_window = Some(wide_window);
+        self
+    }
+
+    /// Number of PSMs reported per spectrum (default: 1, or 5 in wide-window
+    /// mode)
+    pub fn report_psms(mut self, report_psms: usize) -> Self {
+        self.report_psms = Some(report_psms);
+        self
+    }
+
+    /// Deisotope spectra (default: true)
+    pub fn deisotope(mut self, deisotope: bool) -> Self {
+        self.deisotope = Some(deisotope);
+        self
+    }
+
+    /// Detection of isotopic envelopes when deisotoping (default: simple)
+    pub fn deisotope_method(mut self, method: DeisotopeMethod) -> Self {
+        self.deisotope_method = Some(method);
+        self
+    }
+
+    /// Minimum number of peaks of a spectrum to be searched, and number of
+    /// most intense peaks kept (default: 15 and 150)
+    pub fn peaks(mut self, min: usize, max: usize) -> Self {
+        self.peaks = Some((min, max));
+        self
+    }
+
+    /// Check all settings, and fill in defaults
+    pub fn build(self) -> Result<Search, InvalidSearch> {
+        let invalid = |message: String| Err(InvalidSearch(message));
+
+        let precursor_tol = match self.precursor_tol {
+            Some(tol) => tol,
+            None => return invalid("`precursor_tol` must be set".into()),
+        };
+        let fragment_tol = match self.fragment_tol {
+            Some(tol) => tol,
+            None => return invalid("`fragment_tol` must be set".into()),
+        };
+        for (name, tol) in [
+            ("precursor_tol", precursor_tol),
+            ("fragment_tol", fragment_tol),
+        ] {
+            let (Tolerance::Ppm(lo, hi) | Tolerance::Da(lo, hi)) = tol;
+            if lo > hi {
+                return invalid(format!("`{}`: {} is greater than {}", name, lo, hi));
+            }
+        }
+
+        let cleave_at = self.enzyme.cleave_at.as_deref().unwrap_or("KR");
+        if cleave_at != "$" && !cleave_at.bytes().all(|aa| VALID_AA.contains(&aa)) {
+            return invalid(format!("`cleave_at`: invalid residues `{}`", cleave_at));
+        }
+        if let Some(restrict) = self.enzyme.restrict {
+            if !VALID_AA.contains(&(restrict as u8)) {
+                return invalid(format!("`restrict`: invalid residue `{}`", restrict));
+            }
+        }
+        let min_len = self.enzyme.min_len.unwrap_or(5);
+        let max_len = self.enzyme.max_len.unwrap_or(50);
+        if min_len == 0 || min_len > max_len {
+            return invalid(format!(
+                "peptide length must be at least 1, with minimum <= maximum: [{}, {}]",
+                min_len, max_len
+            ));
+        }
+        for (name, range) in [
+            ("peptide_mass", self.peptide_mass),
+            ("fragment_mz", self.fragment_mz),
+        ] {
+            if let Some((lo, hi)) = range {
+                if lo < 0.0 || lo >= hi {
+                    return invalid(format!("`{}` must be 0 <= minimum < maximum", name));
+                }
+            }
+        }
+
+        let target = |target: &str| {
+            ModificationSpecificity::from_str(target).map_err(|e| {
+                let reason = match e {
+                    InvalidModification::Empty => "empty".into(),
+                    InvalidModification::InvalidResidue(c) => format!("unrecognized residue {}", c),
+                    InvalidModification::TooLong(_) => "too long".into(),
+                };
+                InvalidSearch(format!("modification target `{}`: {}", target, reason))
+            })
+        };
+        let mut static_mods = HashMap::default();
+        for (residue, mass) in &self.static_mods {
+            static_mods.insert(target(residue)?, *mass);
+        }
+        let mut variable_mods = HashMap::default();
+        for (residue, masses) in &self.variable_mods {
+            variable_mods.insert(target(residue)?, masses.clone());
+        }
+
+        let precursor_charge = self.precursor_charge.unwrap_or((2, 4));
+        if precursor_charge.0 == 0 || precursor_charge.0 > precursor_charge.1 {
+            return invalid(format!(
+                "precursor charges must be [low, high] with low >= 1: [{}, {}]",
+                precursor_charge.0, precursor_charge.1
+            ));
+        }
+        let isotope_errors = self.isotope_errors.unwrap_or((0, 0));
+        if isotope_errors.0 > isotope_errors.1 {
+            return invalid(format!(
+                "minimum isotope error greater than maximum: [{}, {}]",
+                isotope_errors.0, isotope_errors.1
+            ));
+        }
+        let min_fragment_coverage = self.min_fragment_coverage.unwrap_or(0.0);
+        if !(0.0..=1.0).contains(&min_fragment_coverage) {
+            return invalid("`min_fragment_coverage` must be between 0 and 1".into());
+        }
+        let (min_peaks, max_peaks) = self.peaks.unwrap_or((15, 150));
+        if max_peaks == 0 {
+            return invalid("at least one peak must be kept per spectrum".into());
+        }
+        let wide_window = self.wide_window.unwrap_or(false);
+        let report_psms = self.report_psms.unwrap_or(if wide_window { 5 } else { 1 });
+        if report_psms == 0 {
+            return invalid("`report_psms` must be at least 1".into());
+        }
+
+        let mut database = Parameters {
+            static_mods,
+            variable_mods,
+            ..Builder {
+                enzyme: Some(self.enzyme),
+                peptide_min_mass: self.peptide_mass.map(|range| range.0),
+                peptide_max_mass: self.peptide_mass.map(|range| range.1),
+                fragment_min_mz: self.fragment_mz.map(|range| range.0),
+                fragment_max_mz: self.fragment_mz.map(|range| range.1),
+                ion_kinds: self.ion_kinds,
+                max_variable_mods: self.max_variable_mods,
+                decoy_tag: self.decoy_tag,
+                generate_decoys: self.generate_decoys,
+                equate_il: self.equate_il,
+                ..Builder::default()
+            }
+            .make_parameters()
+        };
+        database.tolerances = Some((precursor_tol, fragment_tol));
+
+        Ok(Search {
+            database,
+            precursor_tol,
+            fragment_tol,
+            precursor_charge,
+            isotope_errors,
+            isotope_error_mode: self.isotope_error_mode.unwrap_or_default(),
+            min_matched_peaks: self.min_matched_peaks.unwrap_or(4),
+            min_fragment_coverage,
+            max_fragment_charge: self.max_fragment_charge,
+            chimera: self.chimera.unwrap_or(wide_window),
+            wide_window,
+            report_psms,
+            deisotope: self.deisotope.unwrap_or(true),
+            deisotope_method: self.deisotope_method.unwrap_or_default(),
+            min_peaks,
+            max_peaks,
+        })
+    }
+}
+
+/// Validated search settings, created by [`SearchBuilder::build`]
+#[derive(Clone, Debug)]
+pub struct Search {
+    pub database: Parameters,
+    pub precursor_tol: Tolerance,
+    pub fragment_tol: Tolerance,
+    pub precursor_charge: (u8, u8),
+    pub isotope_errors: (i8, i8),
+    pub isotope_error_mode: IsotopeErrorMode,
+    pub min_matched_peaks: u16,
+    pub min_fragment_coverage: f32,
+    pub max_fragment_charge: Option<u8>,
+    pub chimera: bool,
+    pub wide_window: bool,
+    pub report_psms: usize,
+    pub deisotope: bool,
+    pub deisotope_method: DeisotopeMethod,
+    /// Spectra with fewer peaks are not searched by the command line tool,
+    /// callers of [`Scorer::score`] should skip them as well
+    pub min_peaks: usize,
+    pub max_peaks: usize,
+}
+
+impl Search {
+    /// Digest `fasta` and build the fragment index
+    pub fn database(&self, fasta: Fasta) -> IndexedDatabase {
+        self.database.clone().build(fasta)
+    }
+
+    /// A [`Scorer`] for `db`, which should have been built by
+    /// [`Search::database`]
+    pub fn scorer<'db>(&self, db: &'db IndexedDatabase) -> Scorer<'db> {
+        Scorer {
+            min_matched_peaks: self.min_matched_peaks,
+            min_fragment_coverage: self.min_fragment_coverage,
+            min_isotope_err: self.isotope_errors.0,
+            max_isotope_err: self.isotope_errors.1,
+            isotope_error_mode: self.isotope_error_mode,
+            min_precursor_charge: self.precursor_charge.0,
+            max_precursor_charge: self.precursor_charge.1,
+            max_fragment_charge: self.max_fragment_charge,
+            min_fragment_mass: self.database.fragment_min_mz,
+            max_fragment_mass: self.database.fragment_max_mz,
+            chimera: self.chimera,
+            report_psms: self.report_psms,
+            wide_window: self.wide_window,
+            ..Scorer::new(db, self.precursor_tol, self.fragment_tol)
+        }
+    }
+
+    pub fn processor(&self) -> SpectrumProcessor {
+        SpectrumProcessor {
+            deisotope_method: self.deisotope_method,
+            ..SpectrumProcessor::new(
+                self.max_peaks,
+                self.database.fragment_min_mz,
+                self.database.fragment_max_mz,
+                self.deisotope,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn builder() -> SearchBuilder {
+        SearchBuilder::default()
+            .precursor_tol(Tolerance::Ppm(-50.0, 50.0))
+            .fragment_tol(Tolerance::Ppm(-10.0, 10.0))
+    }
+
+    #[test]
+    fn defaults_match_configuration_file() {
+        let search = builder().build().unwrap();
+        let database = Builder::default().make_parameters();
+        assert_eq!(search.database.fingerprint(), database.fingerprint());
+        assert_eq!(search.precursor_charge, (2, 4));
+        assert_eq!((search.min_peaks, search.max_peaks), (15, 150));
+        assert!(search.deisotope);
+        assert!(!search.chimera);
+        assert_eq!(search.report_psms, 1);
+        assert_eq!(search.min_fragment_coverage, 0.0);
+
+        let search = builder().wide_window(true).build().unwrap();
+        assert!(search.chimera);
+        assert_eq!(search.report_psms, 5);
+    }
+
+    #[test]
+    fn typed_settings() {
+        let search = builder()
+            .enzyme(Protease::LysN)
+            .missed_cleavages(2)
+            .static_mod("C", 57.0215)
+            .variable_mod("M", 15.9949)
+            .variable_mod("M", 31.9898)
+            .variable_mod("^Q", -17.026549)
+            .build()
+            .unwrap();
+        let enzyme = &search.database.enzyme;
+        assert_eq!(enzyme.cleave_at.as_deref(), Some("K"));
+        assert_eq!(enzyme.restrict, None);
+        assert_eq!(enzyme.c_terminal, Some(false));
+        assert_eq!(enzyme.missed_cleavages, Some(2));
+        assert_eq!(
+            search.database.static_mods[&ModificationSpecificity::Residue(b'C')],
+            57.0215
+        );
+        assert_eq!(
+            search.database.variable_mods[&ModificationSpecificity::Residue(b'M')],
+            vec![15.9949, 31.9898]
+        );
+        assert_eq!(
+            search.database.variable_mods[&ModificationSpecificity::PeptideN(Some(b'Q'))],
+            vec![-17.026549]
+        );
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let invalid = |builder: SearchBuilder| builder.build().unwrap_err().0;
+        assert_eq!(
+            invalid(SearchBuilder::default()),
+            "`precursor_tol` must be set"
+        );
+        assert!(
+            invalid(builder().precursor_tol(Tolerance::Ppm(10.0, -10.0)))
+                .starts_with("`precursor_tol`")
+        );
+        assert!(invalid(builder().precursor_charge(3, 2)).starts_with("precursor charges"));
+        assert!(invalid(builder().precursor_charge(0, 2)).starts_with("precursor charges"));
+        assert!(invalid(builder().isotope_errors(1, -1)).starts_with("minimum isotope"));
+        assert!(invalid(builder().peptide_length(10, 5)).starts_with("peptide length"));
+        assert!(invalid(builder().fragment_mz(2000.0, 150.0)).starts_with("`fragment_mz`"));
+        assert!(invalid(builder().report_psms(0)).starts_with("`report_psms`"));
+        assert!(
+            invalid(builder().min_fragment_coverage(1.5)).starts_with("`min_fragment_coverage`")
+        );
+        assert_eq!(
+            invalid(builder().static_mod("X", 1.0)),
+            "modification target `X`: unrecognized residue X"
+        );
+        assert!(invalid(builder().custom_enzyme(EnzymeBuilder {
+            cleave_at: Some("K1".into()),
+            ..EnzymeBuilder::default()
+        }))
+        .starts_with("`cleave_at`"));
+    }
+}
@@ -0,0 +1,558 @@
+//! SILAC paired searches, and isotope-resolved detection of SILAC pairs in
+//! MS1 spectra
+//!
+//! When `database.silac_labels` is set, every peptide containing labeled
+//! residues is searched in both its light and heavy form - the heavy form
+//! carries the label mass offset on every labeled residue, and each PSM
+//! records the channel it matched. For each confidently identified peptide, the MS1
+//! spectra surrounding the PSM are searched for the co-eluting partner
+//! precursor (light for heavy PSMs, and vice versa), separated by the summed
+//! label mass offset of the peptide. This is used to verify SILAC PSMs, and to
+//! quantify heavy/light ratios.
+
+use crate::database::{IndexedDatabase, PeptideIx};
+use crate::mass::{Tolerance, NEUTRON, PROTON};
+use crate::par::prelude::*;
+use crate::peptide::Peptide;
+use crate::scoring::Feature;
+use crate::spectrum::{select_most_intense_peak, ProcessedSpectrum};
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Maximum difference (Da) between a residue modification and a label mass
+/// offset for the residue to be considered heavy-labeled
+const LABEL_TOLERANCE: f32 = 0.01;
+
+/// Commonly used SILAC labeling schemes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum LabelScheme {
+    /// Lys8 (13C6 15N2) & Arg10 (13C6 15N4)
+    #[serde(rename = "K8R10")]
+    Lys8Arg10,
+    /// Lys6 (13C6) & Arg6 (13C6)
+    #[serde(rename = "K6R6")]
+    Lys6Arg6,
+    /// Lys4 (2H4) & Arg6 (13C6), typically the medium channel of a triplex
+    #[serde(rename = "K4R6")]
+    Lys4Arg6,
+}
+
+impl LabelScheme {
+    /// Mass offset of each labeled residue
+    pub fn labels(&self) -> HashMap<char, f32> {
+        let labels = match self {
+            LabelScheme::Lys8Arg10 => [('K', 8.014199), ('R', 10.008269)],
+            LabelScheme::Lys6Arg6 => [('K', 6.020129), ('R', 6.020129)],
+            LabelScheme::Lys4Arg6 => [('K', 4.025107), ('R', 6.020129)],
+        };
+        labels.into_iter().collect()
+    }
+}
+
+/// Heavy labels, either a named [`LabelScheme`] or the mass offset of each
+/// labeled residue
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum SilacLabels {
+    Scheme(LabelScheme),
+    Residues(HashMap<char, f32>),
+}
+
+impl SilacLabels {
+    /// Mass offset of each labeled residue
+    pub fn labels(&self) -> HashMap<char, f32> {
+        match self {
+            SilacLabels::Scheme(scheme) => scheme.labels(),
+            SilacLabels::Residues(labels) => labels.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SilacSettings {
+    /// Mass offset of each heavy-labeled residue
+    pub labels: HashMap<char, f32>,
+    /// Tolerance (ppm) for matching MS1 peaks
+    pub ppm_tolerance: f32,
+    /// Maximum retention time difference (minutes) between a PSM and the MS1
+    /// scans searched for its partner precursor
+    pub rt_tolerance: f32,
+    /// Minimum number of MS1 scans in which both light and heavy precursors
+    /// must be detected
+    pub min_scans: usize,
+    /// Maximum spectrum-level q-value of PSMs searched for SILAC pairs
+    pub spectrum_fdr: f32,
+}
+
+impl Default for SilacSettings {
+    fn default() -> Self {
+        Self {
+            labels: LabelScheme::Lys8Arg10.labels(),
+            ppm_tolerance: 10.0,
+            rt_tolerance: 0.5,
+            min_scans: 2,
+            spectrum_fdr: 0.01,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Light,
+    Heavy,
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Light => f.write_str("light"),
+            Channel::Heavy => f.write_str("heavy"),
+        }
+    }
+}
+
+/// Result of searching MS1 spectra for the SILAC partner of a PSM
+#[derive(Clone, Debug, PartialEq)]
+pub struct SilacPair {
+    pub psm_id: usize,
+    pub peptide_idx: PeptideIx,
+    pub file_id: usize,
+    pub charge: u8,
+    /// Channel of the identified peptide
+    pub channel: Channel,
+    pub light_mz: f32,
+    pub heavy_mz: f32,
+    /// Number of MS1 scans in which both light and heavy precursors were
+    /// detected (monoisotopic and M+1 peaks)
+    pub scans: usize,
+    /// Were both precursors detected in at least `min_scans` MS1 scans?
+    pub pair_found: bool,
+    /// Summed monoisotopic peak intensities across co-eluting MS1 scans
+    pub light_intensity: f32,
+    pub heavy_intensity: f32,
+}
+
+impl SilacPair {
+    /// Heavy/light intensity ratio, if the pair was found
+    pub fn ratio(&self) -> Option<f32> {
+        match self.pair_found && self.light_intensity > 0.0 {
+            true => Some(self.heavy_intensity / self.light_intensity),
+            false => None,
+        }
+    }
+}
+
+/// Heavy/light ratio of a peptide within a single file, combining the SILAC
+/// pairs of all of its PSMs (of either channel, and any charge state)
+#[derive(Clone, Debug, PartialEq)]
+pub struct SilacPeptide {
+    /// Representative PSM's peptide - the light form, if it was identified
+    pub peptide_idx: PeptideIx,
+    pub file_id: usize,
+    /// Number of PSMs, and number of PSMs with a found pair
+    pub psms: usize,
+    pub pairs: usize,
+    /// Summed intensities of all found pairs
+    pub light_intensity: f32,
+    pub heavy_intensity: f32,
+    /// Median heavy/light ratio of the found pairs
+    pub ratio: Option<f32>,
+    /// Channel of the PSMs, if no pair was found: the partner channel is
+    /// missing, and no ratio can be reported
+    pub missing_partner: Option<Channel>,
+}
+
+/// Combine SILAC pairs into peptide-level heavy/light ratios, for each file.
+///
+/// If a pair was found for any PSM of a peptide, its ratio is the median of
+/// the ratios of found pairs. Otherwise, only one channel was detected: the
+/// ratio is left empty, and the detected channel is reported, as ratios of
+/// peptides that are (nearly) absent from one channel cannot be measured
+pub fn peptide_ratios(db: &IndexedDatabase, pairs: &[SilacPair]) -> Vec<SilacPeptide> {
+    // Light & heavy forms are distinct peptides, so group by file, sequence
+    // and light precursor mass
+    let mut groups: FnvHashMap<(usize, &[u8], i64), Vec<&SilacPair>> = FnvHashMap::default();
+    for pair in pairs {
+        let light_mass = (pair.light_mz - PROTON) * pair.charge.max(1) as f32;
+        groups
+            .entry((
+                pair.file_id,
+                db[pair.peptide_idx].sequence.as_ref(),
+                (light_mass * 100.0).round() as i64,
+            ))
+            .or_default()
+            .push(pair);
+    }
+
+    let mut peptides = groups
+        .into_values()
+        .map(|group| {
+            let representative = group
+                .iter()
+                .find(|pair| pair.channel == Channel::Light)
+                .unwrap_or(&group[0]);
+            let found = group
+                .iter()
+                .filter(|pair| pair.pair_found)
+                .collect::<Vec<_>>();
+            let mut ratios = found
+                .iter()
+                .filter_map(|pair| pair.ratio())
+                .collect::<Vec<_>>();
+            ratios.sort_by(|a, b| a.total_cmp(b));
+            let ratio = match ratios.len() {
+                0 => None,
+                n if n % 2 == 0 => Some((ratios[n / 2 - 1] + ratios[n / 2]) / 2.0),
+                n => Some(ratios[n / 2]),
+            };
+            let missing_partner = match found.is_empty() {
+                true => Some(representative.channel),
+                false => None,
+            };
+
+            SilacPeptide {
+                peptide_idx: representative.peptide_idx,
+                file_id: representative.file_id,
+                psms: group.len(),
+                pairs: found.len(),
+                light_intensity: found.iter().map(|pair| pair.light_intensity).sum(),
+                heavy_intensity: found.iter().map(|pair| pair.heavy_intensity).sum(),
+                ratio,
+                missing_partner,
+            }
+        })
+        .collect::<Vec<_>>();
+    peptides.sort_by_key(|peptide| (peptide.file_id, peptide.peptide_idx));
+    peptides
+}
+
+/// Heavy form of `peptide`, with the label mass offset added to every labeled
+/// residue. Returns `None` if the peptide contains no labeled residues
+pub fn heavy(peptide: &Peptide, labels: &HashMap<char, f32>) -> Option<Peptide> {
+    if labels.is_empty() {
+        return None;
+    }
+    let mut heavy = peptide.clone();
+    let mut delta = 0.0;
+    for (residue, modification) in heavy.sequence.iter().zip(heavy.modifications.iter_mut()) {
+        if let Some(&label) = labels.get(&(*residue as char)) {
+            *modification += label;
+            delta += label;
+        }
+    }
+    match delta != 0.0 {
+        true => {
+            heavy.monoisotopic += delta;
+            Some(heavy)
+        }
+        false => None,
+    }
+}
+
+/// Record the SILAC channel matched by each PSM. PSMs of peptides without
+/// labeled residues are not assigned a channel
+pub fn assign_channels(
+    db: &IndexedDatabase,
+    features: &mut [Feature],
+    labels: &HashMap<char, f32>,
+) {
+    features.par_iter_mut().for_each(|feat| {
+        feat.silac_channel = channel(&db[feat.peptide_idx], labels).map(|(channel, _)| channel);
+    });
+}
+
+/// Determine the SILAC channel of `peptide`, and the mass difference between
+/// its light and heavy forms. Returns `None` if the peptide contains no
+/// labeled residues, or only some of its labelable residues are heavy
+pub fn channel(peptide: &Peptide, labels: &HashMap<char, f32>) -> Option<(Channel, f32)> {
+    let mut delta = 0.0;
+    let mut labelable = 0;
+    let mut heavy = 0;
+    for (residue, modification) in peptide.sequence.iter().zip(&peptide.modifications) {
+        if let Some(&label) = labels.get(&(*residue as char)) {
+            delta += label;
+            labelable += 1;
+            if (modification - label).abs() <= LABEL_TOLERANCE {
+                heavy += 1;
+            }
+        }
+    }
+    match (labelable, heavy) {
+        (0, _) => None,
+        (_, 0) => Some((Channel::Light, delta)),
+        (n, h) if n == h => Some((Channel::Heavy, delta)),
+        _ => None,
+    }
+}
+
+/// Search MS1 spectra for the SILAC partner of every rank-1 target PSM
+/// passing `settings.spectrum_fdr`. Spectrum-level q-values must have been
+/// assigned
+pub fn detect_pairs(
+    db: &IndexedDatabase,
+    features: &[Feature],
+    ms1: &[ProcessedSpectrum],
+    settings: &SilacSettings,
+) -> Vec<SilacPair> {
+    // MS1 scans of each file, sorted by retention time
+    let mut scans: FnvHashMap<usize, Vec<&ProcessedSpectrum>> = FnvHashMap::default();
+    for spectrum in ms1 {
+        scans.entry(spectrum.file_id).or_default().push(spectrum);
+    }
+    for file in scans.values_mut() {
+        file.sort_by(|a, b| a.scan_start_time.total_cmp(&b.scan_start_time));
+    }
+
+    let tolerance = Tolerance::Ppm(-settings.ppm_tolerance, settings.ppm_tolerance);
+    features
+        .par_iter()
+        .filter(|feat| {
+            feat.label == 1 && feat.rank == 1 && feat.spectrum_q <= settings.spectrum_fdr
+        })
+        .filter_map(|feat| {
+            let (channel, delta) = channel(&db[feat.peptide_idx], &settings.labels)?;
+            let light_mass = match channel {
+                Channel::Light => feat.calcmass,
+                Channel::Heavy => feat.calcmass - delta,
+            };
+            let charge = feat.charge.max(1) as f32;
+
+            let mut pair = SilacPair {
+                psm_id: feat.psm_id,
+                peptide_idx: feat.peptide_idx,
+                file_id: feat.file_id,
+                charge: feat.charge,
+                channel,
+                light_mz: light_mass / charge + PROTON,
+                heavy_mz: (light_mass + delta) / charge + PROTON,
+                scans: 0,
+                pair_found: false,
+                light_intensity: 0.0,
+                heavy_intensity: 0.0,
+            };
+
+            let file = scans.get(&feat.file_id).map(Vec::as_slice).unwrap_or(&[]);
+            let lo = file.partition_point(|s| s.scan_start_time < feat.rt - settings.rt_tolerance);
+            for scan in file[lo..]
+                .iter()
+                .take_while(|s| s.scan_start_time <= feat.rt + settings.rt_tolerance)
+            {
+                let light = detect(scan, light_mass / charge, charge, tolerance);
+                let heavy = detect(scan, (light_mass + delta) / charge, charge, tolerance);
+                if let (Some(light), Some(heavy)) = (light, heavy) {
+                    pair.scans += 1;
+                    pair.light_intensity += light;
+                    pair.heavy_intensity += heavy;
+                }
+            }
+            pair.pair_found = pair.scans >= settings.min_scans;
+            Some(pair)
+        })
+        .collect()
+}
+
+/// Intensity of the monoisotopic peak at `mass` (peak units: [M]/z), if
+/// both the monoisotopic and M+1 isotope peaks are present in `scan`
+fn detect(scan: &ProcessedSpectrum, mass: f32, charge: f32, tolerance: Tolerance) -> Option<f32> {
+    let mono = select_most_intense_peak(&scan.peaks, mass, tolerance, None)?;
+    select_most_intense_peak(&scan.peaks, mass + NEUTRON / charge, tolerance, None)?;
+    Some(mono.intensity)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Builder, EnzymeBuilder};
+    use crate::fasta::Fasta;
+    use crate::spectrum::Peak;
+
+    #[test]
+    fn paired_search() {
+        let fasta = Fasta::parse(">sp|AAAAA\nLEQSMRAQLTQLKAQLTQL".into(), "rev_", false);
+        let db = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(6),
+                ..Default::default()
+            }),
+            silac_labels: Some(SilacLabels::Scheme(LabelScheme::Lys8Arg10)),
+            generate_decoys: Some(false),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        // Light & heavy forms of labeled peptides, and the unlabeled C-terminal peptide
+        let mut peptides = db
+            .peptides
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        peptides.sort();
+        assert_eq!(
+            peptides,
+            vec![
+                "AQLTQL",
+                "AQLTQLK",
+                "AQLTQLK[+8.014199]",
+                "LEQSMR",
+                "LEQSMR[+10.008269]"
+            ]
+        );
+
+        let labels = LabelScheme::Lys8Arg10.labels();
+        let mut features = db
+            .peptides
+            .iter()
+            .enumerate()
+            .map(|(idx, _)| Feature {
+                peptide_idx: PeptideIx(idx as u32),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        assign_channels(&db, &mut features, &labels);
+        for feat in &features {
+            let peptide = &db[feat.peptide_idx];
+            let expected = match peptide.to_string().as_str() {
+                "AQLTQL" => None,
+                s if s.contains('[') => Some(Channel::Heavy),
+                _ => Some(Channel::Light),
+            };
+            assert_eq!(feat.silac_channel, expected, "{}", peptide);
+        }
+        let light = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LEQSMR")
+            .unwrap();
+        let heavy = db
+            .peptides
+            .iter()
+            .find(|p| p.to_string() == "LEQSMR[+10.008269]")
+            .unwrap();
+        assert!((heavy.monoisotopic - light.monoisotopic - 10.008269).abs() < 1E-3);
+    }
+
+    #[test]
+    fn silac_pairs() {
+        let fasta = Fasta::parse(">sp|AAAAA\nLEQSMRAQLTQLK".into(), "rev_", false);
+        let db = Builder {
+            enzyme: Some(EnzymeBuilder {
+                missed_cleavages: Some(0),
+                min_len: Some(6),
+                ..Default::default()
+            }),
+            static_mods: Some([("K".to_string(), 8.014199)].into_iter().collect()),
+            generate_decoys: Some(false),
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+
+        let settings = SilacSettings::default();
+        let idx = |sequence: &[u8]| {
+            db.peptides
+                .iter()
+                .position(|p| p.sequence.as_ref() == sequence)
+                .map(|idx| PeptideIx(idx as u32))
+                .unwrap()
+        };
+        let light = idx(b"LEQSMR");
+        let heavy = idx(b"AQLTQLK");
+        assert_eq!(
+            channel(&db[light], &settings.labels).map(|(c, _)| c),
+            Some(Channel::Light)
+        );
+        assert_eq!(
+            channel(&db[heavy], &settings.labels).map(|(c, _)| c),
+            Some(Channel::Heavy)
+        );
+
+        let psm = |psm_id, peptide_idx: PeptideIx| Feature {
+            psm_id,
+            peptide_idx,
+            calcmass: db[peptide_idx].monoisotopic,
+            charge: 2,
+            rt: 10.0,
+            rank: 1,
+            label: 1,
+            spectrum_q: 0.001,
+            ..Default::default()
+        };
+        let features = vec![psm(0, light), psm(1, heavy)];
+
+        // Light & heavy precursors of AQLTQLK (z=2) co-elute, but only the
+        // light precursor of LEQSMR is present
+        let lk = (db[heavy].monoisotopic - 8.014199) / 2.0;
+        let hk = db[heavy].monoisotopic / 2.0;
+        let lr = db[light].monoisotopic / 2.0;
+        let scan = |rt, masses: &[f32]| {
+            let mut peaks = masses
+                .iter()
+                .flat_map(|&mass| {
+                    [
+                        Peak {
+                            mass,
+                            intensity: 100.0,
+                        },
+                        Peak {
+                            mass: mass + NEUTRON / 2.0,
+                            intensity: 50.0,
+                        },
+                    ]
+                })
+                .collect::<Vec<_>>();
+            peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+            ProcessedSpectrum {
+                level: 1,
+                id: String::default(),
+                file_id: 0,
+                scan_start_time: rt,
+                ion_injection_time: 0.0,
+                precursors: Vec::new(),
+                peaks,
+                total_ion_current: 0.0,
+                noise: Vec::new(),
+                ion_mobility: None,
+                compensation_voltage: None,
+            }
+        };
+        let ms1 = vec![
+            scan(9.8, &[lk, hk, lr]),
+            scan(10.1, &[lk, hk, lr]),
+            // Outside of RT tolerance
+            scan(12.0, &[lk, hk, lr]),
+        ];
+
+        let pairs = detect_pairs(&db, &features, &ms1, &settings);
+        assert_eq!(pairs.len(), 2);
+        let found = pairs.iter().find(|p| p.psm_id == 1).unwrap();
+        assert_eq!(found.channel, Channel::Heavy);
+        assert_eq!(found.scans, 2);
+        assert!(found.pair_found);
+        assert_eq!(found.ratio(), Some(1.0));
+
+        let missing = pairs.iter().find(|p| p.psm_id == 0).unwrap();
+        assert_eq!(missing.channel, Channel::Light);
+        assert!(!missing.pair_found);
+        assert_eq!(missing.ratio(), None);
+
+        let peptides = peptide_ratios(&db, &pairs);
+        assert_eq!(peptides.len(), 2);
+        let k = peptides.iter().find(|p| p.peptide_idx == heavy).unwrap();
+        assert_eq!((k.psms, k.pairs), (1, 1));
+        assert_eq!(k.ratio, Some(1.0));
+        assert_eq!(k.missing_partner, None);
+        let r = peptides.iter().find(|p| p.peptide_idx == light).unwrap();
+        assert_eq!((r.psms, r.pairs), (1, 0));
+        assert_eq!(r.ratio, None);
+        assert_eq!(r.missing_partner, Some(Channel::Light));
+    }
+}
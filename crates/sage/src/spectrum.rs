@@ -1,8 +1,9 @@
 use crate::database::binary_search_slice;
-use crate::mass::{Tolerance, NEUTRON, PROTON};
+use crate::mass::{Tolerance, H2O, NEUTRON, NH3, PROTON};
+use serde::{Deserialize, Serialize};
 
 /// A charge-less peak at monoisotopic mass
-#[derive(PartialEq, Copy, Clone, Default, Debug)]
+#[derive(PartialEq, Copy, Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Peak {
     pub intensity: f32,
     pub mass: f32,
@@ -42,9 +43,623 @@ pub struct SpectrumProcessor {
     pub max_fragment_mz: f32,
     pub min_fragment_mz: f32,
     pub deisotope: bool,
+    /// How isotopic envelopes are detected, if `deisotope` is set
+    pub deisotope_method: DeisotopeMethod,
+    /// Which time value populates [`ProcessedSpectrum::scan_start_time`]
+    pub rt_source: RtSource,
+    /// Removal of placeholder and duplicate peaks, before any other processing
+    pub peak_cleanup: PeakCleanup,
+    /// Peak picking of profile MS2 spectra
+    pub centroid: Centroiding,
+    /// Selection of MS2 peaks per m/z window, instead of the `take_top_n`
+    /// most intense peaks of the whole spectrum
+    pub peak_filter: Option<PeakFilter>,
+    /// Removal of MS2 peaks that are not fragment ions
+    pub peak_mask: Option<PeakMask>,
 }
 
-#[derive(Default, Debug, Clone)]
+/// Cleanup of peaks emitted by some converters, which would otherwise skew
+/// top-N peak selection and deisotoping
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PeakCleanup {
+    /// Remove peaks with zero (or negative) intensity
+    pub remove_zero_intensity: bool,
+    /// How to handle multiple peaks with identical m/z
+    pub duplicate_mz: DuplicateMz,
+}
+
+impl Default for PeakCleanup {
+    fn default() -> Self {
+        Self {
+            remove_zero_intensity: true,
+            duplicate_mz: DuplicateMz::MostIntense,
+        }
+    }
+}
+
+/// Detection of the isotopic envelopes of MS2 fragments
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DeisotopeMethod {
+    /// Link peaks spaced by the isotope spacing of any charge, each less
+    /// intense than the previous one (see [`deisotope`])
+    #[default]
+    Simple,
+    /// Fit envelopes against the isotope distribution of averagine, choosing
+    /// the charge and monoisotopic peak that explain the most intensity (see
+    /// [`deisotope_averagine`])
+    Averagine,
+}
+
+/// Neutral losses of the unfragmented precursor removed by [`PeakMask`]:
+/// water, ammonia and phosphoric acid
+const PRECURSOR_LOSSES: [f32; 3] = [H2O, NH3, 97.9769];
+
+/// Number of isotopic peaks of the unfragmented precursor removed by
+/// [`PeakMask`], including the selected peak
+const PRECURSOR_ISOTOPES: usize = 3;
+
+/// Removal of MS2 peaks that are not fragment ions, before deisotoping and
+/// peak selection: they would otherwise take up part of the `max_peaks`
+/// budget, and occasionally match fragments
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PeakMask {
+    /// Remove peaks within this m/z range, e.g. isobaric reporter ions
+    pub reporter_region: Option<(f32, f32)>,
+    /// Remove the unfragmented precursor, its isotopes, and its water,
+    /// ammonia and phosphoric acid losses
+    pub precursor: bool,
+    /// Tolerance of the precursor peaks
+    pub tolerance: Tolerance,
+}
+
+impl PeakMask {
+    /// Remove masked peaks from the MS2 `spectrum` in place, returning the
+    /// number of removed peaks. Precursor losses are only removed if the
+    /// precursor charge is known
+    pub fn apply(&self, spectrum: &mut RawSpectrum) -> usize {
+        let mut masked = Vec::new();
+        if let Some(region) = self.reporter_region {
+            masked.push(region);
+        }
+        if let (true, Some(precursor)) = (self.precursor, spectrum.precursors.first()) {
+            let charge = precursor.charge.unwrap_or(1) as f32;
+            let mut mzs = (0..PRECURSOR_ISOTOPES)
+                .map(|isotope| precursor.mz + isotope as f32 * NEUTRON / charge)
+                .collect::<Vec<_>>();
+            if precursor.charge.is_some() {
+                mzs.extend(
+                    PRECURSOR_LOSSES
+                        .iter()
+                        .map(|loss| precursor.mz - loss / charge),
+                );
+            }
+            masked.extend(mzs.into_iter().map(|mz| self.tolerance.bounds(mz)));
+        }
+        if masked.is_empty() {
+            return 0;
+        }
+
+        let keep = spectrum
+            .mz
+            .iter()
+            .map(|&mz| !masked.iter().any(|&(lo, hi)| mz >= lo && mz <= hi))
+            .collect::<Vec<_>>();
+        let removed = keep.iter().filter(|keep| !**keep).count();
+        if removed > 0 {
+            let retain = |values: &mut Vec<f32>| {
+                let mut idx = 0;
+                values.retain(|_| {
+                    idx += 1;
+                    keep[idx - 1]
+                });
+            };
+            retain(&mut spectrum.mz);
+            retain(&mut spectrum.intensity);
+            if spectrum.noise.len() == keep.len() {
+                retain(&mut spectrum.noise);
+            }
+            if spectrum.mobility.len() == keep.len() {
+                retain(&mut spectrum.mobility);
+            }
+        }
+        removed
+    }
+}
+
+/// Dynamic-range filtering of MS2 peaks: the most intense peaks of each m/z
+/// window are kept, so that low-mass ions are not crowded out by the intense
+/// peaks of another region of the spectrum
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PeakFilter {
+    /// Width of the windows, in Da of (deisotoped) fragment mass
+    pub window: f32,
+    /// Number of most intense peaks kept in each window
+    pub peaks_per_window: usize,
+    /// Peaks less intense than this fraction of the most intense peak of the
+    /// spectrum are removed
+    pub min_relative_intensity: f32,
+}
+
+impl Default for PeakFilter {
+    fn default() -> Self {
+        Self {
+            window: 100.0,
+            peaks_per_window: 12,
+            min_relative_intensity: 0.0,
+        }
+    }
+}
+
+impl PeakFilter {
+    /// Keep the `peaks_per_window` most intense of `peaks` in each window,
+    /// above the relative intensity floor. Peaks are left sorted by window
+    pub fn apply(&self, peaks: &mut Vec<Peak>) {
+        let base = peaks
+            .iter()
+            .fold(0.0f32, |max, peak| max.max(peak.intensity));
+        peaks.retain(|peak| peak.intensity >= base * self.min_relative_intensity);
+
+        let window = |peak: &Peak| (peak.mass / self.window).floor() as i64;
+        peaks.sort_unstable_by(|a, b| {
+            window(a)
+                .cmp(&window(b))
+                .then_with(|| b.intensity.total_cmp(&a.intensity))
+        });
+        let mut current = None;
+        let mut kept = 0;
+        peaks.retain(|peak| {
+            if current != Some(window(peak)) {
+                current = Some(window(peak));
+                kept = 0;
+            }
+            kept += 1;
+            kept <= self.peaks_per_window
+        });
+    }
+}
+
+/// Peak picking (centroiding) of MS2 spectra in profile mode, which would
+/// otherwise be searched as if every profile point was a fragment peak
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Centroiding {
+    /// Centroid the spectra annotated as profile data by the input file
+    #[default]
+    Auto,
+    /// Centroid every MS2 spectrum, e.g. profile data that is not annotated
+    /// as such
+    Always,
+    /// Never centroid, searching profile spectra as they are
+    Never,
+}
+
+impl Centroiding {
+    /// Whether `spectrum` should be centroided (see [`centroid`])
+    pub fn applies(&self, spectrum: &RawSpectrum) -> bool {
+        match self {
+            Centroiding::Auto => spectrum.representation == Representation::Profile,
+            Centroiding::Always => true,
+            Centroiding::Never => false,
+        }
+    }
+}
+
+/// Handling of multiple peaks with identical m/z in a spectrum
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateMz {
+    /// Keep all peaks
+    Keep,
+    /// Keep only the most intense peak
+    #[default]
+    MostIntense,
+    /// Replace the peaks with a single peak, with the summed intensity
+    Sum,
+}
+
+/// Number of peaks removed by [`PeakCleanup`] and [`PeakMask`], and of
+/// centroided spectra
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemovedPeaks {
+    pub zero_intensity: usize,
+    pub duplicate_mz: usize,
+    /// Profile MS2 spectra that were centroided, see [`Centroiding`]
+    pub centroided: usize,
+    /// Reporter ion and precursor peaks removed by [`PeakMask`]
+    pub masked: usize,
+}
+
+impl std::ops::AddAssign for RemovedPeaks {
+    fn add_assign(&mut self, rhs: Self) {
+        self.zero_intensity += rhs.zero_intensity;
+        self.duplicate_mz += rhs.duplicate_mz;
+        self.centroided += rhs.centroided;
+        self.masked += rhs.masked;
+    }
+}
+
+impl PeakCleanup {
+    /// Remove zero-intensity and duplicate m/z peaks from `spectrum` in place,
+    /// returning the number of removed peaks. Peaks are sorted by m/z if
+    /// duplicates are removed
+    pub fn clean(&self, spectrum: &mut RawSpectrum) -> RemovedPeaks {
+        let mut removed = RemovedPeaks::default();
+        let mz = &spectrum.mz;
+        let mut intensity = spectrum.intensity.clone();
+        // Positions of the kept peaks, so that per-peak noise and ion
+        // mobility arrays stay aligned with the peaks
+        let mut peaks = (0..mz.len().min(intensity.len())).collect::<Vec<_>>();
+
+        if self.remove_zero_intensity {
+            let before = peaks.len();
+            peaks.retain(|&idx| intensity[idx] > 0.0);
+            removed.zero_intensity = before - peaks.len();
+        }
+
+        if self.duplicate_mz != DuplicateMz::Keep {
+            peaks.sort_by(|&a, &b| mz[a].total_cmp(&mz[b]));
+            let before = peaks.len();
+            peaks.dedup_by(|next, kept| {
+                if mz[*next] != mz[*kept] {
+                    return false;
+                }
+                match self.duplicate_mz {
+                    DuplicateMz::Sum => intensity[*kept] += intensity[*next],
+                    _ => intensity[*kept] = intensity[*kept].max(intensity[*next]),
+                }
+                true
+            });
+            removed.duplicate_mz = before - peaks.len();
+        }
+
+        if removed != RemovedPeaks::default() {
+            let len = mz.len();
+            let select = |values: &[f32]| peaks.iter().map(|&idx| values[idx]).collect();
+            spectrum.mz = select(mz);
+            spectrum.intensity = select(&intensity);
+            if spectrum.noise.len() == len {
+                spectrum.noise = select(&spectrum.noise);
+            }
+            if spectrum.mobility.len() == len {
+                spectrum.mobility = select(&spectrum.mobility);
+            }
+        }
+        removed
+    }
+}
+
+/// Upper limits on the reported precursor mass and charge of MS2 spectra.
+/// Extreme precursors (e.g. from intact-protein contamination) are skipped
+/// and counted, rather than searched
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PrecursorGuards {
+    /// Maximum neutral precursor mass (Da)
+    pub max_precursor_mass: Option<f32>,
+    /// Maximum reported precursor charge
+    pub max_precursor_charge: Option<u8>,
+    /// Handling of MS2 spectra with a reported precursor charge outside of
+    /// `precursor_charge`
+    #[serde(default)]
+    pub charge_filter: ChargeFilter,
+    /// Searched precursor charge range, set from `precursor_charge`
+    #[serde(skip)]
+    #[cfg_attr(feature = "schemars", schemars(skip))]
+    pub precursor_charge: (u8, u8),
+}
+
+/// Handling of MS2 spectra whose reported precursor charge is outside of the
+/// searched `precursor_charge` range
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ChargeFilter {
+    /// Search the reported charge, even if it is outside of the range
+    #[default]
+    Keep,
+    /// Skip the spectrum
+    Skip,
+    /// Ignore the reported charge, and search all charges in the range
+    Reassign,
+}
+
+/// Number of MS2 spectra skipped (or reassigned) by [`PrecursorGuards`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedPrecursors {
+    pub mass: usize,
+    pub charge: usize,
+    /// Skipped by [`ChargeFilter::Skip`]
+    pub charge_range: usize,
+    /// Searched at all charges by [`ChargeFilter::Reassign`]
+    pub reassigned: usize,
+}
+
+impl std::ops::AddAssign for SkippedPrecursors {
+    fn add_assign(&mut self, rhs: Self) {
+        self.mass += rhs.mass;
+        self.charge += rhs.charge;
+        self.charge_range += rhs.charge_range;
+        self.reassigned += rhs.reassigned;
+    }
+}
+
+impl PrecursorGuards {
+    pub fn is_enabled(&self) -> bool {
+        self.max_precursor_mass.is_some()
+            || self.max_precursor_charge.is_some()
+            || self.charge_filter != ChargeFilter::Keep
+    }
+
+    /// Remove MS2 spectra whose first precursor exceeds the maximum charge or
+    /// mass, returning the number of removed spectra. The precursor mass can
+    /// only be checked if its charge is reported.
+    ///
+    /// Reported charges outside of `precursor_charge` are then handled by
+    /// `charge_filter`
+    pub fn apply(&self, spectra: &mut Vec<ProcessedSpectrum>) -> SkippedPrecursors {
+        let mut skipped = SkippedPrecursors::default();
+        if self.is_enabled() {
+            spectra.retain_mut(|spectrum| self.check(spectrum, &mut skipped));
+        }
+        skipped
+    }
+
+    /// Guard a single spectrum, as it is read: returns `false` if it should
+    /// be removed (see [`PrecursorGuards::apply`]), counting it in `skipped`
+    pub fn check(&self, spectrum: &mut ProcessedSpectrum, skipped: &mut SkippedPrecursors) -> bool {
+        let precursor = match (spectrum.level, spectrum.precursors.first_mut()) {
+            (2, Some(precursor)) => precursor,
+            _ => return true,
+        };
+        let charge = match precursor.charge {
+            Some(charge) => charge,
+            None => return true,
+        };
+        if self.max_precursor_charge.map_or(false, |max| charge > max) {
+            skipped.charge += 1;
+            return false;
+        }
+        let mass = (precursor.mz - PROTON) * charge as f32;
+        if self.max_precursor_mass.map_or(false, |max| mass > max) {
+            skipped.mass += 1;
+            return false;
+        }
+        let (min_charge, max_charge) = self.precursor_charge;
+        if charge < min_charge || charge > max_charge {
+            match self.charge_filter {
+                ChargeFilter::Keep => {}
+                ChargeFilter::Skip => {
+                    skipped.charge_range += 1;
+                    return false;
+                }
+                ChargeFilter::Reassign => {
+                    skipped.reassigned += 1;
+                    precursor.charge = None;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Scan number of a spectrum: the value of the last `scan=` key of its
+/// native identifier, or the identifier itself if it is a number
+pub fn scan_number(id: &str) -> Option<u32> {
+    match id.rfind("scan=") {
+        Some(start) => {
+            let digits = &id[start + 5..];
+            let end = digits
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(digits.len());
+            digits[..end].parse().ok()
+        }
+        None => id.trim().parse().ok(),
+    }
+}
+
+/// Restriction of a search to a slice of each file, e.g. to quickly try
+/// parameters on a part of a long gradient. Both ranges are inclusive
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ScanWindow {
+    /// First and last scan number. Spectra without a scan number (see
+    /// [`scan_number`]) are outside of the range
+    pub scan_range: Option<(u32, u32)>,
+    /// First and last scan start time, in minutes
+    pub rt_range: Option<(f32, f32)>,
+}
+
+impl ScanWindow {
+    pub fn is_enabled(&self) -> bool {
+        self.scan_range.is_some() || self.rt_range.is_some()
+    }
+
+    /// Is a spectrum with native identifier `id`, acquired at
+    /// `scan_start_time`, inside of the window?
+    pub fn contains(&self, id: &str, scan_start_time: f32) -> bool {
+        let scan = match self.scan_range {
+            Some((lo, hi)) => matches!(scan_number(id), Some(scan) if (lo..=hi).contains(&scan)),
+            None => true,
+        };
+        let rt = match self.rt_range {
+            Some((lo, hi)) => (lo..=hi).contains(&scan_start_time),
+            None => true,
+        };
+        scan && rt
+    }
+
+    /// Is a spectrum acquired after the end of the window? Spectra are read
+    /// in acquisition order, so that the rest of a file can be skipped
+    pub fn is_past(&self, id: &str, scan_start_time: f32) -> bool {
+        let scan = match (self.scan_range, scan_number(id)) {
+            (Some((_, hi)), Some(scan)) => scan > hi,
+            _ => false,
+        };
+        let rt = match self.rt_range {
+            Some((_, hi)) => scan_start_time > hi,
+            None => false,
+        };
+        scan || rt
+    }
+}
+
+/// Maximum number of isotopes by which a selected precursor can be above its
+/// monoisotopic peak, for [`PrecursorRefinement`]
+const MAX_PRECURSOR_ISOTOPE: usize = 2;
+
+/// Re-determination of the monoisotopic m/z and charge of MS2 precursors from
+/// the isotopic envelope observed in the preceding MS1 survey scan
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PrecursorRefinement {
+    /// Tolerance of the isotopic peaks of a precursor in the MS1 scan
+    pub tolerance: Tolerance,
+    /// Charges tried for precursors without a reported charge
+    pub precursor_charge: (u8, u8),
+}
+
+/// Number of MS2 precursors changed by [`PrecursorRefinement`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RefinedPrecursors {
+    /// Selected on an isotopic peak instead of the monoisotopic one
+    pub monoisotopic: usize,
+    /// Assigned a charge, which was not reported
+    pub charge: usize,
+}
+
+impl std::ops::AddAssign for RefinedPrecursors {
+    fn add_assign(&mut self, rhs: Self) {
+        self.monoisotopic += rhs.monoisotopic;
+        self.charge += rhs.charge;
+    }
+}
+
+impl PrecursorRefinement {
+    /// Refine the first precursor of the MS2 `spectrum` against the `ms1`
+    /// scan preceding it, counting changed precursors in `refined`.
+    ///
+    /// The selected peak is tried as the monoisotopic peak, and as each of
+    /// the first isotopes of an envelope, at the reported charge - or every
+    /// charge of `precursor_charge` if it is unknown. Envelopes are compared
+    /// to the averagine isotope distribution, including the position one
+    /// isotope below the monoisotopic peak, which should be empty: the
+    /// envelope that fits and explains the most intensity is kept. The
+    /// precursor is left unchanged if the selected peak is not observed, or
+    /// no envelope fits
+    pub fn refine(
+        &self,
+        ms1: &ProcessedSpectrum,
+        spectrum: &mut ProcessedSpectrum,
+        refined: &mut RefinedPrecursors,
+    ) {
+        let precursor = match (spectrum.level, spectrum.precursors.first_mut()) {
+            (2, Some(precursor)) => precursor,
+            _ => return,
+        };
+        // MS1 peaks are stored as [M]/z, without a proton
+        let selected = precursor.mz - PROTON;
+        let intensity = |mass: f32| {
+            select_most_intense_peak(&ms1.peaks, mass, self.tolerance, None)
+                .map_or(0.0, |peak| peak.intensity)
+        };
+        if intensity(selected) <= 0.0 {
+            return;
+        }
+
+        let charges = match precursor.charge {
+            Some(charge) => charge..=charge,
+            None => self.precursor_charge.0.max(1)..=self.precursor_charge.1,
+        };
+        let mut best: Option<(f32, u8, f32)> = None;
+        for charge in charges {
+            let spacing = NEUTRON / charge as f32;
+            for isotope in 0..=MAX_PRECURSOR_ISOTOPE {
+                let mono = selected - isotope as f32 * spacing;
+                // One position below the monoisotopic peak, then the envelope
+                let mut observed = [0.0; AVERAGINE_ISOTOPES + 1];
+                for (n, slot) in observed.iter_mut().enumerate() {
+                    *slot = intensity(mono + (n as f32 - 1.0) * spacing);
+                }
+                let envelope = &observed[1..];
+                if envelope[0] <= 0.0 || envelope.iter().filter(|&&int| int > 0.0).count() < 2 {
+                    continue;
+                }
+
+                let mut theoretical = [0.0; AVERAGINE_ISOTOPES + 1];
+                theoretical[1..].copy_from_slice(&averagine_isotopes(mono * charge as f32));
+                let dot = observed
+                    .iter()
+                    .zip(theoretical.iter())
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>();
+                let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let fit = dot / (norm(&observed) * norm(&theoretical));
+                if fit < MIN_ENVELOPE_FIT {
+                    continue;
+                }
+                let score = fit * envelope.iter().sum::<f32>();
+                if best.map_or(true, |(best, _, _)| score > best) {
+                    best = Some((score, charge, mono));
+                }
+            }
+        }
+
+        let (charge, mono) = match best {
+            Some((_, charge, mono)) => (charge, mono),
+            None => return,
+        };
+        if precursor.charge.is_none() {
+            precursor.charge = Some(charge);
+            refined.charge += 1;
+        }
+        if mono != selected {
+            let mz = mono + PROTON;
+            // Keep the isolation window in place
+            precursor.isolation_window = precursor.isolation_window.map(|window| {
+                let (lo, hi) = window.bounds(precursor.mz);
+                Tolerance::Da(lo - mz, hi - mz)
+            });
+            precursor.mz = mz;
+            refined.monoisotopic += 1;
+        }
+    }
+}
+
+/// Source of the retention time assigned to a processed spectrum
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum RtSource {
+    /// Scan start time, as reported by the instrument
+    #[default]
+    ScanStartTime,
+    /// Scan start time, corrected to the midpoint of the ion accumulation
+    /// (fill) period: `scan_start_time - ion_injection_time / 2`.
+    /// Reduces RT jitter caused by variable fill times on fast-scanning instruments
+    InjectionCorrected,
+}
+
+impl RtSource {
+    /// Retention time (in minutes) of `spectrum`
+    pub fn retention_time(&self, spectrum: &RawSpectrum) -> f32 {
+        match self {
+            RtSource::ScanStartTime => spectrum.scan_start_time,
+            RtSource::InjectionCorrected => {
+                // Ion injection time is reported in milliseconds
+                let fill = spectrum.ion_injection_time.max(0.0) / 60_000.0;
+                (spectrum.scan_start_time - fill / 2.0).max(0.0)
+            }
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Precursor {
     pub mz: f32,
     pub intensity: Option<f32>,
@@ -52,9 +667,11 @@ pub struct Precursor {
     // pub scan: Option<usize>,
     pub spectrum_ref: Option<String>,
     pub isolation_window: Option<Tolerance>,
+    /// Inverse reduced ion mobility (1/K0), for ion mobility spectrometry data
+    pub ion_mobility: Option<f32>,
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct ProcessedSpectrum {
     /// MSn level
     pub level: u8,
@@ -72,6 +689,14 @@ pub struct ProcessedSpectrum {
     pub peaks: Vec<Peak>,
     /// Total ion current
     pub total_ion_current: f32,
+    /// Noise level at each raw peak, stored as [`Peak`]s sorted by mass, if
+    /// noise was read for signal-to-noise quantification. Empty otherwise
+    pub noise: Vec<Peak>,
+    /// Inverse reduced ion mobility (1/K0) of the precursor, if reported, or
+    /// else of the scan, for ion mobility spectrometry data
+    pub ion_mobility: Option<f32>,
+    /// FAIMS compensation voltage, in volts, if reported
+    pub compensation_voltage: Option<f32>,
 }
 
 #[derive(Default, Debug, Clone)]
@@ -89,7 +714,7 @@ pub struct RawSpectrum {
     pub representation: Representation,
     /// Scan start time in minutes
     pub scan_start_time: f32,
-    /// Ion injection time
+    /// Ion injection time in milliseconds
     pub ion_injection_time: f32,
     /// Total ion current
     pub total_ion_current: f32,
@@ -97,6 +722,18 @@ pub struct RawSpectrum {
     pub mz: Vec<f32>,
     /// Intensity array
     pub intensity: Vec<f32>,
+    /// Noise level of each peak (e.g. Orbitrap noise arrays), if requested
+    /// for signal-to-noise quantification. Empty otherwise
+    pub noise: Vec<f32>,
+    /// Inverse reduced ion mobility (1/K0) at which the scan was acquired,
+    /// e.g. for TIMS scans, if reported
+    pub ion_mobility: Option<f32>,
+    /// Inverse reduced ion mobility (1/K0) of each peak (e.g. PASEF spectra
+    /// summed over a mobility range), if reported. Empty otherwise
+    pub mobility: Vec<f32>,
+    /// FAIMS compensation voltage, in volts, at which the scan was acquired,
+    /// if reported
+    pub compensation_voltage: Option<f32>,
 }
 
 impl RawSpectrum {
@@ -108,6 +745,22 @@ impl RawSpectrum {
             ..Default::default()
         }
     }
+
+    /// Ion mobility of the spectrum: the reported scan ion mobility, or else
+    /// the intensity-weighted mean ion mobility of its peaks
+    pub fn mean_ion_mobility(&self) -> Option<f32> {
+        if self.ion_mobility.is_some() || self.mobility.len() != self.intensity.len() {
+            return self.ion_mobility;
+        }
+        let (sum, total) = self
+            .mobility
+            .iter()
+            .zip(&self.intensity)
+            .fold((0.0, 0.0), |(sum, total), (&mobility, &intensity)| {
+                (sum + mobility * intensity, total + intensity)
+            });
+        (total > 0.0).then(|| sum / total)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -174,6 +827,72 @@ pub fn select_most_intense_peak(
 //     spectra.get(idx)
 // }
 
+/// Maximum ratio of the m/z spacings on either side of a profile maximum for
+/// a Gaussian fit across its neighbours
+const MAX_PROFILE_GAP: f64 = 4.0;
+
+/// Pick the peaks of a profile `spectrum` in place, which must be sorted by
+/// m/z. Each local intensity maximum is replaced by a single centroid, at the
+/// apex of a Gaussian fit to the maximum and its two neighbouring points (a
+/// parabola through their log intensities). Maxima at the edge of the
+/// spectrum, next to a zero-intensity point or to a gap in the profile, are
+/// kept as they are
+pub fn centroid(spectrum: &mut RawSpectrum) {
+    let mz = &spectrum.mz;
+    let int = &spectrum.intensity;
+    let mut centroids = Vec::new();
+    for idx in 0..mz.len() {
+        let left = idx.checked_sub(1).map(|i| int[i]);
+        let right = int.get(idx + 1).copied();
+        // Strictly above the previous point, so that plateaus are picked once
+        if int[idx] <= 0.0 || left.map_or(false, |l| l >= int[idx]) {
+            continue;
+        }
+        if right.map_or(false, |r| r > int[idx]) {
+            continue;
+        }
+
+        let (mut apex_mz, mut apex_int) = (mz[idx], int[idx]);
+        if let (Some(l), Some(r)) = (left, right) {
+            let x0 = (mz[idx - 1] - mz[idx]) as f64;
+            let x2 = (mz[idx + 1] - mz[idx]) as f64;
+            // Points separated by a gap (e.g. removed zero-intensity points)
+            // are not part of the same peak
+            let gap = x2 > -x0 * MAX_PROFILE_GAP || -x0 > x2 * MAX_PROFILE_GAP;
+            if l > 0.0 && r > 0.0 && !gap {
+                let y1 = (int[idx] as f64).ln();
+                let y0 = (l as f64).ln() - y1;
+                let y2 = (r as f64).ln() - y1;
+                // y = a * x^2 + b * x + y1, relative to the maximum
+                let a = (y2 / x2 - y0 / x0) / (x2 - x0);
+                let b = y2 / x2 - a * x2;
+                if a < 0.0 {
+                    let offset = (-b / (2.0 * a)).clamp(x0, x2);
+                    apex_mz = mz[idx] + offset as f32;
+                    apex_int = (y1 + b * offset + a * offset * offset).exp() as f32;
+                }
+            }
+        }
+        centroids.push((idx, apex_mz, apex_int));
+    }
+
+    if !spectrum.noise.is_empty() {
+        spectrum.noise = centroids
+            .iter()
+            .map(|&(idx, _, _)| spectrum.noise.get(idx).copied().unwrap_or_default())
+            .collect();
+    }
+    if !spectrum.mobility.is_empty() {
+        spectrum.mobility = centroids
+            .iter()
+            .map(|&(idx, _, _)| spectrum.mobility.get(idx).copied().unwrap_or_default())
+            .collect();
+    }
+    spectrum.mz = centroids.iter().map(|&(_, mz, _)| mz).collect();
+    spectrum.intensity = centroids.iter().map(|&(_, _, int)| int).collect();
+    spectrum.representation = Representation::Centroid;
+}
+
 /// Deisotope a set of peaks by attempting to find C13 peaks under a given `ppm` tolerance
 pub fn deisotope(mz: &[f32], int: &[f32], max_charge: u8, ppm: f32) -> Vec<Deisotoped> {
     let mut peaks = mz
@@ -218,6 +937,120 @@ pub fn deisotope(mz: &[f32], int: &[f32], max_charge: u8, ppm: f32) -> Vec<Deiso
     peaks
 }
 
+/// Number of isotopic peaks of an averagine envelope, including the
+/// monoisotopic peak
+pub(crate) const AVERAGINE_ISOTOPES: usize = 3;
+
+/// Averagine residue (C4.9384 H7.7583 N1.3577 O1.4773 S0.0417): average mass,
+/// and number of carbon and sulfur atoms
+const AVERAGINE_MASS: f32 = 111.1254;
+const AVERAGINE_CARBON: f32 = 4.9384;
+const AVERAGINE_SULFUR: f32 = 0.0417;
+
+/// Minimum cosine similarity between an observed envelope and the averagine
+/// isotope distribution
+const MIN_ENVELOPE_FIT: f32 = 0.9;
+
+/// Relative intensities of the isotopic peaks of an averagine peptide with
+/// neutral monoisotopic mass `mass`
+pub(crate) fn averagine_isotopes(mass: f32) -> [f32; AVERAGINE_ISOTOPES] {
+    let residues = mass / AVERAGINE_MASS;
+    crate::isotopes::peptide_isotopes(
+        (residues * AVERAGINE_CARBON).round() as u16,
+        (residues * AVERAGINE_SULFUR).round() as u16,
+    )
+}
+
+/// Deisotope a set of peaks (sorted by m/z) by fitting isotopic envelopes of
+/// charge 1 to `max_charge` against the averagine isotope distribution,
+/// under a given `ppm` tolerance.
+///
+/// Peaks are considered from most to least intense. Each peak that is not yet
+/// part of an envelope is tried as every isotope of an envelope of every
+/// charge: the envelope explaining the most intensity, weighted by its fit,
+/// is kept. Its monoisotopic peak is assigned the charge and the summed
+/// intensity of the envelope, and its other peaks link to it
+pub fn deisotope_averagine(mz: &[f32], int: &[f32], max_charge: u8, ppm: f32) -> Vec<Deisotoped> {
+    let mut peaks = mz
+        .iter()
+        .zip(int.iter())
+        .map(|(mz, int)| Deisotoped {
+            mz: *mz,
+            intensity: *int,
+            envelope: None,
+            charge: None,
+        })
+        .collect::<Vec<_>>();
+    let mut assigned = vec![false; peaks.len()];
+
+    // Most intense unassigned peak within `ppm` of `target`
+    let find = |target: f32, assigned: &[bool]| {
+        let tol = Tolerance::ppm_to_delta_mass(target, ppm);
+        let start = mz.partition_point(|&mz| mz < target - tol);
+        (start..mz.len())
+            .take_while(|&idx| mz[idx] <= target + tol)
+            .filter(|&idx| !assigned[idx])
+            .max_by(|&a, &b| int[a].total_cmp(&int[b]))
+    };
+
+    let mut order = (0..peaks.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| int[b].total_cmp(&int[a]).then_with(|| a.cmp(&b)));
+
+    for idx in order {
+        if assigned[idx] {
+            continue;
+        }
+        let mut best: Option<(f32, u8, [Option<usize>; AVERAGINE_ISOTOPES])> = None;
+        for charge in 1..=max_charge {
+            let spacing = NEUTRON / charge as f32;
+            for isotope in 0..AVERAGINE_ISOTOPES {
+                let mono_mz = mz[idx] - isotope as f32 * spacing;
+                let mut envelope = [None; AVERAGINE_ISOTOPES];
+                for (n, slot) in envelope.iter_mut().enumerate() {
+                    *slot = match n == isotope {
+                        true => Some(idx),
+                        false => find(mono_mz + n as f32 * spacing, &assigned),
+                    };
+                }
+                // At least the monoisotopic peak, and one more isotope
+                if envelope[0].is_none() || envelope.iter().flatten().count() < 2 {
+                    continue;
+                }
+
+                let theoretical = averagine_isotopes((mono_mz - PROTON) * charge as f32);
+                let observed = envelope.map(|peak| peak.map_or(0.0, |peak| int[peak]));
+                let dot = observed
+                    .iter()
+                    .zip(theoretical.iter())
+                    .map(|(a, b)| a * b)
+                    .sum::<f32>();
+                let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let fit = dot / (norm(&observed) * norm(&theoretical));
+                if fit < MIN_ENVELOPE_FIT {
+                    continue;
+                }
+                let score = fit * observed.iter().sum::<f32>();
+                if best.map_or(true, |(best, _, _)| score > best) {
+                    best = Some((score, charge, envelope));
+                }
+            }
+        }
+
+        if let Some((_, charge, envelope)) = best {
+            let mono = envelope[0].expect("envelopes have a monoisotopic peak");
+            for peak in envelope.iter().flatten().copied() {
+                assigned[peak] = true;
+                peaks[peak].charge = Some(charge);
+                if peak != mono {
+                    peaks[mono].intensity += int[peak];
+                    peaks[peak].envelope = Some(mono);
+                }
+            }
+        }
+    }
+    peaks
+}
+
 /// Path compression of isotopic envelope links
 pub fn path_compression(peaks: &mut [Deisotoped]) {
     for idx in 0..peaks.len() {
@@ -243,6 +1076,13 @@ impl ProcessedSpectrum {
         let (lo, hi) = precursor.isolation_window?.bounds(precursor.mz - PROTON);
         Some(mz >= lo && mz <= hi)
     }
+
+    /// Width (m/z) of the isolation window of the first precursor, if known
+    pub fn isolation_width(&self) -> Option<f32> {
+        let precursor = self.precursors.first()?;
+        let (lo, hi) = precursor.isolation_window?.bounds(precursor.mz);
+        Some(hi - lo)
+    }
 }
 
 impl SpectrumProcessor {
@@ -264,18 +1104,16 @@ impl SpectrumProcessor {
             min_fragment_mz,
             max_fragment_mz,
             deisotope,
+            deisotope_method: DeisotopeMethod::default(),
+            rt_source: RtSource::default(),
+            peak_cleanup: PeakCleanup::default(),
+            centroid: Centroiding::default(),
+            peak_filter: None,
+            peak_mask: None,
         }
     }
 
     fn process_ms2(&self, should_deisotope: bool, spectrum: &RawSpectrum) -> Vec<Peak> {
-        if spectrum.representation != Representation::Centroid {
-            // Panic, because there's really nothing we can do with profile data
-            panic!(
-                "Scan {} contains profile data! Please convert to centroid",
-                spectrum.id
-            );
-        }
-
         // If there is no precursor charge from the mzML file, then deisotope fragments up to z=3
         let charge = spectrum
             .precursors
@@ -284,14 +1122,21 @@ impl SpectrumProcessor {
             .unwrap_or(3);
 
         if should_deisotope {
-            let mut peaks = deisotope(&spectrum.mz, &spectrum.intensity, charge, 10.0);
+            let mut peaks = match self.deisotope_method {
+                DeisotopeMethod::Simple => {
+                    deisotope(&spectrum.mz, &spectrum.intensity, charge, 10.0)
+                }
+                DeisotopeMethod::Averagine => {
+                    deisotope_averagine(&spectrum.mz, &spectrum.intensity, charge, 10.0)
+                }
+            };
             peaks.sort_unstable_by(|a, b| {
                 b.intensity
                     .total_cmp(&a.intensity)
                     .then_with(|| a.mz.total_cmp(&b.mz))
             });
 
-            peaks
+            let peaks = peaks
                 .into_iter()
                 .filter(|peak| {
                     peak.envelope.is_none()
@@ -305,9 +1150,15 @@ impl SpectrumProcessor {
                         mass,
                         intensity: peak.intensity,
                     }
-                })
-                .take(self.take_top_n)
-                .collect::<Vec<Peak>>()
+                });
+            match self.peak_filter {
+                Some(filter) => {
+                    let mut peaks = peaks.collect::<Vec<_>>();
+                    filter.apply(&mut peaks);
+                    peaks
+                }
+                None => peaks.take(self.take_top_n).collect::<Vec<Peak>>(),
+            }
         } else {
             let mut peaks = spectrum
                 .mz
@@ -319,13 +1170,45 @@ impl SpectrumProcessor {
                     Peak { mass, intensity }
                 })
                 .collect::<Vec<_>>();
-            crate::heap::bounded_min_heapify(&mut peaks, self.take_top_n);
-            peaks.truncate(self.take_top_n);
+            self.select_peaks(&mut peaks);
             peaks
         }
     }
 
+    /// Keep the MS2 peaks selected by `peak_filter`, or the `take_top_n` most
+    /// intense ones. Peaks are left unsorted
+    pub fn select_peaks(&self, peaks: &mut Vec<Peak>) {
+        match self.peak_filter {
+            Some(filter) => filter.apply(peaks),
+            None => {
+                crate::heap::bounded_min_heapify(peaks, self.take_top_n);
+                peaks.truncate(self.take_top_n);
+            }
+        }
+    }
+
     pub fn process(&self, spectrum: RawSpectrum) -> ProcessedSpectrum {
+        self.process_with_cleanup(spectrum).0
+    }
+
+    /// Process `spectrum`, also returning the number of peaks removed by
+    /// [`PeakCleanup`], and whether it was centroided
+    pub fn process_with_cleanup(
+        &self,
+        mut spectrum: RawSpectrum,
+    ) -> (ProcessedSpectrum, RemovedPeaks) {
+        // Centroid before cleanup, so that the zero-intensity points of
+        // profile data still separate neighbouring peaks
+        let centroided = spectrum.ms_level == 2 && self.centroid.applies(&spectrum);
+        if centroided {
+            centroid(&mut spectrum);
+        }
+        let mut removed = self.peak_cleanup.clean(&mut spectrum);
+        removed.centroided = centroided as usize;
+        if let (2, Some(mask)) = (spectrum.ms_level, &self.peak_mask) {
+            removed.masked = mask.apply(&mut spectrum);
+        }
+        let scan_start_time = self.rt_source.retention_time(&spectrum);
         let mut peaks = match spectrum.ms_level {
             2 => self.process_ms2(self.deisotope, &spectrum),
             _ => spectrum
@@ -342,16 +1225,36 @@ impl SpectrumProcessor {
         peaks.sort_by(|a, b| a.mass.total_cmp(&b.mass));
         let total_ion_current = peaks.iter().map(|peak| peak.intensity).sum::<f32>();
 
-        ProcessedSpectrum {
+        let mut noise = spectrum
+            .mz
+            .iter()
+            .zip(spectrum.noise.iter())
+            .map(|(&mz, &intensity)| Peak {
+                mass: mz - PROTON,
+                intensity,
+            })
+            .collect::<Vec<_>>();
+        noise.sort_by(|a, b| a.mass.total_cmp(&b.mass));
+
+        let ion_mobility = spectrum
+            .precursors
+            .first()
+            .and_then(|precursor| precursor.ion_mobility)
+            .or_else(|| spectrum.mean_ion_mobility());
+        let processed = ProcessedSpectrum {
             level: spectrum.ms_level,
             id: spectrum.id,
             file_id: spectrum.file_id,
-            scan_start_time: spectrum.scan_start_time,
+            scan_start_time,
             ion_injection_time: spectrum.ion_injection_time,
             precursors: spectrum.precursors,
             peaks,
             total_ion_current,
-        }
+            noise,
+            ion_mobility,
+            compensation_voltage: spectrum.compensation_voltage,
+        };
+        (processed, removed)
     }
 }
 
@@ -359,6 +1262,211 @@ impl SpectrumProcessor {
 mod test {
     use super::*;
 
+    #[test]
+    fn refine_precursors() {
+        // Averagine envelope of a 1500 Da precursor at charge 2, and an
+        // unrelated peak
+        let spacing = NEUTRON / 2.0;
+        let mut peaks = averagine_isotopes(1500.0)
+            .iter()
+            .enumerate()
+            .map(|(n, int)| Peak {
+                mass: 750.0 + n as f32 * spacing,
+                intensity: int * 1000.0,
+            })
+            .collect::<Vec<_>>();
+        peaks.push(Peak {
+            mass: 900.0,
+            intensity: 500.0,
+        });
+        let ms1 = ProcessedSpectrum {
+            level: 1,
+            peaks,
+            ..Default::default()
+        };
+        let ms2 = |mz: f32, charge| ProcessedSpectrum {
+            level: 2,
+            precursors: vec![Precursor {
+                mz: mz + PROTON,
+                charge,
+                isolation_window: Some(Tolerance::Da(-1.0, 1.0)),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let refinement = PrecursorRefinement {
+            tolerance: Tolerance::Ppm(-10.0, 10.0),
+            precursor_charge: (2, 4),
+        };
+        let mut refined = RefinedPrecursors::default();
+
+        // Selected on the first isotope
+        let mut spectrum = ms2(750.0 + spacing, Some(2));
+        refinement.refine(&ms1, &mut spectrum, &mut refined);
+        let precursor = &spectrum.precursors[0];
+        assert!((precursor.mz - PROTON - 750.0).abs() < 1E-3);
+        let (lo, hi) = precursor.isolation_window.unwrap().bounds(precursor.mz);
+        assert!((lo - (749.0 + spacing + PROTON)).abs() < 1E-3);
+        assert!((hi - (751.0 + spacing + PROTON)).abs() < 1E-3);
+        assert_eq!(
+            refined,
+            RefinedPrecursors {
+                monoisotopic: 1,
+                charge: 0
+            }
+        );
+
+        // Unknown charge, selected on the second isotope
+        let mut spectrum = ms2(750.0 + 2.0 * spacing, None);
+        refinement.refine(&ms1, &mut spectrum, &mut refined);
+        assert!((spectrum.precursors[0].mz - PROTON - 750.0).abs() < 1E-3);
+        assert_eq!(spectrum.precursors[0].charge, Some(2));
+        assert_eq!(
+            refined,
+            RefinedPrecursors {
+                monoisotopic: 2,
+                charge: 1
+            }
+        );
+
+        // Correct precursors, and precursors without an envelope, are kept
+        for (mz, charge) in [(750.0, Some(2)), (900.0, None), (800.0, None)] {
+            let mut spectrum = ms2(mz, charge);
+            refinement.refine(&ms1, &mut spectrum, &mut refined);
+            assert_eq!(spectrum.precursors[0].mz, mz + PROTON);
+            assert_eq!(spectrum.precursors[0].charge, charge);
+        }
+        assert_eq!(refined.monoisotopic + refined.charge, 3);
+    }
+
+    #[test]
+    fn precursor_guards() {
+        let spectrum = |level, mz, charge| ProcessedSpectrum {
+            level,
+            precursors: vec![Precursor {
+                mz: mz + PROTON,
+                charge,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut spectra = vec![
+            spectrum(2, 800.0, Some(2)),
+            // Intact protein: 10 kDa
+            spectrum(2, 1000.0, Some(10)),
+            spectrum(2, 1500.0, Some(4)),
+            // Charge unknown, mass can't be checked
+            spectrum(2, 1500.0, None),
+            spectrum(1, 1500.0, Some(4)),
+        ];
+
+        assert_eq!(
+            PrecursorGuards::default().apply(&mut spectra),
+            SkippedPrecursors::default()
+        );
+        assert_eq!(spectra.len(), 5);
+
+        let guards = PrecursorGuards {
+            max_precursor_mass: Some(5000.0),
+            max_precursor_charge: Some(6),
+            ..Default::default()
+        };
+        let skipped = guards.apply(&mut spectra);
+        assert_eq!(
+            skipped,
+            SkippedPrecursors {
+                mass: 1,
+                charge: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(spectra.len(), 3);
+        assert!(spectra
+            .iter()
+            .all(|s| s.level == 1 || s.precursors[0].charge != Some(4)));
+    }
+
+    #[test]
+    fn scan_window() {
+        assert_eq!(
+            scan_number("controllerType=0 controllerNumber=1 scan=30069"),
+            Some(30069)
+        );
+        assert_eq!(scan_number("merged=12 scan=7 frame=3"), Some(7));
+        assert_eq!(scan_number("1234"), Some(1234));
+        assert_eq!(scan_number("sample=1 period=1 cycle=5 experiment=2"), None);
+
+        let window = ScanWindow::default();
+        assert!(!window.is_enabled());
+        assert!(window.contains("cycle=5", 100.0));
+        assert!(!window.is_past("scan=100000", 1000.0));
+
+        let window = ScanWindow {
+            scan_range: Some((100, 200)),
+            rt_range: Some((10.0, 20.0)),
+        };
+        assert!(window.contains("scan=100", 10.0));
+        assert!(window.contains("scan=200", 20.0));
+        assert!(!window.contains("scan=99", 15.0));
+        assert!(!window.contains("scan=150", 20.5));
+        assert!(!window.contains("cycle=150", 15.0));
+        assert!(!window.is_past("scan=150", 15.0));
+        assert!(window.is_past("scan=201", 15.0));
+        assert!(window.is_past("cycle=150", 20.5));
+    }
+
+    #[test]
+    fn charge_filter() {
+        let spectrum = |level, charge| ProcessedSpectrum {
+            level,
+            precursors: vec![Precursor {
+                mz: 500.0,
+                charge,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let spectra = vec![
+            spectrum(2, Some(1)),
+            spectrum(2, Some(3)),
+            spectrum(2, Some(6)),
+            spectrum(2, None),
+            spectrum(1, Some(6)),
+        ];
+
+        let mut guards = PrecursorGuards {
+            precursor_charge: (2, 4),
+            ..Default::default()
+        };
+        assert!(!guards.is_enabled());
+
+        guards.charge_filter = ChargeFilter::Skip;
+        let mut skipped = spectra.clone();
+        assert_eq!(
+            guards.apply(&mut skipped),
+            SkippedPrecursors {
+                charge_range: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(skipped.len(), 3);
+
+        guards.charge_filter = ChargeFilter::Reassign;
+        let mut reassigned = spectra;
+        assert_eq!(
+            guards.apply(&mut reassigned),
+            SkippedPrecursors {
+                reassigned: 2,
+                ..Default::default()
+            }
+        );
+        let charges = reassigned
+            .iter()
+            .map(|s| s.precursors[0].charge)
+            .collect::<Vec<_>>();
+        assert_eq!(charges, vec![None, Some(3), None, None, Some(6)]);
+    }
+
     #[test]
     fn test_deisotope() {
         let mut mz = [
@@ -483,4 +1591,257 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn deisotope_averagine_envelopes() {
+        // Isotopic envelope of a fragment of neutral mass `mass` at `charge`
+        let envelope = |mass: f32, charge: u8, scale: f32| {
+            let mono = mass / charge as f32 + PROTON;
+            averagine_isotopes(mass)
+                .into_iter()
+                .enumerate()
+                .map(move |(n, int)| (mono + n as f32 * NEUTRON / charge as f32, int * scale))
+        };
+        let mut peaks = envelope(500.0, 1, 100.0)
+            .chain(envelope(1500.0, 2, 80.0))
+            .chain(envelope(4500.0, 3, 60.0))
+            .chain([(600.0, 30.0)])
+            .collect::<Vec<_>>();
+        peaks.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (mz, int): (Vec<f32>, Vec<f32>) = peaks.into_iter().unzip();
+
+        // The second isotope is the most intense peak of the heaviest fragment
+        let heavy = averagine_isotopes(4500.0);
+        assert!(heavy[1] > heavy[0]);
+
+        let peaks = deisotope_averagine(&mz, &int, 3, 5.0);
+        let mono = peaks
+            .iter()
+            .filter(|peak| peak.envelope.is_none())
+            .map(|peak| {
+                (
+                    (peak.mz - PROTON) * peak.charge.unwrap_or(1) as f32,
+                    peak.charge,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(mono.len(), 4, "{:?}", mono);
+        for (expected, charge) in [
+            (500.0, Some(1)),
+            (598.9927, None),
+            (1500.0, Some(2)),
+            (4500.0, Some(3)),
+        ] {
+            assert!(
+                mono.iter()
+                    .any(|(mass, z)| (mass - expected).abs() < 0.01 && *z == charge),
+                "{} {:?}",
+                expected,
+                mono
+            );
+        }
+
+        // Envelope intensities are summed into the monoisotopic peak
+        let total = int.iter().sum::<f32>();
+        let kept = peaks
+            .iter()
+            .filter(|peak| peak.envelope.is_none())
+            .map(|peak| peak.intensity)
+            .sum::<f32>();
+        assert!((total - kept).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rt_source() {
+        let spectrum = RawSpectrum {
+            ms_level: 1,
+            scan_start_time: 10.0,
+            ion_injection_time: 60.0,
+            ..Default::default()
+        };
+        let mut sp = SpectrumProcessor::new(100, 0.0, 1500.0, false);
+        assert_eq!(sp.process(spectrum.clone()).scan_start_time, 10.0);
+
+        sp.rt_source = RtSource::InjectionCorrected;
+        let corrected = sp.process(spectrum).scan_start_time;
+        assert!((corrected - (10.0 - 0.0005)).abs() < 1E-6);
+    }
+
+    #[test]
+    fn peak_cleanup() {
+        let spectrum = RawSpectrum {
+            mz: vec![300.0, 200.0, 300.0, 400.0, 500.0, 300.0],
+            intensity: vec![2.0, 0.0, 5.0, 1.0, 0.0, 1.0],
+            mobility: vec![1.0, 1.1, 1.2, 1.3, 1.4, 1.5],
+            ..Default::default()
+        };
+
+        let mut keep = spectrum.clone();
+        let cleanup = PeakCleanup {
+            remove_zero_intensity: false,
+            duplicate_mz: DuplicateMz::Keep,
+        };
+        assert_eq!(cleanup.clean(&mut keep), RemovedPeaks::default());
+        assert_eq!(keep.mz, spectrum.mz);
+
+        let mut most_intense = spectrum.clone();
+        assert_eq!(
+            PeakCleanup::default().clean(&mut most_intense),
+            RemovedPeaks {
+                zero_intensity: 2,
+                duplicate_mz: 2,
+                centroided: 0,
+                masked: 0,
+            }
+        );
+        assert_eq!(most_intense.mz, vec![300.0, 400.0]);
+        assert_eq!(most_intense.intensity, vec![5.0, 1.0]);
+        // Per-peak ion mobility stays aligned with the kept peaks
+        assert_eq!(most_intense.mobility, vec![1.0, 1.3]);
+        let mean = most_intense.mean_ion_mobility().unwrap();
+        assert!((mean - 1.05).abs() < 1E-6, "{}", mean);
+
+        let mut summed = spectrum;
+        let cleanup = PeakCleanup {
+            remove_zero_intensity: false,
+            duplicate_mz: DuplicateMz::Sum,
+        };
+        assert_eq!(cleanup.clean(&mut summed).duplicate_mz, 2);
+        assert_eq!(summed.mz, vec![200.0, 300.0, 400.0, 500.0]);
+        assert_eq!(summed.intensity, vec![0.0, 8.0, 1.0, 0.0]);
+        assert_eq!(summed.mobility, vec![1.1, 1.0, 1.3, 1.4]);
+    }
+
+    #[test]
+    fn peak_filter_windows() {
+        // Weak low-mass ions, and intense peaks above 500 Da
+        let mz = [
+            150.0, 160.0, 170.0, 180.0, 510.0, 520.0, 530.0, 540.0, 550.0,
+        ];
+        let intensity = [10.0, 20.0, 5.0, 15.0, 1000.0, 900.0, 800.0, 700.0, 600.0];
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            representation: Representation::Centroid,
+            mz: mz.iter().map(|mz| mz + PROTON).collect(),
+            intensity: intensity.to_vec(),
+            ..Default::default()
+        };
+        let masses = |processor: &SpectrumProcessor| {
+            processor
+                .process(spectrum.clone())
+                .peaks
+                .iter()
+                .map(|peak| peak.mass.round())
+                .collect::<Vec<_>>()
+        };
+
+        // A global top-4 cut only keeps the intense peaks
+        let mut processor = SpectrumProcessor::new(4, 0.0, 2000.0, false);
+        assert_eq!(masses(&processor), vec![510.0, 520.0, 530.0, 540.0]);
+
+        processor.peak_filter = Some(PeakFilter {
+            window: 100.0,
+            peaks_per_window: 2,
+            min_relative_intensity: 0.01,
+        });
+        assert_eq!(masses(&processor), vec![160.0, 180.0, 510.0, 520.0]);
+
+        processor.deisotope = true;
+        assert_eq!(masses(&processor), vec![160.0, 180.0, 510.0, 520.0]);
+    }
+
+    #[test]
+    fn mask_reporter_and_precursor_peaks() {
+        let precursor = 500.5;
+        let mz = vec![
+            126.1277,
+            131.1382,
+            300.0,
+            precursor - 18.010565 / 2.0,
+            precursor,
+            precursor + NEUTRON / 2.0,
+            700.0,
+        ];
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            representation: Representation::Centroid,
+            precursors: vec![Precursor {
+                mz: precursor,
+                charge: Some(2),
+                ..Default::default()
+            }],
+            intensity: vec![100.0; mz.len()],
+            mz,
+            ..Default::default()
+        };
+        let mask = PeakMask {
+            reporter_region: Some((125.5, 135.5)),
+            precursor: true,
+            tolerance: Tolerance::Da(-0.02, 0.02),
+        };
+
+        let mut masked = spectrum.clone();
+        assert_eq!(mask.apply(&mut masked), 5);
+        assert_eq!(masked.mz, vec![300.0, 700.0]);
+        assert_eq!(masked.intensity.len(), 2);
+
+        // Only the selected peak and its isotopes, if the charge is unknown
+        let mut masked = spectrum.clone();
+        masked.precursors[0].charge = None;
+        let mask = PeakMask {
+            reporter_region: None,
+            ..mask
+        };
+        assert_eq!(mask.apply(&mut masked), 1);
+
+        let mut processor = SpectrumProcessor::new(150, 0.0, 2000.0, false);
+        processor.peak_mask = Some(mask);
+        let (processed, removed) = processor.process_with_cleanup(spectrum);
+        assert_eq!(processed.peaks.len(), 4);
+        assert_eq!(removed.masked, 3);
+    }
+
+    #[test]
+    fn centroid_profile_peaks() {
+        // Two Gaussian peaks, sampled every 2 mDa
+        let gaussian = |mz: f32, center: f32, height: f32| {
+            height * (-(mz - center).powi(2) / (2.0 * 0.005f32.powi(2))).exp()
+        };
+        let mz = (0..400)
+            .map(|i| 499.9 + i as f32 * 0.002)
+            .collect::<Vec<_>>();
+        let spectrum = RawSpectrum {
+            ms_level: 2,
+            intensity: mz
+                .iter()
+                .map(|&mz| gaussian(mz, 500.0013, 1000.0) + gaussian(mz, 500.5, 300.0))
+                .collect(),
+            mz,
+            ..Default::default()
+        };
+
+        let mut centroided = spectrum.clone();
+        centroid(&mut centroided);
+        assert_eq!(centroided.representation, Representation::Centroid);
+        assert_eq!(centroided.mz.len(), 2, "{:?}", centroided.mz);
+        for (idx, (mz, height)) in [(500.0013, 1000.0), (500.5, 300.0)].iter().enumerate() {
+            assert!(
+                (centroided.mz[idx] - mz).abs() < 1E-4,
+                "{:?}",
+                centroided.mz
+            );
+            assert!((centroided.intensity[idx] / height - 1.0).abs() < 0.01);
+        }
+
+        // Profile spectra are centroided, unless disabled
+        let mut processor = SpectrumProcessor::new(150, 0.0, 2000.0, false);
+        let (processed, removed) = processor.process_with_cleanup(spectrum.clone());
+        assert_eq!(processed.peaks.len(), 2);
+        assert_eq!(removed.centroided, 1);
+
+        processor.centroid = Centroiding::Never;
+        let (processed, removed) = processor.process_with_cleanup(spectrum);
+        assert!(processed.peaks.len() > 100);
+        assert_eq!(removed.centroided, 0);
+    }
 }
@@ -1,22 +1,26 @@
 //! TMT quantification
 #![allow(clippy::excessive_precision)]
-#![allow(unused_imports)]
-use crate::database::binary_search_slice;
+use crate::database::{binary_search_slice, IndexedDatabase};
 use crate::ion_series::{IonSeries, Kind};
-use crate::mass::{Tolerance, H2O, NH3, PROTON};
+use crate::mass::{ResidueMasses, Tolerance, H2O, NH3, PROTON};
+use crate::par::prelude::*;
 use crate::peptide::Peptide;
-use crate::scoring::{Feature, Scorer};
+use crate::rollup::ProteinRollup;
+use crate::scoring::{max_fragment_charge, Feature};
 use crate::spectrum::{self, Peak, Precursor, ProcessedSpectrum};
-use rayon::prelude::*;
+use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum Isobaric {
     Tmt6,
     Tmt10,
     Tmt11,
     Tmt16,
     Tmt18,
+    Itraq4,
+    Itraq8,
     User(Vec<f32>),
 }
 
@@ -29,35 +33,53 @@ impl Isobaric {
             Isobaric::Tmt11 => &TMT11PLEX,
             Isobaric::Tmt16 => &TMT18PLEX[0..16],
             Isobaric::Tmt18 => &TMT18PLEX,
+            Isobaric::Itraq4 => &ITRAQ8PLEX[1..5],
+            Isobaric::Itraq8 => &ITRAQ8PLEX,
             Isobaric::User(labels) => labels,
         }
     }
 
+    /// Return the name of each reporter ion channel, e.g. `127N`. User-defined
+    /// reporter ions are numbered from 1
+    pub fn channel_names(&self) -> Vec<String> {
+        let names: &[&str] = match self {
+            Isobaric::Tmt6 => &TMT6PLEX_CHANNELS,
+            Isobaric::Tmt10 => &TMT10PLEX_CHANNELS,
+            Isobaric::Tmt11 => &TMT18PLEX_CHANNELS[0..11],
+            Isobaric::Tmt16 => &TMT18PLEX_CHANNELS[0..16],
+            Isobaric::Tmt18 => &TMT18PLEX_CHANNELS,
+            Isobaric::Itraq4 => &ITRAQ8PLEX_CHANNELS[1..5],
+            Isobaric::Itraq8 => &ITRAQ8PLEX_CHANNELS,
+            Isobaric::User(labels) => {
+                return (1..=labels.len()).map(|idx| idx.to_string()).collect()
+            }
+        };
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
     /// Return the monoisotopic mass of tag
     pub fn modification_mass(&self) -> Option<f32> {
         match self {
             Isobaric::Tmt6 | Isobaric::Tmt10 | Isobaric::Tmt11 => Some(229.162932),
             Isobaric::Tmt16 => Some(304.2071),
             Isobaric::Tmt18 => Some(304.2135),
+            Isobaric::Itraq4 => Some(144.102063),
+            Isobaric::Itraq8 => Some(304.205360),
             Isobaric::User(_) => None,
         }
     }
 
-    /// Return a column name for each tag
+    /// Return a column name for each tag, e.g. `tmt_127N`
     pub fn headers(&self) -> Vec<String> {
-        match self {
-            Isobaric::User(v) => v
-                .iter()
-                .enumerate()
-                .map(|(idx, _)| format!("user_{}", idx + 1))
-                .collect(),
-            _ => self
-                .reporter_masses()
-                .iter()
-                .enumerate()
-                .map(|(idx, _)| format!("tmt_{}", idx + 1))
-                .collect(),
-        }
+        let prefix = match self {
+            Isobaric::Itraq4 | Isobaric::Itraq8 => "itraq",
+            Isobaric::User(_) => "user",
+            _ => "tmt",
+        };
+        self.channel_names()
+            .into_iter()
+            .map(|name| format!("{}_{}", prefix, name))
+            .collect()
     }
 }
 
@@ -70,120 +92,172 @@ pub struct Purity {
 
 /// Calculate SPS purity stats - which SPS precursor ions actually correspond
 /// to theoretical b/y ions, and percentage of total SPS precursor MS2 intensity
-/// that is explained by theoretical b/y ions
-// fn purity_of_match(
-//     precursors: &[Precursor],
-//     ms2: &ProcessedSpectrum,
-//     theoretical_peaks: &[Peak],
-//     max_charge: u8,
-//     fragment_tolerance: Tolerance,
-// ) -> Purity {
-//     let mut explained_intensity = 0.0;
-//     let mut interference = 0.0;
-//     let mut correct_precursors = 0;
-//     let mut incorrect_precursors = 0;
-
-//     for precursor in precursors {
-//         // Spurious SPS precursor introduced by MSConvert
-//         // https://github.com/ProteoWizard/pwiz/issues/2202
-//         if precursor.scan.unwrap_or(0) != ms2.scan {
-//             continue;
-//         }
-
-//         // This is really a m/z window, we haven't performed charge state deconvolution!
-//         // Select a window of MS2 peaks that have been sampled for MS3
-//         let isolation_tolerance = precursor
-//             .isolation_window
-//             .unwrap_or_else(|| Tolerance::Da(-0.5, 0.5));
-//         let isolation_window = isolation_tolerance.bounds(precursor.mz - PROTON);
-
-//         let (idx_lo, idx_hi) = binary_search_slice(
-//             &ms2.peaks,
-//             |peak, key| peak.mass.total_cmp(key),
-//             isolation_window.0,
-//             isolation_window.1,
-//         );
-
-//         // Create slice surrounding SPS peaks selected
-//         let window = &ms2.peaks[idx_lo..idx_hi];
-//         interference += window
-//             .iter()
-//             .filter(|peak| peak.mass >= isolation_window.0 && peak.mass <= isolation_window.1)
-//             .map(|peak| peak.intensity)
-//             .sum::<f32>();
-
-//         // Pick the most intense peak within the isolation window, and decide whether it is
-//         // assignable to the best candidate peptide
-//         let assigned_to_candidate = if let Some(best_peak) =
-//             spectrum::select_closest_peak(window, precursor.mz - PROTON, isolation_tolerance)
-//         {
-//             let mut correct = false;
-//             'outer: for charge in 1..max_charge {
-//                 for loss in [0.0, NH3, H2O] {
-//                     let mass = best_peak.mass * charge as f32 + loss;
-//                     if spectrum::select_closest_peak(theoretical_peaks, mass, fragment_tolerance)
-//                         .is_some()
-//                     {
-//                         correct = true;
-//                         interference -= best_peak.intensity;
-//                         explained_intensity += best_peak.intensity;
-
-//                         break 'outer;
-//                     }
-//                 }
-//             }
-//             correct
-//         } else {
-//             false
-//         };
-
-//         if assigned_to_candidate {
-//             correct_precursors += 1;
-//         } else {
-//             incorrect_precursors += 1;
-//         }
-//     }
-
-//     Purity {
-//         ratio: explained_intensity / (explained_intensity + interference),
-//         correct_precursors,
-//         incorrect_precursors,
-//     }
-// }
-
-// fn mk_theoretical(peptide: &Peptide) -> Vec<Peak> {
-//     let mut theoretical_peaks = IonSeries::new(peptide, Kind::B)
-//         .map(|ion| Peak {
-//             mass: ion.monoisotopic_mass,
-//             intensity: 0.0,
-//         })
-//         .collect::<Vec<_>>();
-
-//     if let Some(crate::mass::Residue::Mod('K', _)) = peptide.sequence.last() {
-//         theoretical_peaks.extend(IonSeries::new(peptide, Kind::Y).map(|ion| Peak {
-//             mass: ion.monoisotopic_mass,
-//             intensity: 0.0,
-//         }));
-//     }
-
-//     theoretical_peaks.sort_unstable_by(|a, b| a.mass.total_cmp(&b.mass));
-//     theoretical_peaks
-// }
-
-#[derive(Debug)]
-pub struct Quant<'ms3> {
-    /// Top hit for this MS3 spectrum
-    pub hit: Feature,
-    /// Top chimeric/co-fragmenting hit for this spectrum
-    pub chimera: Option<Feature>,
-    /// SPS precursor purity for the top hit
-    pub hit_purity: Purity,
-    /// SPS precursor purity for the chimeric hit
-    pub chimera_purity: Option<Purity>,
-    /// Quanitified TMT reporter ion intensities
-    pub intensities: Vec<Option<&'ms3 Peak>>,
-    /// MS3 spectrum
-    pub spectrum: &'ms3 ProcessedSpectrum,
+/// that is explained by theoretical b/y ions. Returns `None` if none of the
+/// SPS precursors were selected from `ms2`
+fn purity_of_match(
+    precursors: &[Precursor],
+    ms2: &ProcessedSpectrum,
+    theoretical_peaks: &[Peak],
+    max_charge: u8,
+    fragment_tolerance: Tolerance,
+) -> Option<Purity> {
+    let mut explained_intensity = 0.0;
+    let mut interference = 0.0;
+    let mut correct_precursors = 0;
+    let mut incorrect_precursors = 0;
+
+    for precursor in precursors {
+        // Spurious SPS precursor introduced by MSConvert
+        // https://github.com/ProteoWizard/pwiz/issues/2202
+        if precursor.spectrum_ref.as_deref() != Some(ms2.id.as_str()) {
+            continue;
+        }
+
+        // This is really a m/z window, we haven't performed charge state deconvolution!
+        // Select a window of MS2 peaks that have been sampled for MS3
+        let isolation_tolerance = precursor
+            .isolation_window
+            .unwrap_or(Tolerance::Da(-0.5, 0.5));
+        let isolation_window = isolation_tolerance.bounds(precursor.mz - PROTON);
+
+        let (idx_lo, idx_hi) = binary_search_slice(
+            &ms2.peaks,
+            |peak, key| peak.mass.total_cmp(key),
+            isolation_window.0,
+            isolation_window.1,
+        );
+
+        // Create slice surrounding SPS peaks selected
+        let window = &ms2.peaks[idx_lo..idx_hi];
+        interference += window
+            .iter()
+            .filter(|peak| peak.mass >= isolation_window.0 && peak.mass <= isolation_window.1)
+            .map(|peak| peak.intensity)
+            .sum::<f32>();
+
+        // Pick the most intense peak within the isolation window, and decide whether it is
+        // assignable to the best candidate peptide
+        let assigned_to_candidate = match spectrum::select_most_intense_peak(
+            window,
+            precursor.mz - PROTON,
+            isolation_tolerance,
+            None,
+        ) {
+            Some(best_peak) => {
+                let mut correct = false;
+                'outer: for charge in 1..max_charge {
+                    for loss in [0.0, NH3, H2O] {
+                        let mass = best_peak.mass * charge as f32 + loss;
+                        if spectrum::select_most_intense_peak(
+                            theoretical_peaks,
+                            mass,
+                            fragment_tolerance,
+                            None,
+                        )
+                        .is_some()
+                        {
+                            correct = true;
+                            interference -= best_peak.intensity;
+                            explained_intensity += best_peak.intensity;
+
+                            break 'outer;
+                        }
+                    }
+                }
+                correct
+            }
+            None => false,
+        };
+
+        if assigned_to_candidate {
+            correct_precursors += 1;
+        } else {
+            incorrect_precursors += 1;
+        }
+    }
+
+    if correct_precursors + incorrect_precursors == 0 {
+        return None;
+    }
+    let total = explained_intensity + interference;
+    Some(Purity {
+        ratio: if total > 0.0 {
+            explained_intensity / total
+        } else {
+            0.0
+        },
+        correct_precursors,
+        incorrect_precursors,
+    })
+}
+
+/// Theoretical fragments carrying an isobaric tag: all b ions, and y ions of
+/// peptides with a C-terminal lysine
+fn mk_theoretical(peptide: &Peptide, masses: ResidueMasses) -> Vec<Peak> {
+    let mut theoretical_peaks = IonSeries::with_residue_masses(peptide, Kind::B, masses)
+        .map(|ion| Peak {
+            mass: ion.monoisotopic_mass,
+            intensity: 0.0,
+        })
+        .collect::<Vec<_>>();
+
+    if peptide.sequence.last() == Some(&b'K') {
+        theoretical_peaks.extend(
+            IonSeries::with_residue_masses(peptide, Kind::Y, masses).map(|ion| Peak {
+                mass: ion.monoisotopic_mass,
+                intensity: 0.0,
+            }),
+        );
+    }
+
+    theoretical_peaks.sort_unstable_by(|a, b| a.mass.total_cmp(&b.mass));
+    theoretical_peaks
+}
+
+/// Assign the SPS precursor purity of each MS3 spectrum in `spectra` to the
+/// reporter ion intensities of its parent MS2 spectrum in `quant`. Purity is
+/// calculated against the rank-1 PSM of the parent MS2 spectrum in `features`
+pub fn assign_sps_purity(
+    quant: &mut [TmtQuant],
+    db: &IndexedDatabase,
+    spectra: &[ProcessedSpectrum],
+    features: &[Feature],
+    fragment_tolerance: Tolerance,
+) {
+    let ms2 = spectra
+        .iter()
+        .filter(|spectrum| spectrum.level == 2)
+        .map(|spectrum| ((spectrum.file_id, spectrum.id.as_str()), spectrum))
+        .collect::<FnvHashMap<_, _>>();
+    let hits = features
+        .iter()
+        .filter(|feat| feat.rank == 1)
+        .map(|feat| ((feat.file_id, &*feat.spec_id), feat))
+        .collect::<FnvHashMap<_, _>>();
+
+    let purity = spectra
+        .par_iter()
+        .filter(|spectrum| spectrum.level == 3)
+        .filter_map(|ms3| {
+            let parent = ms3.precursors.first()?.spectrum_ref.as_deref()?;
+            let ms2 = ms2.get(&(ms3.file_id, parent))?;
+            let hit = hits.get(&(ms3.file_id, parent))?;
+            let purity = purity_of_match(
+                &ms3.precursors,
+                ms2,
+                &mk_theoretical(&db[hit.peptide_idx], db.residue_masses),
+                max_fragment_charge(None, hit.charge),
+                fragment_tolerance,
+            )?;
+            Some(((ms3.file_id, parent), purity))
+        })
+        .collect::<FnvHashMap<_, _>>();
+
+    for q in quant {
+        q.sps_purity = purity
+            .get(&(q.file_id, q.spec_id.as_str()))
+            .map(|purity| purity.ratio);
+    }
 }
 
 /// Return a vector containing the peaks closest to the m/zs defined in
@@ -203,6 +277,35 @@ pub fn find_reporter_ions<'a>(
         .collect()
 }
 
+/// Noise level of the raw peak closest to `mass`
+fn noise_at(noise: &[Peak], mass: f32) -> Option<f32> {
+    let idx = noise.partition_point(|peak| peak.mass < mass);
+    [idx.checked_sub(1), Some(idx)]
+        .into_iter()
+        .flatten()
+        .filter_map(|idx| noise.get(idx))
+        .min_by(|a, b| (a.mass - mass).abs().total_cmp(&(b.mass - mass).abs()))
+        .map(|peak| peak.intensity)
+}
+
+/// Remove reporter ion peaks from an MS2 spectrum, so that they are not
+/// matched as fragment ions. Reporter ions must be quantified beforehand
+pub fn mask_reporter_ions(
+    spectrum: &mut ProcessedSpectrum,
+    isobaric_labels: &Isobaric,
+    isobaric_tolerance: Tolerance,
+) {
+    let windows = isobaric_labels
+        .reporter_masses()
+        .iter()
+        .map(|&label| isobaric_tolerance.bounds(label))
+        .collect::<Vec<_>>();
+    spectrum.peaks.retain(|peak| {
+        let mz = peak.mass + PROTON;
+        !windows.iter().any(|&(lo, hi)| mz >= lo && mz <= hi)
+    });
+}
+
 const TMT6PLEX: [f32; 6] = [
     126.127726, 127.124761, 128.134436, 129.131471, 130.141145, 131.138180,
 ];
@@ -218,83 +321,34 @@ const TMT18PLEX: [f32; 18] = [
     134.154565, 135.15160,
 ];
 
-/// Search MS/MS and quantify isobaric tag intensities from an SPS-MS3 spectrum
-///
-/// * `scorer`: used for searching/scoring precursor MS2 spectrum
-/// * `spectra`: a slice (generally entire mzML) of spectra, that can be searched
-///     for precursor spectra
-/// * `ms3`: The MS3 spectrum to search and quantify
-/// * `isobaric_labels`: specify label m/zs to be used
-/// * `isobaric_tolerance`: specify label tolerance
-// pub fn quantify_sps<'a, 'b>(
-//     scorer: &'a Scorer<'a>,
-//     spectra: &[ProcessedSpectrum],
-//     ms3: &'b ProcessedSpectrum,
-//     isobaric_labels: &Isobaric,
-//     isobaric_tolerance: Tolerance,
-// ) -> Option<Quant<'b>> {
-//     let first_precursor = ms3
-//         .precursors
-//         .first()
-//         .expect("MS3 scan without at least one precursor!");
-
-//     let ms2 = spectrum::find_spectrum_by_id(
-//         spectra,
-//         first_precursor
-//             .scan
-//             .expect("MS3 scan without a MS2 precursor scan ID"),
-//     )
-//     .expect("Couldn't locate parent MS2 scan!");
-
-//     let ms1_charge = ms2
-//         .precursors
-//         .get(0)
-//         .and_then(|p| p.charge)
-//         .unwrap_or(2)
-//         .saturating_sub(1);
-
-//     let scores = scorer.score_chimera(ms2);
-//     let hit = scores.first()?.clone();
-//     let peptide = &scorer.db[hit.peptide_idx];
-//     let hit_purity = purity_of_match(
-//         &ms3.precursors,
-//         ms2,
-//         &mk_theoretical(peptide),
-//         ms1_charge,
-//         scorer.fragment_tol,
-//     );
-
-//     let chimera = scores.get(1).cloned();
-//     let chimera_purity = chimera.as_ref().map(|score| {
-//         purity_of_match(
-//             &ms3.precursors,
-//             ms2,
-//             &mk_theoretical(&scorer.db[score.peptide_idx]),
-//             ms1_charge,
-//             scorer.fragment_tol,
-//         )
-//     });
-
-//     Some(Quant {
-//         hit,
-//         hit_purity,
-//         chimera,
-//         chimera_purity,
-//         intensities: find_reporter_ions(
-//             &ms3.peaks,
-//             isobaric_labels.reporter_masses(),
-//             isobaric_tolerance,
-//         ),
-//         spectrum: ms3,
-//     })
-// }
-
-#[derive(Clone)]
+const ITRAQ8PLEX: [f32; 8] = [
+    113.107873, 114.111228, 115.108263, 116.111618, 117.114973, 118.112008, 119.115363, 121.122072,
+];
+
+const TMT6PLEX_CHANNELS: [&str; 6] = ["126", "127", "128", "129", "130", "131"];
+
+const TMT10PLEX_CHANNELS: [&str; 10] = [
+    "126", "127N", "127C", "128N", "128C", "129N", "129C", "130N", "130C", "131",
+];
+
+const TMT18PLEX_CHANNELS: [&str; 18] = [
+    "126", "127N", "127C", "128N", "128C", "129N", "129C", "130N", "130C", "131N", "131C", "132N",
+    "132C", "133N", "133C", "134N", "134C", "135N",
+];
+
+const ITRAQ8PLEX_CHANNELS: [&str; 8] = ["113", "114", "115", "116", "117", "118", "119", "121"];
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TmtQuant {
     pub spec_id: String,
     pub file_id: usize,
     pub ion_injection_time: f32,
     pub peaks: Vec<f32>,
+    /// Signal-to-noise ratio of each reporter ion, if noise levels were read
+    /// from the mzML file. Empty otherwise
+    pub signal_to_noise: Vec<f32>,
+    /// SPS precursor purity of MS3 spectra, see [`assign_sps_purity`]
+    pub sps_purity: Option<f32>,
 }
 
 /// Quantify isobaric tags from an MS2 or MS3 spectrum
@@ -324,21 +378,651 @@ pub fn quantify(
                     .unwrap_or_default(),
             };
 
-            let peaks = find_reporter_ions(
+            let reporters = find_reporter_ions(
                 &spectrum.peaks,
                 isobaric_labels.reporter_masses(),
                 isobaric_tolerance,
-            )
-            .into_iter()
-            .map(|peak| peak.map(|p| p.intensity).unwrap_or_default())
-            .collect();
+            );
+            let peaks = reporters
+                .iter()
+                .map(|peak| peak.map(|p| p.intensity).unwrap_or_default())
+                .collect();
+            let signal_to_noise = match spectrum.noise.is_empty() {
+                true => Vec::new(),
+                false => reporters
+                    .iter()
+                    .map(|peak| {
+                        peak.and_then(|p| {
+                            let noise = noise_at(&spectrum.noise, p.mass)?;
+                            (noise > 0.0).then(|| p.intensity / noise)
+                        })
+                        .unwrap_or_default()
+                    })
+                    .collect(),
+            };
 
             Some(TmtQuant {
                 spec_id,
                 file_id: spectrum.file_id,
                 ion_injection_time: spectrum.ion_injection_time,
                 peaks,
+                signal_to_noise,
+                sps_purity: None,
             })
         })
         .collect()
 }
+
+/// Correction of reporter ion intensities for the isotopic impurities of
+/// the labeling reagents
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImpurityCorrection {
+    /// Inverse of the (column-normalized) impurity matrix
+    inverse: Vec<Vec<f64>>,
+}
+
+impl ImpurityCorrection {
+    /// Row `i` of `impurities` is the distribution of the reporter ion signal
+    /// of channel `i` across all channels, as listed on the lot certificate of
+    /// the reagents (e.g. `[0.0, 0.0, 92.1, 6.5, 1.4, 0.0]`). Rows are
+    /// normalized to sum to 1, so percentages and fractions are both accepted.
+    ///
+    /// Returns `None` if the matrix is not square, or cannot be inverted
+    pub fn new(impurities: &[Vec<f32>]) -> Option<Self> {
+        let n = impurities.len();
+        if n == 0 || impurities.iter().any(|row| row.len() != n) {
+            return None;
+        }
+
+        // observed[j] = sum_i A[j][i] * true[i], where A[j][i] is the fraction
+        // of channel `i` signal that is observed in channel `j`
+        let mut a = vec![vec![0.0f64; n]; n];
+        for (i, row) in impurities.iter().enumerate() {
+            let total = row.iter().map(|&x| x as f64).sum::<f64>();
+            if total <= 0.0 || row.iter().any(|&x| x < 0.0) {
+                return None;
+            }
+            for (j, &x) in row.iter().enumerate() {
+                a[j][i] = x as f64 / total;
+            }
+        }
+
+        // Gauss-Jordan elimination with partial pivoting
+        let mut inverse = (0..n)
+            .map(|i| (0..n).map(|j| (i == j) as u8 as f64).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        for col in 0..n {
+            let pivot = (col..n).max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))?;
+            if a[pivot][col].abs() < 1E-9 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inverse.swap(col, pivot);
+
+            let scale = a[col][col];
+            a[col].iter_mut().for_each(|x| *x /= scale);
+            inverse[col].iter_mut().for_each(|x| *x /= scale);
+            for row in 0..n {
+                let factor = a[row][col];
+                if row == col || factor == 0.0 {
+                    continue;
+                }
+                for k in 0..n {
+                    a[row][k] -= factor * a[col][k];
+                    inverse[row][k] -= factor * inverse[col][k];
+                }
+            }
+        }
+        Some(Self { inverse })
+    }
+
+    /// Number of reporter ion channels
+    pub fn channels(&self) -> usize {
+        self.inverse.len()
+    }
+
+    /// Correct the reporter ion intensities of a single spectrum in place.
+    /// Negative intensities resulting from the correction are set to 0
+    pub fn correct(&self, peaks: &mut [f32]) {
+        if peaks.len() != self.inverse.len() {
+            return;
+        }
+        let corrected = self
+            .inverse
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .zip(peaks.iter())
+                    .map(|(x, &peak)| x * peak as f64)
+                    .sum::<f64>()
+                    .max(0.0) as f32
+            })
+            .collect::<Vec<_>>();
+        peaks.copy_from_slice(&corrected);
+    }
+}
+
+/// Reporter ion intensities of a single protein, summarized across PSMs
+/// within each plex
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProteinTmtQuant {
+    pub protein: String,
+    /// Summarized reporter ion intensities, for each plex. Empty if the
+    /// protein was not quantified in that plex
+    pub plexes: Vec<Vec<f64>>,
+}
+
+/// Maximum spectrum- and peptide-level q-value of PSMs used for the protein
+/// rollup
+const PROTEIN_Q_VALUE: f32 = 0.01;
+
+/// Summarize reporter ion intensities to the protein level within each plex,
+/// using `method` across PSMs.
+///
+/// Only rank 1, target PSMs at 1% spectrum- and peptide-level FDR are used, and shared peptides are ignored. PSMs with a precursor
+/// isolation purity ([`Feature::isolation_purity`]) below `min_purity` are
+/// ignored, as co-isolated peptides distort their reporter ion ratios - PSMs
+/// without a purity estimate are kept.
+///
+/// * `plex_of_file`: plex of each file (indexed by `file_id`). PSMs from files
+///   that are not assigned to a plex are ignored
+/// * `plexes`: total number of plexes
+pub fn protein_rollup(
+    db: &IndexedDatabase,
+    features: &[Feature],
+    quant: &[TmtQuant],
+    plex_of_file: &[Option<usize>],
+    plexes: usize,
+    min_purity: f32,
+    method: ProteinRollup,
+) -> Vec<ProteinTmtQuant> {
+    let scans = quant
+        .iter()
+        .map(|q| ((q.file_id, q.spec_id.as_str()), q))
+        .collect::<fnv::FnvHashMap<_, _>>();
+
+    let mut proteins: fnv::FnvHashMap<&str, Vec<Vec<Vec<f64>>>> = fnv::FnvHashMap::default();
+    for feature in features {
+        if feature.label != 1
+            || feature.rank != 1
+            || feature.spectrum_q > PROTEIN_Q_VALUE
+            || feature.peptide_q > PROTEIN_Q_VALUE
+            || feature.isolation_purity.map_or(false, |p| p < min_purity)
+        {
+            continue;
+        }
+        let peptide = &db[feature.peptide_idx];
+        let plex = match plex_of_file.get(feature.file_id).copied().flatten() {
+            Some(plex) if !peptide.shared() => plex,
+            _ => continue,
+        };
+        let scan = match scans.get(&(feature.file_id, &*feature.spec_id)) {
+            Some(scan) => scan,
+            None => continue,
+        };
+
+        proteins
+            .entry(peptide.proteins[0].as_str())
+            .or_insert_with(|| vec![Vec::new(); plexes])[plex]
+            .push(scan.peaks.iter().map(|&peak| peak as f64).collect());
+    }
+
+    let mut proteins = proteins
+        .into_iter()
+        .map(|(protein, plexes)| ProteinTmtQuant {
+            protein: protein.to_string(),
+            plexes: plexes.iter().map(|rows| method.summarize(rows)).collect(),
+        })
+        .collect::<Vec<_>>();
+    proteins.sort_by(|a, b| a.protein.cmp(&b.protein));
+    proteins
+}
+
+/// Normalization of reporter ion channels within each file
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    /// Scale each channel so that the median of its (non-zero) intensities
+    /// equals the mean of the channel medians
+    Median,
+    /// Scale each channel so that its summed intensity equals the mean of
+    /// the channel sums
+    Total,
+    /// Scale each spectrum so that its reference channel intensity equals
+    /// the median reference channel intensity of the file
+    Reference,
+}
+
+/// Normalize reporter ion intensities (and signal-to-noise ratios) of each
+/// file, to correct for differences in sample loading between channels.
+///
+/// * `reference_channels`: reference channels of each file (indexed by
+///   `file_id`), only used for [`Normalization::Reference`]. Spectra of files
+///   without reference channels are left unchanged, and spectra without
+///   reference channel intensity cannot be scaled, and are set to 0
+pub fn normalize(quant: &mut [TmtQuant], method: Normalization, reference_channels: &[Vec<usize>]) {
+    let files = quant
+        .iter()
+        .map(|q| q.file_id + 1)
+        .max()
+        .unwrap_or_default();
+    for file_id in 0..files {
+        let mut spectra = quant
+            .iter_mut()
+            .filter(|q| q.file_id == file_id)
+            .collect::<Vec<_>>();
+        let reference = reference_channels
+            .get(file_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        normalize_file(&mut spectra, method, reference, |q| &mut q.peaks);
+        normalize_file(&mut spectra, method, reference, |q| &mut q.signal_to_noise);
+    }
+}
+
+fn normalize_file<F>(
+    spectra: &mut [&mut TmtQuant],
+    method: Normalization,
+    reference: &[usize],
+    values: F,
+) where
+    F: Fn(&mut TmtQuant) -> &mut Vec<f32>,
+{
+    let channels = spectra
+        .iter_mut()
+        .map(|q| values(q).len())
+        .max()
+        .unwrap_or_default();
+    if channels == 0 {
+        return;
+    }
+
+    let median = |mut xs: Vec<f32>| -> Option<f32> {
+        xs.sort_by(|a, b| a.total_cmp(b));
+        match xs.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((xs[n / 2 - 1] + xs[n / 2]) / 2.0),
+            n => Some(xs[n / 2]),
+        }
+    };
+
+    match method {
+        Normalization::Median | Normalization::Total => {
+            let summaries = (0..channels)
+                .map(|channel| {
+                    let xs = spectra
+                        .iter_mut()
+                        .filter_map(|q| values(q).get(channel).copied())
+                        .filter(|x| *x > 0.0)
+                        .collect::<Vec<_>>();
+                    match method {
+                        Normalization::Median => median(xs),
+                        _ => Some(xs.iter().sum::<f32>()).filter(|x| *x > 0.0),
+                    }
+                })
+                .collect::<Vec<_>>();
+            let observed = summaries.iter().flatten().collect::<Vec<_>>();
+            if observed.is_empty() {
+                return;
+            }
+            let target = observed.iter().copied().sum::<f32>() / observed.len() as f32;
+            for q in spectra.iter_mut() {
+                for (x, summary) in values(q).iter_mut().zip(&summaries) {
+                    if let Some(summary) = summary {
+                        *x *= target / summary;
+                    }
+                }
+            }
+        }
+        Normalization::Reference => {
+            if reference.is_empty() {
+                return;
+            }
+            let reference_intensity = |xs: &[f32]| {
+                reference.iter().filter_map(|&idx| xs.get(idx)).sum::<f32>()
+                    / reference.len() as f32
+            };
+            let references = spectra
+                .iter_mut()
+                .map(|q| reference_intensity(values(q)))
+                .filter(|x| *x > 0.0)
+                .collect::<Vec<_>>();
+            let target = match median(references) {
+                Some(target) => target,
+                None => return,
+            };
+            for q in spectra.iter_mut() {
+                let xs = values(q);
+                let intensity = reference_intensity(xs);
+                for x in xs.iter_mut() {
+                    *x = match intensity > 0.0 {
+                        true => *x * target / intensity,
+                        false => 0.0,
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Internal reference scaling (IRS) across plexes, following Plubell et al.
+/// (2017) "Extended Multiplexing of TMT Labeling Reveals Age and High Fat Diet
+/// Specific Proteome Changes in Mouse Epididymal Adipose Tissue".
+///
+/// For each protein, the reference intensity of a plex is the mean of its
+/// `reference_channels` (indexed by plex). All channels of a plex are scaled
+/// so that its reference intensity is equal to the geometric mean of the
+/// reference intensities of all plexes.
+///
+/// Proteins that are not quantified in every plex, or that lack reference
+/// channel intensity in any plex, cannot be scaled, and are removed.
+pub fn internal_reference_scaling(
+    proteins: &mut Vec<ProteinTmtQuant>,
+    reference_channels: &[Vec<usize>],
+) {
+    proteins.retain_mut(|protein| {
+        let references = protein
+            .plexes
+            .iter()
+            .zip(reference_channels)
+            .map(|(channels, reference)| {
+                let sum = reference
+                    .iter()
+                    .filter_map(|&idx| channels.get(idx))
+                    .sum::<f64>();
+                sum / reference.len().max(1) as f64
+            })
+            .collect::<Vec<_>>();
+
+        if references.len() != protein.plexes.len() || references.iter().any(|r| *r <= 0.0) {
+            return false;
+        }
+
+        let geometric_mean =
+            (references.iter().map(|r| r.ln()).sum::<f64>() / references.len() as f64).exp();
+        for (channels, reference) in protein.plexes.iter_mut().zip(&references) {
+            let factor = geometric_mean / reference;
+            for intensity in channels.iter_mut() {
+                *intensity *= factor;
+            }
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::Builder;
+    use crate::fasta::Fasta;
+
+    #[test]
+    fn impurity_correction() {
+        // 10% of channel 1 signal spills into channel 2, 5% of channel 2
+        // signal into channel 1
+        let correction = ImpurityCorrection::new(&[
+            vec![90.0, 10.0, 0.0],
+            vec![5.0, 95.0, 0.0],
+            vec![0.0, 0.0, 1.0],
+        ])
+        .unwrap();
+        assert_eq!(correction.channels(), 3);
+        let mut peaks = [
+            0.9 * 1000.0 + 0.05 * 500.0,
+            0.1 * 1000.0 + 0.95 * 500.0,
+            250.0,
+        ];
+        correction.correct(&mut peaks);
+        for (peak, expected) in peaks.iter().zip([1000.0, 500.0, 250.0]) {
+            assert!((peak - expected).abs() < 1E-3, "{} != {}", peak, expected);
+        }
+
+        // Impurity-only signal is clamped to 0
+        let mut peaks = [0.0, 100.0, 0.0];
+        correction.correct(&mut peaks);
+        assert_eq!(peaks[0], 0.0);
+
+        assert!(ImpurityCorrection::new(&[vec![1.0, 0.0]]).is_none());
+        assert!(ImpurityCorrection::new(&[vec![1.0, 1.0], vec![1.0, 1.0]]).is_none());
+    }
+
+    #[test]
+    fn channel_names() {
+        assert_eq!(Isobaric::Tmt6.headers()[1], "tmt_127");
+        assert_eq!(Isobaric::Tmt10.headers()[9], "tmt_131");
+        assert_eq!(Isobaric::Tmt11.headers()[10], "tmt_131C");
+        assert_eq!(Isobaric::Tmt16.headers()[15], "tmt_134N");
+        assert_eq!(
+            Isobaric::Itraq4.headers(),
+            ["itraq_114", "itraq_115", "itraq_116", "itraq_117"]
+        );
+        assert_eq!(
+            Isobaric::User(vec![100.0, 101.0]).headers(),
+            ["user_1", "user_2"]
+        );
+
+        for isobaric in [
+            Isobaric::Tmt6,
+            Isobaric::Tmt10,
+            Isobaric::Tmt11,
+            Isobaric::Tmt16,
+            Isobaric::Tmt18,
+            Isobaric::Itraq4,
+            Isobaric::Itraq8,
+        ] {
+            assert_eq!(isobaric.headers().len(), isobaric.reporter_masses().len());
+        }
+    }
+
+    #[test]
+    fn sps_purity() {
+        let fasta = Fasta::parse(
+            ">sp|A\nMEWKLEQSMREQALLKAQLTQLKGAVLRLESLIEK".into(),
+            "rev_",
+            true,
+        );
+        let db = Builder {
+            fasta: Some("none".into()),
+            ..Default::default()
+        }
+        .make_parameters()
+        .build(fasta);
+        let (idx, peptide) = db
+            .peptides
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.to_string() == "LESLIEK")
+            .unwrap();
+        let b3 = IonSeries::new(peptide, Kind::B)
+            .nth(1)
+            .unwrap()
+            .monoisotopic_mass;
+
+        let ms2 = ProcessedSpectrum {
+            level: 2,
+            id: "scan=1".into(),
+            peaks: vec![
+                Peak {
+                    mass: b3,
+                    intensity: 100.0,
+                },
+                Peak {
+                    mass: 500.0,
+                    intensity: 50.0,
+                },
+            ],
+            ..Default::default()
+        };
+        let sps = |mz: f32, spectrum_ref: &str| Precursor {
+            mz: mz + PROTON,
+            spectrum_ref: Some(spectrum_ref.into()),
+            isolation_window: Some(Tolerance::Da(-0.5, 0.5)),
+            ..Default::default()
+        };
+        let ms3 = ProcessedSpectrum {
+            level: 3,
+            id: "scan=2".into(),
+            // The last SPS precursor references the wrong scan, and is ignored
+            precursors: vec![
+                sps(b3, "scan=1"),
+                sps(500.0, "scan=1"),
+                sps(800.0, "scan=0"),
+            ],
+            ..Default::default()
+        };
+        let features = vec![Feature {
+            peptide_idx: crate::database::PeptideIx(idx as u32),
+            spec_id: "scan=1".into(),
+            rank: 1,
+            charge: 2,
+            ..Default::default()
+        }];
+        let mut quant = vec![TmtQuant {
+            spec_id: "scan=1".into(),
+            file_id: 0,
+            ion_injection_time: 0.0,
+            peaks: vec![],
+            signal_to_noise: vec![],
+            sps_purity: None,
+        }];
+
+        let purity = purity_of_match(
+            &ms3.precursors,
+            &ms2,
+            &mk_theoretical(peptide, db.residue_masses),
+            2,
+            Tolerance::Ppm(-10.0, 10.0),
+        )
+        .unwrap();
+        assert_eq!(purity.correct_precursors, 1);
+        assert_eq!(purity.incorrect_precursors, 1);
+
+        assign_sps_purity(
+            &mut quant,
+            &db,
+            &[ms2, ms3],
+            &features,
+            Tolerance::Ppm(-10.0, 10.0),
+        );
+        let ratio = quant[0].sps_purity.unwrap();
+        assert!((ratio - 100.0 / 150.0).abs() < 1E-6, "{}", ratio);
+    }
+
+    #[test]
+    fn reporter_ion_masking() {
+        let peak = |mz: f32| Peak {
+            mass: mz - PROTON,
+            intensity: 1.0,
+        };
+        let mut spectrum = ProcessedSpectrum {
+            level: 2,
+            peaks: vec![peak(126.1278), peak(126.5), peak(127.1248), peak(131.1382)],
+            ..Default::default()
+        };
+        let tolerance = Tolerance::Ppm(-20.0, 20.0);
+        let quant = quantify(&[spectrum.clone()], &Isobaric::Tmt6, tolerance, 2);
+        assert_eq!(quant[0].peaks, vec![1.0, 1.0, 0.0, 0.0, 0.0, 1.0]);
+
+        mask_reporter_ions(&mut spectrum, &Isobaric::Tmt6, tolerance);
+        assert_eq!(spectrum.peaks, vec![peak(126.5)]);
+    }
+
+    #[test]
+    fn reporter_signal_to_noise() {
+        let peak = |mz: f32, intensity| Peak {
+            mass: mz - PROTON,
+            intensity,
+        };
+        let mut spectrum = ProcessedSpectrum {
+            level: 3,
+            peaks: vec![peak(126.1278, 100.0), peak(127.1248, 90.0)],
+            ..Default::default()
+        };
+        let tolerance = Tolerance::Ppm(-20.0, 20.0);
+        let quant = quantify(&[spectrum.clone()], &Isobaric::Tmt6, tolerance, 3);
+        assert!(quant[0].signal_to_noise.is_empty());
+
+        // Noise is taken from the closest raw peak
+        spectrum.noise = vec![peak(126.1278, 10.0), peak(127.0, 20.0), peak(127.2, 30.0)];
+        let quant = quantify(&[spectrum], &Isobaric::Tmt6, tolerance, 3);
+        assert_eq!(quant[0].peaks, vec![100.0, 90.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(
+            quant[0].signal_to_noise,
+            vec![10.0, 3.0, 0.0, 0.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn channel_normalization() {
+        let q = |file_id, peaks: Vec<f32>| TmtQuant {
+            spec_id: String::default(),
+            file_id,
+            ion_injection_time: 0.0,
+            peaks,
+            signal_to_noise: vec![],
+            sps_purity: None,
+        };
+        let close = |a: &[f32], b: &[f32]| {
+            assert_eq!(a.len(), b.len());
+            for (x, y) in a.iter().zip(b) {
+                assert!((x - y).abs() < 1E-4, "{:?} != {:?}", a, b);
+            }
+        };
+        let quant = vec![
+            q(0, vec![10.0, 40.0, 0.0]),
+            q(0, vec![20.0, 80.0, 30.0]),
+            q(0, vec![30.0, 120.0, 0.0]),
+            q(1, vec![1.0, 1.0, 1.0]),
+        ];
+
+        // Channel medians (of non-zero intensities) are 20, 80 and 30
+        let mut median = quant.clone();
+        normalize(&mut median, Normalization::Median, &[]);
+        close(&median[0].peaks, &[21.666666, 21.666666, 0.0]);
+        close(&median[1].peaks, &[43.333332, 43.333332, 43.333332]);
+        close(&median[3].peaks, &[1.0, 1.0, 1.0]);
+
+        // Channel totals are 60, 240 and 30
+        let mut total = quant.clone();
+        normalize(&mut total, Normalization::Total, &[]);
+        close(&total[1].peaks, &[36.666668, 36.666668, 110.0]);
+
+        // Median reference (channel 0) intensity of file 0 is 20
+        let mut reference = quant;
+        normalize(&mut reference, Normalization::Reference, &[vec![0]]);
+        close(&reference[0].peaks, &[20.0, 80.0, 0.0]);
+        close(&reference[2].peaks, &[20.0, 80.0, 0.0]);
+        close(&reference[3].peaks, &[1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn irs() {
+        let mut proteins = vec![
+            ProteinTmtQuant {
+                protein: "A".into(),
+                plexes: vec![vec![10.0, 20.0, 40.0], vec![5.0, 10.0, 160.0]],
+            },
+            // Not quantified in the second plex
+            ProteinTmtQuant {
+                protein: "B".into(),
+                plexes: vec![vec![10.0, 20.0, 40.0], vec![]],
+            },
+            // No reference channel intensity in the first plex
+            ProteinTmtQuant {
+                protein: "C".into(),
+                plexes: vec![vec![10.0, 20.0, 0.0], vec![5.0, 10.0, 160.0]],
+            },
+        ];
+
+        internal_reference_scaling(&mut proteins, &[vec![2], vec![2]]);
+        assert_eq!(proteins.len(), 1);
+
+        // Geometric mean of 40 and 160 is 80
+        let scaled = &proteins[0].plexes;
+        assert!((scaled[0][2] - 80.0).abs() < 1E-6);
+        assert!((scaled[1][2] - 80.0).abs() < 1E-6);
+        assert!((scaled[0][0] - 20.0).abs() < 1E-6);
+        assert!((scaled[1][0] - 2.5).abs() < 1E-6);
+    }
+}
@@ -68,3 +68,20 @@ fn check_all_ions_visited(target_fragment_mz: f32, bucket_size: usize) {
     // Make sure we visited every possible fragment
     assert_eq!(expected, visited);
 }
+
+#[quickcheck]
+fn check_contains_precursor(precursor_mass: f32, tolerance: f32) {
+    let database = mk_database(8192);
+    let precursor_mass = precursor_mass.abs() % 5000.0;
+    let tolerance = Tolerance::Da(-(tolerance.abs() % 50.0), tolerance.abs() % 50.0);
+    let (lo, hi) = tolerance.bounds(precursor_mass);
+
+    let expected = database
+        .peptides
+        .iter()
+        .any(|p| p.monoisotopic >= lo && p.monoisotopic <= hi);
+    assert_eq!(
+        expected,
+        database.contains_precursor(precursor_mass, tolerance)
+    );
+}